@@ -7,16 +7,22 @@ use rust_htslib::bam::{self, Read};
 use rustc_hash::{FxHashMap, FxHashSet};
 use std::ops::AddAssign;
 
+use crate::bam_pipeline::OrderedRecordPipeline;
 use crate::errs::{MkError, MkResult};
 use crate::mod_bam::{
     format_mm_ml_tag, BaseModProbs, CollapseMethod, EdgeFilter, ModBaseInfo,
-    SeqPosBaseModProbs,
+    SeqPosBaseModProbs, SkipMode, ML_TAGS, MM_TAGS, MN_TAG,
 };
 use crate::mod_base_code::DnaBase;
 use crate::monoid::Moniod;
 use crate::motifs::motif_bed::OverlappingRegex;
+use crate::position_filter::StrandedPositionFilter;
+use crate::run_summary;
 use crate::threshold_mod_caller::MultipleThresholdModCaller;
-use crate::util::{format_errors_table, get_query_name_string, get_ticker};
+use crate::util::{
+    format_errors_table, get_aligned_pairs_forward, get_query_name_string,
+    get_ticker, AlignmentIdentityFilter, Strand,
+};
 
 #[derive(new)]
 pub(crate) struct OverlappingRegexOffset(OverlappingRegex, usize);
@@ -37,10 +43,12 @@ impl OverlappingRegexOffset {
 struct SequenceMotifs<'a> {
     complex: Vec<&'a OverlappingRegexOffset>,
     simple: FxHashSet<u8>,
+    /// Discard calls matching the motifs instead of keeping them.
+    discard: bool,
 }
 
 impl<'a> SequenceMotifs<'a> {
-    fn new(motifs: &'a [OverlappingRegexOffset]) -> Self {
+    fn new(motifs: &'a [OverlappingRegexOffset], discard: bool) -> Self {
         let (complex, simple) = motifs.iter().fold(
             (Vec::new(), Vec::new()),
             |(mut complex, mut simple), next| {
@@ -57,7 +65,7 @@ impl<'a> SequenceMotifs<'a> {
                 (complex, simple)
             },
         );
-        Self { complex, simple: simple.into_iter().collect() }
+        Self { complex, simple: simple.into_iter().collect(), discard }
     }
 
     fn find_positions(&self, record: &bam::Record) -> FxHashSet<usize> {
@@ -107,7 +115,41 @@ impl SeqPosBaseModProbs {
             })
             .collect::<FxHashMap<usize, BaseModProbs>>();
 
-        Self::new(crate::mod_bam::SkipMode::Explicit, probs)
+        Self::new(SkipMode::Explicit, probs)
+    }
+
+    /// Split the positions into those with a reference position inside the
+    /// regions of `position_filter` and those outside (including unaligned
+    /// positions), both keep the current skip mode.
+    fn partition_regions(
+        self,
+        position_filter: &StrandedPositionFilter<()>,
+        aligned_pairs: &FxHashMap<usize, u64>,
+        chrom_id: i32,
+        reference_strand: Strand,
+    ) -> (Self, Self) {
+        let skip_mode = self.skip_mode;
+        let (inside, outside) = self
+            .pos_to_base_mod_probs
+            .into_iter()
+            .partition::<FxHashMap<usize, BaseModProbs>, _>(|(pos, _)| {
+                aligned_pairs.get(pos).is_some_and(|ref_pos| {
+                    position_filter.contains(
+                        chrom_id,
+                        *ref_pos,
+                        reference_strand,
+                    )
+                })
+            });
+        (Self::new(skip_mode, inside), Self::new(skip_mode, outside))
+    }
+
+    /// Add back positions that were not adjusted. If the adjusted positions
+    /// changed the skip mode (e.g. filtered positions), the new mode is used.
+    fn merge_unadjusted(self, unadjusted: Self) -> Self {
+        let mut probs = self.pos_to_base_mod_probs;
+        probs.extend(unadjusted.pos_to_base_mod_probs);
+        Self::new(self.skip_mode, probs)
     }
 }
 
@@ -118,7 +160,7 @@ fn adjust_mod_probs<'a>(
     edge_filter: Option<&EdgeFilter>,
     filter_only: bool,
     sequence_motifs: &Option<SequenceMotifs<'a>>,
-    discard_motifs: bool,
+    position_filter: Option<&StrandedPositionFilter<()>>,
 ) -> MkResult<bam::Record> {
     let mod_base_info = ModBaseInfo::new_from_record(&record)?;
     let mm_style = mod_base_info.mm_style;
@@ -132,9 +174,36 @@ fn adjust_mod_probs<'a>(
 
     let positions =
         sequence_motifs.as_ref().map(|ms| ms.find_positions(&record));
+    // only calls aligned to a reference position in the regions are adjusted
+    let aligned_pairs = position_filter.map(|_| {
+        get_aligned_pairs_forward(&record)
+            .filter_map(|pair| pair.ok())
+            .collect::<FxHashMap<usize, u64>>()
+    });
 
     for (base, strand, seq_pos_mod_probs) in mod_prob_iter {
         let converter = converters.get(&base).unwrap();
+        let (seq_pos_mod_probs, unadjusted) =
+            match (position_filter, aligned_pairs.as_ref()) {
+                (Some(position_filter), Some(aligned_pairs)) => {
+                    let reference_strand = if record.is_reverse() {
+                        strand.opposite()
+                    } else {
+                        strand
+                    };
+                    let (inside, outside) = seq_pos_mod_probs
+                        .partition_regions(
+                            position_filter,
+                            aligned_pairs,
+                            record.tid(),
+                            reference_strand,
+                        );
+                    let outside = Some(outside)
+                        .filter(|x| !x.pos_to_base_mod_probs.is_empty());
+                    (inside, outside)
+                }
+                _ => (seq_pos_mod_probs, None),
+            };
         // edge filter
         let trimmed_seq_pos_base_mod_probs = if let Some(edge_filter) =
             edge_filter
@@ -155,7 +224,23 @@ fn adjust_mod_probs<'a>(
         } else {
             Some(seq_pos_mod_probs)
         };
-        if let Some(mut seq_pos_mod_probs) = trimmed_seq_pos_base_mod_probs {
+        let trimmed_seq_pos_base_mod_probs =
+            match (trimmed_seq_pos_base_mod_probs, unadjusted) {
+                (Some(adjusted), unadjusted) => Some((adjusted, unadjusted)),
+                // all of the positions in the regions were filtered out, still
+                // keep the positions outside of the regions
+                (None, Some(unadjusted)) => Some((
+                    SeqPosBaseModProbs::new(
+                        SkipMode::Explicit,
+                        FxHashMap::default(),
+                    ),
+                    Some(unadjusted),
+                )),
+                (None, None) => None,
+            };
+        if let Some((mut seq_pos_mod_probs, unadjusted)) =
+            trimmed_seq_pos_base_mod_probs
+        {
             // collapse/convert
             for method in methods {
                 seq_pos_mod_probs = seq_pos_mod_probs.into_collapsed(method);
@@ -175,9 +260,15 @@ fn adjust_mod_probs<'a>(
                 _ => {}
             }
             // motif filter
-            if let Some(positions) = positions.as_ref() {
+            if let Some((positions, sequence_motifs)) =
+                positions.as_ref().zip(sequence_motifs.as_ref())
+            {
                 seq_pos_mod_probs = seq_pos_mod_probs
-                    .filter_motif_positions(positions, discard_motifs)
+                    .filter_motif_positions(positions, sequence_motifs.discard)
+            }
+            if let Some(unadjusted) = unadjusted {
+                seq_pos_mod_probs =
+                    seq_pos_mod_probs.merge_unadjusted(unadjusted);
             }
 
             let (mm, mut ml) = format_mm_ml_tag(
@@ -208,15 +299,45 @@ fn adjust_mod_probs<'a>(
     Ok(record)
 }
 
+/// Apply the thresholded calls to the base modification tags of a single
+/// record, the same as `call-mods` without motif filtering.
+pub(crate) fn call_mods_on_record(
+    record: bam::Record,
+    collapse_methods: &[CollapseMethod],
+    caller: &MultipleThresholdModCaller,
+    edge_filter: Option<&EdgeFilter>,
+) -> MkResult<bam::Record> {
+    adjust_mod_probs(
+        record,
+        collapse_methods,
+        Some(caller),
+        edge_filter,
+        false,
+        &None,
+        None,
+    )
+}
+
+/// Remove the modified base tags from a record.
+fn remove_mod_tags(mut record: bam::Record) -> bam::Record {
+    for tag in MM_TAGS.iter().chain(ML_TAGS.iter()).chain([&MN_TAG]) {
+        let _ = record.remove_aux(tag.as_bytes());
+    }
+    record
+}
+
 pub(crate) fn adjust_modbam(
     reader: &mut bam::Reader,
     writer: &mut bam::Writer,
+    threads: usize,
     collapse_methods: &[CollapseMethod],
     threshold_caller: Option<&MultipleThresholdModCaller>,
     edge_filter: Option<&EdgeFilter>,
+    alignment_filter: Option<&AlignmentIdentityFilter>,
     fail_fast: bool,
     motifs: &Option<Vec<OverlappingRegexOffset>>,
     discard_motifs: bool,
+    position_filter: Option<&StrandedPositionFilter<()>>,
     verb: &'static str,
     suppress_progress: bool,
     filter_only: bool,
@@ -227,84 +348,91 @@ pub(crate) fn adjust_modbam(
     }
     spinner.set_message(verb);
     let mut total = 0usize;
+    let mut n_low_identity = 0u64;
     let mut error_counts = FxHashMap::<String, usize>::default();
-    let sequence_motifs = motifs.as_ref().map(|x| SequenceMotifs::new(x));
-    for (i, result) in reader
-        .records()
-        .map(|r| r.map_err(|e| MkError::HtsLibError(e)))
-        .enumerate()
-    {
-        match result {
-            Ok(record) => {
-                match adjust_mod_probs(
+    let sequence_motifs =
+        motifs.as_ref().map(|x| SequenceMotifs::new(x, discard_motifs));
+    let pool = rayon::ThreadPoolBuilder::new().num_threads(threads).build()?;
+    // the flag is true when the record failed the alignment filter and had
+    // its tags removed
+    let adjust_record = |result: MkResult<bam::Record>| {
+        result.and_then(|record| {
+            if alignment_filter.is_some_and(|filter| !filter.passes(&record)) {
+                // keep the record but don't make calls from it
+                Ok((true, remove_mod_tags(record)))
+            } else {
+                adjust_mod_probs(
                     record,
                     &collapse_methods,
                     threshold_caller,
                     edge_filter,
                     filter_only,
                     &sequence_motifs,
-                    discard_motifs,
-                ) {
-                    Err(mk_error) => {
-                        if fail_fast {
-                            spinner.set_draw_target(
-                                indicatif::ProgressDrawTarget::hidden(),
-                            );
-                            error!("encountered error, failing fast");
-                            bail!("{mk_error}")
-                        } else {
-                            error_counts
-                                .entry(mk_error.to_string())
-                                .or_insert(0usize)
-                                .add_assign(1usize);
-                        }
+                    position_filter,
+                )
+                .map(|record| (false, record))
+            }
+        })
+    };
+    let records = reader
+        .records()
+        .map(|r| r.map_err(|e| MkError::HtsLibError(e)))
+        .enumerate();
+    OrderedRecordPipeline::new(&pool).run(
+        records,
+        |(i, result)| (i, adjust_record(result)),
+        |(i, adjusted)| {
+            let failure = match adjusted {
+                Err(mk_error) => Some(mk_error),
+                Ok((low_identity, record)) => {
+                    if low_identity {
+                        n_low_identity += 1;
+                        error_counts
+                            .entry(
+                                "low alignment identity, tags removed".into(),
+                            )
+                            .or_insert(0usize)
+                            .add_assign(1usize);
                     }
-                    Ok(record) => {
-                        if let Err(err) = writer
-                            .write(&record)
-                            .map_err(|e| MkError::HtsLibError(e))
-                        {
-                            if fail_fast {
-                                spinner.set_draw_target(
-                                    indicatif::ProgressDrawTarget::hidden(),
-                                );
-                                error!("encountered error, failing fast");
-                                bail!("{err}")
-                            } else {
-                                error_counts
-                                    .entry(err.to_string())
-                                    .or_insert(0usize)
-                                    .add_assign(1usize);
-                            }
-                        } else {
+                    match writer.write(&record) {
+                        Err(e) => Some(MkError::HtsLibError(e)),
+                        Ok(_) => {
                             spinner.inc(1);
                             total = i + 1;
+                            None
                         }
                     }
                 }
-            }
-            Err(mk_error) => {
+            };
+            if let Some(mk_error) = failure {
                 if fail_fast {
                     spinner.set_draw_target(
                         indicatif::ProgressDrawTarget::hidden(),
                     );
                     error!("encountered error, failing fast");
                     bail!("{mk_error}")
-                } else {
-                    error_counts
-                        .entry(mk_error.to_string())
-                        .or_insert(0usize)
-                        .add_assign(1usize);
                 }
+                error_counts
+                    .entry(mk_error.to_string())
+                    .or_insert(0usize)
+                    .add_assign(1usize);
             }
-        }
-    }
+            Ok(())
+        },
+    )?;
     spinner.finish_and_clear();
 
     info!("done, {} records processed", total,);
+    let n_errors = error_counts.values().sum::<usize>() as u64;
+    run_summary::record_reads(
+        spinner.position().saturating_sub(n_low_identity),
+        n_low_identity,
+        n_errors.saturating_sub(n_low_identity),
+    );
 
     if !error_counts.is_empty() {
         info!("error/skip counts:");
+        run_summary::record_errors(&error_counts);
         let error_table = format_errors_table(&error_counts);
         info!("\n{error_table}");
     }
@@ -340,7 +468,7 @@ mod adjust_tests {
             OverlappingRegexOffset::new(motif.forward_pattern, offset)
         })
         .collect::<Vec<OverlappingRegexOffset>>();
-        let sequence_motifs = Some(SequenceMotifs::new(&regex_motifs));
+        let sequence_motifs = Some(SequenceMotifs::new(&regex_motifs, false));
 
         let checks = HashMap::from([
             (
@@ -372,7 +500,7 @@ mod adjust_tests {
                     None,
                     false,
                     &sequence_motifs,
-                    false,
+                    None,
                 )
                 .unwrap()
             })
@@ -398,6 +526,8 @@ mod adjust_tests {
         assert!(tested);
 
         let bam_fp = "tests/resources/testing_all_context_calls.bam";
+        let discard_sequence_motifs =
+            Some(SequenceMotifs::new(&regex_motifs, true));
         let mut reader = bam::Reader::from_path(bam_fp).unwrap();
         let iter = reader
             .records()
@@ -409,8 +539,8 @@ mod adjust_tests {
                     None,
                     None,
                     false,
-                    &sequence_motifs,
-                    true,
+                    &discard_sequence_motifs,
+                    None,
                 )
                 .unwrap()
             })
@@ -0,0 +1,257 @@
+//! Shared record pipeline for the commands that rewrite tags in a BAM
+//! (`adjust-mods`, `call-mods`, `update-tags`, and `repair`). Records are
+//! transformed in batches on a thread pool and handed back in input order,
+//! output BGZF compression runs on the writer's own threads. The read-ahead
+//! variant is used by `extract` and `summary` when streaming records from
+//! stdin or an unindexed BAM.
+
+use std::path::Path;
+
+use anyhow::{anyhow, Context};
+use rayon::prelude::*;
+use rust_htslib::bam::{self, Header};
+
+use crate::command_utils::get_bam_writer;
+
+/// Records buffered per thread in each batch, enough to keep the pool busy
+/// without holding too many long reads in memory.
+const RECORDS_PER_THREAD: usize = 256;
+/// Batches read ahead of the batch being transformed.
+const READ_AHEAD_BATCHES: usize = 2;
+
+/// BAM (or SAM) writer compressing output with `threads` threads.
+pub(crate) fn get_threaded_bam_writer(
+    raw: &str,
+    header: &Header,
+    output_sam: bool,
+    threads: usize,
+) -> anyhow::Result<bam::Writer> {
+    let mut writer = get_bam_writer(raw, header, output_sam)?;
+    if threads > 1 {
+        writer.set_threads(threads).with_context(|| {
+            format!("failed to set writer threads on {raw}")
+        })?;
+    }
+    Ok(writer)
+}
+
+/// [`get_threaded_bam_writer`] for a path.
+pub(crate) fn get_threaded_bam_writer_for_path(
+    out_fp: &Path,
+    header: &Header,
+    threads: usize,
+) -> anyhow::Result<bam::Writer> {
+    let raw = out_fp
+        .to_str()
+        .ok_or_else(|| anyhow!("invalid output path {out_fp:?}"))?;
+    get_threaded_bam_writer(raw, header, false, threads)
+}
+
+/// Ordered, batched, parallel map over `items`. Each batch of items is
+/// transformed on `pool` with `transform` and the results are passed to
+/// `sink` in the same order as the input, so output BAMs keep the order
+/// (e.g. sorted by coordinate or read name) of the input. Stops at the
+/// first error returned by `sink`.
+pub(crate) struct OrderedRecordPipeline<'a> {
+    pool: &'a rayon::ThreadPool,
+    batch_size: usize,
+}
+
+impl<'a> OrderedRecordPipeline<'a> {
+    pub(crate) fn new(pool: &'a rayon::ThreadPool) -> Self {
+        let batch_size = pool.current_num_threads().max(1) * RECORDS_PER_THREAD;
+        Self { pool, batch_size }
+    }
+
+    pub(crate) fn run<I, T, F, S>(
+        &self,
+        items: impl Iterator<Item = I>,
+        transform: F,
+        mut sink: S,
+    ) -> anyhow::Result<()>
+    where
+        I: Send,
+        T: Send,
+        F: Fn(I) -> T + Sync + Send,
+        S: FnMut(T) -> anyhow::Result<()>,
+    {
+        // fused so that the reader isn't polled again after the end of the
+        // input, htslib readers can block when read past EOF
+        let mut items = items.fuse().peekable();
+        while items.peek().is_some() {
+            let batch =
+                items.by_ref().take(self.batch_size).collect::<Vec<I>>();
+            let results = self.pool.install(|| {
+                batch.into_par_iter().map(&transform).collect::<Vec<T>>()
+            });
+            for result in results {
+                sink(result)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// [`OrderedRecordPipeline::run`] for inputs that can only be read
+/// serially, e.g. a BAM streamed on stdin or without an index. Batches are
+/// collected from `items` on their own thread and queued on a bounded
+/// channel, so reading (and decompressing) the next batches overlaps with
+/// transforming the current one on the current rayon thread pool. `sink`
+/// returns `false` to stop early.
+pub(crate) fn run_read_ahead<I, T, F, S>(
+    items: impl Iterator<Item = I> + Send,
+    transform: F,
+    sink: S,
+) -> anyhow::Result<()>
+where
+    I: Send,
+    T: Send,
+    F: Fn(I) -> T + Sync + Send,
+    S: FnMut(T) -> anyhow::Result<bool>,
+{
+    let batch_size = rayon::current_num_threads().max(1) * RECORDS_PER_THREAD;
+    read_ahead_batches(items, batch_size, transform, sink)
+}
+
+fn read_ahead_batches<I, T, F, S>(
+    items: impl Iterator<Item = I> + Send,
+    batch_size: usize,
+    transform: F,
+    mut sink: S,
+) -> anyhow::Result<()>
+where
+    I: Send,
+    T: Send,
+    F: Fn(I) -> T + Sync + Send,
+    S: FnMut(T) -> anyhow::Result<bool>,
+{
+    let (snd, rcv) = crossbeam::channel::bounded(READ_AHEAD_BATCHES);
+    std::thread::scope(|scope| {
+        scope.spawn(move || {
+            let mut items = items.fuse().peekable();
+            while items.peek().is_some() {
+                let batch = items.by_ref().take(batch_size).collect::<Vec<I>>();
+                // the receiver hangs up when the sink stops early
+                if snd.send(batch).is_err() {
+                    break;
+                }
+            }
+        });
+        for batch in rcv {
+            let results =
+                batch.into_par_iter().map(&transform).collect::<Vec<T>>();
+            for result in results {
+                if !sink(result)? {
+                    return Ok(());
+                }
+            }
+        }
+        Ok(())
+    })
+}
+
+#[cfg(test)]
+mod bam_pipeline_tests {
+    use crate::bam_pipeline::{read_ahead_batches, OrderedRecordPipeline};
+
+    #[test]
+    fn test_ordered_pipeline_keeps_input_order() {
+        let pool =
+            rayon::ThreadPoolBuilder::new().num_threads(4).build().unwrap();
+        let pipeline = OrderedRecordPipeline { pool: &pool, batch_size: 7 };
+        let mut out = Vec::new();
+        pipeline
+            .run(
+                0..1000usize,
+                |x| {
+                    // make later items in a batch finish first
+                    std::thread::sleep(std::time::Duration::from_micros(
+                        ((1000 - x) % 7) as u64 * 10,
+                    ));
+                    x * 2
+                },
+                |x| {
+                    out.push(x);
+                    Ok(())
+                },
+            )
+            .unwrap();
+        assert_eq!(out, (0..1000usize).map(|x| x * 2).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_ordered_pipeline_stops_on_sink_error() {
+        let pool =
+            rayon::ThreadPoolBuilder::new().num_threads(2).build().unwrap();
+        let pipeline = OrderedRecordPipeline { pool: &pool, batch_size: 10 };
+        let mut seen = 0usize;
+        let res = pipeline.run(
+            0..100usize,
+            |x| x,
+            |x| {
+                seen += 1;
+                if x == 15 {
+                    anyhow::bail!("stop")
+                }
+                Ok(())
+            },
+        );
+        assert!(res.is_err());
+        assert_eq!(seen, 16);
+    }
+
+    #[test]
+    fn test_read_ahead_pipeline_keeps_input_order() {
+        let pool =
+            rayon::ThreadPoolBuilder::new().num_threads(4).build().unwrap();
+        let mut out = Vec::new();
+        pool.install(|| {
+            read_ahead_batches(
+                0..1000usize,
+                7,
+                |x| {
+                    std::thread::sleep(std::time::Duration::from_micros(
+                        ((1000 - x) % 7) as u64 * 10,
+                    ));
+                    x * 2
+                },
+                |x| {
+                    out.push(x);
+                    Ok(true)
+                },
+            )
+        })
+        .unwrap();
+        assert_eq!(out, (0..1000usize).map(|x| x * 2).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_read_ahead_pipeline_stops_early() {
+        let pool =
+            rayon::ThreadPoolBuilder::new().num_threads(2).build().unwrap();
+        let mut seen = 0usize;
+        pool.install(|| {
+            // an unbounded input, the reader has to stop when the sink does
+            read_ahead_batches(
+                0usize..,
+                10,
+                |x| x,
+                |x| {
+                    seen += 1;
+                    Ok(x < 15)
+                },
+            )
+        })
+        .unwrap();
+        assert_eq!(seen, 16);
+        let res = pool.install(|| {
+            read_ahead_batches(
+                0..100usize,
+                10,
+                |x| x,
+                |_| anyhow::bail!("stop"),
+            )
+        });
+        assert!(res.is_err());
+    }
+}
@@ -0,0 +1,523 @@
+use std::cmp::Ordering;
+use std::io::{BufRead, Write};
+use std::path::Path;
+
+use anyhow::{bail, Context};
+use bio::io::fasta::IndexedReader as FastaReader;
+use indicatif::ProgressBar;
+use itertools::Itertools;
+use log::{debug, info, warn};
+use rust_htslib::bgzf::Writer as BgzfWriter;
+use rust_htslib::tpool::ThreadPool;
+use rustc_hash::{FxHashMap, FxHashSet};
+
+use crate::dmr::bedmethyl::BedMethylLine;
+use crate::mod_base_code::{DnaBase, ModCodeRepr};
+use crate::mod_code_registry::ModCodeRegistry;
+use crate::run_summary;
+use crate::tabix::{build_bed_tabix_index, TBI_MAX_POSITION};
+use crate::util::{format_errors_table, StrandRule};
+use crate::writers::bedmethyl_header;
+
+const N_BEDMETHYL_FIELDS: usize = 18;
+
+#[derive(thiserror::Error, Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub(crate) enum BedMethylIssue {
+    #[error("invalid-column-count")]
+    ColumnCount,
+    #[error("invalid-bedmethyl-data")]
+    Unparsable,
+    #[error("invalid-interval")]
+    Interval,
+    #[error("invalid-thick-interval")]
+    ThickInterval,
+    #[error("inconsistent-counts")]
+    Counts,
+    #[error("inconsistent-percent-modified")]
+    PercentModified,
+    #[error("duplicate-record")]
+    Duplicate,
+    #[error("mixed-primary-base")]
+    MixedPrimaryBase,
+    #[error("missing-reference-contig")]
+    MissingContig,
+    #[error("reference-base-mismatch")]
+    ReferenceMismatch,
+    #[error("unsorted")]
+    Unsorted,
+}
+
+/// Validates the fields of a single bedMethyl line, returns the parsed record
+/// when the line is well-formed.
+pub(crate) fn check_bedmethyl_line(
+    line: &str,
+) -> Result<BedMethylLine, BedMethylIssue> {
+    let fields = line.split_ascii_whitespace().collect::<Vec<&str>>();
+    if fields.len() != N_BEDMETHYL_FIELDS {
+        return Err(BedMethylIssue::ColumnCount);
+    }
+    let record =
+        BedMethylLine::parse(line).map_err(|_| BedMethylIssue::Unparsable)?;
+    if record.start() >= record.stop() {
+        return Err(BedMethylIssue::Interval);
+    }
+    let thick_start =
+        fields[6].parse::<u64>().map_err(|_| BedMethylIssue::Unparsable)?;
+    let thick_end =
+        fields[7].parse::<u64>().map_err(|_| BedMethylIssue::Unparsable)?;
+    if thick_start < record.start()
+        || thick_end > record.stop()
+        || thick_start > thick_end
+    {
+        return Err(BedMethylIssue::ThickInterval);
+    }
+    let coverage_again =
+        fields[9].parse::<u64>().map_err(|_| BedMethylIssue::Unparsable)?;
+    let summed_counts =
+        record.count_methylated + record.count_canonical + record.count_other;
+    if coverage_again != record.valid_coverage
+        || summed_counts != record.valid_coverage
+    {
+        return Err(BedMethylIssue::Counts);
+    }
+    let percent_modified =
+        fields[10].parse::<f32>().map_err(|_| BedMethylIssue::Unparsable)?;
+    if record.valid_coverage > 0 {
+        let expected = record.frac_modified() * 100f32;
+        // values are written with 2 decimal places
+        if (expected - percent_modified).abs() > 0.01 {
+            return Err(BedMethylIssue::PercentModified);
+        }
+    }
+
+    Ok(record)
+}
+
+struct ReferenceBases {
+    reader: FastaReader<std::fs::File>,
+    contigs: FxHashSet<String>,
+    curr_contig: Option<(String, Vec<u8>)>,
+}
+
+impl ReferenceBases {
+    fn from_path(fasta_fp: &Path) -> anyhow::Result<Self> {
+        let reader = FastaReader::from_file(&fasta_fp).with_context(|| {
+            format!("failed to open indexed FASTA at {fasta_fp:?}")
+        })?;
+        let contigs = reader
+            .index
+            .sequences()
+            .into_iter()
+            .map(|s| s.name)
+            .collect::<FxHashSet<String>>();
+        Ok(Self { reader, contigs, curr_contig: None })
+    }
+
+    fn check_record(
+        &mut self,
+        record: &BedMethylLine,
+        primary_base: DnaBase,
+    ) -> Result<(), BedMethylIssue> {
+        if !self.contigs.contains(&record.chrom) {
+            return Err(BedMethylIssue::MissingContig);
+        }
+        let load = self
+            .curr_contig
+            .as_ref()
+            .map(|(name, _)| name != &record.chrom)
+            .unwrap_or(true);
+        if load {
+            self.reader
+                .fetch_all(&record.chrom)
+                .and_then(|_| {
+                    let mut seq = Vec::new();
+                    self.reader.read(&mut seq)?;
+                    seq.make_ascii_uppercase();
+                    Ok(seq)
+                })
+                .map(|seq| {
+                    self.curr_contig = Some((record.chrom.to_owned(), seq))
+                })
+                .map_err(|e| {
+                    debug!("failed to fetch {}, {e}", &record.chrom);
+                    BedMethylIssue::MissingContig
+                })?;
+        }
+        // safe because we just loaded it
+        let (_, seq) = self.curr_contig.as_ref().unwrap();
+        let ref_base =
+            seq.get(record.start() as usize).ok_or(BedMethylIssue::Interval)?;
+        match DnaBase::try_from(*ref_base) {
+            Ok(base) => {
+                let read_base = match record.strand {
+                    StrandRule::Negative => base.complement(),
+                    StrandRule::Positive | StrandRule::Both => base,
+                };
+                if read_base == primary_base {
+                    Ok(())
+                } else {
+                    Err(BedMethylIssue::ReferenceMismatch)
+                }
+            }
+            // ambiguous reference bases (N, etc.) cannot be checked
+            Err(_) => Ok(()),
+        }
+    }
+}
+
+pub(crate) struct BedMethylChecker {
+    reference: Option<ReferenceBases>,
+    collect_records: bool,
+    error_counts: FxHashMap<String, usize>,
+    unknown_mod_codes: FxHashSet<ModCodeRepr>,
+    finished_contigs: FxHashSet<String>,
+    prev_position: Option<(String, u64)>,
+    // (strand, mod code) -> primary base for records at the previous position
+    position_records: FxHashMap<(StrandRule, ModCodeRepr), DnaBase>,
+    // contig order of first appearance, used when sorting
+    contig_order: FxHashMap<String, usize>,
+    records: Vec<(usize, u64, u64, StrandRule, ModCodeRepr, String)>,
+    num_records: usize,
+    num_invalid: usize,
+    num_unsorted: usize,
+}
+
+impl BedMethylChecker {
+    pub(crate) fn new(
+        reference_fp: Option<&Path>,
+        collect_records: bool,
+    ) -> anyhow::Result<Self> {
+        let reference =
+            reference_fp.map(ReferenceBases::from_path).transpose()?;
+        Ok(Self {
+            reference,
+            collect_records,
+            error_counts: FxHashMap::default(),
+            unknown_mod_codes: FxHashSet::default(),
+            finished_contigs: FxHashSet::default(),
+            prev_position: None,
+            position_records: FxHashMap::default(),
+            contig_order: FxHashMap::default(),
+            records: Vec::new(),
+            num_records: 0,
+            num_invalid: 0,
+            num_unsorted: 0,
+        })
+    }
+
+    fn add_issue(&mut self, issue: BedMethylIssue) {
+        *self.error_counts.entry(issue.to_string()).or_insert(0) += 1;
+    }
+
+    fn check_sorted(&mut self, record: &BedMethylLine) -> bool {
+        let sorted = match self.prev_position.as_ref() {
+            Some((chrom, start)) if chrom == &record.chrom => {
+                match record.start().cmp(start) {
+                    Ordering::Less => false,
+                    Ordering::Equal => true,
+                    Ordering::Greater => {
+                        self.position_records.clear();
+                        true
+                    }
+                }
+            }
+            Some((chrom, _)) => {
+                if self.finished_contigs.contains(&record.chrom) {
+                    false
+                } else {
+                    self.finished_contigs.insert(chrom.to_owned());
+                    self.position_records.clear();
+                    true
+                }
+            }
+            None => true,
+        };
+        if sorted {
+            self.prev_position =
+                Some((record.chrom.to_owned(), record.start()));
+        }
+        sorted
+    }
+
+    fn check_position(
+        &mut self,
+        record: &BedMethylLine,
+    ) -> Result<(), BedMethylIssue> {
+        let Some(primary_base) =
+            ModCodeRegistry::global().primary_base(&record.raw_mod_code)
+        else {
+            self.unknown_mod_codes.insert(record.raw_mod_code);
+            return Ok(());
+        };
+        let key = (record.strand, record.raw_mod_code);
+        if self.position_records.contains_key(&key) {
+            return Err(BedMethylIssue::Duplicate);
+        }
+        let mixed = self.position_records.iter().any(|((strand, _), base)| {
+            strand == &record.strand && base != &primary_base
+        });
+        if mixed {
+            return Err(BedMethylIssue::MixedPrimaryBase);
+        }
+        self.position_records.insert(key, primary_base);
+        if let Some(reference) = self.reference.as_mut() {
+            reference.check_record(record, primary_base)?;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn check_line(&mut self, line_number: usize, line: &str) {
+        self.num_records += 1;
+        let record = match check_bedmethyl_line(line) {
+            Ok(record) => record,
+            Err(issue) => {
+                debug!("line {line_number}: {issue}");
+                self.num_invalid += 1;
+                self.add_issue(issue);
+                return;
+            }
+        };
+        let sorted = self.check_sorted(&record);
+        if !sorted {
+            self.num_unsorted += 1;
+            debug!("line {line_number}: out of order");
+        } else if let Err(issue) = self.check_position(&record) {
+            debug!("line {line_number}: {issue}");
+            self.num_invalid += 1;
+            self.add_issue(issue);
+            return;
+        }
+
+        if self.collect_records {
+            let n_contigs = self.contig_order.len();
+            let contig_idx = *self
+                .contig_order
+                .entry(record.chrom.to_owned())
+                .or_insert(n_contigs);
+            self.records.push((
+                contig_idx,
+                record.start(),
+                record.stop(),
+                record.strand,
+                record.raw_mod_code,
+                line.trim_end().to_string(),
+            ));
+        }
+    }
+
+    pub(crate) fn check_stream<R: BufRead>(
+        &mut self,
+        in_stream: R,
+        counter: &ProgressBar,
+    ) -> anyhow::Result<()> {
+        for (i, line) in in_stream.lines().enumerate() {
+            let line = line.context("failed to read bedMethyl line")?;
+            if line.starts_with('#') || line.trim().is_empty() {
+                continue;
+            }
+            self.check_line(i + 1, &line);
+            counter.inc(1);
+        }
+        Ok(())
+    }
+
+    /// Write the valid records, sorted, to a bgzip-compressed file and build
+    /// a tabix index for it.
+    pub(crate) fn write_sorted_bgzf(
+        &mut self,
+        out_fp: &Path,
+        with_header: bool,
+        io_threads: usize,
+    ) -> anyhow::Result<()> {
+        let pool = ThreadPool::new(io_threads as u32)?;
+        let mut writer = BgzfWriter::from_path(out_fp)?;
+        writer.set_thread_pool(&pool)?;
+        if with_header {
+            writer.write_all(bedmethyl_header().as_bytes())?;
+        }
+        let records = std::mem::take(&mut self.records);
+        let csi = records.iter().any(|(_, _, end, ..)| *end > TBI_MAX_POSITION);
+        for (.., line) in records.into_iter().sorted_by(|a, b| {
+            (a.0, a.1, a.2, a.3, a.4).cmp(&(b.0, b.1, b.2, b.3, b.4))
+        }) {
+            writer.write_all(line.as_bytes())?;
+            writer.write_all(b"\n")?;
+        }
+        writer.flush()?;
+        drop(writer);
+        build_bed_tabix_index(out_fp, csi)?;
+        if csi {
+            info!(
+                "wrote sorted bedMethyl to {out_fp:?} with CSI index, \
+                 positions are too large for a .tbi"
+            );
+        } else {
+            info!("wrote sorted bedMethyl to {out_fp:?} with tabix index");
+        }
+        Ok(())
+    }
+
+    /// Log the summary of the check, returns an error when the file is invalid
+    /// and `permissive` is false. Out-of-order records are only an error when
+    /// they haven't been fixed.
+    pub(crate) fn report(
+        &self,
+        sort_fixed: bool,
+        permissive: bool,
+    ) -> anyhow::Result<()> {
+        if self.num_records == 0 {
+            bail!("no bedMethyl records found");
+        }
+        let mut error_counts = self.error_counts.clone();
+        if self.num_unsorted > 0 {
+            if sort_fixed {
+                warn!(
+                    "{} records were out of order, output has been sorted",
+                    self.num_unsorted
+                );
+            } else {
+                error_counts.insert(
+                    BedMethylIssue::Unsorted.to_string(),
+                    self.num_unsorted,
+                );
+            }
+        }
+        if !self.unknown_mod_codes.is_empty() {
+            let codes = self.unknown_mod_codes.iter().sorted().join(",");
+            warn!(
+                "unable to check primary base for unknown mod codes: {codes}"
+            );
+        }
+        info!("checked {} records", self.num_records);
+        let total_errors = error_counts.values().sum::<usize>();
+        if total_errors == 0 {
+            info!("no errors");
+            return Ok(());
+        }
+        run_summary::record_errors(&error_counts);
+        info!("errors:\n{}", format_errors_table(&error_counts));
+        let msg = if self.num_invalid > 0 {
+            format!(
+                "input bedMethyl contains {} ({:.2}%) invalid records",
+                self.num_invalid,
+                (self.num_invalid as f32 / self.num_records as f32) * 100f32
+            )
+        } else {
+            format!(
+                "input bedMethyl has {} out of order records, sort it or use \
+                 --out-bed",
+                self.num_unsorted
+            )
+        };
+        if permissive {
+            warn!("{msg}");
+            Ok(())
+        } else {
+            bail!(msg)
+        }
+    }
+}
+
+#[cfg(test)]
+mod check_bedmethyl_tests {
+    use indicatif::ProgressBar;
+
+    use crate::bedmethyl_util::check::{
+        check_bedmethyl_line, BedMethylChecker, BedMethylIssue,
+    };
+
+    fn make_line(
+        chrom: &str,
+        start: u64,
+        code: char,
+        thick_start: u64,
+        pct: &str,
+        counts: [u64; 3],
+    ) -> String {
+        let [n_mod, n_canonical, n_other] = counts;
+        let cov = n_mod + n_canonical + n_other;
+        let stop = start + 1;
+        let fields = [
+            format!("{chrom}\t{start}\t{stop}\t{code}\t{cov}\t+"),
+            format!("{thick_start}\t{stop}\t255,0,0\t{cov}\t{pct}"),
+            format!("{n_mod}\t{n_canonical}\t{n_other}\t0\t0\t0\t0"),
+        ];
+        fields.join("\t")
+    }
+
+    #[test]
+    fn test_check_bedmethyl_line() {
+        let good = make_line("chr20", 10, 'm', 10, "40.00", [4, 5, 1]);
+        assert!(check_bedmethyl_line(&good).is_ok());
+        let mixed_delim = good
+            .split('\t')
+            .enumerate()
+            .map(
+                |(i, f)| if i < 9 { format!("{f}\t") } else { format!("{f} ") },
+            )
+            .collect::<String>();
+        assert!(check_bedmethyl_line(&mixed_delim).is_ok());
+        let too_few = good.rsplit_once('\t').unwrap().0;
+        assert_eq!(
+            check_bedmethyl_line(too_few),
+            Err(BedMethylIssue::ColumnCount)
+        );
+        let bad_interval = good.replacen("\t11\t", "\t10\t", 1);
+        assert_eq!(
+            check_bedmethyl_line(&bad_interval),
+            Err(BedMethylIssue::Interval)
+        );
+        let bad_thick = make_line("chr20", 10, 'm', 9, "40.00", [4, 5, 1]);
+        assert_eq!(
+            check_bedmethyl_line(&bad_thick),
+            Err(BedMethylIssue::ThickInterval)
+        );
+        let bad_counts = good.replacen("\t10\t40.00", "\t11\t40.00", 1);
+        assert_eq!(
+            check_bedmethyl_line(&bad_counts),
+            Err(BedMethylIssue::Counts)
+        );
+        let bad_pct = make_line("chr20", 10, 'm', 10, "50.00", [4, 5, 1]);
+        assert_eq!(
+            check_bedmethyl_line(&bad_pct),
+            Err(BedMethylIssue::PercentModified)
+        );
+    }
+
+    #[test]
+    fn test_check_bedmethyl_sorting_and_codes() {
+        let lines = [
+            make_line("chr1", 10, 'm', 10, "40.00", [4, 5, 1]),
+            make_line("chr1", 10, 'h', 10, "10.00", [1, 5, 4]),
+            // duplicate
+            make_line("chr1", 10, 'h', 10, "10.00", [1, 5, 4]),
+            // mixed primary base
+            make_line("chr1", 10, 'a', 10, "10.00", [1, 9, 0]),
+            // out of order
+            make_line("chr1", 5, 'm', 5, "40.00", [4, 5, 1]),
+            make_line("chr2", 5, 'm', 5, "40.00", [4, 5, 1]),
+            // out of order, chr1 is finished
+            make_line("chr1", 50, 'm', 50, "40.00", [4, 5, 1]),
+            make_line("chr2", 8, 'm', 8, "40.00", [4, 5, 1]),
+        ]
+        .join("\n");
+        let mut checker = BedMethylChecker::new(None, true).unwrap();
+        checker.check_stream(lines.as_bytes(), &ProgressBar::hidden()).unwrap();
+        assert_eq!(checker.num_records, 8);
+        assert_eq!(checker.num_invalid, 2);
+        assert_eq!(checker.num_unsorted, 2);
+        assert_eq!(checker.records.len(), 6);
+        assert_eq!(
+            checker.error_counts.get(&BedMethylIssue::Duplicate.to_string()),
+            Some(&1)
+        );
+        assert_eq!(
+            checker
+                .error_counts
+                .get(&BedMethylIssue::MixedPrimaryBase.to_string()),
+            Some(&1)
+        );
+        assert!(checker.report(true, false).is_err());
+        assert!(checker.report(true, true).is_ok());
+    }
+}
@@ -0,0 +1,309 @@
+use std::collections::HashMap;
+use std::io::{BufRead, Write};
+use std::path::PathBuf;
+
+use anyhow::bail;
+use indicatif::ProgressBar;
+use log::debug;
+use rustc_hash::{FxHashMap, FxHashSet};
+
+use crate::dmr::bedmethyl::BedMethylLine;
+use crate::mod_base_code::ModCodeRepr;
+use crate::parsing_utils::open_text_input;
+use crate::position_filter::StrandedPositionFilter;
+use crate::util::{Strand, StrandRule};
+
+/// Intervals from a BED file keyed by contig name, BED6+ strands are
+/// respected and BED3 intervals apply to both strands.
+pub(super) struct BedIntervals {
+    chrom_to_id: FxHashMap<String, u32>,
+    filter: StrandedPositionFilter<()>,
+}
+
+impl BedIntervals {
+    pub(super) fn from_path(
+        bed_fp: &PathBuf,
+        suppress_pb: bool,
+    ) -> anyhow::Result<Self> {
+        let mut chrom_to_id = FxHashMap::default();
+        for line in open_text_input(bed_fp)?.lines() {
+            let line = line?;
+            if let Some(chrom) = line.split_ascii_whitespace().next() {
+                let next_id = chrom_to_id.len() as u32;
+                chrom_to_id.entry(chrom.to_string()).or_insert(next_id);
+            }
+        }
+        let name_to_id = chrom_to_id
+            .iter()
+            .map(|(name, id)| (name.as_str(), *id))
+            .collect::<HashMap<&str, u32>>();
+        let filter = StrandedPositionFilter::from_bed_file(
+            bed_fp,
+            &name_to_id,
+            suppress_pb,
+        )?;
+        Ok(Self { chrom_to_id, filter })
+    }
+
+    fn overlaps(&self, record: &BedMethylLine) -> bool {
+        let Some(chrom_id) = self.chrom_to_id.get(&record.chrom) else {
+            return false;
+        };
+        match record.strand {
+            StrandRule::Positive => self.filter.contains(
+                *chrom_id as i32,
+                record.start(),
+                Strand::Positive,
+            ),
+            StrandRule::Negative => self.filter.contains(
+                *chrom_id as i32,
+                record.start(),
+                Strand::Negative,
+            ),
+            StrandRule::Both => self.filter.overlaps_not_stranded(
+                *chrom_id,
+                record.start(),
+                record.stop(),
+            ),
+        }
+    }
+}
+
+/// Criteria a bedMethyl record must meet to be kept, all unset criteria
+/// pass every record.
+#[derive(Default)]
+pub(super) struct BedMethylFilter {
+    pub(super) include: Option<BedIntervals>,
+    pub(super) exclude: Option<BedIntervals>,
+    pub(super) mod_codes: Option<FxHashSet<ModCodeRepr>>,
+    pub(super) strand: Option<StrandRule>,
+    pub(super) min_coverage: u64,
+    pub(super) max_coverage: Option<u64>,
+    pub(super) min_percent: Option<f32>,
+    pub(super) max_percent: Option<f32>,
+}
+
+/// Counts of the records seen by [`BedMethylFilter::filter_stream`].
+#[derive(Debug, Default, PartialEq, Eq)]
+pub(super) struct FilterCounts {
+    pub(super) n_records: usize,
+    pub(super) n_kept: usize,
+    pub(super) n_invalid: usize,
+    pub(super) sorted: bool,
+    pub(super) max_end: u64,
+}
+
+impl BedMethylFilter {
+    fn keep(&self, record: &BedMethylLine) -> bool {
+        if record.valid_coverage < self.min_coverage
+            || self.max_coverage.is_some_and(|max| record.valid_coverage > max)
+        {
+            return false;
+        }
+        if self
+            .mod_codes
+            .as_ref()
+            .is_some_and(|codes| !codes.contains(&record.raw_mod_code))
+        {
+            return false;
+        }
+        if self.strand.is_some_and(|strand| strand != record.strand) {
+            return false;
+        }
+        if self.min_percent.is_some() || self.max_percent.is_some() {
+            if record.valid_coverage == 0 {
+                return false;
+            }
+            let percent = record.frac_modified() * 100f32;
+            if self.min_percent.is_some_and(|min| percent < min)
+                || self.max_percent.is_some_and(|max| percent > max)
+            {
+                return false;
+            }
+        }
+        if self.include.as_ref().is_some_and(|ivs| !ivs.overlaps(record)) {
+            return false;
+        }
+        if self.exclude.as_ref().is_some_and(|ivs| ivs.overlaps(record)) {
+            return false;
+        }
+        true
+    }
+
+    /// Write the lines of `in_stream` that pass the filter to `writer`
+    /// unchanged, in a single pass. Header lines are passed through and
+    /// lines that aren't valid bedMethyl records are dropped.
+    pub(super) fn filter_stream<R: BufRead, W: Write>(
+        &self,
+        in_stream: R,
+        writer: &mut W,
+        pb: &ProgressBar,
+    ) -> anyhow::Result<FilterCounts> {
+        let mut counts = FilterCounts { sorted: true, ..Default::default() };
+        let mut finished_contigs = FxHashSet::default();
+        let mut prev: Option<(String, u64)> = None;
+        for line in in_stream.lines() {
+            let line = line?;
+            if line.starts_with('#') || line.starts_with("track") {
+                writer.write_all(line.as_bytes())?;
+                writer.write_all(b"\n")?;
+                continue;
+            }
+            counts.n_records += 1;
+            pb.inc(1);
+            let record = match BedMethylLine::parse(&line) {
+                Ok(record) => record,
+                Err(e) => {
+                    debug!("skipping invalid bedMethyl line, {e}");
+                    counts.n_invalid += 1;
+                    continue;
+                }
+            };
+            if counts.sorted {
+                counts.sorted = match prev.as_mut() {
+                    Some((chrom, start)) if chrom == &record.chrom => {
+                        let ordered = *start <= record.start();
+                        *start = record.start();
+                        ordered
+                    }
+                    Some((chrom, start)) => {
+                        let finished =
+                            std::mem::replace(chrom, record.chrom.to_owned());
+                        *start = record.start();
+                        finished_contigs.insert(finished);
+                        !finished_contigs.contains(&record.chrom)
+                    }
+                    None => {
+                        prev = Some((record.chrom.to_owned(), record.start()));
+                        true
+                    }
+                };
+            }
+            if self.keep(&record) {
+                counts.n_kept += 1;
+                counts.max_end = counts.max_end.max(record.stop());
+                writer.write_all(line.as_bytes())?;
+                writer.write_all(b"\n")?;
+            }
+        }
+        if counts.n_records == 0 {
+            bail!("no bedMethyl records found")
+        }
+        Ok(counts)
+    }
+}
+
+#[cfg(test)]
+mod bedmethyl_filter_tests {
+    use std::io::BufReader;
+
+    use rustc_hash::FxHashSet;
+
+    use crate::bedmethyl_util::filter::{BedMethylFilter, FilterCounts};
+    use crate::dmr::bedmethyl::BedMethylLine;
+    use crate::mod_base_code::ModCodeRepr;
+    use crate::position_filter::Iv;
+    use crate::tabix::ParseBedLine;
+    use crate::util::{get_ticker, StrandRule};
+
+    fn record(
+        chrom: &str,
+        start: u64,
+        code: char,
+        strand: StrandRule,
+        n_mod: u64,
+        n_valid: u64,
+    ) -> BedMethylLine {
+        BedMethylLine::new(
+            chrom.to_string(),
+            Iv { start, stop: start + 1, val: () },
+            ModCodeRepr::Code(code),
+            strand,
+            n_mod,
+            n_valid,
+            n_valid - n_mod,
+            0,
+            0,
+            0,
+            0,
+            0,
+        )
+    }
+
+    fn run_filter(
+        filter: &BedMethylFilter,
+        records: &[&BedMethylLine],
+    ) -> (Vec<String>, FilterCounts) {
+        let lines = records.iter().map(|r| r.to_line()).collect::<String>();
+        let mut out = Vec::new();
+        let counts = filter
+            .filter_stream(
+                BufReader::new(lines.as_bytes()),
+                &mut out,
+                &get_ticker(),
+            )
+            .unwrap();
+        let kept = String::from_utf8(out)
+            .unwrap()
+            .lines()
+            .map(|l| l.to_string())
+            .collect();
+        (kept, counts)
+    }
+
+    fn to_lines(records: &[&BedMethylLine]) -> Vec<String> {
+        records.iter().map(|r| r.to_line().trim_end().to_string()).collect()
+    }
+
+    #[test]
+    fn test_bedmethyl_filter() {
+        let records = [
+            record("chr1", 10, 'm', StrandRule::Positive, 1, 10),
+            record("chr1", 10, 'h', StrandRule::Positive, 5, 10),
+            record("chr1", 11, 'm', StrandRule::Negative, 9, 10),
+            record("chr1", 20, 'm', StrandRule::Positive, 2, 2),
+            record("chr2", 5, 'm', StrandRule::Both, 0, 30),
+        ];
+        let all = records.iter().collect::<Vec<_>>();
+        let (kept, counts) = run_filter(&BedMethylFilter::default(), &all);
+        assert_eq!(kept, to_lines(&all));
+        assert_eq!(
+            counts,
+            FilterCounts {
+                n_records: 5,
+                n_kept: 5,
+                n_invalid: 0,
+                sorted: true,
+                max_end: 21
+            }
+        );
+
+        let filter = BedMethylFilter {
+            mod_codes: Some(FxHashSet::from_iter([ModCodeRepr::Code('m')])),
+            min_coverage: 5,
+            ..Default::default()
+        };
+        let (kept, _) = run_filter(&filter, &all);
+        assert_eq!(kept, to_lines(&[&records[0], &records[2], &records[4]]));
+
+        let filter = BedMethylFilter {
+            strand: Some(StrandRule::Positive),
+            min_percent: Some(20f32),
+            max_percent: Some(50f32),
+            ..Default::default()
+        };
+        let (kept, _) = run_filter(&filter, &all);
+        assert_eq!(kept, to_lines(&[&records[1]]));
+
+        let (_, counts) = run_filter(
+            &BedMethylFilter::default(),
+            &[&records[4], &records[0], &records[3]],
+        );
+        assert!(counts.sorted);
+        let (_, counts) = run_filter(
+            &BedMethylFilter::default(),
+            &[&records[3], &records[4], &records[0]],
+        );
+        assert!(!counts.sorted);
+    }
+}
@@ -0,0 +1,601 @@
+use std::collections::hash_map::Entry;
+use std::fmt::{Display, Formatter};
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, bail, Context};
+use indicatif::ProgressBar;
+use itertools::Itertools;
+use log::debug;
+use rustc_hash::FxHashMap;
+
+use crate::dmr::bedmethyl::BedMethylLine;
+use crate::fasta::ReferenceSequences;
+use crate::tabix::ParseBedLine;
+use crate::util::StrandRule;
+
+/// An ungapped aligned block from a chain, coordinates on the query are in
+/// the orientation of the chain's query strand.
+#[derive(Debug, PartialEq, Eq)]
+struct ChainBlock {
+    source_start: u64,
+    source_end: u64,
+    target_contig: usize,
+    target_start: u64,
+    target_size: u64,
+    reversed: bool,
+}
+
+impl ChainBlock {
+    fn lift(&self, pos: u64) -> u64 {
+        let target_pos = self.target_start + (pos - self.source_start);
+        if self.reversed {
+            self.target_size - 1 - target_pos
+        } else {
+            target_pos
+        }
+    }
+}
+
+/// Mapping between assemblies from a UCSC chain file. The "target" fields
+/// of the chain are the source assembly and the "query" fields are the
+/// assembly to lift positions to.
+#[derive(Debug)]
+pub(super) struct ChainMap {
+    blocks: FxHashMap<String, Vec<ChainBlock>>,
+    target_contigs: Vec<String>,
+}
+
+/// A lifted position.
+#[derive(Debug, PartialEq, Eq)]
+pub(super) struct LiftedPosition<'a> {
+    pub(super) contig: &'a str,
+    pub(super) position: u64,
+    pub(super) reversed: bool,
+}
+
+/// Fields of a chain header line, the source and target positions are
+/// advanced as the alignment data lines are read.
+struct ChainHeader {
+    source: String,
+    source_pos: u64,
+    target_id: usize,
+    target_pos: u64,
+    target_size: u64,
+    reversed: bool,
+}
+
+impl ChainMap {
+    fn parse_header(
+        line: &str,
+        target_ids: &mut FxHashMap<String, usize>,
+        target_contigs: &mut Vec<String>,
+    ) -> anyhow::Result<ChainHeader> {
+        let fields = line.split_ascii_whitespace().collect::<Vec<&str>>();
+        if fields.len() < 12 || fields[0] != "chain" {
+            bail!("invalid chain header, {line}")
+        }
+        let parse = |i: usize| -> anyhow::Result<u64> {
+            fields[i].parse::<u64>().map_err(|e| {
+                anyhow!("invalid chain header field {}, {e}", fields[i])
+            })
+        };
+        if fields[4] != "+" {
+            bail!("only '+' reference strand chains are supported, {line}")
+        }
+        let reversed = match fields[9] {
+            "+" => false,
+            "-" => true,
+            s => bail!("invalid query strand {s}"),
+        };
+        let next_id = target_contigs.len();
+        let target_id =
+            *target_ids.entry(fields[7].to_string()).or_insert_with(|| {
+                target_contigs.push(fields[7].to_string());
+                next_id
+            });
+        Ok(ChainHeader {
+            source: fields[2].to_string(),
+            source_pos: parse(5)?,
+            target_id,
+            target_pos: parse(10)?,
+            target_size: parse(8)?,
+            reversed,
+        })
+    }
+
+    pub(super) fn from_stream<R: BufRead>(reader: R) -> anyhow::Result<Self> {
+        let mut blocks = FxHashMap::<String, Vec<ChainBlock>>::default();
+        let mut target_contigs = Vec::new();
+        let mut target_ids = FxHashMap::<String, usize>::default();
+        let mut curr: Option<ChainHeader> = None;
+        for (i, line) in reader.lines().enumerate() {
+            let line = line?;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            if line.starts_with("chain") {
+                if curr.is_some() {
+                    bail!("chain ending before line {} is incomplete", i + 1)
+                }
+                curr = Some(Self::parse_header(
+                    line,
+                    &mut target_ids,
+                    &mut target_contigs,
+                )?);
+                continue;
+            }
+            let Some(chain) = curr.as_mut() else {
+                bail!("alignment data before chain header at line {}", i + 1)
+            };
+            let fields = line
+                .split_ascii_whitespace()
+                .map(|x| x.parse::<u64>())
+                .collect::<Result<Vec<u64>, _>>()
+                .with_context(|| format!("invalid chain line {}", i + 1))?;
+            let (block_size, dt, dq) = match fields.as_slice() {
+                [block_size, dt, dq] => (*block_size, *dt, *dq),
+                [block_size] => (*block_size, 0, 0),
+                _ => bail!("invalid chain line {}, {line}", i + 1),
+            };
+            if block_size > 0 {
+                blocks.entry(chain.source.clone()).or_default().push(
+                    ChainBlock {
+                        source_start: chain.source_pos,
+                        source_end: chain.source_pos + block_size,
+                        target_contig: chain.target_id,
+                        target_start: chain.target_pos,
+                        target_size: chain.target_size,
+                        reversed: chain.reversed,
+                    },
+                );
+            }
+            chain.source_pos += block_size + dt;
+            chain.target_pos += block_size + dq;
+            if fields.len() == 1 {
+                curr = None;
+            }
+        }
+        if curr.is_some() {
+            bail!("last chain is incomplete")
+        }
+        if blocks.is_empty() {
+            bail!("no alignment blocks found in chain file")
+        }
+        for contig_blocks in blocks.values_mut() {
+            contig_blocks.sort_by_key(|b| b.source_start);
+        }
+        Ok(Self { blocks, target_contigs })
+    }
+
+    pub(super) fn lift(
+        &self,
+        contig: &str,
+        pos: u64,
+    ) -> Option<LiftedPosition<'_>> {
+        let blocks = self.blocks.get(contig)?;
+        let idx = blocks.partition_point(|b| b.source_start <= pos);
+        let block = blocks[..idx].last()?;
+        if pos >= block.source_end {
+            return None;
+        }
+        Some(LiftedPosition {
+            contig: &self.target_contigs[block.target_contig],
+            position: block.lift(pos),
+            reversed: block.reversed,
+        })
+    }
+}
+
+/// Reason a record is not in the lifted output.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum UnliftedReason {
+    Unmapped,
+    ContextChanged,
+    MissingReference,
+}
+
+impl Display for UnliftedReason {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Unmapped => write!(f, "unmapped"),
+            Self::ContextChanged => write!(f, "context_changed"),
+            Self::MissingReference => write!(f, "missing_reference"),
+        }
+    }
+}
+
+/// Source and target references used to check that the sequence around a
+/// position is the same after lifting.
+pub(super) struct ContextCheck {
+    source: ReferenceSequences,
+    target: ReferenceSequences,
+    /// Target sequences lifted to from `cache_source`, the records on one
+    /// source contig usually lift to a few target contigs, interleaved.
+    target_cache: FxHashMap<String, Option<String>>,
+    cache_source: String,
+    flank: usize,
+}
+
+impl ContextCheck {
+    pub(super) fn new(
+        source_fp: &Path,
+        target_fp: &Path,
+        flank: usize,
+    ) -> anyhow::Result<Self> {
+        Ok(Self {
+            source: ReferenceSequences::from_path(source_fp)?,
+            target: ReferenceSequences::from_path(target_fp)?,
+            target_cache: FxHashMap::default(),
+            cache_source: String::new(),
+            flank,
+        })
+    }
+
+    fn kmer(
+        seq: &str,
+        pos: u64,
+        flank: usize,
+        reverse: bool,
+    ) -> Option<Vec<u8>> {
+        let pos = pos as usize;
+        let kmer =
+            seq.as_bytes().get(pos.checked_sub(flank)?..pos + flank + 1)?;
+        if reverse {
+            Some(bio::alphabets::dna::revcomp(kmer))
+        } else {
+            Some(kmer.to_vec())
+        }
+    }
+
+    fn check(
+        &mut self,
+        record: &BedMethylLine,
+        source_pos: u64,
+        lifted: &LiftedPosition,
+    ) -> anyhow::Result<Result<(), UnliftedReason>> {
+        let source_reverse = record.strand == StrandRule::Negative;
+        let Some(source_kmer) =
+            self.source.get_sequence(&record.chrom)?.and_then(|seq| {
+                Self::kmer(seq, source_pos, self.flank, source_reverse)
+            })
+        else {
+            return Ok(Err(UnliftedReason::MissingReference));
+        };
+        if self.cache_source != record.chrom {
+            self.target_cache.clear();
+            self.cache_source = record.chrom.clone();
+        }
+        let target_seq = match self.target_cache.entry(lifted.contig.to_owned())
+        {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => {
+                entry.insert(self.target.load_sequence(lifted.contig)?)
+            }
+        };
+        let Some(target_kmer) = target_seq.as_ref().and_then(|seq| {
+            Self::kmer(
+                seq,
+                lifted.position,
+                self.flank,
+                source_reverse ^ lifted.reversed,
+            )
+        }) else {
+            return Ok(Err(UnliftedReason::MissingReference));
+        };
+        if source_kmer == target_kmer {
+            Ok(Ok(()))
+        } else {
+            Ok(Err(UnliftedReason::ContextChanged))
+        }
+    }
+}
+
+/// Counts of the records seen by [`lift_bedmethyl`].
+#[derive(Debug, Default)]
+pub(super) struct LiftoverCounts {
+    pub(super) n_records: usize,
+    pub(super) n_lifted: usize,
+    pub(super) unlifted: FxHashMap<String, usize>,
+}
+
+/// Maximum number of spill files kept open at once, the records on one
+/// source contig usually lift to a few target contigs.
+const MAX_OPEN_SPILL_FILES: usize = 64;
+
+/// Lifted records written to a temporary file per target contig, so that
+/// only the records for one contig are held in memory when sorting them.
+pub(super) struct LiftedRecordSpill {
+    dir: PathBuf,
+    contig_ids: FxHashMap<String, usize>,
+    writers: FxHashMap<usize, BufWriter<File>>,
+}
+
+impl LiftedRecordSpill {
+    /// Spill files are written to a new directory in `parent_dir`, which is
+    /// removed when the spill is dropped.
+    pub(super) fn new(parent_dir: &Path) -> anyhow::Result<Self> {
+        let dir = parent_dir
+            .join(format!("modkit_liftover_{}.tmp", std::process::id()));
+        std::fs::create_dir(&dir).with_context(|| {
+            format!("failed to make temporary directory {dir:?}")
+        })?;
+        Ok(Self {
+            dir,
+            contig_ids: FxHashMap::default(),
+            writers: FxHashMap::default(),
+        })
+    }
+
+    fn spill_fp(&self, contig_id: usize) -> PathBuf {
+        self.dir.join(format!("{contig_id}.bed"))
+    }
+
+    fn close_writers(&mut self) -> anyhow::Result<()> {
+        for (_, mut writer) in self.writers.drain() {
+            writer.flush()?;
+        }
+        Ok(())
+    }
+
+    fn push(&mut self, record: &BedMethylLine) -> anyhow::Result<()> {
+        let next_id = self.contig_ids.len();
+        let contig_id =
+            *self.contig_ids.entry(record.chrom.clone()).or_insert(next_id);
+        if !self.writers.contains_key(&contig_id)
+            && self.writers.len() >= MAX_OPEN_SPILL_FILES
+        {
+            self.close_writers()?;
+        }
+        let spill_fp = self.spill_fp(contig_id);
+        let writer = match self.writers.entry(contig_id) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => entry.insert(BufWriter::new(
+                OpenOptions::new().create(true).append(true).open(spill_fp)?,
+            )),
+        };
+        writer.write_all(record.to_line().as_bytes())?;
+        Ok(())
+    }
+
+    /// Write the records one contig at a time, sorted by position, returns
+    /// the largest end position written.
+    pub(super) fn write_sorted<W: Write>(
+        mut self,
+        writer: &mut W,
+    ) -> anyhow::Result<u64> {
+        self.close_writers()?;
+        let mut max_stop = 0u64;
+        for (contig, contig_id) in self.contig_ids.iter().sorted() {
+            let spill_fp = self.spill_fp(*contig_id);
+            let records = BufReader::new(File::open(&spill_fp)?)
+                .lines()
+                .map(|line| Ok(BedMethylLine::parse(&line?)?))
+                .collect::<anyhow::Result<Vec<BedMethylLine>>>()
+                .with_context(|| {
+                    format!("failed to read lifted records for {contig}")
+                })?;
+            for record in records.into_iter().sorted_by(|a, b| {
+                (a.start(), a.strand, a.raw_mod_code).cmp(&(
+                    b.start(),
+                    b.strand,
+                    b.raw_mod_code,
+                ))
+            }) {
+                max_stop = max_stop.max(record.stop());
+                writer.write_all(record.to_line().as_bytes())?;
+            }
+            std::fs::remove_file(&spill_fp)?;
+        }
+        Ok(max_stop)
+    }
+}
+
+impl Drop for LiftedRecordSpill {
+    fn drop(&mut self) {
+        self.writers.clear();
+        if let Err(e) = std::fs::remove_dir_all(&self.dir) {
+            debug!("failed to remove {:?}, {e}", self.dir);
+        }
+    }
+}
+
+/// Lift the records in `in_stream` into `spill`. Records that could not be
+/// lifted, or whose reference context changed, are written to
+/// `unlifted_writer` with the reason appended as an extra column.
+pub(super) fn lift_bedmethyl<R: BufRead>(
+    in_stream: R,
+    chain_map: &ChainMap,
+    mut context_check: Option<&mut ContextCheck>,
+    mut unlifted_writer: Option<&mut dyn Write>,
+    spill: &mut LiftedRecordSpill,
+    pb: &ProgressBar,
+) -> anyhow::Result<LiftoverCounts> {
+    let mut counts = LiftoverCounts::default();
+    for line in in_stream.lines() {
+        let line = line?;
+        if line.starts_with('#') || line.starts_with("track") {
+            continue;
+        }
+        let mut record = BedMethylLine::parse(&line)?;
+        counts.n_records += 1;
+        pb.inc(1);
+        let lifted =
+            chain_map.lift(&record.chrom, record.start()).map(|lifted| {
+                // combined-strand records are at the C of a CpG, on a
+                // reversed chain the C lifts to the G of the CpG so use the
+                // G instead
+                if lifted.reversed && record.strand == StrandRule::Both {
+                    let source_pos = record.start() + 1;
+                    (source_pos, chain_map.lift(&record.chrom, source_pos))
+                } else {
+                    (record.start(), Some(lifted))
+                }
+            });
+        let lifted = match lifted {
+            Some((source_pos, Some(lifted))) => match context_check.as_mut() {
+                Some(check) => {
+                    check.check(&record, source_pos, &lifted)?.map(|_| lifted)
+                }
+                None => Ok(lifted),
+            },
+            _ => Err(UnliftedReason::Unmapped),
+        };
+        match lifted {
+            Ok(lifted) => {
+                record.chrom = lifted.contig.to_string();
+                record.interval.start = lifted.position;
+                record.interval.stop = lifted.position + 1;
+                if lifted.reversed {
+                    record.strand = match record.strand {
+                        StrandRule::Positive => StrandRule::Negative,
+                        StrandRule::Negative => StrandRule::Positive,
+                        StrandRule::Both => StrandRule::Both,
+                    };
+                }
+                counts.n_lifted += 1;
+                spill.push(&record)?;
+            }
+            Err(reason) => {
+                debug!("did not lift {line}, {reason}");
+                *counts.unlifted.entry(reason.to_string()).or_insert(0) += 1;
+                if let Some(writer) = unlifted_writer.as_mut() {
+                    writeln!(writer, "{line}\t{reason}")?;
+                }
+            }
+        }
+    }
+    Ok(counts)
+}
+
+#[cfg(test)]
+mod liftover_tests {
+    use std::io::BufReader;
+
+    use indicatif::ProgressBar;
+
+    use crate::bedmethyl_util::liftover::{
+        lift_bedmethyl, ChainMap, LiftedPosition, LiftedRecordSpill,
+    };
+
+    #[test]
+    fn test_chain_map_lift() {
+        // chr1:100-130 aligns to chrA:0-30 with a 5 base gap in chr1 and a 2
+        // base gap in chrA, chr2 aligns to the reverse strand of chrB
+        let chain = "\
+chain 1000 chr1 500 + 100 135 chrA 200 + 0 32 1
+10 5 2
+20
+
+chain 500 chr2 50 + 0 10 chrB 20 - 5 15 2
+10
+";
+        let chain_map =
+            ChainMap::from_stream(BufReader::new(chain.as_bytes())).unwrap();
+        let lifted = |contig: &str, pos: u64| chain_map.lift(contig, pos);
+        assert_eq!(
+            lifted("chr1", 100),
+            Some(LiftedPosition {
+                contig: "chrA",
+                position: 0,
+                reversed: false
+            })
+        );
+        assert_eq!(lifted("chr1", 109).unwrap().position, 9);
+        // in the gap
+        assert_eq!(lifted("chr1", 110), None);
+        assert_eq!(lifted("chr1", 114), None);
+        assert_eq!(lifted("chr1", 115).unwrap().position, 12);
+        assert_eq!(lifted("chr1", 134).unwrap().position, 31);
+        assert_eq!(lifted("chr1", 135), None);
+        assert_eq!(lifted("chr1", 99), None);
+        assert_eq!(lifted("chr3", 0), None);
+        // reverse strand, chrB positions 5..15 on the reverse strand are
+        // forward positions 14..=5
+        assert_eq!(
+            lifted("chr2", 0),
+            Some(LiftedPosition {
+                contig: "chrB",
+                position: 14,
+                reversed: true
+            })
+        );
+        assert_eq!(lifted("chr2", 9).unwrap().position, 5);
+        assert_eq!(lifted("chr2", 10), None);
+
+        assert!(ChainMap::from_stream(BufReader::new("10 2 3\n".as_bytes()))
+            .is_err());
+        assert!(ChainMap::from_stream(BufReader::new(
+            "chain 1 chr1 10 + 0 5 chrA 10 + 0 5 1\n5 0 0\n".as_bytes()
+        ))
+        .is_err());
+    }
+
+    #[test]
+    fn test_lift_bedmethyl_combined_strands() {
+        // chr1 aligns to the reverse strand of chrB and chr2 to chrA
+        let chain = "\
+chain 500 chr1 50 + 0 20 chrB 20 - 0 20 1
+20
+
+chain 500 chr2 50 + 0 20 chrA 20 + 0 20 2
+20
+";
+        let chain_map =
+            ChainMap::from_stream(BufReader::new(chain.as_bytes())).unwrap();
+        let record = |chrom: &str, pos: u64, strand: char| {
+            format!(
+                "{chrom}\t{pos}\t{}\tm\t4\t{strand}\t{pos}\t{}\t255,0,0\t4 \
+                 50.00 2 2 0 0 0 0 0\n",
+                pos + 1,
+                pos + 1
+            )
+        };
+        let in_stream = [
+            record("chr1", 3, '.'),
+            record("chr1", 3, '+'),
+            record("chr2", 3, '.'),
+            // the G of the CpG is not aligned
+            record("chr1", 19, '.'),
+        ]
+        .concat();
+        let spill_dir =
+            std::env::temp_dir().join("test_lift_bedmethyl_combined_strands");
+        std::fs::create_dir_all(&spill_dir).unwrap();
+        let mut spill = LiftedRecordSpill::new(&spill_dir).unwrap();
+        let counts = lift_bedmethyl(
+            BufReader::new(in_stream.as_bytes()),
+            &chain_map,
+            None,
+            None,
+            &mut spill,
+            &ProgressBar::hidden(),
+        )
+        .unwrap();
+        assert_eq!(counts.n_records, 4);
+        assert_eq!(counts.n_lifted, 3);
+        let mut out = Vec::new();
+        spill.write_sorted(&mut out).unwrap();
+        let lifted = String::from_utf8(out)
+            .unwrap()
+            .lines()
+            .map(|l| {
+                let fields = l.split('\t').collect::<Vec<&str>>();
+                (
+                    fields[0].to_string(),
+                    fields[1].to_string(),
+                    fields[5].to_string(),
+                )
+            })
+            .collect::<Vec<(String, String, String)>>();
+        let expected =
+            [("chrA", "3", "."), ("chrB", "15", "."), ("chrB", "16", "-")]
+                .map(|(c, p, s)| (c.to_string(), p.to_string(), s.to_string()));
+        // the C of the CpG on the positive strand of chrB is one before the
+        // position the C on chr1 lifts to
+        assert_eq!(lifted, expected);
+        assert!(std::fs::read_dir(&spill_dir).unwrap().next().is_none());
+    }
+}
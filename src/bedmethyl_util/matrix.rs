@@ -0,0 +1,422 @@
+//! Sites-by-samples (or windows-by-samples) matrices of the fraction modified
+//! across bedMethyl files, written as a Parquet table with a column per
+//! sample so that they can be loaded directly for clustering or PCA.
+
+use std::collections::BTreeMap;
+use std::io::Write;
+
+use anyhow::bail;
+use indicatif::ProgressBar;
+use log::debug;
+use rustc_hash::{FxHashMap, FxHashSet};
+
+use crate::dmr::bedmethyl::BedMethylLine;
+use crate::errs::{MkError, MkResult};
+use crate::mod_base_code::ModCodeRepr;
+use crate::sqlite::ColumnType;
+use crate::util::{StrandRule, MISSING_SYMBOL, TAB};
+
+#[derive(Debug, Copy, Clone, Hash, Eq, PartialEq, Ord, PartialOrd)]
+struct RowKey {
+    start: u64,
+    end: u64,
+    strand: StrandRule,
+}
+
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+struct Cell {
+    n_mod: u64,
+    n_valid: u64,
+}
+
+#[derive(Default, Debug)]
+pub(super) struct SampleCounts {
+    pub(super) n_records: usize,
+    pub(super) n_invalid: usize,
+    pub(super) n_used: usize,
+}
+
+/// Columns of the matrix table, the row labels then the fraction modified,
+/// `n_mod`, and `n_valid` for each sample.
+pub(super) fn matrix_columns(
+    sample_names: &[String],
+) -> Vec<(String, ColumnType)> {
+    let labels = [
+        ("chrom", ColumnType::Text),
+        ("start", ColumnType::Integer),
+        ("end", ColumnType::Integer),
+        ("strand", ColumnType::Text),
+    ]
+    .into_iter()
+    .map(|(name, typ)| (name.to_string(), typ));
+    let per_sample = [
+        ("fraction_modified", ColumnType::Real),
+        ("n_mod", ColumnType::Integer),
+        ("n_valid", ColumnType::Integer),
+    ]
+    .into_iter()
+    .flat_map(|(prefix, typ)| {
+        sample_names.iter().map(move |name| (format!("{prefix}_{name}"), typ))
+    });
+    labels.chain(per_sample).collect()
+}
+
+/// Writes the rows of the matrix as tab-separated lines (see
+/// [`matrix_columns`]), one contig at a time. The records of the samples on
+/// a contig are merged as they're read, so only the records of the current
+/// site (or window) are in memory. Counts for multiple modification codes at
+/// the same site are combined.
+pub(super) struct MatrixWriter<W: Write> {
+    mod_codes: FxHashSet<ModCodeRepr>,
+    window_size: Option<u64>,
+    min_coverage: u64,
+    min_samples: usize,
+    writer: W,
+    n_rows: usize,
+}
+
+impl<W: Write> MatrixWriter<W> {
+    /// Cells (sites or windows of a sample) with less than `min_coverage`
+    /// valid coverage are missing and rows with fewer than `min_samples`
+    /// non-missing cells are skipped.
+    pub(super) fn new(
+        mod_codes: FxHashSet<ModCodeRepr>,
+        window_size: Option<u64>,
+        min_coverage: u64,
+        min_samples: usize,
+        writer: W,
+    ) -> Self {
+        Self {
+            mod_codes,
+            window_size,
+            min_coverage: min_coverage.max(1),
+            min_samples,
+            writer,
+            n_rows: 0,
+        }
+    }
+
+    pub(super) fn n_rows(&self) -> usize {
+        self.n_rows
+    }
+
+    pub(super) fn into_writer(self) -> W {
+        self.writer
+    }
+
+    /// Sites are binned by their start, windows by the window they start in.
+    fn bin(&self, record: &BedMethylLine) -> u64 {
+        match self.window_size {
+            Some(window_size) => record.start() / window_size,
+            None => record.start(),
+        }
+    }
+
+    /// The next record with one of the requested codes, invalid records are
+    /// counted and skipped.
+    fn next_used(
+        &self,
+        records: &mut impl Iterator<Item = MkResult<BedMethylLine>>,
+        counts: &mut SampleCounts,
+        pb: &ProgressBar,
+    ) -> anyhow::Result<Option<BedMethylLine>> {
+        for record in records {
+            counts.n_records += 1;
+            pb.inc(1);
+            match record {
+                Ok(record) if self.mod_codes.contains(&record.raw_mod_code) => {
+                    counts.n_used += 1;
+                    return Ok(Some(record));
+                }
+                Ok(_) => {}
+                Err(MkError::InvalidBedMethyl(reason)) => {
+                    debug!("skipping invalid bedMethyl record, {reason}");
+                    counts.n_invalid += 1;
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Ok(None)
+    }
+
+    /// Write the rows for a contig from the records of each sample on it,
+    /// the records of each sample must be sorted by start.
+    pub(super) fn write_contig<I>(
+        &mut self,
+        chrom: &str,
+        samples: Vec<I>,
+        counts: &mut [SampleCounts],
+        pb: &ProgressBar,
+    ) -> anyhow::Result<()>
+    where
+        I: Iterator<Item = MkResult<BedMethylLine>>,
+    {
+        let n_samples = samples.len();
+        let mut samples = samples
+            .into_iter()
+            .zip(counts.iter_mut())
+            .map(|(mut records, counts)| {
+                self.next_used(&mut records, counts, pb)
+                    .map(|head| (records, counts, head))
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        while let Some(bin) = samples
+            .iter()
+            .filter_map(|(_, _, head)| head.as_ref().map(|r| self.bin(r)))
+            .min()
+        {
+            let mut rows = BTreeMap::<RowKey, Vec<Cell>>::new();
+            for (sample_idx, (records, counts, head)) in
+                samples.iter_mut().enumerate()
+            {
+                // sites are collected first so that codes sharing a primary
+                // base (e.g. h and m) don't count the valid coverage twice
+                let mut sites = FxHashMap::<RowKey, Cell>::default();
+                while let Some(record) = head.take_if(|r| self.bin(r) == bin) {
+                    let key = RowKey {
+                        start: record.start(),
+                        end: record.stop(),
+                        strand: record.strand,
+                    };
+                    let cell = sites.entry(key).or_default();
+                    cell.n_mod += record.count_methylated;
+                    cell.n_valid = cell.n_valid.max(record.valid_coverage);
+                    *head = self.next_used(records, counts, pb)?;
+                    if head.as_ref().is_some_and(|next| self.bin(next) < bin) {
+                        bail!(
+                            "records on {chrom} are not sorted, {} is after {}",
+                            head.as_ref().unwrap().start(),
+                            record.start()
+                        )
+                    }
+                }
+                for (site, cell) in sites {
+                    let key = match self.window_size {
+                        Some(window_size) => RowKey {
+                            start: bin * window_size,
+                            end: (bin + 1) * window_size,
+                            strand: StrandRule::Both,
+                        },
+                        None => site,
+                    };
+                    let row = rows
+                        .entry(key)
+                        .or_insert_with(|| vec![Cell::default(); n_samples]);
+                    row[sample_idx].n_mod += cell.n_mod;
+                    row[sample_idx].n_valid += cell.n_valid;
+                }
+            }
+            for (key, cells) in rows {
+                self.write_row(chrom, key, &cells)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn write_row(
+        &mut self,
+        chrom: &str,
+        key: RowKey,
+        cells: &[Cell],
+    ) -> anyhow::Result<()> {
+        let is_present = |cell: &Cell| cell.n_valid >= self.min_coverage;
+        if cells.iter().filter(|c| is_present(c)).count() < self.min_samples {
+            return Ok(());
+        }
+        let fractions = cells.iter().map(|cell| {
+            if is_present(cell) {
+                (cell.n_mod as f64 / cell.n_valid as f64).to_string()
+            } else {
+                MISSING_SYMBOL.to_string()
+            }
+        });
+        let n_mods = cells.iter().map(|cell| cell.n_mod.to_string());
+        let n_valids = cells.iter().map(|cell| cell.n_valid.to_string());
+        let fields = [
+            chrom.to_string(),
+            key.start.to_string(),
+            key.end.to_string(),
+            key.strand.to_string(),
+        ]
+        .into_iter()
+        .chain(fractions)
+        .chain(n_mods)
+        .chain(n_valids)
+        .collect::<Vec<String>>();
+        writeln!(self.writer, "{}", fields.join(&TAB.to_string()))?;
+        self.n_rows += 1;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod bedmethyl_matrix_tests {
+    use rustc_hash::FxHashSet;
+
+    use crate::bedmethyl_util::matrix::{
+        matrix_columns, MatrixWriter, SampleCounts,
+    };
+    use crate::dmr::bedmethyl::BedMethylLine;
+    use crate::errs::MkResult;
+    use crate::mod_base_code::ModCodeRepr;
+    use crate::util::get_ticker;
+
+    fn bedmethyl_line(
+        chrom: &str,
+        start: u64,
+        code: char,
+        strand: char,
+        n_mod: u64,
+        n_valid: u64,
+    ) -> String {
+        format!(
+            "{chrom}\t{start}\t{}\t{code}\t{n_valid}\t{strand}\t{start}\t{}\t\
+             255,0,0\t{n_valid}\t0.00\t{n_mod}\t{}\t0\t0\t0\t0\t0",
+            start + 1,
+            start + 1,
+            n_valid - n_mod
+        )
+    }
+
+    fn records(
+        lines: &[String],
+    ) -> std::vec::IntoIter<MkResult<BedMethylLine>> {
+        lines
+            .iter()
+            .map(|l| BedMethylLine::parse(l))
+            .collect::<Vec<_>>()
+            .into_iter()
+    }
+
+    fn matrix_writer(
+        codes: &[char],
+        window_size: Option<u64>,
+        min_coverage: u64,
+        min_samples: usize,
+    ) -> MatrixWriter<Vec<u8>> {
+        let codes = codes
+            .iter()
+            .map(|c| ModCodeRepr::Code(*c))
+            .collect::<FxHashSet<ModCodeRepr>>();
+        MatrixWriter::new(
+            codes,
+            window_size,
+            min_coverage,
+            min_samples,
+            Vec::new(),
+        )
+    }
+
+    fn rows(writer: MatrixWriter<Vec<u8>>) -> Vec<String> {
+        String::from_utf8(writer.into_writer())
+            .unwrap()
+            .lines()
+            .map(|l| l.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn test_matrix_sites_and_missing() {
+        let mut writer = matrix_writer(&['m'], None, 1, 1);
+        let pb = get_ticker();
+        let mut counts = vec![SampleCounts::default(), SampleCounts::default()];
+        let a = [bedmethyl_line("chr2", 10, 'm', '+', 5, 10)];
+        writer
+            .write_contig(
+                "chr2",
+                vec![records(&a), records(&[])],
+                &mut counts,
+                &pb,
+            )
+            .unwrap();
+        let a = [
+            bedmethyl_line("chr1", 10, 'm', '+', 1, 4),
+            bedmethyl_line("chr1", 10, 'h', '+', 1, 4),
+        ];
+        let b = [
+            bedmethyl_line("chr1", 10, 'm', '+', 4, 4),
+            bedmethyl_line("chr1", 20, 'm', '-', 1, 2),
+            "chr1\tnot_a_record".to_string(),
+        ];
+        writer
+            .write_contig(
+                "chr1",
+                vec![records(&a), records(&b)],
+                &mut counts,
+                &pb,
+            )
+            .unwrap();
+        assert_eq!((counts[0].n_records, counts[0].n_used), (3, 2));
+        assert_eq!((counts[1].n_records, counts[1].n_invalid), (3, 1));
+        assert_eq!(writer.n_rows(), 3);
+        // contigs are in the order they're written, missing fractions are
+        // "." (null in the table)
+        assert_eq!(
+            rows(writer),
+            vec![
+                "chr2\t10\t11\t+\t0.5\t.\t5\t0\t10\t0",
+                "chr1\t10\t11\t+\t0.25\t1\t1\t4\t4\t4",
+                "chr1\t20\t21\t-\t.\t0.5\t0\t1\t0\t2",
+            ]
+        );
+
+        let mut writer = matrix_writer(&['m'], None, 1, 1);
+        let unsorted = [
+            bedmethyl_line("chr1", 20, 'm', '+', 1, 4),
+            bedmethyl_line("chr1", 10, 'm', '+', 1, 4),
+        ];
+        let mut counts = vec![SampleCounts::default()];
+        assert!(writer
+            .write_contig("chr1", vec![records(&unsorted)], &mut counts, &pb)
+            .is_err());
+    }
+
+    #[test]
+    fn test_matrix_windows_combined_codes() {
+        let a = [
+            bedmethyl_line("chr1", 10, 'h', '+', 1, 10),
+            bedmethyl_line("chr1", 10, 'm', '+', 4, 10),
+            bedmethyl_line("chr1", 11, 'm', '-', 5, 10),
+            bedmethyl_line("chr1", 120, 'm', '+', 1, 2),
+        ];
+        let b = [bedmethyl_line("chr1", 99, 'm', '+', 0, 3)];
+        // the second window only has one sample with coverage >= 2
+        let mut writer = matrix_writer(&['h', 'm'], Some(100), 2, 2);
+        let pb = get_ticker();
+        let mut counts = vec![SampleCounts::default(), SampleCounts::default()];
+        writer
+            .write_contig(
+                "chr1",
+                vec![records(&a), records(&b)],
+                &mut counts,
+                &pb,
+            )
+            .unwrap();
+        // h and m are combined at the same site without double counting the
+        // valid coverage
+        assert_eq!(rows(writer), vec!["chr1\t0\t100\t.\t0.5\t0\t10\t0\t20\t3"]);
+    }
+
+    #[test]
+    fn test_matrix_columns() {
+        let columns = matrix_columns(&["a".to_string(), "b".to_string()])
+            .into_iter()
+            .map(|(name, _)| name)
+            .collect::<Vec<String>>();
+        assert_eq!(
+            columns,
+            vec![
+                "chrom",
+                "start",
+                "end",
+                "strand",
+                "fraction_modified_a",
+                "fraction_modified_b",
+                "n_mod_a",
+                "n_mod_b",
+                "n_valid_a",
+                "n_valid_b",
+            ]
+        );
+    }
+}
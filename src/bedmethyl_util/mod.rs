@@ -13,6 +13,10 @@ use crate::{
     dmr::bedmethyl::BedMethylLine, mod_base_code::ModCodeRepr, util::StrandRule,
 };
 
+mod check;
+mod filter;
+mod liftover;
+mod matrix;
 pub mod subcommands;
 struct BedMethylStream<R: BufRead> {
     in_stream: R,
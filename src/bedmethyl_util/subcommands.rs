@@ -12,23 +12,36 @@ use log::{debug, error, info};
 use rayon::prelude::*;
 use rustc_hash::{FxHashMap, FxHashSet};
 
+use crate::bedmethyl_util::check::BedMethylChecker;
+use crate::bedmethyl_util::filter::{BedIntervals, BedMethylFilter};
+use crate::bedmethyl_util::liftover::{
+    lift_bedmethyl, ChainMap, ContextCheck, LiftedRecordSpill,
+};
+use crate::bedmethyl_util::matrix::{
+    matrix_columns, MatrixWriter, SampleCounts,
+};
 use crate::bedmethyl_util::BedMethylStream;
+use crate::bigwig::write_bigwig;
 use crate::command_utils::calculate_chunk_size;
 use crate::dmr::bedmethyl::BedMethylLine;
+use crate::errs::MkResult;
 use crate::interval_chunks::{
     ChromCoordinates, ReferenceIntervalsFeeder, TotalLength,
 };
 use crate::logging::init_logging;
 use crate::mod_base_code::ModCodeRepr;
-use crate::tabix::{HtsTabixHandler, ParseBedLine};
+use crate::parquet_table::ParquetTableWriter;
+use crate::tabix::{
+    build_bed_tabix_index, BedMethylTbxIndex, HtsTabixHandler, ParseBedLine,
+    TBI_MAX_POSITION,
+};
 use crate::util::{
     create_out_directory, get_guage, get_subroutine_progress_bar, get_ticker,
     read_sequence_lengths_file, ReferenceRecord, StrandRule,
 };
-use crate::writers::bedmethyl_header;
-use bigtools::{
-    beddata::BedParserStreamingIterator, BigWigWrite, InputSortType,
-};
+use crate::writers::{bedmethyl_header, FinishWrite};
+use bigtools::InputSortType;
+use rust_htslib::tpool::ThreadPool;
 
 #[derive(Subcommand)]
 pub enum EntryBedMethyl {
@@ -40,6 +53,26 @@ pub enum EntryBedMethyl {
     /// For details on the BigWig format see https://doi.org/10.1093/bioinformatics/btq351.
     #[command(name = "tobigwig")]
     ToBigWig(EntryToBigWig),
+    /// Check a bedMethyl file for malformed records: column counts, sort
+    /// order, coordinate sanity, and mod-code/primary base consistency.
+    /// Optionally write a sorted, bgzip-compressed copy with a tabix index.
+    #[command(name = "check", alias = "check-bedmethyl")]
+    Check(EntryCheckBedMethyl),
+    /// Filter a bedMethyl in a single streaming pass by BED intervals,
+    /// modification code, strand, coverage, and percent modified. Writes
+    /// bgzip-compressed output.
+    #[command(name = "filter")]
+    Filter(EntryFilterBedMethyl),
+    /// Convert bedMethyl coordinates to another assembly using a UCSC chain
+    /// file, optionally dropping records where the reference sequence
+    /// context changes.
+    #[command(name = "liftover")]
+    Liftover(EntryLiftoverBedMethyl),
+    /// Make a sites-by-samples (or windows-by-samples) matrix of the fraction
+    /// modified from multiple bedMethyl files (e.g. from `pileup --bgzf` of
+    /// each sample), written as a Parquet table ready for clustering or PCA.
+    #[command(name = "matrix")]
+    Matrix(EntryMatrixBedMethyl),
 }
 
 impl EntryBedMethyl {
@@ -47,6 +80,10 @@ impl EntryBedMethyl {
         match self {
             EntryBedMethyl::MergeBedMethyl(x) => x.run(),
             EntryBedMethyl::ToBigWig(x) => x.run(),
+            EntryBedMethyl::Check(x) => x.run(),
+            EntryBedMethyl::Filter(x) => x.run(),
+            EntryBedMethyl::Liftover(x) => x.run(),
+            EntryBedMethyl::Matrix(x) => x.run(),
         }
     }
 }
@@ -56,7 +93,7 @@ impl EntryBedMethyl {
 pub struct EntryMergeBedMethyl {
     /// Input bedMethyl table(s). Should be bgzip-compressed and have an
     /// associated Tabix index. The tabix index will be assumed to be
-    /// $this_file.tbi.
+    /// $this_file.tbi or $this_file.csi.
     #[arg(num_args(2..))]
     in_bedmethyl: Vec<PathBuf>,
     /// Specify the output file to write the results table.
@@ -489,14 +526,6 @@ impl EntryToBigWig {
             info!("loaded {} chromosomes", chrom_sizes.len());
         });
 
-        let mut outb = BigWigWrite::create_file(&self.out_fp, chrom_sizes)?;
-        outb.options.max_zooms = self.nzooms;
-        outb.options.manual_zoom_sizes = self.zooms.clone();
-        outb.options.compress = !self.uncompressed;
-        outb.options.input_sort_type = InputSortType::ALL;
-        outb.options.block_size = self.block_size;
-        outb.options.inmemory = self.inmemory;
-
         let in_stream: Box<dyn BufRead> = match self.in_bedmethyl.as_str() {
             "-" | "stdin" => Box::new(BufReader::new(std::io::stdin().lock())),
             p @ _ => {
@@ -511,15 +540,667 @@ impl EntryToBigWig {
             self.negative_strand_values,
             counter.clone(),
         )?;
-        let vals = BedParserStreamingIterator::new(in_stream, false);
+        write_bigwig(
+            &self.out_fp,
+            chrom_sizes,
+            in_stream,
+            self.nthreads,
+            |options| {
+                options.max_zooms = self.nzooms;
+                options.manual_zoom_sizes = self.zooms.clone();
+                options.compress = !self.uncompressed;
+                options.input_sort_type = InputSortType::ALL;
+                options.block_size = self.block_size;
+                options.inmemory = self.inmemory;
+            },
+        )?;
+        let message = format!("finished, wrote {} records", counter.position());
 
-        let rt = tokio::runtime::Builder::new_multi_thread()
-            .worker_threads(self.nthreads)
-            .build()?;
+        if self.suppress_progress {
+            debug!("{message}");
+        } else {
+            info!("{message}");
+        }
+        Ok(())
+    }
+}
 
-        outb.write(vals, rt)?;
-        let message = format!("finished, wrote {} records", counter.position());
+#[derive(Args)]
+#[command(arg_required_else_help = true)]
+pub struct EntryCheckBedMethyl {
+    /// Input bedMethyl, can be plain text or bgzip-compressed, "-" or "stdin"
+    /// indicates an (uncompressed) input stream.
+    in_bedmethyl: String,
+    /// Reference sequence in FASTA format, with a .fai index. When provided,
+    /// the base at each record is checked against the primary base of the
+    /// modification code (complemented for negative-strand records).
+    #[arg(long = "ref")]
+    reference_fasta: Option<PathBuf>,
+    /// Don't exit 1 when invalid records are found in the input.
+    #[arg(long, default_value_t = false)]
+    permissive: bool,
+
+    /// Write the valid records, sorted, to this bgzip-compressed file and
+    /// create a tabix index ($out_bed.tbi) for it, or a CSI index
+    /// ($out_bed.csi) when positions are too large for tabix. Out-of-order
+    /// records are not considered an error when this option is used.
+    #[clap(help_heading = "Output Options")]
+    #[arg(long, short = 'o')]
+    out_bed: Option<PathBuf>,
+    /// Force overwrite the output file.
+    #[clap(help_heading = "Output Options")]
+    #[arg(long, default_value_t = false)]
+    force: bool,
+    /// Output a header with the bedMethyl.
+    #[clap(help_heading = "Output Options")]
+    #[arg(long = "header", alias = "with-header", default_value_t = false)]
+    with_header: bool,
+
+    /// Number of bgzf threads to use when writing output.
+    #[clap(help_heading = "Compute Options")]
+    #[arg(long, default_value_t = 2)]
+    io_threads: usize,
+
+    /// Specify a file for debug logs to be written to, otherwise ignore them.
+    /// Setting a file is recommended. (alias: log)
+    #[clap(help_heading = "Logging Options")]
+    #[arg(long, alias = "log")]
+    log_filepath: Option<PathBuf>,
+    /// Hide the progress bar
+    #[clap(help_heading = "Logging Options")]
+    #[arg(long, default_value_t = false)]
+    suppress_progress: bool,
+}
+
+impl EntryCheckBedMethyl {
+    pub fn run(&self) -> anyhow::Result<()> {
+        let _handle = init_logging(self.log_filepath.as_ref());
+        if let Some(out_fp) = self.out_bed.as_ref() {
+            if out_fp.exists() && !self.force {
+                bail!("refusing to overwrite {out_fp:?}, use --force");
+            }
+            create_out_directory(out_fp)?;
+        }
+        let mpb = MultiProgress::new();
+        if self.suppress_progress {
+            mpb.set_draw_target(ProgressDrawTarget::hidden());
+        }
+        let counter = mpb.add(get_ticker());
+        counter.set_message("records checked");
+
+        let in_stream: Box<dyn BufRead> = match self.in_bedmethyl.as_str() {
+            "-" | "stdin" => Box::new(BufReader::new(std::io::stdin().lock())),
+            p => {
+                let reader = rust_htslib::bgzf::Reader::from_path(p)
+                    .with_context(|| format!("failed to open {p}"))?;
+                Box::new(BufReader::new(reader))
+            }
+        };
+
+        let mut checker = BedMethylChecker::new(
+            self.reference_fasta.as_deref(),
+            self.out_bed.is_some(),
+        )?;
+        checker.check_stream(in_stream, &counter)?;
+        counter.finish_and_clear();
+
+        if let Some(out_fp) = self.out_bed.as_ref() {
+            checker.write_sorted_bgzf(
+                out_fp,
+                self.with_header,
+                self.io_threads,
+            )?;
+        }
+
+        checker.report(self.out_bed.is_some(), self.permissive)
+    }
+}
+
+#[derive(Args)]
+#[command(arg_required_else_help = true)]
+pub struct EntryFilterBedMethyl {
+    /// Input bedMethyl, can be plain text or bgzip-compressed, "-" or "stdin"
+    /// indicates an (uncompressed) input stream.
+    in_bedmethyl: String,
+    /// Only keep records overlapping intervals in this BED file. BED6+
+    /// strands are respected, BED3 intervals apply to both strands.
+    #[clap(help_heading = "Selection Options")]
+    #[arg(long, alias = "include-positions")]
+    include_bed: Option<PathBuf>,
+    /// Remove records overlapping intervals in this BED file.
+    #[clap(help_heading = "Selection Options")]
+    #[arg(long)]
+    exclude_bed: Option<PathBuf>,
+    /// Only keep records with these modification codes, can be
+    /// comma-separated or passed multiple times.
+    #[clap(help_heading = "Selection Options")]
+    #[arg(long, short = 'c', alias = "codes", value_delimiter = ',', action = clap::ArgAction::Append)]
+    mod_codes: Option<Vec<String>>,
+    /// Only keep records on this strand, "both" selects records with strand
+    /// ".", for example from `pileup --combine-strands`.
+    #[clap(help_heading = "Selection Options")]
+    #[arg(long)]
+    strand: Option<StrandRule>,
+    /// Only keep records with at least this much valid coverage.
+    #[clap(help_heading = "Filtering Options")]
+    #[arg(short = 'm', long, alias = "min-cov", default_value_t = 0)]
+    min_coverage: u64,
+    /// Only keep records with at most this much valid coverage.
+    #[clap(help_heading = "Filtering Options")]
+    #[arg(long, alias = "max-cov")]
+    max_coverage: Option<u64>,
+    /// Only keep records with at least this percent modified (0-100).
+    #[clap(help_heading = "Filtering Options")]
+    #[arg(long)]
+    min_percent: Option<f32>,
+    /// Only keep records with at most this percent modified (0-100).
+    #[clap(help_heading = "Filtering Options")]
+    #[arg(long)]
+    max_percent: Option<f32>,
+
+    /// Write the kept records to this bgzip-compressed file, default is to
+    /// write uncompressed records to stdout.
+    #[clap(help_heading = "Output Options")]
+    #[arg(long, short = 'o')]
+    out_bed: Option<PathBuf>,
+    /// Create a tabix index for the output ($out_bed.tbi), or a CSI index
+    /// ($out_bed.csi) when positions are too large for tabix. The input must
+    /// be sorted, the command fails on unsorted input.
+    #[clap(help_heading = "Output Options")]
+    #[arg(long, default_value_t = false, requires = "out_bed")]
+    index: bool,
+    /// Force overwrite the output file.
+    #[clap(help_heading = "Output Options")]
+    #[arg(long, default_value_t = false)]
+    force: bool,
+
+    /// Number of bgzf threads to use when reading and writing.
+    #[clap(help_heading = "Compute Options")]
+    #[arg(long, default_value_t = 2)]
+    io_threads: usize,
+
+    /// Specify a file for debug logs to be written to, otherwise ignore them.
+    /// Setting a file is recommended. (alias: log)
+    #[clap(help_heading = "Logging Options")]
+    #[arg(long, alias = "log")]
+    log_filepath: Option<PathBuf>,
+    /// Hide the progress bar
+    #[clap(help_heading = "Logging Options")]
+    #[arg(long, default_value_t = false)]
+    suppress_progress: bool,
+}
+
+impl EntryFilterBedMethyl {
+    fn load_filter(&self) -> anyhow::Result<BedMethylFilter> {
+        for (name, pct) in [
+            ("--min-percent", self.min_percent),
+            ("--max-percent", self.max_percent),
+        ] {
+            if pct.is_some_and(|pct| !(0f32..=100f32).contains(&pct)) {
+                bail!("{name} must be between 0 and 100")
+            }
+        }
+        let mod_codes = self
+            .mod_codes
+            .as_ref()
+            .map(|codes| {
+                codes
+                    .iter()
+                    .map(|raw| ModCodeRepr::parse(raw))
+                    .collect::<anyhow::Result<FxHashSet<ModCodeRepr>>>()
+            })
+            .transpose()?;
+        let include = self
+            .include_bed
+            .as_ref()
+            .map(|fp| BedIntervals::from_path(fp, self.suppress_progress))
+            .transpose()?;
+        let exclude = self
+            .exclude_bed
+            .as_ref()
+            .map(|fp| BedIntervals::from_path(fp, self.suppress_progress))
+            .transpose()?;
+        Ok(BedMethylFilter {
+            include,
+            exclude,
+            mod_codes,
+            strand: self.strand,
+            min_coverage: self.min_coverage,
+            max_coverage: self.max_coverage,
+            min_percent: self.min_percent,
+            max_percent: self.max_percent,
+        })
+    }
+
+    pub fn run(&self) -> anyhow::Result<()> {
+        let _handle = init_logging(self.log_filepath.as_ref());
+        if let Some(out_fp) = self.out_bed.as_ref() {
+            if out_fp.exists() && !self.force {
+                bail!("refusing to overwrite {out_fp:?}, use --force");
+            }
+            create_out_directory(out_fp)?;
+        }
+        let filter = self.load_filter()?;
+        let mpb = MultiProgress::new();
+        if self.suppress_progress {
+            mpb.set_draw_target(ProgressDrawTarget::hidden());
+        }
+        let counter = mpb.add(get_ticker());
+        counter.set_message("records processed");
+
+        let pool = ThreadPool::new(self.io_threads as u32)?;
+        let in_stream: Box<dyn BufRead> = match self.in_bedmethyl.as_str() {
+            "-" | "stdin" => Box::new(BufReader::new(std::io::stdin().lock())),
+            p => {
+                let mut reader = rust_htslib::bgzf::Reader::from_path(p)
+                    .with_context(|| format!("failed to open {p}"))?;
+                reader.set_thread_pool(&pool)?;
+                Box::new(BufReader::new(reader))
+            }
+        };
+        let counts = match self.out_bed.as_ref() {
+            Some(out_fp) => {
+                let mut writer = rust_htslib::bgzf::Writer::from_path(out_fp)?;
+                writer.set_thread_pool(&pool)?;
+                let counts =
+                    filter.filter_stream(in_stream, &mut writer, &counter)?;
+                writer.flush()?;
+                drop(writer);
+                if self.index {
+                    if !counts.sorted {
+                        bail!(
+                            "input bedMethyl is not sorted, wrote {out_fp:?} \
+                             without an index, use `modkit bedmethyl check \
+                             --out-bed` to sort it"
+                        )
+                    }
+                    let csi = counts.max_end > TBI_MAX_POSITION;
+                    build_bed_tabix_index(out_fp, csi)?;
+                }
+                counts
+            }
+            None => {
+                let mut writer = BufWriter::new(std::io::stdout());
+                let counts =
+                    filter.filter_stream(in_stream, &mut writer, &counter)?;
+                writer.flush()?;
+                counts
+            }
+        };
+        counter.finish_and_clear();
+        if counts.n_invalid > 0 {
+            error!(
+                "skipped {} invalid bedMethyl records, see --log for details",
+                counts.n_invalid
+            );
+        }
+        let message = format!(
+            "finished, kept {} of {} records",
+            counts.n_kept, counts.n_records
+        );
+        if self.suppress_progress {
+            debug!("{message}");
+        } else {
+            info!("{message}");
+        }
+        Ok(())
+    }
+}
+
+#[derive(Args)]
+#[command(arg_required_else_help = true)]
+pub struct EntryLiftoverBedMethyl {
+    /// Input bedMethyl, can be plain text or bgzip-compressed, "-" or "stdin"
+    /// indicates an (uncompressed) input stream.
+    in_bedmethyl: String,
+    /// UCSC chain file from the assembly of the input to the new assembly,
+    /// for example hg38ToHg19.over.chain.gz. Can be gzip-compressed.
+    #[arg(long)]
+    chain: PathBuf,
+    /// Reference sequence of the input bedMethyl in FASTA format, with a .fai
+    /// index. When passed with --to-ref, records where the reference
+    /// sequence around the position differs between the assemblies are
+    /// dropped.
+    #[arg(long, requires = "to_ref")]
+    from_ref: Option<PathBuf>,
+    /// Reference sequence of the new assembly in FASTA format, with a .fai
+    /// index.
+    #[arg(long, requires = "from_ref")]
+    to_ref: Option<PathBuf>,
+    /// Number of bases on each side of the position to compare between the
+    /// references, 1 checks the base and its neighbors (e.g. the G of a CpG).
+    #[arg(long, default_value_t = 1)]
+    context_flank: usize,
+
+    /// Write the lifted records, sorted, to this bgzip-compressed file and
+    /// create a tabix index for it. Default is to write uncompressed records
+    /// to stdout. The records are sorted one contig at a time with temporary
+    /// files in the directory of this file (or the system temporary
+    /// directory).
+    #[clap(help_heading = "Output Options")]
+    #[arg(long, short = 'o')]
+    out_bed: Option<PathBuf>,
+    /// Write the records that could not be lifted to this file, with the
+    /// reason (unmapped, context_changed, or missing_reference) as an extra
+    /// column.
+    #[clap(help_heading = "Output Options")]
+    #[arg(long)]
+    unlifted: Option<PathBuf>,
+    /// Force overwrite the output files.
+    #[clap(help_heading = "Output Options")]
+    #[arg(long, default_value_t = false)]
+    force: bool,
+
+    /// Number of bgzf threads to use when reading and writing.
+    #[clap(help_heading = "Compute Options")]
+    #[arg(long, default_value_t = 2)]
+    io_threads: usize,
+
+    /// Specify a file for debug logs to be written to, otherwise ignore them.
+    /// Setting a file is recommended. (alias: log)
+    #[clap(help_heading = "Logging Options")]
+    #[arg(long, alias = "log")]
+    log_filepath: Option<PathBuf>,
+    /// Hide the progress bar
+    #[clap(help_heading = "Logging Options")]
+    #[arg(long, default_value_t = false)]
+    suppress_progress: bool,
+}
+
+impl EntryLiftoverBedMethyl {
+    pub fn run(&self) -> anyhow::Result<()> {
+        let _handle = init_logging(self.log_filepath.as_ref());
+        for out_fp in self.out_bed.iter().chain(self.unlifted.iter()) {
+            if out_fp.exists() && !self.force {
+                bail!("refusing to overwrite {out_fp:?}, use --force");
+            }
+            create_out_directory(out_fp)?;
+        }
+        let pool = ThreadPool::new(self.io_threads as u32)?;
+        let chain_map = rust_htslib::bgzf::Reader::from_path(&self.chain)
+            .map_err(|e| anyhow::anyhow!("{e}"))
+            .and_then(|reader| ChainMap::from_stream(BufReader::new(reader)))
+            .with_context(|| {
+                format!("failed to load chain file {:?}", &self.chain)
+            })?;
+        let mut context_check =
+            match (self.from_ref.as_ref(), self.to_ref.as_ref()) {
+                (Some(from_ref), Some(to_ref)) => Some(ContextCheck::new(
+                    from_ref,
+                    to_ref,
+                    self.context_flank,
+                )?),
+                _ => None,
+            };
+        let mut unlifted_writer = self
+            .unlifted
+            .as_ref()
+            .map(|fp| File::create(fp).map(BufWriter::new))
+            .transpose()?;
+
+        let mpb = MultiProgress::new();
+        if self.suppress_progress {
+            mpb.set_draw_target(ProgressDrawTarget::hidden());
+        }
+        let counter = mpb.add(get_ticker());
+        counter.set_message("records processed");
+        let in_stream: Box<dyn BufRead> = match self.in_bedmethyl.as_str() {
+            "-" | "stdin" => Box::new(BufReader::new(std::io::stdin().lock())),
+            p => {
+                let mut reader = rust_htslib::bgzf::Reader::from_path(p)
+                    .with_context(|| format!("failed to open {p}"))?;
+                reader.set_thread_pool(&pool)?;
+                Box::new(BufReader::new(reader))
+            }
+        };
+        let spill_dir = self
+            .out_bed
+            .as_ref()
+            .and_then(|fp| fp.parent())
+            .map(|dir| dir.to_path_buf())
+            .unwrap_or_else(std::env::temp_dir);
+        let mut spill = LiftedRecordSpill::new(&spill_dir)?;
+        let counts = lift_bedmethyl(
+            in_stream,
+            &chain_map,
+            context_check.as_mut(),
+            unlifted_writer.as_mut().map(|w| w as &mut dyn Write),
+            &mut spill,
+            &counter,
+        )?;
+        counter.finish_and_clear();
+        if let Some(mut writer) = unlifted_writer {
+            writer.flush()?;
+        }
+
+        match self.out_bed.as_ref() {
+            Some(out_fp) => {
+                let mut writer = rust_htslib::bgzf::Writer::from_path(out_fp)?;
+                writer.set_thread_pool(&pool)?;
+                let max_stop = spill.write_sorted(&mut writer)?;
+                writer.flush()?;
+                drop(writer);
+                build_bed_tabix_index(out_fp, max_stop > TBI_MAX_POSITION)?;
+            }
+            None => {
+                let mut writer = BufWriter::new(std::io::stdout());
+                spill.write_sorted(&mut writer)?;
+                writer.flush()?;
+            }
+        }
+
+        for (reason, count) in counts.unlifted.iter().sorted() {
+            info!("{count} records not lifted, {reason}");
+        }
+        let message = format!(
+            "finished, lifted {} of {} records",
+            counts.n_lifted, counts.n_records
+        );
+        if self.suppress_progress {
+            debug!("{message}");
+        } else {
+            info!("{message}");
+        }
+        Ok(())
+    }
+}
+
+#[derive(Args)]
+#[command(arg_required_else_help = true)]
+pub struct EntryMatrixBedMethyl {
+    /// Input bedMethyl files, one per sample, bgzip-compressed and
+    /// tabix-indexed (as written by `pileup --bgzf`). The samples are merged
+    /// one contig at a time as they're read.
+    #[arg(required = true, num_args = 1..)]
+    in_bedmethyls: Vec<PathBuf>,
+    /// Output Parquet file, with a row for each site (or window) and the
+    /// columns `chrom`, `start`, `end`, `strand`, then
+    /// `fraction_modified_<sample>`, `n_mod_<sample>`, and
+    /// `n_valid_<sample>` for each sample. Missing fractions are null.
+    #[clap(help_heading = "Output Options")]
+    #[arg(long, short = 'o')]
+    out_parquet: PathBuf,
+    /// Names of the samples, in the same order as the inputs, comma-separated
+    /// or passed multiple times. Default is to use the input file names.
+    #[clap(help_heading = "Output Options")]
+    #[arg(long, value_delimiter = ',', action = clap::ArgAction::Append)]
+    sample_names: Option<Vec<String>>,
+    /// Force overwrite the output file.
+    #[clap(help_heading = "Output Options")]
+    #[arg(long, default_value_t = false)]
+    force: bool,
 
+    /// Modification codes to use, counts for multiple codes at the same site
+    /// are combined (e.g. --mod-codes h,m for 5hmC and 5mC).
+    #[clap(help_heading = "Selection Options")]
+    #[arg(
+        short = 'c',
+        long,
+        value_delimiter = ',',
+        required = true,
+        alias = "mod-code"
+    )]
+    mod_codes: Vec<String>,
+    /// Sum the counts in non-overlapping windows of this many bases, giving
+    /// a windows-by-samples matrix. Windows combine both strands. Default is
+    /// one row per site and strand.
+    #[clap(help_heading = "Selection Options")]
+    #[arg(long, short = 'w')]
+    window_size: Option<u64>,
+    /// Cells (sites or windows of a sample) with less valid coverage than
+    /// this are missing.
+    #[clap(help_heading = "Filtering Options")]
+    #[arg(short = 'm', long, alias = "min-cov", default_value_t = 1)]
+    min_coverage: u64,
+    /// Only keep rows with at least this many samples that aren't missing,
+    /// set to the number of samples for a matrix without missing values.
+    #[clap(help_heading = "Filtering Options")]
+    #[arg(long, default_value_t = 1)]
+    min_samples: usize,
+
+    /// Number of bgzf threads to use when reading each input.
+    #[clap(help_heading = "Compute Options")]
+    #[arg(long, default_value_t = 2)]
+    io_threads: usize,
+
+    /// Specify a file for debug logs to be written to, otherwise ignore them.
+    /// Setting a file is recommended. (alias: log)
+    #[clap(help_heading = "Logging Options")]
+    #[arg(long, alias = "log")]
+    log_filepath: Option<PathBuf>,
+    /// Hide the progress bar
+    #[clap(help_heading = "Logging Options")]
+    #[arg(long, default_value_t = false)]
+    suppress_progress: bool,
+}
+
+impl EntryMatrixBedMethyl {
+    fn sample_names(&self) -> anyhow::Result<Vec<String>> {
+        let names = match self.sample_names.as_ref() {
+            Some(names) => {
+                if names.len() != self.in_bedmethyls.len() {
+                    bail!(
+                        "got {} sample names for {} inputs",
+                        names.len(),
+                        self.in_bedmethyls.len()
+                    )
+                }
+                names.clone()
+            }
+            None => self
+                .in_bedmethyls
+                .iter()
+                .map(|fp| {
+                    let name = fp
+                        .file_name()
+                        .map(|name| name.to_string_lossy().to_string())
+                        .unwrap_or_else(|| fp.to_string_lossy().to_string());
+                    name.trim_end_matches(".gz")
+                        .trim_end_matches(".bed")
+                        .to_string()
+                })
+                .collect(),
+        };
+        if let Some(duplicated) = names.iter().duplicates().next() {
+            bail!("duplicated sample name {duplicated}, use --sample-names")
+        }
+        Ok(names)
+    }
+
+    pub fn run(&self) -> anyhow::Result<()> {
+        let _handle = init_logging(self.log_filepath.as_ref());
+        if self.out_parquet.exists() && !self.force {
+            bail!("refusing to overwrite {:?}, use --force", self.out_parquet);
+        }
+        if self.window_size == Some(0) {
+            bail!("--window-size must be greater than 0")
+        }
+        if self.min_samples > self.in_bedmethyls.len() {
+            bail!(
+                "--min-samples ({}) is more than the number of inputs ({})",
+                self.min_samples,
+                self.in_bedmethyls.len()
+            )
+        }
+        let sample_names = self.sample_names()?;
+        let mod_codes = self
+            .mod_codes
+            .iter()
+            .map(|raw| ModCodeRepr::parse(raw))
+            .collect::<anyhow::Result<FxHashSet<ModCodeRepr>>>()?;
+        let handlers = self
+            .in_bedmethyls
+            .iter()
+            .map(BedMethylTbxIndex::from_path)
+            .collect::<anyhow::Result<Vec<BedMethylTbxIndex>>>()?;
+        // contigs in the order of the first input, then any contigs that
+        // are only in the other inputs
+        let contigs = handlers
+            .iter()
+            .flat_map(|handler| handler.get_contigs_in_order())
+            .unique()
+            .collect::<Vec<String>>();
+        create_out_directory(&self.out_parquet)?;
+
+        let mpb = MultiProgress::new();
+        if self.suppress_progress {
+            mpb.set_draw_target(ProgressDrawTarget::hidden());
+        }
+        let counter = mpb.add(get_ticker());
+        counter.set_message("records processed");
+
+        let writer = ParquetTableWriter::new(
+            &self.out_parquet,
+            self.force,
+            "matrix",
+            &matrix_columns(&sample_names),
+        )?;
+        let mut matrix = MatrixWriter::new(
+            mod_codes,
+            self.window_size,
+            self.min_coverage,
+            self.min_samples,
+            writer,
+        );
+        let mut counts = sample_names
+            .iter()
+            .map(|_| SampleCounts::default())
+            .collect::<Vec<SampleCounts>>();
+        for contig in contigs {
+            let records = handlers
+                .iter()
+                .map(|handler| handler.iter_contig(&contig, self.io_threads))
+                .collect::<MkResult<Vec<_>>>()?;
+            matrix
+                .write_contig(&contig, records, &mut counts, &counter)
+                .with_context(|| format!("failed to make rows for {contig}"))?;
+        }
+        counter.finish_and_clear();
+        let n_rows = matrix.n_rows();
+        matrix.into_writer().finish_write()?;
+
+        for ((fp, name), counts) in
+            self.in_bedmethyls.iter().zip(sample_names.iter()).zip(counts)
+        {
+            if counts.n_invalid > 0 {
+                error!(
+                    "skipped {} invalid bedMethyl records in {fp:?}, see \
+                     --log for details",
+                    counts.n_invalid
+                );
+            }
+            if counts.n_used == 0 {
+                error!("no records with the requested codes in {fp:?}");
+            }
+            debug!(
+                "sample {name}, used {} of {} records",
+                counts.n_used, counts.n_records
+            );
+        }
+        let message = format!(
+            "finished, wrote {n_rows} rows by {} samples",
+            sample_names.len()
+        );
         if self.suppress_progress {
             debug!("{message}");
         } else {
@@ -0,0 +1,293 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::thread::JoinHandle;
+
+use anyhow::{anyhow, bail, Context};
+use bigtools::bed::bedparser::{BedValueError, StreamingBedValues};
+use bigtools::beddata::BedParserStreamingIterator;
+use bigtools::{BBIWriteOptions, BigWigWrite, InputSortType, Value};
+use crossbeam::channel::{bounded, Receiver, Sender};
+use log::{debug, warn};
+use rustc_hash::FxHashSet;
+
+/// (start, end, value) intervals on a contig.
+type Intervals = Vec<(u32, u32, f32)>;
+/// Sends each contig's intervals to the thread writing the bigWig.
+type WriterHandle =
+    (Sender<(String, Intervals)>, JoinHandle<anyhow::Result<()>>);
+
+/// Create a bigWig at `out_fp` and write `values` to it on a runtime with
+/// `threads` worker threads. The intervals on each contig must be sorted,
+/// the contigs can be in any order unless `configure` sets
+/// `InputSortType::ALL`.
+pub(crate) fn write_bigwig<S>(
+    out_fp: &Path,
+    chrom_sizes: HashMap<String, u32>,
+    values: S,
+    threads: usize,
+    configure: impl FnOnce(&mut BBIWriteOptions),
+) -> anyhow::Result<()>
+where
+    S: StreamingBedValues<Value = Value>,
+{
+    let mut outb = BigWigWrite::create_file(out_fp, chrom_sizes)
+        .with_context(|| format!("failed to create bigWig at {out_fp:?}"))?;
+    outb.options.input_sort_type = InputSortType::START;
+    configure(&mut outb.options);
+    let allow_out_of_order_chroms =
+        matches!(outb.options.input_sort_type, InputSortType::START);
+    let vals =
+        BedParserStreamingIterator::new(values, allow_out_of_order_chroms);
+    let rt = tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(threads)
+        .build()?;
+    outb.write(vals, rt)
+        .with_context(|| format!("failed to write bigWig at {out_fp:?}"))
+}
+
+/// Sort the intervals on a contig and make them non-overlapping, an interval
+/// that overlaps the next one is trimmed to end where the next one starts.
+/// Intervals with the same start keep the shortest, then the first added.
+fn non_overlapping(mut intervals: Intervals) -> Intervals {
+    intervals.sort_by_key(|(start, end, _)| (*start, *end));
+    intervals.dedup_by_key(|(start, _, _)| *start);
+    let next_starts = intervals
+        .iter()
+        .skip(1)
+        .map(|(start, _, _)| Some(*start))
+        .chain(std::iter::once(None))
+        .collect::<Vec<Option<u32>>>();
+    intervals
+        .into_iter()
+        .zip(next_starts)
+        .map(|((start, end, value), next_start)| {
+            let end = next_start.map(|n| end.min(n)).unwrap_or(end);
+            (start, end, value)
+        })
+        .collect()
+}
+
+/// The intervals of each contig, received from a [`BigWigTrack`].
+struct ContigIntervalsStream {
+    rcv: Receiver<(String, Intervals)>,
+    chrom: String,
+    intervals: std::vec::IntoIter<(u32, u32, f32)>,
+}
+
+impl ContigIntervalsStream {
+    fn new(rcv: Receiver<(String, Intervals)>) -> Self {
+        Self { rcv, chrom: String::new(), intervals: Vec::new().into_iter() }
+    }
+}
+
+impl StreamingBedValues for ContigIntervalsStream {
+    type Value = Value;
+
+    fn next(&mut self) -> Option<Result<(&str, Self::Value), BedValueError>> {
+        let (start, end, value) = loop {
+            match self.intervals.next() {
+                Some(interval) => break interval,
+                None => {
+                    let (chrom, intervals) = self.rcv.recv().ok()?;
+                    self.chrom = chrom;
+                    self.intervals = intervals.into_iter();
+                }
+            }
+        };
+        Some(Ok((self.chrom.as_str(), Value { start, end, value })))
+    }
+}
+
+/// Writes intervals to a bigWig track as they are added. Only the intervals
+/// of the contig being added are kept in memory, they can be added in any
+/// order and are sorted and made non-overlapping (see [`non_overlapping`])
+/// when the next contig starts. Each finished contig is streamed to a
+/// background thread writing the bigWig, so all of the intervals for a
+/// contig must be added together. The file is only created once there is
+/// an interval to write.
+pub(crate) struct BigWigTrack {
+    out_fp: PathBuf,
+    chrom_sizes: HashMap<String, u32>,
+    threads: usize,
+    current: Option<(String, Intervals)>,
+    finished_contigs: FxHashSet<String>,
+    writer: Option<WriterHandle>,
+    n_intervals: usize,
+}
+
+impl BigWigTrack {
+    pub(crate) fn new(
+        out_fp: &Path,
+        chrom_sizes: HashMap<String, u32>,
+        threads: usize,
+    ) -> Self {
+        Self {
+            out_fp: out_fp.to_path_buf(),
+            chrom_sizes,
+            threads,
+            current: None,
+            finished_contigs: FxHashSet::default(),
+            writer: None,
+            n_intervals: 0,
+        }
+    }
+
+    /// Add an interval, intervals on contigs that aren't in the chrom sizes
+    /// are skipped.
+    pub(crate) fn add(
+        &mut self,
+        chrom: &str,
+        start: u32,
+        end: u32,
+        value: f32,
+    ) -> anyhow::Result<()> {
+        match self.current.as_mut() {
+            Some((current, intervals)) if current == chrom => {
+                intervals.push((start, end, value));
+                return Ok(());
+            }
+            _ => self.finish_contig()?,
+        }
+        if self.finished_contigs.contains(chrom) {
+            bail!(
+                "intervals on {chrom} were not grouped together, input must \
+                 be grouped by contig to write bigWig at {:?}",
+                self.out_fp
+            )
+        }
+        self.current = Some((chrom.to_string(), vec![(start, end, value)]));
+        Ok(())
+    }
+
+    fn finish_contig(&mut self) -> anyhow::Result<()> {
+        let Some((chrom, intervals)) = self.current.take() else {
+            return Ok(());
+        };
+        self.finished_contigs.insert(chrom.clone());
+        if !self.chrom_sizes.contains_key(&chrom) {
+            debug!("{chrom} not in reference, skipping for bigWig");
+            return Ok(());
+        }
+        let intervals = non_overlapping(intervals);
+        self.n_intervals += intervals.len();
+        let (snd, _) = match self.writer.as_ref() {
+            Some(writer) => writer,
+            None => self.writer.insert(self.start_writer()),
+        };
+        if snd.send((chrom, intervals)).is_err() {
+            // the writer stopped early, report its error
+            self.join_writer()?;
+            bail!("bigWig writer stopped early for {:?}", self.out_fp)
+        }
+        Ok(())
+    }
+
+    fn start_writer(&self) -> WriterHandle {
+        let (snd, rcv) = bounded(2);
+        let out_fp = self.out_fp.clone();
+        let chrom_sizes = self.chrom_sizes.clone();
+        let threads = self.threads;
+        let handle = std::thread::spawn(move || {
+            write_bigwig(
+                &out_fp,
+                chrom_sizes,
+                ContigIntervalsStream::new(rcv),
+                threads,
+                |_| {},
+            )
+        });
+        (snd, handle)
+    }
+
+    fn join_writer(&mut self) -> anyhow::Result<()> {
+        match self.writer.take() {
+            Some((snd, handle)) => {
+                drop(snd);
+                handle
+                    .join()
+                    .map_err(|_| anyhow!("bigWig writer thread panicked"))?
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Write the remaining intervals and finish the bigWig, returns the
+    /// number of intervals written.
+    pub(crate) fn finish(mut self) -> anyhow::Result<usize> {
+        self.finish_contig()?;
+        if self.writer.is_none() {
+            warn!("no intervals to write to bigWig at {:?}", self.out_fp);
+        }
+        self.join_writer()?;
+        debug!(
+            "wrote {} interval(s) to bigWig at {:?}",
+            self.n_intervals, self.out_fp
+        );
+        Ok(self.n_intervals)
+    }
+}
+
+#[cfg(test)]
+mod bigwig_tests {
+    use std::collections::HashMap;
+
+    use bigtools::BigWigRead;
+
+    use crate::bigwig::{non_overlapping, BigWigTrack};
+
+    #[test]
+    fn test_non_overlapping() {
+        let observed = non_overlapping(vec![
+            (10, 30, 0.5),
+            (0, 20, 0.1),
+            (10, 25, 0.2),
+            (40, 60, 1.0),
+            (40, 60, 2.0),
+        ]);
+        assert_eq!(observed, vec![(0, 10, 0.1), (10, 25, 0.2), (40, 60, 1.0)]);
+    }
+
+    #[test]
+    fn test_bigwig_track() {
+        let out_fp = std::env::temp_dir().join("test_bigwig_track.bw");
+        let chrom_sizes = HashMap::from([
+            ("chr1".to_string(), 100u32),
+            ("chr2".to_string(), 100u32),
+        ]);
+        let mut track = BigWigTrack::new(&out_fp, chrom_sizes.clone(), 1);
+        // contigs in any order, intervals in any order within a contig
+        track.add("chr2", 5, 6, 0.5).unwrap();
+        track.add("chr2", 1, 2, 0.25).unwrap();
+        track.add("chrUn", 1, 2, 1.0).unwrap();
+        track.add("chr1", 0, 1, 1.0).unwrap();
+        assert_eq!(track.finish().unwrap(), 3);
+
+        let mut reader = BigWigRead::open_file(&out_fp).unwrap();
+        let intervals = |reader: &mut BigWigRead<_>, chrom: &str| {
+            reader
+                .get_interval(chrom, 0, 100)
+                .unwrap()
+                .map(|v| {
+                    let v = v.unwrap();
+                    (v.start, v.end, v.value)
+                })
+                .collect::<Vec<(u32, u32, f32)>>()
+        };
+        assert_eq!(intervals(&mut reader, "chr1"), vec![(0, 1, 1.0)]);
+        assert_eq!(
+            intervals(&mut reader, "chr2"),
+            vec![(1, 2, 0.25), (5, 6, 0.5)]
+        );
+
+        let mut track = BigWigTrack::new(&out_fp, chrom_sizes.clone(), 1);
+        track.add("chr1", 0, 1, 1.0).unwrap();
+        track.add("chr2", 0, 1, 1.0).unwrap();
+        assert!(track.add("chr1", 5, 6, 1.0).is_err());
+
+        let empty_fp = std::env::temp_dir().join("test_bigwig_track_empty.bw");
+        let _ = std::fs::remove_file(&empty_fp);
+        let track = BigWigTrack::new(&empty_fp, chrom_sizes, 1);
+        assert_eq!(track.finish().unwrap(), 0);
+        assert!(!empty_fp.exists());
+    }
+}
@@ -1,5 +1,9 @@
-use clap::Parser;
+use std::path::PathBuf;
+
+use clap::{CommandFactory, FromArgMatches, Parser};
 use mod_kit::commands::Commands;
+use mod_kit::mod_code_registry::init_mod_code_registry;
+use mod_kit::run_summary::write_run_summary;
 
 #[derive(Parser)]
 #[command(version)]
@@ -9,16 +13,45 @@ use mod_kit::commands::Commands;
 struct Cli {
     #[command(subcommand)]
     command: Commands,
+    /// Write a JSON summary of the run to this file when the command
+    /// finishes, with the error counts by category and the number of reads
+    /// used, skipped, and failed (when the command tracks them).
+    #[arg(long, global = true, hide_short_help = true)]
+    run_summary: Option<PathBuf>,
+    /// Tab-separated file of modification codes to add to the built-in
+    /// codes, with columns code, primary base, name, and (optionally) a
+    /// `#RRGGBB` color. Used wherever modification codes are associated with
+    /// a primary base, named, or colored (e.g. `dmr`, `pileup`, `validate`,
+    /// and `sample-probs` plots). Built-in codes can be renamed and
+    /// recolored but keep their primary base.
+    #[arg(long, global = true, hide_short_help = true)]
+    mod_code_file: Option<PathBuf>,
 }
 
 fn main() -> Result<(), String> {
-    let cli = Cli::parse();
+    let matches = Cli::command().get_matches();
+    let command_name = matches.subcommand_name().unwrap_or("").to_string();
+    let cli = match Cli::from_arg_matches(&matches) {
+        Ok(cli) => cli,
+        Err(e) => e.exit(),
+    };
     unsafe {
         rust_htslib::htslib::hts_set_log_level(
             rust_htslib::htslib::htsLogLevel_HTS_LOG_OFF,
         );
     }
-    if let Err(err) = cli.command.run() {
+    let result = match cli.mod_code_file.as_ref() {
+        Some(fp) => init_mod_code_registry(fp).and_then(|_| cli.command.run()),
+        None => cli.command.run(),
+    };
+    if let Some(summary_fp) = cli.run_summary.as_ref() {
+        if let Err(e) =
+            write_run_summary(summary_fp, &command_name, result.as_ref().err())
+        {
+            eprintln!("> failed to write run summary to {summary_fp:?}, {e}");
+        }
+    }
+    if let Err(err) = result {
         eprintln!("> Error! {err}");
         for cause in err.chain().skip(1) {
             eprintln!(" caused by {cause}")
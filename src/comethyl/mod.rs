@@ -0,0 +1,415 @@
+use std::ops::Range;
+use std::path::Path;
+use std::sync::Arc;
+
+use log::debug;
+use rust_htslib::bam::{self, FetchDefinition, Read};
+use rustc_hash::FxHashMap;
+
+use crate::mod_bam::{BaseModCall, ModBaseInfo};
+use crate::mod_base_code::ModCodeRepr;
+use crate::motifs::motif_bed::RegexMotif;
+use crate::read_ids_to_base_mod_probs::{PositionModCalls, ReadBaseModProfile};
+use crate::reads_sampler::sampling_schedule::ReferenceSequencesLookup;
+use crate::threshold_mod_caller::MultipleThresholdModCaller;
+use crate::util::{record_is_not_primary, Strand};
+
+pub mod subcommand;
+
+/// Co-occurrence of modification states at a pair of positions, "a" is the
+/// upstream (anchor) position and "b" is the downstream position.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+struct PairCounts {
+    n_mod_mod: u32,
+    n_mod_canonical: u32,
+    n_canonical_mod: u32,
+    n_canonical_canonical: u32,
+}
+
+impl PairCounts {
+    fn add(&mut self, a_modified: bool, b_modified: bool) {
+        match (a_modified, b_modified) {
+            (true, true) => self.n_mod_mod += 1,
+            (true, false) => self.n_mod_canonical += 1,
+            (false, true) => self.n_canonical_mod += 1,
+            (false, false) => self.n_canonical_canonical += 1,
+        }
+    }
+
+    fn n_reads(&self) -> u32 {
+        self.n_mod_mod
+            + self.n_mod_canonical
+            + self.n_canonical_mod
+            + self.n_canonical_canonical
+    }
+
+    /// Squared Pearson correlation between the binary modification states of
+    /// the two positions. NaN when either position is invariant.
+    fn r_squared(&self) -> f64 {
+        let n = self.n_reads() as f64;
+        let p_a = (self.n_mod_mod + self.n_mod_canonical) as f64 / n;
+        let p_b = (self.n_mod_mod + self.n_canonical_mod) as f64 / n;
+        let d = self.n_mod_mod as f64 / n - p_a * p_b;
+        let denom = p_a * (1f64 - p_a) * p_b * (1f64 - p_b);
+        if denom <= 0f64 {
+            f64::NAN
+        } else {
+            d.powi(2) / denom
+        }
+    }
+
+    /// Mutual information (in bits) between the modification states of the
+    /// two positions.
+    fn mutual_information(&self) -> f64 {
+        let n = self.n_reads() as f64;
+        let p_a = (self.n_mod_mod + self.n_mod_canonical) as f64 / n;
+        let p_b = (self.n_mod_mod + self.n_canonical_mod) as f64 / n;
+        [
+            (self.n_mod_mod, p_a, p_b),
+            (self.n_mod_canonical, p_a, 1f64 - p_b),
+            (self.n_canonical_mod, 1f64 - p_a, p_b),
+            (self.n_canonical_canonical, 1f64 - p_a, 1f64 - p_b),
+        ]
+        .into_iter()
+        .filter(|(count, _, _)| *count > 0)
+        .map(|(count, p_x, p_y)| {
+            let p_xy = count as f64 / n;
+            p_xy * (p_xy / (p_x * p_y)).log2()
+        })
+        .sum::<f64>()
+        .max(0f64)
+    }
+}
+
+pub(super) struct ComethylRow {
+    chrom: String,
+    pos_a: u64,
+    pos_b: u64,
+    strand: Option<Strand>,
+    counts: PairCounts,
+}
+
+impl ComethylRow {
+    pub(super) fn header() -> String {
+        [
+            "chrom",
+            "pos_a",
+            "pos_b",
+            "strand",
+            "distance",
+            "n_reads",
+            "n_mod_mod",
+            "n_mod_canonical",
+            "n_canonical_mod",
+            "n_canonical_canonical",
+            "r_squared",
+            "mutual_information",
+        ]
+        .join("\t")
+    }
+
+    pub(super) fn to_row(&self) -> String {
+        let strand = self
+            .strand
+            .map(|s| s.to_char().to_string())
+            .unwrap_or(".".to_string());
+        format!(
+            "{}\t{}\t{}\t{strand}\t{}\t{}\t{}\t{}\t{}\t{}\t{:.6}\t{:.6}",
+            self.chrom,
+            self.pos_a,
+            self.pos_b,
+            self.pos_b - self.pos_a,
+            self.counts.n_reads(),
+            self.counts.n_mod_mod,
+            self.counts.n_mod_canonical,
+            self.counts.n_canonical_mod,
+            self.counts.n_canonical_canonical,
+            self.counts.r_squared(),
+            self.counts.mutual_information(),
+        )
+    }
+}
+
+/// Settings shared by all intervals.
+pub(super) struct PairSettings {
+    pub(super) max_distance: u64,
+    pub(super) min_coverage: u32,
+    pub(super) combine_strands: bool,
+    pub(super) mod_code: Option<ModCodeRepr>,
+}
+
+/// A chunk of a contig to calculate co-methylation over. Pairs are reported
+/// when the upstream position is within `anchors`, the downstream position
+/// can be up to `max_distance` past the end of the anchors.
+pub(super) struct ComethylInterval {
+    chrom: String,
+    tid: u32,
+    anchors: Range<u64>,
+    fetch_end: u64,
+    positions: Vec<(u64, Strand)>,
+    lookup: FxHashMap<(u64, Strand), usize>,
+}
+
+impl ComethylInterval {
+    pub(super) fn new(
+        chrom: &str,
+        tid: u32,
+        anchors: Range<u64>,
+        contig_length: u64,
+        motifs: &[RegexMotif],
+        settings: &PairSettings,
+        reference_sequences: &ReferenceSequencesLookup,
+    ) -> anyhow::Result<Self> {
+        let fetch_end =
+            (anchors.end + settings.max_distance).min(contig_length);
+        let max_motif_length =
+            motifs.iter().map(|m| m.length() as u64).max().unwrap_or(1);
+        let seq_start = anchors.start.saturating_sub(max_motif_length);
+        let seq_end = (fetch_end + max_motif_length).min(contig_length);
+        let seq = reference_sequences
+            .get_subsequence_by_name(
+                chrom,
+                seq_start as usize..seq_end as usize,
+            )?
+            .into_iter()
+            .collect::<String>();
+
+        let mut hits = motifs
+            .iter()
+            .flat_map(|motif| {
+                motif.find_hits(&seq).into_iter().map(|(pos, strand)| {
+                    let pos = pos as u64 + seq_start;
+                    let key = if settings.combine_strands
+                        && strand == Strand::Negative
+                    {
+                        let offset = motif.motif_info.offset() as u64;
+                        (pos.saturating_sub(offset), Strand::Positive)
+                    } else {
+                        (pos, strand)
+                    };
+                    ((pos, strand), key)
+                })
+            })
+            .filter(|(_, (pos, _))| *pos >= anchors.start && *pos < fetch_end)
+            .collect::<Vec<((u64, Strand), (u64, Strand))>>();
+        hits.sort_by_key(|(_, key)| *key);
+
+        let mut positions = Vec::new();
+        let mut lookup = FxHashMap::default();
+        for (raw, key) in hits {
+            if positions.last() != Some(&key) {
+                positions.push(key);
+            }
+            lookup.insert(raw, positions.len() - 1);
+        }
+
+        Ok(Self {
+            chrom: chrom.to_string(),
+            tid,
+            anchors,
+            fetch_end,
+            positions,
+            lookup,
+        })
+    }
+
+    pub(super) fn length(&self) -> u64 {
+        self.anchors.end - self.anchors.start
+    }
+
+    /// Fetch the reads overlapping this interval and tabulate pairwise
+    /// co-occurrence of modification calls.
+    pub(super) fn into_rows(
+        self,
+        bam_fp: &Path,
+        caller: Arc<MultipleThresholdModCaller>,
+        settings: &PairSettings,
+        io_threads: usize,
+    ) -> anyhow::Result<Vec<ComethylRow>> {
+        if self.positions.len() < 2 {
+            return Ok(Vec::new());
+        }
+        let mut reader = bam::IndexedReader::from_path(bam_fp)?;
+        reader.set_threads(io_threads)?;
+        reader.fetch(FetchDefinition::Region(
+            self.tid as i32,
+            self.anchors.start as i64,
+            self.fetch_end as i64,
+        ))?;
+
+        let mut pair_counts =
+            FxHashMap::<(usize, usize), PairCounts>::default();
+        for record in reader.records().filter_map(|r| r.ok()).filter(|record| {
+            !(record.is_unmapped()
+                || record_is_not_primary(record)
+                || record.seq_len() == 0)
+        }) {
+            let name = String::from_utf8_lossy(record.qname()).to_string();
+            let calls = match ModBaseInfo::new_from_record(&record).and_then(
+                |modbase_info| {
+                    ReadBaseModProfile::process_record(
+                        &record,
+                        &name,
+                        modbase_info,
+                        None,
+                        None,
+                        1,
+                    )
+                },
+            ) {
+                Ok(profile) => {
+                    self.read_calls(&profile, &caller, settings.mod_code)
+                }
+                Err(e) => {
+                    debug!("read {name} failed to extract modbase info, {e}");
+                    continue;
+                }
+            };
+            let Some(calls) = calls else {
+                debug!("read {name} has calls on both strands, skipping");
+                continue;
+            };
+
+            for (i, &(idx_a, a_modified)) in calls.iter().enumerate() {
+                let (pos_a, strand_a) = self.positions[idx_a];
+                if pos_a >= self.anchors.end {
+                    break;
+                }
+                for &(idx_b, b_modified) in calls[(i + 1)..].iter() {
+                    let (pos_b, strand_b) = self.positions[idx_b];
+                    if pos_b - pos_a > settings.max_distance {
+                        break;
+                    }
+                    if !settings.combine_strands && strand_a != strand_b {
+                        continue;
+                    }
+                    pair_counts
+                        .entry((idx_a, idx_b))
+                        .or_default()
+                        .add(a_modified, b_modified);
+                }
+            }
+        }
+
+        let mut rows = pair_counts
+            .into_iter()
+            .filter(|(_, counts)| counts.n_reads() >= settings.min_coverage)
+            .collect::<Vec<((usize, usize), PairCounts)>>();
+        rows.sort_by_key(|(key, _)| *key);
+        let rows = rows
+            .into_iter()
+            .map(|((idx_a, idx_b), counts)| {
+                let (pos_a, strand) = self.positions[idx_a];
+                let (pos_b, _) = self.positions[idx_b];
+                ComethylRow {
+                    chrom: self.chrom.clone(),
+                    pos_a,
+                    pos_b,
+                    strand: if settings.combine_strands {
+                        None
+                    } else {
+                        Some(strand)
+                    },
+                    counts,
+                }
+            })
+            .collect();
+        Ok(rows)
+    }
+
+    /// Binary modification calls for a read at the motif positions of this
+    /// interval, sorted by position. Filtered calls, and calls of
+    /// modifications other than `mod_code` (when specified), are removed.
+    /// Returns None for reads with calls on both strands (duplex).
+    fn read_calls(
+        &self,
+        profile: &ReadBaseModProfile,
+        caller: &MultipleThresholdModCaller,
+        mod_code: Option<ModCodeRepr>,
+    ) -> Option<Vec<(usize, bool)>> {
+        let position_calls = PositionModCalls::from_profile(profile);
+        let mut strands = position_calls.iter().map(|p| p.mod_strand);
+        if let Some(first) = strands.next() {
+            if strands.any(|s| s != first) {
+                return None;
+            }
+        }
+        let mut calls = position_calls
+            .into_iter()
+            .filter_map(|p| {
+                let ref_pos = p.ref_position.filter(|&x| x >= 0)? as u64;
+                let idx = *self.lookup.get(&(ref_pos, p.mod_strand))?;
+                let modified =
+                    match caller.call(&p.canonical_base, &p.base_mod_probs) {
+                        BaseModCall::Canonical(_) => false,
+                        BaseModCall::Modified(_, code) => match mod_code {
+                            Some(x) if x != code => return None,
+                            _ => true,
+                        },
+                        BaseModCall::Filtered => return None,
+                    };
+                Some((idx, modified))
+            })
+            .collect::<Vec<(usize, bool)>>();
+        calls.sort_by_key(|(idx, _)| *idx);
+        calls.dedup_by_key(|(idx, _)| *idx);
+        Some(calls)
+    }
+}
+
+#[cfg(test)]
+mod comethyl_tests {
+    use crate::comethyl::PairCounts;
+
+    #[test]
+    fn test_pair_counts_stats() {
+        // perfectly concordant
+        let counts = PairCounts {
+            n_mod_mod: 5,
+            n_mod_canonical: 0,
+            n_canonical_mod: 0,
+            n_canonical_canonical: 5,
+        };
+        assert_eq!(counts.n_reads(), 10);
+        assert!((counts.r_squared() - 1.0).abs() < 1e-9);
+        assert!((counts.mutual_information() - 1.0).abs() < 1e-9);
+
+        // independent
+        let counts = PairCounts {
+            n_mod_mod: 5,
+            n_mod_canonical: 5,
+            n_canonical_mod: 5,
+            n_canonical_canonical: 5,
+        };
+        assert!(counts.r_squared().abs() < 1e-9);
+        assert!(counts.mutual_information().abs() < 1e-9);
+
+        // one position is invariant
+        let counts = PairCounts {
+            n_mod_mod: 4,
+            n_mod_canonical: 6,
+            n_canonical_mod: 0,
+            n_canonical_canonical: 0,
+        };
+        assert!(counts.r_squared().is_nan());
+        assert!(counts.mutual_information().abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_pair_counts_add() {
+        let mut counts = PairCounts::default();
+        counts.add(true, true);
+        counts.add(true, false);
+        counts.add(true, false);
+        counts.add(false, false);
+        assert_eq!(
+            counts,
+            PairCounts {
+                n_mod_mod: 1,
+                n_mod_canonical: 2,
+                n_canonical_mod: 0,
+                n_canonical_canonical: 1,
+            }
+        );
+    }
+}
@@ -0,0 +1,351 @@
+use std::fs::File;
+use std::io::{stdout, BufWriter, Write};
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use anyhow::{bail, Context};
+use clap::Args;
+use indicatif::MultiProgress;
+use log::{debug, info};
+use rayon::prelude::*;
+use rust_htslib::bam::{self, Read};
+
+use crate::comethyl::{ComethylInterval, ComethylRow, PairSettings};
+use crate::command_utils::{
+    get_threshold_from_options, parse_per_mod_thresholds, parse_thresholds,
+};
+use crate::logging::init_logging;
+use crate::mod_base_code::ModCodeRepr;
+use crate::motifs::motif_bed::RegexMotif;
+use crate::reads_sampler::sampling_schedule::{
+    IdxStats, ReferenceSequencesLookup,
+};
+use crate::threshold_mod_caller::MultipleThresholdModCaller;
+use crate::util::{
+    create_out_directory, get_master_progress_bar, get_ticker, Region,
+};
+
+#[derive(Args)]
+#[command(arg_required_else_help = true)]
+pub struct EntryComethyl {
+    /// Input modBAM, must be sorted and have an associated index.
+    in_bam: PathBuf,
+    /// Reference sequence in FASTA format.
+    #[arg(long = "ref", alias = "reference")]
+    reference_fasta: PathBuf,
+    /// Respect soft masking in the reference FASTA.
+    #[arg(long, default_value_t = false)]
+    mask: bool,
+    /// Motif to calculate co-methylation between, multiple motifs can be
+    /// used by repeating this option.
+    #[arg(long, num_args = 2, action = clap::ArgAction::Append)]
+    motif: Option<Vec<String>>,
+    /// Use CpG motifs. Short hand for --motif CG 0 --combine-strands
+    #[arg(long, conflicts_with = "motif", default_value_t = false)]
+    cpg: bool,
+    /// Combine modification calls on the positive and negative strands and
+    /// report pairs on just the positive strand. Motifs must be palindromic.
+    #[arg(long, default_value_t = false)]
+    combine_strands: bool,
+    /// Modification code to consider as "modified", calls of other
+    /// modifications on the same primary base are ignored. Default is to
+    /// consider any modification as modified.
+    #[arg(long)]
+    mod_code: Option<String>,
+    /// Maximum distance in base pairs between a pair of positions.
+    #[arg(long, default_value_t = 500)]
+    max_distance: u64,
+    /// Minimum number of reads with a valid call at both positions to report
+    /// a pair.
+    #[arg(long = "min-coverage", default_value_t = 10)]
+    min_coverage: u32,
+    /// Process only the specified region of the BAM. Format should be
+    /// <chrom_name>:<start>-<end> or <chrom_name>.
+    #[arg(long)]
+    region: Option<String>,
+    /// Interval chunk size in base pairs to process concurrently.
+    #[clap(help_heading = "Compute Options")]
+    #[arg(long, default_value_t = 100_000, hide_short_help = true)]
+    interval_size: u64,
+    /// Optionally specify a file to write output to, default is stdout.
+    #[clap(help_heading = "Output Options")]
+    #[arg(long, short = 'o')]
+    out_file: Option<PathBuf>,
+    /// Force overwrite of existing output file.
+    #[clap(help_heading = "Output Options")]
+    #[arg(long, default_value_t = false)]
+    force: bool,
+    /// Do not perform any filtering, include all mod base calls in output.
+    #[clap(help_heading = "Filtering Options")]
+    #[arg(group = "thresholds", long, default_value_t = false)]
+    no_filtering: bool,
+    /// Sample this many reads when estimating the filtering threshold.
+    #[clap(help_heading = "Sampling Options")]
+    #[arg(long, default_value_t = 10_042)]
+    num_reads: usize,
+    /// Filter out modified base calls where the probability of the predicted
+    /// variant is below this confidence percentile. For example, 0.1 will
+    /// filter out the 10% lowest confidence modification calls.
+    #[clap(help_heading = "Filtering Options")]
+    #[arg(
+        group = "thresholds",
+        short = 'p',
+        long,
+        default_value_t = 0.1,
+        hide_short_help = true
+    )]
+    filter_percentile: f32,
+    /// Specify the filter threshold globally or per-base. Global filter
+    /// threshold can be specified with by a decimal number (e.g. 0.75).
+    /// Per-base thresholds can be specified by colon-separated values, for
+    /// example C:0.75 specifies a threshold value of 0.75 for cytosine
+    /// modification calls.
+    #[clap(help_heading = "Filtering Options")]
+    #[arg(
+        long,
+        group = "thresholds",
+        action = clap::ArgAction::Append,
+        alias = "pass_threshold"
+    )]
+    filter_threshold: Option<Vec<String>>,
+    /// Specify a passing threshold to use for a base modification, independent
+    /// of the threshold for the primary sequence base or the default. For
+    /// example, to set the pass threshold for 5hmC to 0.8 use
+    /// `--mod-threshold h:0.8`.
+    #[clap(help_heading = "Filtering Options")]
+    #[arg(
+        long,
+        alias = "mod-threshold",
+        action = clap::ArgAction::Append
+    )]
+    mod_thresholds: Option<Vec<String>>,
+    /// Number of threads to use.
+    #[clap(help_heading = "Compute Options")]
+    #[arg(short = 't', long, default_value_t = 4)]
+    threads: usize,
+    /// Number of BAM-reading threads to use.
+    #[clap(help_heading = "Compute Options")]
+    #[arg(long, default_value_t = 1, hide_short_help = true)]
+    io_threads: usize,
+    /// Send debug logs to this file, setting this file is recommended.
+    #[clap(help_heading = "Logging Options")]
+    #[arg(long, alias = "log")]
+    log_filepath: Option<PathBuf>,
+    /// Hide progress bars
+    #[clap(help_heading = "Logging Options")]
+    #[arg(long, hide_short_help = true, default_value_t = false)]
+    suppress_progress: bool,
+}
+
+impl EntryComethyl {
+    fn get_motifs(&self) -> anyhow::Result<(Vec<RegexMotif>, bool)> {
+        if self.cpg {
+            info!("using CpG motif and combining strands");
+            return Ok((vec![RegexMotif::parse_string("CG", 0)?], true));
+        }
+        let Some(raw_motif_parts) = self.motif.as_ref() else {
+            bail!("invalid input options, must provide --motif or --cpg")
+        };
+        let motifs = RegexMotif::from_raw_parts(raw_motif_parts, false)?;
+        if self.combine_strands && !motifs.iter().all(|m| m.is_palendrome()) {
+            bail!("motifs must be palindromic to combine strands")
+        }
+        info!("parsed motifs {motifs:?}");
+        Ok((motifs, self.combine_strands))
+    }
+
+    fn get_threshold_caller(
+        &self,
+        region: Option<&Region>,
+        pool: &rayon::ThreadPool,
+    ) -> anyhow::Result<MultipleThresholdModCaller> {
+        let per_mod_thresholds = self
+            .mod_thresholds
+            .as_ref()
+            .map(|raw| parse_per_mod_thresholds(raw))
+            .transpose()?;
+        if let Some(raw_threshold) = self.filter_threshold.as_ref() {
+            parse_thresholds(raw_threshold, per_mod_thresholds)
+        } else {
+            pool.install(|| {
+                get_threshold_from_options(
+                    &self.in_bam,
+                    None,
+                    self.threads,
+                    1_000_000,
+                    None,
+                    self.num_reads,
+                    self.no_filtering,
+                    self.filter_percentile,
+                    None,
+                    region,
+                    per_mod_thresholds,
+                    None,
+                    None,
+                    None,
+                    true,
+                    None,
+                    false,
+                    self.suppress_progress,
+                )
+            })
+        }
+    }
+
+    pub fn run(&self) -> anyhow::Result<()> {
+        let _handle = init_logging(self.log_filepath.as_ref());
+        if self.max_distance == 0 {
+            bail!("--max-distance must be at least 1")
+        }
+        if self.interval_size == 0 {
+            bail!("--interval-size must be at least 1")
+        }
+        if self.min_coverage < 1 {
+            bail!("--min-coverage must be at least 1")
+        }
+        IdxStats::check_any_mapped_reads(&self.in_bam, None, None, None)
+            .with_context(|| {
+                format!(
+                    "did not find any mapped reads in {:?}, perform alignment \
+                     first",
+                    &self.in_bam
+                )
+            })?;
+        let (motifs, combine_strands) = self.get_motifs()?;
+        let mod_code = self
+            .mod_code
+            .as_ref()
+            .map(|x| ModCodeRepr::parse(x))
+            .transpose()?;
+        let settings = PairSettings {
+            max_distance: self.max_distance,
+            min_coverage: self.min_coverage,
+            combine_strands,
+            mod_code,
+        };
+
+        let mut writer: Box<dyn Write> = match self.out_file.as_ref() {
+            Some(out_fp) => {
+                create_out_directory(out_fp)?;
+                if out_fp.exists() && !self.force {
+                    bail!("refusing to overwrite existing file {out_fp:?}")
+                }
+                Box::new(BufWriter::new(File::create(out_fp)?))
+            }
+            None => Box::new(BufWriter::new(stdout())),
+        };
+
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(self.threads)
+            .build()?;
+        let multi_pb = MultiProgress::new();
+        if self.suppress_progress {
+            multi_pb.set_draw_target(indicatif::ProgressDrawTarget::hidden());
+        }
+
+        let header =
+            bam::IndexedReader::from_path(&self.in_bam)?.header().to_owned();
+        let region = self
+            .region
+            .as_ref()
+            .map(|raw| Region::parse_str(raw, &header))
+            .transpose()?;
+        let reference_sequences = ReferenceSequencesLookup::new(
+            &[self.in_bam.clone()],
+            &self.reference_fasta,
+            self.mask,
+            &multi_pb,
+        )?;
+        let caller = self.get_threshold_caller(region.as_ref(), &pool)?;
+        let caller = Arc::new(caller);
+
+        let contigs = (0..header.target_count())
+            .filter_map(|tid| {
+                let name = String::from_utf8_lossy(header.tid2name(tid));
+                reference_sequences.name_to_chrom_id(&name)?;
+                let length = header.target_len(tid)?;
+                let range = match region.as_ref() {
+                    Some(r) if r.name == name => {
+                        (r.start as u64)..(r.end as u64).min(length)
+                    }
+                    Some(_) => return None,
+                    None => 0..length,
+                };
+                Some((name.to_string(), tid, range, length))
+            })
+            .collect::<Vec<_>>();
+        if contigs.is_empty() {
+            bail!("zero contigs to process, check the region and reference")
+        }
+        let total_length =
+            contigs.iter().map(|(_, _, r, _)| r.end - r.start).sum::<u64>();
+
+        let genome_prog =
+            multi_pb.add(get_master_progress_bar(total_length as usize));
+        genome_prog.set_message("genome positions processed");
+        let rows_written = multi_pb.add(get_ticker());
+        rows_written.set_message("rows written");
+        let intervals_failed = multi_pb.add(get_ticker());
+        intervals_failed.set_message("intervals failed");
+
+        writeln!(writer, "{}", ComethylRow::header())?;
+        let batch_size = self.threads * 2;
+        for (chrom, tid, range, length) in contigs {
+            let starts = (range.start..range.end)
+                .step_by(self.interval_size as usize)
+                .collect::<Vec<u64>>();
+            for batch in starts.chunks(batch_size) {
+                let results = pool.install(|| {
+                    batch
+                        .par_iter()
+                        .map(|&start| {
+                            let end =
+                                (start + self.interval_size).min(range.end);
+                            let interval = ComethylInterval::new(
+                                &chrom,
+                                tid,
+                                start..end,
+                                length,
+                                &motifs,
+                                &settings,
+                                &reference_sequences,
+                            )?;
+                            let interval_length = interval.length();
+                            let rows = interval.into_rows(
+                                &self.in_bam,
+                                caller.clone(),
+                                &settings,
+                                self.io_threads,
+                            );
+                            genome_prog.inc(interval_length);
+                            rows
+                        })
+                        .collect::<Vec<anyhow::Result<Vec<ComethylRow>>>>()
+                });
+                for result in results {
+                    match result {
+                        Ok(rows) => {
+                            for row in rows {
+                                writeln!(writer, "{}", row.to_row())?;
+                                rows_written.inc(1);
+                            }
+                        }
+                        Err(e) => {
+                            debug!("interval on {chrom} failed, {e}");
+                            intervals_failed.inc(1);
+                        }
+                    }
+                }
+            }
+        }
+        writer.flush()?;
+
+        multi_pb.clear()?;
+        info!(
+            "finished, wrote {} pairs, {} intervals failed",
+            rows_written.position(),
+            intervals_failed.position()
+        );
+        Ok(())
+    }
+}
@@ -1,16 +1,21 @@
 use std::collections::HashMap;
+use std::io::Write;
 use std::path::PathBuf;
 
 use anyhow::{anyhow, bail, Context};
+use clap::Args;
 use itertools::Itertools;
 use log::{debug, info, warn};
 use rust_htslib::bam::{self, Header};
 
 use crate::adjust::OverlappingRegexOffset;
+use crate::errs::IoRetry;
+use crate::interval_chunks::{ReferenceIntervalsFeeder, TotalLength};
 use crate::mod_bam::{CollapseMethod, EdgeFilter};
 use crate::mod_base_code::{DnaBase, ModCodeRepr};
 use crate::motifs::motif_bed::RegexMotif;
 use crate::position_filter::StrandedPositionFilter;
+use crate::reads_sampler::sampling_schedule::ContigQuotas;
 use crate::threshold_mod_caller::MultipleThresholdModCaller;
 use crate::thresholds::calc_threshold_from_bam;
 use crate::util::{create_out_directory, Region};
@@ -73,6 +78,7 @@ pub(crate) fn parse_thresholds(
 
 pub(crate) fn get_threshold_from_options(
     in_bam: &PathBuf,
+    reference: Option<&PathBuf>,
     threads: usize,
     interval_size: u32,
     sample_frac: Option<f64>,
@@ -86,6 +92,8 @@ pub(crate) fn get_threshold_from_options(
     collapse_method: Option<&CollapseMethod>,
     position_filter: Option<&StrandedPositionFilter<()>>,
     only_mapped: bool,
+    contig_quotas: Option<ContigQuotas>,
+    per_read_group: bool,
     suppress_progress: bool,
 ) -> anyhow::Result<MultipleThresholdModCaller> {
     if no_filtering {
@@ -103,8 +111,9 @@ pub(crate) fn get_threshold_from_options(
             (None, Some(num_reads))
         }
     };
-    let per_base_thresholds = calc_threshold_from_bam(
+    let (per_base_thresholds, read_group_thresholds) = calc_threshold_from_bam(
         in_bam,
+        reference,
         threads,
         interval_size,
         sample_frac,
@@ -116,6 +125,8 @@ pub(crate) fn get_threshold_from_options(
         collapse_method,
         position_filter,
         only_mapped,
+        contig_quotas,
+        per_read_group,
         suppress_progress,
     )?;
 
@@ -126,11 +137,71 @@ pub(crate) fn get_threshold_from_options(
         );
     }
 
-    Ok(MultipleThresholdModCaller::new(
+    let caller = MultipleThresholdModCaller::new(
         per_base_thresholds,
         per_mod_thresholds.unwrap_or(HashMap::new()),
         0f32,
-    ))
+    );
+    if per_read_group && read_group_thresholds.is_empty() {
+        warn!(
+            "no sampled reads have a read group (RG tag), using the same \
+             thresholds for all reads"
+        );
+    }
+    let read_group_callers = read_group_thresholds
+        .into_iter()
+        .map(|(read_group, thresholds)| {
+            let rg_caller = MultipleThresholdModCaller::new(
+                thresholds,
+                HashMap::new(),
+                0f32,
+            )
+            .inherit_missing(&caller);
+            (read_group, rg_caller)
+        })
+        .collect::<HashMap<String, MultipleThresholdModCaller>>();
+    Ok(caller.with_read_group_callers(read_group_callers))
+}
+
+/// Parse user-specified thresholds for read groups, given as pairs of the
+/// read group and a threshold in the same format as `--filter-threshold`
+/// (e.g. `rg1 C:0.8` or `rg1 0.8`). The thresholds for a read group can be
+/// repeated, thresholds missing for a read group are taken from the
+/// `caller`.
+pub(crate) fn parse_read_group_thresholds(
+    raw_read_group_thresholds: &[String],
+    caller: &MultipleThresholdModCaller,
+) -> anyhow::Result<HashMap<String, MultipleThresholdModCaller>> {
+    let raw_per_read_group = raw_read_group_thresholds
+        .chunks(2)
+        .map(|pair| match pair {
+            [read_group, raw_threshold] => Ok((read_group, raw_threshold)),
+            _ => Err(anyhow!(
+                "read group thresholds should be pairs of <RG> <THRESHOLD>"
+            )),
+        })
+        .collect::<anyhow::Result<Vec<(&String, &String)>>>()?
+        .into_iter()
+        .into_group_map();
+    raw_per_read_group
+        .into_iter()
+        .map(|(read_group, raw_thresholds)| {
+            info!("parsing thresholds for read group {read_group}");
+            let raw_thresholds =
+                raw_thresholds.into_iter().cloned().collect::<Vec<String>>();
+            let (default, per_base_thresholds) =
+                parse_per_base_thresholds(&raw_thresholds).with_context(
+                    || format!("invalid threshold for read group {read_group}"),
+                )?;
+            let rg_caller = MultipleThresholdModCaller::new(
+                per_base_thresholds,
+                HashMap::new(),
+                default.unwrap_or(0f32),
+            )
+            .inherit_missing(caller);
+            Ok((read_group.to_owned(), rg_caller))
+        })
+        .collect()
 }
 
 fn parse_raw_threshold(raw: &str) -> anyhow::Result<(DnaBase, f32)> {
@@ -276,6 +347,66 @@ pub(crate) fn parse_edge_filter_input(
     }
 }
 
+/// Retry options shared by the subcommands that read from network
+/// filesystems, see [`IoRetry`].
+#[derive(Args, Debug, Clone)]
+pub(crate) struct IoRetryArgs {
+    /// Number of times to retry opening or fetching from an input (BAM or
+    /// bedMethyl) after a transient IO error (e.g. a network filesystem
+    /// timing out), errors from corrupt or truncated files are not retried.
+    #[clap(help_heading = "Compute Options")]
+    #[arg(long, default_value_t = 3)]
+    io_retries: u32,
+    /// Seconds to wait before the first retry after a transient IO error, the
+    /// wait doubles with each retry.
+    #[clap(help_heading = "Compute Options")]
+    #[arg(long, default_value_t = 1.0)]
+    io_retry_backoff: f32,
+}
+
+impl IoRetryArgs {
+    pub(crate) fn io_retry(&self) -> anyhow::Result<IoRetry> {
+        IoRetry::new(self.io_retries, self.io_retry_backoff)
+    }
+}
+
+/// How `pileup` and `pileup-hemi` divide the reads sampled to estimate the
+/// pass threshold between contigs.
+#[derive(Args, Debug, Clone)]
+pub(crate) struct ContigQuotaArgs {
+    /// How to divide the --num-reads used to estimate the pass threshold
+    /// between contigs when using an indexed modBAM. reads: proportional to
+    /// the number of mapped reads on each contig. length: proportional to
+    /// the length of each contig with mapped reads. uniform: the same number
+    /// of reads from each contig with mapped reads, useful when coverage is
+    /// very uneven, for example amplicons with a genomic background. Default
+    /// is reads for BAM and length for CRAM.
+    #[clap(help_heading = "Sampling Options")]
+    #[arg(long, conflicts_with = "sampling_frac", hide_short_help = true)]
+    contig_quotas: Option<ContigQuotas>,
+    /// Sample the --num-reads used to estimate the pass threshold evenly
+    /// from every contig with mapped reads, the same as --contig-quotas
+    /// uniform.
+    #[clap(help_heading = "Sampling Options")]
+    #[arg(
+        long,
+        conflicts_with_all = ["sampling_frac", "contig_quotas"],
+        default_value_t = false,
+        hide_short_help = true
+    )]
+    sampling_frac_per_contig: bool,
+}
+
+impl ContigQuotaArgs {
+    pub(crate) fn contig_quotas(&self) -> Option<ContigQuotas> {
+        if self.sampling_frac_per_contig {
+            Some(ContigQuotas::uniform)
+        } else {
+            self.contig_quotas
+        }
+    }
+}
+
 pub(crate) fn calculate_chunk_size(
     chunk_size: Option<usize>,
     interval_size: u32,
@@ -302,6 +433,74 @@ pub(crate) fn calculate_chunk_size(
     }
 }
 
+/// What a run would do, printed by `--dry-run` once the inputs have been
+/// validated instead of doing any work.
+pub(crate) struct DryRunPlan {
+    subcommand: &'static str,
+    steps: Vec<(String, String)>,
+    outputs: Vec<String>,
+}
+
+impl DryRunPlan {
+    pub(crate) fn new(subcommand: &'static str) -> Self {
+        Self { subcommand, steps: Vec::new(), outputs: Vec::new() }
+    }
+
+    pub(crate) fn add_step(&mut self, name: &str, description: String) {
+        self.steps.push((name.to_string(), description));
+    }
+
+    /// Add the number of intervals and batches the feeder makes, and the
+    /// number of positions they cover.
+    pub(crate) fn add_intervals(
+        &mut self,
+        mut feeder: ReferenceIntervalsFeeder,
+        interval_size: u32,
+        chunk_size: usize,
+    ) -> anyhow::Result<()> {
+        let total_length = feeder.total_length();
+        let (n_batches, n_intervals) = feeder.fold_ok(
+            (0usize, 0usize),
+            |(batches, intervals), batch| {
+                (batches + 1, intervals + batch.len())
+            },
+        )?;
+        self.add_step(
+            "intervals",
+            format!(
+                "{n_intervals} of up to {interval_size} bp covering \
+                 {total_length} positions, in {n_batches} batches of up to \
+                 {chunk_size} intervals"
+            ),
+        );
+        Ok(())
+    }
+
+    pub(crate) fn add_output(&mut self, output: String) {
+        self.outputs.push(output);
+    }
+
+    /// Write the plan to stdout, nothing else is written in a dry run so
+    /// this is safe even when the output would go to stdout.
+    pub(crate) fn print(&self) -> anyhow::Result<()> {
+        let mut stdout = std::io::stdout().lock();
+        writeln!(
+            stdout,
+            "modkit {} dry run, inputs are valid",
+            self.subcommand
+        )?;
+        for (name, description) in self.steps.iter() {
+            writeln!(stdout, "{name}: {description}")?;
+        }
+        writeln!(stdout, "outputs:")?;
+        for output in self.outputs.iter() {
+            writeln!(stdout, "  {output}")?;
+        }
+        stdout.flush()?;
+        Ok(())
+    }
+}
+
 pub(crate) fn parse_forward_motifs(
     input_motifs: &Option<Vec<String>>,
     cpg: bool,
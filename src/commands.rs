@@ -1,46 +1,60 @@
 use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::BufWriter;
 use std::num::ParseFloatError;
 use std::ops::AddAssign;
 use std::path::{Path, PathBuf};
 
 use crate::adjust::adjust_modbam;
+use crate::bam_pipeline::{get_threaded_bam_writer, OrderedRecordPipeline};
 use crate::bedmethyl_util::subcommands::EntryBedMethyl;
+use crate::comethyl::subcommand::EntryComethyl;
 use crate::command_utils::{
     get_bam_writer, get_serial_reader, get_threshold_from_options,
     parse_edge_filter_input, parse_forward_motifs, parse_per_mod_thresholds,
     parse_thresholds, using_stream,
 };
+use crate::compare_pileups::subcommand::EntryComparePileups;
+use crate::coverage::subcommand::EntryCoverage;
 use crate::dmr::subcommands::BedMethylDmr;
 use crate::entropy::subcommand::MethylationEntropy;
 use crate::errs::{MkError, MkResult};
 use crate::extract::subcommand::ExtractMods;
 use crate::localise::subcommand::EntryLocalize;
 use crate::logging::init_logging;
+use crate::metagene::subcommand::EntryMetagene;
 use crate::mod_bam::{
     format_mm_ml_tag, CollapseMethod, ModBaseInfo, SkipMode, ML_TAGS, MM_TAGS,
 };
 use crate::mod_base_code::{DnaBase, ModCodeRepr};
 use crate::modbam_util::subcommands::EntryModBam;
 use crate::monoid::Moniod;
+use crate::motif_stats::subcommand::EntryMotifStats;
 use crate::motifs::subcommand::{EntryFindMotifs, EntryMotifs};
 use crate::pileup::subcommand::{DuplexModBamPileup, ModBamPileup};
 use crate::position_filter::StrandedPositionFilter;
+use crate::provenance::BasecallProvenance;
 use crate::read_ids_to_base_mod_probs::ReadIdsToBaseModProbs;
 use crate::reads_sampler::get_sampled_read_ids_to_base_mod_probs;
 use crate::reads_sampler::record_sampler::RecordSampler;
+use crate::reads_sampler::sampling_schedule::ContigQuotas;
 use crate::record_processor::RecordProcessor;
 use crate::repair_tags::RepairTags;
+use crate::run_summary;
+use crate::serve::subcommand::EntryServe;
 use crate::stats::subcommand::EntryStats;
 use crate::summarize::{sampled_reads_to_summary, ModSummary};
 use crate::threshold_mod_caller::MultipleThresholdModCaller;
 use crate::thresholds::{calc_thresholds_per_base, Percentiles};
 use crate::util::{
-    add_modkit_pg_records, format_errors_table, get_master_progress_bar,
-    get_targets, get_ticker, Region,
+    add_modkit_pg_records, create_out_directory, format_errors_table,
+    get_master_progress_bar, get_targets, get_ticker, standard_output_path,
+    AlignmentIdentityFilter, Region,
 };
 use crate::validate::subcommand::ValidateFromModBam;
 use crate::writers::{
-    MultiTableWriter, OutWriter, SampledProbs, TableWriter, TsvWriter,
+    write_histograms_tsv, write_summary_html, MultiTableWriter, OutWriter,
+    SampledProbs, TableFormat, TableWriter, TsvWriter,
 };
 use anyhow::{anyhow, bail, Context, Result as AnyhowResult};
 use clap::{Args, Subcommand, ValueEnum};
@@ -122,8 +136,27 @@ pub enum Commands {
     /// counts "localized" around genomic features of interest.
     #[clap(alias = "localise")]
     Localize(EntryLocalize),
+    /// Aggregate pileup counts over scaled gene bodies (or 5'UTR, CDS, and
+    /// 3'UTR) and their flanks from a GTF to make a metagene profile.
+    Metagene(EntryMetagene),
+    /// Calculate pairwise co-methylation (r-squared and mutual information)
+    /// between nearby motif positions from read-level base modification calls.
+    Comethyl(EntryComethyl),
     /// Calculate base modification levels over regions.
     Stats(EntryStats),
+    /// Calculate genome-wide modification levels per sequence motif (CpG,
+    /// CHG, CHH, GATC, or user-provided motifs) from a bedMethyl and
+    /// reference.
+    MotifStats(EntryMotifStats),
+    /// Summarize the distribution and uniformity (Gini coefficient and
+    /// Lorenz curve) of valid coverage per contig and genome-wide from a
+    /// bedMethyl, optionally over all motif sites in a reference.
+    Coverage(EntryCoverage),
+    /// Compare two bedMethyls site by site, reporting the differences in
+    /// fraction modified, correlation, and Bland-Altman limits of agreement
+    /// without any statistical testing. Useful for quick regression checks
+    /// when changing a pipeline.
+    ComparePileups(EntryComparePileups),
     /// Utilities to work with bedMethyl files
     #[clap(subcommand)]
     #[command(name = "bedmethyl", alias = "bm")]
@@ -132,6 +165,9 @@ pub enum Commands {
     #[clap(subcommand)]
     #[command(name = "modbam", alias = "mb")]
     ModBam(EntryModBam),
+    /// Serve pileup, extract, entropy, and bedMethyl region queries over HTTP
+    /// with JSON responses.
+    Serve(EntryServe),
 }
 
 impl Commands {
@@ -152,9 +188,15 @@ impl Commands {
             Self::Motif(x) => x.run(),
             Self::Entropy(x) => x.run(),
             Self::Localize(x) => x.run(),
+            Self::Metagene(x) => x.run(),
+            Self::Comethyl(x) => x.run(),
             Self::Stats(x) => x.run(),
+            Self::MotifStats(x) => x.run(),
+            Self::Coverage(x) => x.run(),
+            Self::ComparePileups(x) => x.run(),
             Self::BedMethyl(x) => x.run(),
             Self::ModBam(x) => x.run(),
+            Self::Serve(x) => x.run(),
         }
     }
 }
@@ -354,6 +396,14 @@ pub struct Adjust {
     #[clap(help_heading = "Modified Base Options")]
     #[arg(long, requires = "motif", default_value_t = false)]
     discard_motifs: bool,
+    /// Only adjust base modification calls aligned to reference positions
+    /// within the regions in this BED file, calls outside the regions (and
+    /// calls on unaligned bases) are left untouched. Applies to `--ignore`,
+    /// `--convert`, `--edge-filter`, `--filter-probs`, and `--motif`. When
+    /// the BED has a strand column, only calls on that strand are adjusted.
+    #[clap(help_heading = "Selection Options")]
+    #[arg(long)]
+    include_bed: Option<PathBuf>,
 
     /// Hide the progress bar.
     #[clap(help_heading = "Logging Options")]
@@ -495,6 +545,7 @@ impl Adjust {
                 pool.install(|| {
                     get_threshold_from_options(
                         &Path::new(&self.in_bam).to_path_buf(),
+                        None,
                         self.threads,
                         self.sampling_interval_size,
                         None,
@@ -508,6 +559,8 @@ impl Adjust {
                         methods.get(0),
                         None,
                         self.only_mapped,
+                        None,
+                        false,
                         self.suppress_progress,
                     )
                 })?
@@ -517,15 +570,38 @@ impl Adjust {
             None
         };
 
+        let position_filter = self
+            .include_bed
+            .as_ref()
+            .map(|bed_fp| {
+                let targets = get_targets(reader.header(), None);
+                let chrom_to_tid = targets
+                    .iter()
+                    .map(|reference_record| {
+                        (reference_record.name.as_str(), reference_record.tid)
+                    })
+                    .collect::<HashMap<&str, u32>>();
+                info!("only adjusting base modification calls in regions");
+                StrandedPositionFilter::from_bed_file(
+                    bed_fp,
+                    &chrom_to_tid,
+                    self.suppress_progress,
+                )
+            })
+            .transpose()?;
+
         adjust_modbam(
             &mut reader,
             &mut bam_writer,
+            self.threads,
             &methods,
             caller.as_ref(),
             edge_filter.as_ref(),
+            None,
             self.fail_fast,
             &motifs,
             self.discard_motifs,
+            position_filter.as_ref(),
             "Adjusting modBAM, records processed",
             self.suppress_progress,
             self.filter_probs,
@@ -582,6 +658,12 @@ pub struct SampleModBaseProbs {
     #[clap(help_heading = "Output Options")]
     #[arg(long, requires = "out_dir", default_value_t = false)]
     force: bool,
+    /// Format of the output tables, markdown tables can be pasted into
+    /// reports and csv is easiest to parse. When writing to --out-dir the
+    /// file extension follows the format.
+    #[clap(help_heading = "Output Options")]
+    #[arg(long, default_value_t = TableFormat::pretty)]
+    table_format: TableFormat,
     /// Ignore a modified base class  _in_situ_ by redistributing base
     /// modification probability equally across other options. For example,
     /// if collapsing 'h', with 'm' and canonical options, half of the
@@ -613,6 +695,13 @@ pub struct SampleModBaseProbs {
     #[clap(help_heading = "Output Options")]
     #[arg(long = "hist", requires = "out_dir", default_value_t = false)]
     histogram: bool,
+    /// Also write the thresholds to a JSON file in the output directory,
+    /// including the full percentile curve for each primary base (the
+    /// threshold at every 0.5 percentile), so that thresholds at other
+    /// percentiles can be looked up without sampling the modBAM again.
+    #[clap(help_heading = "Output Options")]
+    #[arg(long, requires = "out_dir", default_value_t = false)]
+    json: bool,
     /// Set colors of primary bases in histogram, should be RGB format, e.g.
     /// "#0000FF" is defailt for canonical cytosine
     #[clap(help_heading = "Output Options")]
@@ -652,6 +741,15 @@ pub struct SampleModBaseProbs {
     #[clap(help_heading = "Sampling Options")]
     #[arg(short, requires = "sampling_frac", long)]
     seed: Option<u64>,
+    /// How to divide the sampled reads between contigs when using an indexed
+    /// modBAM. reads: proportional to the number of mapped reads on each
+    /// contig, so sparse contigs (e.g. chrM or decoys) get few reads. length:
+    /// proportional to the length of each contig with mapped reads. Default
+    /// is reads for BAM and length for CRAM, using reads with a CRAM
+    /// requires counting the records first.
+    #[clap(help_heading = "Sampling Options")]
+    #[arg(long, hide_short_help = true)]
+    contig_quotas: Option<ContigQuotas>,
 
     /// Process only the specified region of the BAM when collecting
     /// probabilities. Format should be <chrom_name>:<start>-<end> or
@@ -690,6 +788,8 @@ impl SampleModBaseProbs {
                 self.prefix.as_ref(),
                 self.force,
                 self.histogram,
+                self.json,
+                self.table_format,
             )?;
         }
 
@@ -796,7 +896,7 @@ impl SampleModBaseProbs {
                     self.seed,
                 );
                 let read_ids_to_base_mod_probs =
-                    ReadIdsToBaseModProbs::process_records(
+                    ReadIdsToBaseModProbs::process_records_read_ahead(
                         reader.records(),
                         !self.suppress_progress,
                         record_sampler,
@@ -805,8 +905,6 @@ impl SampleModBaseProbs {
                         position_filter.as_ref(),
                         self.only_mapped || position_filter.is_some(),
                         false,
-                        None,
-                        None,
                     )?;
                 debug!("sampled {} records", read_ids_to_base_mod_probs.len());
                 read_ids_to_base_mod_probs
@@ -814,6 +912,7 @@ impl SampleModBaseProbs {
                 drop(reader);
                 get_sampled_read_ids_to_base_mod_probs::<ReadIdsToBaseModProbs>(
                     &Path::new(&self.in_bam).to_path_buf(),
+                    None,
                     self.threads,
                     self.interval_size,
                     sample_frac,
@@ -823,7 +922,9 @@ impl SampleModBaseProbs {
                     collapse_method.as_ref(),
                     edge_filter.as_ref(),
                     position_filter.as_ref(),
+                    None,
                     self.only_mapped || position_filter.is_some(),
+                    self.contig_quotas,
                     self.suppress_progress,
                 )?
             };
@@ -846,6 +947,13 @@ impl SampleModBaseProbs {
                 .progress_with(pb)
                 .map(|(canonical_base, mut probs)| {
                     Percentiles::new(&mut probs, &desired_percentiles)
+                        .and_then(|percs| {
+                            if self.json {
+                                percs.with_curve(&probs)
+                            } else {
+                                Ok(percs)
+                            }
+                        })
                         .with_context(|| {
                             format!(
                                 "failed to calculate threshold for base {}",
@@ -862,6 +970,7 @@ impl SampleModBaseProbs {
                 self.prefix.clone(),
                 extra_dna_colors,
                 extra_mod_colors,
+                self.table_format,
             );
 
             let mut writer: Box<dyn OutWriter<SampledProbs>> =
@@ -873,7 +982,7 @@ impl SampleModBaseProbs {
                     sampled_probs.check_path(p, self.force)?;
                     Box::new(MultiTableWriter::new(p.clone()))
                 } else {
-                    Box::new(TsvWriter::new_stdout(None))
+                    Box::new(TableWriter::new(self.table_format))
                 };
 
             writer.write(sampled_probs)?;
@@ -902,6 +1011,48 @@ pub struct ModSummarize {
     #[clap(help_heading = "Output Options")]
     #[arg(long = "tsv", default_value_t = false)]
     tsv_format: bool,
+    /// Format of the summary tables, markdown tables can be pasted into
+    /// reports and csv is easiest to parse.
+    #[clap(help_heading = "Output Options")]
+    #[arg(
+        long,
+        default_value_t = TableFormat::pretty,
+        conflicts_with = "tsv_format"
+    )]
+    table_format: TableFormat,
+    /// Also write an HTML report with the summary tables and histograms of
+    /// the sampled base modification probabilities to this file.
+    #[clap(help_heading = "Output Options")]
+    #[arg(long)]
+    html: Option<PathBuf>,
+    /// Write the summary into this directory instead of to stdout. The
+    /// summary is written to `<prefix>_summary.tsv` with `--tsv`, otherwise
+    /// to `<prefix>_summary.txt` (`.md` or `.csv` depending on
+    /// `--table-format`).
+    #[clap(help_heading = "Output Options")]
+    #[arg(long)]
+    out_dir: Option<PathBuf>,
+    /// Prefix the summary file name in `--out-dir` with this string.
+    #[clap(help_heading = "Output Options")]
+    #[arg(long, requires = "out_dir")]
+    prefix: Option<String>,
+    /// Also write the histograms of the sampled base modification
+    /// probabilities for each modification code to this TSV file. The table
+    /// is the same as the one written by `sample-probs --hist`.
+    #[clap(help_heading = "Output Options")]
+    #[arg(long)]
+    histograms_tsv: Option<PathBuf>,
+    /// Overwrite the HTML report, histograms TSV, or the summary in
+    /// `--out-dir` if they exist.
+    #[clap(help_heading = "Output Options")]
+    #[arg(long, default_value_t = false)]
+    force: bool,
+    /// Also report the modification calls split by the reference strand they
+    /// are aligned to, useful for spotting strand-biased calling. Only calls
+    /// on mapped reads are counted in the per-strand breakdown.
+    #[clap(help_heading = "Output Options")]
+    #[arg(long, default_value_t = false)]
+    by_strand: bool,
     /// Hide the progress bar.
     #[clap(help_heading = "Logging Options")]
     #[arg(long, default_value_t = false, hide_short_help = true)]
@@ -939,6 +1090,15 @@ pub struct ModSummarize {
     #[clap(help_heading = "Sampling Options")]
     #[arg(short, requires = "sampling_frac", long)]
     seed: Option<u64>,
+    /// How to divide the sampled reads between contigs when using an indexed
+    /// modBAM. reads: proportional to the number of mapped reads on each
+    /// contig, so sparse contigs (e.g. chrM or decoys) get few reads. length:
+    /// proportional to the length of each contig with mapped reads. Default
+    /// is reads for BAM and length for CRAM, using reads with a CRAM
+    /// requires counting the records first.
+    #[clap(help_heading = "Sampling Options")]
+    #[arg(long, hide_short_help = true)]
+    contig_quotas: Option<ContigQuotas>,
 
     // threshold options
     /// Do not perform any filtering, include all base modification calls in
@@ -1024,6 +1184,13 @@ pub struct ModSummarize {
     #[clap(help_heading = "Selection Options")]
     #[arg(long)]
     region: Option<String>,
+    /// BED file of regions to process. Only the parts of the (indexed) BAM
+    /// overlapping these regions are read, and all base modification calls
+    /// on the reads aligned to them are summarized. Much faster than
+    /// --include-bed for targeted panels.
+    #[clap(help_heading = "Selection Options")]
+    #[arg(long, conflicts_with = "region")]
+    regions: Option<PathBuf>,
     /// When using regions, interval chunk size in base pairs to process
     /// concurrently. Smaller interval chunk sizes will use less memory but
     /// incur more overhead.
@@ -1033,8 +1200,32 @@ pub struct ModSummarize {
 }
 
 impl ModSummarize {
+    /// Path of the summary file in `--out-dir`, if set.
+    fn out_path(&self) -> Option<PathBuf> {
+        self.out_dir.as_ref().map(|out_dir| {
+            let name = match (self.tsv_format, self.table_format) {
+                (true, _) => "summary.tsv",
+                (false, TableFormat::pretty) => "summary.txt",
+                (false, TableFormat::markdown) => "summary.md",
+                (false, TableFormat::csv) => "summary.csv",
+            };
+            standard_output_path(out_dir, self.prefix.as_ref(), name)
+        })
+    }
+
     pub fn run(&self) -> AnyhowResult<()> {
         let _handle = init_logging(self.log_filepath.as_ref());
+        for fp in self
+            .html
+            .iter()
+            .chain(self.histograms_tsv.iter())
+            .cloned()
+            .chain(self.out_path())
+        {
+            if fp.exists() && !self.force {
+                bail!("refusing to overwrite {fp:?}")
+            }
+        }
         let mut reader = get_serial_reader(&self.in_bam)?;
 
         let pool = rayon::ThreadPoolBuilder::new()
@@ -1083,6 +1274,20 @@ impl ModSummarize {
                 )
             })
             .transpose()?;
+        let traversal_regions = self
+            .regions
+            .as_ref()
+            .map(|bed_fp| {
+                if using_stream(&self.in_bam) {
+                    bail!("cannot use --regions when streaming the modBAM")
+                }
+                StrandedPositionFilter::from_bam_and_bed(
+                    &Path::new(&self.in_bam).to_path_buf(),
+                    bed_fp,
+                    self.suppress_progress,
+                )
+            })
+            .transpose()?;
 
         let filter_thresholds = if let Some(raw_thresholds) =
             &self.filter_threshold
@@ -1105,7 +1310,8 @@ impl ModSummarize {
                 None
             };
 
-        let mod_summary = pool.install(|| {
+        let provenance = BasecallProvenance::from_header_view(reader.header());
+        let (mod_summary, histograms) = pool.install(|| {
             let read_ids_to_base_mod_calls = if using_stream(&self.in_bam) {
                 reader.set_threads(self.threads)?;
                 let record_sampler = RecordSampler::new_from_options(
@@ -1114,7 +1320,7 @@ impl ModSummarize {
                     self.seed,
                 );
                 let read_ids_to_base_mod_probs =
-                    ReadIdsToBaseModProbs::process_records(
+                    ReadIdsToBaseModProbs::process_records_read_ahead(
                         reader.records(),
                         !self.suppress_progress,
                         record_sampler,
@@ -1123,8 +1329,6 @@ impl ModSummarize {
                         position_filter.as_ref(),
                         self.only_mapped || position_filter.is_some(),
                         false,
-                        None,
-                        None,
                     )?;
                 debug!("sampled {} records", read_ids_to_base_mod_probs.len());
                 read_ids_to_base_mod_probs
@@ -1132,6 +1336,7 @@ impl ModSummarize {
                 drop(reader);
                 get_sampled_read_ids_to_base_mod_probs::<ReadIdsToBaseModProbs>(
                     &Path::new(&self.in_bam).to_path_buf(),
+                    None,
                     self.threads,
                     self.interval_size,
                     sample_frac,
@@ -1141,7 +1346,11 @@ impl ModSummarize {
                     collapse_method.as_ref(),
                     edge_filter.as_ref(),
                     position_filter.as_ref(),
-                    self.only_mapped || position_filter.is_some(),
+                    traversal_regions.as_ref(),
+                    self.only_mapped
+                        || position_filter.is_some()
+                        || traversal_regions.is_some(),
+                    self.contig_quotas,
                     self.suppress_progress,
                 )?
             };
@@ -1161,19 +1370,59 @@ impl ModSummarize {
                 )?
             };
 
+            let with_histograms =
+                self.html.is_some() || self.histograms_tsv.is_some();
+            let histograms = with_histograms.then(|| {
+                read_ids_to_base_mod_calls
+                    .get_per_mod_histograms(self.suppress_progress)
+            });
+
             sampled_reads_to_summary(
                 read_ids_to_base_mod_calls,
                 &threshold_caller,
                 region.as_ref(),
+                self.by_strand,
+                provenance,
                 self.suppress_progress,
             )
+            .map(|summary| (summary, histograms))
         })?;
 
-        let mut writer: Box<dyn OutWriter<ModSummary>> = if self.tsv_format {
-            Box::new(TsvWriter::new_stdout(None))
-        } else {
-            Box::new(TableWriter::new())
-        };
+        if let Some(html_fp) = self.html.as_ref() {
+            create_out_directory(html_fp)?;
+            let mut fh = BufWriter::new(File::create(html_fp)?);
+            write_summary_html(
+                &mut fh,
+                &mod_summary,
+                histograms.as_ref(),
+                &format!("modkit summary: {}", self.in_bam),
+            )?;
+            info!("wrote HTML report to {html_fp:?}");
+        }
+        if let (Some(fp), Some(histograms)) =
+            (self.histograms_tsv.as_ref(), histograms.as_ref())
+        {
+            create_out_directory(fp)?;
+            write_histograms_tsv(
+                BufWriter::new(File::create(fp)?),
+                histograms,
+            )?;
+            info!("wrote probability histograms to {fp:?}");
+        }
+
+        let mut writer: Box<dyn OutWriter<ModSummary>> =
+            match (self.out_path(), self.tsv_format) {
+                (Some(fp), true) => {
+                    create_out_directory(&fp)?;
+                    Box::new(TsvWriter::new_path(&fp, true, None)?)
+                }
+                (Some(fp), false) => {
+                    create_out_directory(&fp)?;
+                    Box::new(TableWriter::new_file(&fp, self.table_format)?)
+                }
+                (None, true) => Box::new(TsvWriter::new_stdout(None)),
+                (None, false) => Box::new(TableWriter::new(self.table_format)),
+            };
         writer.write(mod_summary)?;
         Ok(())
     }
@@ -1292,8 +1541,16 @@ impl Update {
         let mut header = bam::Header::from_template(reader.header());
         add_modkit_pg_records(&mut header);
 
-        let mut out_bam =
-            get_bam_writer(&self.out_bam, &header, self.output_sam)?;
+        let mut out_bam = get_threaded_bam_writer(
+            &self.out_bam,
+            &header,
+            self.output_sam,
+            threads,
+        )?;
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .build()
+            .with_context(|| "failed to make threadpool")?;
         let spinner = get_ticker();
 
         spinner.set_message("Updating ModBAM");
@@ -1317,42 +1574,37 @@ impl Update {
             }
         };
 
-        let record_iter = reader
+        let no_implicit_probs = self.no_implicit_probs;
+        let records = reader
             .records()
             .map(|res| res.map_err(|e| MkError::HtsLibError(e)))
-            .map(|res| {
-                res.and_then(|record| {
-                    update_mod_tags(record, self.no_implicit_probs, to_mode)
-                })
-            });
-
-        for (i, result) in record_iter.enumerate() {
-            match result {
-                Ok(record) => {
-                    match out_bam
-                        .write(&record)
-                        .map_err(|e| MkError::HtsLibError(e))
-                    {
-                        Ok(_) => {
-                            spinner.inc(1);
-                            total = i + 1;
-                        }
-                        Err(mk_error) => {
-                            error_counts
-                                .entry(mk_error.to_string())
-                                .or_insert(0usize)
-                                .add_assign(1usize);
-                        }
+            .enumerate();
+        OrderedRecordPipeline::new(&pool).run(
+            records,
+            |(i, res)| {
+                let updated = res.and_then(|record| {
+                    update_mod_tags(record, no_implicit_probs, to_mode)
+                });
+                (i, updated)
+            },
+            |(i, result)| {
+                match result.and_then(|record| {
+                    out_bam.write(&record).map_err(|e| MkError::HtsLibError(e))
+                }) {
+                    Ok(_) => {
+                        spinner.inc(1);
+                        total = i + 1;
+                    }
+                    Err(mk_error) => {
+                        error_counts
+                            .entry(mk_error.to_string())
+                            .or_insert(0usize)
+                            .add_assign(1usize);
                     }
                 }
-                Err(mk_error) => {
-                    error_counts
-                        .entry(mk_error.to_string())
-                        .or_insert(0usize)
-                        .add_assign(1usize);
-                }
-            }
-        }
+                Ok(())
+            },
+        )?;
 
         spinner.finish_and_clear();
 
@@ -1360,6 +1612,7 @@ impl Update {
 
         if !error_counts.is_empty() {
             info!("error/skip counts:");
+            run_summary::record_errors(&error_counts);
             let error_table = format_errors_table(&error_counts);
             info!("\n{error_table}");
         }
@@ -1535,6 +1788,18 @@ pub struct CallMods {
     /// of keeping them).
     #[arg(long, requires = "motif", default_value_t = false)]
     discard_motifs: bool,
+    /// Only make calls on mapped reads with at least this alignment identity,
+    /// the fraction of aligned columns (matches, mismatches, insertions, and
+    /// deletions) that aren't edits according to the NM tag. Reads that fail
+    /// are written with their base modification tags removed. Reads without
+    /// an NM tag fail. All reads are used when estimating the threshold.
+    #[arg(long)]
+    min_identity: Option<f32>,
+    /// Only make calls on mapped reads with at most this many edits (NM tag)
+    /// to the reference. Reads that fail are written with their base
+    /// modification tags removed.
+    #[arg(long)]
+    max_nm: Option<u32>,
 
     /// Output SAM format instead of BAM.
     #[arg(long, default_value_t = false)]
@@ -1562,6 +1827,10 @@ impl CallMods {
             .as_ref()
             .map(|raw| parse_edge_filter_input(raw, self.invert_edge_filter))
             .transpose()?;
+        let alignment_filter = AlignmentIdentityFilter::from_options(
+            self.min_identity,
+            self.max_nm,
+        )?;
 
         let per_mod_thresholds =
             if let Some(raw_per_mod_thresholds) = &self.mod_thresholds {
@@ -1599,6 +1868,7 @@ impl CallMods {
             pool.install(|| {
                 get_threshold_from_options(
                     &Path::new(&self.in_bam).to_path_buf(),
+                    None,
                     self.threads,
                     self.sampling_interval_size,
                     self.sampling_frac,
@@ -1612,6 +1882,8 @@ impl CallMods {
                     None,
                     None,
                     false,
+                    None,
+                    false,
                     self.suppress_progress,
                 )
             })?
@@ -1620,12 +1892,15 @@ impl CallMods {
         adjust_modbam(
             &mut reader,
             &mut bam_writer,
+            self.threads,
             &[],
             Some(&caller),
             edge_filter.as_ref(),
+            alignment_filter.as_ref(),
             self.fail_fast,
             &motifs,
             self.discard_motifs,
+            None,
             "Calling Mods, records processed",
             self.suppress_progress,
             false,
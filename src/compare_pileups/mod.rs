@@ -0,0 +1,439 @@
+use std::collections::BTreeMap;
+use std::io::BufRead;
+
+use indicatif::ProgressBar;
+use log::debug;
+use rustc_hash::FxHashMap;
+
+use crate::dmr::bedmethyl::BedMethylLine;
+use crate::mod_base_code::ModCodeRepr;
+use crate::util::StrandRule;
+
+pub mod subcommand;
+
+/// Valid coverage and number of modified calls of a site, keyed by contig
+/// then start, strand, and modification code.
+pub(super) type PileupLookup =
+    FxHashMap<String, FxHashMap<(u64, StrandRule, ModCodeRepr), (u64, u64)>>;
+
+/// Which records of the bedMethyls to compare.
+#[derive(Debug, Copy, Clone)]
+pub(super) struct RecordFilter {
+    pub(super) min_coverage: u64,
+    pub(super) mod_code: Option<ModCodeRepr>,
+}
+
+impl RecordFilter {
+    pub(super) fn keep(&self, record: &BedMethylLine) -> bool {
+        record.valid_coverage >= self.min_coverage.max(1)
+            && self.mod_code.map(|c| c == record.raw_mod_code).unwrap_or(true)
+    }
+}
+
+/// Stream the records of a bedMethyl that pass the `filter`, records that
+/// fail to parse are counted and skipped.
+pub(super) fn for_each_record(
+    reader: impl BufRead,
+    filter: &RecordFilter,
+    pb: &ProgressBar,
+    mut f: impl FnMut(BedMethylLine) -> anyhow::Result<()>,
+) -> anyhow::Result<usize> {
+    let mut n_failed = 0usize;
+    for line in reader.lines() {
+        let line = line?;
+        if line.starts_with('#') || line.starts_with("track") {
+            continue;
+        }
+        pb.inc(1);
+        match BedMethylLine::parse(&line) {
+            Ok(record) if filter.keep(&record) => f(record)?,
+            Ok(_) => {}
+            Err(e) => {
+                debug!("{e}");
+                n_failed += 1;
+            }
+        }
+    }
+    Ok(n_failed)
+}
+
+/// Running sums of the fraction modified in the two bedMethyls at shared
+/// sites, used to calculate the agreement and Bland-Altman statistics. The
+/// difference is always the second bedMethyl minus the first.
+#[derive(Debug, Default, Copy, Clone)]
+pub(super) struct DeltaStats {
+    n: u64,
+    sum_a: f64,
+    sum_b: f64,
+    sum_a_sq: f64,
+    sum_b_sq: f64,
+    sum_ab: f64,
+    sum_abs_delta: f64,
+}
+
+impl DeltaStats {
+    fn add(&mut self, a: f64, b: f64) {
+        self.n += 1;
+        self.sum_a += a;
+        self.sum_b += b;
+        self.sum_a_sq += a * a;
+        self.sum_b_sq += b * b;
+        self.sum_ab += a * b;
+        self.sum_abs_delta += (b - a).abs();
+    }
+
+    fn combine(&mut self, other: &Self) {
+        self.n += other.n;
+        self.sum_a += other.sum_a;
+        self.sum_b += other.sum_b;
+        self.sum_a_sq += other.sum_a_sq;
+        self.sum_b_sq += other.sum_b_sq;
+        self.sum_ab += other.sum_ab;
+        self.sum_abs_delta += other.sum_abs_delta;
+    }
+
+    /// Pearson correlation, NaN when there are fewer than 2 sites or either
+    /// set of values is constant.
+    fn pearson_r(&self) -> f64 {
+        if self.n < 2 {
+            return f64::NAN;
+        }
+        let n = self.n as f64;
+        let cov = self.sum_ab - self.sum_a * self.sum_b / n;
+        let var_a = self.sum_a_sq - self.sum_a * self.sum_a / n;
+        let var_b = self.sum_b_sq - self.sum_b * self.sum_b / n;
+        if var_a <= 0f64 || var_b <= 0f64 {
+            f64::NAN
+        } else {
+            cov / (var_a * var_b).sqrt()
+        }
+    }
+
+    fn sum_delta_sq(&self) -> f64 {
+        (self.sum_b_sq - 2f64 * self.sum_ab + self.sum_a_sq).max(0f64)
+    }
+
+    fn mean_delta(&self) -> f64 {
+        (self.sum_b - self.sum_a) / self.n as f64
+    }
+
+    /// Sample standard deviation of the differences, NaN with fewer than 2
+    /// sites.
+    fn sd_delta(&self) -> f64 {
+        if self.n < 2 {
+            return f64::NAN;
+        }
+        let n = self.n as f64;
+        let sum_delta = self.sum_b - self.sum_a;
+        let var =
+            (self.sum_delta_sq() - sum_delta * sum_delta / n) / (n - 1f64);
+        var.max(0f64).sqrt()
+    }
+
+    fn mean_abs_delta(&self) -> f64 {
+        self.sum_abs_delta / self.n as f64
+    }
+
+    fn rmsd(&self) -> f64 {
+        (self.sum_delta_sq() / self.n as f64).sqrt()
+    }
+
+    /// Bland-Altman 95% limits of agreement, mean difference +/- 1.96
+    /// standard deviations.
+    fn limits_of_agreement(&self) -> (f64, f64) {
+        let mean = self.mean_delta();
+        let sd = self.sd_delta();
+        (mean - 1.96 * sd, mean + 1.96 * sd)
+    }
+
+    fn fields(&self) -> String {
+        let n = self.n as f64;
+        let (loa_lower, loa_upper) = self.limits_of_agreement();
+        [
+            self.pearson_r(),
+            self.sum_a / n,
+            self.sum_b / n,
+            self.mean_delta(),
+            self.sd_delta(),
+            self.mean_abs_delta(),
+            self.rmsd(),
+            loa_lower,
+            loa_upper,
+        ]
+        .iter()
+        .map(|x| format!("{x:.6}"))
+        .collect::<Vec<String>>()
+        .join("\t")
+    }
+}
+
+/// Site counts and agreement for one modification code.
+#[derive(Debug, Clone)]
+pub(super) struct CodeComparison {
+    n_sites_a: u64,
+    n_sites_b: u64,
+    sum_coverage_a: u64,
+    sum_coverage_b: u64,
+    shared: DeltaStats,
+    bins: Vec<DeltaStats>,
+}
+
+impl CodeComparison {
+    fn new(n_bins: usize) -> Self {
+        Self {
+            n_sites_a: 0,
+            n_sites_b: 0,
+            sum_coverage_a: 0,
+            sum_coverage_b: 0,
+            shared: DeltaStats::default(),
+            bins: vec![DeltaStats::default(); n_bins],
+        }
+    }
+
+    fn combine(&mut self, other: &Self) {
+        self.n_sites_a += other.n_sites_a;
+        self.n_sites_b += other.n_sites_b;
+        self.sum_coverage_a += other.sum_coverage_a;
+        self.sum_coverage_b += other.sum_coverage_b;
+        self.shared.combine(&other.shared);
+        for (bin, other_bin) in self.bins.iter_mut().zip(other.bins.iter()) {
+            bin.combine(other_bin);
+        }
+    }
+
+    fn row(&self, label: &str) -> String {
+        let n_shared = self.shared.n;
+        let mean_coverage = |sum: u64| sum as f64 / n_shared as f64;
+        format!(
+            "{label}\t{}\t{}\t{n_shared}\t{}\t{}\t{:.3}\t{:.3}\t{}",
+            self.n_sites_a,
+            self.n_sites_b,
+            self.n_sites_a - n_shared,
+            self.n_sites_b - n_shared,
+            mean_coverage(self.sum_coverage_a),
+            mean_coverage(self.sum_coverage_b),
+            self.shared.fields()
+        )
+    }
+}
+
+/// Comparison of two bedMethyls, per modification code.
+pub(super) struct PileupComparison {
+    per_code: BTreeMap<ModCodeRepr, CodeComparison>,
+    n_bins: usize,
+}
+
+impl PileupComparison {
+    pub(super) fn new(n_bins: usize) -> Self {
+        Self { per_code: BTreeMap::new(), n_bins: n_bins.max(1) }
+    }
+
+    fn code_comparison(
+        &mut self,
+        mod_code: ModCodeRepr,
+    ) -> &mut CodeComparison {
+        let n_bins = self.n_bins;
+        self.per_code
+            .entry(mod_code)
+            .or_insert_with(|| CodeComparison::new(n_bins))
+    }
+
+    pub(super) fn add_site_a(&mut self, mod_code: ModCodeRepr) {
+        self.code_comparison(mod_code).n_sites_a += 1;
+    }
+
+    pub(super) fn add_site_b(&mut self, mod_code: ModCodeRepr) {
+        self.code_comparison(mod_code).n_sites_b += 1;
+    }
+
+    /// Add a site in both bedMethyls given as (valid coverage, number
+    /// modified).
+    pub(super) fn add_shared(
+        &mut self,
+        mod_code: ModCodeRepr,
+        counts_a: (u64, u64),
+        counts_b: (u64, u64),
+    ) {
+        let frac_a = counts_a.1 as f64 / counts_a.0 as f64;
+        let frac_b = counts_b.1 as f64 / counts_b.0 as f64;
+        let bin = bland_altman_bin((frac_a + frac_b) / 2f64, self.n_bins);
+        let comparison = self.code_comparison(mod_code);
+        comparison.sum_coverage_a += counts_a.0;
+        comparison.sum_coverage_b += counts_b.0;
+        comparison.shared.add(frac_a, frac_b);
+        comparison.bins[bin].add(frac_a, frac_b);
+    }
+
+    fn iter_rows(&self) -> impl Iterator<Item = (String, &CodeComparison)> {
+        self.per_code
+            .iter()
+            .map(|(code, comparison)| (code.to_string(), comparison))
+    }
+
+    fn all_codes(&self) -> CodeComparison {
+        self.per_code.values().fold(
+            CodeComparison::new(self.n_bins),
+            |mut acc, comparison| {
+                acc.combine(comparison);
+                acc
+            },
+        )
+    }
+
+    pub(super) fn n_shared(&self) -> u64 {
+        self.per_code.values().map(|c| c.shared.n).sum()
+    }
+
+    pub(super) fn summary_header() -> String {
+        [
+            "mod_code",
+            "n_sites_a",
+            "n_sites_b",
+            "n_shared",
+            "n_only_a",
+            "n_only_b",
+            "mean_coverage_a",
+            "mean_coverage_b",
+            "pearson_r",
+            "mean_frac_a",
+            "mean_frac_b",
+            "mean_delta",
+            "sd_delta",
+            "mean_abs_delta",
+            "rmsd",
+            "loa_lower",
+            "loa_upper",
+        ]
+        .join("\t")
+    }
+
+    /// One row per modification code and a row for all codes together.
+    pub(super) fn summary_rows(&self) -> Vec<String> {
+        let all = self.all_codes();
+        self.iter_rows()
+            .map(|(label, comparison)| comparison.row(&label))
+            .chain(std::iter::once(all.row("all")))
+            .collect()
+    }
+
+    pub(super) fn bland_altman_header() -> String {
+        [
+            "mod_code",
+            "bin_start",
+            "bin_end",
+            "n_sites",
+            "mean_delta",
+            "sd_delta",
+            "loa_lower",
+            "loa_upper",
+        ]
+        .join("\t")
+    }
+
+    /// The differences binned by the mean fraction modified of the two
+    /// bedMethyls, empty bins are skipped.
+    pub(super) fn bland_altman_rows(&self) -> Vec<String> {
+        let width = 1f64 / self.n_bins as f64;
+        self.iter_rows()
+            .flat_map(|(label, comparison)| {
+                comparison
+                    .bins
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, stats)| stats.n > 0)
+                    .map(|(i, stats)| {
+                        let (loa_lower, loa_upper) =
+                            stats.limits_of_agreement();
+                        [
+                            label.clone(),
+                            format!("{:.3}", i as f64 * width),
+                            format!("{:.3}", (i + 1) as f64 * width),
+                            stats.n.to_string(),
+                            format!("{:.6}", stats.mean_delta()),
+                            format!("{:.6}", stats.sd_delta()),
+                            format!("{loa_lower:.6}"),
+                            format!("{loa_upper:.6}"),
+                        ]
+                        .join("\t")
+                    })
+                    .collect::<Vec<String>>()
+            })
+            .collect()
+    }
+}
+
+fn bland_altman_bin(mean_frac: f64, n_bins: usize) -> usize {
+    ((mean_frac * n_bins as f64) as usize).min(n_bins - 1)
+}
+
+pub(super) fn site_header() -> String {
+    [
+        "chrom",
+        "start",
+        "end",
+        "strand",
+        "mod_code",
+        "valid_coverage_a",
+        "valid_coverage_b",
+        "frac_mod_a",
+        "frac_mod_b",
+        "delta",
+        "mean",
+    ]
+    .join("\t")
+}
+
+pub(super) fn site_row(record: &BedMethylLine, counts_b: (u64, u64)) -> String {
+    let frac_a = record.count_methylated as f64 / record.valid_coverage as f64;
+    let frac_b = counts_b.1 as f64 / counts_b.0 as f64;
+    format!(
+        "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{frac_a:.6}\t{frac_b:.6}\t{:.6}\t{:.6}",
+        record.chrom,
+        record.start(),
+        record.stop(),
+        record.strand,
+        record.raw_mod_code,
+        record.valid_coverage,
+        counts_b.0,
+        frac_b - frac_a,
+        (frac_a + frac_b) / 2f64,
+    )
+}
+
+#[cfg(test)]
+mod compare_pileups_tests {
+    use crate::compare_pileups::{bland_altman_bin, DeltaStats};
+
+    #[test]
+    fn test_delta_stats() {
+        let mut stats = DeltaStats::default();
+        for (a, b) in [(0.1, 0.2), (0.5, 0.5), (0.9, 0.7)] {
+            stats.add(a, b);
+        }
+        assert!((stats.mean_delta() - (-0.1 / 3.0)).abs() < 1e-9);
+        assert!((stats.mean_abs_delta() - 0.1).abs() < 1e-9);
+        assert!((stats.rmsd() - (0.05f64 / 3.0).sqrt()).abs() < 1e-9);
+        let deltas = [0.1, 0.0, -0.2];
+        let mean = deltas.iter().sum::<f64>() / 3.0;
+        let sd = (deltas.iter().map(|d| (d - mean) * (d - mean)).sum::<f64>()
+            / 2.0)
+            .sqrt();
+        assert!((stats.sd_delta() - sd).abs() < 1e-9);
+        let (lower, upper) = stats.limits_of_agreement();
+        assert!((upper - lower - 2.0 * 1.96 * sd).abs() < 1e-9);
+        assert!(stats.pearson_r() > 0.9);
+
+        let mut single = DeltaStats::default();
+        single.add(0.5, 0.5);
+        assert!(single.sd_delta().is_nan());
+        assert!(single.pearson_r().is_nan());
+    }
+
+    #[test]
+    fn test_bland_altman_bin() {
+        assert_eq!(bland_altman_bin(0.0, 10), 0);
+        assert_eq!(bland_altman_bin(0.55, 10), 5);
+        assert_eq!(bland_altman_bin(1.0, 10), 9);
+        assert_eq!(bland_altman_bin(1.0, 1), 0);
+    }
+}
@@ -0,0 +1,213 @@
+use std::fs::File;
+use std::io::{stdout, BufRead, BufReader, BufWriter, Write};
+use std::path::PathBuf;
+
+use anyhow::{bail, Context};
+use clap::Args;
+use indicatif::{MultiProgress, ProgressDrawTarget};
+use log::{info, warn};
+
+use crate::compare_pileups::{
+    for_each_record, site_header, site_row, PileupComparison, PileupLookup,
+    RecordFilter,
+};
+use crate::logging::init_logging;
+use crate::mod_base_code::ModCodeRepr;
+use crate::util::{create_out_directory, get_ticker};
+
+#[derive(Args)]
+#[command(arg_required_else_help = true)]
+pub struct EntryComparePileups {
+    /// First bedMethyl table (for example from the current pipeline), can be
+    /// plain text or bgzip-compressed.
+    bedmethyl_a: PathBuf,
+    /// Second bedMethyl table to compare to the first, can be plain text or
+    /// bgzip-compressed. All of the records are loaded into memory.
+    bedmethyl_b: PathBuf,
+    /// Only compare sites with at least this valid coverage in both
+    /// bedMethyls.
+    #[clap(help_heading = "Selection Options")]
+    #[arg(long, default_value_t = 1)]
+    min_coverage: u64,
+    /// Only compare records with this modification code (e.g. m). By
+    /// default all codes are compared, each one separately.
+    #[clap(help_heading = "Selection Options")]
+    #[arg(long)]
+    mod_code: Option<String>,
+    /// Optionally specify a file to write the summary to, default is stdout.
+    #[clap(help_heading = "Output Options")]
+    #[arg(long, short = 'o')]
+    out_file: Option<PathBuf>,
+    /// Write the fraction modified in both bedMethyls and their difference
+    /// for each shared site to this file.
+    #[clap(help_heading = "Output Options")]
+    #[arg(long)]
+    sites: Option<PathBuf>,
+    /// Write the differences binned by the mean fraction modified of the two
+    /// bedMethyls (for a Bland-Altman plot) to this file.
+    #[clap(help_heading = "Output Options")]
+    #[arg(long)]
+    bland_altman: Option<PathBuf>,
+    /// Number of evenly spaced bins of the mean fraction modified.
+    #[clap(help_heading = "Output Options")]
+    #[arg(long, default_value_t = 10, requires = "bland_altman")]
+    bins: usize,
+    /// Force overwrite of existing output files.
+    #[clap(help_heading = "Output Options")]
+    #[arg(long, short = 'f', default_value_t = false)]
+    force: bool,
+    /// Don't add the header describing the columns to the output.
+    #[clap(help_heading = "Output Options")]
+    #[arg(long, default_value_t = false)]
+    no_header: bool,
+    /// Hide the progress bar.
+    #[clap(help_heading = "Logging Options")]
+    #[arg(long, default_value_t = false, hide_short_help = true)]
+    suppress_progress: bool,
+    /// Specify a file to write debug logs to.
+    #[clap(help_heading = "Logging Options")]
+    #[arg(long, alias = "log")]
+    log_filepath: Option<PathBuf>,
+}
+
+impl EntryComparePileups {
+    fn create_writer(&self, fp: &PathBuf) -> anyhow::Result<BufWriter<File>> {
+        create_out_directory(fp)?;
+        if fp.exists() && !self.force {
+            bail!("refusing to overwrite existing file {fp:?}")
+        }
+        Ok(BufWriter::new(File::create(fp)?))
+    }
+
+    fn open_bedmethyl(fp: &PathBuf) -> anyhow::Result<impl BufRead> {
+        let reader = rust_htslib::bgzf::Reader::from_path(fp)
+            .with_context(|| format!("failed to open {fp:?}"))?;
+        Ok(BufReader::new(reader))
+    }
+
+    pub fn run(&self) -> anyhow::Result<()> {
+        let _handle = init_logging(self.log_filepath.as_ref());
+        if self.bins == 0 {
+            bail!("--bins must be at least 1")
+        }
+        let filter = RecordFilter {
+            min_coverage: self.min_coverage,
+            mod_code: self
+                .mod_code
+                .as_ref()
+                .map(|raw| ModCodeRepr::parse(raw))
+                .transpose()?,
+        };
+        let mut writer: Box<dyn Write> = match self.out_file.as_ref() {
+            Some(out_fp) => Box::new(self.create_writer(out_fp)?),
+            None => Box::new(BufWriter::new(stdout())),
+        };
+        let mut sites_writer =
+            self.sites.as_ref().map(|fp| self.create_writer(fp)).transpose()?;
+        let mut bland_altman_writer = self
+            .bland_altman
+            .as_ref()
+            .map(|fp| self.create_writer(fp))
+            .transpose()?;
+
+        let mpb = MultiProgress::new();
+        if self.suppress_progress {
+            mpb.set_draw_target(ProgressDrawTarget::hidden());
+        }
+        let pb = mpb.add(get_ticker());
+        let mut comparison = PileupComparison::new(self.bins);
+
+        pb.set_message(format!("{:?} records loaded", self.bedmethyl_b));
+        let mut lookup = PileupLookup::default();
+        let n_failed_b = for_each_record(
+            Self::open_bedmethyl(&self.bedmethyl_b)?,
+            &filter,
+            &pb,
+            |record| {
+                comparison.add_site_b(record.raw_mod_code);
+                lookup.entry(record.chrom).or_default().insert(
+                    (record.interval.start, record.strand, record.raw_mod_code),
+                    (record.valid_coverage, record.count_methylated),
+                );
+                Ok(())
+            },
+        )?;
+        pb.reset();
+
+        if let Some(sites_writer) = sites_writer.as_mut() {
+            if !self.no_header {
+                writeln!(sites_writer, "{}", site_header())?;
+            }
+        }
+        pb.set_message(format!("{:?} records compared", self.bedmethyl_a));
+        let n_failed_a = for_each_record(
+            Self::open_bedmethyl(&self.bedmethyl_a)?,
+            &filter,
+            &pb,
+            |record| {
+                comparison.add_site_a(record.raw_mod_code);
+                let counts_b =
+                    lookup.get_mut(&record.chrom).and_then(|sites| {
+                        sites.remove(&(
+                            record.interval.start,
+                            record.strand,
+                            record.raw_mod_code,
+                        ))
+                    });
+                if let Some(counts_b) = counts_b {
+                    comparison.add_shared(
+                        record.raw_mod_code,
+                        (record.valid_coverage, record.count_methylated),
+                        counts_b,
+                    );
+                    if let Some(sites_writer) = sites_writer.as_mut() {
+                        writeln!(
+                            sites_writer,
+                            "{}",
+                            site_row(&record, counts_b)
+                        )?;
+                    }
+                }
+                Ok(())
+            },
+        )?;
+        pb.finish_and_clear();
+        if n_failed_a > 0 || n_failed_b > 0 {
+            warn!(
+                "{n_failed_a} record(s) in {:?} and {n_failed_b} record(s) in \
+                 {:?} failed to parse",
+                self.bedmethyl_a, self.bedmethyl_b
+            );
+        }
+        if comparison.n_shared() == 0 {
+            warn!("zero sites are in both bedMethyls");
+        }
+
+        if !self.no_header {
+            writeln!(writer, "{}", PileupComparison::summary_header())?;
+        }
+        for row in comparison.summary_rows() {
+            writeln!(writer, "{row}")?;
+        }
+        writer.flush()?;
+        if let Some(sites_writer) = sites_writer.as_mut() {
+            sites_writer.flush()?;
+        }
+        if let Some(bland_altman_writer) = bland_altman_writer.as_mut() {
+            if !self.no_header {
+                writeln!(
+                    bland_altman_writer,
+                    "{}",
+                    PileupComparison::bland_altman_header()
+                )?;
+            }
+            for row in comparison.bland_altman_rows() {
+                writeln!(bland_altman_writer, "{row}")?;
+            }
+            bland_altman_writer.flush()?;
+        }
+        info!("finished, compared {} shared site(s)", comparison.n_shared());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,441 @@
+use std::collections::BTreeMap;
+use std::io::BufRead;
+
+use anyhow::bail;
+use indicatif::ProgressBar;
+use itertools::Itertools;
+use log::debug;
+use log_once::warn_once;
+use rustc_hash::FxHashSet;
+
+use crate::dmr::bedmethyl::BedMethylLine;
+use crate::fasta::ReferenceSequences;
+use crate::monoid::Moniod;
+use crate::motifs::motif_bed::{find_motif_hits, RegexMotif};
+use crate::util::{Strand, StrandRule};
+
+pub mod subcommand;
+
+/// Number of sites with each valid coverage.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub(super) struct CoverageHistogram {
+    counts: BTreeMap<u64, u64>,
+}
+
+impl CoverageHistogram {
+    fn add(&mut self, coverage: u64, n_sites: u64) {
+        if n_sites > 0 {
+            *self.counts.entry(coverage).or_insert(0) += n_sites;
+        }
+    }
+
+    fn n_sites(&self) -> u64 {
+        self.counts.values().sum()
+    }
+
+    fn total_coverage(&self) -> u64 {
+        self.counts.iter().map(|(cov, n)| cov * n).sum()
+    }
+
+    fn mean(&self) -> f64 {
+        let n = self.n_sites();
+        if n == 0 {
+            0f64
+        } else {
+            self.total_coverage() as f64 / n as f64
+        }
+    }
+
+    /// Smallest coverage where at least `q` of the sites have at most that
+    /// coverage.
+    fn quantile(&self, q: f64) -> u64 {
+        let n = self.n_sites();
+        let rank = ((q * n as f64).ceil() as u64).max(1);
+        let mut seen = 0u64;
+        for (cov, count) in self.counts.iter() {
+            seen += count;
+            if seen >= rank {
+                return *cov;
+            }
+        }
+        0
+    }
+
+    fn fraction_at_least(&self, threshold: u64) -> f64 {
+        let n = self.n_sites();
+        if n == 0 {
+            0f64
+        } else {
+            let above =
+                self.counts.range(threshold..).map(|(_, n)| *n).sum::<u64>();
+            above as f64 / n as f64
+        }
+    }
+
+    /// Gini coefficient of the coverage over sites, 0 when every site has
+    /// the same coverage and approaching 1 when all of the coverage is at a
+    /// single site.
+    fn gini(&self) -> f64 {
+        let n = self.n_sites();
+        let total = self.total_coverage();
+        if n == 0 || total == 0 {
+            return 0f64;
+        }
+        // sum of rank * coverage with sites sorted by coverage, ranks
+        // starting at 1
+        let mut rank = 0u64;
+        let mut weighted = 0f64;
+        for (cov, count) in self.counts.iter() {
+            let rank_sum = count * rank + count * (count + 1) / 2;
+            weighted += rank_sum as f64 * *cov as f64;
+            rank += count;
+        }
+        let n = n as f64;
+        (2f64 * weighted) / (n * total as f64) - (n + 1f64) / n
+    }
+
+    /// Points on the Lorenz curve, the fraction of the total coverage in
+    /// the lowest-coverage fraction of sites, at `n_points` evenly spaced
+    /// fractions of sites.
+    fn lorenz_curve(&self, n_points: usize) -> Vec<(f64, f64)> {
+        let n = self.n_sites();
+        let total = self.total_coverage();
+        // (sites, coverage) before each bucket and the bucket coverage
+        let mut cumulative = Vec::with_capacity(self.counts.len());
+        let (mut sites, mut coverage) = (0u64, 0u64);
+        for (cov, count) in self.counts.iter() {
+            cumulative.push((sites, coverage, *cov));
+            sites += count;
+            coverage += cov * count;
+        }
+        (0..=n_points)
+            .map(|i| {
+                let frac_sites = i as f64 / n_points as f64;
+                let target = (frac_sites * n as f64).round() as u64;
+                let idx =
+                    cumulative.partition_point(|(before, ..)| *before < target);
+                let coverage = match idx.checked_sub(1) {
+                    Some(idx) => {
+                        let (before, cov_before, cov) = cumulative[idx];
+                        cov_before + (target - before) * cov
+                    }
+                    None => 0,
+                };
+                let frac_coverage = if total == 0 {
+                    0f64
+                } else {
+                    coverage as f64 / total as f64
+                };
+                (frac_sites, frac_coverage)
+            })
+            .collect()
+    }
+}
+
+impl Moniod for CoverageHistogram {
+    fn zero() -> Self {
+        Self::default()
+    }
+
+    fn op(self, other: Self) -> Self {
+        let mut this = self;
+        this.op_mut(other);
+        this
+    }
+
+    fn op_mut(&mut self, other: Self) {
+        for (cov, n) in other.counts {
+            self.add(cov, n);
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.counts.len()
+    }
+}
+
+/// Coverage distribution of one contig, or the whole genome.
+pub(super) struct ContigCoverage {
+    pub(super) name: String,
+    pub(super) histogram: CoverageHistogram,
+}
+
+impl ContigCoverage {
+    pub(super) fn header(thresholds: &[u64]) -> String {
+        let mut fields = [
+            "contig",
+            "n_sites",
+            "n_covered",
+            "mean_coverage",
+            "p10_coverage",
+            "median_coverage",
+            "p90_coverage",
+            "gini",
+        ]
+        .into_iter()
+        .map(|x| x.to_string())
+        .collect::<Vec<String>>();
+        fields.extend(thresholds.iter().map(|t| format!("frac_ge_{t}")));
+        fields.join("\t")
+    }
+
+    pub(super) fn row(&self, thresholds: &[u64]) -> String {
+        let hist = &self.histogram;
+        let mut fields = vec![
+            self.name.to_string(),
+            hist.n_sites().to_string(),
+            (hist.n_sites() - hist.counts.get(&0).copied().unwrap_or(0))
+                .to_string(),
+            format!("{:.3}", hist.mean()),
+            hist.quantile(0.1).to_string(),
+            hist.quantile(0.5).to_string(),
+            hist.quantile(0.9).to_string(),
+            format!("{:.4}", hist.gini()),
+        ];
+        fields.extend(
+            thresholds
+                .iter()
+                .map(|t| format!("{:.4}", hist.fraction_at_least(*t))),
+        );
+        fields.join("\t")
+    }
+
+    pub(super) fn lorenz_rows(&self, n_points: usize) -> Vec<String> {
+        self.histogram
+            .lorenz_curve(n_points)
+            .into_iter()
+            .map(|(frac_sites, frac_coverage)| {
+                format!("{}\t{frac_sites:.4}\t{frac_coverage:.4}", self.name)
+            })
+            .collect()
+    }
+}
+
+/// Motif sites in the reference, used to count sites without any coverage
+/// in the bedMethyl.
+pub(super) struct MotifSites {
+    motifs: Vec<RegexMotif>,
+    reference: ReferenceSequences,
+    combined_strands: bool,
+}
+
+impl MotifSites {
+    pub(super) fn new(
+        motifs: Vec<RegexMotif>,
+        reference: ReferenceSequences,
+        combined_strands: bool,
+    ) -> anyhow::Result<Self> {
+        if motifs.is_empty() {
+            bail!("need at least one motif")
+        }
+        if combined_strands && motifs.iter().any(|m| !m.is_palendrome()) {
+            bail!("motifs must be palindromic with combined strands")
+        }
+        Ok(Self { motifs, reference, combined_strands })
+    }
+
+    fn strand_of(&self, record: &BedMethylLine) -> Strand {
+        match record.strand {
+            StrandRule::Negative => Strand::Negative,
+            StrandRule::Positive | StrandRule::Both => Strand::Positive,
+        }
+    }
+
+    /// Whether the record is at a motif site, `None` when the contig is not
+    /// in the reference.
+    fn is_site(
+        &mut self,
+        record: &BedMethylLine,
+    ) -> anyhow::Result<Option<bool>> {
+        let strand = self.strand_of(record);
+        let pos = record.start() as usize;
+        Ok(self.reference.get_sequence(&record.chrom)?.map(|seq| {
+            self.motifs.iter().any(|motif| motif.matches_at(seq, pos, strand))
+        }))
+    }
+
+    /// Number of motif sites on a contig, with combined strands only the
+    /// positive strand sites are counted.
+    fn count_sites(&mut self, contig: &str) -> anyhow::Result<u64> {
+        let combined_strands = self.combined_strands;
+        let motifs = &self.motifs;
+        let Some(seq) = self.reference.get_sequence(contig)? else {
+            return Ok(0);
+        };
+        let mut hits = motifs
+            .iter()
+            .flat_map(|motif| find_motif_hits(seq, motif))
+            .filter(|(_, strand)| {
+                !combined_strands || *strand == Strand::Positive
+            })
+            .map(|(pos, strand)| (pos, strand == Strand::Positive))
+            .collect::<Vec<(usize, bool)>>();
+        hits.sort_unstable();
+        hits.dedup();
+        Ok(hits.len() as u64)
+    }
+}
+
+/// Collect the coverage distribution of each contig from a bedMethyl
+/// stream. Records for different modification codes at the same position
+/// and strand are counted once. When `motif_sites` is provided only records
+/// at motif sites are used and motif sites missing from the bedMethyl are
+/// counted with zero coverage, including those on contigs without any
+/// records.
+pub(super) fn collect_coverage<R: BufRead>(
+    in_stream: R,
+    mut motif_sites: Option<&mut MotifSites>,
+    pb: &ProgressBar,
+) -> anyhow::Result<Vec<ContigCoverage>> {
+    let mut contigs: Vec<ContigCoverage> = Vec::new();
+    let mut seen = FxHashSet::default();
+    let mut prev: Option<BedMethylLine> = None;
+    let mut n_observed = 0u64;
+    for line in in_stream.lines() {
+        let line = line?;
+        if line.starts_with('#') || line.starts_with("track") {
+            continue;
+        }
+        let record = BedMethylLine::parse(&line)?;
+        pb.inc(1);
+        if prev.as_ref().is_some_and(|p| p.same_position_and_strand_as(&record))
+        {
+            continue;
+        }
+        if let Some(sites) = motif_sites.as_mut() {
+            match sites.is_site(&record)? {
+                Some(true) => {}
+                Some(false) => continue,
+                None => {
+                    warn_once!(
+                        "contig {} is not in the reference, skipping",
+                        &record.chrom
+                    );
+                    continue;
+                }
+            }
+        }
+        let new_contig =
+            contigs.last().map(|c| c.name != record.chrom).unwrap_or(true);
+        if new_contig {
+            if !seen.insert(record.chrom.to_owned()) {
+                bail!(
+                    "bedMethyl is not sorted, {} seen more than once",
+                    record.chrom
+                )
+            }
+            finish_contig(
+                contigs.last_mut(),
+                motif_sites.as_deref_mut(),
+                n_observed,
+            )?;
+            n_observed = 0;
+            contigs.push(ContigCoverage {
+                name: record.chrom.to_owned(),
+                histogram: CoverageHistogram::default(),
+            });
+        }
+        // safe because we just pushed a contig if needed
+        contigs.last_mut().unwrap().histogram.add(record.valid_coverage, 1);
+        n_observed += 1;
+        prev = Some(record);
+    }
+    finish_contig(contigs.last_mut(), motif_sites.as_deref_mut(), n_observed)?;
+
+    if let Some(sites) = motif_sites {
+        for name in sites.reference.contig_names() {
+            if seen.contains(&name) {
+                continue;
+            }
+            let n_sites = sites.count_sites(&name)?;
+            debug!("{name} has {n_sites} motif sites without coverage");
+            let mut histogram = CoverageHistogram::default();
+            histogram.add(0, n_sites);
+            contigs.push(ContigCoverage { name, histogram });
+        }
+    }
+    if contigs.is_empty() {
+        bail!("no bedMethyl records found")
+    }
+    contigs.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(contigs)
+}
+
+fn finish_contig(
+    contig: Option<&mut ContigCoverage>,
+    motif_sites: Option<&mut MotifSites>,
+    n_observed: u64,
+) -> anyhow::Result<()> {
+    if let (Some(contig), Some(sites)) = (contig, motif_sites) {
+        let n_sites = sites.count_sites(&contig.name)?;
+        if n_sites < n_observed {
+            bail!(
+                "found more records at motif sites ({n_observed}) than motif \
+                 sites ({n_sites}) on {}",
+                contig.name
+            )
+        }
+        contig.histogram.add(0, n_sites - n_observed);
+    }
+    Ok(())
+}
+
+/// Genome-wide coverage distribution.
+pub(super) fn genome_coverage(contigs: &[ContigCoverage]) -> ContigCoverage {
+    let histogram = contigs
+        .iter()
+        .map(|c| c.histogram.clone())
+        .fold(CoverageHistogram::zero(), |a, b| a.op(b));
+    ContigCoverage { name: "genome".to_string(), histogram }
+}
+
+pub(super) fn parse_thresholds(raw: &[u64]) -> Vec<u64> {
+    raw.iter().copied().sorted().dedup().collect()
+}
+
+#[cfg(test)]
+mod coverage_tests {
+    use crate::coverage::CoverageHistogram;
+
+    #[test]
+    fn test_coverage_histogram_stats() {
+        let mut hist = CoverageHistogram::default();
+        for cov in [10, 10, 10, 10] {
+            hist.add(cov, 1);
+        }
+        assert_eq!(hist.gini(), 0f64);
+        assert_eq!(hist.mean(), 10f64);
+        assert_eq!(hist.quantile(0.5), 10);
+        assert_eq!(
+            hist.lorenz_curve(4),
+            vec![
+                (0f64, 0f64),
+                (0.25, 0.25),
+                (0.5, 0.5),
+                (0.75, 0.75),
+                (1f64, 1f64)
+            ]
+        );
+
+        // all of the coverage at one of four sites
+        let mut hist = CoverageHistogram::default();
+        hist.add(0, 3);
+        hist.add(8, 1);
+        assert!((hist.gini() - 0.75).abs() < 1e-9);
+        assert_eq!(hist.quantile(0.5), 0);
+        assert_eq!(hist.quantile(1.0), 8);
+        assert_eq!(hist.fraction_at_least(1), 0.25);
+        assert_eq!(hist.lorenz_curve(4)[3], (0.75, 0f64));
+
+        let mut hist = CoverageHistogram::default();
+        for cov in [1, 2, 3, 4] {
+            hist.add(cov, 1);
+        }
+        // sum_i sum_j |x_i - x_j| / (2 n^2 mean) = 20 / 80
+        assert!((hist.gini() - 0.25).abs() < 1e-9);
+        assert_eq!(hist.quantile(0.1), 1);
+        assert_eq!(hist.quantile(0.9), 4);
+        assert_eq!(hist.fraction_at_least(3), 0.5);
+        assert_eq!(hist.lorenz_curve(2)[1], (0.5, 0.3));
+    }
+}
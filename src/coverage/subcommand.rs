@@ -0,0 +1,180 @@
+use std::fs::File;
+use std::io::{stdout, BufRead, BufReader, BufWriter, Write};
+use std::path::PathBuf;
+
+use anyhow::{bail, Context};
+use clap::Args;
+use indicatif::{MultiProgress, ProgressDrawTarget};
+use itertools::Itertools;
+use log::info;
+
+use crate::coverage::{
+    collect_coverage, genome_coverage, parse_thresholds, ContigCoverage,
+    MotifSites,
+};
+use crate::fasta::ReferenceSequences;
+use crate::logging::init_logging;
+use crate::motifs::motif_bed::RegexMotif;
+use crate::util::{create_out_directory, get_ticker};
+
+#[derive(Args)]
+#[command(arg_required_else_help = true)]
+pub struct EntryCoverage {
+    /// Input bedMethyl table, can be plain text or bgzip-compressed. Use "-"
+    /// or "stdin" to read from standard input, for example to calculate
+    /// coverage directly from a modBAM with `modkit pileup <bam> - | modkit
+    /// coverage -`. The bedMethyl must be sorted by contig.
+    in_bedmethyl: String,
+    /// Coverage thresholds to report the fraction of sites with at least
+    /// this valid coverage, comma-separated.
+    #[arg(
+        long,
+        value_delimiter = ',',
+        default_values_t = vec![1u64, 5, 10, 20, 30]
+    )]
+    thresholds: Vec<u64>,
+    /// Reference sequence in FASTA format, must have an associated FAI
+    /// index. Required with --motif and --cpg.
+    #[clap(help_heading = "Motif Options")]
+    #[arg(long = "ref", alias = "reference")]
+    reference_fasta: Option<PathBuf>,
+    /// Only use bedMethyl records at motif sites and count motif sites that
+    /// aren't in the bedMethyl as having zero coverage. The first argument
+    /// is the sequence motif and the second argument is the 0-based offset
+    /// to the modified base in the motif, for example --motif CG 0. Can be
+    /// passed multiple times.
+    #[clap(help_heading = "Motif Options")]
+    #[arg(
+        long,
+        action = clap::ArgAction::Append,
+        num_args = 2,
+        requires = "reference_fasta"
+    )]
+    motif: Option<Vec<String>>,
+    /// Shorthand for --motif CG 0.
+    #[clap(help_heading = "Motif Options")]
+    #[arg(long, default_value_t = false, requires = "reference_fasta")]
+    cpg: bool,
+    /// The bedMethyl has strands combined (the output of pileup with
+    /// --combine-strands), only count motif sites on the positive strand.
+    /// Motifs must be palindromic.
+    #[clap(help_heading = "Motif Options")]
+    #[arg(long, default_value_t = false)]
+    combine_strands: bool,
+    /// Optionally specify a file to write output to, default is stdout.
+    #[clap(help_heading = "Output Options")]
+    #[arg(long, short = 'o')]
+    out_file: Option<PathBuf>,
+    /// Write points of the Lorenz curve, the fraction of coverage in the
+    /// least-covered fraction of sites, for each contig and the genome to
+    /// this file.
+    #[clap(help_heading = "Output Options")]
+    #[arg(long)]
+    lorenz: Option<PathBuf>,
+    /// Number of evenly spaced points on the Lorenz curve.
+    #[clap(help_heading = "Output Options")]
+    #[arg(long, default_value_t = 100, requires = "lorenz")]
+    lorenz_points: usize,
+    /// Force overwrite of existing output files.
+    #[clap(help_heading = "Output Options")]
+    #[arg(long, short = 'f', default_value_t = false)]
+    force: bool,
+    /// Don't add the header describing the columns to the output.
+    #[clap(help_heading = "Output Options")]
+    #[arg(long, default_value_t = false)]
+    no_header: bool,
+    /// Hide the progress bar.
+    #[clap(help_heading = "Logging Options")]
+    #[arg(long, default_value_t = false, hide_short_help = true)]
+    suppress_progress: bool,
+    /// Specify a file to write debug logs to.
+    #[clap(help_heading = "Logging Options")]
+    #[arg(long, alias = "log")]
+    log_filepath: Option<PathBuf>,
+}
+
+impl EntryCoverage {
+    fn motif_sites(&self) -> anyhow::Result<Option<MotifSites>> {
+        let Some(reference_fp) = self.reference_fasta.as_ref() else {
+            return Ok(None);
+        };
+        let raw_motifs = self.motif.clone().unwrap_or_default();
+        if raw_motifs.is_empty() && !self.cpg {
+            bail!("--ref requires --motif or --cpg")
+        }
+        let motifs = RegexMotif::from_raw_parts(&raw_motifs, self.cpg)?;
+        info!("only using records at motif sites: {}", motifs.iter().join(" "));
+        let reference = ReferenceSequences::from_path(reference_fp)?;
+        MotifSites::new(motifs, reference, self.combine_strands).map(Some)
+    }
+
+    fn create_writer(&self, fp: &PathBuf) -> anyhow::Result<BufWriter<File>> {
+        create_out_directory(fp)?;
+        if fp.exists() && !self.force {
+            bail!("refusing to overwrite existing file {fp:?}")
+        }
+        Ok(BufWriter::new(File::create(fp)?))
+    }
+
+    pub fn run(&self) -> anyhow::Result<()> {
+        let _handle = init_logging(self.log_filepath.as_ref());
+        if self.lorenz_points == 0 {
+            bail!("--lorenz-points must be at least 1")
+        }
+        let thresholds = parse_thresholds(&self.thresholds);
+        let mut motif_sites = self.motif_sites()?;
+        let mut writer: Box<dyn Write> = match self.out_file.as_ref() {
+            Some(out_fp) => Box::new(self.create_writer(out_fp)?),
+            None => Box::new(BufWriter::new(stdout())),
+        };
+        let mut lorenz_writer = self
+            .lorenz
+            .as_ref()
+            .map(|fp| self.create_writer(fp))
+            .transpose()?;
+        let in_stream: Box<dyn BufRead> = match self.in_bedmethyl.as_str() {
+            "-" | "stdin" => Box::new(BufReader::new(std::io::stdin().lock())),
+            p => {
+                let reader = rust_htslib::bgzf::Reader::from_path(p)
+                    .with_context(|| format!("failed to open {p}"))?;
+                Box::new(BufReader::new(reader))
+            }
+        };
+
+        let mpb = MultiProgress::new();
+        if self.suppress_progress {
+            mpb.set_draw_target(ProgressDrawTarget::hidden());
+        }
+        let pb = mpb.add(get_ticker());
+        pb.set_message("bedMethyl records processed");
+        let contigs = collect_coverage(in_stream, motif_sites.as_mut(), &pb)?;
+        pb.finish_and_clear();
+        let genome = genome_coverage(&contigs);
+
+        if !self.no_header {
+            writeln!(writer, "{}", ContigCoverage::header(&thresholds))?;
+        }
+        for contig in contigs.iter().chain(std::iter::once(&genome)) {
+            writeln!(writer, "{}", contig.row(&thresholds))?;
+        }
+        writer.flush()?;
+        if let Some(lorenz_writer) = lorenz_writer.as_mut() {
+            if !self.no_header {
+                writeln!(lorenz_writer, "contig\tfrac_sites\tfrac_coverage")?;
+            }
+            for contig in contigs.iter().chain(std::iter::once(&genome)) {
+                for row in contig.lorenz_rows(self.lorenz_points) {
+                    writeln!(lorenz_writer, "{row}")?;
+                }
+            }
+            lorenz_writer.flush()?;
+        }
+        info!(
+            "finished, {} contigs, {} sites",
+            contigs.len(),
+            genome.histogram.n_sites()
+        );
+
+        Ok(())
+    }
+}
@@ -1,7 +1,10 @@
-use std::collections::{HashMap, HashSet};
+use std::collections::btree_map::Entry;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::fmt::Display;
+use std::ops::Range;
 
 use anyhow::Context;
+use clap::ValueEnum;
 use derive_new::new;
 use itertools::{Itertools, MinMaxResult};
 use log_once::debug_once;
@@ -14,7 +17,8 @@ use rustc_hash::{FxHashMap, FxHashSet};
 use crate::dmr::llr_model::AggregatedCounts;
 use crate::errs::{MkError, MkResult};
 use crate::genome_positions::StrandedPosition;
-use crate::mod_base_code::{DnaBase, ModCodeRepr};
+use crate::mod_base_code::{DnaBase, ModCodeRepr, METHYL_CYTOSINE};
+use crate::monoid::BorrowingMoniod;
 use crate::parsing_utils::{
     consume_char, consume_digit, consume_float, consume_string,
     consume_string_from_list,
@@ -86,7 +90,197 @@ fn parse_bedmethyl_line(l: &str) -> IResult<&str, BedMethylLine> {
     ))
 }
 
+/// Format of the sample tables given to `dmr`. Bisulfite tables (Bismark
+/// and methylKit) are converted to 5mC (`m`) bedMethyl records as they are
+/// read.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, ValueEnum)]
+#[allow(non_camel_case_types)]
+pub enum DmrInputFormat {
+    /// Detect the format from the first record of each input.
+    auto,
+    /// bedMethyl, e.g. from `modkit pileup`.
+    bedmethyl,
+    /// Bismark coverage (`.cov`) or genome-wide cytosine report.
+    bismark,
+    /// methylKit tab-delimited methylation calls.
+    methylkit,
+}
+
+impl Display for DmrInputFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DmrInputFormat::auto => write!(f, "auto"),
+            DmrInputFormat::bedmethyl => write!(f, "bedmethyl"),
+            DmrInputFormat::bismark => write!(f, "bismark"),
+            DmrInputFormat::methylkit => write!(f, "methylkit"),
+        }
+    }
+}
+
+impl DmrInputFormat {
+    /// Guess the format of a sample table from one of its records.
+    pub(crate) fn detect(line: &str) -> MkResult<Self> {
+        let fields = line.split_whitespace().collect::<Vec<&str>>();
+        let is_int = |f: &str| f.parse::<u64>().is_ok();
+        let is_float = |f: &str| f.parse::<f32>().is_ok();
+        match fields.as_slice() {
+            [_, start, stop, code, ..]
+                if fields.len() >= 18
+                    && is_int(start)
+                    && is_int(stop)
+                    && ModCodeRepr::parse(
+                        code.split(',').next().unwrap_or(""),
+                    )
+                    .is_ok() =>
+            {
+                Ok(Self::bedmethyl)
+            }
+            [_, start, stop, pct, n_meth, n_unmeth]
+                if is_int(start)
+                    && is_int(stop)
+                    && is_float(pct)
+                    && is_int(n_meth)
+                    && is_int(n_unmeth) =>
+            {
+                Ok(Self::bismark)
+            }
+            [_, pos, "+" | "-", n_meth, n_unmeth, ..]
+                if is_int(pos) && is_int(n_meth) && is_int(n_unmeth) =>
+            {
+                Ok(Self::bismark)
+            }
+            [_, _, pos, "F" | "R", cov, freq_c, freq_t]
+                if is_int(pos)
+                    && is_int(cov)
+                    && is_float(freq_c)
+                    && is_float(freq_t) =>
+            {
+                Ok(Self::methylkit)
+            }
+            _ => Err(MkError::InvalidBedMethyl(format!(
+                "could not detect the format of record:\n{line}\nuse \
+                 --input-format to set it"
+            ))),
+        }
+    }
+
+    /// Parse a record into a bedMethyl record, `auto` is parsed as bedMethyl.
+    pub(crate) fn parse_line(&self, line: &str) -> MkResult<BedMethylLine> {
+        match self {
+            Self::auto | Self::bedmethyl => BedMethylLine::parse(line),
+            Self::bismark => BedMethylLine::parse_bismark(line),
+            Self::methylkit => BedMethylLine::parse_methylkit(line),
+        }
+    }
+}
+
 impl BedMethylLine {
+    /// A 5mC record from bisulfite counts at a 0-based position.
+    fn from_bisulfite_counts(
+        chrom: &str,
+        start: u64,
+        strand: StrandRule,
+        count_methylated: u64,
+        count_canonical: u64,
+    ) -> Self {
+        BedMethylLine::new(
+            chrom.to_string(),
+            Iv { start, stop: start + 1, val: () },
+            METHYL_CYTOSINE,
+            strand,
+            count_methylated,
+            count_methylated + count_canonical,
+            count_canonical,
+            0,
+            0,
+            0,
+            0,
+            0,
+        )
+    }
+
+    /// Parse a Bismark coverage record (`chrom start end %meth n_meth
+    /// n_unmeth`, 1-based) or cytosine report record (`chrom pos strand
+    /// n_meth n_unmeth context trinucleotide`, 1-based). Coverage records
+    /// don't have a strand so they're reported on both strands.
+    pub(crate) fn parse_bismark(line: &str) -> MkResult<Self> {
+        let err = |reason: &str| {
+            MkError::InvalidBedMethyl(format!(
+                "invalid Bismark record:\n{line}\nerror: {reason}"
+            ))
+        };
+        let parse_int = |raw: &str| {
+            raw.parse::<u64>().map_err(|e| err(&format!("{raw}, {e}")))
+        };
+        let fields = line.split_whitespace().collect::<Vec<&str>>();
+        let (chrom, pos, strand, n_meth, n_unmeth) = match fields.as_slice() {
+            [chrom, pos, strand @ ("+" | "-"), n_meth, n_unmeth, ..] => {
+                let strand = if *strand == "+" {
+                    StrandRule::Positive
+                } else {
+                    StrandRule::Negative
+                };
+                (*chrom, *pos, strand, *n_meth, *n_unmeth)
+            }
+            [chrom, pos, _end, _pct, n_meth, n_unmeth] => {
+                (*chrom, *pos, StrandRule::Both, *n_meth, *n_unmeth)
+            }
+            _ => return Err(err("unexpected number of fields")),
+        };
+        let pos = parse_int(pos)?;
+        if pos == 0 {
+            return Err(err("positions should be 1-based"));
+        }
+        Ok(Self::from_bisulfite_counts(
+            chrom,
+            pos - 1,
+            strand,
+            parse_int(n_meth)?,
+            parse_int(n_unmeth)?,
+        ))
+    }
+
+    /// Parse a methylKit record (`chrBase chr base strand coverage freqC
+    /// freqT`, 1-based with F/R strands and percentages), the methylated
+    /// count is recovered from the coverage and freqC.
+    pub(crate) fn parse_methylkit(line: &str) -> MkResult<Self> {
+        let err = |reason: &str| {
+            MkError::InvalidBedMethyl(format!(
+                "invalid methylKit record:\n{line}\nerror: {reason}"
+            ))
+        };
+        let fields = line.split_whitespace().collect::<Vec<&str>>();
+        let [_chr_base, chrom, pos, strand, coverage, freq_c, _freq_t] =
+            fields.as_slice()
+        else {
+            return Err(err("unexpected number of fields"));
+        };
+        let strand = match *strand {
+            "F" | "+" => StrandRule::Positive,
+            "R" | "-" => StrandRule::Negative,
+            _ => return Err(err(&format!("invalid strand {strand}"))),
+        };
+        let pos = pos.parse::<u64>().map_err(|e| err(&e.to_string()))?;
+        if pos == 0 {
+            return Err(err("positions should be 1-based"));
+        }
+        let coverage =
+            coverage.parse::<u64>().map_err(|e| err(&e.to_string()))?;
+        let freq_c = freq_c.parse::<f64>().map_err(|e| err(&e.to_string()))?;
+        if !(0f64..=100f64).contains(&freq_c) {
+            return Err(err(&format!("invalid freqC {freq_c}")));
+        }
+        let count_methylated =
+            ((coverage as f64 * freq_c / 100f64).round() as u64).min(coverage);
+        Ok(Self::from_bisulfite_counts(
+            chrom,
+            pos - 1,
+            strand,
+            count_methylated,
+            coverage - count_methylated,
+        ))
+    }
+
     pub fn parse(line: &str) -> MkResult<Self> {
         parse_bedmethyl_line(line).map(|(_, this)| this).map_err(|e| {
             MkError::InvalidBedMethyl(format!(
@@ -159,6 +353,52 @@ impl BedMethylLine {
     }
 }
 
+/// Merge the records on the negative strand into the positive strand
+/// position of their CpG (one base upstream), summing the counts of records
+/// with the same modification code, as `pileup --combine-strands` would.
+/// Only combined records starting within `range` and with at least
+/// `min_valid_coverage` are kept. Negative strand records that aren't part of
+/// a CpG will be moved as well, so the records should be from CpG positions.
+pub(crate) fn combine_cpg_strands(
+    lines: Vec<BedMethylLine>,
+    range: &Range<u64>,
+    min_valid_coverage: u64,
+) -> Vec<BedMethylLine> {
+    let mut combined = BTreeMap::<(u64, ModCodeRepr), BedMethylLine>::new();
+    for mut line in lines {
+        if line.strand == StrandRule::Negative {
+            if line.interval.start == 0 {
+                continue;
+            }
+            line.interval.start -= 1;
+            line.interval.stop -= 1;
+        }
+        line.strand = StrandRule::Both;
+        match combined.entry((line.start(), line.raw_mod_code)) {
+            Entry::Vacant(entry) => {
+                entry.insert(line);
+            }
+            Entry::Occupied(mut entry) => {
+                let agg = entry.get_mut();
+                agg.count_methylated += line.count_methylated;
+                agg.valid_coverage += line.valid_coverage;
+                agg.count_canonical += line.count_canonical;
+                agg.count_other += line.count_other;
+                agg.count_delete += line.count_delete;
+                agg.count_fail += line.count_fail;
+                agg.count_diff += line.count_diff;
+                agg.count_nocall += line.count_nocall;
+            }
+        }
+    }
+    combined
+        .into_values()
+        .filter(|l| {
+            range.contains(&l.start()) && l.valid_coverage >= min_valid_coverage
+        })
+        .collect()
+}
+
 impl Display for BedMethylLine {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(f, "{}", self.to_line())
@@ -177,8 +417,24 @@ pub(super) fn aggregate_counts(
     bm_lines: &[&BedMethylLine],
     code_lookup: &FxHashMap<ModCodeRepr, DnaBase>,
 ) -> MkResult<AggregatedCounts> {
+    aggregate_counts_per_position(bm_lines, code_lookup).map(|site_counts| {
+        site_counts
+            .values()
+            .fold(AggregatedCounts::zero(), |acc, counts| acc.op(counts))
+    })
+}
+
+/// Counts at each position, the records for each modification code at a
+/// position are combined.
+pub(super) type SiteCounts =
+    FxHashMap<StrandedPosition<DnaBase>, AggregatedCounts>;
+
+pub(super) fn aggregate_counts_per_position(
+    bm_lines: &[&BedMethylLine],
+    code_lookup: &FxHashMap<ModCodeRepr, DnaBase>,
+) -> MkResult<SiteCounts> {
     if bm_lines.is_empty() {
-        return Ok(AggregatedCounts::default());
+        return Ok(SiteCounts::default());
     }
     assert_eq!(
         bm_lines.iter().map(|l| &l.chrom).collect::<HashSet<_>>().len(),
@@ -209,9 +465,9 @@ pub(super) fn aggregate_counts(
             (pos, codes_to_lines.values().map(|x| *x).collect())
         })
         .collect::<_>();
-    let (counts_per_code, total) = grouped_by_position.into_iter().try_fold(
-        (HashMap::new(), 0),
-        |(mut acc, mut total_so_far), (_pos, grouped)| {
+    grouped_by_position
+        .into_iter()
+        .map(|(pos, grouped)| {
             let valid_covs = grouped
                 .iter()
                 .map(|bml| bml.valid_coverage)
@@ -244,8 +500,9 @@ pub(super) fn aggregate_counts(
             // check that the sum of canonical counts and
             // modified counts is equal to the valid coverage
             let mut check = grouped[0].count_canonical as usize;
+            let mut counts_per_code = HashMap::new();
             for x in &grouped {
-                *acc.entry(x.raw_mod_code).or_insert(0) +=
+                *counts_per_code.entry(x.raw_mod_code).or_insert(0) +=
                     x.count_methylated as usize;
                 check += x.count_methylated as usize;
             }
@@ -273,16 +530,10 @@ pub(super) fn aggregate_counts(
                 debug_once!("{message}");
                 return Err(MkError::InvalidBedMethyl(message));
             }
-            total_so_far += valid_coverage;
-            Ok((acc, total_so_far))
-        },
-    )?;
-
-    // todo don't need this match
-    match AggregatedCounts::try_new(counts_per_code, total) {
-        Ok(x) => Ok(x),
-        Err(e) => Err(e),
-    }
+            AggregatedCounts::try_new(counts_per_code, valid_coverage)
+                .map(|counts| (pos, counts))
+        })
+        .collect()
 }
 
 #[cfg(test)]
@@ -292,9 +543,12 @@ mod bedmethylline_tests {
     use std::io::{BufRead, BufReader};
     use std::path::Path;
 
-    use crate::dmr::bedmethyl::{aggregate_counts2, BedMethylLine};
+    use crate::dmr::bedmethyl::{
+        aggregate_counts2, combine_cpg_strands, BedMethylLine, DmrInputFormat,
+    };
     use crate::genome_positions::GenomePositions;
-    use crate::mod_base_code::{DnaBase, ModCodeRepr, MOD_CODE_TO_DNA_BASE};
+    use crate::mod_base_code::{DnaBase, ModCodeRepr};
+    use crate::mod_code_registry::ModCodeRegistry;
     use crate::position_filter::Iv;
     use crate::util::StrandRule;
 
@@ -402,24 +656,22 @@ mod bedmethylline_tests {
             .unwrap()
             .into_iter()
             .collect::<HashSet<_>>();
+        let code_lookup = ModCodeRegistry::global().code_to_base();
         let bedmethyl_lines = BufReader::new(fh)
             .lines()
             .map(|l| BedMethylLine::parse(&l.unwrap()).unwrap())
             .filter(|l| {
-                positions
-                    .contains(&l.get_stranded_position(&MOD_CODE_TO_DNA_BASE))
+                positions.contains(&l.get_stranded_position(&code_lookup))
             })
             .collect::<Vec<BedMethylLine>>();
-        let counts =
-            aggregate_counts2(&bedmethyl_lines, &MOD_CODE_TO_DNA_BASE).unwrap();
+        let counts = aggregate_counts2(&bedmethyl_lines, &code_lookup).unwrap();
         assert_eq!(&counts.string_counts(), "h:2,m:4");
         assert_eq!(counts.total, 6);
         let filtered_bm_lines = bedmethyl_lines
             .into_iter()
             .filter(|l| l.raw_mod_code == ModCodeRepr::Code('m'))
             .collect::<Vec<BedMethylLine>>();
-        assert!(aggregate_counts2(&filtered_bm_lines, &MOD_CODE_TO_DNA_BASE)
-            .is_err());
+        assert!(aggregate_counts2(&filtered_bm_lines, &code_lookup).is_err());
     }
 
     #[test]
@@ -432,4 +684,85 @@ mod bedmethylline_tests {
             assert_eq!(record.frac_modified(), expected[i]);
         }
     }
+
+    #[test]
+    #[rustfmt::skip]
+    fn test_combine_cpg_strands() {
+        let lines = [
+            "chr20\t5\t6\tm\t4\t-\t5\t6\t255,0,0\t4 50.00 2 2 0 0 0 0 0",
+            "chr20\t10\t11\tm\t4\t+\t10\t11\t255,0,0\t4 50.00 2 1 1 0 0 0 0",
+            "chr20\t10\t11\th\t4\t+\t10\t11\t255,0,0\t4 25.00 1 2 1 0 0 0 0",
+            "chr20\t11\t12\tm\t6\t-\t11\t12\t255,0,0\t6 50.00 3 3 0 0 0 0 0",
+            "chr20\t20\t21\tm\t2\t+\t20\t21\t255,0,0\t2 50.00 1 1 0 0 0 0 0",
+            "chr20\t30\t31\tm\t3\t-\t30\t31\t255,0,0\t3 100.00 3 0 0 0 0 0 0",
+        ]
+        .into_iter()
+        .map(|l| BedMethylLine::parse(l).unwrap())
+        .collect::<Vec<BedMethylLine>>();
+        let combined = combine_cpg_strands(lines, &(5..30), 3)
+            .into_iter()
+            .map(|l| (l.start(), l.raw_mod_code, l.strand, l.count_methylated, l.valid_coverage))
+            .collect::<Vec<_>>();
+        // position 5 moves to 4 which is outside of the range, position 20 is
+        // below the minimum coverage, and position 30 (one past the end of the
+        // range) moves into the range
+        let expected = vec![
+            (10, ModCodeRepr::Code('h'), StrandRule::Both, 1, 4),
+            (10, ModCodeRepr::Code('m'), StrandRule::Both, 5, 10),
+            (29, ModCodeRepr::Code('m'), StrandRule::Both, 3, 3),
+        ];
+        assert_eq!(combined, expected);
+    }
+
+    #[test]
+    fn test_parse_bisulfite_formats() {
+        let bedmethyl = "chr20\t10034963\t10034964\tm\t19\t-\t10034963\\
+                         t10034964\t255,0,0\t19 94.74 18 1 0 0 1 0 2";
+        let bismark_cov = "chr20\t10034964\t10034964\t94.736842\t18\t1";
+        let bismark_report = "chr20\t10034964\t-\t18\t1\tCG\tCGT";
+        let methylkit = "chr20.10034964\tchr20\t10034964\tR\t19\t94.74\t5.26";
+        for (line, expected) in [
+            (bedmethyl, DmrInputFormat::bedmethyl),
+            (bismark_cov, DmrInputFormat::bismark),
+            (bismark_report, DmrInputFormat::bismark),
+            (methylkit, DmrInputFormat::methylkit),
+        ] {
+            assert_eq!(DmrInputFormat::detect(line).unwrap(), expected);
+        }
+        assert!(DmrInputFormat::detect("chr20\t10034964").is_err());
+
+        let expected = |strand: StrandRule| {
+            BedMethylLine::new(
+                "chr20".to_string(),
+                Iv { start: 10034963, stop: 10034964, val: () },
+                ModCodeRepr::Code('m'),
+                strand,
+                18,
+                19,
+                1,
+                0,
+                0,
+                0,
+                0,
+                0,
+            )
+        };
+        assert_eq!(
+            BedMethylLine::parse_bismark(bismark_cov).unwrap(),
+            expected(StrandRule::Both)
+        );
+        assert_eq!(
+            BedMethylLine::parse_bismark(bismark_report).unwrap(),
+            expected(StrandRule::Negative)
+        );
+        assert_eq!(
+            BedMethylLine::parse_methylkit(methylkit).unwrap(),
+            expected(StrandRule::Negative)
+        );
+        assert!(BedMethylLine::parse_bismark(methylkit).is_err());
+        assert!(BedMethylLine::parse_methylkit(
+            "chr20.1\tchr20\t1\tF\t19\t101.0\t0.0"
+        )
+        .is_err());
+    }
 }
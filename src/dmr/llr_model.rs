@@ -2,10 +2,12 @@ use std::collections::{HashMap, HashSet};
 use std::fmt::{Display, Formatter};
 
 use anyhow::{anyhow, bail};
+use clap::ValueEnum;
 use itertools::Itertools;
 use log::debug;
 use rv::prelude::*;
 
+use crate::dmr::bedmethyl::SiteCounts;
 use crate::dmr::util::{cohen_h, CohenHResult, DmrInterval};
 use crate::errs::{MkError, MkResult};
 use crate::mod_base_code::ModCodeRepr;
@@ -148,6 +150,112 @@ impl Display for AggregatedCounts {
     }
 }
 
+/// How the `effect_size` of a region is estimated from the sites in the
+/// region.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, ValueEnum)]
+#[allow(non_camel_case_types)]
+pub enum RegionEffectSize {
+    /// Difference in the fraction modified of the counts pooled over all
+    /// sites in the region, deeply covered sites contribute the most.
+    pooled,
+    /// As pooled, but the valid coverage of each site is first capped at the
+    /// --winsorize-quantile of the site coverages in the region.
+    winsorized,
+    /// Median of the per-site differences in fraction modified, over the
+    /// sites with coverage in both conditions. Each site contributes equally
+    /// regardless of coverage.
+    median,
+}
+
+impl Display for RegionEffectSize {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::pooled => write!(f, "pooled"),
+            Self::winsorized => write!(f, "winsorized"),
+            Self::median => write!(f, "median"),
+        }
+    }
+}
+
+/// Estimate the region effect size (fraction modified in the control minus
+/// fraction modified in the experiment) with `method` from the counts at each
+/// site. Returns `None` when there are no sites to estimate from (e.g. no
+/// sites are covered in both conditions for `median`).
+pub(super) fn site_effect_size(
+    method: RegionEffectSize,
+    winsorize_quantile: f64,
+    control_sites: &SiteCounts,
+    exp_sites: &SiteCounts,
+) -> Option<f32> {
+    let frac = |counts: &AggregatedCounts| {
+        counts.modified_counts() as f64 / counts.total as f64
+    };
+    match method {
+        RegionEffectSize::pooled => {
+            let pool = |sites: &SiteCounts| {
+                sites.values().fold(AggregatedCounts::zero(), |acc, counts| {
+                    acc.op(counts)
+                })
+            };
+            let (control, exp) = (pool(control_sites), pool(exp_sites));
+            if control.total == 0 || exp.total == 0 {
+                None
+            } else {
+                Some(control.frac_modified() - exp.frac_modified())
+            }
+        }
+        RegionEffectSize::winsorized => {
+            let coverages = control_sites
+                .values()
+                .chain(exp_sites.values())
+                .map(|counts| counts.total)
+                .filter(|&total| total > 0)
+                .sorted()
+                .collect::<Vec<usize>>();
+            if coverages.is_empty() {
+                return None;
+            }
+            let idx = ((coverages.len() - 1) as f64 * winsorize_quantile)
+                .round() as usize;
+            let cap = coverages[idx.min(coverages.len() - 1)] as f64;
+            let winsorized_frac = |sites: &SiteCounts| {
+                let (modified, total) = sites
+                    .values()
+                    .filter(|counts| counts.total > 0)
+                    .fold((0f64, 0f64), |(modified, total), counts| {
+                        let weight = (counts.total as f64).min(cap);
+                        (modified + frac(counts) * weight, total + weight)
+                    });
+                (total > 0f64).then(|| modified / total)
+            };
+            winsorized_frac(control_sites)
+                .zip(winsorized_frac(exp_sites))
+                .map(|(control, exp)| (control - exp) as f32)
+        }
+        RegionEffectSize::median => {
+            let diffs = control_sites
+                .iter()
+                .filter(|(_, counts)| counts.total > 0)
+                .filter_map(|(pos, control)| {
+                    exp_sites
+                        .get(pos)
+                        .filter(|exp| exp.total > 0)
+                        .map(|exp| frac(control) - frac(exp))
+                })
+                .sorted_by(|a, b| a.partial_cmp(b).unwrap())
+                .collect::<Vec<f64>>();
+            let n = diffs.len();
+            if n == 0 {
+                None
+            } else if n % 2 == 1 {
+                Some(diffs[n / 2] as f32)
+            } else {
+                Some(((diffs[n / 2 - 1] + diffs[n / 2]) / 2f64) as f32)
+            }
+        }
+    }
+}
+
 #[derive(Debug)]
 /// Counts of modifications for each condition, scored
 pub(super) struct ModificationCounts {
@@ -155,11 +263,24 @@ pub(super) struct ModificationCounts {
     exp_counts: AggregatedCounts,
     interval: DmrInterval,
     pub(crate) score: f64,
+    /// Difference in fraction modified between the conditions, from the
+    /// pooled counts unless set with [`Self::with_effect_size`].
+    effect_size: f32,
     pub(super) cohen_hresult: CohenHResult,
+    /// Counts for each sample (by sample index) that went into
+    /// `control_counts` and `exp_counts`, only kept when raw counts are
+    /// written.
+    sample_counts: Vec<(usize, bool, AggregatedCounts)>,
 }
 
 impl ModificationCounts {
-    pub(super) fn header(a_name: &str, b_name: &str) -> String {
+    /// `region_columns` are the names of the columns passed through from
+    /// the regions BED, see [`DmrInterval::extra_columns`].
+    pub(super) fn header(
+        a_name: &str,
+        b_name: &str,
+        region_columns: &[String],
+    ) -> String {
         let mut s = [
             "#chrom",
             "start",
@@ -180,6 +301,8 @@ impl ModificationCounts {
             "cohen_h_low",
             "cohen_h_high",
         ]
+        .into_iter()
+        .chain(region_columns.iter().map(|x| x.as_str()))
         .join("\t");
         s.push('\n');
         s
@@ -192,15 +315,61 @@ impl ModificationCounts {
     ) -> MkResult<Self> {
         let score = llk_ratio(&control_counts, &exp_counts)?;
         let coh_res = cohen_h(&control_counts, &exp_counts);
+        let effect_size =
+            control_counts.frac_modified() - exp_counts.frac_modified();
         Ok(Self {
             control_counts,
             exp_counts,
             interval,
             score,
+            effect_size,
             cohen_hresult: coh_res,
+            sample_counts: Vec::new(),
         })
     }
 
+    /// Replace the pooled effect size, e.g. with one estimated by
+    /// [`site_effect_size`].
+    pub(super) fn with_effect_size(self, effect_size: f32) -> Self {
+        Self { effect_size, ..self }
+    }
+
+    /// Attach the per-sample counts for the control (`a`) and experiment
+    /// (`b`) conditions, ordered by sample index.
+    pub(super) fn with_sample_counts(
+        self,
+        control_sample_counts: Vec<(usize, AggregatedCounts)>,
+        exp_sample_counts: Vec<(usize, AggregatedCounts)>,
+    ) -> Self {
+        let sample_counts = control_sample_counts
+            .into_iter()
+            .map(|(idx, counts)| (idx, true, counts))
+            .chain(
+                exp_sample_counts
+                    .into_iter()
+                    .map(|(idx, counts)| (idx, false, counts)),
+            )
+            .collect();
+        Self { sample_counts, ..self }
+    }
+
+    /// The chrom, start, end, name, and strand columns of this region.
+    pub(super) fn location(&self) -> String {
+        format!(
+            "{}\t{}\t{}\t{}\t{}",
+            self.interval.chrom,
+            self.interval.start(),
+            self.interval.stop(),
+            self.interval.name,
+            self.interval.strand
+        )
+    }
+
+    /// The per-sample counts attached with [`Self::with_sample_counts`].
+    pub(super) fn sample_counts(&self) -> &[(usize, bool, AggregatedCounts)] {
+        &self.sample_counts
+    }
+
     pub(super) fn to_row(&self) -> anyhow::Result<String> {
         let sep = '\t';
         let start = self.interval.start();
@@ -224,7 +393,7 @@ impl ModificationCounts {
         {}{sep}\
         {}{sep}\
         {}{sep}\
-        {}\n\
+        {}{}\n\
         ",
             self.interval.chrom,
             start,
@@ -244,15 +413,80 @@ impl ModificationCounts {
             self.cohen_hresult.h,
             self.cohen_hresult.h_low,
             self.cohen_hresult.h_high,
+            self.interval.extra_columns_suffix(sep),
         );
         Ok(line)
     }
 
-    fn effect_size(&self) -> f32 {
-        self.control_counts.frac_modified() - self.exp_counts.frac_modified()
+    pub(super) fn effect_size(&self) -> f32 {
+        self.effect_size
+    }
+
+    /// P-value of a G-test of independence between the condition and the
+    /// modification state counts, used for the run summary.
+    pub(super) fn g_test_pvalue(&self) -> f64 {
+        g_test_pvalue(&self.control_counts, &self.exp_counts)
     }
 }
 
+/// G-test (likelihood ratio test) of the 2 x k contingency table of
+/// canonical and modification code counts for each condition. Categories
+/// without counts in either condition are dropped, when there is nothing to
+/// compare the p-value is 1.
+pub(super) fn g_test_pvalue(
+    control_counts: &AggregatedCounts,
+    exp_counts: &AggregatedCounts,
+) -> f64 {
+    let codes = control_counts
+        .mod_code_counts
+        .keys()
+        .chain(exp_counts.mod_code_counts.keys())
+        .copied()
+        .collect::<HashSet<ModCodeRepr>>();
+    let row = |counts: &AggregatedCounts| {
+        codes
+            .iter()
+            .sorted()
+            .map(|code| *counts.mod_code_counts.get(code).unwrap_or(&0))
+            .chain(std::iter::once(counts.get_canonical_counts()))
+            .map(|x| x as f64)
+            .collect::<Vec<f64>>()
+    };
+    let control_row = row(control_counts);
+    let exp_row = row(exp_counts);
+    let control_total = control_row.iter().sum::<f64>();
+    let exp_total = exp_row.iter().sum::<f64>();
+    let n = control_total + exp_total;
+    let columns = control_row
+        .into_iter()
+        .zip(exp_row)
+        .filter(|(a, b)| a + b > 0f64)
+        .collect::<Vec<(f64, f64)>>();
+    if columns.len() < 2 || control_total == 0f64 || exp_total == 0f64 {
+        return 1f64;
+    }
+    let term = |observed: f64, expected: f64| {
+        if observed > 0f64 {
+            observed * (observed / expected).ln()
+        } else {
+            0f64
+        }
+    };
+    let g = 2f64
+        * columns
+            .iter()
+            .map(|(a, b)| {
+                let col_total = a + b;
+                term(*a, control_total * col_total / n)
+                    + term(*b, exp_total * col_total / n)
+            })
+            .sum::<f64>();
+    let df = (columns.len() - 1) as f64;
+    statrs::distribution::ChiSquared::new(df)
+        .map(|chi2| statrs::distribution::ContinuousCDF::sf(&chi2, g.max(0f64)))
+        .unwrap_or(1f64)
+}
+
 fn dirichlet_llk(
     counts: &AggregatedCounts,
     prior: &Dirichlet,
@@ -372,10 +606,16 @@ mod dmr_model_tests {
     use rv::dist::Categorical;
     use rv::prelude::{Bernoulli, Rv};
 
-    use crate::dmr::llr_model::{llk_beta, llk_dirichlet, AggregatedCounts};
+    use crate::dmr::bedmethyl::SiteCounts;
+    use crate::dmr::llr_model::{
+        g_test_pvalue, llk_beta, llk_dirichlet, site_effect_size,
+        AggregatedCounts, RegionEffectSize,
+    };
+    use crate::genome_positions::StrandedPosition;
     use crate::mod_base_code::{
-        ModCodeRepr, HYDROXY_METHYL_CYTOSINE, METHYL_CYTOSINE,
+        DnaBase, ModCodeRepr, HYDROXY_METHYL_CYTOSINE, METHYL_CYTOSINE,
     };
+    use crate::util::Strand;
 
     fn methyl_sample(p: f64, n: usize, rng: &mut StdRng) -> AggregatedCounts {
         let mod_count = Bernoulli::new(p)
@@ -434,4 +674,71 @@ mod dmr_model_tests {
         let llk_b = llk_dirichlet(&control, &exp).unwrap();
         assert!(llk_a > llk_b);
     }
+
+    #[test]
+    fn test_g_test_pvalue() {
+        let code = METHYL_CYTOSINE;
+        let counts = |n_mod: usize, total: usize| {
+            AggregatedCounts::try_new(HashMap::from([(code, n_mod)]), total)
+                .unwrap()
+        };
+        // identical proportions, no evidence of a difference
+        let p = g_test_pvalue(&counts(10, 20), &counts(50, 100));
+        assert!((p - 1f64).abs() < 1e-9, "{p}");
+        // 2 x 2 table [[90, 10], [10, 90]], G = 2 * sum O ln(O/E) = 146.5
+        let p = g_test_pvalue(&counts(90, 100), &counts(10, 100));
+        assert!(p < 1e-30, "{p}");
+        // no counts in one condition
+        assert_eq!(g_test_pvalue(&counts(0, 0), &counts(10, 100)), 1f64);
+    }
+
+    #[test]
+    fn test_site_effect_size() {
+        let counts = |n_mod: usize, total: usize| {
+            AggregatedCounts::try_new(
+                HashMap::from([(METHYL_CYTOSINE, n_mod)]),
+                total,
+            )
+            .unwrap()
+        };
+        let site = |position: u64| StrandedPosition {
+            position,
+            strand: Strand::Positive,
+            value: DnaBase::C,
+        };
+        // one deeply covered site that differs, three shallow sites that
+        // don't
+        let control = [(0, counts(900, 1000))]
+            .into_iter()
+            .chain((1..4).map(|pos| (pos, counts(1, 10))))
+            .map(|(pos, counts)| (site(pos), counts))
+            .collect::<SiteCounts>();
+        let exp = [(0, counts(100, 1000))]
+            .into_iter()
+            .chain((1..4).map(|pos| (pos, counts(1, 10))))
+            .map(|(pos, counts)| (site(pos), counts))
+            .collect::<SiteCounts>();
+        let effect = |method: RegionEffectSize, quantile: f64| {
+            site_effect_size(method, quantile, &control, &exp).unwrap()
+        };
+        let pooled = effect(RegionEffectSize::pooled, 0.9);
+        assert!((pooled - (903f32 / 1030f32 - 103f32 / 1030f32)).abs() < 1e-6);
+        // coverage capped at 10, (0.9 * 10 + 0.1 * 30) / 40 - 0.1
+        let winsorized = effect(RegionEffectSize::winsorized, 0.5);
+        assert!((winsorized - 0.2).abs() < 1e-6, "{winsorized}");
+        // the cap is the largest coverage, same as pooling
+        let winsorized = effect(RegionEffectSize::winsorized, 1.0);
+        assert!((winsorized - pooled).abs() < 1e-6, "{winsorized}");
+        // per-site differences are [0.8, 0, 0, 0]
+        assert_eq!(effect(RegionEffectSize::median, 0.9), 0f32);
+        // no sites in common
+        let other = [(site(10), counts(1, 10))].into_iter().collect();
+        assert!(site_effect_size(
+            RegionEffectSize::median,
+            0.9,
+            &control,
+            &other
+        )
+        .is_none());
+    }
 }
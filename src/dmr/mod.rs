@@ -4,5 +4,8 @@ mod llr_model;
 mod pairwise;
 mod single_site;
 pub mod subcommands;
+mod summary;
 mod tabix;
+mod tracks;
+mod trend;
 mod util;
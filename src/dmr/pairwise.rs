@@ -1,18 +1,25 @@
+use std::io::Write;
 use std::sync::Arc;
 
-use crate::dmr::bedmethyl::{aggregate_counts, BedMethylLine};
-use crate::dmr::llr_model::{AggregatedCounts, ModificationCounts};
+use crate::dmr::bedmethyl::{
+    aggregate_counts_per_position, BedMethylLine, SiteCounts,
+};
+use crate::dmr::llr_model::{
+    site_effect_size, AggregatedCounts, ModificationCounts, RegionEffectSize,
+};
+use crate::dmr::summary::DmrRunSummary;
 use crate::dmr::tabix::{ChromToSampleBMLines, MultiSampleIndex};
 use crate::dmr::util::{DmrBatch, RegionOfInterest, RoiIter};
 use crate::errs::{MkError, MkResult};
 use crate::monoid::BorrowingMoniod;
 use indicatif::{MultiProgress, ProgressBar};
+use itertools::Itertools;
 use log::{debug, error};
 use rayon::prelude::*;
 use rustc_hash::FxHashMap;
 
 #[inline]
-fn filter_sample_records<'a>(
+pub(super) fn filter_sample_records<'a>(
     sample_records: &'a ChromToSampleBMLines,
     roi: &RegionOfInterest,
     sample_index: &MultiSampleIndex,
@@ -44,21 +51,48 @@ fn filter_sample_records<'a>(
         .unwrap_or_else(|| FxHashMap::default())
 }
 
+/// Combined counts over all samples, the counts for each sample, ordered
+/// by sample index, and the combined counts at each site.
+type PerSampleCounts =
+    (AggregatedCounts, Vec<(usize, AggregatedCounts)>, SiteCounts);
+
 #[inline]
 fn aggregate_counts_per_sample(
     per_sample_filtered_records: &FxHashMap<usize, Vec<&BedMethylLine>>,
     sample_index: &MultiSampleIndex,
-) -> MkResult<AggregatedCounts> {
+) -> MkResult<PerSampleCounts> {
     // per_sample_filtered_records should always have non-zero length vectors
-    let combined_counts = per_sample_filtered_records
-        .values()
-        .map(|records| aggregate_counts(&records, &sample_index.code_lookup))
-        .collect::<MkResult<Vec<AggregatedCounts>>>()?;
-    combined_counts.into_iter().reduce(|a, b| a.op(&b)).ok_or_else(|| {
-        // shouldn't really ever happen?
-        debug!("all samples failed.. check the logs");
-        MkError::DmrMissing
-    })
+    let mut site_counts = SiteCounts::default();
+    let sample_counts = per_sample_filtered_records
+        .iter()
+        .sorted_by_key(|(sample, _)| **sample)
+        .map(|(sample, records)| {
+            let sample_site_counts = aggregate_counts_per_position(
+                &records,
+                &sample_index.code_lookup,
+            )?;
+            let counts = sample_site_counts
+                .values()
+                .fold(AggregatedCounts::zero(), |acc, counts| acc.op(counts));
+            for (pos, counts) in sample_site_counts {
+                site_counts
+                    .entry(pos)
+                    .or_insert_with(AggregatedCounts::zero)
+                    .op_mut(&counts);
+            }
+            Ok((*sample, counts))
+        })
+        .collect::<MkResult<Vec<(usize, AggregatedCounts)>>>()?;
+    let combined_counts = sample_counts
+        .iter()
+        .map(|(_, counts)| counts.clone())
+        .reduce(|a, b| a.op(&b))
+        .ok_or_else(|| {
+            // shouldn't really ever happen?
+            debug!("all samples failed.. check the logs");
+            MkError::DmrMissing
+        })?;
+    Ok((combined_counts, sample_counts, site_counts))
 }
 
 /// Return type here is a little complicated:
@@ -69,6 +103,9 @@ fn aggregate_counts_per_sample(
 pub(super) fn get_modification_counts(
     sample_index: &MultiSampleIndex,
     dmr_batch: DmrBatch<Vec<RegionOfInterest>>,
+    keep_sample_counts: bool,
+    effect_size_method: RegionEffectSize,
+    winsorize_quantile: f64,
 ) -> MkResult<Vec<Result<ModificationCounts, (MkError, Option<MkError>)>>> {
     // these are the bedmethyl records associated with the entire batch.
     // however, due to how tabix works, there will likely be additional
@@ -110,14 +147,46 @@ pub(super) fn get_modification_counts(
                 let exp_counts =
                     aggregate_counts_per_sample(&filtered_b, &sample_index);
                 match (control_counts, exp_counts) {
-                    (Ok(control_counts), Ok(exp_counts)) => {
-                        ModificationCounts::new(
+                    (
+                        Ok((
                             control_counts,
-                            exp_counts,
-                            region_of_interest.dmr_interval,
-                        )
-                        .map_err(|e| (e, None))
-                    }
+                            control_sample_counts,
+                            control_site_counts,
+                        )),
+                        Ok((exp_counts, exp_sample_counts, exp_site_counts)),
+                    ) => ModificationCounts::new(
+                        control_counts,
+                        exp_counts,
+                        region_of_interest.dmr_interval,
+                    )
+                    .map(|counts| {
+                        if effect_size_method == RegionEffectSize::pooled {
+                            return counts;
+                        }
+                        match site_effect_size(
+                            effect_size_method,
+                            winsorize_quantile,
+                            &control_site_counts,
+                            &exp_site_counts,
+                        ) {
+                            Some(effect_size) => {
+                                counts.with_effect_size(effect_size)
+                            }
+                            // e.g. no sites covered in both conditions
+                            None => counts,
+                        }
+                    })
+                    .map(|counts| {
+                        if keep_sample_counts {
+                            counts.with_sample_counts(
+                                control_sample_counts,
+                                exp_sample_counts,
+                            )
+                        } else {
+                            counts
+                        }
+                    })
+                    .map_err(|e| (e, None)),
                     (Err(e), Err(f)) => {
                         debug!(
                             "{}: failed to aggregate control counts, {} and \
@@ -153,6 +222,68 @@ pub(super) fn get_modification_counts(
     Ok(modification_counts_results)
 }
 
+/// Companion output with the counts for each sample that were used to score
+/// each region or site.
+pub(super) struct RawCountsWriter {
+    writer: Box<dyn std::io::Write>,
+    /// Sample names, indexed by sample index.
+    sample_names: Vec<String>,
+}
+
+impl RawCountsWriter {
+    pub(super) fn new(
+        writer: Box<dyn std::io::Write>,
+        sample_names: Vec<String>,
+    ) -> Self {
+        Self { writer, sample_names }
+    }
+
+    pub(super) fn write_header(&mut self) -> std::io::Result<()> {
+        let header = [
+            "#chrom",
+            "start",
+            "end",
+            "name",
+            "strand",
+            "condition",
+            "sample",
+            "counts",
+            "n_modified",
+            "n_valid",
+        ]
+        .join("\t");
+        writeln!(self.writer, "{header}")
+    }
+
+    /// Write one row per sample, `location` is the chrom, start, end, name,
+    /// and strand columns of the region or site.
+    pub(super) fn write_rows(
+        &mut self,
+        location: &str,
+        a_name: &str,
+        b_name: &str,
+        sample_counts: &[(usize, bool, AggregatedCounts)],
+    ) -> std::io::Result<()> {
+        for (idx, is_control, counts) in sample_counts {
+            let condition = if *is_control { a_name } else { b_name };
+            let sample =
+                self.sample_names.get(*idx).map(|s| s.as_str()).unwrap_or(".");
+            writeln!(
+                self.writer,
+                "{location}\t{condition}\t{sample}\t{}\t{}\t{}",
+                counts.string_counts(),
+                counts.modified_counts(),
+                counts.total
+            )?;
+        }
+        Ok(())
+    }
+
+    pub(super) fn flush(&mut self) -> std::io::Result<()> {
+        self.writer.flush()
+    }
+}
+
 pub(super) fn run_pairwise_dmr(
     dmr_interval_iter: RoiIter,
     sample_index: Arc<MultiSampleIndex>,
@@ -160,15 +291,26 @@ pub(super) fn run_pairwise_dmr(
     mut writer: Box<dyn std::io::Write>,
     pb: ProgressBar,
     header: bool,
+    region_columns: &[String],
     a_name: &str,
     b_name: &str,
     failure_counter: ProgressBar,
     batch_failures: ProgressBar,
     multi_progress: MultiProgress,
-) -> anyhow::Result<(usize, FxHashMap<String, usize>)> {
+    mut raw_counts: Option<RawCountsWriter>,
+    effect_size_method: RegionEffectSize,
+    winsorize_quantile: f64,
+) -> anyhow::Result<(usize, FxHashMap<String, usize>, DmrRunSummary)> {
     if header {
-        writer.write(ModificationCounts::header(a_name, b_name).as_bytes())?;
+        writer.write(
+            ModificationCounts::header(a_name, b_name, region_columns)
+                .as_bytes(),
+        )?;
     }
+    if let Some(raw_counts) = raw_counts.as_mut() {
+        raw_counts.write_header()?;
+    }
+    let keep_sample_counts = raw_counts.is_some();
 
     let (snd, rcv) = crossbeam_channel::bounded(1000);
 
@@ -193,7 +335,13 @@ pub(super) fn run_pairwise_dmr(
                     }
                 }
             };
-            match get_modification_counts(&sample_index, batch) {
+            match get_modification_counts(
+                &sample_index,
+                batch,
+                keep_sample_counts,
+                effect_size_method,
+                winsorize_quantile,
+            ) {
                 Ok(results) => {
                     let results = BatchResult::Results(results);
                     match snd.send(results) {
@@ -230,6 +378,7 @@ pub(super) fn run_pairwise_dmr(
 
     let mut success_count = 0;
     let mut region_error_counts = FxHashMap::<String, usize>::default();
+    let mut dmr_summary = DmrRunSummary::new("regions");
     let mut err: Option<MkError> = None;
     'rcv_loop: for batch_result in rcv {
         match batch_result {
@@ -238,6 +387,18 @@ pub(super) fn run_pairwise_dmr(
                     match result {
                         Ok(counts) => {
                             writer.write(counts.to_row()?.as_bytes())?;
+                            if let Some(raw_counts) = raw_counts.as_mut() {
+                                raw_counts.write_rows(
+                                    &counts.location(),
+                                    a_name,
+                                    b_name,
+                                    counts.sample_counts(),
+                                )?;
+                            }
+                            dmr_summary.add(
+                                counts.g_test_pvalue(),
+                                counts.effect_size() as f64,
+                            );
                             success_count += 1;
                             pb.inc(1);
                         }
@@ -284,10 +445,13 @@ pub(super) fn run_pairwise_dmr(
     }
 
     pb.finish_and_clear();
+    if let Some(raw_counts) = raw_counts.as_mut() {
+        raw_counts.flush()?;
+    }
 
     if let Some(e) = err {
         Err(e.into())
     } else {
-        Ok((success_count, region_error_counts))
+        Ok((success_count, region_error_counts, dmr_summary))
     }
 }
@@ -16,15 +16,19 @@ use rustc_hash::FxHashMap;
 
 use crate::dmr::beta_diff::{BetaParams, PMapEstimator};
 use crate::dmr::llr_model::{llk_ratio, AggregatedCounts};
+use crate::dmr::pairwise::RawCountsWriter;
+use crate::dmr::summary::DmrRunSummary;
 use crate::dmr::tabix::{
     MultiSampleIndex, SampleToChromBMLines, SingleSiteSampleIndex,
 };
+use crate::dmr::tracks::DmrBigWigTrack;
 use crate::dmr::util::{cohen_h, DmrBatchOfPositions};
 use crate::errs::{MkError, MkResult};
 use crate::genome_positions::{GenomePositions, StrandedPosition};
 use crate::hmm::{HmmModel, States};
 use crate::mod_base_code::{DnaBase, ModCodeRepr};
 use crate::monoid::BorrowingMoniod;
+use crate::run_summary;
 use crate::thresholds::percentile_linear_interp;
 use crate::util::{
     format_errors_table, get_subroutine_progress_bar, get_ticker, Region,
@@ -46,7 +50,7 @@ pub(super) struct SingleSiteDmrAnalysis {
 impl SingleSiteDmrAnalysis {
     pub(super) fn new(
         sample_index: MultiSampleIndex,
-        genome_positions: GenomePositions,
+        genome_positions: Arc<GenomePositions>,
         cap_coverages: bool,
         num_a: usize,
         num_b: usize,
@@ -64,7 +68,6 @@ impl SingleSiteDmrAnalysis {
         let sample_index =
             SingleSiteSampleIndex::new(sample_index, num_a, num_b)
                 .map(|x| Arc::new(x))?;
-        let genome_positions = Arc::new(genome_positions);
         if cap_coverages {
             info!("capping coverages when combining samples");
         }
@@ -140,7 +143,9 @@ impl SingleSiteDmrAnalysis {
         decay_distance: u32,
         linear_transitions: bool,
         mut writer: Box<dyn Write>,
-    ) -> anyhow::Result<()> {
+        mut bigwig_track: Option<DmrBigWigTrack>,
+        mut raw_counts: Option<RawCountsWriter>,
+    ) -> anyhow::Result<DmrRunSummary> {
         let matched_samples = self.sample_index.matched_replicate_samples();
         let multiple_samples = self.sample_index.multiple_samples();
         if matched_samples {
@@ -174,6 +179,10 @@ impl SingleSiteDmrAnalysis {
             } else {
                 Box::new(DummySegmenter::new())
             };
+        if let Some(raw_counts) = raw_counts.as_mut() {
+            raw_counts.write_header()?;
+        }
+        let keep_sample_counts = raw_counts.is_some();
 
         let (scores_snd, scores_rcv) = crossbeam::channel::bounded(1000);
         let processed_batches = self.multi_progress.add(get_ticker());
@@ -212,6 +221,7 @@ impl SingleSiteDmrAnalysis {
                                     batch_of_positions,
                                     sample_index.clone(),
                                     pmap_estimator.clone(),
+                                    keep_sample_counts,
                                 )
                             })
                             .collect::<Vec<MkResult<Vec<ChromToSingleScores>>>>(
@@ -273,6 +283,7 @@ impl SingleSiteDmrAnalysis {
 
         let mut success_count = 0usize;
         let mut error_counts = FxHashMap::<String, usize>::default();
+        let mut dmr_summary = DmrRunSummary::new("sites");
         let mut err: Option<MkError> = None;
         'rcv_loop: for batch_result in scores_rcv {
             match batch_result {
@@ -311,6 +322,33 @@ impl SingleSiteDmrAnalysis {
                                             )
                                             .as_bytes(),
                                     )?;
+                                    if let Some(raw_counts) =
+                                        raw_counts.as_mut()
+                                    {
+                                        raw_counts.write_rows(
+                                            &scores.location(&chrom),
+                                            "a",
+                                            "b",
+                                            &scores.sample_counts,
+                                        )?;
+                                    }
+                                    if let Some(track) = bigwig_track.as_mut() {
+                                        let value =
+                                            track.track_value().transform(
+                                                scores.effect_size,
+                                                scores.score,
+                                                scores.map_pval,
+                                            );
+                                        track.add(
+                                            &chrom,
+                                            scores.position,
+                                            value,
+                                        )?;
+                                    }
+                                    dmr_summary.add(
+                                        scores.map_pval,
+                                        scores.effect_size,
+                                    );
                                     success_counter.inc(1);
                                     success_count += 1;
                                 }
@@ -353,8 +391,16 @@ impl SingleSiteDmrAnalysis {
             return Err(e.into());
         }
 
+        if let Some(track) = bigwig_track {
+            track.finish()?;
+        }
+        if let Some(raw_counts) = raw_counts.as_mut() {
+            raw_counts.flush()?;
+        }
+
         if !error_counts.is_empty() {
             self.multi_progress.suspend(|| {
+                run_summary::record_errors(&error_counts);
                 let error_table = format_errors_table(&error_counts);
                 error!("errors:\n{error_table}");
             });
@@ -365,7 +411,7 @@ impl SingleSiteDmrAnalysis {
             success_count,
             failure_counter.position(),
         );
-        Ok(())
+        Ok(dmr_summary)
     }
 }
 
@@ -447,20 +493,21 @@ impl SingleSiteBatches {
                 self.curr_contig_end,
             );
             let interval = self.curr_pos..end;
-            if let Some(positions) = self.genome_positions.get_positions(
+            let n_positions = self.genome_positions.count_positions(
                 &self.curr_contig,
                 &interval,
                 StrandRule::Both,
-            ) {
-                let interval_length = positions.len() as u64;
+            );
+            if n_positions > 0 {
                 current_batch.add_chunks(
                     &self.curr_contig,
                     interval,
-                    positions,
+                    &self.genome_positions,
+                    n_positions,
                     &self.sample_index.control_idxs,
                     &self.sample_index.exp_idxs,
                 );
-                current_batch_length += interval_length;
+                current_batch_length += n_positions as u64;
             }
 
             if current_batch_length >= self.interval_size {
@@ -516,6 +563,9 @@ struct SingleSiteDmrScore {
     replicate_effect_sizes: Vec<f64>,
     pct_a_samples: usize,
     pct_b_samples: usize,
+    /// Per-sample counts, (sample index, is control, counts), only kept
+    /// when writing --raw-counts.
+    sample_counts: Vec<(usize, bool, AggregatedCounts)>,
 }
 
 impl SingleSiteDmrScore {
@@ -565,12 +615,13 @@ impl SingleSiteDmrScore {
     }
 
     fn new_multi(
-        counts_a: &[AggregatedCounts],
-        counts_b: &[AggregatedCounts],
+        counts_a: &[(usize, AggregatedCounts)],
+        counts_b: &[(usize, AggregatedCounts)],
         sample_index: &SingleSiteSampleIndex,
         position: u64,
         strand: Strand,
         estimator: &PMapEstimator,
+        keep_sample_counts: bool,
     ) -> MkResult<Self> {
         let (replicate_epmap, replicate_effect_sizes) = if sample_index
             .matched_replicate_samples()
@@ -579,7 +630,7 @@ impl SingleSiteDmrScore {
             let n_samples = counts_a.len();
             let mut replicate_epmap = Vec::with_capacity(n_samples);
             let mut replicate_effect_size = Vec::with_capacity(n_samples);
-            for (a, b) in counts_a.iter().zip(counts_b) {
+            for ((_, a), (_, b)) in counts_a.iter().zip(counts_b) {
                 let epmap = estimator.predict(a, b).map_err(|e| {
                     debug!("failed to calculate MAP-based p-value, {e}");
                     MkError::BetaDiffCalcError
@@ -618,6 +669,19 @@ impl SingleSiteDmrScore {
             })?;
         let llr_score = llk_ratio(&collapsed_a, &collapsed_b)?;
         let cohen_result = cohen_h(&collapsed_a, &collapsed_b);
+        let sample_counts = if keep_sample_counts {
+            counts_a
+                .iter()
+                .map(|(idx, counts)| (*idx, true, counts.clone()))
+                .chain(
+                    counts_b
+                        .iter()
+                        .map(|(idx, counts)| (*idx, false, counts.clone())),
+                )
+                .collect()
+        } else {
+            Vec::new()
+        };
         Ok(Self {
             counts_a: collapsed_a,
             counts_b: collapsed_b,
@@ -636,9 +700,20 @@ impl SingleSiteDmrScore {
             replicate_effect_sizes,
             pct_a_samples,
             pct_b_samples,
+            sample_counts,
         })
     }
 
+    /// The chrom, start, end, name, and strand columns of this site.
+    fn location(&self, chrom: &str) -> String {
+        format!(
+            "{chrom}\t{}\t{}\t.\t{}",
+            self.position,
+            self.position.saturating_add(1),
+            self.strand.to_char()
+        )
+    }
+
     fn to_row(
         &self,
         multiple_samples: bool,
@@ -777,16 +852,16 @@ impl SingleSiteDmrScore {
 }
 
 fn collapse_counts(
-    counts: &[AggregatedCounts],
+    counts: &[(usize, AggregatedCounts)],
     balance: bool,
 ) -> AggregatedCounts {
     if counts.len() == 1 {
-        counts[0].clone()
+        counts[0].1.clone()
     } else if balance {
-        let total_cov = counts.iter().map(|ac| ac.total).sum::<usize>();
+        let total_cov = counts.iter().map(|(_, ac)| ac.total).sum::<usize>();
         let n = counts.len();
         let target_cov = total_cov as f32 / n as f32;
-        counts.iter().fold(AggregatedCounts::zero(), |agg, next| {
+        counts.iter().fold(AggregatedCounts::zero(), |agg, (_, next)| {
             let counts = next
                 .iter_mod_fractions()
                 .map(|(code, frac)| {
@@ -798,7 +873,9 @@ fn collapse_counts(
             agg.op(&ac)
         })
     } else {
-        counts.iter().fold(AggregatedCounts::zero(), |agg, next| agg.op(next))
+        counts
+            .iter()
+            .fold(AggregatedCounts::zero(), |agg, (_, next)| agg.op(next))
     }
 }
 
@@ -807,6 +884,7 @@ fn process_batch_of_positions(
     batch: DmrBatchOfPositions,
     sample_index: Arc<SingleSiteSampleIndex>,
     pmap_estimator: Arc<PMapEstimator>,
+    keep_sample_counts: bool,
 ) -> MkResult<Vec<ChromToSingleScores>> {
     let (a_lines, b_lines) =
         sample_index.read_bedmethyl_lines_organized_by_position(batch)?;
@@ -839,6 +917,7 @@ fn process_batch_of_positions(
                             pos.position,
                             pos.strand,
                             &pmap_estimator,
+                            keep_sample_counts,
                         )
                     })
                 })
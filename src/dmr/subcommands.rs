@@ -12,17 +12,25 @@ use log::{debug, error, info};
 use prettytable::row;
 use rustc_hash::FxHashMap;
 
-use crate::dmr::bedmethyl::BedMethylLine;
-use crate::dmr::pairwise::run_pairwise_dmr;
+use crate::command_utils::{DryRunPlan, IoRetryArgs};
+use crate::dmr::bedmethyl::{BedMethylLine, DmrInputFormat};
+use crate::dmr::llr_model::RegionEffectSize;
+use crate::dmr::pairwise::{run_pairwise_dmr, RawCountsWriter};
 use crate::dmr::single_site::SingleSiteDmrAnalysis;
-use crate::dmr::tabix::MultiSampleIndex;
-use crate::dmr::util::{parse_roi_bed, HandleMissing, RoiIter};
+use crate::dmr::tabix::{MultiSampleIndex, ReferenceCheck};
+use crate::dmr::tracks::{DmrBigWigTrack, TrackValue};
+use crate::dmr::trend::{run_trend_dmr, TrendDesign, TrendResult, TrendTest};
+use crate::dmr::util::{
+    parse_roi_bed, roi_extra_column_names, HandleMissing, RoiIter,
+};
 use crate::errs::MkResult;
 use crate::genome_positions::GenomePositions;
 use crate::logging::init_logging;
-use crate::mod_base_code::{DnaBase, ModCodeRepr, MOD_CODE_TO_DNA_BASE};
+use crate::mod_base_code::{DnaBase, ModCodeRepr};
+use crate::mod_code_registry::ModCodeRegistry;
 use crate::monoid::Moniod;
-use crate::tabix::{BedMethylTbxIndex, HtsTabixHandler};
+use crate::run_summary;
+use crate::tabix::{is_remote_path, BedMethylTbxIndex, HtsTabixHandler};
 use crate::util::{
     create_out_directory, format_errors_table, get_master_progress_bar,
     get_subroutine_progress_bar, get_ticker,
@@ -36,7 +44,7 @@ pub enum BedMethylDmr {
     /// Output is a BED file with the score column indicating the magnitude of
     /// the difference in methylation between the two samples. See the online
     /// documentation for additional details.
-    Pair(PairwiseDmr),
+    Pair(Box<PairwiseDmr>),
     /// Compare regions between all pairs of samples (for example a trio sample
     /// set or haplotyped trio sample set). As with `pair` all inputs must be
     /// bgzip compressed bedMethyl files with associated tabix indices.
@@ -45,6 +53,13 @@ pub enum BedMethylDmr {
     /// difference in methylation between the two samples indicated in the
     /// file name. See the online documentation for additional details.
     Multi(MultiSampleDmr),
+    /// Test for monotonic trends in methylation over ordered groups of
+    /// samples (for example a time-course or dose-response series) in
+    /// regions. Each sample is a bgzip bedMethyl with an associated tabix
+    /// index, assigned to a named group. Output is a BED file with the
+    /// score column indicating the significance of the trend. See the online
+    /// documentation for additional details.
+    Trend(TrendDmr),
 }
 
 impl BedMethylDmr {
@@ -52,6 +67,7 @@ impl BedMethylDmr {
         match self {
             Self::Pair(x) => x.run(),
             Self::Multi(x) => x.run(),
+            Self::Trend(x) => x.run(),
         }
     }
 }
@@ -60,14 +76,15 @@ impl BedMethylDmr {
 #[command(arg_required_else_help = true)]
 pub struct PairwiseDmr {
     /// Bgzipped bedMethyl file for the first (usually control) sample. There
-    /// should be a tabix index with the same name and .tbi next to this
-    /// file or the --index-a option must be provided.
+    /// should be a tabix index with the same name and .tbi (or .csi) next to
+    /// this file. Can be an https:// URL, the index is then fetched from the
+    /// same URL with .tbi (or .csi) appended.
     #[clap(help_heading = "Sample Options")]
     #[arg(short = 'a')]
     control_bed_methyl: Vec<PathBuf>,
     /// Bgzipped bedMethyl file for the second (usually experimental) sample.
-    /// There should be a tabix index with the same name and .tbi next to
-    /// this file or the --index-b option must be provided.
+    /// There should be a tabix index with the same name and .tbi (or .csi)
+    /// next to this file. Can be an https:// URL, as with -a.
     #[clap(help_heading = "Sample Options")]
     #[arg(short = 'b')]
     exp_bed_methyl: Vec<PathBuf>,
@@ -80,6 +97,42 @@ pub struct PairwiseDmr {
     #[clap(help_heading = "Output Options")]
     #[arg(long, alias = "with-header", default_value_t = false)]
     header: bool,
+    /// Also write a per-site bigWig track to this file, only available with
+    /// single-site analysis. The value written at each site is set with
+    /// --bigwig-value. Sequence sizes are taken from the reference.
+    #[clap(help_heading = "Output Options")]
+    #[arg(long, conflicts_with = "regions_bed")]
+    bigwig: Option<PathBuf>,
+    /// Value to write to the bigWig track at each site.
+    #[clap(help_heading = "Output Options")]
+    #[arg(long, requires = "bigwig", default_value_t = TrackValue::effect_size)]
+    bigwig_value: TrackValue,
+    /// Also write the counts from each sample that were used to score each
+    /// region (or site) to this file, one row per sample per region (or
+    /// site).
+    #[clap(help_heading = "Output Options")]
+    #[arg(long)]
+    raw_counts: Option<PathBuf>,
+    /// Also write a run-level summary to this file as JSON, the number of
+    /// significant regions (or sites) at several p-value thresholds, the
+    /// distribution of effect sizes, and the genomic inflation factor
+    /// (lambda) estimated from the p-values. The summary is always logged.
+    #[clap(help_heading = "Output Options")]
+    #[arg(long)]
+    summary: Option<PathBuf>,
+    /// How the effect_size column of each region is estimated. By default
+    /// the counts of all sites in the region are pooled, so a few deeply
+    /// covered sites (e.g. in amplicon data) can dominate. "winsorized" caps
+    /// the coverage of each site before pooling, "median" takes the median of
+    /// the per-site differences. Only changes the effect_size column.
+    #[clap(help_heading = "Output Options")]
+    #[arg(long, requires = "regions_bed", default_value_t = RegionEffectSize::pooled)]
+    region_effect_size: RegionEffectSize,
+    /// With --region-effect-size winsorized, cap the valid coverage of each
+    /// site at this quantile of the site coverages in the region.
+    #[clap(help_heading = "Output Options")]
+    #[arg(long, default_value_t = 0.9, hide_short_help = true)]
+    winsorize_quantile: f64,
     /// BED file of regions over which to compare methylation levels. Should be
     /// tab-separated (spaces allowed in the "name" column). Requires
     /// chrom, chromStart and chromEnd. The Name column is optional. Strand
@@ -87,6 +140,18 @@ pub struct PairwiseDmr {
     /// each site.
     #[arg(long, short = 'r', alias = "regions")]
     regions_bed: Option<PathBuf>,
+    /// Append the columns after the strand (column 6) of the `--regions` BED
+    /// file to each output row, for example gene IDs or categories. Column
+    /// names in the header are taken from the last `#` line of the BED file
+    /// when it names every column.
+    #[clap(help_heading = "Output Options")]
+    #[arg(
+        long,
+        requires = "regions_bed",
+        default_value_t = false,
+        hide_short_help = true
+    )]
+    keep_region_columns: bool,
     /// Path to reference fasta for used in the pileup/alignment.
     #[arg(long = "ref")]
     reference_fasta: PathBuf,
@@ -175,9 +240,10 @@ pub struct PairwiseDmr {
     /// bedMethyl file contains custom codes or codes that are not part of
     /// the specification, you can specify which primary base they
     /// belong to here with --assign-code x:C meaning associate modification
-    /// code "x" with cytosine (C) primary sequence bases. If a code is
-    /// encountered that is not part of the specification, the bedMethyl
-    /// record will not be used, this will be logged.
+    /// code "x" with cytosine (C) primary sequence bases. Codes added with
+    /// `--mod-code-file` are also used. If a code is encountered that is not
+    /// part of the specification, the bedMethyl record will not be used,
+    /// this will be logged.
     #[clap(help_heading = "Sample Options")]
     #[arg(long="assign-code", action=clap::ArgAction::Append)]
     mod_code_assignments: Option<Vec<String>>,
@@ -204,6 +270,8 @@ pub struct PairwiseDmr {
     #[clap(help_heading = "Compute Options")]
     #[arg(long, default_value_t = 4)]
     io_threads: usize,
+    #[clap(flatten)]
+    io_retry_args: IoRetryArgs,
     /// Control the  batch size. The batch size is the number of regions to
     /// load at a time. Each region will be processed concurrently. Loading
     /// more regions at a time will decrease IO to load data, but will use
@@ -216,6 +284,13 @@ pub struct PairwiseDmr {
     #[clap(help_heading = "Sample Options")]
     #[arg(long, short = 'k', default_value_t = false)]
     mask: bool,
+    /// Fail when a bedMethyl record doesn't match the reference, the primary
+    /// base of its modification code isn't at its position (e.g. the sample
+    /// was made with a different assembly). By default these records are
+    /// skipped and the number for each sample is logged.
+    #[clap(help_heading = "Sample Options")]
+    #[arg(long, default_value_t = false)]
+    strict: bool,
     /// Don't show progress bars
     #[clap(help_heading = "Logging Options")]
     #[arg(long, default_value_t = false)]
@@ -224,6 +299,11 @@ pub struct PairwiseDmr {
     #[clap(help_heading = "Compute Options")]
     #[arg(short = 'f', long, default_value_t = false)]
     force: bool,
+    /// Validate the samples, reference, and regions, print the planned
+    /// analysis and output files, then exit without writing anything.
+    #[clap(help_heading = "Compute Options")]
+    #[arg(long, default_value_t = false)]
+    dry_run: bool,
     /// How to handle regions found in the `--regions` BED file.
     /// quiet => ignore regions that are not found in the tabix header
     /// warn => log (debug) regions that are missing
@@ -237,6 +317,22 @@ pub struct PairwiseDmr {
     #[clap(help_heading = "Sample Options")]
     #[arg(long, alias = "min-coverage", default_value_t = 0)]
     min_valid_coverage: u64,
+    /// Combine the counts for the positive and negative strand records of
+    /// each CpG, as `pileup --combine-strands` would, when the input
+    /// bedMethyls have stranded records. Records on the negative strand are
+    /// moved to the cytosine on the positive strand, the inputs should be
+    /// CpG pileups (e.g. made with --cpg).
+    #[clap(help_heading = "Sample Options")]
+    #[arg(long, default_value_t = false)]
+    combine_strands: bool,
+    /// Format of the sample inputs. With "auto" the format of each input is
+    /// detected from its first record. Bismark coverage or cytosine report
+    /// files and methylKit files are converted to 5mC (m) bedMethyl records,
+    /// they must be bgzip-compressed and tabix-indexed as with bedMethyl
+    /// inputs.
+    #[clap(help_heading = "Sample Options")]
+    #[arg(long, value_enum, default_value_t = DmrInputFormat::auto)]
+    input_format: DmrInputFormat,
     /// Prior distribution for estimating MAP-based p-value. Should be two
     /// arguments for alpha and beta (e.g. 1.0 1.0). See
     /// `dmr_scoring_details.md` for additional details on how the metric
@@ -353,13 +449,13 @@ impl PairwiseDmr {
                     }
                 },
             )?;
-            Ok(MOD_CODE_TO_DNA_BASE
-                .clone()
+            Ok(ModCodeRegistry::global()
+                .code_to_base()
                 .into_iter()
                 .chain(user_assignments.into_iter())
                 .collect())
         } else {
-            Ok(MOD_CODE_TO_DNA_BASE.clone())
+            Ok(ModCodeRegistry::global().code_to_base())
         }
     }
 
@@ -382,6 +478,86 @@ impl PairwiseDmr {
         Self::parse_raw_assignments(raw_mod_code_assignments)
     }
 
+    /// Check the outputs before loading anything, existing files are only
+    /// overwritten with `--force`.
+    fn check_outputs(&self) -> anyhow::Result<()> {
+        if self.force {
+            return Ok(());
+        }
+        let existing = self
+            .out_path
+            .as_ref()
+            .map(PathBuf::from)
+            .into_iter()
+            .chain(self.summary.iter().cloned())
+            .chain(self.raw_counts.iter().cloned())
+            .chain(self.bigwig.iter().cloned())
+            .find(|fp| fp.exists());
+        if let Some(fp) = existing {
+            bail!("refusing to overwrite existing file {fp:?}")
+        }
+        Ok(())
+    }
+
+    /// Print what a run would do, see `--dry-run`.
+    fn print_dry_run_plan(
+        &self,
+        common_contigs: usize,
+        batch_size: usize,
+    ) -> anyhow::Result<()> {
+        let mut plan = DryRunPlan::new("dmr pair");
+        let sample_paths = |fps: &[PathBuf]| {
+            fps.iter().map(|fp| fp.to_string_lossy()).join(", ")
+        };
+        plan.add_step("a samples", sample_paths(&self.control_bed_methyl));
+        plan.add_step("b samples", sample_paths(&self.exp_bed_methyl));
+        plan.add_step(
+            "reference",
+            format!(
+                "{}, {common_contigs} sequence(s) common with both samples",
+                self.reference_fasta.to_string_lossy()
+            ),
+        );
+        plan.add_step("modified bases", self.modified_bases.iter().join(", "));
+        match self.regions_bed.as_ref() {
+            Some(roi_bed) => {
+                let rois = parse_roi_bed(roi_bed, self.keep_region_columns)
+                    .with_context(|| {
+                        format!(
+                            "failed to parse supplied regions at {roi_bed:?}"
+                        )
+                    })?;
+                plan.add_step(
+                    "regions",
+                    format!(
+                        "{} from {}, in batches of {batch_size}",
+                        rois.len(),
+                        roi_bed.to_string_lossy()
+                    ),
+                );
+            }
+            None => plan.add_step(
+                "single-site",
+                format!(
+                    "intervals of {} bp, in batches of {batch_size}",
+                    self.interval_size
+                ),
+            ),
+        }
+        plan.add_output(
+            self.out_path.clone().unwrap_or_else(|| "stdout".to_string()),
+        );
+        for fp in self
+            .summary
+            .iter()
+            .chain(self.raw_counts.iter())
+            .chain(self.bigwig.iter())
+        {
+            plan.add_output(fp.to_string_lossy().to_string());
+        }
+        plan.print()
+    }
+
     pub fn run(&self) -> anyhow::Result<()> {
         let _handle = init_logging(self.log_filepath.as_ref());
         let pool = rayon::ThreadPoolBuilder::new()
@@ -391,7 +567,11 @@ impl PairwiseDmr {
         {
             bail!("need to provide at least 1 'a' sample and 'b' sample")
         }
+        if !(0f64..=1f64).contains(&self.winsorize_quantile) {
+            bail!("--winsorize-quantile must be between 0 and 1")
+        }
         let code_lookup = self.check_modified_bases()?;
+        self.check_outputs()?;
 
         let mpb = MultiProgress::new();
         if self.suppress_progress {
@@ -414,15 +594,22 @@ impl PairwiseDmr {
             );
         }
 
+        let io_retry = self.io_retry_args.io_retry()?;
         let a_handlers = self
             .control_bed_methyl
             .iter()
-            .map(|fp| BedMethylTbxIndex::from_path(fp))
+            .map(|fp| {
+                BedMethylTbxIndex::from_path(fp)
+                    .map(|handler| handler.with_retry(io_retry))
+            })
             .collect::<anyhow::Result<Vec<BedMethylTbxIndex>>>()?;
         let b_handlers = self
             .exp_bed_methyl
             .iter()
-            .map(|fp| HtsTabixHandler::<BedMethylLine>::from_path(fp))
+            .map(|fp| {
+                HtsTabixHandler::<BedMethylLine>::from_path(fp)
+                    .map(|handler| handler.with_retry(io_retry))
+            })
             .collect::<anyhow::Result<Vec<BedMethylTbxIndex>>>()?;
         let handlers = a_handlers
             .into_iter()
@@ -434,37 +621,36 @@ impl PairwiseDmr {
             code_lookup,
             self.min_valid_coverage,
             self.io_threads,
-        );
+        )
+        .with_combine_strands(self.combine_strands)
+        .with_input_format(self.input_format)?;
         let total = self.control_bed_methyl.len() + self.exp_bed_methyl.len();
         let control_idxs =
             (0..self.control_bed_methyl.len()).collect::<Vec<usize>>();
         let exp_idxs =
             (self.control_bed_methyl.len()..total).collect::<Vec<usize>>();
 
-        let writer: Box<dyn Write> = {
-            match self.out_path.as_ref() {
-                None => Box::new(BufWriter::new(std::io::stdout())),
-                Some(fp) => {
-                    let p = Path::new(fp);
-                    create_out_directory(p)?;
-                    if p.exists() && !self.force {
-                        bail!("refusing to overwrite existing file {}", fp)
-                    } else {
-                        let fh = File::create(p)?;
-                        Box::new(BufWriter::new(fh))
-                    }
-                }
-            }
-        };
-
         info!("reading reference FASTA at {:?}", self.reference_fasta);
-        let genome_positions = GenomePositions::new_from_sequences(
+        let genome_positions = Arc::new(GenomePositions::new_from_sequences(
             &modified_bases,
             &self.reference_fasta,
             self.mask,
             &sample_index.all_contigs(),
             &mpb,
-        )?;
+        )?);
+        let sample_names = self
+            .control_bed_methyl
+            .iter()
+            .chain(self.exp_bed_methyl.iter())
+            .map(|p| p.to_string_lossy().to_string())
+            .collect::<Vec<String>>();
+        let reference_check = Arc::new(ReferenceCheck::new(
+            genome_positions.clone(),
+            self.strict,
+            sample_names.clone(),
+        ));
+        let sample_index =
+            sample_index.with_reference_check(reference_check.clone());
         let mut tab = prettytable::Table::new();
         tab.set_format(
             *prettytable::format::consts::FORMAT_NO_LINESEP_WITH_TITLE,
@@ -501,6 +687,29 @@ impl PairwiseDmr {
                 (self.threads as f32 * 1.5f32).floor() as usize
             });
 
+        if self.dry_run {
+            return self.print_dry_run_plan(common_contigs, batch_size);
+        }
+
+        let writer: Box<dyn Write> = match self.out_path.as_ref() {
+            None => Box::new(BufWriter::new(std::io::stdout())),
+            Some(fp) => {
+                let p = Path::new(fp);
+                create_out_directory(p)?;
+                Box::new(BufWriter::new(File::create(p)?))
+            }
+        };
+        let raw_counts = self
+            .raw_counts
+            .as_ref()
+            .map(|fp| -> anyhow::Result<RawCountsWriter> {
+                create_out_directory(fp)?;
+                let writer: Box<dyn Write> =
+                    Box::new(BufWriter::new(File::create(fp)?));
+                Ok(RawCountsWriter::new(writer, sample_names))
+            })
+            .transpose()?;
+
         if self.is_single_site() {
             info!("running single-site analysis");
             let linear_transitions = if self.fine_grained {
@@ -508,7 +717,20 @@ impl PairwiseDmr {
             } else {
                 !self.log_transition_decay
             };
-            return SingleSiteDmrAnalysis::new(
+            let bigwig_track = self
+                .bigwig
+                .as_ref()
+                .map(|fp| {
+                    DmrBigWigTrack::new(
+                        fp,
+                        self.force,
+                        self.bigwig_value,
+                        genome_positions.contig_sizes(),
+                        self.threads,
+                    )
+                })
+                .transpose()?;
+            let dmr_summary = SingleSiteDmrAnalysis::new(
                 sample_index,
                 genome_positions,
                 self.cap_coverages,
@@ -534,25 +756,33 @@ impl PairwiseDmr {
                 self.decay_distance,
                 linear_transitions,
                 writer,
-            );
+                bigwig_track,
+                raw_counts,
+            )?;
+            reference_check.report();
+            return dmr_summary
+                .with_reference_mismatches(reference_check.counts())
+                .report(self.summary.as_ref());
         }
 
         let sample_index = Arc::new(sample_index);
-        let genome_positions = Arc::new(genome_positions);
 
-        let regions_of_interest =
-            if let Some(roi_bed) = self.regions_bed.as_ref() {
-                let rois = parse_roi_bed(roi_bed).with_context(|| {
+        let (regions_of_interest, region_columns) = if let Some(roi_bed) =
+            self.regions_bed.as_ref()
+        {
+            let rois = parse_roi_bed(roi_bed, self.keep_region_columns)
+                .with_context(|| {
                     format!("failed to parse supplied regions at {roi_bed:?}")
                 })?;
-                info!("loaded {} regions", rois.len());
-                rois
-            } else {
-                unreachable!(
-                    "regions should always be available unless we're doing \
+            info!("loaded {} regions", rois.len());
+            let region_columns = roi_extra_column_names(roi_bed, &rois)?;
+            (rois, region_columns)
+        } else {
+            unreachable!(
+                "regions should always be available unless we're doing \
                      single-site analysis"
-                )
-            };
+            )
+        };
 
         info!("loading {batch_size} regions at a time");
 
@@ -576,18 +806,22 @@ impl PairwiseDmr {
             &mpb,
         )?;
 
-        let (success_count, region_errors) = run_pairwise_dmr(
+        let (success_count, region_errors, dmr_summary) = run_pairwise_dmr(
             dmr_interval_iter,
             sample_index.clone(),
             pool,
             writer,
             pb,
             self.header,
+            &region_columns,
             "a",
             "b",
             failures.clone(),
             batch_failures.clone(),
             mpb.clone(),
+            raw_counts,
+            self.region_effect_size,
+            self.winsorize_quantile,
         )?;
 
         mpb.suspend(|| {
@@ -597,12 +831,18 @@ impl PairwiseDmr {
                 failures.position()
             );
             if !region_errors.is_empty() {
+                run_summary::record_errors(&region_errors);
                 let tab = format_errors_table(&region_errors);
                 error!("region errors:\n{tab}");
             }
         });
 
-        Ok(())
+        mpb.suspend(|| {
+            reference_check.report();
+            dmr_summary
+                .with_reference_mismatches(reference_check.counts())
+                .report(self.summary.as_ref())
+        })
     }
 }
 
@@ -611,7 +851,8 @@ impl PairwiseDmr {
 pub struct MultiSampleDmr {
     /// Two or more named samples to compare. Two arguments are required <path>
     /// <name>. This option should be repeated at least two times. When two
-    /// samples have the same name, they will be combined.
+    /// samples have the same name, they will be combined. The path can be an
+    /// https:// URL to a remote bgzipped bedMethyl with a tabix index.
     #[clap(help_heading = "Sample Options")]
     #[arg(short = 's', long = "sample", num_args = 2)]
     samples: Vec<String>,
@@ -622,6 +863,13 @@ pub struct MultiSampleDmr {
     #[clap(help_heading = "Sample Options")]
     #[arg(long, short = 'r', alias = "regions")]
     regions_bed: PathBuf,
+    /// Append the columns after the strand (column 6) of the `--regions` BED
+    /// file to each output row, for example gene IDs or categories. Column
+    /// names in the header are taken from the last `#` line of the BED file
+    /// when it names every column.
+    #[clap(help_heading = "Output Options")]
+    #[arg(long, default_value_t = false, hide_short_help = true)]
+    keep_region_columns: bool,
     /// Include header in output
     #[clap(help_heading = "Output Options")]
     #[arg(long, alias = "with-header", default_value_t = false)]
@@ -634,6 +882,19 @@ pub struct MultiSampleDmr {
     #[clap(help_heading = "Output Options")]
     #[arg(short = 'p', long)]
     prefix: Option<String>,
+    /// How the effect_size column of each region is estimated. By default
+    /// the counts of all sites in the region are pooled, so a few deeply
+    /// covered sites (e.g. in amplicon data) can dominate. "winsorized" caps
+    /// the coverage of each site before pooling, "median" takes the median of
+    /// the per-site differences. Only changes the effect_size column.
+    #[clap(help_heading = "Output Options")]
+    #[arg(long, default_value_t = RegionEffectSize::pooled)]
+    region_effect_size: RegionEffectSize,
+    /// With --region-effect-size winsorized, cap the valid coverage of each
+    /// site at this quantile of the site coverages in the region.
+    #[clap(help_heading = "Output Options")]
+    #[arg(long, default_value_t = 0.9, hide_short_help = true)]
+    winsorize_quantile: f64,
     /// Path to reference fasta for the pileup.
     #[clap(help_heading = "Sample Options")]
     #[arg(long = "ref")]
@@ -652,9 +913,10 @@ pub struct MultiSampleDmr {
     /// bedMethyl file contains custom codes or codes that are not part of
     /// the specification, you can specify which primary base they
     /// belong to here with --assign-code x:C meaning associate modification
-    /// code "x" with cytosine (C) primary sequence bases. If a code is
-    /// encountered that is not part of the specification, the bedMethyl
-    /// record will not be used, this will be logged.
+    /// code "x" with cytosine (C) primary sequence bases. Codes added with
+    /// `--mod-code-file` are also used. If a code is encountered that is not
+    /// part of the specification, the bedMethyl record will not be used,
+    /// this will be logged.
     #[clap(help_heading = "Sample Options")]
     #[arg(long="assign-code", action=clap::ArgAction::Append)]
     mod_code_assignments: Option<Vec<String>>,
@@ -670,10 +932,19 @@ pub struct MultiSampleDmr {
     #[clap(help_heading = "Compute Options")]
     #[arg(long, default_value_t = 4)]
     io_threads: usize,
+    #[clap(flatten)]
+    io_retry_args: IoRetryArgs,
     /// Respect soft masking in the reference FASTA.
     #[clap(help_heading = "Sample Options")]
     #[arg(long, short = 'k', default_value_t = false)]
     mask: bool,
+    /// Fail when a bedMethyl record doesn't match the reference, the primary
+    /// base of its modification code isn't at its position (e.g. the sample
+    /// was made with a different assembly). By default these records are
+    /// skipped and the number for each sample is logged.
+    #[clap(help_heading = "Sample Options")]
+    #[arg(long, default_value_t = false)]
+    strict: bool,
     /// Don't show progress bars
     #[clap(help_heading = "Logging Options")]
     #[arg(long, default_value_t = false)]
@@ -682,6 +953,11 @@ pub struct MultiSampleDmr {
     #[clap(help_heading = "Output Options")]
     #[arg(short = 'f', long, default_value_t = false)]
     force: bool,
+    /// Validate the samples, reference, and regions, print the planned
+    /// comparisons and output files, then exit without writing anything.
+    #[clap(help_heading = "Compute Options")]
+    #[arg(long, default_value_t = false)]
+    dry_run: bool,
     /// How to handle regions found in the `--regions` BED file.
     /// quiet => ignore regions that are not found in the tabix header
     /// warn => log (debug) regions that are missing
@@ -695,6 +971,22 @@ pub struct MultiSampleDmr {
     #[clap(help_heading = "Sample Options")]
     #[arg(long, alias = "min-coverage", default_value_t = 0)]
     min_valid_coverage: u64,
+    /// Combine the counts for the positive and negative strand records of
+    /// each CpG, as `pileup --combine-strands` would, when the input
+    /// bedMethyls have stranded records. Records on the negative strand are
+    /// moved to the cytosine on the positive strand, the inputs should be
+    /// CpG pileups (e.g. made with --cpg).
+    #[clap(help_heading = "Sample Options")]
+    #[arg(long, default_value_t = false)]
+    combine_strands: bool,
+    /// Format of the sample inputs. With "auto" the format of each input is
+    /// detected from its first record. Bismark coverage or cytosine report
+    /// files and methylKit files are converted to 5mC (m) bedMethyl records,
+    /// they must be bgzip-compressed and tabix-indexed as with bedMethyl
+    /// inputs.
+    #[clap(help_heading = "Sample Options")]
+    #[arg(long, value_enum, default_value_t = DmrInputFormat::auto)]
+    input_format: DmrInputFormat,
 }
 
 impl MultiSampleDmr {
@@ -703,11 +995,7 @@ impl MultiSampleDmr {
         a_name: &str,
         b_name: &str,
     ) -> anyhow::Result<Box<BufWriter<File>>> {
-        let fp = if let Some(p) = self.prefix.as_ref() {
-            self.out_dir.join(format!("{}_{}_{}.bed", p, a_name, b_name))
-        } else {
-            self.out_dir.join(format!("{}_{}.bed", a_name, b_name))
-        };
+        let fp = self.out_path(a_name, b_name);
         if fp.exists() && !self.force {
             bail!(
                 "refusing to overwrite {:?}",
@@ -721,17 +1009,25 @@ impl MultiSampleDmr {
         }
     }
 
+    fn out_path(&self, a_name: &str, b_name: &str) -> PathBuf {
+        if let Some(p) = self.prefix.as_ref() {
+            self.out_dir.join(format!("{}_{}_{}.bed", p, a_name, b_name))
+        } else {
+            self.out_dir.join(format!("{}_{}.bed", a_name, b_name))
+        }
+    }
+
     pub fn run(&self) -> anyhow::Result<()> {
         let _handle = init_logging(self.log_filepath.as_ref());
-        if !self.out_dir.exists() {
-            info!("creating directory at {:?}", &self.out_dir);
-            std::fs::create_dir_all(&self.out_dir)?;
+        if !(0f64..=1f64).contains(&self.winsorize_quantile) {
+            bail!("--winsorize-quantile must be between 0 and 1")
         }
         let code_lookup = PairwiseDmr::validate_modified_bases(
             &self.modified_bases,
             self.mod_code_assignments.as_ref(),
         )?;
 
+        let io_retry = self.io_retry_args.io_retry()?;
         let handlers = self
             .samples
             .chunks(2)
@@ -747,9 +1043,11 @@ impl MultiSampleDmr {
                 } else {
                     let fp = Path::new(raw[0].as_str()).to_path_buf();
                     let name = raw[1].to_string();
-                    if fp.exists() {
+                    if fp.exists() || is_remote_path(&fp) {
                         match BedMethylTbxIndex::from_path(&fp) {
-                            Ok(handler) => Some((i, name, handler)),
+                            Ok(handler) => {
+                                Some((i, name, handler.with_retry(io_retry)))
+                            }
                             Err(e) => {
                                 error!("failed to load {name}, {e}");
                                 None
@@ -772,12 +1070,14 @@ impl MultiSampleDmr {
             .collect::<MkResult<Vec<DnaBase>>>()
             .context("failed to parse modified base")?;
 
-        let (names, handlers) = handlers.into_iter().fold(
-            (HashMap::new(), Vec::new()),
-            |(mut names, mut handlers), (sample_id, name, handler)| {
+        let (names, sample_names, handlers) = handlers.into_iter().fold(
+            (HashMap::new(), Vec::new(), Vec::new()),
+            |(mut names, mut sample_names, mut handlers),
+             (sample_id, name, handler)| {
+                sample_names.push(name.clone());
                 names.entry(name).or_insert_with(Vec::new).push(sample_id);
                 handlers.push(handler);
-                (names, handlers)
+                (names, sample_names, handlers)
             },
         );
         for (name, ids) in &names {
@@ -788,36 +1088,85 @@ impl MultiSampleDmr {
                 );
             }
         }
+        let samples = names.keys().sorted().collect::<Vec<&String>>();
+        let out_paths = samples
+            .iter()
+            .tuple_combinations()
+            .map(|(a_name, b_name)| self.out_path(a_name, b_name))
+            .collect::<Vec<PathBuf>>();
+        if let Some(fp) = out_paths.iter().find(|fp| fp.exists() && !self.force)
+        {
+            bail!("refusing to overwrite {fp:?}")
+        }
 
         let sample_index = MultiSampleIndex::new(
             handlers,
             code_lookup,
             self.min_valid_coverage,
             self.io_threads,
-        );
+        )
+        .with_combine_strands(self.combine_strands)
+        .with_input_format(self.input_format)?;
 
-        let genome_positions = GenomePositions::new_from_sequences(
+        let genome_positions = Arc::new(GenomePositions::new_from_sequences(
             &motifs,
             &self.reference_fasta,
             self.mask,
             &sample_index.all_contigs(),
             &mpb,
-        )?;
+        )?);
+        let reference_check = Arc::new(ReferenceCheck::new(
+            genome_positions.clone(),
+            self.strict,
+            sample_names,
+        ));
 
-        let regions_of_interest = parse_roi_bed(&self.regions_bed)?;
+        let regions_of_interest =
+            parse_roi_bed(&self.regions_bed, self.keep_region_columns)?;
+        let region_columns =
+            roi_extra_column_names(&self.regions_bed, &regions_of_interest)?;
 
-        let sample_index = Arc::new(sample_index);
-        let genome_positions = Arc::new(genome_positions);
+        let sample_index = Arc::new(
+            sample_index.with_reference_check(reference_check.clone()),
+        );
 
         info!("loaded {} regions", regions_of_interest.len());
 
         let chunk_size = (self.threads as f32 * 1.5f32).floor() as usize;
+        if self.dry_run {
+            let mut plan = DryRunPlan::new("dmr multi");
+            for (name, ids) in names.iter().sorted_by_key(|(name, _)| *name) {
+                let paths =
+                    ids.iter().map(|&i| &self.samples[i * 2]).join(", ");
+                plan.add_step(&format!("sample {name}"), paths);
+            }
+            plan.add_step(
+                "reference",
+                self.reference_fasta.to_string_lossy().to_string(),
+            );
+            plan.add_step(
+                "regions",
+                format!(
+                    "{} from {}, in batches of {chunk_size}",
+                    regions_of_interest.len(),
+                    self.regions_bed.to_string_lossy()
+                ),
+            );
+            plan.add_step("comparisons", out_paths.len().to_string());
+            for fp in out_paths.iter() {
+                plan.add_output(fp.to_string_lossy().to_string());
+            }
+            return plan.print();
+        }
+        if !self.out_dir.exists() {
+            info!("creating directory at {:?}", &self.out_dir);
+            std::fs::create_dir_all(&self.out_dir)?;
+        }
         info!("processing {chunk_size} regions concurrently");
 
         let sample_pb =
             mpb.add(get_master_progress_bar(sample_index.num_combinations()?));
 
-        let samples = names.keys().sorted().collect::<Vec<&String>>();
         for pair in
             samples.into_iter().combinations(2).progress_with(sample_pb.clone())
         {
@@ -856,19 +1205,24 @@ impl MultiSampleDmr {
             ) {
                 Ok(dmr_interval_iter) => {
                     let writer = self.get_writer(a_name, b_name)?;
-                    let (success_count, region_errors) = run_pairwise_dmr(
-                        dmr_interval_iter,
-                        sample_index.clone(),
-                        pool,
-                        writer,
-                        pb,
-                        self.header,
-                        a_name,
-                        b_name,
-                        failures.clone(),
-                        batch_failures.clone(),
-                        mpb.clone(),
-                    )?;
+                    let (success_count, region_errors, dmr_summary) =
+                        run_pairwise_dmr(
+                            dmr_interval_iter,
+                            sample_index.clone(),
+                            pool,
+                            writer,
+                            pb,
+                            self.header,
+                            &region_columns,
+                            a_name,
+                            b_name,
+                            failures.clone(),
+                            batch_failures.clone(),
+                            mpb.clone(),
+                            None,
+                            self.region_effect_size,
+                            self.winsorize_quantile,
+                        )?;
                     mpb.suspend(|| {
                         info!(
                             "{} regions processed successfully and {} regions \
@@ -879,11 +1233,13 @@ impl MultiSampleDmr {
                             &b_name,
                         );
                         if !region_errors.is_empty() {
+                            run_summary::record_errors(&region_errors);
                             let tab = format_errors_table(&region_errors);
                             error!("region errors:\n{tab}");
                             all_region_errors.op_mut(region_errors);
                         }
                     });
+                    mpb.suspend(|| dmr_summary.report(None))?;
                 }
                 Err(e) => {
                     mpb.suspend(|| {
@@ -900,6 +1256,325 @@ impl MultiSampleDmr {
                 }
             }
         }
+        reference_check.report();
+
+        Ok(())
+    }
+}
+
+#[derive(Args)]
+#[command(arg_required_else_help = true)]
+pub struct TrendDmr {
+    /// Samples to test, two arguments are required <path> <group>. This
+    /// option should be repeated for each sample, samples with the same
+    /// group name are replicates of that group. At least two groups are
+    /// required. The path can be an https:// URL to a remote bgzipped
+    /// bedMethyl with a tabix index.
+    #[clap(help_heading = "Sample Options")]
+    #[arg(short = 's', long = "sample", num_args = 2)]
+    samples: Vec<String>,
+    /// Order of the groups, comma-separated, for example
+    /// --group-order 0h,6h,24h. By default groups are ordered as they first
+    /// appear in the --sample arguments.
+    #[clap(help_heading = "Sample Options")]
+    #[arg(long, value_delimiter = ',')]
+    group_order: Option<Vec<String>>,
+    /// BED file of regions over which to test for trends. Should be
+    /// tab-separated (spaces allowed in the "name" column). Requires
+    /// chrom, chromStart and chromEnd. The Name column is optional. Use a
+    /// BED of single positions (e.g. from `modkit motif bed`) to test
+    /// individual sites.
+    #[clap(help_heading = "Sample Options")]
+    #[arg(long, short = 'r', alias = "regions")]
+    regions_bed: PathBuf,
+    /// Append the columns after the strand (column 6) of the `--regions` BED
+    /// file to each output row, for example gene IDs or categories. Column
+    /// names in the header are taken from the last `#` line of the BED file
+    /// when it names every column.
+    #[clap(help_heading = "Output Options")]
+    #[arg(long, default_value_t = false, hide_short_help = true)]
+    keep_region_columns: bool,
+    /// Path to reference fasta for the pileup.
+    #[clap(help_heading = "Sample Options")]
+    #[arg(long = "ref")]
+    reference_fasta: PathBuf,
+    /// Bases to use to calculate the trend, may be multiple. For example,
+    /// to use only cytosine modifications use --base C.
+    #[clap(help_heading = "Sample Options")]
+    #[arg(short, long="base", alias = "modified-bases", action=clap::ArgAction::Append)]
+    modified_bases: Vec<char>,
+    /// Extra assignments of modification codes to their respective primary
+    /// bases, for example --assign-code x:C. See the help for `dmr pair` for
+    /// details.
+    #[clap(help_heading = "Sample Options")]
+    #[arg(long="assign-code", action=clap::ArgAction::Append)]
+    mod_code_assignments: Option<Vec<String>>,
+    /// Minimum valid coverage required to use an entry from a bedMethyl.
+    #[clap(help_heading = "Sample Options")]
+    #[arg(long, alias = "min-coverage", default_value_t = 0)]
+    min_valid_coverage: u64,
+    /// Combine the counts for the positive and negative strand records of
+    /// each CpG when the input bedMethyls have stranded records.
+    #[clap(help_heading = "Sample Options")]
+    #[arg(long, default_value_t = false)]
+    combine_strands: bool,
+    /// Format of the sample inputs. With "auto" the format of each input is
+    /// detected from its first record. Bismark coverage or cytosine report
+    /// files and methylKit files are converted to 5mC (m) bedMethyl records,
+    /// they must be bgzip-compressed and tabix-indexed as with bedMethyl
+    /// inputs.
+    #[clap(help_heading = "Sample Options")]
+    #[arg(long, value_enum, default_value_t = DmrInputFormat::auto)]
+    input_format: DmrInputFormat,
+    /// Respect soft masking in the reference FASTA.
+    #[clap(help_heading = "Sample Options")]
+    #[arg(long, short = 'k', default_value_t = false)]
+    mask: bool,
+    /// Fail when a bedMethyl record doesn't match the reference, the primary
+    /// base of its modification code isn't at its position (e.g. the sample
+    /// was made with a different assembly). By default these records are
+    /// skipped and the number for each sample is logged.
+    #[clap(help_heading = "Sample Options")]
+    #[arg(long, default_value_t = false)]
+    strict: bool,
+    /// Test to use. armitage: Cochran-Armitage test on the counts pooled
+    /// over the replicates of each group, with the group index as the
+    /// score. jonckheere: Jonckheere-Terpstra test on the fraction modified
+    /// of each replicate, only informative with replicates.
+    #[clap(help_heading = "Test Options")]
+    #[arg(long, default_value_t = TrendTest::armitage)]
+    test: TrendTest,
+    /// Path to file to direct output, optional, no argument will direct output
+    /// to stdout.
+    #[clap(help_heading = "Output Options")]
+    #[arg(short = 'o', long)]
+    out_path: Option<PathBuf>,
+    /// Include header in output
+    #[clap(help_heading = "Output Options")]
+    #[arg(long, alias = "with-header", default_value_t = false)]
+    header: bool,
+    /// Force overwrite of output file, if it already exists.
+    #[clap(help_heading = "Output Options")]
+    #[arg(short = 'f', long, default_value_t = false)]
+    force: bool,
+    /// Number of threads to use.
+    #[clap(help_heading = "Compute Options")]
+    #[arg(short = 't', long, default_value_t = 4)]
+    threads: usize,
+    /// Number of threads to use when for decompression.
+    #[clap(help_heading = "Compute Options")]
+    #[arg(long, default_value_t = 4)]
+    io_threads: usize,
+    #[clap(flatten)]
+    io_retry_args: IoRetryArgs,
+    /// How to handle regions found in the `--regions` BED file.
+    /// quiet => ignore regions that are not found in the tabix header
+    /// warn => log (debug) regions that are missing
+    /// fatal => log (error) and exit the program when a region is missing.
+    #[clap(help_heading = "Logging Options")]
+    #[arg(long="missing", default_value_t=HandleMissing::quiet)]
+    handle_missing: HandleMissing,
+    /// File to write logs to, it's recommended to use this option.
+    #[clap(help_heading = "Logging Options")]
+    #[arg(long, alias = "log")]
+    log_filepath: Option<PathBuf>,
+    /// Don't show progress bars
+    #[clap(help_heading = "Logging Options")]
+    #[arg(long, default_value_t = false)]
+    suppress_progress: bool,
+}
+
+impl TrendDmr {
+    /// Group names in order.
+    fn groups(&self) -> anyhow::Result<Vec<String>> {
+        if self.samples.len() % 2 != 0 {
+            bail!("samples should be pairs of <path> <group>")
+        }
+        let appearance_order = self
+            .samples
+            .chunks(2)
+            .map(|raw| raw[1].to_string())
+            .unique()
+            .collect::<Vec<String>>();
+        let groups = match self.group_order.as_ref() {
+            Some(order) => {
+                if order.iter().unique().count() != order.len() {
+                    bail!("--group-order cannot have duplicate groups")
+                }
+                if let Some(missing) =
+                    appearance_order.iter().find(|g| !order.contains(g))
+                {
+                    bail!("group {missing} is not in --group-order")
+                }
+                if let Some(extra) =
+                    order.iter().find(|g| !appearance_order.contains(g))
+                {
+                    bail!("group {extra} in --group-order has no samples")
+                }
+                order.clone()
+            }
+            None => appearance_order,
+        };
+        if groups.len() < 2 {
+            bail!("need at least 2 groups to test for a trend")
+        }
+        Ok(groups)
+    }
+
+    /// Path and group index of each sample.
+    fn samples(&self, groups: &[String]) -> Vec<(PathBuf, usize)> {
+        self.samples
+            .chunks(2)
+            .map(|raw| {
+                let group =
+                    groups.iter().position(|g| g == &raw[1]).unwrap_or(0);
+                (Path::new(raw[0].as_str()).to_path_buf(), group)
+            })
+            .collect()
+    }
+
+    pub fn run(&self) -> anyhow::Result<()> {
+        let _handle = init_logging(self.log_filepath.as_ref());
+        let code_lookup = PairwiseDmr::validate_modified_bases(
+            &self.modified_bases,
+            self.mod_code_assignments.as_ref(),
+        )?;
+        let groups = self.groups()?;
+        let samples = self.samples(&groups);
+        for (idx, group) in groups.iter().enumerate() {
+            let n_replicates =
+                samples.iter().filter(|(_, g)| *g == idx).count();
+            info!("group {idx}: {group}, {n_replicates} sample(s)");
+        }
+
+        let writer: Box<dyn Write> = match self.out_path.as_ref() {
+            Some(fp) => {
+                if fp.exists() && !self.force {
+                    bail!("refusing to overwrite existing file {fp:?}")
+                }
+                create_out_directory(fp)?;
+                Box::new(BufWriter::new(File::create(fp)?))
+            }
+            None => Box::new(BufWriter::new(std::io::stdout())),
+        };
+        let mut writer = writer;
+
+        let io_retry = self.io_retry_args.io_retry()?;
+        let mut handlers = Vec::with_capacity(samples.len());
+        for (fp, _) in samples.iter() {
+            if !(fp.exists() || is_remote_path(fp)) {
+                bail!("bedMethyl at {fp:?} not found")
+            }
+            handlers.push(
+                BedMethylTbxIndex::from_path(fp)
+                    .with_context(|| format!("failed to load {fp:?}"))?
+                    .with_retry(io_retry),
+            );
+        }
+        let sample_groups = samples
+            .iter()
+            .enumerate()
+            .map(|(sample_id, (_, group))| (sample_id, *group))
+            .collect::<FxHashMap<usize, usize>>();
+
+        let mpb = MultiProgress::new();
+        if self.suppress_progress {
+            mpb.set_draw_target(indicatif::ProgressDrawTarget::hidden());
+        }
+        let motifs = self
+            .modified_bases
+            .iter()
+            .map(|c| DnaBase::parse(*c))
+            .collect::<MkResult<Vec<DnaBase>>>()
+            .context("failed to parse modified base")?;
+        let sample_index = MultiSampleIndex::new(
+            handlers,
+            code_lookup,
+            self.min_valid_coverage,
+            self.io_threads,
+        )
+        .with_combine_strands(self.combine_strands)
+        .with_input_format(self.input_format)?;
+        let genome_positions = Arc::new(GenomePositions::new_from_sequences(
+            &motifs,
+            &self.reference_fasta,
+            self.mask,
+            &sample_index.all_contigs(),
+            &mpb,
+        )?);
+        let sample_names = samples
+            .iter()
+            .map(|(fp, _)| fp.to_string_lossy().to_string())
+            .collect::<Vec<String>>();
+        let reference_check = Arc::new(ReferenceCheck::new(
+            genome_positions.clone(),
+            self.strict,
+            sample_names,
+        ));
+        let sample_index =
+            sample_index.with_reference_check(reference_check.clone());
+        let regions_of_interest =
+            parse_roi_bed(&self.regions_bed, self.keep_region_columns)?;
+        let region_columns =
+            roi_extra_column_names(&self.regions_bed, &regions_of_interest)?;
+        info!("loaded {} regions", regions_of_interest.len());
+        if self.header {
+            writer.write_all(
+                TrendResult::header(&groups, &region_columns).as_bytes(),
+            )?;
+        }
+        let n_regions = regions_of_interest.len();
+
+        // the region iterator reads two sets of samples, the first group is
+        // read as 'a' and the remaining groups as 'b'
+        let (a_idxs, b_idxs): (Vec<usize>, Vec<usize>) = sample_groups
+            .keys()
+            .sorted()
+            .partition(|id| sample_groups[*id] == 0);
+        let sample_index = Arc::new(sample_index);
+        let chunk_size = (self.threads as f32 * 1.5f32).floor() as usize;
+        let roi_iter = RoiIter::new(
+            &a_idxs,
+            &b_idxs,
+            &groups[0],
+            "other groups",
+            sample_index.clone(),
+            regions_of_interest,
+            chunk_size,
+            self.handle_missing,
+            genome_positions,
+            &mpb,
+        )?;
+
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(self.threads)
+            .build()?;
+        let pb = mpb.add(get_subroutine_progress_bar(n_regions));
+        pb.set_message("regions processed");
+        let failures = mpb.add(get_ticker());
+        failures.set_message("regions failed to process");
+        let (success_count, region_errors) = run_trend_dmr(
+            roi_iter,
+            sample_index,
+            TrendDesign::new(sample_groups, groups.len(), self.test),
+            pool,
+            writer,
+            pb,
+            failures.clone(),
+        )?;
+        mpb.suspend(|| {
+            info!(
+                "{success_count} regions processed successfully and {} \
+                 regions failed",
+                failures.position()
+            );
+            if !region_errors.is_empty() {
+                run_summary::record_errors(&region_errors);
+                let tab = format_errors_table(&region_errors);
+                error!("region errors:\n{tab}");
+            }
+            reference_check.report();
+        });
 
         Ok(())
     }
@@ -0,0 +1,318 @@
+//! Run-level summary of a DMR analysis, the number of significant regions
+//! (or sites) at several p-value thresholds, the distribution of effect
+//! sizes, and the genomic inflation factor (lambda) estimated from the
+//! p-values. Values are accumulated into fixed-width histograms so that
+//! genome-wide single-site runs don't need to keep every score.
+
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+use log::info;
+use prettytable::{row, Table};
+use statrs::function::erf::erfc_inv;
+
+use crate::util::create_out_directory;
+use crate::util::json::{json_float, json_object, json_string};
+
+/// P-value thresholds to count significant regions at.
+const P_VALUE_THRESHOLDS: [f64; 4] = [0.05, 0.01, 0.001, 0.0001];
+/// Median of the chi-squared distribution with 1 degree of freedom.
+const CHI2_1DF_MEDIAN: f64 = 0.454936423119572;
+/// Resolution and maximum of the -log10(p-value) histogram.
+const NEG_LOG10_P_RESOLUTION: f64 = 0.001;
+const NEG_LOG10_P_MAX: f64 = 300f64;
+/// Resolution of the effect size histogram, effect sizes are between -1
+/// and 1.
+const EFFECT_SIZE_RESOLUTION: f64 = 0.001;
+const EFFECT_SIZE_QUANTILES: [f64; 5] = [0.05, 0.25, 0.5, 0.75, 0.95];
+
+pub(super) struct DmrRunSummary {
+    what: &'static str,
+    n: u64,
+    significant_counts: [u64; P_VALUE_THRESHOLDS.len()],
+    neg_log10_p_hist: Vec<u64>,
+    effect_size_hist: Vec<u64>,
+    n_effect_sizes: u64,
+    effect_size_sum: f64,
+    effect_size_sum_sq: f64,
+    min_effect_size: f64,
+    max_effect_size: f64,
+    /// Number of records in each sample that didn't match the reference.
+    reference_mismatches: Vec<(String, usize)>,
+}
+
+impl DmrRunSummary {
+    /// `what` is the kind of thing scored, e.g. "regions" or "sites".
+    pub(super) fn new(what: &'static str) -> Self {
+        let n_p_bins =
+            (NEG_LOG10_P_MAX / NEG_LOG10_P_RESOLUTION).ceil() as usize + 1;
+        let n_effect_bins = (2f64 / EFFECT_SIZE_RESOLUTION).round() as usize;
+        Self {
+            what,
+            n: 0,
+            significant_counts: [0; P_VALUE_THRESHOLDS.len()],
+            neg_log10_p_hist: vec![0; n_p_bins],
+            effect_size_hist: vec![0; n_effect_bins + 1],
+            n_effect_sizes: 0,
+            effect_size_sum: 0f64,
+            effect_size_sum_sq: 0f64,
+            min_effect_size: f64::INFINITY,
+            max_effect_size: f64::NEG_INFINITY,
+            reference_mismatches: Vec::new(),
+        }
+    }
+
+    /// Add the number of records in each sample that didn't match the
+    /// reference to the summary.
+    pub(super) fn with_reference_mismatches(
+        self,
+        reference_mismatches: Vec<(String, usize)>,
+    ) -> Self {
+        Self { reference_mismatches, ..self }
+    }
+
+    pub(super) fn add(&mut self, p_value: f64, effect_size: f64) {
+        if p_value.is_nan() {
+            return;
+        }
+        let p_value = p_value.clamp(0f64, 1f64);
+        self.n += 1;
+        for (threshold, count) in
+            P_VALUE_THRESHOLDS.iter().zip(self.significant_counts.iter_mut())
+        {
+            if p_value <= *threshold {
+                *count += 1;
+            }
+        }
+        let neg_log10_p = (-p_value.log10()).clamp(0f64, NEG_LOG10_P_MAX);
+        let bin = (neg_log10_p / NEG_LOG10_P_RESOLUTION).round() as usize;
+        let last = self.neg_log10_p_hist.len() - 1;
+        self.neg_log10_p_hist[bin.min(last)] += 1;
+
+        if effect_size.is_finite() {
+            let effect_size = effect_size.clamp(-1f64, 1f64);
+            let bin = ((effect_size + 1f64) / EFFECT_SIZE_RESOLUTION).round()
+                as usize;
+            let last = self.effect_size_hist.len() - 1;
+            self.effect_size_hist[bin.min(last)] += 1;
+            self.n_effect_sizes += 1;
+            self.effect_size_sum += effect_size;
+            self.effect_size_sum_sq += effect_size * effect_size;
+            self.min_effect_size = self.min_effect_size.min(effect_size);
+            self.max_effect_size = self.max_effect_size.max(effect_size);
+        }
+    }
+
+    /// Index of the bin containing the `q` quantile of the values in `hist`.
+    fn quantile_bin(hist: &[u64], total: u64, q: f64) -> Option<usize> {
+        if total == 0 {
+            return None;
+        }
+        let rank = ((q * total as f64).ceil() as u64).max(1);
+        let mut cumulative = 0u64;
+        hist.iter().position(|count| {
+            cumulative += count;
+            cumulative >= rank
+        })
+    }
+
+    fn median_p_value(&self) -> Option<f64> {
+        // the median -log10(p) is the -log10 of the median p-value
+        Self::quantile_bin(&self.neg_log10_p_hist, self.n, 0.5)
+            .map(|bin| 10f64.powf(-(bin as f64 * NEG_LOG10_P_RESOLUTION)))
+    }
+
+    /// Genomic inflation factor, the median of the p-values converted to
+    /// chi-squared (1 degree of freedom) statistics over the expected
+    /// median. Values near 1 indicate calibrated p-values, values much
+    /// larger than 1 indicate inflation.
+    fn lambda(&self) -> Option<f64> {
+        self.median_p_value().map(|p| {
+            // chi-squared (1 df) statistic with upper tail probability p
+            let z = erfc_inv(p);
+            2f64 * z * z / CHI2_1DF_MEDIAN
+        })
+    }
+
+    fn effect_size_quantile(&self, q: f64) -> Option<f64> {
+        Self::quantile_bin(&self.effect_size_hist, self.n_effect_sizes, q)
+            .map(|bin| bin as f64 * EFFECT_SIZE_RESOLUTION - 1f64)
+    }
+
+    fn effect_size_mean_and_sd(&self) -> Option<(f64, f64)> {
+        if self.n_effect_sizes == 0 {
+            return None;
+        }
+        let n = self.n_effect_sizes as f64;
+        let mean = self.effect_size_sum / n;
+        let var = (self.effect_size_sum_sq / n - mean * mean).max(0f64);
+        Some((mean, var.sqrt()))
+    }
+
+    fn to_json(&self) -> String {
+        let opt_float = |x: Option<f64>| {
+            x.map(|x| json_float(x as f32)).unwrap_or("null".to_string())
+        };
+        let significant = P_VALUE_THRESHOLDS
+            .iter()
+            .zip(self.significant_counts.iter())
+            .map(|(threshold, count)| (format!("{threshold}"), *count))
+            .collect::<Vec<(String, u64)>>();
+        let significant = significant
+            .iter()
+            .map(|(threshold, count)| (threshold.as_str(), count.to_string()))
+            .collect::<Vec<(&str, String)>>();
+        let quantiles = EFFECT_SIZE_QUANTILES
+            .iter()
+            .map(|q| (format!("q{:02}", (q * 100f64).round() as u32), *q))
+            .collect::<Vec<(String, f64)>>();
+        let reference_mismatches = self
+            .reference_mismatches
+            .iter()
+            .map(|(name, count)| (name.as_str(), count.to_string()))
+            .collect::<Vec<(&str, String)>>();
+        let mean_and_sd = self.effect_size_mean_and_sd();
+        let mut effect_sizes = vec![
+            ("mean", opt_float(mean_and_sd.map(|(mean, _)| mean))),
+            ("sd", opt_float(mean_and_sd.map(|(_, sd)| sd))),
+            (
+                "min",
+                opt_float(
+                    (self.n_effect_sizes > 0).then_some(self.min_effect_size),
+                ),
+            ),
+        ];
+        for (label, q) in quantiles.iter() {
+            effect_sizes.push((
+                label.as_str(),
+                opt_float(self.effect_size_quantile(*q)),
+            ));
+        }
+        effect_sizes.push((
+            "max",
+            opt_float(
+                (self.n_effect_sizes > 0).then_some(self.max_effect_size),
+            ),
+        ));
+        json_object(&[
+            ("scored", json_string(self.what)),
+            ("n", self.n.to_string()),
+            ("significant_at_p", json_object(&significant)),
+            ("median_p_value", opt_float(self.median_p_value())),
+            ("lambda", opt_float(self.lambda())),
+            ("effect_size", json_object(&effect_sizes)),
+            ("reference_mismatches", json_object(&reference_mismatches)),
+        ])
+    }
+
+    /// Human-readable table of the summary for logging.
+    pub(super) fn table(&self) -> Table {
+        let fmt = |x: Option<f64>| {
+            x.map(|x| format!("{x:.4}")).unwrap_or("NA".to_string())
+        };
+        let mut tab = Table::new();
+        tab.set_format(
+            *prettytable::format::consts::FORMAT_NO_LINESEP_WITH_TITLE,
+        );
+        tab.set_titles(row!["statistic", "value"]);
+        tab.add_row(row![format!("{} scored", self.what), self.n]);
+        for (threshold, count) in
+            P_VALUE_THRESHOLDS.iter().zip(self.significant_counts.iter())
+        {
+            tab.add_row(row![format!("p <= {threshold}"), count]);
+        }
+        tab.add_row(row!["median p-value", fmt(self.median_p_value())]);
+        tab.add_row(row!["lambda", fmt(self.lambda())]);
+        let mean_and_sd = self.effect_size_mean_and_sd();
+        tab.add_row(row![
+            "effect size mean",
+            fmt(mean_and_sd.map(|(mean, _)| mean))
+        ]);
+        tab.add_row(row!["effect size sd", fmt(mean_and_sd.map(|(_, sd)| sd))]);
+        for q in EFFECT_SIZE_QUANTILES {
+            tab.add_row(row![
+                format!("effect size q{:02}", (q * 100f64).round() as u32),
+                fmt(self.effect_size_quantile(q))
+            ]);
+        }
+        for (name, count) in self.reference_mismatches.iter() {
+            tab.add_row(row![format!("reference mismatches {name}"), count]);
+        }
+        tab
+    }
+
+    /// Log the summary table and, when `out_fp` is given, write the summary
+    /// as JSON.
+    pub(super) fn report(
+        &self,
+        out_fp: Option<&PathBuf>,
+    ) -> anyhow::Result<()> {
+        info!("DMR run summary:\n{}", self.table());
+        if let Some(out_fp) = out_fp {
+            self.write_json(out_fp)?;
+            info!("wrote DMR run summary to {out_fp:?}");
+        }
+        Ok(())
+    }
+
+    fn write_json(&self, out_fp: &Path) -> anyhow::Result<()> {
+        create_out_directory(out_fp)?;
+        let fh = File::create(out_fp).with_context(|| {
+            format!("failed to create DMR summary at {out_fp:?}")
+        })?;
+        let mut writer = BufWriter::new(fh);
+        writeln!(writer, "{}", self.to_json())?;
+        writer.flush()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod dmr_summary_tests {
+    use crate::dmr::summary::DmrRunSummary;
+
+    #[test]
+    fn test_dmr_summary_uniform_p_values_lambda_near_one() {
+        let mut summary = DmrRunSummary::new("regions");
+        let n = 10_000;
+        for i in 0..n {
+            let p = (i as f64 + 0.5) / n as f64;
+            let effect_size = (i as f64 / n as f64) - 0.5;
+            summary.add(p, effect_size);
+        }
+        assert_eq!(summary.n, n);
+        assert_eq!(summary.significant_counts, [500, 100, 10, 1]);
+        let lambda = summary.lambda().unwrap();
+        assert!((lambda - 1f64).abs() < 0.01, "{lambda}");
+        let median = summary.effect_size_quantile(0.5).unwrap();
+        assert!(median.abs() < 0.002, "{median}");
+        let (mean, _sd) = summary.effect_size_mean_and_sd().unwrap();
+        assert!(mean.abs() < 0.001);
+    }
+
+    #[test]
+    fn test_dmr_summary_inflated_p_values() {
+        let mut summary = DmrRunSummary::new("sites");
+        for _ in 0..100 {
+            summary.add(1e-8, 0.5);
+        }
+        assert_eq!(summary.significant_counts, [100, 100, 100, 100]);
+        assert!(summary.lambda().unwrap() > 10f64);
+        assert!(summary.to_json().contains("\"lambda\":"));
+    }
+
+    #[test]
+    fn test_dmr_summary_empty() {
+        let summary = DmrRunSummary::new("regions");
+        assert!(summary.lambda().is_none());
+        assert!(summary.to_json().contains("\"lambda\":null"));
+        assert!(summary.to_json().contains("\"reference_mismatches\":{}"));
+        let summary = summary
+            .with_reference_mismatches(vec![("a.bed.gz".to_string(), 3)]);
+        assert!(summary
+            .to_json()
+            .contains("\"reference_mismatches\":{\"a.bed.gz\":3}"));
+    }
+}
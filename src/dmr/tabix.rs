@@ -1,16 +1,22 @@
 use std::collections::{BTreeMap, HashSet};
 use std::fmt::Debug;
 use std::ops::Range;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
 
 use anyhow::bail;
+use itertools::Itertools;
+use log::{info, warn};
 use rayon::prelude::*;
 use rustc_hash::{FxHashMap, FxHashSet};
 
-use crate::dmr::bedmethyl::{aggregate_counts2, BedMethylLine};
+use crate::dmr::bedmethyl::{
+    aggregate_counts2, combine_cpg_strands, BedMethylLine, DmrInputFormat,
+};
 use crate::dmr::llr_model::AggregatedCounts;
 use crate::dmr::util::{n_choose_2, DmrBatch, DmrBatchOfPositions};
-use crate::errs::MkResult;
-use crate::genome_positions::StrandedPosition;
+use crate::errs::{MkError, MkResult};
+use crate::genome_positions::{GenomePositions, StrandedPosition};
 use crate::mod_base_code::{DnaBase, ModCodeRepr};
 use crate::monoid::Moniod;
 use crate::tabix::BedMethylTbxIndex;
@@ -21,19 +27,107 @@ pub(super) type ChromToSampleBMLines =
 /// Sample id -> {Chrom -> <BedMethyl_records>}
 pub(super) type SampleToChromBMLines =
     FxHashMap<usize, FxHashMap<String, Vec<BedMethylLine>>>;
-/// Chrom -> {StrandedPosition -> <X_i>} for all i in samples
+/// Chrom -> {StrandedPosition -> <(i, X_i)>} for all i in samples, ordered
+/// by sample id
 pub(super) type ChromToPosAggregatedCounts = FxHashMap<
     String,
-    BTreeMap<StrandedPosition<DnaBase>, Vec<AggregatedCounts>>,
+    BTreeMap<StrandedPosition<DnaBase>, Vec<(usize, AggregatedCounts)>>,
 >;
 /// Usually (control, experiment)
 pub(super) type BedMethylLinesResult<T> = MkResult<(T, T)>;
 
+/// Counts the bedMethyl records of each sample that don't match the
+/// reference, a record at a position without the primary base of its
+/// modification code (e.g. a stale reference or a different assembly).
+pub(super) struct ReferenceCheck {
+    genome_positions: Arc<GenomePositions>,
+    /// Fail on the first mismatch instead of counting it.
+    strict: bool,
+    /// Name of each sample, in sample index order.
+    sample_names: Vec<String>,
+    mismatches: Vec<AtomicUsize>,
+}
+
+impl ReferenceCheck {
+    pub(super) fn new(
+        genome_positions: Arc<GenomePositions>,
+        strict: bool,
+        sample_names: Vec<String>,
+    ) -> Self {
+        let mismatches =
+            sample_names.iter().map(|_| AtomicUsize::new(0)).collect();
+        Self { genome_positions, strict, sample_names, mismatches }
+    }
+
+    fn check(
+        &self,
+        sample_id: usize,
+        chrom: &str,
+        records: &[BedMethylLine],
+        code_lookup: &FxHashMap<ModCodeRepr, DnaBase>,
+    ) -> MkResult<()> {
+        let mut n_mismatches = 0usize;
+        for record in records {
+            let position = record.get_stranded_position(code_lookup);
+            if !self.genome_positions.is_reference_mismatch(chrom, &position) {
+                continue;
+            }
+            if self.strict {
+                let name = self
+                    .sample_names
+                    .get(sample_id)
+                    .map(|name| name.as_str())
+                    .unwrap_or("sample");
+                return Err(MkError::DmrReferenceMismatch(format!(
+                    "{name} has a {} record at {chrom}:{} ({}) where the \
+                     reference doesn't have a {}",
+                    record.raw_mod_code,
+                    record.start(),
+                    position.strand.to_char(),
+                    position.value.char()
+                )));
+            }
+            n_mismatches += 1;
+        }
+        if let Some(count) = self.mismatches.get(sample_id) {
+            count.fetch_add(n_mismatches, Ordering::Relaxed);
+        }
+        Ok(())
+    }
+
+    /// Number of mismatched records seen for each sample, in sample index
+    /// order.
+    pub(super) fn counts(&self) -> Vec<(String, usize)> {
+        self.sample_names
+            .iter()
+            .zip(self.mismatches.iter())
+            .map(|(name, count)| (name.clone(), count.load(Ordering::Relaxed)))
+            .collect()
+    }
+
+    /// Log the number of mismatched records for each sample that had any.
+    pub(super) fn report(&self) {
+        for (name, count) in self.counts() {
+            if count > 0 {
+                warn!(
+                    "{count} record(s) in {name} don't match the primary base \
+                     in the reference and were not used, check that the \
+                     sample was made with the same reference"
+                );
+            }
+        }
+    }
+}
+
 pub(super) struct MultiSampleIndex {
     index_handlers: Vec<BedMethylTbxIndex>,
     pub code_lookup: FxHashMap<ModCodeRepr, DnaBase>,
     min_valid_coverage: u64,
     io_threads: usize,
+    combine_strands: bool,
+    /// Format of each sample, in the same order as `index_handlers`.
+    input_formats: Vec<DmrInputFormat>,
+    reference_check: Option<Arc<ReferenceCheck>>,
 }
 
 impl MultiSampleIndex {
@@ -43,14 +137,55 @@ impl MultiSampleIndex {
         min_valid_coverage: u64,
         io_threads: usize,
     ) -> Self {
+        let handlers_len = handlers.len();
         Self {
             index_handlers: handlers,
             min_valid_coverage,
             code_lookup,
             io_threads,
+            combine_strands: false,
+            input_formats: vec![DmrInputFormat::bedmethyl; handlers_len],
+            reference_check: None,
         }
     }
 
+    /// Check the records that are read against the reference.
+    pub(super) fn with_reference_check(
+        self,
+        reference_check: Arc<ReferenceCheck>,
+    ) -> Self {
+        Self { reference_check: Some(reference_check), ..self }
+    }
+
+    /// Combine the positive and negative strand records of CpGs as they are
+    /// read.
+    pub(super) fn with_combine_strands(self, combine_strands: bool) -> Self {
+        Self { combine_strands, ..self }
+    }
+
+    /// Set the format of the sample inputs, with `auto` the format of each
+    /// sample is detected from its first record.
+    pub(super) fn with_input_format(
+        self,
+        input_format: DmrInputFormat,
+    ) -> anyhow::Result<Self> {
+        let input_formats = self
+            .index_handlers
+            .iter()
+            .map(|handler| {
+                let resolved = handler.resolve_input_format(input_format)?;
+                if resolved != DmrInputFormat::bedmethyl {
+                    info!(
+                        "reading {:?} as {resolved}, converting to 5mC records",
+                        handler.indexed_fp
+                    );
+                }
+                Ok(resolved)
+            })
+            .collect::<anyhow::Result<Vec<DmrInputFormat>>>()?;
+        Ok(Self { input_formats, ..self })
+    }
+
     #[inline]
     fn read_bedmethyl_files(
         &self,
@@ -71,6 +206,7 @@ impl MultiSampleIndex {
                 // chunks is a mapping of each chrom to the range in that chrom
                 // to fetch
                 .map(|(sample_id, handler, chunks)| {
+                    let input_format = self.input_formats[sample_id];
                     // actually read the bedmethyl here
                     let grouped_by_chrom =
                         chunks
@@ -78,14 +214,49 @@ impl MultiSampleIndex {
                             // here we read the bedmethyl and have a mapping of
                             // chrom to records
                             .map(|(chrom, range)| {
-                                let bm_lines = handler
-                                    .read_bedmethyl_check_code(
+                                // when combining strands, fetch one more base
+                                // for the negative strand half of the last
+                                // CpG and filter on the combined coverage
+                                let bm_lines = if self.combine_strands {
+                                    handler
+                                        .read_bedmethyl_check_code(
+                                            chrom,
+                                            &(range.start..range.end + 1),
+                                            0,
+                                            &self.code_lookup,
+                                            self.io_threads,
+                                            input_format,
+                                        )
+                                        .map(|lines| {
+                                            combine_cpg_strands(
+                                                lines,
+                                                range,
+                                                self.min_valid_coverage,
+                                            )
+                                        })
+                                } else {
+                                    handler.read_bedmethyl_check_code(
                                         chrom,
                                         range,
                                         self.min_valid_coverage,
                                         &self.code_lookup,
-                                        self.io_threads
-                                    );
+                                        self.io_threads,
+                                        input_format,
+                                    )
+                                };
+                                let bm_lines = bm_lines.and_then(|lines| {
+                                    if let Some(check) =
+                                        self.reference_check.as_ref()
+                                    {
+                                        check.check(
+                                            sample_id,
+                                            chrom,
+                                            &lines,
+                                            &self.code_lookup,
+                                        )?;
+                                    }
+                                    Ok(lines)
+                                });
                                 bm_lines.map(|lines| (chrom.to_owned(), lines))
                             })
                             .collect::<MkResult<
@@ -208,7 +379,9 @@ impl SingleSiteSampleIndex {
         let mut agg = FxHashMap::default();
 
         // samples should be length ~1-5
-        for chrom_to_filtered_bm_records in sample.into_values() {
+        for (sample_id, chrom_to_filtered_bm_records) in
+            sample.into_iter().sorted_by_key(|(sample_id, _)| *sample_id)
+        {
             let chrom_to_counts = chrom_to_filtered_bm_records
                 .into_iter()
                 .map(|(chrom, lines)| {
@@ -253,7 +426,7 @@ impl SingleSiteSampleIndex {
                         Ok(aggregated_counts) => chrom_agg
                             .entry(position)
                             .or_insert(Vec::new())
-                            .push(aggregated_counts),
+                            .push((sample_id, aggregated_counts)),
                         Err(e) => return Err(e),
                     }
                 }
@@ -0,0 +1,124 @@
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::path::{Path, PathBuf};
+
+use clap::ValueEnum;
+use log::info;
+
+use crate::bigwig::BigWigTrack;
+use crate::util::create_out_directory;
+
+/// Which per-site value to write to the bigWig track.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, ValueEnum)]
+#[allow(non_camel_case_types)]
+pub(super) enum TrackValue {
+    /// The effect size, fraction modified in 'a' minus fraction modified in
+    /// 'b'.
+    effect_size,
+    /// The likelihood ratio score.
+    score,
+    /// The -log10 of the MAP-based p-value.
+    neg_log10_pval,
+}
+
+impl Display for TrackValue {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::effect_size => write!(f, "effect-size"),
+            Self::score => write!(f, "score"),
+            Self::neg_log10_pval => write!(f, "neg-log10-pval"),
+        }
+    }
+}
+
+/// Smallest p-value used when taking -log10, keeps the track values finite.
+const MIN_PVAL: f64 = 1e-300;
+
+impl TrackValue {
+    pub(super) fn transform(
+        &self,
+        effect_size: f64,
+        score: f64,
+        map_pval: f64,
+    ) -> f32 {
+        let x = match self {
+            Self::effect_size => effect_size,
+            Self::score => score,
+            Self::neg_log10_pval => map_pval.max(MIN_PVAL).recip().log10(),
+        };
+        x as f32
+    }
+}
+
+/// Writes per-site values from single-site DMR as a bigWig track while the
+/// sites are scored, see [`BigWigTrack`].
+pub(super) struct DmrBigWigTrack {
+    out_fp: PathBuf,
+    track_value: TrackValue,
+    track: BigWigTrack,
+}
+
+impl DmrBigWigTrack {
+    pub(super) fn new<'a>(
+        out_fp: &Path,
+        force: bool,
+        track_value: TrackValue,
+        contig_sizes: impl Iterator<Item = (&'a String, usize)>,
+        threads: usize,
+    ) -> anyhow::Result<Self> {
+        create_out_directory(out_fp)?;
+        if out_fp.exists() && !force {
+            anyhow::bail!("refusing to overwrite existing file {out_fp:?}")
+        }
+        let chrom_sizes = contig_sizes
+            .map(|(name, size)| (name.to_owned(), size as u32))
+            .collect::<HashMap<String, u32>>();
+        Ok(Self {
+            out_fp: out_fp.to_path_buf(),
+            track_value,
+            track: BigWigTrack::new(out_fp, chrom_sizes, threads),
+        })
+    }
+
+    pub(super) fn track_value(&self) -> TrackValue {
+        self.track_value
+    }
+
+    /// Both strands can have a score at the same reference position, bigWig
+    /// intervals cannot overlap so the first one added is kept.
+    pub(super) fn add(
+        &mut self,
+        chrom: &str,
+        position: u64,
+        value: f32,
+    ) -> anyhow::Result<()> {
+        let start = position as u32;
+        self.track.add(chrom, start, start + 1, value)
+    }
+
+    /// Finish writing the bigWig, returns the number of sites written.
+    pub(super) fn finish(self) -> anyhow::Result<usize> {
+        let n_sites = self.track.finish()?;
+        info!("wrote {n_sites} site(s) to bigWig at {:?}", self.out_fp);
+        Ok(n_sites)
+    }
+}
+
+#[cfg(test)]
+mod tracks_tests {
+    use crate::dmr::tracks::TrackValue;
+
+    #[test]
+    fn test_track_value_transform() {
+        assert_eq!(TrackValue::effect_size.transform(-0.5, 10.0, 0.1), -0.5);
+        assert_eq!(TrackValue::score.transform(-0.5, 10.0, 0.1), 10.0);
+        assert!(
+            (TrackValue::neg_log10_pval.transform(-0.5, 10.0, 0.01) - 2.0)
+                .abs()
+                < 1e-6
+        );
+        assert!(TrackValue::neg_log10_pval
+            .transform(0.0, 0.0, 0.0)
+            .is_finite());
+    }
+}
@@ -0,0 +1,412 @@
+use std::fmt::{Display, Formatter};
+use std::io::Write;
+use std::sync::Arc;
+
+use clap::ValueEnum;
+use indicatif::ProgressBar;
+use itertools::Itertools;
+use log::{debug, error};
+use rayon::prelude::*;
+use rustc_hash::FxHashMap;
+use statrs::function::erf::erfc;
+
+use crate::dmr::bedmethyl::aggregate_counts;
+use crate::dmr::llr_model::AggregatedCounts;
+use crate::dmr::pairwise::filter_sample_records;
+use crate::dmr::tabix::{ChromToSampleBMLines, MultiSampleIndex};
+use crate::dmr::util::{DmrInterval, RegionOfInterest, RoiIter};
+use crate::errs::{MkError, MkResult};
+use crate::monoid::BorrowingMoniod;
+
+/// Test used to score monotonic trends in methylation over ordered groups.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, ValueEnum)]
+#[allow(non_camel_case_types)]
+pub(super) enum TrendTest {
+    /// Cochran-Armitage test for trend on the counts pooled over the
+    /// replicates in each group, with the group index as the score.
+    armitage,
+    /// Jonckheere-Terpstra test on the fraction modified of each replicate,
+    /// requires replicates to be informative.
+    jonckheere,
+}
+
+impl Display for TrendTest {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::armitage => write!(f, "armitage"),
+            Self::jonckheere => write!(f, "jonckheere"),
+        }
+    }
+}
+
+/// Two-sided p-value of a standard normal statistic.
+fn normal_two_sided_p(z: f64) -> f64 {
+    erfc(z.abs() / std::f64::consts::SQRT_2).min(1f64)
+}
+
+/// Cochran-Armitage test for trend in the fraction modified, `counts` are
+/// the (modified, total) counts of each group and the score of each group is
+/// its index. Returns the z-score, positive when the fraction modified
+/// increases with the group index, and the weighted least squares slope of
+/// the fraction modified on the group index.
+fn cochran_armitage(counts: &[(usize, usize)]) -> (f64, f64) {
+    let n = counts.iter().map(|(_, total)| *total as f64).sum::<f64>();
+    if n == 0f64 {
+        return (0f64, 0f64);
+    }
+    let p = counts.iter().map(|(m, _)| *m as f64).sum::<f64>() / n;
+    let mean_score = counts
+        .iter()
+        .enumerate()
+        .map(|(t, (_, total))| t as f64 * *total as f64)
+        .sum::<f64>()
+        / n;
+    let (t_stat, ss_scores) = counts.iter().enumerate().fold(
+        (0f64, 0f64),
+        |(t_stat, ss), (t, (m, total))| {
+            let (t, m, total) = (t as f64, *m as f64, *total as f64);
+            (
+                t_stat + t * (m - total * p),
+                ss + total * (t - mean_score).powi(2),
+            )
+        },
+    );
+    if ss_scores == 0f64 {
+        return (0f64, 0f64);
+    }
+    let slope = t_stat / ss_scores;
+    let variance = p * (1f64 - p) * ss_scores;
+    if variance == 0f64 {
+        (0f64, slope)
+    } else {
+        (t_stat / variance.sqrt(), slope)
+    }
+}
+
+/// Jonckheere-Terpstra test for an ordered alternative, `groups` are the
+/// observations in each group in order. Returns the z-score of the normal
+/// approximation (without a tie correction), positive when the observations
+/// increase with the group index.
+fn jonckheere_terpstra(groups: &[Vec<f64>]) -> f64 {
+    let j = groups
+        .iter()
+        .tuple_combinations()
+        .map(|(lower, higher)| {
+            lower
+                .iter()
+                .cartesian_product(higher.iter())
+                .map(|(a, b)| {
+                    if b > a {
+                        1f64
+                    } else if b == a {
+                        0.5f64
+                    } else {
+                        0f64
+                    }
+                })
+                .sum::<f64>()
+        })
+        .sum::<f64>();
+    let n = groups.iter().map(|g| g.len() as f64).sum::<f64>();
+    let sum_sq = groups.iter().map(|g| (g.len() as f64).powi(2)).sum::<f64>();
+    let expected = (n.powi(2) - sum_sq) / 4f64;
+    let variance = (n.powi(2) * (2f64 * n + 3f64)
+        - groups
+            .iter()
+            .map(|g| {
+                let k = g.len() as f64;
+                k.powi(2) * (2f64 * k + 3f64)
+            })
+            .sum::<f64>())
+        / 72f64;
+    if variance <= 0f64 {
+        0f64
+    } else {
+        (j - expected) / variance.sqrt()
+    }
+}
+
+/// Trend test result for one region.
+#[derive(Debug)]
+pub(super) struct TrendResult {
+    interval: DmrInterval,
+    group_counts: Vec<AggregatedCounts>,
+    z_score: f64,
+    p_value: f64,
+    slope: f64,
+}
+
+impl TrendResult {
+    /// `region_columns` are the names of the columns passed through from
+    /// the regions BED, see [`DmrInterval::extra_columns`].
+    pub(super) fn header(
+        group_names: &[String],
+        region_columns: &[String],
+    ) -> String {
+        let groups = group_names.join(",");
+        let mut s = [
+            "#chrom".to_string(),
+            "start".to_string(),
+            "end".to_string(),
+            "name".to_string(),
+            "score".to_string(),
+            "strand".to_string(),
+            format!("counts({groups})"),
+            format!("totals({groups})"),
+            format!("pct_modified({groups})"),
+            "slope".to_string(),
+            "z_score".to_string(),
+            "p_value".to_string(),
+        ]
+        .into_iter()
+        .chain(region_columns.iter().cloned())
+        .join("\t");
+        s.push('\n');
+        s
+    }
+
+    /// `per_group` has the counts of each replicate in each group, in the
+    /// group order. Groups without any counts are skipped.
+    fn new(
+        interval: DmrInterval,
+        per_group: Vec<Vec<AggregatedCounts>>,
+        test: TrendTest,
+    ) -> MkResult<Self> {
+        let per_group = per_group
+            .into_iter()
+            .map(|replicates| {
+                replicates
+                    .into_iter()
+                    .filter(|counts| counts.total > 0)
+                    .collect::<Vec<AggregatedCounts>>()
+            })
+            .collect::<Vec<_>>();
+        if per_group.iter().filter(|g| !g.is_empty()).count() < 2 {
+            return Err(MkError::DmrMissing);
+        }
+        let group_counts = per_group
+            .iter()
+            .map(|replicates| {
+                replicates
+                    .iter()
+                    .fold(AggregatedCounts::zero(), |acc, x| acc.op(x))
+            })
+            .collect::<Vec<AggregatedCounts>>();
+        let pooled = group_counts
+            .iter()
+            .map(|counts| (counts.modified_counts(), counts.total))
+            .collect::<Vec<(usize, usize)>>();
+        let (armitage_z, slope) = cochran_armitage(&pooled);
+        let z_score = match test {
+            TrendTest::armitage => armitage_z,
+            TrendTest::jonckheere => {
+                let fractions = per_group
+                    .iter()
+                    .filter(|g| !g.is_empty())
+                    .map(|replicates| {
+                        replicates
+                            .iter()
+                            .map(|counts| counts.frac_modified() as f64)
+                            .collect::<Vec<f64>>()
+                    })
+                    .collect::<Vec<Vec<f64>>>();
+                jonckheere_terpstra(&fractions)
+            }
+        };
+        Ok(Self {
+            interval,
+            group_counts,
+            z_score,
+            p_value: normal_two_sided_p(z_score),
+            slope,
+        })
+    }
+
+    pub(super) fn to_row(&self) -> String {
+        let score = -self.p_value.max(f64::MIN_POSITIVE).log10();
+        let counts = self
+            .group_counts
+            .iter()
+            .map(|counts| counts.modified_counts())
+            .join(",");
+        let totals =
+            self.group_counts.iter().map(|counts| counts.total).join(",");
+        let pct_modified = self
+            .group_counts
+            .iter()
+            .map(|counts| {
+                if counts.total == 0 {
+                    ".".to_string()
+                } else {
+                    format!("{:.2}", counts.frac_modified() * 100f32)
+                }
+            })
+            .join(",");
+        let sep = '\t';
+        format!(
+            "{}{sep}{}{sep}{}{sep}{}{sep}{score}{sep}{}{sep}{counts}{sep}\
+             {totals}{sep}{pct_modified}{sep}{}{sep}{}{sep}{:e}{}\n",
+            self.interval.chrom,
+            self.interval.start(),
+            self.interval.stop(),
+            self.interval.name,
+            self.interval.strand,
+            self.slope,
+            self.z_score,
+            self.p_value,
+            self.interval.extra_columns_suffix(sep),
+        )
+    }
+}
+
+/// Assignment of samples to ordered groups and the test to use.
+pub(super) struct TrendDesign {
+    /// Sample id to the index of its group.
+    sample_groups: FxHashMap<usize, usize>,
+    n_groups: usize,
+    test: TrendTest,
+}
+
+impl TrendDesign {
+    pub(super) fn new(
+        sample_groups: FxHashMap<usize, usize>,
+        n_groups: usize,
+        test: TrendTest,
+    ) -> Self {
+        Self { sample_groups, n_groups, test }
+    }
+}
+
+/// Score the trend in each region of interest.
+fn score_batch(
+    sample_index: &MultiSampleIndex,
+    design: &TrendDesign,
+    rois: Vec<RegionOfInterest>,
+    lines_a: ChromToSampleBMLines,
+    lines_b: ChromToSampleBMLines,
+) -> Vec<MkResult<TrendResult>> {
+    rois.into_par_iter()
+        .map(|roi| {
+            let mut per_group = vec![Vec::new(); design.n_groups];
+            let records = filter_sample_records(&lines_a, &roi, sample_index)
+                .into_iter()
+                .chain(filter_sample_records(&lines_b, &roi, sample_index));
+            for (sample_id, records) in records {
+                let counts =
+                    aggregate_counts(&records, &sample_index.code_lookup)?;
+                if let Some(group) = design.sample_groups.get(&sample_id) {
+                    per_group[*group].push(counts);
+                }
+            }
+            TrendResult::new(roi.dmr_interval, per_group, design.test)
+        })
+        .collect()
+}
+
+pub(super) fn run_trend_dmr(
+    dmr_interval_iter: RoiIter,
+    sample_index: Arc<MultiSampleIndex>,
+    design: TrendDesign,
+    pool: rayon::ThreadPool,
+    mut writer: Box<dyn Write>,
+    pb: ProgressBar,
+    failure_counter: ProgressBar,
+) -> anyhow::Result<(usize, FxHashMap<String, usize>)> {
+    let mut success_count = 0usize;
+    let mut region_error_counts = FxHashMap::<String, usize>::default();
+    for batch in dmr_interval_iter {
+        let (lines_a, lines_b) = match sample_index
+            .read_bedmethyl_group_by_chrom(&batch)
+        {
+            Ok(lines) => lines,
+            Err(e) => {
+                pb.suspend(|| {
+                    error!(
+                        "failed to read bedMethyl records for {} regions, {e}",
+                        batch.dmr_chunks.len()
+                    )
+                });
+                return Err(e.into());
+            }
+        };
+        let results = pool.install(|| {
+            score_batch(
+                &sample_index,
+                &design,
+                batch.dmr_chunks,
+                lines_a,
+                lines_b,
+            )
+        });
+        for result in results {
+            match result {
+                Ok(trend) => {
+                    writer.write_all(trend.to_row().as_bytes())?;
+                    success_count += 1;
+                    pb.inc(1);
+                }
+                Err(MkError::InvalidBedMethyl(message)) => {
+                    pb.suspend(|| {
+                        error!(
+                            "encountered invalid bedMethyl record(s), \
+                             {message}, stopping"
+                        );
+                    });
+                    return Err(MkError::InvalidBedMethyl(message).into());
+                }
+                Err(e) => {
+                    debug!("region failed, {e}");
+                    *region_error_counts.entry(e.to_string()).or_insert(0) += 1;
+                    failure_counter.inc(1);
+                }
+            }
+        }
+    }
+    writer.flush()?;
+    pb.finish_and_clear();
+
+    Ok((success_count, region_error_counts))
+}
+
+#[cfg(test)]
+mod trend_tests {
+    use crate::dmr::trend::{
+        cochran_armitage, jonckheere_terpstra, normal_two_sided_p,
+    };
+
+    #[test]
+    fn test_cochran_armitage() {
+        // increasing fraction modified, 10%, 50%, 90%
+        let (z, slope) = cochran_armitage(&[(10, 100), (50, 100), (90, 100)]);
+        // T = 0 * (10 - 50) + 1 * 0 + 2 * 40 = 80, var = 0.25 * 200 = 50
+        assert!((z - 80f64 / 50f64.sqrt()).abs() < 1e-9);
+        assert!((slope - 0.4).abs() < 1e-9);
+        assert!(normal_two_sided_p(z) < 1e-10);
+
+        let (z, slope) = cochran_armitage(&[(90, 100), (50, 100), (10, 100)]);
+        assert!(z < 0f64);
+        assert!((slope + 0.4).abs() < 1e-9);
+
+        // no trend
+        let (z, slope) = cochran_armitage(&[(50, 100), (50, 100), (50, 100)]);
+        assert_eq!((z, slope), (0f64, 0f64));
+        assert_eq!(normal_two_sided_p(z), 1f64);
+        // all modified, no variance
+        let (z, _) = cochran_armitage(&[(10, 10), (20, 20)]);
+        assert_eq!(z, 0f64);
+    }
+
+    #[test]
+    fn test_jonckheere_terpstra() {
+        let groups = vec![vec![0.1, 0.2], vec![0.3, 0.4], vec![0.5, 0.6]];
+        // every pair is ordered, J = 12, E = (36 - 12) / 4 = 6,
+        // var = (36 * 15 - 3 * 4 * 7) / 72 = 6.333
+        let z = jonckheere_terpstra(&groups);
+        let expected = 6f64 / ((36f64 * 15f64 - 84f64) / 72f64).sqrt();
+        assert!((z - expected).abs() < 1e-9);
+        let reversed = groups.into_iter().rev().collect::<Vec<_>>();
+        assert!((jonckheere_terpstra(&reversed) + expected).abs() < 1e-9);
+        let ties = vec![vec![0.5], vec![0.5], vec![0.5]];
+        assert_eq!(jonckheere_terpstra(&ties), 0f64);
+    }
+}
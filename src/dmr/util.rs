@@ -1,8 +1,7 @@
 use std::cmp::Ordering;
 use std::collections::VecDeque;
 use std::fmt::{Debug, Display, Formatter};
-use std::fs::File;
-use std::io::{BufRead, BufReader};
+use std::io::BufRead;
 use std::path::Path;
 use std::sync::Arc;
 
@@ -10,6 +9,7 @@ use crate::dmr::llr_model::AggregatedCounts;
 use crate::dmr::tabix::MultiSampleIndex;
 use crate::genome_positions::{GenomePositions, StrandedPosition};
 use crate::mod_base_code::DnaBase;
+use crate::parsing_utils::open_text_input;
 use crate::position_filter::Iv;
 use crate::util::{GenomeRegion, StrandRule};
 use anyhow::bail;
@@ -46,9 +46,22 @@ pub(super) struct DmrInterval {
     pub(super) chrom: String,
     pub(super) name: String,
     pub(super) strand: StrandRule,
+    /// Columns after the strand in the regions BED, only kept when they're
+    /// passed through to the output.
+    #[new(default)]
+    pub(super) extra_columns: Vec<String>,
 }
 
 impl DmrInterval {
+    /// The extra columns, each preceded by `sep`, to append to an output row.
+    pub(super) fn extra_columns_suffix(&self, sep: char) -> String {
+        self.extra_columns.iter().fold(String::new(), |mut acc, x| {
+            acc.push(sep);
+            acc.push_str(x);
+            acc
+        })
+    }
+
     pub(super) fn parse_unstranded_bed_line(
         line: &str,
     ) -> anyhow::Result<Self> {
@@ -66,6 +79,7 @@ impl DmrInterval {
             chrom: genome_region.chrom,
             name,
             strand: genome_region.strand,
+            extra_columns: Vec::new(),
         })
     }
 
@@ -84,6 +98,12 @@ impl DmrInterval {
             chrom: genome_region.chrom,
             name,
             strand: genome_region.strand,
+            extra_columns: line
+                .trim_end()
+                .split('\t')
+                .skip(6)
+                .map(|x| x.to_string())
+                .collect(),
         })
     }
 
@@ -280,29 +300,51 @@ pub(super) struct DmrBatch<T: Default + Debug> {
     pub(super) regions: FxHashMap<String, std::ops::Range<u64>>,
 }
 
-/// A batch of single positions, mapping of chrom to stranded position
-pub type DmrBatchOfPositions =
-    DmrBatch<FxHashMap<String, FxHashSet<StrandedPosition<DnaBase>>>>;
+/// The intervals of a batch of single positions, the positions themselves are
+/// looked up in the genome-wide bitsets instead of being collected.
+#[derive(Default)]
+pub(super) struct PositionChunks {
+    genome_positions: Option<Arc<GenomePositions>>,
+    intervals: FxHashMap<String, Vec<std::ops::Range<u64>>>,
+    n_positions: usize,
+}
+
+impl Debug for PositionChunks {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("PositionChunks")
+            .field("intervals", &self.intervals)
+            .field("n_positions", &self.n_positions)
+            .finish()
+    }
+}
+
+/// A batch of single positions, mapping of chrom to intervals of stranded
+/// positions
+pub type DmrBatchOfPositions = DmrBatch<PositionChunks>;
 impl DmrBatchOfPositions {
     pub(super) fn num_chunks(&self) -> usize {
-        self.dmr_chunks.values().map(|positions| positions.len()).sum::<usize>()
+        self.dmr_chunks.n_positions
     }
 
     pub(super) fn add_chunks(
         &mut self,
         contig: &str,
         range: std::ops::Range<u64>,
-        positions: Vec<StrandedPosition<DnaBase>>,
+        genome_positions: &Arc<GenomePositions>,
+        n_positions: usize,
         control_idxs: &[usize],
         experiment_idxs: &[usize],
     ) {
-        let contig_positions = self
-            .dmr_chunks
-            .entry(contig.to_owned())
-            .or_insert_with(|| FxHashSet::default());
-        for position in positions {
-            contig_positions.insert(position);
+        if self.dmr_chunks.genome_positions.is_none() {
+            self.dmr_chunks.genome_positions = Some(genome_positions.clone());
         }
+        let contig_intervals =
+            self.dmr_chunks.intervals.entry(contig.to_owned()).or_default();
+        match contig_intervals.last_mut() {
+            Some(last) if last.end == range.start => last.end = range.end,
+            _ => contig_intervals.push(range.clone()),
+        }
+        self.dmr_chunks.n_positions += n_positions;
         for i in control_idxs {
             self.idxs_a.insert(*i);
         }
@@ -322,10 +364,21 @@ impl DmrBatchOfPositions {
         chrom: &str,
         position: &StrandedPosition<DnaBase>,
     ) -> bool {
-        self.dmr_chunks
+        let in_intervals = self
+            .dmr_chunks
+            .intervals
             .get(chrom)
-            .map(|positions| positions.contains(position))
-            .unwrap_or(false)
+            .map(|intervals| {
+                intervals.iter().any(|iv| iv.contains(&position.position))
+            })
+            .unwrap_or(false);
+        in_intervals
+            && self
+                .dmr_chunks
+                .genome_positions
+                .as_ref()
+                .map(|gp| gp.contains(chrom, position))
+                .unwrap_or(false)
     }
 }
 
@@ -408,10 +461,14 @@ impl Iterator for RoiIter {
     }
 }
 
+/// Parse the regions in a BED file, the columns after the strand (BED6) are
+/// kept when `keep_extra_columns` is set, regions with fewer columns than
+/// the others are padded with `.`.
 pub(super) fn parse_roi_bed<P: AsRef<Path>>(
     fp: P,
+    keep_extra_columns: bool,
 ) -> anyhow::Result<Vec<DmrInterval>> {
-    let mut reader = BufReader::new(File::open(fp)?)
+    let mut reader = open_text_input(fp)?
         .lines()
         .filter_map(|r| match r {
             Ok(l) => Some(l),
@@ -437,10 +494,19 @@ pub(super) fn parse_roi_bed<P: AsRef<Path>>(
         |l: &str| DmrInterval::parse_stranded_bed_line(l)
     };
 
-    let intervals = reader
+    let mut intervals = reader
         // todo check that regions do not overlap
         .map(|line| parser(&line))
         .collect::<anyhow::Result<Vec<DmrInterval>>>()?;
+    let n_extra_columns =
+        intervals.iter().map(|roi| roi.extra_columns.len()).max().unwrap_or(0);
+    for roi in intervals.iter_mut() {
+        if keep_extra_columns {
+            roi.extra_columns.resize(n_extra_columns, ".".to_string());
+        } else {
+            roi.extra_columns.clear();
+        }
+    }
 
     if intervals.is_empty() {
         bail!("didn't parse any regions")
@@ -449,6 +515,35 @@ pub(super) fn parse_roi_bed<P: AsRef<Path>>(
     }
 }
 
+/// Output header names of the extra columns kept by [`parse_roi_bed`]. Names
+/// are taken from the header line of the BED file (the last line starting
+/// with `#` before the regions) when it has a name for every column,
+/// otherwise they're `column_<n>` with the 1-based column number.
+pub(super) fn roi_extra_column_names<P: AsRef<Path>>(
+    fp: P,
+    rois: &[DmrInterval],
+) -> anyhow::Result<Vec<String>> {
+    let n_extra_columns =
+        rois.first().map(|roi| roi.extra_columns.len()).unwrap_or(0);
+    let header = open_text_input(fp)?
+        .lines()
+        .map_while(Result::ok)
+        .take_while(|l| l.starts_with('#'))
+        .last();
+    let header_names = header
+        .map(|l| {
+            l.trim_end()
+                .split('\t')
+                .skip(6)
+                .map(|x| x.to_string())
+                .collect::<Vec<String>>()
+        })
+        .filter(|names| names.len() == n_extra_columns);
+    Ok(header_names.unwrap_or_else(|| {
+        (0..n_extra_columns).map(|i| format!("column_{}", i + 7)).collect()
+    }))
+}
+
 pub(crate) fn n_choose_2(n: usize) -> anyhow::Result<usize> {
     match n {
         0 | 1 => bail!("n must be >= 2"),
@@ -510,7 +605,9 @@ pub(super) fn cohen_h(
 
 #[cfg(test)]
 mod dmr_util_tests {
-    use crate::dmr::util::{calc_cohen_h, parse_roi_bed, DmrInterval};
+    use crate::dmr::util::{
+        calc_cohen_h, parse_roi_bed, roi_extra_column_names, DmrInterval,
+    };
     use crate::position_filter::Iv;
     use crate::util::StrandRule;
     use std::ops::Neg;
@@ -566,25 +663,28 @@ mod dmr_util_tests {
     #[test]
     fn test_roi_parsing() {
         let fp = "tests/resources/sim_cpg_regions.bed";
-        let rois = parse_roi_bed(fp).unwrap();
+        let rois = parse_roi_bed(fp, false).unwrap();
         let expected = [
             DmrInterval {
                 interval: Iv { start: 10172120, stop: 10172545, val: () },
                 chrom: "chr20".to_string(),
                 name: "r1".to_string(),
                 strand: StrandRule::Both,
+                extra_columns: Vec::new(),
             },
             DmrInterval {
                 interval: Iv { start: 10217487, stop: 10218336, val: () },
                 chrom: "chr20".to_string(),
                 name: "r2".to_string(),
                 strand: StrandRule::Both,
+                extra_columns: Vec::new(),
             },
             DmrInterval {
                 interval: Iv { start: 10034963, stop: 10035266, val: () },
                 chrom: "chr20".to_string(),
                 name: "r3".to_string(),
                 strand: StrandRule::Both,
+                extra_columns: Vec::new(),
             },
         ]
         .to_vec();
@@ -594,35 +694,68 @@ mod dmr_util_tests {
     #[test]
     fn test_roi_parsing_noname() {
         let fp = "tests/resources/sim_cpg_regions_noname.bed";
-        let rois = parse_roi_bed(fp).unwrap();
+        let rois = parse_roi_bed(fp, false).unwrap();
         let expected = [
             DmrInterval {
                 interval: Iv { start: 10172120, stop: 10172545, val: () },
                 chrom: "chr20".to_string(),
                 name: "chr20:10172120-10172545".to_string(),
                 strand: StrandRule::Both,
+                extra_columns: Vec::new(),
             },
             DmrInterval {
                 interval: Iv { start: 10217487, stop: 10218336, val: () },
                 chrom: "chr20".to_string(),
                 name: "chr20:10217487-10218336".to_string(),
                 strand: StrandRule::Both,
+                extra_columns: Vec::new(),
             },
             DmrInterval {
                 interval: Iv { start: 10034963, stop: 10035266, val: () },
                 chrom: "chr20".to_string(),
                 name: "chr20:10034963-10035266".to_string(),
                 strand: StrandRule::Both,
+                extra_columns: Vec::new(),
             },
         ]
         .to_vec();
         assert_eq!(rois, expected);
     }
 
+    #[test]
+    fn test_roi_parsing_extra_columns() {
+        let fp = "tests/resources/sim_cpg_regions_extra_columns.bed";
+        let rois = parse_roi_bed(fp, true).unwrap();
+        let extra_columns = rois
+            .iter()
+            .map(|roi| roi.extra_columns.join(","))
+            .collect::<Vec<String>>();
+        // regions missing a column are padded
+        assert_eq!(
+            extra_columns,
+            vec!["ENSG01,promoter", "ENSG02,.", "ENSG03,enhancer"]
+        );
+        assert_eq!(rois[1].extra_columns_suffix('\t'), "\tENSG02\t.");
+        let names = roi_extra_column_names(fp, &rois).unwrap();
+        assert_eq!(names, vec!["gene_id", "category"]);
+
+        // without a header line the columns are named by their number
+        let names = roi_extra_column_names(
+            "tests/resources/sim_cpg_regions.bed",
+            &rois,
+        )
+        .unwrap();
+        assert_eq!(names, vec!["column_7", "column_8"]);
+
+        let rois = parse_roi_bed(fp, false).unwrap();
+        assert!(rois.iter().all(|roi| roi.extra_columns.is_empty()));
+        assert!(roi_extra_column_names(fp, &rois).unwrap().is_empty());
+    }
+
     #[test]
     fn test_roi_parsing_motif_bed() {
         let fp = "tests/resources/test_motif_bed_drach.bed";
-        let rois = parse_roi_bed(fp).unwrap();
+        let rois = parse_roi_bed(fp, false).unwrap();
         assert_eq!(rois.len(), 10);
     }
 
@@ -0,0 +1,307 @@
+use std::collections::{BTreeMap, VecDeque};
+use std::io::Write;
+
+use derive_new::new;
+use itertools::Itertools;
+use rustc_hash::FxHashMap;
+
+use crate::dmr::bedmethyl::{combine_cpg_strands, BedMethylLine};
+use crate::entropy::methylation_entropy::miller_madow_correction;
+use crate::entropy::EntropyNorm;
+use crate::errs::MkResult;
+use crate::util::{StrandRule, TAB};
+
+/// Column names for the windows output when using `--in-bedmethyl`.
+pub(super) const BEDMETHYL_WINDOWS_HEADER: &str = concat!(
+    "#chrom\tstart\tend\tentropy\tstrand\t",
+    "min_valid_coverage\tepipolymorphism\n"
+);
+
+/// The aggregate counts at one position and strand of a bedMethyl, as the
+/// fraction of valid calls that were canonical, each modification code, or
+/// another modification not in the file.
+#[derive(Debug)]
+struct BedMethylSite {
+    start: u64,
+    stop: u64,
+    valid_coverage: u64,
+    fractions: Vec<f32>,
+}
+
+impl BedMethylSite {
+    /// Make a site from the records (one per modification code) at a
+    /// position and strand. `None` when the position has no valid coverage.
+    fn from_records(records: &[&BedMethylLine]) -> Option<Self> {
+        let first = records.first()?;
+        let valid_coverage =
+            records.iter().map(|r| r.valid_coverage).max().unwrap_or(0);
+        if valid_coverage == 0 {
+            return None;
+        }
+        let n_modified =
+            records.iter().map(|r| r.count_methylated).sum::<u64>();
+        // modifications that aren't in the file (e.g. a bedMethyl filtered
+        // to only 5mC) are counted together
+        let n_other = valid_coverage
+            .saturating_sub(first.count_canonical)
+            .saturating_sub(n_modified);
+        let fractions = std::iter::once(first.count_canonical)
+            .chain(records.iter().map(|r| r.count_methylated))
+            .chain(std::iter::once(n_other))
+            .filter(|&count| count > 0)
+            .map(|count| count as f32 / valid_coverage as f32)
+            .collect();
+        Some(Self {
+            start: first.start(),
+            stop: first.stop(),
+            valid_coverage,
+            fractions,
+        })
+    }
+
+    /// Entropy of the calls at this position, in bits.
+    fn entropy(&self, miller_madow: bool) -> f32 {
+        let entropy =
+            self.fractions.iter().fold(0f32, |acc, p| acc - p * p.log2());
+        if miller_madow {
+            entropy
+                + miller_madow_correction(
+                    self.fractions.len(),
+                    self.valid_coverage as f32,
+                )
+        } else {
+            entropy
+        }
+    }
+
+    /// Probability that two reads have the same call at this position.
+    fn homozygosity(&self) -> f32 {
+        self.fractions.iter().map(|p| p * p).sum()
+    }
+}
+
+/// Counts of what happened while calculating entropy over a bedMethyl.
+#[derive(Debug, Default)]
+pub(super) struct BedMethylEntropyCounts {
+    pub(super) windows_written: usize,
+    pub(super) sites: usize,
+    pub(super) low_coverage_sites: usize,
+}
+
+/// Approximate the methylation entropy of windows from the aggregate counts
+/// in a bedMethyl. Without the reads, the calls at the positions in a window
+/// are assumed to be independent, so the entropy of a window is the sum of
+/// the entropy at each position (an upper bound on the entropy of the read
+/// patterns) and the epipolymorphism is one minus the product of the
+/// probabilities that two reads agree at each position.
+#[derive(new)]
+pub(super) struct BedMethylEntropy {
+    num_positions: usize,
+    window_size: usize,
+    min_valid_coverage: u64,
+    combine_strands: bool,
+    norm: EntropyNorm,
+    miller_madow: bool,
+}
+
+impl BedMethylEntropy {
+    /// Calculate the windows on one contig and write them to `out`, `records`
+    /// must be sorted by start position (as they are from a tabix index).
+    pub(super) fn process_contig<W: Write>(
+        &self,
+        chrom: &str,
+        records: impl Iterator<Item = MkResult<BedMethylLine>>,
+        drop_zeros: bool,
+        out: &mut W,
+    ) -> anyhow::Result<BedMethylEntropyCounts> {
+        let mut counts = BedMethylEntropyCounts::default();
+        let mut windows =
+            FxHashMap::<StrandRule, VecDeque<BedMethylSite>>::default();
+        // records are held until no later record can be at the same
+        // position, negative strand records are moved to the positive strand
+        // position of their CpG when combining strands
+        let mut pending = BTreeMap::<u64, Vec<BedMethylLine>>::new();
+        for record in records {
+            let record = record?;
+            let key = if self.combine_strands
+                && record.strand == StrandRule::Negative
+            {
+                record.start().saturating_sub(1)
+            } else {
+                record.start()
+            };
+            let ready = record.start().saturating_sub(1);
+            pending.entry(key).or_default().push(record);
+            while pending
+                .first_key_value()
+                .map(|(&k, _)| k < ready)
+                .unwrap_or(false)
+            {
+                let (_, lines) = pending.pop_first().unwrap();
+                self.add_position(
+                    chrom,
+                    lines,
+                    &mut windows,
+                    drop_zeros,
+                    &mut counts,
+                    out,
+                )?;
+            }
+        }
+        while let Some((_, lines)) = pending.pop_first() {
+            self.add_position(
+                chrom,
+                lines,
+                &mut windows,
+                drop_zeros,
+                &mut counts,
+                out,
+            )?;
+        }
+        Ok(counts)
+    }
+
+    fn add_position<W: Write>(
+        &self,
+        chrom: &str,
+        lines: Vec<BedMethylLine>,
+        windows: &mut FxHashMap<StrandRule, VecDeque<BedMethylSite>>,
+        drop_zeros: bool,
+        counts: &mut BedMethylEntropyCounts,
+        out: &mut W,
+    ) -> anyhow::Result<()> {
+        let lines = if self.combine_strands {
+            combine_cpg_strands(lines, &(0..u64::MAX), 0)
+        } else {
+            lines
+        };
+        let by_strand = lines.iter().into_group_map_by(|l| l.strand);
+        for (strand, records) in
+            by_strand.into_iter().sorted_by_key(|(s, _)| *s)
+        {
+            let Some(site) = BedMethylSite::from_records(&records) else {
+                continue;
+            };
+            counts.sites += 1;
+            let sites = windows.entry(strand).or_default();
+            // windows can't span positions without enough coverage
+            if site.valid_coverage < self.min_valid_coverage {
+                counts.low_coverage_sites += 1;
+                sites.clear();
+                continue;
+            }
+            sites.push_back(site);
+            if sites.len() < self.num_positions {
+                continue;
+            }
+            if let Some(row) = self.window_row(chrom, strand, sites, drop_zeros)
+            {
+                out.write_all(row.as_bytes())?;
+                counts.windows_written += 1;
+            }
+            sites.pop_front();
+        }
+        Ok(())
+    }
+
+    fn window_row(
+        &self,
+        chrom: &str,
+        strand: StrandRule,
+        sites: &VecDeque<BedMethylSite>,
+        drop_zeros: bool,
+    ) -> Option<String> {
+        let (first, last) = (sites.front()?, sites.back()?);
+        if last.stop.saturating_sub(first.start) > self.window_size as u64 {
+            return None;
+        }
+        let entropy =
+            sites.iter().map(|s| s.entropy(self.miller_madow)).sum::<f32>();
+        let entropy = match self.norm {
+            EntropyNorm::window => entropy / sites.len() as f32,
+            EntropyNorm::none | EntropyNorm::reads => entropy,
+        };
+        if drop_zeros && entropy == 0f32 {
+            return None;
+        }
+        let epipolymorphism =
+            1f32 - sites.iter().map(|s| s.homozygosity()).product::<f32>();
+        let min_coverage = sites.iter().map(|s| s.valid_coverage).min()?;
+        // combined CpGs are reported on the positive strand, as they are with
+        // mod-BAM inputs
+        let strand =
+            if self.combine_strands { StrandRule::Positive } else { strand };
+        Some(format!(
+            "{chrom}{TAB}{}{TAB}{}{TAB}{entropy}{TAB}{strand}{TAB}\
+             {min_coverage}{TAB}{epipolymorphism}\n",
+            first.start, last.stop
+        ))
+    }
+}
+
+#[cfg(test)]
+mod bedmethyl_entropy_tests {
+    use crate::dmr::bedmethyl::BedMethylLine;
+    use crate::entropy::bedmethyl::{BedMethylEntropy, BedMethylSite};
+    use crate::entropy::EntropyNorm;
+
+    fn line(start: u64, strand: char, code: char, n_mod: u64) -> BedMethylLine {
+        let (stop, n_canonical) = (start + 1, 10 - n_mod);
+        let raw = [
+            format!("chr1\t{start}\t{stop}\t{code}\t10\t{strand}"),
+            format!("{start}\t{stop}\t255,0,0\t10\t0.0"),
+            format!("{n_mod}\t{n_canonical}\t0\t0\t0\t0\t0"),
+        ]
+        .join("\t");
+        BedMethylLine::parse(&raw).unwrap()
+    }
+
+    #[test]
+    fn test_bedmethyl_site_entropy() {
+        let fully_methylated = line(0, '+', 'm', 10);
+        let site = BedMethylSite::from_records(&[&fully_methylated]).unwrap();
+        assert_eq!(site.entropy(false), 0f32);
+        assert_eq!(site.homozygosity(), 1f32);
+
+        let half = line(0, '+', 'm', 5);
+        let site = BedMethylSite::from_records(&[&half]).unwrap();
+        assert!((site.entropy(false) - 1f32).abs() < 1e-6);
+        assert!((site.homozygosity() - 0.5f32).abs() < 1e-6);
+        assert!(site.entropy(true) > site.entropy(false));
+    }
+
+    #[test]
+    fn test_bedmethyl_entropy_windows() {
+        let calculator =
+            BedMethylEntropy::new(2, 10, 1, true, EntropyNorm::window, false);
+        // a CpG at 0 with both strands, a CpG at 4, and one too far away to be
+        // in a window with it
+        let records = vec![
+            line(0, '+', 'm', 10),
+            line(1, '-', 'm', 0),
+            line(4, '+', 'm', 5),
+            line(5, '-', 'm', 5),
+            line(40, '+', 'm', 5),
+        ];
+        let mut out = Vec::new();
+        let counts = calculator
+            .process_contig(
+                "chr1",
+                records.into_iter().map(Ok),
+                false,
+                &mut out,
+            )
+            .unwrap();
+        assert_eq!(counts.sites, 3);
+        assert_eq!(counts.windows_written, 1);
+        let out = String::from_utf8(out).unwrap();
+        let parts = out.trim().split('\t').collect::<Vec<&str>>();
+        assert_eq!(&parts[..3], &["chr1", "0", "5"]);
+        // the combined CpG at 0 is half methylated and the one at 4 is half
+        // methylated, 1 bit each
+        assert_eq!(parts[3].parse::<f32>().unwrap(), 1f32);
+        assert_eq!(parts[4], "+");
+        assert_eq!(parts[5], "20");
+        assert_eq!(parts[6].parse::<f32>().unwrap(), 0.75f32);
+    }
+}
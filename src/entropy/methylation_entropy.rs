@@ -3,7 +3,7 @@ use itertools::Itertools;
 use log_once::debug_once;
 use regex::Regex;
 use rustc_hash::{FxHashMap, FxHashSet};
-use std::collections::{BTreeSet, HashMap};
+use std::collections::BTreeSet;
 use std::str::Chars;
 use substring::Substring;
 
@@ -139,34 +139,75 @@ fn all_patterns_dp(
     all_combs.into_iter().sorted().collect::<Vec<String>>()
 }
 
-fn calc_entropy(sequences: &[String], window_size: usize) -> f32 {
+/// Pattern weights of each sequence, a sequence with filtered positions is
+/// split evenly between all of the patterns it matches. Returns the indices
+/// of the patterns matched by each sequence with their weights, and the total
+/// weight of each pattern.
+fn pattern_weights(
+    sequences: &[String],
+    window_size: usize,
+) -> (Vec<Vec<(usize, f32)>>, Vec<f32>) {
     let mut alphabet_info =
         AlphabetInfo::from_sequences(sequences, window_size);
     let patterns = all_patterns_dp(sequences, window_size, &mut alphabet_info);
 
     let mut cache = FxHashMap::default();
-    let counts = sequences.iter().fold(HashMap::new(), |mut acc, seq| {
-        // let re = seq_to_regex(seq, &alphabet_info.wildcard_regex);
-        let re = if let Some(re) = cache.get(seq) {
-            re
-        } else {
-            let re = alphabet_info.seq_to_regex(seq);
-            cache.insert(seq, re);
-            cache.get(seq).unwrap()
-        };
-        let matches = patterns
-            .iter()
-            .filter(|p| re.is_match(p))
-            .collect::<Vec<&String>>();
-        assert!(matches.len() > 0, "no matches for {seq} in {patterns:?}");
-        let factor = 1f32 / matches.len() as f32;
-        for pattern in matches {
-            *acc.entry(pattern).or_insert(0f32) += factor;
-        }
-        acc
-    });
+    let mut counts = vec![0f32; patterns.len()];
+    let weights = sequences
+        .iter()
+        .map(|seq| {
+            // let re = seq_to_regex(seq, &alphabet_info.wildcard_regex);
+            let re = if let Some(re) = cache.get(seq) {
+                re
+            } else {
+                let re = alphabet_info.seq_to_regex(seq);
+                cache.insert(seq, re);
+                cache.get(seq).unwrap()
+            };
+            let matches = patterns
+                .iter()
+                .enumerate()
+                .filter(|(_, p)| re.is_match(p))
+                .map(|(idx, _)| idx)
+                .collect::<Vec<usize>>();
+            assert!(matches.len() > 0, "no matches for {seq} in {patterns:?}");
+            let factor = 1f32 / matches.len() as f32;
+            matches
+                .into_iter()
+                .map(|idx| {
+                    counts[idx] += factor;
+                    (idx, factor)
+                })
+                .collect::<Vec<(usize, f32)>>()
+        })
+        .collect::<Vec<_>>();
+
+    (weights, counts)
+}
+
+/// Miller-Madow correction, in bits, for the downward bias of the plug-in
+/// entropy estimate when `n_observed` distinct outcomes are seen in
+/// `n_samples` samples.
+pub(super) fn miller_madow_correction(
+    n_observed: usize,
+    n_samples: f32,
+) -> f32 {
+    if n_samples <= 0f32 {
+        0f32
+    } else {
+        n_observed.saturating_sub(1) as f32
+            / (2f32 * n_samples * std::f32::consts::LN_2)
+    }
+}
+
+fn calc_entropy(
+    sequences: &[String],
+    window_size: usize,
+    miller_madow: bool,
+) -> f32 {
+    let (_, counts) = pattern_weights(sequences, window_size);
 
-    let total = counts.values().sum::<f32>();
+    let total = counts.iter().sum::<f32>();
     if total - sequences.len() as f32 > 1e-3 {
         if total > sequences.len() as f32 {
             debug_once!(
@@ -179,22 +220,68 @@ fn calc_entropy(sequences: &[String], window_size: usize) -> f32 {
         }
     }
     debug_assert!((total - sequences.len() as f32) < 1f32);
-    counts
-        .values()
+    let shannons = counts
+        .iter()
+        .filter(|&&x| x > 0f32)
         .map(|&x| {
             let p = x / total;
             p * (p.log2())
         })
         .sum::<f32>()
-        * -1f32
+        * -1f32;
+    if miller_madow {
+        let n_observed = counts.iter().filter(|&&x| x > 0f32).count();
+        shannons + miller_madow_correction(n_observed, total)
+    } else {
+        shannons
+    }
+}
+
+/// The contribution of each sequence to the methylation entropy, the
+/// contributions sum to the entropy from [`calc_me_entropy`]. The
+/// Miller-Madow correction is split evenly between the sequences.
+pub(super) fn calc_me_entropy_per_read(
+    sequences: &[String],
+    window_size: usize,
+    constant: f32,
+    miller_madow: bool,
+) -> Vec<f32> {
+    let (weights, counts) = pattern_weights(sequences, window_size);
+    let total = counts.iter().sum::<f32>();
+    let correction = if miller_madow {
+        let n_observed = counts.iter().filter(|&&x| x > 0f32).count();
+        miller_madow_correction(n_observed, total) / sequences.len() as f32
+    } else {
+        0f32
+    };
+    weights
+        .iter()
+        .map(|seq_weights| {
+            let contribution = seq_weights
+                .iter()
+                .map(|&(idx, w)| {
+                    let p = counts[idx] / total;
+                    (w / total) * p.log2()
+                })
+                .sum::<f32>()
+                * -constant
+                + correction * constant;
+            if contribution == -0f32 {
+                0f32
+            } else {
+                contribution
+            }
+        })
+        .collect()
 }
 
 pub(super) fn calc_me_entropy(
     sequences: &[String],
     window_size: usize,
     constant: f32,
+    miller_madow: bool,
 ) -> f32 {
-    let shannons = calc_entropy(sequences, window_size);
+    let shannons = calc_entropy(sequences, window_size, miller_madow);
     let me_entropy = constant * shannons;
     if me_entropy == -0f32 {
         0f32
@@ -203,10 +290,150 @@ pub(super) fn calc_me_entropy(
     }
 }
 
+/// Epipolymorphism of the patterns in a window, the probability that two
+/// reads drawn at random have different patterns. Reads with filtered
+/// positions ('*') are split evenly between the patterns they match, as they
+/// are when calculating the entropy.
+pub(super) fn calc_epipolymorphism(
+    sequences: &[String],
+    window_size: usize,
+) -> f32 {
+    if sequences.is_empty() {
+        return 0f32;
+    }
+    let (_, counts) = pattern_weights(sequences, window_size);
+    let total = sequences.len() as f32;
+    let homozygosity =
+        counts.iter().map(|count| (count / total).powi(2)).sum::<f32>();
+    (1f32 - homozygosity).max(0f32)
+}
+
+/// Number of reads with each observed pattern in a window, the most common
+/// pattern first (ties are ordered by pattern).
+pub(super) fn epiallele_counts(sequences: &[String]) -> Vec<(String, usize)> {
+    sequences
+        .iter()
+        .counts()
+        .into_iter()
+        .map(|(pattern, count)| (pattern.to_string(), count))
+        .sorted_by(|(a, n_a), (b, n_b)| n_b.cmp(n_a).then_with(|| a.cmp(b)))
+        .collect()
+}
+
+/// Conditional (transition) entropy, in bits, of the state at a position
+/// given the states at the `order` preceding positions in the window. The
+/// transitions from every read and every position in the window are pooled,
+/// transitions that involve a filtered position ('*') are skipped. With
+/// `miller_madow` the joint and context entropies are both bias corrected.
+pub(super) fn calc_transition_entropy(
+    sequences: &[String],
+    order: usize,
+    miller_madow: bool,
+) -> f32 {
+    let mut context_counts = FxHashMap::<&[u8], f32>::default();
+    let mut transition_counts = FxHashMap::<&[u8], f32>::default();
+    for seq in sequences.iter().map(|s| s.as_bytes()) {
+        for transition in seq.windows(order + 1) {
+            if transition.contains(&b'*') {
+                continue;
+            }
+            *context_counts.entry(&transition[..order]).or_insert(0f32) += 1f32;
+            *transition_counts.entry(transition).or_insert(0f32) += 1f32;
+        }
+    }
+    let total = transition_counts.values().sum::<f32>();
+    if total == 0f32 {
+        return 0f32;
+    }
+    let entropy = |counts: &FxHashMap<&[u8], f32>| {
+        counts
+            .values()
+            .map(|&x| {
+                let p = x / total;
+                p * p.log2()
+            })
+            .sum::<f32>()
+            * -1f32
+    };
+    // H(next | context) = H(context, next) - H(context)
+    let conditional = entropy(&transition_counts) - entropy(&context_counts);
+    let conditional = if miller_madow {
+        conditional
+            + transition_correction(&transition_counts, &context_counts, total)
+    } else {
+        conditional
+    };
+    conditional.max(0f32)
+}
+
+/// Difference of the Miller-Madow corrections of the joint (transition) and
+/// context entropies.
+fn transition_correction(
+    transition_counts: &FxHashMap<&[u8], f32>,
+    context_counts: &FxHashMap<&[u8], f32>,
+    total: f32,
+) -> f32 {
+    miller_madow_correction(transition_counts.len(), total)
+        - miller_madow_correction(context_counts.len(), total)
+}
+
+/// The contribution of each sequence to the transition entropy (before it is
+/// clamped at zero), the sum of the terms of each of its transitions. The
+/// Miller-Madow correction is split evenly between the sequences.
+pub(super) fn calc_transition_entropy_per_read(
+    sequences: &[String],
+    order: usize,
+    miller_madow: bool,
+) -> Vec<f32> {
+    let mut context_counts = FxHashMap::<&[u8], f32>::default();
+    let mut transition_counts = FxHashMap::<&[u8], f32>::default();
+    for seq in sequences.iter().map(|s| s.as_bytes()) {
+        for transition in seq.windows(order + 1) {
+            if transition.contains(&b'*') {
+                continue;
+            }
+            *context_counts.entry(&transition[..order]).or_insert(0f32) += 1f32;
+            *transition_counts.entry(transition).or_insert(0f32) += 1f32;
+        }
+    }
+    let total = transition_counts.values().sum::<f32>();
+    let correction = if miller_madow {
+        transition_correction(&transition_counts, &context_counts, total)
+            / sequences.len() as f32
+    } else {
+        0f32
+    };
+    sequences
+        .iter()
+        .map(|seq| {
+            let contribution = seq
+                .as_bytes()
+                .windows(order + 1)
+                .filter(|transition| !transition.contains(&b'*'))
+                .map(|transition| {
+                    let p_transition = transition_counts[transition] / total;
+                    let p_context =
+                        context_counts[&transition[..order]] / total;
+                    (p_transition.log2() - p_context.log2()) / total
+                })
+                .sum::<f32>()
+                * -1f32
+                + correction;
+            if contribution == -0f32 {
+                0f32
+            } else {
+                contribution
+            }
+        })
+        .collect()
+}
+
 #[cfg(test)]
 mod methylation_entropy_tests {
     use crate::entropy::methylation_entropy::{
-        all_patterns_dp, calc_entropy, calc_me_entropy, AlphabetInfo,
+        all_patterns_dp, calc_entropy, calc_epipolymorphism, calc_me_entropy,
+        calc_me_entropy_per_read, calc_transition_entropy,
+        calc_transition_entropy_per_read, epiallele_counts, AlphabetInfo,
     };
     use assert_approx_eq::assert_approx_eq;
 
@@ -218,21 +445,21 @@ mod methylation_entropy_tests {
             "0000".to_string(),
             "0000".to_string(),
         ];
-        assert_eq!(calc_me_entropy(&sequences, 4, 0.25), 0.0);
+        assert_eq!(calc_me_entropy(&sequences, 4, 0.25, false), 0.0);
         let sequences = vec![
             "1111".to_string(),
             "1111".to_string(),
             "1111".to_string(),
             "1111".to_string(),
         ];
-        assert_eq!(calc_me_entropy(&sequences, 4, 0.25), 0.0);
+        assert_eq!(calc_me_entropy(&sequences, 4, 0.25, false), 0.0);
         let sequences = vec![
             "0010".to_string(),
             "0010".to_string(),
             "0010".to_string(),
             "0010".to_string(),
         ];
-        assert_eq!(calc_me_entropy(&sequences, 4, 0.25), 0.0);
+        assert_eq!(calc_me_entropy(&sequences, 4, 0.25, false), 0.0);
         let sequences = vec![
             "1111".to_string(),
             "1111".to_string(),
@@ -243,7 +470,7 @@ mod methylation_entropy_tests {
             "0000".to_string(),
             "0000".to_string(),
         ];
-        assert_eq!(calc_me_entropy(&sequences, 4, 0.25), 0.25);
+        assert_eq!(calc_me_entropy(&sequences, 4, 0.25, false), 0.25);
         let sequences = vec![
             "1111".to_string(),
             "1111".to_string(),
@@ -254,7 +481,7 @@ mod methylation_entropy_tests {
             "0000".to_string(),
             "0000".to_string(),
         ];
-        assert_eq!(calc_me_entropy(&sequences, 4, 0.25), 0.50);
+        assert_eq!(calc_me_entropy(&sequences, 4, 0.25, false), 0.50);
         let sequences = vec![
             "0000".to_string(),
             "1111".to_string(),
@@ -265,7 +492,7 @@ mod methylation_entropy_tests {
             "0000".to_string(),
             "1111".to_string(),
         ];
-        assert_eq!(calc_me_entropy(&sequences, 4, 0.25), 0.47640976);
+        assert_eq!(calc_me_entropy(&sequences, 4, 0.25, false), 0.47640976);
     }
 
     #[test]
@@ -286,7 +513,7 @@ mod methylation_entropy_tests {
                 "1111".to_string(),
             ]
         );
-        let entropy = calc_entropy(&sequences, 4);
+        let entropy = calc_entropy(&sequences, 4, false);
         assert_eq!(entropy, 1.75);
 
         let sequences = vec!["1*11", "1111", "1011", "1111"]
@@ -297,7 +524,7 @@ mod methylation_entropy_tests {
         let alphabet_info = AlphabetInfo::from_sequences(&sequences, 4);
         let patterns = all_patterns_dp(&sequences, 4, &alphabet_info);
         assert_eq!(patterns, vec!["1011".to_string(), "1111".to_string(),]);
-        let entropy = calc_entropy(&sequences, 4);
+        let entropy = calc_entropy(&sequences, 4, false);
         assert_eq!(entropy, 0.95443404);
 
         let sequences = vec!["1*01", "1101", "1011", "1111"]
@@ -316,7 +543,7 @@ mod methylation_entropy_tests {
                 "1111".to_string(),
             ]
         );
-        let entropy = calc_entropy(&sequences, 4);
+        let entropy = calc_entropy(&sequences, 4, false);
         assert_approx_eq!(entropy, 1.9, 0.01);
 
         let sequences = vec!["*010", "1010", "0010"]
@@ -327,7 +554,7 @@ mod methylation_entropy_tests {
         let alphabet_info = AlphabetInfo::from_sequences(&sequences, 4);
         let patterns = all_patterns_dp(&sequences, 4, &alphabet_info);
         assert_eq!(patterns, vec!["0010".to_string(), "1010".to_string(),]);
-        let entropy = calc_entropy(&sequences, 4);
+        let entropy = calc_entropy(&sequences, 4, false);
         assert_eq!(entropy, 1.0f32);
 
         let sequences = vec!["1010", "1010", "1010", "1010"]
@@ -336,10 +563,102 @@ mod methylation_entropy_tests {
             .collect::<Vec<String>>();
 
         let _alphabet_info = AlphabetInfo::from_sequences(&sequences, 4);
-        let entropy = calc_entropy(&sequences, 4);
+        let entropy = calc_entropy(&sequences, 4, false);
         assert_eq!(entropy, 0f32);
     }
 
+    #[test]
+    fn test_calc_transition_entropy() {
+        let to_strings = |xs: Vec<&str>| {
+            xs.into_iter().map(|x| x.to_string()).collect::<Vec<String>>()
+        };
+        // alternating patterns are perfectly predictable from the previous
+        // position, even though the joint entropy is not zero
+        let sequences = to_strings(vec!["0101", "1010", "0101", "1010"]);
+        assert_eq!(calc_transition_entropy(&sequences, 1, false), 0f32);
+        assert!(calc_me_entropy(&sequences, 4, 0.25, false) > 0f32);
+        let sequences = to_strings(vec!["0000", "1111"]);
+        assert_eq!(calc_transition_entropy(&sequences, 1, false), 0f32);
+        // every transition is equally likely
+        let sequences = to_strings(vec!["0011", "1100", "0110", "1001"]);
+        assert_approx_eq!(
+            calc_transition_entropy(&sequences, 1, false),
+            1f32,
+            1e-6
+        );
+        // filtered positions are skipped
+        let sequences = to_strings(vec!["01*1", "0101"]);
+        assert_eq!(calc_transition_entropy(&sequences, 1, false), 0f32);
+        // second order, the next state is determined by the previous two
+        let sequences = to_strings(vec!["001001", "010010", "100100"]);
+        assert_eq!(calc_transition_entropy(&sequences, 2, false), 0f32);
+        assert!(calc_transition_entropy(&sequences, 1, false) > 0f32);
+        // no usable transitions
+        let sequences = to_strings(vec!["0*0*", "*0*0"]);
+        assert_eq!(calc_transition_entropy(&sequences, 1, false), 0f32);
+    }
+
+    #[test]
+    fn test_miller_madow_correction() {
+        let to_strings = |xs: Vec<&str>| {
+            xs.into_iter().map(|x| x.to_string()).collect::<Vec<String>>()
+        };
+        let ln2 = std::f32::consts::LN_2;
+        // a single pattern has no correction
+        let sequences = to_strings(vec!["0000", "0000", "0000"]);
+        assert_eq!(calc_me_entropy(&sequences, 4, 0.25, true), 0f32);
+        // two patterns in eight reads, (2 - 1) / (2 * 8 * ln(2)) bits
+        let sequences = to_strings(vec![
+            "1111", "1111", "1111", "1111", "0000", "0000", "0000", "0000",
+        ]);
+        let expected = 1f32 + 1f32 / (16f32 * ln2);
+        assert_approx_eq!(calc_entropy(&sequences, 4, true), expected, 1e-6);
+        assert_approx_eq!(
+            calc_me_entropy(&sequences, 4, 0.25, true),
+            0.25 * expected,
+            1e-6
+        );
+        let per_read = calc_me_entropy_per_read(&sequences, 4, 0.25, true);
+        assert_approx_eq!(per_read.iter().sum::<f32>(), 0.25 * expected, 1e-6);
+        // 12 transitions, 4 observed transitions and 2 observed contexts
+        let sequences = to_strings(vec!["0011", "1100", "0110", "1001"]);
+        let expected = 1f32 + (3f32 - 1f32) / (24f32 * ln2);
+        assert_approx_eq!(
+            calc_transition_entropy(&sequences, 1, true),
+            expected,
+            1e-6
+        );
+        let per_read = calc_transition_entropy_per_read(&sequences, 1, true);
+        assert_approx_eq!(per_read.iter().sum::<f32>(), expected, 1e-6);
+    }
+
+    #[test]
+    fn test_calc_epipolymorphism() {
+        let strings = |xs: &[&str]| {
+            xs.iter().map(|x| x.to_string()).collect::<Vec<String>>()
+        };
+        let sequences = strings(&["0000", "0000", "0000", "0000"]);
+        assert_eq!(calc_epipolymorphism(&sequences, 4), 0f32);
+        let sequences = strings(&["1111", "1111", "0000", "0000"]);
+        assert_approx_eq!(calc_epipolymorphism(&sequences, 4), 0.5f32);
+        let sequences = strings(&["0000", "1111", "0101", "1010"]);
+        assert_approx_eq!(calc_epipolymorphism(&sequences, 4), 0.75f32);
+        // the read with a filtered position is split between 1111 and 1011
+        let sequences = strings(&["1*11", "1111", "1011", "1111"]);
+        let expected = 1f32 - (2.5f32 / 4f32).powi(2) - (1.5f32 / 4f32).powi(2);
+        assert_approx_eq!(calc_epipolymorphism(&sequences, 4), expected);
+        assert_eq!(calc_epipolymorphism(&[], 4), 0f32);
+
+        assert_eq!(
+            epiallele_counts(&sequences),
+            vec![
+                ("1111".to_string(), 2),
+                ("1*11".to_string(), 1),
+                ("1011".to_string(), 1)
+            ]
+        );
+    }
+
     #[test]
     #[should_panic]
     fn test_alphabet_info() {
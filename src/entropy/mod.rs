@@ -1,44 +1,260 @@
-use std::collections::{BTreeSet, HashMap, HashSet, VecDeque};
-use std::fs::File;
-use std::io::{BufRead, BufReader};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
+use std::io::BufRead;
 use std::ops::Range;
 use std::path::PathBuf;
 use std::sync::Arc;
 
 use anyhow::{anyhow, bail, Context};
+use clap::ValueEnum;
 use derive_new::new;
 use itertools::{Itertools, MinMaxResult};
 use log::{debug, info};
 use nom::character::complete::multispace1;
 use nom::IResult;
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::{Rng, SeedableRng};
 use rayon::prelude::*;
 use rust_htslib::bam::ext::BamRecordExtensions;
 use rust_htslib::bam::{self, FetchDefinition, Read};
+use rust_lapper::{Interval, Lapper};
 use rustc_hash::FxHashMap;
 
-use crate::entropy::methylation_entropy::calc_me_entropy;
+use crate::entropy::methylation_entropy::{
+    calc_epipolymorphism, calc_me_entropy, calc_me_entropy_per_read,
+    calc_transition_entropy, calc_transition_entropy_per_read,
+    epiallele_counts,
+};
 use crate::errs::{MkError, MkResult};
 use crate::mod_bam::{BaseModCall, ModBaseInfo};
 use crate::mod_base_code::{DnaBase, ModCodeRepr};
 use crate::motifs::motif_bed::RegexMotif;
+use crate::parsing_utils::open_text_input;
+use crate::pileup::cpg_islands::{count_cpgs, find_cpg_islands};
+use crate::position_filter::StrandedPositionFilter;
 use crate::read_ids_to_base_mod_probs::{PositionModCalls, ReadBaseModProfile};
 use crate::reads_sampler::sampling_schedule::ReferenceSequencesLookup;
 use crate::threshold_mod_caller::MultipleThresholdModCaller;
 use crate::thresholds::percentile_linear_interp;
-use crate::util::{record_is_not_primary, ReferenceRecord, Strand};
+use crate::util::{
+    get_indexed_reader, record_has_tag_value, record_is_not_primary,
+    AlignmentIdentityFilter, ReferenceRecord, SamTag, Strand, StrandRule,
+};
 
+mod bedmethyl;
 mod methylation_entropy;
 pub mod subcommand;
+mod summary;
+mod tracks;
 mod writers;
 
 type BaseAndPosition = (DnaBase, u64);
 
+/// How the methylation entropy of a window is scaled.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, ValueEnum)]
+#[allow(non_camel_case_types)]
+pub(super) enum EntropyNorm {
+    /// Entropy in bits, not scaled.
+    none,
+    /// Divide by the number of positions in the window, bits per position.
+    window,
+    /// Divide by log2 of the number of reads, the largest possible entropy
+    /// with that many reads, so that 1 means every read has a different
+    /// pattern.
+    reads,
+}
+
+/// Unit of the `--step` between the starts of consecutive sliding windows.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, ValueEnum)]
+#[allow(non_camel_case_types)]
+pub(super) enum StepUnit {
+    /// Motif positions, the next window starts this many motif positions
+    /// after the first position of the previous window.
+    positions,
+    /// Base pairs, the next window starts at the first motif position at
+    /// least this many base pairs after the start of the previous window.
+    bp,
+}
+
+/// Bootstrap confidence interval for the entropy of each window, the read
+/// patterns are resampled with replacement `num_resamples` times.
+#[derive(Copy, Clone, Debug, new)]
+pub(super) struct Bootstrap {
+    num_resamples: usize,
+    /// Fraction of the resampled entropies inside the interval, e.g. 0.95.
+    confidence_level: f32,
+}
+
+/// How the entropy of the encoded patterns of a window is calculated.
+/// Entropy of a window compared with the entropy expected when the calls at
+/// each position are shuffled among the reads, which keeps the level of
+/// modification at each position but breaks up the reads' patterns.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub(super) struct ShuffledNull {
+    /// Mean entropy of the shuffled patterns.
+    mean_entropy: f32,
+    /// (observed - mean) / standard deviation of the shuffled entropies,
+    /// `None` when every shuffle has the same entropy.
+    z_score: Option<f32>,
+}
+
+#[derive(Copy, Clone, Debug, new)]
+pub(super) struct EntropyEstimator {
+    /// Calculate the transition entropy with this order instead of the
+    /// methylation entropy, the result is always in bits per position.
+    markov_order: Option<usize>,
+    norm: EntropyNorm,
+    /// Add the Miller-Madow small-sample bias correction.
+    miller_madow: bool,
+    #[new(default)]
+    bootstrap: Option<Bootstrap>,
+    /// Number of times to shuffle the calls among reads for the null
+    /// entropy.
+    #[new(default)]
+    shuffle_null: Option<usize>,
+}
+
+impl EntropyEstimator {
+    pub(super) fn with_bootstrap(self, bootstrap: Bootstrap) -> Self {
+        Self { bootstrap: Some(bootstrap), ..self }
+    }
+
+    pub(super) fn with_shuffle_null(self, num_shuffles: usize) -> Self {
+        Self { shuffle_null: Some(num_shuffles), ..self }
+    }
+
+    fn constant(&self, window_size: usize, num_reads: usize) -> f32 {
+        match self.norm {
+            EntropyNorm::none => 1f32,
+            EntropyNorm::window => 1f32 / window_size as f32,
+            EntropyNorm::reads if num_reads > 1 => {
+                1f32 / (num_reads as f32).log2()
+            }
+            // a single read always has zero entropy
+            EntropyNorm::reads => 1f32,
+        }
+    }
+
+    fn entropy(&self, patterns: &[String], window_size: usize) -> f32 {
+        match self.markov_order {
+            Some(order) => {
+                calc_transition_entropy(patterns, order, self.miller_madow)
+            }
+            None => calc_me_entropy(
+                patterns,
+                window_size,
+                self.constant(window_size, patterns.len()),
+                self.miller_madow,
+            ),
+        }
+    }
+
+    /// Lower and upper bounds of the bootstrap confidence interval of
+    /// [`Self::entropy`], `None` when not bootstrapping. The random number
+    /// generator is seeded from the window so that the interval doesn't
+    /// change from run to run.
+    fn confidence_interval(
+        &self,
+        patterns: &[String],
+        window_size: usize,
+        seed: u64,
+    ) -> Option<(f32, f32)> {
+        let bootstrap = self.bootstrap?;
+        if patterns.is_empty() {
+            return None;
+        }
+        let mut rng = StdRng::seed_from_u64(seed);
+        // a resample where every read is filtered at a position has no
+        // calls to fill in that position with, these are skipped
+        let has_calls = |resampled: &[String]| {
+            (0..window_size).all(|i| {
+                resampled.iter().any(|p| p.as_bytes().get(i) != Some(&b'*'))
+            })
+        };
+        let mut entropies = (0..bootstrap.num_resamples)
+            .filter_map(|_| {
+                let resampled = (0..patterns.len())
+                    .map(|_| patterns[rng.gen_range(0..patterns.len())].clone())
+                    .collect::<Vec<String>>();
+                has_calls(&resampled)
+                    .then(|| self.entropy(&resampled, window_size))
+            })
+            .collect::<Vec<f32>>();
+        entropies.sort_by(|a, b| a.total_cmp(b));
+        let alpha = (1f32 - bootstrap.confidence_level) / 2f32;
+        let lower = percentile_linear_interp(&entropies, alpha).ok()?;
+        let upper = percentile_linear_interp(&entropies, 1f32 - alpha).ok()?;
+        Some((lower, upper))
+    }
+
+    /// Compare `observed` with the entropy of the patterns after shuffling
+    /// the calls at each position among the reads, `None` when not
+    /// calculating the null. Seeded from the window like
+    /// [`Self::confidence_interval`].
+    fn shuffled_null(
+        &self,
+        patterns: &[String],
+        window_size: usize,
+        observed: f32,
+        seed: u64,
+    ) -> Option<ShuffledNull> {
+        let num_shuffles = self.shuffle_null?;
+        if patterns.is_empty() {
+            return None;
+        }
+        let mut rng = StdRng::seed_from_u64(seed);
+        let entropies = (0..num_shuffles)
+            .map(|_| {
+                let shuffled =
+                    shuffle_patterns(patterns, window_size, &mut rng);
+                self.entropy(&shuffled, window_size)
+            })
+            .collect::<Vec<f32>>();
+        let n = entropies.len() as f32;
+        let mean_entropy = entropies.iter().sum::<f32>() / n;
+        let var =
+            entropies.iter().map(|e| (e - mean_entropy).powi(2)).sum::<f32>()
+                / (n - 1f32);
+        let sd = var.sqrt();
+        let z_score =
+            (sd > f32::EPSILON).then(|| (observed - mean_entropy) / sd);
+        Some(ShuffledNull { mean_entropy, z_score })
+    }
+
+    /// Contribution of each pattern to [`Self::entropy`].
+    fn read_entropies(
+        &self,
+        patterns: &[String],
+        window_size: usize,
+    ) -> Vec<f32> {
+        match self.markov_order {
+            Some(order) => calc_transition_entropy_per_read(
+                patterns,
+                order,
+                self.miller_madow,
+            ),
+            None => calc_me_entropy_per_read(
+                patterns,
+                window_size,
+                self.constant(window_size, patterns.len()),
+                self.miller_madow,
+            ),
+        }
+    }
+}
+
 #[derive(Debug)]
 pub(super) enum GenomeWindow {
     CombineStrands {
         interval: Range<u64>,
         neg_to_pos_positions: FxHashMap<BaseAndPosition, BaseAndPosition>,
         read_patterns: Vec<Vec<BaseModCall>>,
+        // names of the reads in `read_patterns`, only kept for read-level
+        // output
+        read_names: Vec<String>,
+        // number of reads offered to `read_patterns`, more than its length
+        // when the reads are subsampled
+        reads_seen: usize,
         position_valid_coverages: Vec<u32>,
     },
     Stranded {
@@ -50,11 +266,40 @@ pub(super) enum GenomeWindow {
         neg_positions: Option<Vec<BaseAndPosition>>,
         pos_read_patterns: Vec<Vec<BaseModCall>>,
         neg_read_patterns: Vec<Vec<BaseModCall>>,
+        pos_read_names: Vec<String>,
+        neg_read_names: Vec<String>,
+        pos_reads_seen: usize,
+        neg_reads_seen: usize,
         pos_position_valid_coverages: Vec<u32>,
         neg_position_valid_coverages: Vec<u32>,
     },
 }
 
+/// Keeps a uniform random sample of at most `max_reads` read patterns in each
+/// window (reservoir sampling) so that memory is bounded in very deep regions.
+/// The random number generator is seeded from the window so that the output
+/// doesn't change from run to run.
+pub(super) struct ReadReservoir {
+    max_reads: usize,
+    rng: StdRng,
+}
+
+impl ReadReservoir {
+    fn new(max_reads: usize, seed: u64) -> Self {
+        Self { max_reads, rng: StdRng::seed_from_u64(seed) }
+    }
+
+    /// Index to put the `n_seen`th read at, `None` means the read is dropped.
+    fn slot(&mut self, n_seen: usize) -> Option<usize> {
+        if n_seen <= self.max_reads {
+            Some(n_seen - 1)
+        } else {
+            let j = self.rng.gen_range(0..n_seen);
+            (j < self.max_reads).then_some(j)
+        }
+    }
+}
+
 impl GenomeWindow {
     fn new_combine_strands(
         interval: Range<u64>,
@@ -66,6 +311,8 @@ impl GenomeWindow {
             interval,
             neg_to_pos_positions,
             read_patterns: Vec::new(),
+            read_names: Vec::new(),
+            reads_seen: 0,
             position_valid_coverages,
         }
     }
@@ -122,6 +369,10 @@ impl GenomeWindow {
             neg_positions,
             pos_read_patterns: Vec::new(),
             neg_read_patterns: Vec::new(),
+            pos_read_names: Vec::new(),
+            neg_read_names: Vec::new(),
+            pos_reads_seen: 0,
+            neg_reads_seen: 0,
             pos_position_valid_coverages,
             neg_position_valid_coverages,
         }
@@ -160,17 +411,83 @@ impl GenomeWindow {
         };
     }
 
-    fn add_pattern(&mut self, strand: &Strand, pattern: Vec<BaseModCall>) {
+    fn add_pattern(
+        &mut self,
+        strand: &Strand,
+        pattern: Vec<BaseModCall>,
+        read_name: Option<&str>,
+        reservoir: Option<&mut ReadReservoir>,
+    ) {
+        let (read_patterns, read_names, reads_seen) = match self {
+            Self::Stranded {
+                pos_read_patterns,
+                neg_read_patterns,
+                pos_read_names,
+                neg_read_names,
+                pos_reads_seen,
+                neg_reads_seen,
+                ..
+            } => match strand {
+                Strand::Positive => {
+                    (pos_read_patterns, pos_read_names, pos_reads_seen)
+                }
+                Strand::Negative => {
+                    (neg_read_patterns, neg_read_names, neg_reads_seen)
+                }
+            },
+            Self::CombineStrands {
+                read_patterns,
+                read_names,
+                reads_seen,
+                ..
+            } => (read_patterns, read_names, reads_seen),
+        };
+        *reads_seen += 1;
+        let slot = match reservoir {
+            Some(reservoir) => match reservoir.slot(*reads_seen) {
+                Some(slot) => slot,
+                None => return,
+            },
+            None => read_patterns.len(),
+        };
+        if slot == read_patterns.len() {
+            read_patterns.push(pattern);
+            if let Some(name) = read_name {
+                read_names.push(name.to_string());
+            }
+        } else {
+            read_patterns[slot] = pattern;
+            if let Some(name) = read_name {
+                read_names[slot] = name.to_string();
+            }
+        }
+    }
+
+    /// Number of reads that covered the window on a strand, including any
+    /// that were dropped when subsampling.
+    fn reads_seen(&self, strand: &Strand) -> usize {
         match self {
-            Self::Stranded { pos_read_patterns, neg_read_patterns, .. } => {
+            Self::Stranded { pos_reads_seen, neg_reads_seen, .. } => {
                 match strand {
-                    Strand::Positive => pos_read_patterns.push(pattern),
-                    Strand::Negative => neg_read_patterns.push(pattern),
+                    Strand::Positive => *pos_reads_seen,
+                    Strand::Negative => *neg_reads_seen,
                 }
             }
-            Self::CombineStrands { read_patterns, .. } => {
-                read_patterns.push(pattern);
+            Self::CombineStrands { reads_seen, .. } => *reads_seen,
+        }
+    }
+
+    /// Names of the reads added to the patterns for a strand, empty unless
+    /// read names were kept.
+    fn read_names(&self, strand: &Strand) -> &[String] {
+        match self {
+            Self::Stranded { pos_read_names, neg_read_names, .. } => {
+                match strand {
+                    Strand::Positive => pos_read_names,
+                    Strand::Negative => neg_read_names,
+                }
             }
+            Self::CombineStrands { read_names, .. } => read_names,
         }
     }
 
@@ -218,29 +535,17 @@ impl GenomeWindow {
 
     fn add_read_to_patterns(
         &mut self,
-        ref_pos_to_basemod_call: &FxHashMap<BaseAndPosition, BaseModCall>,
-        reference_start: i64,
-        reference_end: i64,
-        strand: Strand,
+        message: &Message,
         max_filtered_positions: usize,
+        keep_read_name: bool,
+        reservoir: Option<&mut ReadReservoir>,
     ) {
+        let ref_pos_to_basemod_call = &message.mod_calls;
+        let strand = message.strand;
+        let read_name = keep_read_name.then_some(message.name.as_str());
         // check that the read fully covers the interval
-        let reference_start = if reference_start >= 0 {
-            Some(reference_start as u64)
-        } else {
-            None
-        };
-        let reference_end = if reference_start
-            .map(|x| reference_end > x as i64)
-            .unwrap_or(false)
-        {
-            Some(reference_end as u64)
-        } else {
-            None
-        };
-
-        let overlaps = reference_start
-            .and_then(|s| reference_end.map(|t| (s, t)))
+        let overlaps = message
+            .reference_span()
             .map(|(s, t)| match (self.start(&strand), self.end(&strand)) {
                 (Some(wind_start), Some(wind_end)) => {
                     s <= wind_start && t >= wind_end
@@ -325,7 +630,7 @@ impl GenomeWindow {
                 _ => self.inc_coverage(i, &strand),
             }
         }
-        self.add_pattern(&strand, pattern);
+        self.add_pattern(&strand, pattern, read_name, reservoir);
     }
 
     fn get_mod_code_lookup(&self) -> FxHashMap<ModCodeRepr, char> {
@@ -425,6 +730,16 @@ impl GenomeWindow {
                     chrom_id,
                     start: self.start(&strand).unwrap(),
                     end: self.end(&strand).unwrap(),
+                    min_coverage: position_valid_coverages
+                        .iter()
+                        .min()
+                        .copied()
+                        .unwrap_or(0),
+                    max_coverage: position_valid_coverages
+                        .iter()
+                        .max()
+                        .copied()
+                        .unwrap_or(0),
                 };
                 return Err(err);
             }
@@ -435,11 +750,43 @@ impl GenomeWindow {
         &self,
         chrom_id: u32,
         min_valid_coverage: u32,
+        estimator: EntropyEstimator,
+        report_epialleles: bool,
+        per_mod_codes: &[ModCodeRepr],
     ) -> WindowEntropy {
         let window_size = self.size();
-        let constant = 1f32 / window_size as f32; // todo make this configurable
+        let calc_entropy =
+            |patterns: &[String]| estimator.entropy(patterns, window_size);
+        let calc_read_entropies =
+            |patterns: Vec<String>, read_names: &[String]| {
+                if read_names.is_empty() {
+                    return Vec::new();
+                }
+                debug_assert_eq!(patterns.len(), read_names.len());
+                let contributions =
+                    estimator.read_entropies(&patterns, window_size);
+                read_names
+                    .iter()
+                    .zip(patterns)
+                    .zip(contributions)
+                    .map(|((read_id, pattern), entropy)| ReadEntropy {
+                        read_id: read_id.to_string(),
+                        pattern,
+                        entropy,
+                    })
+                    .collect::<Vec<ReadEntropy>>()
+            };
 
         let mod_code_lookup = self.get_mod_code_lookup();
+        let calc_mod_code_entropies = |patterns: &[String]| {
+            per_mod_codes
+                .iter()
+                .map(|code| {
+                    let encoded = mod_code_lookup.get(code).copied();
+                    calc_entropy(&binarize_patterns(patterns, encoded))
+                })
+                .collect::<Vec<f32>>()
+        };
         let positive_encoded_patterns = match &self {
             Self::CombineStrands {
                 read_patterns,
@@ -520,23 +867,79 @@ impl GenomeWindow {
 
         let pos_me_entropy = positive_encoded_patterns.map(|maybe_patterns| {
             maybe_patterns.map(|patterns| {
-                let me_entropy =
-                    calc_me_entropy(&patterns, window_size, constant);
+                let me_entropy = calc_entropy(&patterns);
                 let num_reads = patterns.len();
                 let interval = self.start(&Strand::Positive).unwrap()
                     ..self.end(&Strand::Positive).unwrap().saturating_add(1);
-                MethylationEntropy::new(me_entropy, num_reads, interval)
+                let entropy_ci = estimator.confidence_interval(
+                    &patterns,
+                    window_size,
+                    interval.start,
+                );
+                let shuffled_null = estimator.shuffled_null(
+                    &patterns,
+                    window_size,
+                    me_entropy,
+                    interval.start,
+                );
+                let mut me_entropy =
+                    MethylationEntropy::new(me_entropy, num_reads, interval);
+                me_entropy.entropy_ci = entropy_ci;
+                me_entropy.shuffled_null = shuffled_null;
+                me_entropy.mod_code_entropies =
+                    calc_mod_code_entropies(&patterns);
+                me_entropy.num_reads_dropped = self
+                    .reads_seen(&Strand::Positive)
+                    .saturating_sub(num_reads);
+                me_entropy.epipolymorphism =
+                    calc_epipolymorphism(&patterns, window_size);
+                if report_epialleles {
+                    me_entropy.epialleles = epiallele_counts(&patterns);
+                }
+                me_entropy.read_entropies = calc_read_entropies(
+                    patterns,
+                    self.read_names(&Strand::Positive),
+                );
+                me_entropy
             })
         });
 
         let neg_me_entropy = negative_patterns.map(|maybe_patterns| {
             maybe_patterns.map(|patterns| {
-                let me_entropy =
-                    calc_me_entropy(&patterns, window_size, constant);
+                let me_entropy = calc_entropy(&patterns);
                 let num_reads = patterns.len();
                 let interval = self.start(&Strand::Negative).unwrap()
                     ..self.end(&Strand::Negative).unwrap().saturating_add(1);
-                MethylationEntropy::new(me_entropy, num_reads, interval)
+                let entropy_ci = estimator.confidence_interval(
+                    &patterns,
+                    window_size,
+                    interval.start,
+                );
+                let shuffled_null = estimator.shuffled_null(
+                    &patterns,
+                    window_size,
+                    me_entropy,
+                    interval.start,
+                );
+                let mut me_entropy =
+                    MethylationEntropy::new(me_entropy, num_reads, interval);
+                me_entropy.entropy_ci = entropy_ci;
+                me_entropy.shuffled_null = shuffled_null;
+                me_entropy.mod_code_entropies =
+                    calc_mod_code_entropies(&patterns);
+                me_entropy.num_reads_dropped = self
+                    .reads_seen(&Strand::Negative)
+                    .saturating_sub(num_reads);
+                me_entropy.epipolymorphism =
+                    calc_epipolymorphism(&patterns, window_size);
+                if report_epialleles {
+                    me_entropy.epialleles = epiallele_counts(&patterns);
+                }
+                me_entropy.read_entropies = calc_read_entropies(
+                    patterns,
+                    self.read_names(&Strand::Negative),
+                );
+                me_entropy
             })
         });
 
@@ -560,6 +963,9 @@ pub(super) struct GenomeWindows {
     chrom_id: u32,
     entropy_windows: Vec<GenomeWindow>,
     region_name: Option<String>,
+    /// Interval to report for each window instead of the span of its
+    /// positions, used when the windows are given in a BED file.
+    window_intervals: Option<Vec<Range<u64>>>,
 }
 
 pub(super) enum EntropyCalculation {
@@ -567,6 +973,31 @@ pub(super) enum EntropyCalculation {
     Region(RegionEntropy),
 }
 
+impl EntropyCalculation {
+    /// Number of successful windows that were subsampled and the total
+    /// number of reads that were dropped from them.
+    pub(super) fn subsampled_counts(&self) -> (usize, usize) {
+        let window_entropies = match self {
+            Self::Windows(window_entropies) => window_entropies,
+            Self::Region(region_entropy) => &region_entropy.window_entropies,
+        };
+        window_entropies
+            .iter()
+            .flat_map(|w| {
+                [w.pos_me_entropy.as_ref(), w.neg_me_entropy.as_ref()]
+            })
+            .filter_map(|me_entropy| match me_entropy {
+                Some(Ok(me_entropy)) if me_entropy.num_reads_dropped > 0 => {
+                    Some(me_entropy.num_reads_dropped)
+                }
+                _ => None,
+            })
+            .fold((0, 0), |(windows, reads), dropped| {
+                (windows + 1, reads + dropped)
+            })
+    }
+}
+
 impl GenomeWindows {
     fn new(
         chrom_id: u32,
@@ -574,10 +1005,32 @@ impl GenomeWindows {
         region_name: Option<String>,
     ) -> Self {
         assert!(!entropy_windows.is_empty());
-        Self { chrom_id, entropy_windows, region_name }
+        Self { chrom_id, entropy_windows, region_name, window_intervals: None }
+    }
+
+    fn with_window_intervals(self, window_intervals: Vec<Range<u64>>) -> Self {
+        assert_eq!(window_intervals.len(), self.entropy_windows.len());
+        Self { window_intervals: Some(window_intervals), ..self }
     }
 
     fn get_range(&self) -> Range<u64> {
+        if self.window_intervals.is_some() {
+            // windows from a BED file aren't necessarily sorted or the same
+            // size, so the last window doesn't always end last
+            let start = self
+                .entropy_windows
+                .iter()
+                .map(|w| w.leftmost())
+                .min()
+                .expect("self.entropy_windows should not be empty");
+            let end = self
+                .entropy_windows
+                .iter()
+                .map(|w| w.rightmost())
+                .max()
+                .expect("self.entropy_windows should not be empty");
+            return start..end;
+        }
         // these expects are checked in a few places, make them .unwrap()s
         let start = self
             .entropy_windows
@@ -604,29 +1057,63 @@ impl GenomeWindows {
         self,
         chrom_id: u32,
         min_coverage: u32,
+        estimator: EntropyEstimator,
+        report_epialleles: bool,
+        per_mod_codes: &[ModCodeRepr],
+        quantiles: &[f32],
     ) -> EntropyCalculation {
         // to appease the bC we have to get the interval
         // here, but it's only used if we're summarizing a region
         let interval = self.get_range();
-        let window_entropies = self
+        let mut window_entropies = self
             .entropy_windows
             .par_iter()
-            .map(|ew| ew.into_entropy(chrom_id, min_coverage))
+            .map(|ew| {
+                ew.into_entropy(
+                    chrom_id,
+                    min_coverage,
+                    estimator,
+                    report_epialleles,
+                    per_mod_codes,
+                )
+            })
             .collect::<Vec<_>>();
+        if let Some(window_intervals) = self.window_intervals.as_ref() {
+            for (window_entropy, interval) in
+                window_entropies.iter_mut().zip(window_intervals)
+            {
+                for me_entropy in [
+                    window_entropy.pos_me_entropy.as_mut(),
+                    window_entropy.neg_me_entropy.as_mut(),
+                ]
+                .into_iter()
+                .flatten()
+                .filter_map(|res| res.as_mut().ok())
+                {
+                    me_entropy.interval = interval.clone();
+                }
+            }
+        }
         let chrom_id = self.chrom_id;
         if let Some(region_name) = self.region_name {
             let mut pos_entropies = Vec::with_capacity(window_entropies.len());
             let mut pos_num_reads = Vec::with_capacity(window_entropies.len());
             let mut pos_num_fails = 0usize;
+            let mut pos_mod_code_entropies =
+                Vec::with_capacity(window_entropies.len());
             let mut neg_entropies = Vec::with_capacity(window_entropies.len());
             let mut neg_num_reads = Vec::with_capacity(window_entropies.len());
             let mut neg_num_fails = 0usize;
+            let mut neg_mod_code_entropies =
+                Vec::with_capacity(window_entropies.len());
 
             for window_entropy in window_entropies.iter() {
                 match window_entropy.pos_me_entropy.as_ref() {
                     Some(Ok(me_entropy)) => {
                         pos_entropies.push(me_entropy.me_entropy);
                         pos_num_reads.push(me_entropy.num_reads);
+                        pos_mod_code_entropies
+                            .push(me_entropy.mod_code_entropies.as_slice());
                     }
                     Some(Err(_e)) => {
                         pos_num_fails += 1;
@@ -637,6 +1124,8 @@ impl GenomeWindows {
                     Some(Ok(me_entropy)) => {
                         neg_entropies.push(me_entropy.me_entropy);
                         neg_num_reads.push(me_entropy.num_reads);
+                        neg_mod_code_entropies
+                            .push(me_entropy.mod_code_entropies.as_slice());
                     }
                     Some(Err(_e)) => {
                         neg_num_fails += 1;
@@ -646,15 +1135,29 @@ impl GenomeWindows {
                 }
             }
 
-            // todo make sure the semantics here are what I want,
-            //  should pos_entropy_stats be an Option?
-            let pos_entropy_stats = DescriptiveStats::new(
-                &pos_entropies,
-                &pos_num_reads,
-                pos_num_fails,
-                chrom_id,
-                &interval,
-            );
+            // if pos_entropies is empty and there are no fails, we never saw
+            // any positive strand me entropies, e.g. with `--strand negative`
+            let pos_entropy_stats =
+                if pos_entropies.is_empty() && pos_num_fails == 0 {
+                    None
+                } else {
+                    Some(
+                        DescriptiveStats::new(
+                            &pos_entropies,
+                            &pos_num_reads,
+                            pos_num_fails,
+                            quantiles,
+                            chrom_id,
+                            &interval,
+                        )
+                        .map(|stats| {
+                            stats.with_mod_code_entropies(
+                                &pos_mod_code_entropies,
+                                per_mod_codes.len(),
+                            )
+                        }),
+                    )
+                };
             // if neg_entropies is empty and there are no fails, we never saw
             // any negative strand me entropies
             let neg_entropy_stats = if neg_entropies.is_empty()
@@ -668,13 +1171,22 @@ impl GenomeWindows {
             } else {
                 // this will fail correctly if there are neg_entropies is empty
                 // but there are fails
-                Some(DescriptiveStats::new(
-                    &neg_entropies,
-                    &neg_num_reads,
-                    neg_num_fails,
-                    chrom_id,
-                    &interval,
-                ))
+                Some(
+                    DescriptiveStats::new(
+                        &neg_entropies,
+                        &neg_num_reads,
+                        neg_num_fails,
+                        quantiles,
+                        chrom_id,
+                        &interval,
+                    )
+                    .map(|stats| {
+                        stats.with_mod_code_entropies(
+                            &neg_mod_code_entropies,
+                            per_mod_codes.len(),
+                        )
+                    }),
+                )
             };
 
             let region_entropy = RegionEntropy::new(
@@ -700,10 +1212,199 @@ struct MotifHit {
     base: DnaBase,
 }
 
+/// Regions (or windows) read from a BED file or found in the reference.
+type BedRegions = Box<dyn Iterator<Item = anyhow::Result<BedRegion>> + Send>;
+
+/// Read the regions in a BED file as they're needed, also returns the total
+/// length of the regions from a first pass over the file.
+fn stream_bed_regions(bed_fp: &PathBuf) -> anyhow::Result<(usize, BedRegions)> {
+    // only the lengths are kept from this first pass, for the progress bar,
+    // the regions are read again as they're needed
+    let total_length = open_text_input(bed_fp)
+        .with_context(|| format!("failed to load regions at {bed_fp:?}"))?
+        .lines()
+        .map_while(Result::ok)
+        .filter_map(|l| BedRegion::parse_str(&l).ok())
+        .map(|bed_region| bed_region.length())
+        .sum::<usize>();
+    // the BED reader can't be sent to the thread that consumes the windows,
+    // so the regions are read on their own thread and handed over on a
+    // bounded channel
+    let (snd, rcv) = crossbeam::channel::bounded(REGIONS_PER_FETCH);
+    let bed_fp = bed_fp.clone();
+    std::thread::spawn(move || {
+        let reader = match open_text_input(&bed_fp) {
+            Ok(reader) => reader,
+            Err(e) => {
+                let _ = snd.send(Err(e));
+                return;
+            }
+        };
+        let bed_regions = reader
+            .lines()
+            // change the lines into Errors
+            .map(|r| r.map_err(|e| anyhow!("failed to read line, {e}")))
+            // Parse the lines
+            .map(|r| r.and_then(|l| BedRegion::parse_str(&l)));
+        for bed_region in bed_regions {
+            if snd.send(bed_region).is_err() {
+                break;
+            }
+        }
+    });
+    Ok((total_length, Box::new(rcv.into_iter())))
+}
+
+/// Number of regions whose reference subsequences are fetched at a time,
+/// bounds the memory used by regions waiting to be windowed.
+const REGIONS_PER_FETCH: usize = 1_000;
+
+/// Regions that haven't had their reference subsequence fetched yet.
+struct PendingRegions {
+    bed_regions: BedRegions,
+    reference_sequences_lookup: Arc<ReferenceSequencesLookup>,
+    /// Total length of the regions still in `bed_regions`.
+    remaining_length: usize,
+    failures: HashMap<String, usize>,
+    exhausted: bool,
+}
+
+impl PendingRegions {
+    /// Fetch the subsequences of up to `n` valid regions, invalid regions are
+    /// tallied by reason and logged once all of the regions are read.
+    fn fetch(
+        &mut self,
+        n: usize,
+    ) -> Vec<(ReferenceRecord, String, StrandRule, Vec<char>)> {
+        let mut fetched = Vec::with_capacity(n);
+        while fetched.len() < n && !self.exhausted {
+            let Some(res) = self.bed_regions.next() else {
+                self.exhausted = true;
+                self.log_failures();
+                break;
+            };
+            let res = res.and_then(|bed_region| {
+                self.remaining_length =
+                    self.remaining_length.saturating_sub(bed_region.length());
+                self.reference_sequences_lookup
+                    .get_subsequence_by_name(
+                        bed_region.chrom.as_str(),
+                        bed_region.interval.clone(),
+                    )
+                    .map(|seq| (bed_region, seq))
+            });
+            match res {
+                Ok((bed_region, seq)) => {
+                    let tid = self
+                        .reference_sequences_lookup
+                        .name_to_chrom_id(bed_region.chrom.as_str())
+                        .unwrap();
+                    let start = bed_region.interval.start as u32;
+                    let length = bed_region.length() as u32;
+                    let reference_record = ReferenceRecord::new(
+                        tid,
+                        start,
+                        length,
+                        bed_region.chrom,
+                    );
+                    fetched.push((
+                        reference_record,
+                        bed_region.name,
+                        bed_region.strand,
+                        seq,
+                    ));
+                }
+                Err(e) => {
+                    *self.failures.entry(e.to_string()).or_insert(0) += 1;
+                }
+            }
+        }
+        fetched
+    }
+
+    fn log_failures(&self) {
+        if !self.failures.is_empty() {
+            debug!("failure reasons while parsing regions BED file");
+            for (cause, count) in
+                self.failures.iter().sorted_by(|(_, a), (_, b)| a.cmp(b))
+            {
+                debug!("\t {cause}: {count}")
+            }
+        }
+    }
+}
+
+/// Name and strand of a region.
+type RegionLabel = (String, StrandRule);
+
+/// Sequences (whole contigs or the subsequences of regions) that haven't been
+/// windowed yet. Region subsequences are fetched [`REGIONS_PER_FETCH`] at a
+/// time as the queue empties so that memory doesn't grow with the number of
+/// regions.
+struct SequenceQueue {
+    work_queue: VecDeque<(ReferenceRecord, Vec<char>)>,
+    /// name and strand of each region in `work_queue`
+    region_names: VecDeque<RegionLabel>,
+    pending_regions: Option<PendingRegions>,
+}
+
+impl SequenceQueue {
+    fn new(work_queue: VecDeque<(ReferenceRecord, Vec<char>)>) -> Self {
+        Self {
+            work_queue,
+            region_names: VecDeque::new(),
+            pending_regions: None,
+        }
+    }
+
+    fn new_with_regions(pending_regions: PendingRegions) -> Self {
+        Self {
+            work_queue: VecDeque::new(),
+            region_names: VecDeque::new(),
+            pending_regions: Some(pending_regions),
+        }
+    }
+
+    /// Fetch the next regions if the queue is empty, returns false if there
+    /// are no sequences left.
+    fn fill(&mut self) -> bool {
+        if self.work_queue.is_empty() {
+            if let Some(pending) = self.pending_regions.as_mut() {
+                for (record, name, strand, seq) in
+                    pending.fetch(REGIONS_PER_FETCH)
+                {
+                    self.work_queue.push_back((record, seq));
+                    self.region_names.push_back((name, strand));
+                }
+            }
+        }
+        !self.work_queue.is_empty()
+    }
+
+    /// The next sequence and, when using regions, the region name and strand.
+    fn pop_front(
+        &mut self,
+    ) -> Option<(ReferenceRecord, Vec<char>, Option<RegionLabel>)> {
+        if !self.fill() {
+            return None;
+        }
+        let (record, seq) = self.work_queue.pop_front()?;
+        Some((record, seq, self.region_names.pop_front()))
+    }
+
+    fn total_length(&self) -> usize {
+        self.work_queue.iter().map(|(_, s)| s.len()).sum::<usize>()
+            + self
+                .pending_regions
+                .as_ref()
+                .map(|pending| pending.remaining_length)
+                .unwrap_or(0)
+    }
+}
+
 struct SlidingWindows {
     motifs: Vec<RegexMotif>,
-    work_queue: VecDeque<(ReferenceRecord, Vec<char>)>,
-    region_names: VecDeque<String>,
+    sequences: SequenceQueue,
     window_size: usize,
     num_positions: usize,
     batch_size: usize,
@@ -711,16 +1412,30 @@ struct SlidingWindows {
     curr_contig: ReferenceRecord,
     curr_seq: Vec<char>,
     curr_region_name: Option<String>,
+    /// strand of the current region, windows in a stranded region only use
+    /// motif positions on that strand (unless combining strands)
+    curr_region_strand: StrandRule,
     combine_strands: bool,
     /// the longest motif length, so we find motifs that are in the window, but
     /// reach outside the window
     motif_search_adj: usize,
+    /// distance between the starts of consecutive windows
+    step: usize,
+    step_unit: StepUnit,
+    /// motif positions still to be skipped before the next window can
+    /// start, when stepping by more than 1 position
+    positions_to_skip: usize,
+    /// windows overlapping these intervals are skipped
+    exclude: Option<StrandedPositionFilter<()>>,
+    n_excluded: usize,
+    /// only motif positions on this strand are used in windows
+    strand: StrandRule,
     done: bool,
 }
 
 impl SlidingWindows {
     fn new_with_regions(
-        reference_sequences_lookup: ReferenceSequencesLookup,
+        reference_sequences_lookup: Arc<ReferenceSequencesLookup>,
         regions_bed_fp: &PathBuf,
         motifs: Vec<RegexMotif>,
         combine_strands: bool,
@@ -728,134 +1443,94 @@ impl SlidingWindows {
         window_size: usize,
         batch_size: usize,
     ) -> anyhow::Result<Self> {
-        let regions_iter =
-            BufReader::new(File::open(regions_bed_fp).with_context(|| {
-                format!("failed to load regions at {regions_bed_fp:?}")
-            })?)
-            .lines()
-            // change the lines into Errors
-            .map(|r| r.map_err(|e| anyhow!("failed to read line, {e}")))
-            // Parse the lines
-            .map(|r| r.and_then(|l| BedRegion::parse_str(&l)))
-            // grab the subsequences, also collect up the errors for invalid BED
-            // lines
-            .map(|r| {
-                r.and_then(|bed_region| {
-                    let start = bed_region.interval.start;
-                    let end = bed_region.interval.end;
-                    let interval = start..end;
-                    reference_sequences_lookup
-                        .get_subsequence_by_name(
-                            bed_region.chrom.as_str(),
-                            interval,
+        let (total_length, bed_regions) = stream_bed_regions(regions_bed_fp)?;
+        Self::from_bed_regions(
+            reference_sequences_lookup,
+            bed_regions,
+            total_length,
+            motifs,
+            combine_strands,
+            num_positions,
+            window_size,
+            batch_size,
+        )
+    }
+
+    /// Use the CpG islands found in the reference sequences as the regions.
+    fn new_with_cpg_islands(
+        reference_sequences_lookup: Arc<ReferenceSequencesLookup>,
+        motifs: Vec<RegexMotif>,
+        combine_strands: bool,
+        num_positions: usize,
+        window_size: usize,
+        batch_size: usize,
+    ) -> anyhow::Result<Self> {
+        let bed_regions = reference_sequences_lookup
+            .iter_reference_sequences()
+            .flat_map(|(chrom, seq)| {
+                let seq = seq.iter().map(|b| *b as u8).collect::<Vec<u8>>();
+                let islands = find_cpg_islands(&seq)
+                    .into_iter()
+                    .map(|range| {
+                        let n_cpgs = count_cpgs(&seq[range.clone()]);
+                        BedRegion::new(
+                            chrom.to_string(),
+                            range,
+                            format!("CpG:{n_cpgs}"),
                         )
-                        .map(|seq| (bed_region, seq))
-                })
+                    })
+                    .collect::<Vec<BedRegion>>();
+                debug!("{} CpG island(s) on {chrom}", islands.len());
+                islands
             })
-            .map_ok(|(bed_region, seq)| {
-                let tid = reference_sequences_lookup
-                    .name_to_chrom_id(bed_region.chrom.as_str())
-                    .unwrap();
-                let start = bed_region.interval.start as u32;
-                let length = bed_region.length() as u32;
-                let chrom_name = bed_region.chrom;
-                let region_name = bed_region.name;
-                let reference_record =
-                    ReferenceRecord::new(tid, start, length, chrom_name);
-                (reference_record, region_name, seq)
-            });
-
-        // accumulators for the above iterator, could have done this all in a
-        // fold, but with 3 accumulators this is easier to look at and
-        // ends up being the same thing
-        let mut work_queue = VecDeque::new();
-        let mut region_queue = VecDeque::new();
-        let mut failures = HashMap::new();
+            .collect::<Vec<BedRegion>>();
+        if bed_regions.is_empty() {
+            bail!("zero CpG islands found in the reference sequences")
+        }
+        info!("calculating entropy over {} CpG island(s)", bed_regions.len());
+        let total_length =
+            bed_regions.iter().map(|bed_region| bed_region.length()).sum();
+        Self::from_bed_regions(
+            reference_sequences_lookup,
+            Box::new(bed_regions.into_iter().map(anyhow::Ok)),
+            total_length,
+            motifs,
+            combine_strands,
+            num_positions,
+            window_size,
+            batch_size,
+        )
+    }
 
-        let mut add_failure = |cause: String| {
-            *failures.entry(cause).or_insert(0) += 1;
+    fn from_bed_regions(
+        reference_sequences_lookup: Arc<ReferenceSequencesLookup>,
+        bed_regions: BedRegions,
+        total_length: usize,
+        motifs: Vec<RegexMotif>,
+        combine_strands: bool,
+        num_positions: usize,
+        window_size: usize,
+        batch_size: usize,
+    ) -> anyhow::Result<Self> {
+        let pending_regions = PendingRegions {
+            bed_regions,
+            reference_sequences_lookup,
+            remaining_length: total_length,
+            failures: HashMap::new(),
+            exhausted: false,
         };
-
-        for res in regions_iter {
-            match res {
-                Ok((reference_record, region_name, subseq)) => {
-                    work_queue.push_back((reference_record, subseq));
-                    region_queue.push_back(region_name);
-                }
-                Err(e) => {
-                    add_failure(e.to_string());
-                }
-            }
+        let mut sequences = SequenceQueue::new_with_regions(pending_regions);
+        if !sequences.fill() {
+            bail!("no valid regions parsed");
         }
-
-        if !failures.is_empty() {
-            debug!("failure reasons while parsing regions BED file");
-            for (cause, count) in
-                failures.iter().sorted_by(|(_, a), (_, b)| a.cmp(b))
-            {
-                debug!("\t {cause}: {count}")
-            }
-        }
-
-        if work_queue.is_empty() {
-            bail!("no valid regions parsed");
-        }
-
-        assert_eq!(region_queue.len(), work_queue.len());
-        let (curr_contig, curr_seq, curr_position, curr_region_name) = loop {
-            let (ref_record, subseq, region_name) =
-                match (work_queue.pop_front(), region_queue.pop_front()) {
-                    (Some((rr, subseq)), Some(region_name)) => {
-                        anyhow::Ok((rr, subseq, region_name))
-                    }
-                    _ => bail!(
-                        "didn't find at least 1 sequence with valid start \
-                         position"
-                    ),
-                }?;
-            if let Some(start_position) =
-                Self::find_start_position(&subseq, &motifs)
-            {
-                info!(
-                    "starting with region {region_name} at 0-based position \
-                     {} on contig {}",
-                    start_position + ref_record.start as usize,
-                    &ref_record.name
-                );
-                break (ref_record, subseq, start_position, region_name);
-            } else {
-                info!("region {region_name} has no valid positions, skipping");
-                continue;
-            }
-        };
-        debug!(
-            "parsed {} regions, starting with {} on contig {}",
-            region_queue.len() + 1usize,
-            &curr_region_name,
-            curr_contig.name
-        );
-        let motif_search_adj = motifs
-            .iter()
-            .map(|motif| motif.length())
-            .filter(|l| *l > 1)
-            .max()
-            .unwrap_or(0);
-
-        Ok(Self {
+        Self::from_sequences(
+            sequences,
             motifs,
-            work_queue,
-            region_names: region_queue,
-            window_size,
+            combine_strands,
             num_positions,
+            window_size,
             batch_size,
-            curr_position,
-            curr_contig,
-            curr_seq,
-            curr_region_name: Some(curr_region_name),
-            combine_strands,
-            motif_search_adj,
-            done: false,
-        })
+        )
     }
 
     fn new(
@@ -866,28 +1541,69 @@ impl SlidingWindows {
         window_size: usize,
         batch_size: usize,
     ) -> anyhow::Result<Self> {
-        let mut work_queue =
-            reference_sequence_lookup.into_reference_sequences();
+        let sequences = SequenceQueue::new(
+            reference_sequence_lookup.into_reference_sequences(),
+        );
+        Self::from_sequences(
+            sequences,
+            motifs,
+            combine_strands,
+            num_positions,
+            window_size,
+            batch_size,
+        )
+    }
 
-        let (curr_contig, curr_seq, curr_position) = loop {
-            let (curr_record, curr_seq) =
-                work_queue.pop_front().ok_or_else(|| {
+    fn from_sequences(
+        mut sequences: SequenceQueue,
+        motifs: Vec<RegexMotif>,
+        combine_strands: bool,
+        num_positions: usize,
+        window_size: usize,
+        batch_size: usize,
+    ) -> anyhow::Result<Self> {
+        let (curr_contig, curr_seq, curr_position, curr_region) = loop {
+            let (curr_record, curr_seq, region) =
+                sequences.pop_front().ok_or_else(|| {
                     anyhow!(
                         "didn't find at least 1 sequence with a valid start \
                          position"
                     )
                 })?;
-            if let Some(pos) = Self::find_start_position(&curr_seq, &motifs) {
-                info!(
-                    "starting with contig {} at 0-based position {pos}",
-                    &curr_record.name
-                );
-                break (curr_record, curr_seq, pos);
-            } else {
-                info!(
-                    "contig {} had no valid motif positions, skipping..",
-                    curr_record.name
-                );
+            let start_position = Self::find_start_position(&curr_seq, &motifs);
+            match (start_position, region) {
+                (Some(pos), Some((region_name, region_strand))) => {
+                    info!(
+                        "starting with region {region_name} at 0-based \
+                         position {} on contig {}",
+                        pos + curr_record.start as usize,
+                        &curr_record.name
+                    );
+                    break (
+                        curr_record,
+                        curr_seq,
+                        pos,
+                        Some((region_name, region_strand)),
+                    );
+                }
+                (Some(pos), None) => {
+                    info!(
+                        "starting with contig {} at 0-based position {pos}",
+                        &curr_record.name
+                    );
+                    break (curr_record, curr_seq, pos, None);
+                }
+                (None, Some((region_name, _))) => {
+                    info!(
+                        "region {region_name} has no valid positions, skipping"
+                    );
+                }
+                (None, None) => {
+                    info!(
+                        "contig {} had no valid motif positions, skipping..",
+                        curr_record.name
+                    );
+                }
             }
         };
         let motif_search_adj = motifs
@@ -896,24 +1612,70 @@ impl SlidingWindows {
             .filter(|l| *l > 1)
             .max()
             .unwrap_or(0);
+        let (curr_region_name, curr_region_strand) = match curr_region {
+            Some((name, strand)) => (Some(name), strand),
+            None => (None, StrandRule::Both),
+        };
 
         Ok(Self {
             motifs,
-            work_queue,
-            region_names: VecDeque::new(),
+            sequences,
             window_size,
             num_positions,
             batch_size,
             curr_position,
             curr_contig,
             curr_seq,
-            curr_region_name: None,
+            curr_region_name,
+            curr_region_strand,
             combine_strands,
             motif_search_adj,
+            step: 1,
+            step_unit: StepUnit::positions,
+            positions_to_skip: 0,
+            exclude: None,
+            n_excluded: 0,
+            strand: StrandRule::Both,
             done: false,
         })
     }
 
+    /// Start each window `step` motif positions or base pairs after the
+    /// start of the previous window, by default windows start at each motif
+    /// position.
+    pub(super) fn with_step(self, step: usize, step_unit: StepUnit) -> Self {
+        Self { step, step_unit, ..self }
+    }
+
+    /// Skip windows that overlap any of these intervals, on either strand.
+    pub(super) fn with_exclude(
+        self,
+        exclude: Option<StrandedPositionFilter<()>>,
+    ) -> Self {
+        Self { exclude, ..self }
+    }
+
+    /// Only make windows from motif positions on this strand.
+    pub(super) fn with_strand(self, strand: StrandRule) -> Self {
+        Self { strand, ..self }
+    }
+
+    /// When the window overlaps an excluded interval, the end of the
+    /// right-most overlapping interval. Windows starting before this
+    /// position will also overlap it.
+    #[inline]
+    fn excluded_until(&self, window: &GenomeWindow) -> Option<u64> {
+        let exclude = self.exclude.as_ref()?;
+        // window intervals end at the last motif position
+        let (start, end) = (window.leftmost(), window.rightmost() + 1);
+        let tid = self.curr_contig.tid;
+        [&exclude.pos_positions, &exclude.neg_positions]
+            .into_iter()
+            .filter_map(|intervals| intervals.get(&tid))
+            .flat_map(|lp| lp.find(start, end).map(|iv| iv.stop))
+            .max()
+    }
+
     #[inline]
     fn take_hits_if_enough(
         &self,
@@ -1125,13 +1887,61 @@ impl SlidingWindows {
                         })
                         .collect::<Vec<MotifHit>>()
                 })
+                .filter(|x| {
+                    self.strand.covers(x.strand)
+                        && (self.combine_strands
+                            || self.curr_region_strand.covers(x.strand))
+                })
                 .sorted_by(|a, b| a.pos.cmp(&b.pos))
                 .partition(|x| x.strand == Strand::Positive);
+            if self.positions_to_skip > 0 {
+                // only the positive strand positions are used when combining
+                // strands
+                let positions = pos_hits
+                    .iter()
+                    .chain(neg_hits.iter().filter(|_| !self.combine_strands))
+                    .map(|mh| mh.pos as usize - self.curr_contig.start as usize)
+                    .collect::<BTreeSet<usize>>();
+                match positions.iter().nth(self.positions_to_skip) {
+                    Some(&next) => {
+                        self.positions_to_skip = 0;
+                        self.curr_position = next;
+                    }
+                    None => {
+                        self.positions_to_skip -= positions.len();
+                        self.curr_position = positions
+                            .last()
+                            .map(|&last| last + 1)
+                            .unwrap_or(end);
+                    }
+                }
+                continue;
+            }
             if let Some(entropy_window) =
                 self.enough_hits_for_window(&pos_hits, &neg_hits)
             {
+                if let Some(excluded_end) = self.excluded_until(&entropy_window)
+                {
+                    self.n_excluded += 1;
+                    self.positions_to_skip = 0;
+                    self.curr_position = (excluded_end as usize)
+                        .max(entropy_window.leftmost() as usize + 1)
+                        .checked_sub(self.curr_contig.start as usize)
+                        .expect(
+                            "should be able to subtract contig start from \
+                             position",
+                        );
+                    continue;
+                }
+                let step = match self.step_unit {
+                    StepUnit::positions => {
+                        self.positions_to_skip = self.step - 1;
+                        1usize
+                    }
+                    StepUnit::bp => self.step,
+                };
                 let new_genome_space_position =
-                    (entropy_window.leftmost() as usize).saturating_add(1usize);
+                    (entropy_window.leftmost() as usize).saturating_add(step);
                 // info!("new genome position {new_genome_space_position}");
                 // need to re-adjust to relative coordinates instead of genome
                 // coordinates
@@ -1202,46 +2012,48 @@ impl SlidingWindows {
     }
 
     fn update_current_contig(&mut self) {
-        'search: loop {
-            if let Some((record, seq)) = self.work_queue.pop_front() {
-                match Self::find_start_position(&seq, &self.motifs) {
-                    Some(start_pos) => {
-                        self.curr_contig = record;
-                        self.curr_position = start_pos;
-                        self.curr_seq = seq;
-                        let region_name = self.region_names.pop_front();
-                        self.curr_region_name = region_name;
-                        break 'search;
-                    }
-                    None => {
-                        if let Some(region_name) = self.region_names.pop_front()
-                        {
-                            debug!(
-                                "skipping region {region_name}, no valid \
-                                 positions for motifs {:?}",
-                                &self.motifs
-                            )
-                        } else {
-                            debug!(
-                                "skipping {}, no valid positions for motifs \
-                                 {:?}",
-                                &record.name, &self.motifs
-                            )
-                        }
-                        continue;
+        while let Some((record, seq, region)) = self.sequences.pop_front() {
+            match Self::find_start_position(&seq, &self.motifs) {
+                Some(start_pos) => {
+                    self.curr_contig = record;
+                    self.curr_position = start_pos;
+                    self.positions_to_skip = 0;
+                    self.curr_seq = seq;
+                    (self.curr_region_name, self.curr_region_strand) =
+                        match region {
+                            Some((name, strand)) => (Some(name), strand),
+                            None => (None, StrandRule::Both),
+                        };
+                    return;
+                }
+                None => {
+                    if let Some((region_name, _)) = region {
+                        debug!(
+                            "skipping region {region_name}, no valid \
+                             positions for motifs {:?}",
+                            &self.motifs
+                        )
+                    } else {
+                        debug!(
+                            "skipping {}, no valid positions for motifs {:?}",
+                            &record.name, &self.motifs
+                        )
                     }
                 }
-            } else {
-                assert!(self.region_names.is_empty());
-                self.done = true;
-                break 'search;
             }
         }
+        assert!(self.sequences.region_names.is_empty());
+        if self.exclude.is_some() {
+            info!(
+                "skipped windows overlapping {} excluded interval(s)",
+                self.n_excluded
+            );
+        }
+        self.done = true;
     }
 
     pub(super) fn total_length(&self) -> usize {
-        self.work_queue.iter().map(|(_, s)| s.len()).sum::<usize>()
-            + self.curr_seq.len()
+        self.sequences.total_length() + self.curr_seq.len()
     }
 }
 
@@ -1292,7 +2104,7 @@ impl Iterator for SlidingWindows {
                 && windows.len() > self.batch_size
             {
                 assert!(
-                    self.region_names.is_empty(),
+                    self.sequences.region_names.is_empty(),
                     "region names should be empty here!"
                 );
                 let finished_windows =
@@ -1310,7 +2122,7 @@ impl Iterator for SlidingWindows {
 
         if !windows.is_empty() {
             assert!(
-                self.region_names.is_empty(),
+                self.sequences.region_names.is_empty(),
                 "region names should be empty here also!"
             );
             let entropy_windows =
@@ -1326,11 +2138,321 @@ impl Iterator for SlidingWindows {
     }
 }
 
+/// Chrom ID, windows, and the BED interval of each window.
+type WindowsGroup = (u32, Vec<GenomeWindow>, Vec<Range<u64>>);
+
+/// Windows given in a BED file. The entropy of each window is calculated over
+/// all of the motif positions inside it, instead of `num_positions`
+/// positions, so that the windows can match those used by other tools.
+pub(super) struct BedWindows {
+    bed_windows: BedRegions,
+    reference_sequences_lookup: Arc<ReferenceSequencesLookup>,
+    contig_sizes: HashMap<String, u32>,
+    motifs: Vec<RegexMotif>,
+    combine_strands: bool,
+    batch_size: usize,
+    /// the longest motif length, so we find motifs that start in the window
+    /// but reach outside of it
+    motif_search_adj: usize,
+    /// Windows (and their BED intervals) on the same contig that haven't been
+    /// put in a batch yet.
+    group: Option<WindowsGroup>,
+    /// only motif positions on this strand are used in windows
+    strand: StrandRule,
+    total_length: usize,
+    num_empty: usize,
+    failures: HashMap<String, usize>,
+    done: bool,
+}
+
+impl BedWindows {
+    pub(super) fn new(
+        reference_sequences_lookup: Arc<ReferenceSequencesLookup>,
+        windows_bed_fp: &PathBuf,
+        motifs: Vec<RegexMotif>,
+        combine_strands: bool,
+        batch_size: usize,
+    ) -> anyhow::Result<Self> {
+        let (total_length, bed_windows) = stream_bed_regions(windows_bed_fp)?;
+        let contig_sizes = reference_sequences_lookup.get_contig_sizes();
+        let motif_search_adj = motifs
+            .iter()
+            .map(|motif| motif.length())
+            .filter(|l| *l > 1)
+            .max()
+            .unwrap_or(0);
+        Ok(Self {
+            bed_windows,
+            reference_sequences_lookup,
+            contig_sizes,
+            motifs,
+            combine_strands,
+            batch_size,
+            motif_search_adj,
+            group: None,
+            strand: StrandRule::Both,
+            total_length,
+            num_empty: 0,
+            failures: HashMap::new(),
+            done: false,
+        })
+    }
+
+    pub(super) fn total_length(&self) -> usize {
+        self.total_length
+    }
+
+    /// Only make windows from motif positions on this strand.
+    pub(super) fn with_strand(self, strand: StrandRule) -> Self {
+        Self { strand, ..self }
+    }
+
+    /// The windows for a BED record, one when combining strands otherwise one
+    /// for each strand with positions, along with the chrom ID and interval.
+    /// Empty when there are no motif positions in the interval.
+    fn get_windows(
+        &self,
+        bed_region: BedRegion,
+    ) -> anyhow::Result<(u32, Range<u64>, Vec<GenomeWindow>)> {
+        let chrom = bed_region.chrom.as_str();
+        let interval = bed_region.interval;
+        let tid = self
+            .reference_sequences_lookup
+            .name_to_chrom_id(chrom)
+            .ok_or_else(|| anyhow!("seq {chrom} not in used references"))?;
+        let contig_length =
+            self.contig_sizes
+                .get(chrom)
+                .map(|l| *l as usize)
+                .ok_or_else(|| anyhow!("seq {chrom} not in used references"))?;
+        if interval.end > contig_length {
+            bail!(
+                "interval {}-{} is out of bounds for {chrom} (length \
+                 {contig_length})",
+                interval.start,
+                interval.end,
+            )
+        }
+        let bed_interval = (interval.start as u64)..(interval.end as u64);
+        let fetch_start = interval.start.saturating_sub(self.motif_search_adj);
+        let fetch_end = std::cmp::min(
+            interval.end.saturating_add(self.motif_search_adj),
+            contig_length,
+        );
+        let seq = self
+            .reference_sequences_lookup
+            .get_subsequence_by_name(chrom, fetch_start..fetch_end)?;
+        let subseq = seq.iter().collect::<String>();
+        let (pos_hits, neg_hits): (Vec<MotifHit>, Vec<MotifHit>) = self
+            .motifs
+            .iter()
+            .flat_map(|motif| {
+                motif
+                    .find_hits(&subseq)
+                    .into_iter()
+                    .filter_map(|(pos, strand)| {
+                        let genome_position = pos + fetch_start;
+                        if !interval.contains(&genome_position) {
+                            return None;
+                        }
+                        let dna_base = DnaBase::parse(seq[pos]).ok()?;
+                        let base = if strand == Strand::Negative {
+                            dna_base.complement()
+                        } else {
+                            dna_base
+                        };
+                        let neg_position = motif
+                            .motif_info
+                            .negative_strand_position(genome_position as u32)
+                            .map(|x| x as u64);
+                        Some(MotifHit::new(
+                            genome_position as u64,
+                            neg_position,
+                            strand,
+                            base,
+                        ))
+                    })
+                    .collect::<Vec<MotifHit>>()
+            })
+            .filter(|x| self.strand.covers(x.strand))
+            .partition(|x| x.strand == Strand::Positive);
+
+        if self.combine_strands {
+            let neg_to_pos = pos_hits
+                .iter()
+                .filter_map(|motif_hit| {
+                    motif_hit.neg_position.map(|np| {
+                        ((motif_hit.base, np), (motif_hit.base, motif_hit.pos))
+                    })
+                })
+                .collect::<FxHashMap<BaseAndPosition, BaseAndPosition>>();
+            let window = match neg_to_pos
+                .keys()
+                .chain(neg_to_pos.values())
+                .map(|(_, x)| x)
+                .minmax()
+            {
+                MinMaxResult::MinMax(s, t) => Some(*s..*t),
+                MinMaxResult::OneElement(x) => Some(*x..(*x + 1u64)),
+                MinMaxResult::NoElements => None,
+            }
+            .map(|interval| {
+                GenomeWindow::new_combine_strands(
+                    interval,
+                    neg_to_pos.len(),
+                    neg_to_pos,
+                )
+            });
+            Ok((tid, bed_interval, window.into_iter().collect()))
+        } else {
+            // more than one motif can hit the same position
+            let positions = |hits: Vec<MotifHit>| {
+                let positions = hits
+                    .into_iter()
+                    .map(|mh| (mh.pos, mh.base))
+                    .collect::<BTreeMap<u64, DnaBase>>()
+                    .into_iter()
+                    .map(|(pos, base)| (base, pos))
+                    .collect::<Vec<BaseAndPosition>>();
+                (!positions.is_empty()).then_some(positions)
+            };
+            let windows = [
+                positions(pos_hits).map(|p| {
+                    let n = p.len();
+                    GenomeWindow::new_stranded(Some(p), None, n)
+                }),
+                positions(neg_hits).map(|p| {
+                    let n = p.len();
+                    GenomeWindow::new_stranded(None, Some(p), n)
+                }),
+            ]
+            .into_iter()
+            .flatten()
+            .collect();
+            Ok((tid, bed_interval, windows))
+        }
+    }
+
+    fn finish_group(
+        (chrom_id, windows, intervals): WindowsGroup,
+    ) -> GenomeWindows {
+        GenomeWindows::new(chrom_id, windows, None)
+            .with_window_intervals(intervals)
+    }
+
+    fn log_skipped(&self) {
+        if self.num_empty > 0 {
+            info!(
+                "{} window(s) had no positions for the motifs and were \
+                 skipped",
+                self.num_empty
+            );
+        }
+        if !self.failures.is_empty() {
+            debug!("failure reasons while parsing windows BED file");
+            for (cause, count) in
+                self.failures.iter().sorted_by(|(_, a), (_, b)| a.cmp(b))
+            {
+                debug!("\t {cause}: {count}")
+            }
+        }
+    }
+}
+
+impl Iterator for BedWindows {
+    type Item = Vec<GenomeWindows>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut batch = Vec::with_capacity(self.batch_size);
+        while !self.done && batch.len() < self.batch_size {
+            let Some(bed_region) = self.bed_windows.next() else {
+                self.done = true;
+                if let Some(group) = self.group.take() {
+                    batch.push(Self::finish_group(group));
+                }
+                self.log_skipped();
+                break;
+            };
+            let (chrom_id, interval, windows) =
+                match bed_region.and_then(|r| self.get_windows(r)) {
+                    Ok(res) => res,
+                    Err(e) => {
+                        *self.failures.entry(e.to_string()).or_insert(0) += 1;
+                        continue;
+                    }
+                };
+            if windows.is_empty() {
+                self.num_empty += 1;
+                continue;
+            }
+            let intervals = vec![interval; windows.len()];
+            match self.group.as_mut() {
+                Some((group_chrom_id, group_windows, group_intervals))
+                    if *group_chrom_id == chrom_id
+                        && group_windows.len() < self.batch_size =>
+                {
+                    group_windows.extend(windows);
+                    group_intervals.extend(intervals);
+                }
+                _ => {
+                    if let Some(group) =
+                        self.group.replace((chrom_id, windows, intervals))
+                    {
+                        batch.push(Self::finish_group(group));
+                    }
+                }
+            }
+        }
+
+        if batch.is_empty() {
+            None
+        } else {
+            Some(batch)
+        }
+    }
+}
+
 #[derive(new, Debug)]
 pub(super) struct MethylationEntropy {
     me_entropy: f32,
     num_reads: usize,
     interval: Range<u64>,
+    /// Contribution of each read to the entropy, only calculated for
+    /// read-level output.
+    #[new(default)]
+    read_entropies: Vec<ReadEntropy>,
+    /// Entropy calculated separately for each modification code requested
+    /// with `--per-mod-code`, in the same order.
+    #[new(default)]
+    mod_code_entropies: Vec<f32>,
+    /// Reads covering the window that were dropped by subsampling
+    /// (`--max-reads-per-window`).
+    #[new(default)]
+    num_reads_dropped: usize,
+    /// Probability that two reads in the window have different patterns.
+    #[new(default)]
+    epipolymorphism: f32,
+    /// Number of reads with each pattern, only collected for
+    /// `--report-epialleles`.
+    #[new(default)]
+    epialleles: Vec<(String, usize)>,
+    /// Lower and upper bounds of the bootstrap confidence interval of
+    /// `me_entropy`, only calculated with `--bootstrap`.
+    #[new(default)]
+    entropy_ci: Option<(f32, f32)>,
+    /// Entropy of the window with the calls shuffled among reads, only
+    /// calculated with `--shuffle-null`.
+    #[new(default)]
+    shuffled_null: Option<ShuffledNull>,
+}
+
+/// The encoded pattern of a read in a window and its contribution to the
+/// window's entropy.
+#[derive(Debug)]
+pub(super) struct ReadEntropy {
+    pub(super) read_id: String,
+    pub(super) pattern: String,
+    pub(super) entropy: f32,
 }
 
 // todo make this an enum, one for regions
@@ -1351,6 +2473,9 @@ struct DescriptiveStats {
     min_num_reads: usize,
     failed_count: usize,
     successful_count: usize,
+    mean_mod_code_entropies: Vec<f32>,
+    /// Entropy at each of the `--quantiles`, in the same order.
+    entropy_quantiles: Vec<f32>,
 }
 
 impl DescriptiveStats {
@@ -1362,6 +2487,7 @@ impl DescriptiveStats {
         measurements: &[f32],
         n_reads: &[usize],
         n_fails: usize,
+        quantiles: &[f32],
         chrom_id: u32,
         interval: &Range<u64>,
     ) -> MkResult<Self> {
@@ -1382,8 +2508,17 @@ impl DescriptiveStats {
                 "measurements and n_reads should be the same length"
             );
             let mean_entropy = Self::mean(measurements);
+            let sorted_measurements = measurements
+                .iter()
+                .copied()
+                .sorted_by(|a, b| a.total_cmp(b))
+                .collect::<Vec<f32>>();
             let median_entropy =
-                percentile_linear_interp(measurements, 0.5f32)?;
+                percentile_linear_interp(&sorted_measurements, 0.5f32)?;
+            let entropy_quantiles = quantiles
+                .iter()
+                .map(|&q| percentile_linear_interp(&sorted_measurements, q))
+                .collect::<MkResult<Vec<f32>>>()?;
             // safe because of above check
             let (min_entropy, max_entropy) = match measurements.iter().minmax()
             {
@@ -1417,10 +2552,33 @@ impl DescriptiveStats {
                 min_num_reads,
                 successful_count: success_count,
                 failed_count: n_fails,
+                mean_mod_code_entropies: Vec::new(),
+                entropy_quantiles,
             })
         }
     }
 
+    /// Set the mean of each per-modification code entropy over the
+    /// successful windows, `window_entropies` has the per-code entropies of
+    /// each window.
+    fn with_mod_code_entropies(
+        self,
+        window_entropies: &[&[f32]],
+        n_codes: usize,
+    ) -> Self {
+        let mean_mod_code_entropies = (0..n_codes)
+            .map(|i| {
+                Self::mean(
+                    &window_entropies
+                        .iter()
+                        .map(|es| es[i])
+                        .collect::<Vec<_>>(),
+                )
+            })
+            .collect();
+        Self { mean_mod_code_entropies, ..self }
+    }
+
     pub(super) fn to_row(
         &self,
         chrom: &str,
@@ -1429,6 +2587,7 @@ impl DescriptiveStats {
         strand: Strand,
         region_name: &str,
     ) -> String {
+        use crate::entropy::writers::mod_code_columns;
         use crate::util::TAB;
 
         format!(
@@ -1446,7 +2605,7 @@ impl DescriptiveStats {
             {}{TAB}\
             {}{TAB}\
             {}{TAB}\
-            {}\n",
+            {}{}{}\n",
             self.mean_entropy,
             strand.to_char(),
             self.median_entropy,
@@ -1456,7 +2615,9 @@ impl DescriptiveStats {
             self.min_num_reads,
             self.max_num_reads,
             self.successful_count,
-            self.failed_count
+            self.failed_count,
+            mod_code_columns("", &self.entropy_quantiles),
+            mod_code_columns("", &self.mean_mod_code_entropies)
         )
     }
 }
@@ -1465,7 +2626,7 @@ impl DescriptiveStats {
 pub(super) struct RegionEntropy {
     chrom_id: u32,
     interval: Range<u64>,
-    pos_entropy_stats: MkResult<DescriptiveStats>,
+    pos_entropy_stats: Option<MkResult<DescriptiveStats>>,
     neg_entropy_stats: Option<MkResult<DescriptiveStats>>,
     region_name: String,
     window_entropies: Vec<WindowEntropy>,
@@ -1477,19 +2638,80 @@ struct Message {
     reference_start: i64,
     reference_end: i64,
     strand: Strand,
-    // _name: String,
+    name: String,
+}
+
+impl Message {
+    /// The reference span of the read, `None` for reads without a valid
+    /// alignment span, which can't cover any window.
+    fn reference_span(&self) -> Option<(u64, u64)> {
+        if self.reference_start >= 0
+            && self.reference_end > self.reference_start
+        {
+            Some((self.reference_start as u64, self.reference_end as u64))
+        } else {
+            None
+        }
+    }
+}
+
+/// Which reads are used in the entropy calculation, reads that don't pass
+/// are skipped before their base modification calls are decoded.
+#[derive(Debug, Clone, Default)]
+pub(super) struct EntropyReadFilter {
+    /// Skip reads with any of these tag values.
+    exclude_tags: Vec<(SamTag, String)>,
+    min_mapq: Option<u8>,
+    min_read_length: Option<usize>,
+    alignment_filter: Option<AlignmentIdentityFilter>,
+}
+
+impl EntropyReadFilter {
+    pub(super) fn new(
+        exclude_tags: Vec<(SamTag, String)>,
+        min_mapq: Option<u8>,
+        min_read_length: Option<usize>,
+        alignment_filter: Option<AlignmentIdentityFilter>,
+    ) -> Self {
+        Self { exclude_tags, min_mapq, min_read_length, alignment_filter }
+    }
+
+    fn passes(&self, record: &bam::Record) -> bool {
+        self.min_mapq.map(|mapq| record.mapq() >= mapq).unwrap_or(true)
+            && self
+                .min_read_length
+                .map(|length| record.seq_len() >= length)
+                .unwrap_or(true)
+            && !record_has_tag_value(record, &self.exclude_tags)
+            && self
+                .alignment_filter
+                .as_ref()
+                .map(|filter| filter.passes(record))
+                .unwrap_or(true)
+    }
 }
 
 fn process_bam_fp(
     bam_fp: &PathBuf,
+    reference: Option<&PathBuf>,
     fetch_definition: FetchDefinition,
     caller: Arc<MultipleThresholdModCaller>,
     io_threads: usize,
+    read_filter: &EntropyReadFilter,
 ) -> anyhow::Result<Vec<Message>> {
-    let mut reader = bam::IndexedReader::from_path(bam_fp)?;
+    let mut reader = get_indexed_reader(bam_fp, reference)?;
     reader.set_threads(io_threads)?;
     reader.fetch(fetch_definition)?;
+    Ok(decode_fetched_reads(&mut reader, caller, read_filter))
+}
 
+/// Decode the reads from a reader that has already been fetched to the
+/// region of interest, reads that fail to decode are skipped.
+fn decode_fetched_reads(
+    reader: &mut bam::IndexedReader,
+    caller: Arc<MultipleThresholdModCaller>,
+    read_filter: &EntropyReadFilter,
+) -> Vec<Message> {
     let record_iter = reader
         .records()
         .filter_map(|r| r.ok())
@@ -1497,6 +2719,7 @@ fn process_bam_fp(
             !record.is_unmapped()
                 && !(record_is_not_primary(&record) || record.seq_len() == 0)
         })
+        .filter(|record| read_filter.passes(record))
         .filter_map(|record| {
             String::from_utf8(record.qname().to_vec())
                 .ok()
@@ -1560,6 +2783,7 @@ fn process_bam_fp(
                         record.reference_start(),
                         record.reference_end(),
                         strand,
+                        name,
                     );
                     messages.push(msg);
                 }
@@ -1569,58 +2793,279 @@ fn process_bam_fp(
             }
         };
     }
-    Ok(messages)
+    messages
 }
 
-pub(super) fn process_entropy_window(
-    mut entropy_windows: GenomeWindows,
-    min_coverage: u32,
-    max_filtered_positions: usize,
+/// Base modification calls from each BAM over the interval covered by a
+/// [`GenomeWindows`], decoded but not yet added to the windows. Decoding is
+/// I/O-bound and calculating the entropy is CPU-bound, so the two are done in
+/// separate stages that can overlap.
+pub(super) struct DecodedWindows {
+    entropy_windows: GenomeWindows,
+    messages: Vec<anyhow::Result<Vec<Message>>>,
+    /// Subsample the reads in each window to at most this many, see
+    /// [`ReadReservoir`].
+    max_reads: Option<usize>,
+}
+
+/// Fetch and decode the reads overlapping `entropy_windows` from each BAM,
+/// reads that don't pass the `read_filter` are skipped. When `max_reads` is
+/// set, windows covered by more reads are subsampled.
+pub(super) fn decode_entropy_window(
+    entropy_windows: GenomeWindows,
     io_threads: usize,
     caller: Arc<MultipleThresholdModCaller>,
     bam_fps: &[PathBuf],
-) -> anyhow::Result<EntropyCalculation> {
+    reference: Option<&PathBuf>,
+    read_filter: &EntropyReadFilter,
+    max_reads: Option<usize>,
+) -> anyhow::Result<DecodedWindows> {
     let bam_fp = &bam_fps[0];
-    let reader = bam::IndexedReader::from_path(bam_fp)?;
-    let chrom_id = entropy_windows.chrom_id;
+    let reader = get_indexed_reader(bam_fp, reference)?;
     drop(reader);
 
-    let results = bam_fps
+    let messages = bam_fps
         .into_par_iter()
         .map(|fp| {
             process_bam_fp(
                 fp,
+                reference,
                 entropy_windows.get_fetch_definition(),
                 caller.clone(),
                 io_threads,
+                read_filter,
             )
         })
         .collect::<Vec<anyhow::Result<Vec<Message>>>>();
 
-    for message_result in results {
-        match message_result {
-            Ok(messages) => {
-                for message in messages {
-                    entropy_windows.entropy_windows.par_iter_mut().for_each(
-                        |window| {
-                            window.add_read_to_patterns(
-                                &message.mod_calls,
-                                message.reference_start,
-                                message.reference_end,
-                                message.strand,
-                                max_filtered_positions,
-                            )
-                        },
-                    );
+    Ok(DecodedWindows { entropy_windows, messages, max_reads })
+}
+
+impl DecodedWindows {
+    /// Add the decoded reads to the windows they cover (see
+    /// [`add_reads_to_windows`]), the windows are processed in parallel, and
+    /// calculate the entropy.
+    pub(super) fn into_entropy_calculation(
+        self,
+        min_coverage: u32,
+        max_filtered_positions: usize,
+        estimator: EntropyEstimator,
+        read_level: bool,
+        report_epialleles: bool,
+        per_mod_codes: &[ModCodeRepr],
+        quantiles: &[f32],
+    ) -> EntropyCalculation {
+        let Self { mut entropy_windows, messages, max_reads } = self;
+        let chrom_id = entropy_windows.chrom_id;
+        let messages = messages
+            .into_iter()
+            .filter_map(|message_result| match message_result {
+                Ok(messages) => Some(messages),
+                Err(e) => {
+                    debug!("failed to run bam {e}");
+                    None
                 }
+            })
+            .flatten()
+            .collect::<Vec<Message>>();
+        add_reads_to_windows(
+            &mut entropy_windows.entropy_windows,
+            &messages,
+            max_filtered_positions,
+            read_level,
+            max_reads,
+        );
+
+        entropy_windows.into_entropy_calculation(
+            chrom_id,
+            min_coverage,
+            estimator,
+            report_epialleles,
+            per_mod_codes,
+            quantiles,
+        )
+    }
+}
+
+/// Add the reads to the windows they cover. A read has to cover a window to
+/// be used, so the reads are binned by their reference span and each window
+/// only checks the reads that overlap it. The intervals hold the index of
+/// the read so that the reads are added in input order, which the reservoir
+/// sampling depends on.
+fn add_reads_to_windows(
+    windows: &mut [GenomeWindow],
+    messages: &[Message],
+    max_filtered_positions: usize,
+    read_level: bool,
+    max_reads: Option<usize>,
+) {
+    let read_spans = Lapper::new(
+        messages
+            .iter()
+            .enumerate()
+            .filter_map(|(idx, message)| {
+                message.reference_span().map(|(start, end)| Interval {
+                    start,
+                    stop: end + 1,
+                    val: idx,
+                })
+            })
+            .collect(),
+    );
+    windows.par_iter_mut().for_each(|window| {
+        let mut reservoir =
+            max_reads.map(|n| ReadReservoir::new(n, window.leftmost()));
+        let mut overlapping = read_spans
+            .find(window.leftmost(), window.rightmost() + 1)
+            .map(|iv| iv.val)
+            .collect::<Vec<usize>>();
+        overlapping.sort_unstable();
+        for idx in overlapping {
+            window.add_read_to_patterns(
+                &messages[idx],
+                max_filtered_positions,
+                read_level,
+                reservoir.as_mut(),
+            )
+        }
+    });
+}
+
+/// Re-encode joint patterns so that only the modification encoded as `code`
+/// is modified ('1'), every other call (including other modifications) is
+/// unmodified ('0') and filtered positions are kept. When `code` is `None`
+/// the modification wasn't observed and every valid call is unmodified.
+fn binarize_patterns(patterns: &[String], code: Option<char>) -> Vec<String> {
+    patterns
+        .iter()
+        .map(|pattern| {
+            pattern
+                .chars()
+                .map(|c| match c {
+                    '*' => '*',
+                    c if Some(c) == code => '1',
+                    _ => '0',
+                })
+                .collect::<String>()
+        })
+        .collect()
+}
+
+/// Shuffle the calls (including filtered positions) at each position of
+/// the patterns among the reads.
+fn shuffle_patterns(
+    patterns: &[String],
+    window_size: usize,
+    rng: &mut StdRng,
+) -> Vec<String> {
+    let mut shuffled = patterns
+        .iter()
+        .map(|p| p.as_bytes().to_vec())
+        .collect::<Vec<Vec<u8>>>();
+    for i in 0..window_size {
+        let mut column = shuffled.iter().map(|p| p[i]).collect::<Vec<u8>>();
+        column.shuffle(rng);
+        for (p, c) in shuffled.iter_mut().zip(column) {
+            p[i] = c;
+        }
+    }
+    shuffled.into_iter().map(|p| String::from_utf8(p).unwrap()).collect()
+}
+
+/// Entropy of a single window, flattened so it can be reported outside of
+/// this module.
+pub(crate) struct WindowEntropyRecord {
+    pub(crate) start: u64,
+    pub(crate) end: u64,
+    pub(crate) strand: Strand,
+    pub(crate) entropy: f32,
+    pub(crate) num_reads: usize,
+}
+
+/// Calculate methylation entropy for each window in a single region, used for
+/// ad-hoc queries (e.g. `serve`) with a reader that is kept between queries.
+/// Windows that fail are tallied by reason.
+pub(crate) fn calculate_region_window_entropies(
+    reference_sequences_lookup: &Arc<ReferenceSequencesLookup>,
+    chrom: &str,
+    interval: Range<usize>,
+    motifs: Vec<RegexMotif>,
+    combine_strands: bool,
+    num_positions: usize,
+    window_size: usize,
+    min_coverage: u32,
+    max_filtered_positions: usize,
+    caller: Arc<MultipleThresholdModCaller>,
+    reader: &mut bam::IndexedReader,
+) -> anyhow::Result<(Vec<WindowEntropyRecord>, FxHashMap<String, usize>)> {
+    let name = format!("{chrom}:{}-{}", interval.start, interval.end);
+    let bed_region = BedRegion::new(chrom.to_string(), interval, name);
+    let length = bed_region.length();
+    let sliding_windows = SlidingWindows::from_bed_regions(
+        reference_sequences_lookup.clone(),
+        Box::new(std::iter::once(Ok(bed_region))),
+        length,
+        motifs,
+        combine_strands,
+        num_positions,
+        window_size,
+        1,
+    )?;
+
+    let mut records = Vec::new();
+    let mut failures = FxHashMap::default();
+    for genome_windows in sliding_windows.flatten() {
+        reader.fetch(genome_windows.get_fetch_definition())?;
+        let messages =
+            decode_fetched_reads(
+            reader,
+            caller.clone(),
+            &EntropyReadFilter::default(),
+        );
+        let decoded = DecodedWindows {
+            entropy_windows: genome_windows,
+            messages: vec![Ok(messages)],
+            max_reads: None,
+        };
+        let window_entropies = match decoded.into_entropy_calculation(
+            min_coverage,
+            max_filtered_positions,
+            EntropyEstimator::new(None, EntropyNorm::window, false),
+            false,
+            false,
+            &[],
+            &[],
+        ) {
+            EntropyCalculation::Windows(window_entropies) => window_entropies,
+            EntropyCalculation::Region(region_entropy) => {
+                region_entropy.window_entropies
             }
-            Err(e) => {
-                debug!("failed to run bam {e}");
+        };
+        for window_entropy in window_entropies {
+            for (strand, me_entropy) in [
+                (Strand::Positive, window_entropy.pos_me_entropy),
+                (Strand::Negative, window_entropy.neg_me_entropy),
+            ] {
+                match me_entropy {
+                    Some(Ok(me_entropy)) => {
+                        records.push(WindowEntropyRecord {
+                            start: me_entropy.interval.start,
+                            end: me_entropy.interval.end,
+                            strand,
+                            entropy: me_entropy.me_entropy,
+                            num_reads: me_entropy.num_reads,
+                        });
+                    }
+                    Some(Err(e)) => {
+                        *failures.entry(e.to_string()).or_insert(0) += 1;
+                    }
+                    None => {}
+                }
             }
         }
     }
 
-    Ok(entropy_windows.into_entropy_calculation(chrom_id, min_coverage))
+    Ok((records, failures))
 }
 
 #[derive(new, Debug)]
@@ -1628,6 +3073,10 @@ struct BedRegion {
     chrom: String,
     interval: Range<usize>,
     name: String,
+    /// Strand from the 6th (BED6) column, both strands for BED3/4 lines and
+    /// `.`.
+    #[new(value = "StrandRule::Both")]
+    strand: StrandRule,
 }
 
 impl BedRegion {
@@ -1648,7 +3097,7 @@ impl BedRegion {
         };
 
         let interval = (start as usize)..(stop as usize);
-        let this = Self { chrom, interval, name };
+        let this = Self { chrom, interval, name, strand: StrandRule::Both };
         Ok((rest, this))
     }
 
@@ -1662,12 +3111,39 @@ impl BedRegion {
                     bail!("end must be after start")
                 }
             })
+            .and_then(|this| {
+                let strand = match raw.split('\t').nth(5).map(|x| x.trim()) {
+                    Some(raw_strand) => raw_strand
+                        .parse::<char>()
+                        .map_err(|_| anyhow!("illegal strand {raw_strand}"))
+                        .and_then(StrandRule::try_from)?,
+                    None => StrandRule::Both,
+                };
+                Ok(Self { strand, ..this })
+            })
     }
 }
 
 #[cfg(test)]
 mod entropy_mod_tests {
-    use crate::entropy::BedRegion;
+    use crate::entropy::methylation_entropy::calc_me_entropy;
+    use std::collections::HashMap;
+    use std::path::PathBuf;
+    use std::sync::Arc;
+
+    use indicatif::MultiProgress;
+
+    use crate::entropy::{
+        add_reads_to_windows, binarize_patterns, shuffle_patterns, BedRegion,
+        EntropyEstimator, EntropyNorm, GenomeWindow, Message, PendingRegions,
+        ReadReservoir, SequenceQueue, REGIONS_PER_FETCH,
+    };
+    use crate::mod_bam::BaseModCall;
+    use crate::reads_sampler::sampling_schedule::ReferenceSequencesLookup;
+    use crate::util::{Strand, StrandRule};
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+    use rustc_hash::FxHashMap;
 
     #[test]
     fn test_bed_region_parsing() {
@@ -1676,11 +3152,20 @@ mod entropy_mod_tests {
         assert_eq!(&bed_region.chrom, "chr1");
         assert_eq!(bed_region.interval, 100usize..101);
         assert_eq!(&bed_region.name, "foo");
+        assert_eq!(bed_region.strand, StrandRule::Both);
         let raw = "chr1\t100\t101\tfoo\t400\t.\tmorestuff\n";
         let bed_region = BedRegion::parse_str(raw).expect("should parse");
         assert_eq!(&bed_region.chrom, "chr1");
         assert_eq!(bed_region.interval, 100usize..101);
         assert_eq!(&bed_region.name, "foo");
+        assert_eq!(bed_region.strand, StrandRule::Both);
+        let raw = "chr1\t100\t101\tfoo\t400\t+\tmorestuff\n";
+        let bed_region = BedRegion::parse_str(raw).expect("should parse");
+        assert_eq!(bed_region.strand, StrandRule::Positive);
+        let raw = "chr1\t100\t101\tfoo\t400\t-\n";
+        let bed_region = BedRegion::parse_str(raw).expect("should parse");
+        assert_eq!(bed_region.strand, StrandRule::Negative);
+        assert!(BedRegion::parse_str("chr1\t100\t101\tfoo\t400\tx").is_err());
 
         let raw = "chr20\t279148\t279507\tCpG: 39";
         let bed_region = BedRegion::parse_str(raw).expect("should parse");
@@ -1688,4 +3173,226 @@ mod entropy_mod_tests {
         assert_eq!(bed_region.interval, 279148usize..279507);
         assert_eq!(&bed_region.name, "CpG: 39");
     }
+
+    #[test]
+    fn test_binarize_patterns() {
+        let patterns =
+            vec!["0120".to_string(), "2*11".to_string(), "0000".to_string()];
+        let m = binarize_patterns(&patterns, Some('1'));
+        assert_eq!(m, vec!["0100", "0*11", "0000"]);
+        let h = binarize_patterns(&patterns, Some('2'));
+        assert_eq!(h, vec!["0010", "1*00", "0000"]);
+        let missing = binarize_patterns(&patterns, None);
+        assert_eq!(missing, vec!["0000", "0*00", "0000"]);
+        // only one modification, so the per-code entropy is the joint entropy
+        let single = vec!["0110".to_string(), "1*00".to_string()];
+        let window_size = 4;
+        let constant = 1f32 / window_size as f32;
+        assert_eq!(
+            calc_me_entropy(
+                &binarize_patterns(&single, Some('1')),
+                window_size,
+                constant,
+                false
+            ),
+            calc_me_entropy(&single, window_size, constant, false)
+        );
+    }
+
+    #[test]
+    fn test_shuffled_null() {
+        let patterns = ["0101", "1010", "0*01", "1111"]
+            .into_iter()
+            .map(|p| p.to_string())
+            .collect::<Vec<String>>();
+        let window_size = 4;
+        let mut rng = StdRng::seed_from_u64(42);
+        let shuffled = shuffle_patterns(&patterns, window_size, &mut rng);
+        // the calls at each position are kept, only their reads change
+        for i in 0..window_size {
+            let column = |ps: &[String]| {
+                let mut column =
+                    ps.iter().map(|p| p.as_bytes()[i]).collect::<Vec<u8>>();
+                column.sort();
+                column
+            };
+            assert_eq!(column(&shuffled), column(&patterns));
+        }
+
+        let estimator = EntropyEstimator::new(None, EntropyNorm::window, false);
+        assert!(estimator
+            .shuffled_null(&patterns, window_size, 0.5, 0)
+            .is_none());
+        let estimator = estimator.with_shuffle_null(100);
+        let null =
+            estimator.shuffled_null(&patterns, window_size, 0.5, 0).unwrap();
+        assert!(null.z_score.is_some());
+        assert_eq!(
+            estimator.shuffled_null(&patterns, window_size, 0.5, 0),
+            Some(null)
+        );
+        // every read has the same calls, so shuffling can't change anything
+        let same = vec!["0110".to_string(); 5];
+        let observed = estimator.entropy(&same, window_size);
+        let null =
+            estimator.shuffled_null(&same, window_size, observed, 0).unwrap();
+        assert_eq!(null.mean_entropy, observed);
+        assert!(null.z_score.is_none());
+    }
+
+    #[test]
+    fn test_read_reservoir() {
+        let sample = |max_reads: usize| {
+            let mut window = GenomeWindow::new_combine_strands(
+                0..10,
+                1,
+                FxHashMap::default(),
+            );
+            let mut reservoir = ReadReservoir::new(max_reads, 10);
+            for i in 0..100 {
+                window.add_pattern(
+                    &Strand::Positive,
+                    vec![BaseModCall::Canonical(i as f32)],
+                    Some(&format!("read_{i}")),
+                    Some(&mut reservoir),
+                );
+            }
+            assert_eq!(window.reads_seen(&Strand::Positive), 100);
+            let GenomeWindow::CombineStrands {
+                read_patterns, read_names, ..
+            } = window
+            else {
+                panic!("should be combine strands")
+            };
+            assert_eq!(read_patterns.len(), read_names.len());
+            // names stay with their patterns when reads are replaced
+            for (pattern, name) in read_patterns.iter().zip(read_names.iter()) {
+                let BaseModCall::Canonical(i) = pattern[0] else {
+                    panic!("should be canonical")
+                };
+                assert_eq!(name, &format!("read_{i}"));
+            }
+            read_names
+        };
+        let sampled = sample(5);
+        assert_eq!(sampled.len(), 5);
+        assert_ne!(
+            sampled,
+            (0..5).map(|i| format!("read_{i}")).collect::<Vec<_>>()
+        );
+        // same seed, same sample
+        assert_eq!(sampled, sample(5));
+        // fewer reads than the cap, all are kept in order
+        let all = sample(100);
+        assert_eq!(
+            all,
+            (0..100).map(|i| format!("read_{i}")).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn test_add_reads_to_windows() {
+        let mut windows = [0..10, 100..110, 105..120]
+            .into_iter()
+            .map(|interval| {
+                GenomeWindow::new_combine_strands(
+                    interval,
+                    1,
+                    FxHashMap::default(),
+                )
+            })
+            .collect::<Vec<GenomeWindow>>();
+        let messages = [
+            ("covers_all", 0, 200),
+            ("covers_first", 0, 10),
+            ("partial", 5, 105),
+            ("unmapped", -1, -1),
+            ("covers_second", 95, 112),
+            ("covers_last_two", 100, 120),
+        ]
+        .into_iter()
+        .map(|(name, start, end)| {
+            Message::new(
+                FxHashMap::default(),
+                start,
+                end,
+                Strand::Positive,
+                name.to_string(),
+            )
+        })
+        .collect::<Vec<Message>>();
+        add_reads_to_windows(&mut windows, &messages, 0, true, None);
+        let read_names = windows
+            .into_iter()
+            .map(|window| {
+                let GenomeWindow::CombineStrands { read_names, .. } = window
+                else {
+                    panic!("should be combine strands")
+                };
+                read_names
+            })
+            .collect::<Vec<Vec<String>>>();
+        // only reads that cover each window, in input order
+        assert_eq!(
+            read_names,
+            vec![
+                vec!["covers_all", "covers_first"],
+                vec!["covers_all", "covers_second", "covers_last_two"],
+                vec!["covers_all", "covers_last_two"],
+            ]
+        );
+    }
+
+    #[test]
+    fn test_sequence_queue_fetches_regions_in_batches() {
+        let mpb = MultiProgress::new();
+        mpb.set_draw_target(indicatif::ProgressDrawTarget::hidden());
+        let lookup = ReferenceSequencesLookup::new(
+            &[PathBuf::from("tests/resources/bc_anchored_10_reads.sorted.bam")],
+            &PathBuf::from("tests/resources/CGI_ladder_3.6kb_ref.fa"),
+            false,
+            &mpb,
+        )
+        .unwrap();
+        let n_regions = REGIONS_PER_FETCH * 2 + 5;
+        let bed_regions = (0..n_regions)
+            .map(|i| {
+                BedRegion::new(
+                    "oligo_1512_adapters".to_string(),
+                    23..62,
+                    format!("region{i}"),
+                )
+            })
+            .chain(std::iter::once(BedRegion::new(
+                "missing".to_string(),
+                0..10,
+                "missing".to_string(),
+            )))
+            .collect::<Vec<BedRegion>>();
+        let total_length = bed_regions.iter().map(|r| r.length()).sum();
+        let mut sequences = SequenceQueue::new_with_regions(PendingRegions {
+            bed_regions: Box::new(bed_regions.into_iter().map(anyhow::Ok)),
+            reference_sequences_lookup: Arc::new(lookup),
+            remaining_length: total_length,
+            failures: HashMap::new(),
+            exhausted: false,
+        });
+        assert_eq!(sequences.total_length(), total_length);
+        let mut popped = 0usize;
+        while let Some((record, seq, name)) = sequences.pop_front() {
+            assert!(sequences.work_queue.len() < REGIONS_PER_FETCH);
+            assert_eq!(record.start, 23);
+            assert_eq!(seq.len(), 39);
+            assert_eq!(
+                name,
+                Some((format!("region{popped}"), StrandRule::Both))
+            );
+            popped += 1;
+        }
+        assert_eq!(popped, n_regions);
+        assert_eq!(sequences.total_length(), 0);
+        let pending = sequences.pending_regions.as_ref().unwrap();
+        assert!(pending.exhausted);
+        assert_eq!(pending.failures.values().sum::<usize>(), 1);
+    }
 }
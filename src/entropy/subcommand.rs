@@ -1,46 +1,173 @@
 use std::collections::HashMap;
-use std::path::PathBuf;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 
-use crate::command_utils::parse_per_mod_thresholds;
-use crate::entropy::writers::{EntropyWriter, RegionsWriter, WindowsWriter};
-use crate::entropy::{process_entropy_window, SlidingWindows};
+use crate::command_utils::{parse_per_mod_thresholds, DryRunPlan};
+use crate::entropy::bedmethyl::{BedMethylEntropy, BEDMETHYL_WINDOWS_HEADER};
+use crate::entropy::summary::EntropyRunSummary;
+use crate::entropy::tracks::EntropyBigWigTracks;
+use crate::entropy::writers::{
+    failed_windows_writer, thresholds_comment, EntropyWriter, EpialleleWriter,
+    ReadLevelWriter, RegionsWriter, WindowsWriter,
+};
+use crate::entropy::{
+    decode_entropy_window, BedWindows, Bootstrap, EntropyEstimator,
+    EntropyNorm, EntropyReadFilter, GenomeWindows, SlidingWindows, StepUnit,
+};
 use crate::logging::init_logging;
-use crate::mod_base_code::DnaBase;
+use crate::mod_base_code::{DnaBase, ModCodeRepr};
 use crate::monoid::Moniod;
 use crate::motifs::motif_bed::RegexMotif;
+use crate::position_filter::StrandedPositionFilter;
+use crate::provenance::BasecallProvenance;
 use crate::reads_sampler::sampling_schedule::{
     IdxStats, ReferenceSequencesLookup,
 };
+use crate::run_summary;
+use crate::tabix::{
+    build_bed_tabix_index, BedMethylTbxIndex, TBI_MAX_POSITION,
+};
 use crate::threshold_mod_caller::MultipleThresholdModCaller;
 use crate::thresholds::{
     get_modbase_probs_from_bam, log_calculated_thresholds,
     percentile_linear_interp,
 };
-use crate::util::{format_errors_table, get_master_progress_bar, get_ticker};
+use crate::util::{
+    create_out_directory, format_errors_table, get_master_progress_bar,
+    get_ticker, parse_tag_values, standard_output_path,
+    AlignmentIdentityFilter, StrandRule,
+};
 use anyhow::{bail, Context};
 use clap::Args;
 use indicatif::MultiProgress;
+use itertools::Itertools;
 use log::{debug, error, info};
 use rayon::prelude::*;
 use rustc_hash::FxHashMap;
 
+/// Batches of windows that can be decoded ahead of the entropy calculation,
+/// bounds the memory used by reads waiting to be processed.
+const DECODED_BATCHES_IN_FLIGHT: usize = 2;
+
+/// Batches of windows, either sliding windows or windows from a BED file.
+type EntropyWindows = Box<dyn Iterator<Item = Vec<GenomeWindows>> + Send>;
+
 #[derive(Args)]
 #[command(arg_required_else_help = true)]
 pub struct MethylationEntropy {
     /// Input mod-BAM, may be repeated multiple times to calculate entropy
     /// across all input mod-BAMs.
-    #[arg(short = 's', long = "in-bam", required = true)]
+    #[arg(
+        short = 's',
+        long = "in-bam",
+        required_unless_present = "in_bedmethyl"
+    )]
     in_bams: Vec<PathBuf>,
+    /// Approximate the entropy from a bgzip-compressed and tabix-indexed
+    /// bedMethyl instead of mod-BAMs, for when the reads are no longer
+    /// available. Without reads the positions in a window are assumed to be
+    /// independent, the entropy is the sum of the entropy of the calls at
+    /// each position (scaled with `--entropy-norm`) and an epipolymorphism
+    /// column is added. Windows are `num_positions` consecutive positions in
+    /// the bedMethyl, so the reference and motif options are not used.
+    #[arg(
+        long,
+        conflicts_with_all = [
+            "in_bams", "reference_fasta", "motif", "base", "region_mode",
+            "read_level_out", "report_epialleles", "bigwig",
+            "failed_windows", "markov_order", "windows_bed",
+            "per_mod_code", "max_reads_per_window", "exclude_tag",
+            "min_mapq", "min_read_length", "min_identity", "max_nm",
+            "bootstrap", "shuffle_null", "step", "step_unit",
+            "thresholds", "mod_thresholds", "bgzf", "exclude_bed",
+            "strand", "summary",
+        ]
+    )]
+    in_bedmethyl: Option<PathBuf>,
     /// Output BED file, if using `--region` this must be a directory.
     #[clap(help_heading = "Output Options")]
     #[arg(short = 'o', long)]
     out_bed: Option<PathBuf>,
-    /// Only used with `--regions`, prefix files in output directory with this
-    /// string.
+    /// Write outputs into this directory with standardized names instead of
+    /// using `--out-bed`. Windows are written to `<prefix>_entropy.bed`,
+    /// with `--regions` or `--cgi-auto` the directory is used as the regions
+    /// output directory.
+    #[clap(help_heading = "Output Options")]
+    #[arg(long, conflicts_with = "out_bed")]
+    out_dir: Option<PathBuf>,
+    /// Used with `--regions`, `--cgi-auto`, or `--out-dir`, prefix files in
+    /// output directory with this string.
     #[clap(help_heading = "Output Options")]
-    #[arg(long, requires = "regions_fp")]
+    #[arg(long)]
     prefix: Option<String>,
+    /// Write the windows output bgzip-compressed and build a tabix index
+    /// next to it (`.tbi`, or `.csi` when a contig is longer than 512 Mb) so
+    /// it can be read by region. Requires `--out-bed` or `--out-dir`, with
+    /// `--out-dir` windows are written to `<prefix>_entropy.bed.gz`. Windows
+    /// from `--windows-bed` must be sorted for the output to be indexed.
+    #[clap(help_heading = "Output Options")]
+    #[arg(long, conflicts_with = "region_mode", default_value_t = false)]
+    bgzf: bool,
+    /// Only used with `--regions` or `--cgi-auto`, also write a BED12 file
+    /// (<prefix>_regions.bed12) with one record per region and strand where
+    /// the blocks are the windows that were used to calculate the region
+    /// summary, overlapping windows are merged into a single block. A 13th
+    /// column has the mean entropy of the windows in each block.
+    #[clap(help_heading = "Output Options")]
+    #[arg(long, requires = "region_mode", default_value_t = false)]
+    bed12: bool,
+    /// Only used with `--regions` or `--cgi-auto`, also report these
+    /// quantiles of the window entropies in each region, e.g. `--quantiles
+    /// 0.1,0.25,0.75,0.9`. An `entropy_q<quantile>` column is added to the
+    /// regions output for each quantile, before any `--per-mod-code`
+    /// columns.
+    #[clap(help_heading = "Output Options")]
+    #[arg(long, requires = "region_mode", value_delimiter = ',')]
+    quantiles: Vec<f32>,
+    /// Write windows that failed because they had no reads (zero-reads) or
+    /// too few reads at one or more positions (insufficient-coverage) to
+    /// this BED file. The columns are chrom, start, end, reason, strand, and
+    /// the minimum and maximum valid coverage over the positions in the
+    /// window.
+    #[clap(help_heading = "Output Options")]
+    #[arg(long)]
+    failed_windows: Option<PathBuf>,
+    /// Write a summary of the run to this file as JSON, the number of
+    /// windows on each contig that were scored or failed because they had
+    /// no reads (zero_coverage) or too few reads (insufficient_coverage),
+    /// and a histogram of window coverage. The coverage of a scored window
+    /// is the number of reads used, for a window that failed it's the
+    /// smallest valid coverage over the positions in the window.
+    #[clap(help_heading = "Output Options")]
+    #[arg(long)]
+    summary: Option<PathBuf>,
+    /// Write the encoded pattern (e.g. `01*10`) of every read in every
+    /// window and the read's contribution to the window entropy to this TSV.
+    /// The columns are chrom, start, end, strand, read_id, pattern, and
+    /// entropy_contribution, the contributions of the reads in a window sum
+    /// to the window's entropy.
+    #[clap(help_heading = "Output Options")]
+    #[arg(long, hide_short_help = true)]
+    read_level_out: Option<PathBuf>,
+    /// Write the epiallele frequency spectrum of every window to this TSV,
+    /// the number of reads with each observed pattern (e.g. `01*10`). The
+    /// columns are chrom, start, end, strand, pattern, count, and frequency
+    /// (the fraction of the window's reads with the pattern), the most
+    /// common pattern in a window is first.
+    #[clap(help_heading = "Output Options")]
+    #[arg(long, hide_short_help = true)]
+    report_epialleles: Option<PathBuf>,
+    /// Also write the entropy of each window to a bigWig track at this path.
+    /// When strands are combined (e.g. with `--cpg`) a single track is
+    /// written, otherwise one track per strand is written with `_positive`
+    /// and `_negative` added to the file name. Overlapping windows are
+    /// trimmed to end where the next window starts. Regions from `--regions`
+    /// or `--windows-bed` must be grouped by contig.
+    #[clap(help_heading = "Output Options")]
+    #[arg(long)]
+    bigwig: Option<PathBuf>,
     /// Number of modified positions to consider at a time
     #[arg(short = 'n', long, default_value_t = 4)]
     num_positions: usize,
@@ -54,6 +181,17 @@ pub struct MethylationEntropy {
     /// other bases can be used CGACGATCGGCG.
     #[arg(short = 'w', long, default_value_t = 50)]
     window_size: usize,
+    /// Distance between the starts of consecutive windows, in units of
+    /// `--step-unit`. By default each window starts at the motif position
+    /// after the first position of the previous window, so consecutive
+    /// windows share all but one position. Set to `--num-positions` for
+    /// windows that don't overlap. When strands aren't combined the motif
+    /// positions on both strands are counted, e.g. a CpG is 2 positions.
+    #[arg(long, default_value_t = 1)]
+    step: usize,
+    /// Unit of `--step`, motif positions or base pairs.
+    #[arg(long, value_enum, default_value_t = StepUnit::positions)]
+    step_unit: StepUnit,
     /// Do not perform any filtering, include all mod base calls in output.
     #[clap(help_heading = "Filtering Options")]
     #[arg(group = "thresholds", long, default_value_t = false)]
@@ -106,6 +244,36 @@ pub struct MethylationEntropy {
         action = clap::ArgAction::Append
     )]
     mod_thresholds: Option<Vec<String>>,
+    /// Exclude reads with this tag value from the entropy calculation, given
+    /// as TAG:VALUE. For example, `--exclude-tag HP:0` removes reads with
+    /// haplotype tag 0. May be repeated, reads matching any of the tag values
+    /// are excluded. Reads are still used when estimating the filter
+    /// threshold.
+    #[clap(help_heading = "Filtering Options")]
+    #[arg(long, action = clap::ArgAction::Append)]
+    exclude_tag: Vec<String>,
+    /// Only use reads with at least this mapping quality. Reads are still
+    /// used when estimating the filter threshold.
+    #[clap(help_heading = "Filtering Options")]
+    #[arg(long)]
+    min_mapq: Option<u8>,
+    /// Only use reads with at least this many bases. Reads are still used
+    /// when estimating the filter threshold.
+    #[clap(help_heading = "Filtering Options")]
+    #[arg(long)]
+    min_read_length: Option<usize>,
+    /// Only use reads with at least this alignment identity, the fraction of
+    /// aligned columns (matches, mismatches, insertions, and deletions) that
+    /// aren't edits according to the NM tag. Reads without an NM tag are
+    /// skipped. Reads are still used when estimating the filter threshold.
+    #[clap(help_heading = "Filtering Options")]
+    #[arg(long)]
+    min_identity: Option<f32>,
+    /// Only use reads with at most this many edits (NM tag) to the
+    /// reference. Reads without an NM tag are skipped.
+    #[clap(help_heading = "Filtering Options")]
+    #[arg(long)]
+    max_nm: Option<u32>,
     /// Number of threads to use.
     #[clap(help_heading = "Compute Options")]
     #[arg(short = 't', long, default_value_t = 4)]
@@ -114,9 +282,20 @@ pub struct MethylationEntropy {
     #[clap(help_heading = "Compute Options")]
     #[arg(long, hide_short_help = true)]
     io_threads: Option<usize>,
-    /// Reference sequence in FASTA format.
-    #[arg(long = "ref", alias = "reference")]
-    reference_fasta: PathBuf,
+    /// Validate the inputs (BAMs, reference, regions, BED files, motifs, and
+    /// thresholds), print the planned positions and output files, then exit
+    /// without writing anything. Filter thresholds are not estimated in a
+    /// dry run.
+    #[clap(help_heading = "Compute Options")]
+    #[arg(long, default_value_t = false, conflicts_with = "in_bedmethyl")]
+    dry_run: bool,
+    /// Reference sequence in FASTA format, also used to decode CRAM input.
+    #[arg(
+        long = "ref",
+        alias = "reference",
+        required_unless_present = "in_bedmethyl"
+    )]
+    reference_fasta: Option<PathBuf>,
     /// Respect soft masking in the reference FASTA.
     #[arg(long, requires = "reference_fasta", default_value_t = false)]
     mask: bool,
@@ -132,13 +311,52 @@ pub struct MethylationEntropy {
     /// Primary sequence base to calculate modification entropy on.
     #[arg(long, conflicts_with="cpg", action = clap::ArgAction::Append)]
     base: Option<Vec<DnaBase>>,
-    /// Regions over which to calculate descriptive statistics
-    #[arg(long = "regions")]
+    /// Regions over which to calculate descriptive statistics. Regions with
+    /// a `+` or `-` strand (BED6 column 6) only use the windows on that
+    /// strand, e.g. for promoters, regions without a strand (BED3 or `.`) use
+    /// both. The strand is ignored when combining strands.
+    #[arg(long = "regions", group = "region_mode")]
     regions_fp: Option<PathBuf>,
+    /// Find CpG islands in the reference sequences and use them as the
+    /// regions, instead of passing a BED file with `--regions`. Islands are
+    /// at least 200 bp with G+C content over 50% and an observed/expected
+    /// CpG ratio over 0.6.
+    #[arg(long, group = "region_mode", default_value_t = false)]
+    cgi_auto: bool,
+    /// BED file of windows to calculate entropy on, instead of sliding
+    /// windows of `num_positions` positions. The entropy of each window is
+    /// calculated with all of the motif positions inside the interval and
+    /// the output has one record per window (and strand), so the windows
+    /// can match those used by other tools. Reads must cover every position
+    /// in the window, consider setting `--max-filtered-positions` for long
+    /// windows. Windows without any motif positions are skipped.
+    #[arg(
+        long,
+        conflicts_with_all = [
+            "region_mode", "markov_order", "step", "step_unit"
+        ]
+    )]
+    windows_bed: Option<PathBuf>,
+    /// BED file of regions to exclude, e.g. the ENCODE blacklist or
+    /// telomeres. Sliding windows overlapping any interval in the file are
+    /// skipped, strands in the file are ignored. With `--regions` or
+    /// `--cgi-auto` the region statistics only use the remaining windows.
+    #[arg(long, conflicts_with = "windows_bed")]
+    exclude_bed: Option<PathBuf>,
     /// Combine modification counts on the positive and negative strands and
     /// report entropy on just the positive strand.
     #[arg(long, conflicts_with_all=["base", "cpg"], default_value_t=false)]
     combine_strands: bool,
+    /// Only calculate entropy on windows of motif positions on this strand,
+    /// for assays where only one strand is of interest. Cannot be used when
+    /// combining strands (including with `--cpg`).
+    #[arg(
+        long,
+        value_enum,
+        default_value_t = StrandRule::Both,
+        conflicts_with_all = ["combine_strands", "cpg"]
+    )]
+    strand: StrandRule,
     /// Minimum coverage required at each position in the window. Windows
     /// without at least this many valid reads will be skipped, but
     /// positions within the window with enough coverage can be used by
@@ -178,6 +396,69 @@ pub struct MethylationEntropy {
     /// will be 50% of `num_positions`.
     #[arg(long)]
     max_filtered_positions: Option<usize>,
+    /// Calculate the conditional (transition) entropy of each position given
+    /// the preceding N positions in the window, instead of the joint entropy
+    /// of all `num_positions`. Transitions from all reads and positions in
+    /// the window are pooled, the result is in bits per position. Must be
+    /// less than `num_positions`.
+    #[arg(long, value_name = "N")]
+    markov_order: Option<usize>,
+    /// How to scale the methylation entropy of each window. `window`
+    /// divides by the number of positions (bits per position), `reads`
+    /// divides by log2 of the number of reads (the largest possible entropy
+    /// with that many reads), and `none` reports bits. Not used with
+    /// `--markov-order`, which is always in bits per position.
+    #[arg(
+        long,
+        value_enum,
+        default_value_t = EntropyNorm::window,
+        conflicts_with = "markov_order"
+    )]
+    entropy_norm: EntropyNorm,
+    /// Add the Miller-Madow correction, (observed patterns - 1) / (2 ln(2)
+    /// reads) bits, to the entropy of each window. The plug-in entropy is
+    /// biased low when there are few reads, the correction makes entropy
+    /// values more comparable across coverage levels.
+    #[arg(long, default_value_t = false)]
+    miller_madow: bool,
+    /// Calculate a bootstrap confidence interval for the entropy of each
+    /// window by resampling the reads' patterns with replacement this many
+    /// times. Adds `entropy_lower_ci` and `entropy_upper_ci` columns to the
+    /// windows output, after any `--per-mod-code` columns. Useful at low
+    /// coverage, where the entropy of a window is uncertain.
+    #[arg(long, value_name = "N")]
+    bootstrap: Option<usize>,
+    /// Confidence level of the `--bootstrap` interval.
+    #[arg(long, requires = "bootstrap", default_value_t = 0.95)]
+    confidence_level: f32,
+    /// Compare the entropy of each window with a null made by shuffling the
+    /// calls at each position among the reads this many times. Shuffling
+    /// keeps the level of modification at each position and the number of
+    /// reads, so the null has the same coverage-dependent bias as the
+    /// observed entropy. Adds `null_entropy` (mean of the shuffles) and
+    /// `entropy_z_score` columns to the windows output, after any
+    /// `--bootstrap` columns. Negative z-scores mean the reads' patterns are
+    /// more ordered than expected from the per-position modification
+    /// levels.
+    #[arg(long, value_name = "N")]
+    shuffle_null: Option<usize>,
+    /// Also calculate the entropy separately for each of these modification
+    /// codes (e.g. m,h), in addition to the joint entropy over all
+    /// modifications. For each code, calls of any other modification are
+    /// counted as unmodified. An `entropy_<code>` column is added to the
+    /// windows output for each code (and a `mean_entropy_<code>` column to
+    /// the regions output).
+    #[clap(help_heading = "Output Options")]
+    #[arg(long, value_delimiter = ',', action = clap::ArgAction::Append)]
+    per_mod_code: Option<Vec<String>>,
+    /// Maximum number of reads to keep in each window, when more reads
+    /// cover a window a uniform random sample of this many reads is used
+    /// (the sample is the same from run to run). Bounds memory use on very
+    /// deep data, e.g. amplicons. The number of windows that were subsampled
+    /// is reported at the end of the run. Default is to use all reads.
+    #[clap(help_heading = "Compute Options")]
+    #[arg(long, value_name = "N")]
+    max_reads_per_window: Option<usize>,
 }
 
 impl MethylationEntropy {
@@ -186,46 +467,147 @@ impl MethylationEntropy {
         if self.num_positions == 0 {
             bail!("num-positions must be at least 1")
         }
+        if self.step == 0 {
+            bail!("step must be at least 1")
+        }
+        if self.quantiles.iter().any(|q| !(0f32..=1f32).contains(q)) {
+            bail!("quantiles must be between 0 and 1")
+        }
         if self.min_valid_coverage < 1 {
             bail!("min-valid-coverage must be at least 1")
         }
+        if self.prefix.is_some()
+            && self.out_dir.is_none()
+            && self.regions_fp.is_none()
+            && !self.cgi_auto
+        {
+            bail!("--prefix requires --out-dir, --regions, or --cgi-auto")
+        }
+        if self.bgzf && self.out_bed.is_none() && self.out_dir.is_none() {
+            bail!("--bgzf requires --out-bed or --out-dir")
+        }
+        if let Some(bedmethyl_fp) = self.in_bedmethyl.as_ref() {
+            return self.run_bedmethyl(bedmethyl_fp);
+        }
+        if let Some(max_reads) = self.max_reads_per_window {
+            if max_reads < self.min_valid_coverage as usize {
+                bail!(
+                    "max-reads-per-window must be at least min-coverage ({})",
+                    self.min_valid_coverage
+                )
+            }
+            info!("subsampling windows to at most {max_reads} reads");
+        }
+        if let Some(order) = self.markov_order {
+            if order == 0 || order >= self.num_positions {
+                bail!(
+                    "markov-order must be at least 1 and less than \
+                     num-positions ({})",
+                    self.num_positions
+                )
+            }
+            info!("calculating order-{order} transition entropy");
+        }
+        if self.miller_madow {
+            info!("adding Miller-Madow bias correction");
+        }
+        let estimator = EntropyEstimator::new(
+            self.markov_order,
+            self.entropy_norm,
+            self.miller_madow,
+        );
+        let estimator = match self.bootstrap {
+            Some(num_resamples) => {
+                if num_resamples < 2 {
+                    bail!("bootstrap must be at least 2")
+                }
+                if !(self.confidence_level > 0f32
+                    && self.confidence_level < 1f32)
+                {
+                    bail!("confidence-level must be between 0 and 1")
+                }
+                info!(
+                    "calculating {}% confidence intervals with \
+                     {num_resamples} bootstrap resamples",
+                    self.confidence_level * 100f32
+                );
+                estimator.with_bootstrap(Bootstrap::new(
+                    num_resamples,
+                    self.confidence_level,
+                ))
+            }
+            None => estimator,
+        };
+        let estimator = match self.shuffle_null {
+            Some(num_shuffles) => {
+                if num_shuffles < 2 {
+                    bail!("shuffle-null must be at least 2")
+                }
+                info!(
+                    "comparing window entropy to {num_shuffles} shuffles of \
+                     the calls among reads"
+                );
+                estimator.with_shuffle_null(num_shuffles)
+            }
+            None => estimator,
+        };
+        let per_mod_codes = self
+            .per_mod_code
+            .as_ref()
+            .map(|codes| {
+                codes
+                    .iter()
+                    .map(|raw| ModCodeRepr::parse(raw))
+                    .collect::<anyhow::Result<Vec<ModCodeRepr>>>()
+            })
+            .transpose()?
+            .unwrap_or_default()
+            .into_iter()
+            .unique()
+            .collect::<Vec<ModCodeRepr>>();
+        if !per_mod_codes.is_empty() {
+            info!(
+                "calculating entropy separately for modification codes: {}",
+                per_mod_codes.iter().join(",")
+            );
+        }
+        let exclude_tags = parse_tag_values(&self.exclude_tag)?;
+        if !exclude_tags.is_empty() {
+            info!(
+                "excluding reads with tag values {}",
+                self.exclude_tag.join(", ")
+            );
+        }
+        if let Some(min_mapq) = self.min_mapq {
+            info!("using reads with mapping quality at least {min_mapq}");
+        }
+        if let Some(min_read_length) = self.min_read_length {
+            info!("using reads with length at least {min_read_length}");
+        }
+        let read_filter = EntropyReadFilter::new(
+            exclude_tags,
+            self.min_mapq,
+            self.min_read_length,
+            AlignmentIdentityFilter::from_options(
+                self.min_identity,
+                self.max_nm,
+            )?,
+        );
         for bam_fp in self.in_bams.iter() {
-            IdxStats::check_any_mapped_reads(&bam_fp, None, None)
-                .with_context(|| {
-                    format!(
-                        "did not find any mapped reads in {bam_fp:?}, perform \
+            IdxStats::check_any_mapped_reads(
+                &bam_fp,
+                self.reference_fasta.as_ref(),
+                None,
+                None,
+            )
+            .with_context(|| {
+                format!(
+                    "did not find any mapped reads in {bam_fp:?}, perform \
                          alignment first"
-                    )
-                })?;
+                )
+            })?;
         }
 
-        let mut writer: Box<dyn EntropyWriter> =
-            match (self.out_bed.as_ref(), self.regions_fp.is_some()) {
-                (Some(out_fp), false) => Box::new(
-                    WindowsWriter::new_file(out_fp, self.header, self.verbose)
-                        .context("failed to make writer to file")?,
-                ),
-                (Some(out_dir), true) => Box::new(
-                    RegionsWriter::new(
-                        out_dir,
-                        self.prefix.as_ref(),
-                        self.header,
-                        self.verbose,
-                    )
-                    .context(
-                        "failed to make regions writer, output must be a \
-                         directory",
-                    )?,
-                ),
-                (None, false) => Box::new(
-                    WindowsWriter::new_stdout(self.header, self.verbose)
-                        .context("failed to make writer to stdout")?,
-                ),
-                (None, true) => {
-                    bail!("must provide output directory with regions")
-                }
-            };
-
         let pool = rayon::ThreadPoolBuilder::new()
             .num_threads(self.threads)
             .build()?;
@@ -292,6 +674,8 @@ impl MethylationEntropy {
                 ),
             };
 
+        self.check_outputs(combine_strands)?;
+
         let batch_size = (self.threads as f32 * 1.5f32).floor() as usize;
         let window_size = self.window_size;
 
@@ -299,45 +683,252 @@ impl MethylationEntropy {
             info!("combining (+)-strand and (-)-strand modification calls");
         }
 
+        let reference_fasta = self
+            .reference_fasta
+            .as_ref()
+            .context("--ref is required with --in-bam")?;
         let reference_sequence_lookup = ReferenceSequencesLookup::new(
             &self.in_bams,
-            &self.reference_fasta,
+            reference_fasta,
             self.mask,
             &multi_pb,
         )?;
         let chrom_id_to_name =
             reference_sequence_lookup.get_chrom_id_to_name_lookup();
-
-        let sliding_windows = pool.install(|| {
-            if let Some(regions_fp) = self.regions_fp.as_ref() {
-                SlidingWindows::new_with_regions(
-                    reference_sequence_lookup,
-                    regions_fp,
-                    motifs,
-                    combine_strands,
-                    self.num_positions,
-                    window_size,
-                    batch_size,
-                )
-            } else {
-                SlidingWindows::new(
-                    reference_sequence_lookup,
-                    motifs,
-                    combine_strands,
-                    self.num_positions,
-                    window_size,
-                    batch_size,
+        let exclude = self
+            .exclude_bed
+            .as_ref()
+            .map(|fp| {
+                let name_to_tid = chrom_id_to_name
+                    .iter()
+                    .map(|(tid, name)| (name.as_str(), *tid))
+                    .collect::<HashMap<&str, u32>>();
+                StrandedPositionFilter::from_bed_file(
+                    fp,
+                    &name_to_tid,
+                    self.suppress_progress,
                 )
-            }
-        })?;
+                .with_context(|| {
+                    format!("failed to load excluded regions from {fp:?}")
+                })
+            })
+            .transpose()?;
+        let contig_sizes = reference_sequence_lookup.get_contig_sizes();
+        let csi = contig_sizes
+            .values()
+            .any(|&length| length as u64 > TBI_MAX_POSITION);
+        let motif_labels = motifs.iter().join(", ");
+
+        let (total_length, sliding_windows) =
+            pool.install(|| -> anyhow::Result<(usize, EntropyWindows)> {
+                if let Some(windows_bed_fp) = self.windows_bed.as_ref() {
+                    let bed_windows = BedWindows::new(
+                        Arc::new(reference_sequence_lookup),
+                        windows_bed_fp,
+                        motifs,
+                        combine_strands,
+                        batch_size,
+                    )?
+                    .with_strand(self.strand);
+                    Ok((bed_windows.total_length(), Box::new(bed_windows)))
+                } else {
+                    let sliding_windows =
+                        if let Some(regions_fp) = self.regions_fp.as_ref() {
+                            SlidingWindows::new_with_regions(
+                                Arc::new(reference_sequence_lookup),
+                                regions_fp,
+                                motifs,
+                                combine_strands,
+                                self.num_positions,
+                                window_size,
+                                batch_size,
+                            )
+                        } else if self.cgi_auto {
+                            SlidingWindows::new_with_cpg_islands(
+                                Arc::new(reference_sequence_lookup),
+                                motifs,
+                                combine_strands,
+                                self.num_positions,
+                                window_size,
+                                batch_size,
+                            )
+                        } else {
+                            SlidingWindows::new(
+                                reference_sequence_lookup,
+                                motifs,
+                                combine_strands,
+                                self.num_positions,
+                                window_size,
+                                batch_size,
+                            )
+                        }?
+                        .with_step(self.step, self.step_unit)
+                        .with_exclude(exclude)
+                        .with_strand(self.strand);
+                    Ok((
+                        sliding_windows.total_length(),
+                        Box::new(sliding_windows),
+                    ))
+                }
+            })?;
 
+        if self.dry_run {
+            return self.print_dry_run_plan(
+                &pool,
+                reference_fasta,
+                &motif_labels,
+                total_length,
+                batch_size,
+                combine_strands,
+                csi,
+            );
+        }
+
+        // thresholds are estimated before opening the outputs so that they can
+        // be recorded in the header
         let threshold_caller =
             self.get_threshold_caller(&pool).map(|c| Arc::new(c))?;
+        let provenance = self.in_bams.iter().try_fold(
+            BasecallProvenance::zero(),
+            |acc, bam_fp| {
+                BasecallProvenance::from_path(bam_fp).map(|p| acc.op(p))
+            },
+        )?;
+        let header = self.header.then(|| {
+            format!(
+                "{}{}",
+                thresholds_comment(&threshold_caller),
+                provenance.header_comment()
+            )
+        });
+
+        let failed_out = self
+            .failed_windows
+            .as_ref()
+            .map(|fp| {
+                failed_windows_writer(fp, self.header)
+                    .context("failed to make failed windows writer")
+            })
+            .transpose()?;
+        let mut run_summary =
+            self.summary.as_ref().map(|_| EntropyRunSummary::default());
+        let mut read_level_out = self
+            .read_level_out
+            .as_ref()
+            .map(|fp| {
+                create_out_directory(fp)?;
+                ReadLevelWriter::new(fp, self.header)
+                    .context("failed to make read-level writer")
+            })
+            .transpose()?;
+        let mut epiallele_out = self
+            .report_epialleles
+            .as_ref()
+            .map(|fp| {
+                create_out_directory(fp)?;
+                EpialleleWriter::new(fp, self.header)
+                    .context("failed to make epiallele writer")
+            })
+            .transpose()?;
+        let region_mode = self.regions_fp.is_some() || self.cgi_auto;
+        let out_bed = match (self.out_dir.as_ref(), region_mode) {
+            (Some(out_dir), false) => {
+                let fp = standard_output_path(
+                    out_dir,
+                    self.prefix.as_ref(),
+                    if self.bgzf { "entropy.bed.gz" } else { "entropy.bed" },
+                );
+                create_out_directory(&fp)?;
+                Some(fp)
+            }
+            (Some(out_dir), true) => Some(out_dir.clone()),
+            (None, _) => self.out_bed.clone(),
+        };
+        let mut writer: Box<dyn EntropyWriter> =
+            match (out_bed.as_ref(), region_mode) {
+                (Some(out_fp), false) if self.bgzf => Box::new(
+                    WindowsWriter::new_bgzf(
+                        out_fp,
+                        self.force,
+                        self.io_threads.unwrap_or(self.threads),
+                        header.as_deref(),
+                        &per_mod_codes,
+                        self.bootstrap.is_some(),
+                        self.shuffle_null.is_some(),
+                        self.verbose,
+                    )
+                    .context("failed to make bgzf writer to file")?
+                    .with_failed_windows(failed_out),
+                ),
+                (Some(out_fp), false) => Box::new(
+                    WindowsWriter::new_file(
+                        out_fp,
+                        header.as_deref(),
+                        &per_mod_codes,
+                        self.bootstrap.is_some(),
+                        self.shuffle_null.is_some(),
+                        self.verbose,
+                    )
+                    .context("failed to make writer to file")?
+                    .with_failed_windows(failed_out),
+                ),
+                (Some(out_dir), true) => Box::new(
+                    RegionsWriter::new(
+                        out_dir,
+                        self.prefix.as_ref(),
+                        header.as_deref(),
+                        &per_mod_codes,
+                        &self.quantiles,
+                        self.bootstrap.is_some(),
+                        self.shuffle_null.is_some(),
+                        self.bed12,
+                        self.verbose,
+                    )
+                    .context(
+                        "failed to make regions writer, output must be a \
+                         directory",
+                    )?
+                    .with_failed_windows(failed_out),
+                ),
+                (None, false) => Box::new(
+                    WindowsWriter::new_stdout(
+                        header.as_deref(),
+                        &per_mod_codes,
+                        self.bootstrap.is_some(),
+                        self.shuffle_null.is_some(),
+                        self.verbose,
+                    )
+                    .context("failed to make writer to stdout")?
+                    .with_failed_windows(failed_out),
+                ),
+                (None, true) => {
+                    bail!("must provide output directory with regions")
+                }
+            };
+
+        let mut bigwig_tracks = self
+            .bigwig
+            .as_ref()
+            .map(|fp| {
+                EntropyBigWigTracks::new(
+                    fp,
+                    combine_strands,
+                    self.force,
+                    contig_sizes,
+                    self.threads,
+                )
+            })
+            .transpose()?;
 
         let (snd, rcv) = crossbeam::channel::bounded(10_000);
 
         let bam_fps = self.in_bams.clone();
+        let reference_fp = reference_fasta.clone();
         let min_coverage = self.min_valid_coverage;
+        let read_level = read_level_out.is_some();
+        let report_epialleles = epiallele_out.is_some();
+        let max_reads = self.max_reads_per_window;
+        let quantiles = self.quantiles.clone();
         let threads = self.threads;
         let io_threads = self.io_threads.unwrap_or(threads);
         let max_filtered = self.max_filtered_positions.unwrap_or_else(|| {
@@ -347,21 +938,26 @@ impl MethylationEntropy {
             max_filt_pos
         });
 
-        let genome_prog = multi_pb
-            .add(get_master_progress_bar(sliding_windows.total_length()));
+        let genome_prog = multi_pb.add(get_master_progress_bar(total_length));
         let rows_written = multi_pb.add(get_ticker());
         let windows_failed = multi_pb.add(get_ticker());
         let batches_failed = multi_pb.add(get_ticker());
 
-        let what =
-            if self.regions_fp.is_some() { "regions" } else { "windows" };
+        let what = if region_mode { "regions" } else { "windows" };
 
         genome_prog.set_message("genome positions processed");
         rows_written.set_message("rows written");
         windows_failed.set_message(format!("{what} failed"));
         batches_failed.set_message("batches failed");
 
-        pool.spawn(move || {
+        // windows are fetched and decoded from the BAMs on their own pool
+        // and handed to the compute pool over a bounded channel so reading
+        // the next batches overlaps with calculating entropy on this one
+        let decode_pool =
+            rayon::ThreadPoolBuilder::new().num_threads(threads).build()?;
+        let (decoded_snd, decoded_rcv) =
+            crossbeam::channel::bounded(DECODED_BATCHES_IN_FLIGHT);
+        decode_pool.spawn(move || {
             for batch in sliding_windows {
                 let n_pos = batch
                     .iter()
@@ -370,37 +966,46 @@ impl MethylationEntropy {
                         r.end - r.start
                     })
                     .sum::<u64>();
-                let mut results = Vec::new();
-                let (entropies, _) = rayon::join(
-                    || {
-                        let rs = batch
-                            .into_par_iter()
-                            .map(|window| {
-                                process_entropy_window(
-                                    window,
-                                    min_coverage,
-                                    max_filtered,
-                                    io_threads,
-                                    threshold_caller.clone(),
-                                    &bam_fps,
-                                )
-                            })
-                            .collect::<Vec<_>>();
-                        genome_prog.inc(n_pos);
-                        rs
-                    },
-                    || {
-                        results.into_iter().for_each(|entropy| {
-                            match snd.send(entropy) {
-                                Ok(_) => {}
-                                Err(e) => {
-                                    error!("failed to send on channel, {e}");
-                                }
-                            }
+                let decoded = batch
+                    .into_par_iter()
+                    .map(|window| {
+                        decode_entropy_window(
+                            window,
+                            io_threads,
+                            threshold_caller.clone(),
+                            &bam_fps,
+                            Some(&reference_fp),
+                            &read_filter,
+                            max_reads,
+                        )
+                    })
+                    .collect::<Vec<_>>();
+                if let Err(e) = decoded_snd.send((n_pos, decoded)) {
+                    error!("failed to send on channel, {e}");
+                    break;
+                }
+            }
+        });
+
+        pool.spawn(move || {
+            for (n_pos, decoded) in decoded_rcv {
+                let results = decoded
+                    .into_par_iter()
+                    .map(|decoded| {
+                        decoded.map(|decoded| {
+                            decoded.into_entropy_calculation(
+                                min_coverage,
+                                max_filtered,
+                                estimator,
+                                read_level,
+                                report_epialleles,
+                                &per_mod_codes,
+                                &quantiles,
+                            )
                         })
-                    },
-                );
-                results = entropies;
+                    })
+                    .collect::<Vec<_>>();
+                genome_prog.inc(n_pos);
                 results.into_iter().for_each(|entropy| {
                     match snd.send(entropy) {
                         Ok(_) => {}
@@ -413,9 +1018,34 @@ impl MethylationEntropy {
         });
 
         let mut failure_reasons = FxHashMap::default();
+        let mut windows_subsampled = 0usize;
+        let mut reads_dropped = 0usize;
         for batch_result in rcv.iter() {
             match batch_result {
                 Ok(entropy_calculation) => {
+                    let (n_windows, n_reads) =
+                        entropy_calculation.subsampled_counts();
+                    windows_subsampled += n_windows;
+                    reads_dropped += n_reads;
+                    if let Some(read_level_out) = read_level_out.as_mut() {
+                        read_level_out
+                            .write(&entropy_calculation, &chrom_id_to_name)?;
+                    }
+                    if let Some(epiallele_out) = epiallele_out.as_mut() {
+                        epiallele_out
+                            .write(&entropy_calculation, &chrom_id_to_name)?;
+                    }
+                    if let Some(run_summary) = run_summary.as_mut() {
+                        run_summary
+                            .add(&entropy_calculation, &chrom_id_to_name);
+                    }
+                    if let Some(bigwig_tracks) = bigwig_tracks.as_mut() {
+                        bigwig_tracks.add(
+                            &entropy_calculation,
+                            &chrom_id_to_name,
+                            self.drop_zeros,
+                        )?;
+                    }
                     writer.write(
                         entropy_calculation,
                         &chrom_id_to_name,
@@ -432,21 +1062,249 @@ impl MethylationEntropy {
             }
         }
 
+        if let Some(read_level_out) = read_level_out.as_mut() {
+            read_level_out.flush()?;
+        }
+        if let Some(epiallele_out) = epiallele_out.as_mut() {
+            epiallele_out.flush()?;
+        }
+        if let Some(bigwig_tracks) = bigwig_tracks {
+            bigwig_tracks.finish()?;
+        }
+        // finishes the bgzf stream so that it can be indexed
+        writer.finish()?;
+        if let Some(out_fp) = out_bed.as_ref().filter(|_| self.bgzf) {
+            build_bed_tabix_index(out_fp, csi).with_context(|| {
+                format!("failed to index windows output {out_fp:?}")
+            })?;
+        }
+
         multi_pb.clear()?;
         info!(
             "finished, {} {what} processed successfully, {} windows failed",
             rows_written.position(),
             windows_failed.position()
         );
+        if let Some(max_reads) = self.max_reads_per_window {
+            info!(
+                "{windows_subsampled} windows had more than {max_reads} reads \
+                 and were subsampled, {reads_dropped} reads were dropped from \
+                 them in total"
+            );
+        }
 
         if !failure_reasons.is_empty() {
+            run_summary::record_errors(&failure_reasons);
             let error_table = format_errors_table(&failure_reasons);
             info!("error/skip counts:\n{error_table}");
         }
+        if let Some((summary, fp)) = run_summary.zip(self.summary.as_ref()) {
+            summary.write_json(fp)?;
+            info!("wrote entropy run summary to {fp:?}");
+        }
+
+        Ok(())
+    }
+
+    fn run_bedmethyl(&self, bedmethyl_fp: &PathBuf) -> anyhow::Result<()> {
+        if self.entropy_norm == EntropyNorm::reads {
+            bail!(
+                "--entropy-norm reads cannot be used with --in-bedmethyl, the \
+                 number of reads in each window is not known"
+            )
+        }
+        let combine_strands = self.cpg || self.combine_strands;
+        if combine_strands {
+            info!("combining (+)-strand and (-)-strand CpG records");
+        }
+        let index = BedMethylTbxIndex::from_path(bedmethyl_fp)?;
+        let out_fp = match self.out_dir.as_ref() {
+            Some(out_dir) => Some(standard_output_path(
+                out_dir,
+                self.prefix.as_ref(),
+                "entropy.bed",
+            )),
+            None => self.out_bed.clone(),
+        };
+        let mut out: Box<dyn Write> = match out_fp.as_ref() {
+            Some(fp) => {
+                if fp.exists() && !self.force {
+                    bail!("refusing to overwrite {fp:?}")
+                }
+                create_out_directory(fp)?;
+                Box::new(BufWriter::new(File::create(fp)?))
+            }
+            None => Box::new(BufWriter::new(std::io::stdout())),
+        };
+        if self.header {
+            out.write_all(BEDMETHYL_WINDOWS_HEADER.as_bytes())?;
+        }
+        if self.miller_madow {
+            info!("adding Miller-Madow bias correction");
+        }
+
+        let calculator = BedMethylEntropy::new(
+            self.num_positions,
+            self.window_size,
+            self.min_valid_coverage as u64,
+            combine_strands,
+            self.entropy_norm,
+            self.miller_madow,
+        );
+        let io_threads = self.io_threads.unwrap_or(self.threads);
+        let (mut windows, mut sites, mut low_coverage_sites) = (0, 0, 0);
+        for chrom in index.get_contigs_in_order() {
+            let records = index.iter_contig(&chrom, io_threads)?;
+            let counts = calculator
+                .process_contig(&chrom, records, self.drop_zeros, &mut out)
+                .with_context(|| format!("failed to process {chrom}"))?;
+            debug!(
+                "{chrom}: {} windows from {} positions",
+                counts.windows_written, counts.sites
+            );
+            windows += counts.windows_written;
+            sites += counts.sites;
+            low_coverage_sites += counts.low_coverage_sites;
+        }
+        out.flush()?;
+        info!(
+            "finished, {windows} windows written from {sites} positions, \
+             {low_coverage_sites} positions had less than {} valid coverage",
+            self.min_valid_coverage
+        );
 
         Ok(())
     }
 
+    /// Files a run would write and the files among them that are only
+    /// overwritten with `--force`.
+    fn planned_outputs(
+        &self,
+        combine_strands: bool,
+        csi: bool,
+    ) -> anyhow::Result<(Vec<String>, Vec<PathBuf>)> {
+        let region_mode = self.regions_fp.is_some() || self.cgi_auto;
+        let mut outputs = Vec::new();
+        let mut guarded = Vec::new();
+        if region_mode {
+            let Some(out_dir) = self.out_dir.as_ref().or(self.out_bed.as_ref())
+            else {
+                bail!("must provide output directory with regions")
+            };
+            let names = ["regions.bed", "windows.bedgraph", "regions.bed12"];
+            for name in names.iter().take(if self.bed12 { 3 } else { 2 }) {
+                let fp =
+                    standard_output_path(out_dir, self.prefix.as_ref(), name);
+                outputs.push(fp.to_string_lossy().to_string());
+            }
+        } else {
+            let out_bed = match self.out_dir.as_ref() {
+                Some(out_dir) => Some(standard_output_path(
+                    out_dir,
+                    self.prefix.as_ref(),
+                    if self.bgzf { "entropy.bed.gz" } else { "entropy.bed" },
+                )),
+                None => self.out_bed.clone(),
+            };
+            match out_bed {
+                Some(fp) if self.bgzf => {
+                    let index = if csi { "csi" } else { "tbi" };
+                    outputs.push(fp.to_string_lossy().to_string());
+                    outputs.push(format!("{}.{index}", fp.to_string_lossy()));
+                    guarded.push(fp);
+                }
+                Some(fp) => outputs.push(fp.to_string_lossy().to_string()),
+                None => outputs.push("stdout".to_string()),
+            }
+        }
+        let bigwig_tracks = self.bigwig.as_ref().map(|fp| {
+            let (pos_fp, neg_fp) =
+                EntropyBigWigTracks::track_paths(fp, combine_strands);
+            std::iter::once(pos_fp).chain(neg_fp)
+        });
+        for fp in [
+            self.failed_windows.as_ref(),
+            self.summary.as_ref(),
+            self.read_level_out.as_ref(),
+            self.report_epialleles.as_ref(),
+        ]
+        .into_iter()
+        .flatten()
+        .cloned()
+        .chain(bigwig_tracks.into_iter().flatten())
+        {
+            outputs.push(fp.to_string_lossy().to_string());
+            guarded.push(fp);
+        }
+        Ok((outputs, guarded))
+    }
+
+    /// Check the outputs before doing any work, existing files are only
+    /// overwritten with `--force`.
+    fn check_outputs(&self, combine_strands: bool) -> anyhow::Result<()> {
+        if self.force {
+            return Ok(());
+        }
+        let (_, guarded) = self.planned_outputs(combine_strands, false)?;
+        if let Some(fp) = guarded.iter().find(|fp| fp.exists()) {
+            bail!("refusing to overwrite {fp:?}, use --force")
+        }
+        Ok(())
+    }
+
+    /// Print what a run would do, see `--dry-run`.
+    #[allow(clippy::too_many_arguments)]
+    fn print_dry_run_plan(
+        &self,
+        pool: &rayon::ThreadPool,
+        reference_fasta: &Path,
+        motif_labels: &str,
+        total_length: usize,
+        batch_size: usize,
+        combine_strands: bool,
+        csi: bool,
+    ) -> anyhow::Result<()> {
+        let mut plan = DryRunPlan::new("entropy");
+        plan.add_step(
+            "inputs",
+            self.in_bams.iter().map(|fp| fp.to_string_lossy()).join(", "),
+        );
+        plan.add_step(
+            "reference",
+            reference_fasta.to_string_lossy().to_string(),
+        );
+        let strands = if combine_strands { ", strands combined" } else { "" };
+        plan.add_step("motifs", format!("{motif_labels}{strands}"));
+        let what = if self.regions_fp.is_some() || self.cgi_auto {
+            "regions"
+        } else {
+            "windows"
+        };
+        plan.add_step(
+            what,
+            format!(
+                "{total_length} positions, windows of {} motif positions in \
+                 batches of up to {batch_size}",
+                self.num_positions
+            ),
+        );
+        let thresholds = if self.no_filtering || self.filter_threshold.is_some()
+        {
+            self.get_threshold_caller(pool)?.describe()
+        } else {
+            format!(
+                "estimated from ~{} sampled reads with filter percentile {}",
+                self.num_reads, self.filter_percentile
+            )
+        };
+        plan.add_step("thresholds", thresholds);
+        let (outputs, _) = self.planned_outputs(combine_strands, csi)?;
+        for output in outputs {
+            plan.add_output(output);
+        }
+        plan.print()
+    }
+
     fn get_threshold_caller(
         &self,
         pool: &rayon::ThreadPool,
@@ -458,6 +1316,10 @@ impl MethylationEntropy {
                 parse_per_mod_thresholds(raw_per_mod_thresholds)
             })
             .transpose()?;
+        if self.no_filtering {
+            info!("not performing filtering");
+            return Ok(MultipleThresholdModCaller::new_passthrough());
+        }
         if let Some(base_threshold) = self.filter_threshold {
             info!("using threshold {base_threshold}");
             if let Some(mod_thresholds) = per_mod_thresholds.as_ref() {
@@ -477,6 +1339,7 @@ impl MethylationEntropy {
                 for in_bam in self.in_bams.iter() {
                     let per_base_thresholds = get_modbase_probs_from_bam(
                         in_bam,
+                        self.reference_fasta.as_ref(),
                         self.threads,
                         1_000_000,
                         None,
@@ -487,6 +1350,7 @@ impl MethylationEntropy {
                         None,
                         None,
                         true,
+                        None,
                         self.suppress_progress,
                     )?;
                     agg.op_mut(per_base_thresholds);
@@ -504,6 +1368,11 @@ impl MethylationEntropy {
                     })
                     .collect::<anyhow::Result<HashMap<DnaBase, f32>>>()?;
                 log_calculated_thresholds(&per_base_thresholds);
+                if let Some(mod_thresholds) = per_mod_thresholds.as_ref() {
+                    mod_thresholds.iter().for_each(|(code, val)| {
+                        info!("using threshold value {val} for mod-code {code}")
+                    });
+                }
                 Ok(MultipleThresholdModCaller::new(
                     per_base_thresholds,
                     per_mod_thresholds.unwrap_or(HashMap::new()),
@@ -0,0 +1,220 @@
+//! Run-level summary of an entropy run, the number of windows on each contig
+//! that were scored or failed (by reason) and a histogram of window
+//! coverage, so that runs can be checked without parsing the debug log.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+use anyhow::Context;
+
+use crate::entropy::{EntropyCalculation, MethylationEntropy};
+use crate::errs::{MkError, MkResult};
+use crate::util::create_out_directory;
+use crate::util::json::json_object;
+
+#[derive(Default, Debug, PartialEq, Eq)]
+struct ContigWindowCounts {
+    scored: u64,
+    zero_coverage: u64,
+    insufficient_coverage: u64,
+    other_failures: u64,
+}
+
+impl ContigWindowCounts {
+    fn to_json(&self) -> String {
+        json_object(&[
+            ("scored", self.scored.to_string()),
+            ("zero_coverage", self.zero_coverage.to_string()),
+            ("insufficient_coverage", self.insufficient_coverage.to_string()),
+            ("other", self.other_failures.to_string()),
+        ])
+    }
+}
+
+/// Counts of windows (per strand) by contig and coverage. The coverage of a
+/// scored window is the number of reads used, the coverage of a window that
+/// failed is the smallest valid coverage over its positions.
+#[derive(Default)]
+pub(super) struct EntropyRunSummary {
+    contig_counts: BTreeMap<String, ContigWindowCounts>,
+    coverage_hist: BTreeMap<u64, u64>,
+}
+
+impl EntropyRunSummary {
+    pub(super) fn add(
+        &mut self,
+        entropy_calculation: &EntropyCalculation,
+        chrom_id_to_name: &HashMap<u32, String>,
+    ) {
+        let window_entropies = match entropy_calculation {
+            EntropyCalculation::Windows(window_entropies) => window_entropies,
+            EntropyCalculation::Region(region_entropy) => {
+                &region_entropy.window_entropies
+            }
+        };
+        for window_entropy in window_entropies {
+            let chrom = chrom_id_to_name
+                .get(&window_entropy.chrom_id)
+                .cloned()
+                .unwrap_or_else(|| window_entropy.chrom_id.to_string());
+            for me_entropy in [
+                window_entropy.pos_me_entropy.as_ref(),
+                window_entropy.neg_me_entropy.as_ref(),
+            ]
+            .into_iter()
+            .flatten()
+            {
+                self.add_window(&chrom, me_entropy);
+            }
+        }
+    }
+
+    fn add_window(
+        &mut self,
+        chrom: &str,
+        me_entropy: &MkResult<MethylationEntropy>,
+    ) {
+        if !self.contig_counts.contains_key(chrom) {
+            self.contig_counts
+                .insert(chrom.to_string(), ContigWindowCounts::default());
+        }
+        let counts = self.contig_counts.get_mut(chrom).unwrap();
+        let coverage = match me_entropy {
+            Ok(me_entropy) => {
+                counts.scored += 1;
+                Some(me_entropy.num_reads as u64)
+            }
+            Err(MkError::EntropyZeroCoverage { .. }) => {
+                counts.zero_coverage += 1;
+                Some(0)
+            }
+            Err(MkError::EntropyInsufficientCoverage {
+                min_coverage, ..
+            }) => {
+                counts.insufficient_coverage += 1;
+                Some(*min_coverage as u64)
+            }
+            Err(_) => {
+                counts.other_failures += 1;
+                None
+            }
+        };
+        if let Some(coverage) = coverage {
+            *self.coverage_hist.entry(coverage).or_insert(0) += 1;
+        }
+    }
+
+    fn to_json(&self) -> String {
+        let total = self.contig_counts.values().fold(
+            ContigWindowCounts::default(),
+            |mut acc, counts| {
+                acc.scored += counts.scored;
+                acc.zero_coverage += counts.zero_coverage;
+                acc.insufficient_coverage += counts.insufficient_coverage;
+                acc.other_failures += counts.other_failures;
+                acc
+            },
+        );
+        let contigs = self
+            .contig_counts
+            .iter()
+            .map(|(chrom, counts)| (chrom.as_str(), counts.to_json()))
+            .collect::<Vec<(&str, String)>>();
+        let coverage_hist = self
+            .coverage_hist
+            .iter()
+            .map(|(coverage, count)| (coverage.to_string(), count.to_string()))
+            .collect::<Vec<(String, String)>>();
+        let coverage_hist = coverage_hist
+            .iter()
+            .map(|(coverage, count)| (coverage.as_str(), count.clone()))
+            .collect::<Vec<(&str, String)>>();
+        json_object(&[
+            ("windows", total.to_json()),
+            ("contigs", json_object(&contigs)),
+            ("coverage_histogram", json_object(&coverage_hist)),
+        ])
+    }
+
+    /// Write the summary as JSON to `out_fp`.
+    pub(super) fn write_json(&self, out_fp: &Path) -> anyhow::Result<()> {
+        create_out_directory(out_fp)?;
+        let fh = File::create(out_fp).with_context(|| {
+            format!("failed to create entropy summary at {out_fp:?}")
+        })?;
+        let mut writer = BufWriter::new(fh);
+        writeln!(writer, "{}", self.to_json())?;
+        writer.flush()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod entropy_summary_tests {
+    use crate::entropy::summary::{ContigWindowCounts, EntropyRunSummary};
+    use crate::entropy::MethylationEntropy;
+    use crate::errs::MkError;
+
+    #[test]
+    fn test_entropy_summary_counts() {
+        let mut summary = EntropyRunSummary::default();
+        summary
+            .add_window("chr1", &Ok(MethylationEntropy::new(0.5, 10, 0..10)));
+        summary.add_window(
+            "chr1",
+            &Err(MkError::EntropyZeroCoverage {
+                chrom_id: 0,
+                start: 10,
+                end: 20,
+            }),
+        );
+        summary.add_window(
+            "chr2",
+            &Err(MkError::EntropyInsufficientCoverage {
+                chrom_id: 1,
+                start: 0,
+                end: 10,
+                min_coverage: 2,
+                max_coverage: 8,
+            }),
+        );
+        summary
+            .add_window("chr2", &Ok(MethylationEntropy::new(0.1, 10, 10..20)));
+        assert_eq!(
+            summary.contig_counts.get("chr1"),
+            Some(&ContigWindowCounts {
+                scored: 1,
+                zero_coverage: 1,
+                insufficient_coverage: 0,
+                other_failures: 0,
+            })
+        );
+        assert_eq!(
+            summary.to_json(),
+            concat!(
+                r#"{"windows":{"scored":2,"zero_coverage":1,"#,
+                r#""insufficient_coverage":1,"other":0},"#,
+                r#""contigs":{"chr1":{"scored":1,"zero_coverage":1,"#,
+                r#""insufficient_coverage":0,"other":0},"#,
+                r#""chr2":{"scored":1,"zero_coverage":0,"#,
+                r#""insufficient_coverage":1,"other":0}},"#,
+                r#""coverage_histogram":{"0":1,"2":1,"10":2}}"#
+            )
+        );
+    }
+
+    #[test]
+    fn test_entropy_summary_empty() {
+        let summary = EntropyRunSummary::default();
+        assert_eq!(
+            summary.to_json(),
+            concat!(
+                r#"{"windows":{"scored":0,"zero_coverage":0,"#,
+                r#""insufficient_coverage":0,"other":0},"#,
+                r#""contigs":{},"coverage_histogram":{}}"#
+            )
+        );
+    }
+}
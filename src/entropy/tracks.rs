@@ -0,0 +1,163 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use log::info;
+
+use crate::bigwig::BigWigTrack;
+use crate::entropy::{EntropyCalculation, WindowEntropy};
+use crate::util::{create_out_directory, Strand};
+
+/// Writes the entropy of each window as bigWig tracks while the windows are
+/// calculated, one track when the strands are combined or one per strand.
+/// Windows can overlap, each window is trimmed to end where the next window
+/// starts, see [`BigWigTrack`].
+pub(super) struct EntropyBigWigTracks {
+    pos_track: BigWigTrack,
+    neg_track: Option<BigWigTrack>,
+}
+
+impl EntropyBigWigTracks {
+    pub(super) fn new(
+        out_fp: &Path,
+        combine_strands: bool,
+        force: bool,
+        chrom_sizes: HashMap<String, u32>,
+        threads: usize,
+    ) -> anyhow::Result<Self> {
+        let (pos_out_fp, neg_out_fp) =
+            Self::track_paths(out_fp, combine_strands);
+        for fp in std::iter::once(&pos_out_fp).chain(neg_out_fp.as_ref()) {
+            create_out_directory(fp)?;
+            if fp.exists() && !force {
+                anyhow::bail!("refusing to overwrite existing file {fp:?}")
+            }
+        }
+        let neg_track = neg_out_fp
+            .map(|fp| BigWigTrack::new(&fp, chrom_sizes.clone(), threads));
+        Ok(Self {
+            pos_track: BigWigTrack::new(&pos_out_fp, chrom_sizes, threads),
+            neg_track,
+        })
+    }
+
+    /// Paths of the positive (or combined) strand track and the negative
+    /// strand track, when strands aren't combined.
+    pub(super) fn track_paths(
+        out_fp: &Path,
+        combine_strands: bool,
+    ) -> (PathBuf, Option<PathBuf>) {
+        if combine_strands {
+            (out_fp.to_path_buf(), None)
+        } else {
+            (
+                Self::stranded_path(out_fp, Strand::Positive),
+                Some(Self::stranded_path(out_fp, Strand::Negative)),
+            )
+        }
+    }
+
+    /// Path of the track for a strand, e.g. `entropy.bw` becomes
+    /// `entropy_positive.bw`.
+    fn stranded_path(out_fp: &Path, strand: Strand) -> PathBuf {
+        let strand_label = match strand {
+            Strand::Positive => "positive",
+            Strand::Negative => "negative",
+        };
+        let stem = out_fp
+            .file_stem()
+            .map(|s| s.to_string_lossy().to_string())
+            .unwrap_or_default();
+        let file_name = match out_fp.extension() {
+            Some(ext) => {
+                format!("{stem}_{strand_label}.{}", ext.to_string_lossy())
+            }
+            None => format!("{stem}_{strand_label}"),
+        };
+        out_fp.with_file_name(file_name)
+    }
+
+    pub(super) fn add(
+        &mut self,
+        entropy_calculation: &EntropyCalculation,
+        chrom_id_to_name: &HashMap<u32, String>,
+        drop_zeros: bool,
+    ) -> anyhow::Result<()> {
+        let window_entropies = match entropy_calculation {
+            EntropyCalculation::Windows(window_entropies) => window_entropies,
+            EntropyCalculation::Region(region_entropy) => {
+                &region_entropy.window_entropies
+            }
+        };
+        for window_entropy in window_entropies {
+            self.add_window(window_entropy, chrom_id_to_name, drop_zeros)?;
+        }
+        Ok(())
+    }
+
+    fn add_window(
+        &mut self,
+        window_entropy: &WindowEntropy,
+        chrom_id_to_name: &HashMap<u32, String>,
+        drop_zeros: bool,
+    ) -> anyhow::Result<()> {
+        let Some(chrom) = chrom_id_to_name.get(&window_entropy.chrom_id) else {
+            return Ok(());
+        };
+        for (track, me_entropy) in [
+            (Some(&mut self.pos_track), window_entropy.pos_me_entropy.as_ref()),
+            (self.neg_track.as_mut(), window_entropy.neg_me_entropy.as_ref()),
+        ] {
+            let (Some(track), Some(Ok(me_entropy))) = (track, me_entropy)
+            else {
+                continue;
+            };
+            if drop_zeros && me_entropy.me_entropy == 0f32 {
+                continue;
+            }
+            track.add(
+                chrom,
+                me_entropy.interval.start as u32,
+                me_entropy.interval.end as u32,
+                me_entropy.me_entropy,
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Finish writing the bigWig tracks, returns the number of intervals
+    /// written.
+    pub(super) fn finish(self) -> anyhow::Result<usize> {
+        let mut n_intervals = 0usize;
+        for track in std::iter::once(self.pos_track).chain(self.neg_track) {
+            n_intervals += track.finish()?;
+        }
+        info!("wrote {n_intervals} interval(s) to entropy bigWig track(s)");
+        Ok(n_intervals)
+    }
+}
+
+#[cfg(test)]
+mod entropy_tracks_tests {
+    use std::path::Path;
+
+    use crate::entropy::tracks::EntropyBigWigTracks;
+    use crate::util::Strand;
+
+    #[test]
+    fn test_stranded_path() {
+        assert_eq!(
+            EntropyBigWigTracks::stranded_path(
+                Path::new("out/entropy.bw"),
+                Strand::Negative
+            ),
+            Path::new("out/entropy_negative.bw")
+        );
+        assert_eq!(
+            EntropyBigWigTracks::stranded_path(
+                Path::new("entropy"),
+                Strand::Positive
+            ),
+            Path::new("entropy_positive")
+        );
+    }
+}
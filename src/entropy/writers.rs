@@ -1,15 +1,226 @@
-use crate::entropy::{EntropyCalculation, WindowEntropy};
+use crate::entropy::{
+    EntropyCalculation, MethylationEntropy, ShuffledNull, WindowEntropy,
+};
 use crate::errs::MkError;
-use crate::util::{Strand, TAB};
+use crate::mod_base_code::ModCodeRepr;
+use crate::threshold_mod_caller::MultipleThresholdModCaller;
+use crate::util::{standard_output_path, Strand, MISSING_SYMBOL, TAB};
+use crate::writers::{new_bgzf_writer, FinishWrite};
 use anyhow::{anyhow, bail};
+use gzp::deflate::Bgzf;
+use gzp::par::compress::ParCompress;
 use indicatif::ProgressBar;
+use itertools::Itertools;
 use log::debug;
 use rustc_hash::FxHashMap;
 use std::collections::HashMap;
 use std::fs::File;
 use std::io::{stdout, BufWriter, Write};
-use std::ops::AddAssign;
-use std::path::PathBuf;
+use std::ops::{AddAssign, Range};
+use std::path::{Path, PathBuf};
+
+const FAILED_WINDOWS_HEADER: &str = concat!(
+    "#chrom\tstart\tend\treason\tstrand\t",
+    "min_valid_coverage\tmax_valid_coverage\n"
+);
+
+const WINDOWS_COLUMNS: &str = concat!(
+    "#chrom\tstart\tend\tentropy\tstrand\t",
+    "num_reads\tepipolymorphism"
+);
+
+const READ_LEVEL_COLUMNS: [&str; 7] = [
+    "#chrom",
+    "start",
+    "end",
+    "strand",
+    "read_id",
+    "pattern",
+    "entropy_contribution",
+];
+
+/// Writes the encoded pattern of each read in each window and its
+/// contribution to the window's entropy, the contributions of the reads in a
+/// window sum to the entropy of the window.
+pub(super) struct ReadLevelWriter {
+    output: BufWriter<File>,
+}
+
+impl ReadLevelWriter {
+    pub(super) fn new(out_fp: &PathBuf, header: bool) -> anyhow::Result<Self> {
+        let mut output = BufWriter::new(File::create(out_fp)?);
+        if header {
+            writeln!(output, "{}", READ_LEVEL_COLUMNS.join("\t"))?;
+        }
+        Ok(Self { output })
+    }
+
+    pub(super) fn write(
+        &mut self,
+        entropy_calculation: &EntropyCalculation,
+        chrom_id_to_name: &HashMap<u32, String>,
+    ) -> anyhow::Result<()> {
+        let window_entropies = match entropy_calculation {
+            EntropyCalculation::Windows(window_entropies) => window_entropies,
+            EntropyCalculation::Region(region_entropy) => {
+                &region_entropy.window_entropies
+            }
+        };
+        for window_entropy in window_entropies {
+            let chrom = chrom_id_to_name
+                .get(&window_entropy.chrom_id)
+                .ok_or_else(|| {
+                    anyhow!(
+                        "missing chrom name for {}",
+                        window_entropy.chrom_id
+                    )
+                })?;
+            for (strand, me_entropy) in [
+                (Strand::Positive, window_entropy.pos_me_entropy.as_ref()),
+                (Strand::Negative, window_entropy.neg_me_entropy.as_ref()),
+            ] {
+                let Some(Ok(me_entropy)) = me_entropy else {
+                    continue;
+                };
+                for read_entropy in me_entropy.read_entropies.iter() {
+                    writeln!(
+                        self.output,
+                        "{chrom}{TAB}{}{TAB}{}{TAB}{}{TAB}{}{TAB}{}{TAB}{}",
+                        me_entropy.interval.start,
+                        me_entropy.interval.end,
+                        strand.to_char(),
+                        read_entropy.read_id,
+                        read_entropy.pattern,
+                        read_entropy.entropy
+                    )?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub(super) fn flush(&mut self) -> std::io::Result<()> {
+        self.output.flush()
+    }
+}
+
+const EPIALLELE_COLUMNS: [&str; 7] =
+    ["#chrom", "start", "end", "strand", "pattern", "count", "frequency"];
+
+/// Writes the number of reads with each observed pattern (epiallele) in each
+/// window, the most common pattern first.
+pub(super) struct EpialleleWriter {
+    output: BufWriter<File>,
+}
+
+impl EpialleleWriter {
+    pub(super) fn new(out_fp: &PathBuf, header: bool) -> anyhow::Result<Self> {
+        let mut output = BufWriter::new(File::create(out_fp)?);
+        if header {
+            writeln!(output, "{}", EPIALLELE_COLUMNS.join("\t"))?;
+        }
+        Ok(Self { output })
+    }
+
+    pub(super) fn write(
+        &mut self,
+        entropy_calculation: &EntropyCalculation,
+        chrom_id_to_name: &HashMap<u32, String>,
+    ) -> anyhow::Result<()> {
+        let window_entropies = match entropy_calculation {
+            EntropyCalculation::Windows(window_entropies) => window_entropies,
+            EntropyCalculation::Region(region_entropy) => {
+                &region_entropy.window_entropies
+            }
+        };
+        for window_entropy in window_entropies {
+            let chrom = chrom_id_to_name
+                .get(&window_entropy.chrom_id)
+                .ok_or_else(|| {
+                    anyhow!(
+                        "missing chrom name for {}",
+                        window_entropy.chrom_id
+                    )
+                })?;
+            for (strand, me_entropy) in [
+                (Strand::Positive, window_entropy.pos_me_entropy.as_ref()),
+                (Strand::Negative, window_entropy.neg_me_entropy.as_ref()),
+            ] {
+                let Some(Ok(me_entropy)) = me_entropy else {
+                    continue;
+                };
+                for (pattern, count) in me_entropy.epialleles.iter() {
+                    let frequency = *count as f32 / me_entropy.num_reads as f32;
+                    writeln!(
+                        self.output,
+                        "{chrom}{TAB}{}{TAB}{}{TAB}{}{TAB}{pattern}{TAB}\
+                         {count}{TAB}{frequency}",
+                        me_entropy.interval.start,
+                        me_entropy.interval.end,
+                        strand.to_char(),
+                    )?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub(super) fn flush(&mut self) -> std::io::Result<()> {
+        self.output.flush()
+    }
+}
+
+/// Make a row for the failed windows BED, only windows that failed because
+/// of coverage have an interval to report.
+fn failed_window_row(
+    chrom: &str,
+    err: &MkError,
+    strand: Strand,
+) -> Option<String> {
+    let (start, end, min_coverage, max_coverage) = match err {
+        MkError::EntropyZeroCoverage { start, end, .. } => (*start, *end, 0, 0),
+        MkError::EntropyInsufficientCoverage {
+            start,
+            end,
+            min_coverage,
+            max_coverage,
+            ..
+        } => (*start, *end, *min_coverage, *max_coverage),
+        _ => return None,
+    };
+    Some(format!(
+        "{chrom}{TAB}{start}{TAB}{end}{TAB}{err}{TAB}{}{TAB}{min_coverage}\
+         {TAB}{max_coverage}\n",
+        strand.to_char()
+    ))
+}
+
+/// Open the optional BED of windows that failed.
+pub(super) fn failed_windows_writer(
+    out_fp: &PathBuf,
+    header: bool,
+) -> anyhow::Result<BufWriter<File>> {
+    let mut output = BufWriter::new(File::create(out_fp)?);
+    if header {
+        output.write_all(FAILED_WINDOWS_HEADER.as_bytes())?;
+    }
+    Ok(output)
+}
+
+/// Extra tab-separated columns for each of the `--per-mod-code` codes (or
+/// `--quantiles`), each value is prefixed with a tab and `prefix`. Empty when
+/// there are no values.
+pub(super) fn mod_code_columns<T: std::fmt::Display>(
+    prefix: &str,
+    values: impl IntoIterator<Item = T>,
+) -> String {
+    values.into_iter().fold(String::new(), |mut acc, value| {
+        acc.push(TAB);
+        acc.push_str(prefix);
+        acc.push_str(&value.to_string());
+        acc
+    })
+}
 
 #[inline(always)]
 fn write_entropy_windows<T: Write>(
@@ -21,6 +232,7 @@ fn write_entropy_windows<T: Write>(
     failure_counter: &ProgressBar,
     failure_reasons: &mut FxHashMap<String, usize>,
     verbose: bool,
+    mut failed_out: Option<&mut BufWriter<File>>,
 ) -> anyhow::Result<()> {
     for entropy in window_entropies {
         let name =
@@ -33,12 +245,16 @@ fn write_entropy_windows<T: Write>(
                     || !drop_zeros
                 {
                     let row = format!(
-                        "{name}\t{}\t{}\t{}\t{}\t{}\n",
+                        "{name}\t{}\t{}\t{}\t{}\t{}\t{}{}{}{}\n",
                         pos_entropy.interval.start,
                         pos_entropy.interval.end,
                         pos_entropy.me_entropy,
                         Strand::Positive.to_char(),
-                        pos_entropy.num_reads
+                        pos_entropy.num_reads,
+                        pos_entropy.epipolymorphism,
+                        mod_code_columns("", &pos_entropy.mod_code_entropies),
+                        confidence_interval_columns(pos_entropy.entropy_ci),
+                        shuffled_null_columns(pos_entropy.shuffled_null),
                     );
                     writer.write(&row.as_bytes())?;
                     write_counter.inc(1);
@@ -70,23 +286,34 @@ fn write_entropy_windows<T: Write>(
                                 chrom_id,
                                 start,
                                 end,
+                                min_coverage,
+                                ..
                             } => {
                                 if let Some(chrom) =
                                     chrom_id_to_name.get(chrom_id)
                                 {
                                     debug!(
-                                        "{chrom}:{start}-{end}: zero coverage"
+                                        "{chrom}:{start}-{end}: insufficient \
+                                         coverage ({min_coverage})"
                                     );
                                 } else {
                                     debug!(
-                                        "{chrom_id}:{start}-{end}: zero \
-                                         coverage"
+                                        "{chrom_id}:{start}-{end}: \
+                                         insufficient coverage \
+                                         ({min_coverage})"
                                     );
                                 }
                             }
                             _ => {}
                         }
                     }
+                    if let Some(failed_out) = failed_out.as_mut() {
+                        if let Some(row) =
+                            failed_window_row(name, e, Strand::Positive)
+                        {
+                            failed_out.write_all(row.as_bytes())?;
+                        }
+                    }
                     failure_counter.inc(1);
                     failure_reasons
                         .entry(e.to_string())
@@ -103,18 +330,29 @@ fn write_entropy_windows<T: Write>(
                     || !drop_zeros
                 {
                     let row = format!(
-                        "{name}\t{}\t{}\t{}\t{}\t{}\n",
+                        "{name}\t{}\t{}\t{}\t{}\t{}\t{}{}{}{}\n",
                         neg_entropy.interval.start,
                         neg_entropy.interval.end,
                         neg_entropy.me_entropy,
                         Strand::Negative.to_char(),
-                        neg_entropy.num_reads
+                        neg_entropy.num_reads,
+                        neg_entropy.epipolymorphism,
+                        mod_code_columns("", &neg_entropy.mod_code_entropies),
+                        confidence_interval_columns(neg_entropy.entropy_ci),
+                        shuffled_null_columns(neg_entropy.shuffled_null),
                     );
                     writer.write(&row.as_bytes())?;
                     write_counter.inc(1);
                 }
             }
             Some(Err(e)) => {
+                if let Some(failed_out) = failed_out.as_mut() {
+                    if let Some(row) =
+                        failed_window_row(name, e, Strand::Negative)
+                    {
+                        failed_out.write_all(row.as_bytes())?;
+                    }
+                }
                 failure_counter.inc(1);
                 failure_reasons
                     .entry(e.to_string())
@@ -127,6 +365,52 @@ fn write_entropy_windows<T: Write>(
     Ok(())
 }
 
+/// Make a BED12 record for a region, the blocks are the (merged) windows
+/// that were successfully measured. The 13th column has the mean entropy of
+/// the windows in each block.
+fn region_bed12_row(
+    chrom: &str,
+    region_name: &str,
+    strand: Strand,
+    windows: &[&MethylationEntropy],
+) -> Option<String> {
+    let mut windows = windows.to_vec();
+    windows.sort_by_key(|w| (w.interval.start, w.interval.end));
+    let mut blocks: Vec<(Range<u64>, Vec<f32>)> = Vec::new();
+    for window in windows {
+        match blocks.last_mut() {
+            Some((block, entropies)) if window.interval.start <= block.end => {
+                block.end = std::cmp::max(block.end, window.interval.end);
+                entropies.push(window.me_entropy);
+            }
+            _ => {
+                blocks.push((window.interval.clone(), vec![window.me_entropy]))
+            }
+        }
+    }
+    let start = blocks.first()?.0.start;
+    let end = blocks.last()?.0.end;
+    let n_windows = blocks.iter().map(|(_, es)| es.len()).sum::<usize>();
+    let mean_entropy = blocks.iter().flat_map(|(_, es)| es.iter()).sum::<f32>()
+        / n_windows as f32;
+    let score = (mean_entropy * 1000f32).round().clamp(0f32, 1000f32) as u32;
+    let block_sizes =
+        blocks.iter().map(|(b, _)| (b.end - b.start).to_string()).join(",");
+    let block_starts =
+        blocks.iter().map(|(b, _)| (b.start - start).to_string()).join(",");
+    let block_entropies = blocks
+        .iter()
+        .map(|(_, es)| (es.iter().sum::<f32>() / es.len() as f32).to_string())
+        .join(",");
+    Some(format!(
+        "{chrom}{TAB}{start}{TAB}{end}{TAB}{region_name}{TAB}{score}{TAB}{}\
+         {TAB}{start}{TAB}{end}{TAB}0,0,0{TAB}{}{TAB}{block_sizes}{TAB}\
+         {block_starts}{TAB}{block_entropies}\n",
+        strand.to_char(),
+        blocks.len(),
+    ))
+}
+
 pub(super) trait EntropyWriter {
     fn write(
         &mut self,
@@ -137,54 +421,177 @@ pub(super) trait EntropyWriter {
         failure_counter: &ProgressBar,
         failure_reasons: &mut FxHashMap<String, usize>,
     ) -> anyhow::Result<()>;
+    /// Called once after the last calculation has been written.
+    fn finish(&mut self) -> anyhow::Result<()>;
+}
+
+/// Column names for the windows output, with an `entropy_<code>` column
+/// for each of the `per_mod_codes`, the bounds of the confidence interval
+/// with `--bootstrap`, and the shuffled null with `--shuffle-null`.
+fn windows_header(
+    per_mod_codes: &[ModCodeRepr],
+    confidence_intervals: bool,
+    shuffle_null: bool,
+) -> String {
+    let mod_code_columns = mod_code_columns("entropy_", per_mod_codes);
+    let ci_columns = if confidence_intervals {
+        format!("{TAB}entropy_lower_ci{TAB}entropy_upper_ci")
+    } else {
+        String::new()
+    };
+    let null_columns = if shuffle_null {
+        format!("{TAB}null_entropy{TAB}entropy_z_score")
+    } else {
+        String::new()
+    };
+    format!("{WINDOWS_COLUMNS}{mod_code_columns}{ci_columns}{null_columns}\n")
+}
+
+/// The bounds of the `--bootstrap` confidence interval, each prefixed with
+/// a tab. Empty without `--bootstrap`.
+fn confidence_interval_columns(entropy_ci: Option<(f32, f32)>) -> String {
+    entropy_ci
+        .map(|(lower, upper)| format!("{TAB}{lower}{TAB}{upper}"))
+        .unwrap_or_default()
+}
+
+/// The mean entropy of the `--shuffle-null` shuffles and the z-score of the
+/// observed entropy, each prefixed with a tab. The z-score is "." when the
+/// shuffles all have the same entropy. Empty without `--shuffle-null`.
+fn shuffled_null_columns(shuffled_null: Option<ShuffledNull>) -> String {
+    shuffled_null
+        .map(|null| {
+            let z_score = null
+                .z_score
+                .map(|z| z.to_string())
+                .unwrap_or_else(|| MISSING_SYMBOL.to_string());
+            format!("{TAB}{}{TAB}{z_score}", null.mean_entropy)
+        })
+        .unwrap_or_default()
 }
 
-const WINDOWS_HEADER: &'static str = "\
-        #chrom\tstart\tend\tentropy\tstrand\tnum_reads\n";
+/// Comment line written above the column names recording the thresholds
+/// used to filter base modification calls, see
+/// [`MultipleThresholdModCaller::describe`].
+pub(super) fn thresholds_comment(
+    caller: &MultipleThresholdModCaller,
+) -> String {
+    format!("#thresholds: {}\n", caller.describe())
+}
 
 pub(super) struct WindowsWriter<T: Write> {
     output: BufWriter<T>,
     verbose: bool,
+    failed_out: Option<BufWriter<File>>,
+}
+
+impl<T: Write> WindowsWriter<T> {
+    /// Also write windows that failed to this BED.
+    pub(super) fn with_failed_windows(
+        self,
+        failed_out: Option<BufWriter<File>>,
+    ) -> Self {
+        Self { failed_out, ..self }
+    }
 }
 
 impl WindowsWriter<File> {
     pub(super) fn new_file(
         out_fp: &PathBuf,
-        header: bool,
+        header: Option<&str>,
+        per_mod_codes: &[ModCodeRepr],
+        confidence_intervals: bool,
+        shuffle_null: bool,
         verbose: bool,
     ) -> anyhow::Result<Self> {
         let mut output = BufWriter::new(File::create(out_fp)?);
-        if header {
-            output.write(WINDOWS_HEADER.as_bytes())?;
+        if let Some(comment) = header {
+            output.write_all(comment.as_bytes())?;
+            output.write(
+                windows_header(
+                    per_mod_codes,
+                    confidence_intervals,
+                    shuffle_null,
+                )
+                .as_bytes(),
+            )?;
         }
-        Ok(Self { output, verbose })
+        Ok(Self { output, verbose, failed_out: None })
+    }
+}
+
+impl WindowsWriter<ParCompress<Bgzf>> {
+    /// Write the windows bgzip-compressed so that they can be indexed with
+    /// tabix once the writer is finished.
+    pub(super) fn new_bgzf(
+        out_fp: &Path,
+        force: bool,
+        threads: usize,
+        header: Option<&str>,
+        per_mod_codes: &[ModCodeRepr],
+        confidence_intervals: bool,
+        shuffle_null: bool,
+        verbose: bool,
+    ) -> anyhow::Result<Self> {
+        let mut output =
+            BufWriter::new(new_bgzf_writer(out_fp, force, threads)?);
+        if let Some(comment) = header {
+            output.write_all(comment.as_bytes())?;
+            output.write_all(
+                windows_header(
+                    per_mod_codes,
+                    confidence_intervals,
+                    shuffle_null,
+                )
+                .as_bytes(),
+            )?;
+        }
+        Ok(Self { output, verbose, failed_out: None })
     }
 }
 
 impl WindowsWriter<std::io::Stdout> {
     pub(super) fn new_stdout(
-        header: bool,
+        header: Option<&str>,
+        per_mod_codes: &[ModCodeRepr],
+        confidence_intervals: bool,
+        shuffle_null: bool,
         verbose: bool,
     ) -> anyhow::Result<Self> {
         let mut output = BufWriter::new(stdout());
-        if header {
-            output.write(WINDOWS_HEADER.as_bytes())?;
+        if let Some(comment) = header {
+            output.write_all(comment.as_bytes())?;
+            output.write(
+                windows_header(
+                    per_mod_codes,
+                    confidence_intervals,
+                    shuffle_null,
+                )
+                .as_bytes(),
+            )?;
         }
-        Ok(Self { output, verbose })
+        Ok(Self { output, verbose, failed_out: None })
     }
 }
 
 pub(super) struct RegionsWriter {
     regions_bed_out: BufWriter<File>,
     windows_bed_out: BufWriter<File>,
+    regions_bed12_out: Option<BufWriter<File>>,
     verbose: bool,
+    failed_out: Option<BufWriter<File>>,
 }
 
 impl RegionsWriter {
     pub(super) fn new(
         out_dir: &PathBuf,
         prefix: Option<&String>,
-        header: bool,
+        header: Option<&str>,
+        per_mod_codes: &[ModCodeRepr],
+        quantiles: &[f32],
+        confidence_intervals: bool,
+        shuffle_null: bool,
+        bed12: bool,
         verbose: bool,
     ) -> anyhow::Result<Self> {
         if out_dir.is_file() {
@@ -192,24 +599,30 @@ impl RegionsWriter {
         }
         std::fs::create_dir_all(out_dir)?;
         debug_assert!(out_dir.exists(), "out_dir should exist now");
-        let mut regions_bed_out = if let Some(p) = prefix {
-            let fp = out_dir.join(format!("{p}_regions.bed"));
-            BufWriter::new(File::create(fp)?)
+        let mut regions_bed_out = BufWriter::new(File::create(
+            standard_output_path(out_dir, prefix, "regions.bed"),
+        )?);
+        let mut windows_bed_out = BufWriter::new(File::create(
+            standard_output_path(out_dir, prefix, "windows.bedgraph"),
+        )?);
+        let regions_bed12_out = if bed12 {
+            let fp = standard_output_path(out_dir, prefix, "regions.bed12");
+            Some(BufWriter::new(File::create(fp)?))
         } else {
-            let fp = out_dir.join("regions.bed");
-            BufWriter::new(File::create(fp)?)
+            None
         };
 
-        let mut windows_bed_out = if let Some(p) = prefix {
-            let fp = out_dir.join(format!("{p}_windows.bedgraph"));
-            BufWriter::new(File::create(fp)?)
-        } else {
-            let fp = out_dir.join("windows.bedgraph");
-            BufWriter::new(File::create(fp)?)
-        };
-
-        if header {
-            windows_bed_out.write(WINDOWS_HEADER.as_bytes())?;
+        if let Some(comment) = header {
+            windows_bed_out.write_all(comment.as_bytes())?;
+            windows_bed_out.write(
+                windows_header(
+                    per_mod_codes,
+                    confidence_intervals,
+                    shuffle_null,
+                )
+                .as_bytes(),
+            )?;
+            regions_bed_out.write_all(comment.as_bytes())?;
             regions_bed_out.write(
                 &format!(
                     "\
@@ -226,17 +639,33 @@ impl RegionsWriter {
                 min_num_reads{TAB}\
                 max_num_reads{TAB}\
                 successful_window_count{TAB}\
-                failed_window_count\n"
+                failed_window_count{}{}\n",
+                    mod_code_columns("entropy_q", quantiles),
+                    mod_code_columns("mean_entropy_", per_mod_codes)
                 )
                 .as_bytes(),
             )?;
         }
 
-        Ok(Self { windows_bed_out, regions_bed_out, verbose })
+        Ok(Self {
+            windows_bed_out,
+            regions_bed_out,
+            regions_bed12_out,
+            verbose,
+            failed_out: None,
+        })
+    }
+
+    /// Also write windows that failed to this BED.
+    pub(super) fn with_failed_windows(
+        self,
+        failed_out: Option<BufWriter<File>>,
+    ) -> Self {
+        Self { failed_out, ..self }
     }
 }
 
-impl<T: Write> EntropyWriter for WindowsWriter<T> {
+impl<T: FinishWrite> EntropyWriter for WindowsWriter<T> {
     fn write(
         &mut self,
         entropy_calculation: EntropyCalculation,
@@ -257,12 +686,19 @@ impl<T: Write> EntropyWriter for WindowsWriter<T> {
                     failure_counter,
                     failure_reasons,
                     self.verbose,
+                    self.failed_out.as_mut(),
                 )?;
             }
             EntropyCalculation::Region(_) => bail!("shouldn't have regions"),
         }
         Ok(())
     }
+    fn finish(&mut self) -> anyhow::Result<()> {
+        if let Some(failed_out) = self.failed_out.as_mut() {
+            failed_out.flush()?;
+        }
+        self.output.finish_write()
+    }
 }
 
 impl EntropyWriter for RegionsWriter {
@@ -285,7 +721,7 @@ impl EntropyWriter for RegionsWriter {
                 let end = region_entropy.interval.end;
                 let region_name = region_entropy.region_name;
                 match region_entropy.pos_entropy_stats {
-                    Ok(pos_entropy_stats) => {
+                    Some(Ok(pos_entropy_stats)) => {
                         let row = pos_entropy_stats.to_row(
                             &chrom,
                             start,
@@ -296,13 +732,14 @@ impl EntropyWriter for RegionsWriter {
                         self.regions_bed_out.write(row.as_bytes())?;
                         write_counter.inc(1);
                     }
-                    Err(e) => {
+                    Some(Err(e)) => {
                         failure_counter.inc(1);
                         failure_reasons
                             .entry(e.to_string())
                             .or_insert(0usize)
                             .add_assign(1usize);
                     }
+                    None => {}
                 }
                 match region_entropy.neg_entropy_stats {
                     Some(Ok(neg_entropy_stats)) => {
@@ -328,6 +765,38 @@ impl EntropyWriter for RegionsWriter {
                     }
                     None => {}
                 }
+                if let Some(bed12_out) = self.regions_bed12_out.as_mut() {
+                    let strand_windows = [
+                        (
+                            Strand::Positive,
+                            region_entropy
+                                .window_entropies
+                                .iter()
+                                .filter_map(|w| w.pos_me_entropy.as_ref())
+                                .filter_map(|r| r.as_ref().ok())
+                                .collect::<Vec<&MethylationEntropy>>(),
+                        ),
+                        (
+                            Strand::Negative,
+                            region_entropy
+                                .window_entropies
+                                .iter()
+                                .filter_map(|w| w.neg_me_entropy.as_ref())
+                                .filter_map(|r| r.as_ref().ok())
+                                .collect::<Vec<&MethylationEntropy>>(),
+                        ),
+                    ];
+                    for (strand, windows) in strand_windows {
+                        if let Some(row) = region_bed12_row(
+                            chrom,
+                            &region_name,
+                            strand,
+                            &windows,
+                        ) {
+                            bed12_out.write_all(row.as_bytes())?;
+                        }
+                    }
+                }
                 write_entropy_windows(
                     &mut self.windows_bed_out,
                     &region_entropy.window_entropies,
@@ -337,6 +806,7 @@ impl EntropyWriter for RegionsWriter {
                     failure_counter,
                     failure_reasons,
                     self.verbose,
+                    self.failed_out.as_mut(),
                 )?;
             }
             EntropyCalculation::Windows(_) => {
@@ -346,4 +816,15 @@ impl EntropyWriter for RegionsWriter {
 
         Ok(())
     }
+    fn finish(&mut self) -> anyhow::Result<()> {
+        self.regions_bed_out.flush()?;
+        self.windows_bed_out.flush()?;
+        if let Some(bed12_out) = self.regions_bed12_out.as_mut() {
+            bed12_out.flush()?;
+        }
+        if let Some(failed_out) = self.failed_out.as_mut() {
+            failed_out.flush()?;
+        }
+        Ok(())
+    }
 }
@@ -1,4 +1,7 @@
 use std::string::FromUtf8Error;
+use std::time::Duration;
+
+use log::warn;
 
 pub type MkResult<T, E = MkError> = Result<T, E>;
 
@@ -19,6 +22,8 @@ pub enum MkError {
     InvalidSkipMode,
     #[error("non-primary-no-MN")]
     NonPrimaryMissingMn,
+    #[error("MM-MN-mismatch")]
+    MmMnMismatch(String),
     #[error("aux-data-missing")]
     AuxMissing,
     #[error("multiple-tag-instances")]
@@ -49,6 +54,8 @@ pub enum MkError {
     DmrMissing,
     #[error("invalid-bedmethyl-data")]
     InvalidBedMethyl(String),
+    #[error("reference-mismatch, {}", .0)]
+    DmrReferenceMismatch(String),
 
     // Misc
     #[error("invalid-record-name")]
@@ -68,12 +75,20 @@ pub enum MkError {
     ContigMissing(String),
     #[error("invalid-io-read")]
     InvalidIO,
+    #[error("transient-io-error, {}", .0)]
+    TransientIo(String),
 
     // Entropy
     #[error("zero-reads")]
     EntropyZeroCoverage { chrom_id: u32, start: u64, end: u64 },
     #[error("insufficient-coverage")]
-    EntropyInsufficientCoverage { chrom_id: u32, start: u64, end: u64 },
+    EntropyInsufficientCoverage {
+        chrom_id: u32,
+        start: u64,
+        end: u64,
+        min_coverage: u32,
+        max_coverage: u32,
+    },
 
     // Maths
     #[error("not enough datapoints, got {}", .0)]
@@ -86,6 +101,143 @@ pub enum MkError {
     LlrCalcError,
 }
 
+impl MkError {
+    /// Classify a failed htslib open, seek, or fetch by `os_error`, the errno
+    /// left by the failed call (see [`hts_io`]). When the OS reports a
+    /// condition that can clear up on its own (e.g. EIO, ETIMEDOUT, or ESTALE
+    /// from a network filesystem) the error is [`MkError::TransientIo`] and
+    /// can be retried, otherwise it is kept as [`MkError::HtsLibError`].
+    /// Errors reading records (e.g. a truncated file) are never transient.
+    fn from_hts_io(
+        e: rust_htslib::errors::Error,
+        os_error: std::io::Error,
+    ) -> Self {
+        use rust_htslib::errors::Error;
+        let io_error = matches!(
+            e,
+            Error::FileNotFound { .. }
+                | Error::Fetch
+                | Error::FileSeek
+                | Error::GenomicSeek { .. }
+                | Error::TabixNoIter
+                | Error::TabixInvalidIndex
+                | Error::BamOpen { .. }
+                | Error::BamInvalidIndex { .. }
+        );
+        if io_error && is_transient_os_error(&os_error) {
+            Self::TransientIo(format!("{e} ({os_error})"))
+        } else {
+            Self::HtsLibError(e)
+        }
+    }
+
+    /// Errors that may succeed when the operation is retried.
+    pub fn is_transient(&self) -> bool {
+        matches!(self, Self::TransientIo(_))
+    }
+}
+
+/// Run an htslib open, seek, or fetch, a failure is classified with
+/// [`MkError::from_hts_io`]. errno is cleared before the call so that a
+/// value left over from an earlier call on this thread isn't mistaken for
+/// the cause.
+pub(crate) fn hts_io<T>(
+    f: impl FnOnce() -> Result<T, rust_htslib::errors::Error>,
+) -> MkResult<T> {
+    errno::set_errno(errno::Errno(0));
+    f().map_err(|e| {
+        let os_error = std::io::Error::from_raw_os_error(errno::errno().0);
+        MkError::from_hts_io(e, os_error)
+    })
+}
+
+fn is_transient_os_error(os_error: &std::io::Error) -> bool {
+    use std::io::ErrorKind;
+    // EIO is 5 on all of the platforms we support and doesn't have its own
+    // ErrorKind
+    os_error.raw_os_error() == Some(5)
+        || matches!(
+            os_error.kind(),
+            ErrorKind::TimedOut
+                | ErrorKind::Interrupted
+                | ErrorKind::WouldBlock
+                | ErrorKind::ConnectionReset
+                | ErrorKind::ConnectionAborted
+                | ErrorKind::ConnectionRefused
+                | ErrorKind::NotConnected
+                | ErrorKind::BrokenPipe
+                | ErrorKind::HostUnreachable
+                | ErrorKind::NetworkUnreachable
+                | ErrorKind::NetworkDown
+                | ErrorKind::StaleNetworkFileHandle
+                | ErrorKind::ResourceBusy
+        )
+}
+
+/// How many times to retry an open or read that failed with a transient IO error
+/// ([`MkError::TransientIo`]), the wait doubles after each attempt. Other
+/// errors (e.g. a corrupt file) are returned immediately.
+#[derive(Debug, Copy, Clone)]
+pub struct IoRetry {
+    max_retries: u32,
+    backoff: Duration,
+}
+
+impl Default for IoRetry {
+    fn default() -> Self {
+        Self { max_retries: 0, backoff: Duration::from_secs(1) }
+    }
+}
+
+impl IoRetry {
+    pub(crate) fn new(
+        max_retries: u32,
+        backoff_seconds: f32,
+    ) -> anyhow::Result<Self> {
+        let backoff =
+            Duration::try_from_secs_f32(backoff_seconds).map_err(|_| {
+                anyhow::anyhow!(
+                    "invalid retry backoff {backoff_seconds}, should be a \
+                     non-negative number of seconds"
+                )
+            })?;
+        Ok(Self { max_retries, backoff })
+    }
+
+    /// Run `f` until it succeeds, fails with an error that isn't transient,
+    /// or runs out of retries. `what` describes the operation for the logs.
+    pub(crate) fn run<T>(
+        &self,
+        what: impl Fn() -> String,
+        mut f: impl FnMut() -> MkResult<T>,
+    ) -> MkResult<T> {
+        let mut attempt = 0u32;
+        loop {
+            match f() {
+                Err(e) if e.is_transient() && attempt < self.max_retries => {
+                    let wait =
+                        self.backoff.saturating_mul(1 << attempt.min(16));
+                    attempt += 1;
+                    warn!(
+                        "{}, {e}, retrying in {wait:?} ({attempt} of {})",
+                        what(),
+                        self.max_retries
+                    );
+                    std::thread::sleep(wait);
+                }
+                Err(e) if e.is_transient() && self.max_retries > 0 => {
+                    return Err(MkError::TransientIo(format!(
+                        "{}, gave up after {} retries, {e}",
+                        what(),
+                        self.max_retries
+                    )));
+                }
+                res => return res,
+            }
+        }
+    }
+}
+
 #[derive(thiserror::Error, Debug)]
 pub enum ConflictError {
     #[error("inferred-prob-greater-than-one")]
@@ -95,3 +247,74 @@ pub enum ConflictError {
     #[error("explicit-and-inferred")]
     ExplicitConflictInferred,
 }
+
+#[cfg(test)]
+mod errs_tests {
+    use crate::errs::{hts_io, IoRetry, MkError};
+
+    #[test]
+    fn test_io_retry() {
+        let retry = IoRetry::new(2, 0f32).unwrap();
+        let mut attempts = 0;
+        let res = retry.run(
+            || "reading".to_string(),
+            || {
+                attempts += 1;
+                if attempts < 3 {
+                    Err(MkError::TransientIo("timed out".to_string()))
+                } else {
+                    Ok(attempts)
+                }
+            },
+        );
+        assert_eq!(res.unwrap(), 3);
+
+        // out of retries
+        let mut attempts = 0;
+        let res = retry.run(
+            || "reading".to_string(),
+            || -> Result<(), MkError> {
+                attempts += 1;
+                Err(MkError::TransientIo("timed out".to_string()))
+            },
+        );
+        assert_eq!(attempts, 3);
+        assert!(res.unwrap_err().to_string().contains("gave up after 2"));
+
+        // errors that aren't transient, e.g. a corrupt file, aren't retried
+        let mut attempts = 0;
+        let res = retry.run(
+            || "reading".to_string(),
+            || -> Result<(), MkError> {
+                attempts += 1;
+                Err(MkError::HtsLibError(
+                    rust_htslib::errors::Error::TabixTruncatedRecord,
+                ))
+            },
+        );
+        assert_eq!(attempts, 1);
+        assert!(matches!(res, Err(MkError::HtsLibError(_))));
+        assert!(IoRetry::new(1, -1f32).is_err());
+    }
+
+    #[test]
+    fn test_hts_io_classification() {
+        use rust_htslib::errors::Error;
+        // a stale errno (EIO) from an earlier call isn't used
+        errno::set_errno(errno::Errno(5));
+        let res = hts_io(|| -> Result<(), Error> { Err(Error::Fetch) });
+        assert!(matches!(res, Err(MkError::HtsLibError(Error::Fetch))));
+        // set by the failing call
+        let res = hts_io(|| -> Result<(), Error> {
+            errno::set_errno(errno::Errno(5));
+            Err(Error::Fetch)
+        });
+        assert!(res.unwrap_err().is_transient());
+        // truncated records are never transient
+        let res = hts_io(|| -> Result<(), Error> {
+            errno::set_errno(errno::Errno(5));
+            Err(Error::BamTruncatedRecord)
+        });
+        assert!(matches!(res, Err(MkError::HtsLibError(_))));
+    }
+}
@@ -1,5 +1,20 @@
-use clap::Args;
-use std::path::PathBuf;
+use crate::command_utils::{using_stream, DryRunPlan};
+use crate::extract::features::FeatureLookup;
+use crate::extract::writer::RowOptions;
+use crate::interval_chunks::ReferenceIntervalsFeeder;
+use crate::motifs::motif_bed::AmbiguousBases;
+use crate::reads_sampler::sampling_schedule::ContigQuotas;
+use anyhow::bail;
+use clap::{Args, ValueEnum};
+use std::path::{Path, PathBuf};
+
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
+#[allow(non_camel_case_types)]
+pub(super) enum ExtractOutFormat {
+    tsv,
+    sqlite,
+    parquet,
+}
 
 #[derive(Args)]
 pub(super) struct InputArgs {
@@ -22,6 +37,19 @@ pub(super) struct InputArgs {
     #[clap(help_heading = "Compute Options")]
     #[arg(long, requires = "bgzf", default_value_t = 4)]
     pub out_threads: usize,
+    /// Output format. With `sqlite` the output file will be a SQLite database
+    /// with a single table, "extract" for `full` and "calls" for `calls`,
+    /// with the same columns as the table output and indices on (chrom,
+    /// ref_position) and read_id. With `parquet` the output file will be a
+    /// Parquet file with the same, typed, columns.
+    #[clap(help_heading = "Output Options")]
+    #[arg(
+        long,
+        value_enum,
+        default_value_t = ExtractOutFormat::tsv,
+        conflicts_with = "bgzf"
+    )]
+    pub out_format: ExtractOutFormat,
 
     /// Number of reads that can be in memory at a time. Increasing this value
     /// will increase thread usage, at the cost of memory usage.
@@ -40,10 +68,19 @@ pub(super) struct InputArgs {
     /// probabilities as additional rows. The primary alignment will have
     /// all of the base modification probabilities (including soft-clipped
     /// ones, unless --mapped-only is used). The non-primary alignments
-    /// will only have mapped bases in the output.
+    /// will only have mapped bases in the output, and soft-clipped bases
+    /// with --include-softclipped.
     #[clap(help_heading = "Selection Options")]
     #[arg(long, alias = "non-primary", default_value_t = false)]
     pub allow_non_primary: bool,
+    /// Keep calls on soft-clipped bases of mapped reads when only mapped
+    /// positions would otherwise be output, e.g. with --mapped-only,
+    /// --include-bed, a motif, or a region, for modifications in adapters
+    /// or UMIs. These calls have a ref_position of -1. Implies
+    /// --cigar-context, the calls have a cigar_context of soft_clip.
+    #[clap(help_heading = "Selection Options")]
+    #[arg(long, default_value_t = false)]
+    pub include_softclipped: bool,
     /// Number of reads to use. Note that when using a sorted, indexed modBAM
     /// that the sampling algorithm will attempt to sample records evenly
     /// over the length of the reference sequence. The result is the final
@@ -54,11 +91,40 @@ pub(super) struct InputArgs {
     #[clap(help_heading = "Selection Options")]
     #[arg(long)]
     pub num_reads: Option<usize>,
+    /// How to divide `--num-reads` between contigs when using an indexed
+    /// modBAM. reads: proportional to the number of mapped reads on each
+    /// contig, so sparse contigs (e.g. chrM or decoys) get few reads. length:
+    /// proportional to the length of each contig with mapped reads. Default
+    /// is reads for BAM and length for CRAM, using reads with a CRAM
+    /// requires counting the records first.
+    #[clap(help_heading = "Selection Options")]
+    #[arg(long, requires = "num_reads", hide_short_help = true)]
+    pub contig_quotas: Option<ContigQuotas>,
+    /// Only use mapped reads with at least this alignment identity, the
+    /// fraction of aligned columns (matches, mismatches, insertions, and
+    /// deletions) that aren't edits according to the NM tag. Reads without
+    /// an NM tag are skipped. Unmapped reads are not filtered.
+    #[clap(help_heading = "Selection Options")]
+    #[arg(long)]
+    pub min_identity: Option<f32>,
+    /// Only use mapped reads with at most this many edits (NM tag) to the
+    /// reference. Reads without an NM tag are skipped.
+    #[clap(help_heading = "Selection Options")]
+    #[arg(long)]
+    pub max_nm: Option<u32>,
     /// Process only reads that are aligned to a specified region of the BAM.
     /// Format should be <chrom_name>:<start>-<end> or <chrom_name>.
     #[clap(help_heading = "Selection Options")]
     #[arg(long)]
     pub region: Option<String>,
+    /// BED file of regions to process. Only the parts of the indexed BAM
+    /// overlapping these regions are read, reads aligned to them are output
+    /// with all of their base modification calls. Much faster than
+    /// --include-bed for targeted panels, the two can be combined to also
+    /// restrict the output positions.
+    #[clap(help_heading = "Selection Options")]
+    #[arg(long, conflicts_with_all = ["region", "ignore_index"])]
+    pub regions: Option<PathBuf>,
     /// Force overwrite of output file
     #[clap(help_heading = "Output Options")]
     #[arg(long, default_value_t = false)]
@@ -77,11 +143,44 @@ pub(super) struct InputArgs {
     #[clap(help_heading = "Compute Options")]
     #[arg(long, default_value_t = false, hide_short_help = true)]
     pub ignore_index: bool,
+    /// Validate the inputs (BAM, reference, regions, BED files, and
+    /// thresholds), print the planned intervals and output files, then exit
+    /// without writing anything. Motif positions are not searched for and
+    /// filter thresholds are not estimated in a dry run.
+    #[clap(help_heading = "Compute Options")]
+    #[arg(long, default_value_t = false)]
+    pub dry_run: bool,
 
     /// Don't print the header lines in the output tables.
     #[clap(help_heading = "Output Options")]
     #[arg(long, default_value_t = false)]
     pub no_headers: bool,
+    /// Label output for direct RNA data, thymine (T) is reported as uracil
+    /// (U) in the ref_kmer, query_kmer, canonical_base, and
+    /// modified_primary_base columns. Uracil in the reference is always
+    /// treated as thymine when matching motifs.
+    #[clap(help_heading = "Output Options")]
+    #[arg(long, default_value_t = false)]
+    pub rna: bool,
+    /// Add cigar_context and indel_distance columns describing where each
+    /// call sits in the alignment. cigar_context is one of match, insertion,
+    /// soft_clip, or "." for unmapped reads. indel_distance is the distance
+    /// in read bases to the nearest insertion or deletion (0 inside an
+    /// insertion, 1 when adjacent), or -1 when the read has none. Use these to
+    /// exclude calls near alignment artifacts downstream.
+    #[clap(help_heading = "Output Options")]
+    #[arg(long, default_value_t = false)]
+    pub cigar_context: bool,
+    /// Add feature_name, feature_start_offset, and feature_center_offset
+    /// columns with the position of each call relative to the feature it's
+    /// in, from --include-bed (or --regions), for per-read metaplots. Offsets
+    /// are in the direction of the feature, so on - strand features they're
+    /// counted from the end. Features without a name are named
+    /// chrom:start-end. When features overlap, the one with the nearest
+    /// center is used, calls outside of the features have ".".
+    #[clap(help_heading = "Output Options")]
+    #[arg(long, default_value_t = false)]
+    pub feature_coords: bool,
 
     /// BED file with regions to include (alias: include-positions). Implicitly
     /// only includes mapped sites.
@@ -111,6 +210,17 @@ pub(super) struct InputArgs {
     #[clap(help_heading = "Modified Base Options")]
     #[arg(long, requires = "motif", default_value_t = false)]
     pub annotate_motifs: bool,
+    /// When used with `--motif` or `--cpg` also output a row for each motif
+    /// position a read is aligned to but doesn't have a base modification
+    /// call at, for example when the call was removed by `--edge-filter`.
+    /// Only bases the read has calls for are included, e.g. only C's for a
+    /// read with 5mC calls. These rows have "." in the mod_qual and mod_code
+    /// (call_prob and call_code for `calls`) columns and follow the calls of
+    /// each read, so the completeness of each read can be measured from the
+    /// table alone.
+    #[clap(help_heading = "Modified Base Options")]
+    #[arg(long, conflicts_with = "annotate_motifs", default_value_t = false)]
+    pub include_no_calls: bool,
     /// Only output counts at CpG motifs. Requires a reference sequence to be
     /// provided.
     #[clap(help_heading = "Modified Base Options")]
@@ -126,6 +236,20 @@ pub(super) struct InputArgs {
         hide_short_help = true
     )]
     pub mask: bool,
+    /// How to handle ambiguous (non-ACGT) bases in the reference when
+    /// finding motifs and reporting the ref_kmer column. skip: ambiguous
+    /// bases never match a motif and are reported as-is. match-any:
+    /// ambiguous bases match any motif base and are reported as N. expand:
+    /// IUPAC codes match a motif base when the code includes that base, e.g.
+    /// R matches A or G, and are reported as-is.
+    #[clap(help_heading = "Modified Base Options")]
+    #[arg(
+        long,
+        requires = "reference",
+        default_value_t = AmbiguousBases::skip,
+        hide_short_help = true
+    )]
+    pub ambiguous_bases: AmbiguousBases,
 
     /// Discard base modification calls that are this many bases from the start
     /// or the end of the read. Two comma-separated values may be provided
@@ -175,3 +299,96 @@ pub(super) struct InputArgs {
     #[arg(long, hide_short_help = true)]
     pub ignore_implicit: bool,
 }
+
+impl InputArgs {
+    /// Whether to add the cigar_context and indel_distance columns, these
+    /// label the calls kept with `--include-softclipped`.
+    pub(super) fn with_cigar_context(&self) -> bool {
+        self.cigar_context || self.include_softclipped
+    }
+
+    pub(super) fn row_options(
+        &self,
+        feature_lookup: Option<FeatureLookup>,
+    ) -> RowOptions {
+        RowOptions {
+            rna_labels: self.rna,
+            ambiguous_bases: self.ambiguous_bases,
+            with_cigar_context: self.with_cigar_context(),
+            feature_lookup,
+        }
+    }
+
+    /// Check the output before doing any work, SQLite and Parquet outputs
+    /// can't be written to stdout and existing files are only overwritten
+    /// with `--force`.
+    pub(super) fn check_output(&self) -> anyhow::Result<()> {
+        let stdout = using_stream(&self.out_path);
+        match self.out_format {
+            ExtractOutFormat::sqlite if stdout => {
+                bail!("cannot write SQLite output to stdout")
+            }
+            ExtractOutFormat::parquet if stdout => {
+                bail!("cannot write Parquet output to stdout")
+            }
+            _ => {}
+        }
+        if !stdout && Path::new(&self.out_path).exists() && !self.force {
+            bail!(
+                "refusing to write over existing file {}, use --force",
+                self.out_path
+            )
+        }
+        Ok(())
+    }
+
+    /// Steps of the plan printed by `--dry-run` that are shared by `full`
+    /// and `calls`.
+    pub(super) fn dry_run_plan(
+        &self,
+        subcommand: &'static str,
+        reference: Option<&PathBuf>,
+        references_and_intervals: Option<ReferenceIntervalsFeeder>,
+    ) -> anyhow::Result<DryRunPlan> {
+        let mut plan = DryRunPlan::new(subcommand);
+        plan.add_step("input", self.in_bam.clone());
+        if let Some(reference) = reference {
+            plan.add_step("reference", reference.to_string_lossy().to_string());
+        }
+        match references_and_intervals {
+            Some(feeder) => plan.add_intervals(
+                feeder,
+                self.interval_size,
+                (self.threads as f32 * 1.5f32).floor() as usize,
+            )?,
+            None => plan.add_step(
+                "intervals",
+                "none, the reads are scanned in order".to_string(),
+            ),
+        }
+        if let Some(num_reads) = self.num_reads {
+            plan.add_step("reads", format!("up to {num_reads}"));
+        }
+        if using_stream(&self.out_path) {
+            plan.add_output("stdout".to_string());
+        } else {
+            plan.add_output(self.out_path.clone());
+        }
+        Ok(plan)
+    }
+
+    /// Features to add relative coordinates for with `--feature-coords`.
+    pub(super) fn feature_lookup(
+        &self,
+    ) -> anyhow::Result<Option<FeatureLookup>> {
+        if !self.feature_coords {
+            return Ok(None);
+        }
+        match self.include_bed.as_ref().or(self.regions.as_ref()) {
+            Some(bed_fp) => FeatureLookup::from_bed_file(bed_fp).map(Some),
+            None => {
+                bail!("--feature-coords requires --include-bed or --regions")
+            }
+        }
+    }
+}
@@ -0,0 +1,134 @@
+use std::io::BufRead;
+use std::path::Path;
+
+use anyhow::{bail, Context};
+use log::info;
+use rust_lapper::{Interval, Lapper};
+use rustc_hash::FxHashMap;
+
+use crate::parsing_utils::open_text_input;
+use crate::util::{GenomeRegion, StrandRule, MISSING_SYMBOL, TAB};
+
+/// Features from a BED file, used to add the position of each call relative
+/// to the feature it's in with `--feature-coords`, for metaplots without
+/// joining against the BED.
+pub(crate) struct FeatureLookup {
+    features: Vec<GenomeRegion>,
+    lookup: FxHashMap<String, Lapper<u64, usize>>,
+}
+
+impl FeatureLookup {
+    pub(crate) const COLUMNS: [&'static str; 3] =
+        ["feature_name", "feature_start_offset", "feature_center_offset"];
+
+    /// Load the features in a BED3+ file, features are stranded when the
+    /// line has a strand column (BED6+).
+    pub(crate) fn from_bed_file(bed_fp: &Path) -> anyhow::Result<Self> {
+        let mut features = Vec::new();
+        for line in open_text_input(bed_fp)?.lines() {
+            let line = line
+                .with_context(|| format!("failed to read from {bed_fp:?}"))?;
+            if line.starts_with('#') || line.trim().is_empty() {
+                continue;
+            }
+            let feature = if line.split_whitespace().count() <= 5 {
+                GenomeRegion::parse_unstranded_bed_line(&line)?
+            } else {
+                GenomeRegion::parse_stranded_bed_line(&line)?
+            };
+            features.push(feature);
+        }
+        if features.is_empty() {
+            bail!("zero features in {bed_fp:?}")
+        }
+        info!("annotating calls with {} feature(s)", features.len());
+
+        let mut intervals =
+            FxHashMap::<String, Vec<Interval<u64, usize>>>::default();
+        for (idx, feature) in features.iter().enumerate() {
+            intervals.entry(feature.chrom.clone()).or_default().push(
+                Interval { start: feature.start, stop: feature.end, val: idx },
+            );
+        }
+        let lookup = intervals
+            .into_iter()
+            .map(|(chrom, intervals)| (chrom, Lapper::new(intervals)))
+            .collect();
+
+        Ok(Self { features, lookup })
+    }
+
+    /// The feature containing a position, when features overlap the one with
+    /// the nearest center (then the earliest) is used.
+    fn feature_at(
+        &self,
+        chrom: &str,
+        ref_position: u64,
+    ) -> Option<&GenomeRegion> {
+        self.lookup
+            .get(chrom)?
+            .find(ref_position, ref_position + 1)
+            .map(|interval| &self.features[interval.val])
+            .min_by_key(|feature| {
+                (feature.midpoint().abs_diff(ref_position), feature.start)
+            })
+    }
+
+    /// Tab-separated feature columns for a call, the feature name (or
+    /// `chrom:start-end` when the BED doesn't have names) and the offsets of
+    /// the position from the feature start and center. Offsets are in the
+    /// direction of the feature, so on `-` strand features they're counted
+    /// from the end. All "." when the call isn't in a feature.
+    pub(crate) fn to_columns(&self, chrom: &str, ref_position: i64) -> String {
+        let feature = u64::try_from(ref_position)
+            .ok()
+            .and_then(|pos| self.feature_at(chrom, pos));
+        let Some(feature) = feature else {
+            return [MISSING_SYMBOL; 3].join(&TAB.to_string());
+        };
+        let name = feature.name.clone().unwrap_or_else(|| {
+            format!("{}:{}-{}", feature.chrom, feature.start, feature.end)
+        });
+        let center = feature.midpoint() as i64;
+        let (start_offset, center_offset) = match feature.strand {
+            StrandRule::Negative => {
+                (feature.end as i64 - 1 - ref_position, center - ref_position)
+            }
+            StrandRule::Positive | StrandRule::Both => {
+                (ref_position - feature.start as i64, ref_position - center)
+            }
+        };
+        format!("{name}{TAB}{start_offset}{TAB}{center_offset}")
+    }
+}
+
+#[cfg(test)]
+mod features_tests {
+    use std::io::Write;
+
+    use crate::extract::features::FeatureLookup;
+
+    #[test]
+    fn test_feature_columns() {
+        let mut bed = tempfile::NamedTempFile::new().unwrap();
+        writeln!(bed, "chr1\t100\t110\tgene_a\t0\t+").unwrap();
+        writeln!(bed, "chr1\t200\t210\tgene_b\t0\t-").unwrap();
+        writeln!(bed, "chr1\t105\t131\tgene_c\t0\t+").unwrap();
+        let lookup = FeatureLookup::from_bed_file(bed.path()).unwrap();
+        assert_eq!(lookup.to_columns("chr1", 100), "gene_a\t0\t-5");
+        // gene_a and gene_c overlap, 108 is closer to the center of gene_a
+        assert_eq!(lookup.to_columns("chr1", 108), "gene_a\t8\t3");
+        assert_eq!(lookup.to_columns("chr1", 110), "gene_c\t5\t-8");
+        // offsets count from the end of - strand features
+        assert_eq!(lookup.to_columns("chr1", 209), "gene_b\t0\t-4");
+        assert_eq!(lookup.to_columns("chr1", 200), "gene_b\t9\t5");
+        assert_eq!(lookup.to_columns("chr1", 210), ".\t.\t.");
+        assert_eq!(lookup.to_columns("chr2", 100), ".\t.\t.");
+        assert_eq!(lookup.to_columns("chr1", -1), ".\t.\t.");
+
+        let mut bed = tempfile::NamedTempFile::new().unwrap();
+        writeln!(bed, "chr1\t10\t20").unwrap();
+        let lookup = FeatureLookup::from_bed_file(bed.path()).unwrap();
+        assert_eq!(lookup.to_columns("chr1", 12), "chr1:10-20\t2\t-3");
+    }
+}
@@ -1,4 +1,5 @@
 mod args;
+pub(crate) mod features;
 pub mod subcommand;
 mod util;
 pub mod writer;
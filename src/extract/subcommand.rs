@@ -1,25 +1,30 @@
 use std::collections::HashMap;
 use std::path::{Path, PathBuf};
 
-use anyhow::bail;
+use anyhow::{bail, Context};
 use bio::io::fasta::Reader as FastaReader;
 use clap::{Args, Subcommand};
 use crossbeam_channel::bounded;
 use indicatif::{MultiProgress, ProgressIterator};
-use log::{debug, error, info};
+use log::{debug, error, info, warn};
 use rayon::{ThreadPool, ThreadPoolBuilder};
 use rust_htslib::bam::{self, Read};
 
+use crate::adjust::call_mods_on_record;
 use crate::command_utils::{
-    get_serial_reader, get_threshold_from_options, parse_edge_filter_input,
-    parse_per_mod_thresholds, parse_thresholds, using_stream,
+    get_bam_writer, get_serial_reader, get_threshold_from_options,
+    parse_edge_filter_input, parse_per_mod_thresholds, parse_thresholds,
+    using_stream,
+};
+use crate::extract::args::{ExtractOutFormat, InputArgs};
+use crate::extract::util::{ModCodeFilter, ReferencePositionFilter};
+use crate::extract::writer::{
+    sqlite_columns, OutwriterWithMemory, TsvWriterWithContigNames,
+    SQLITE_INDICES,
 };
-use crate::extract::args::InputArgs;
-use crate::extract::util::ReferencePositionFilter;
-use crate::extract::writer::{OutwriterWithMemory, TsvWriterWithContigNames};
 use crate::interval_chunks::ReferenceIntervalsFeeder;
 use crate::logging::init_logging_smart;
-use crate::mod_bam::CollapseMethod;
+use crate::mod_bam::{report_mm_mn_mismatches, CollapseMethod};
 use crate::mod_base_code::ModCodeRepr;
 use crate::motifs::motif_bed::MotifPositionLookup;
 use crate::read_ids_to_base_mod_probs::{
@@ -27,8 +32,12 @@ use crate::read_ids_to_base_mod_probs::{
 };
 use crate::reads_sampler::sampling_schedule::SamplingSchedule;
 use crate::record_processor::WithRecords;
+use crate::run_summary;
 use crate::threshold_mod_caller::MultipleThresholdModCaller;
-use crate::util::{get_ticker, Region, KMER_SIZE};
+use crate::util::{
+    add_modkit_pg_records, get_indexed_reader, get_ticker, uracil_to_thymine,
+    AlignmentIdentityFilter, Region, KMER_SIZE,
+};
 use crate::writers::TsvWriter;
 
 #[derive(Subcommand)]
@@ -58,9 +67,20 @@ pub struct EntryExtractFull {
     #[clap(flatten)]
     input_args: InputArgs,
     /// Path to reference FASTA to extract reference context information from.
-    /// Required for motif selection.
+    /// Required for motif selection. Also used to decode CRAM input.
     #[arg(long, alias = "ref")]
     pub reference: Option<PathBuf>,
+    /// Only output rows for these modification codes, comma-separated, e.g.
+    /// `--mod-codes m,h`. Rows for other codes are dropped as each read is
+    /// processed, before they are written.
+    #[clap(help_heading = "Modified Base Options")]
+    #[arg(long, value_delimiter = ',', conflicts_with = "exclude_mod_codes")]
+    pub mod_codes: Option<Vec<String>>,
+    /// Do not output rows for these modification codes, comma-separated,
+    /// e.g. `--exclude-mod-codes h`.
+    #[clap(help_heading = "Modified Base Options")]
+    #[arg(long, value_delimiter = ',')]
+    pub exclude_mod_codes: Option<Vec<String>>,
 }
 
 impl EntryExtractFull {
@@ -104,6 +124,7 @@ impl EntryExtractFull {
         if self.input_args.kmer_size > KMER_SIZE {
             bail!("kmer size must be less than or equal to {KMER_SIZE}")
         }
+        self.input_args.check_output()?;
 
         let multi_prog = MultiProgress::new();
         if self.input_args.suppress_progress || stream_out {
@@ -131,6 +152,9 @@ impl EntryExtractFull {
             .transpose()?;
 
         let mut reader = get_serial_reader(&self.input_args.in_bam)?;
+        if let Some(reference) = self.reference.as_ref() {
+            reader.set_reference(reference)?;
+        }
         let header = reader.header().to_owned();
 
         let queue_size = self.input_args.queue_size;
@@ -166,7 +190,12 @@ impl EntryExtractFull {
                     .filter_map(|r| r.ok())
                     .filter(|record| name_to_tid.get(record.id()).is_some())
                     .map(|record| {
-                        (record.id().to_owned(), record.seq().to_vec())
+                        let seq = record
+                            .seq()
+                            .iter()
+                            .map(|&b| uracil_to_thymine(b))
+                            .collect::<Vec<u8>>();
+                        (record.id().to_owned(), seq)
                     })
                     .collect::<HashMap<String, Vec<u8>>>()
             }
@@ -191,19 +220,44 @@ impl EntryExtractFull {
             &multi_prog,
             &pool,
         )?;
+        let feature_lookup = self.input_args.feature_lookup()?;
+        let with_features = feature_lookup.is_some();
+        let alignment_filter = AlignmentIdentityFilter::from_options(
+            self.input_args.min_identity,
+            self.input_args.max_nm,
+        )?;
+        let mod_code_filter = ModCodeFilter::from_options(
+            self.mod_codes.as_ref(),
+            self.exclude_mod_codes.as_ref(),
+        )?;
+        if self.input_args.dry_run {
+            return self
+                .input_args
+                .dry_run_plan(
+                    "extract full",
+                    self.reference.as_ref(),
+                    references_and_intervals,
+                )?
+                .print();
+        }
 
         // allowed to use the sampling schedule if there is an index, if
         // asked for num_reads with no index, scan first N reads
         let schedule = match (self.input_args.num_reads, self.using_stdin()) {
             (_, true) | (None, false) => None,
             (Some(num_reads), false) => {
-                match bam::IndexedReader::from_path(&self.input_args.in_bam) {
+                match get_indexed_reader(
+                    &self.input_args.in_bam,
+                    self.reference.as_ref(),
+                ) {
                     Ok(_) => Some(SamplingSchedule::from_num_reads(
                         &self.input_args.in_bam,
+                        self.reference.as_ref(),
                         num_reads,
                         region.as_ref(),
-                        reference_position_filter.include_pos.as_ref(),
+                        reference_position_filter.sampling_positions(),
                         reference_position_filter.include_unmapped_reads,
+                        self.input_args.contig_quotas,
                     )?),
                     Err(_) => {
                         debug!(
@@ -230,6 +284,7 @@ impl EntryExtractFull {
         let threads = self.input_args.threads;
         let mapped_only = self.input_args.mapped_only;
         let in_bam = self.input_args.in_bam.clone();
+        let reference = self.reference.clone();
         let kmer_size = self.input_args.kmer_size;
         let allow_non_primary = self.input_args.allow_non_primary;
         let remove_inferred = self.input_args.ignore_implicit;
@@ -238,13 +293,17 @@ impl EntryExtractFull {
             super::util::run_extract_reads(
                 reader,
                 in_bam,
+                reference,
                 references_and_intervals,
                 schedule,
                 collapse_method,
                 edge_filter,
+                alignment_filter,
+                false,
                 allow_non_primary,
                 kmer_size,
                 remove_inferred,
+                mod_code_filter,
                 reference_position_filter,
                 snd,
                 queue_size,
@@ -259,10 +318,62 @@ impl EntryExtractFull {
         let output_header = if self.input_args.no_headers {
             None
         } else {
-            Some(ModProfile::header(with_motifs))
+            Some(ModProfile::header(
+                with_motifs,
+                self.input_args.with_cigar_context(),
+                with_features,
+            ))
         };
+        let row_options = self.input_args.row_options(feature_lookup);
         let mut writer: Box<dyn OutwriterWithMemory<ReadsBaseModProfile>> =
             match self.input_args.out_path.as_str() {
+                out_path
+                    if self.input_args.out_format
+                        == ExtractOutFormat::sqlite =>
+                {
+                    let tsv_writer = TsvWriter::new_sqlite(
+                        out_path,
+                        self.input_args.force,
+                        "extract",
+                        &sqlite_columns(&ModProfile::header(
+                            with_motifs,
+                            self.input_args.with_cigar_context(),
+                            with_features,
+                        )),
+                        SQLITE_INDICES,
+                    )?;
+                    let writer = TsvWriterWithContigNames::new(
+                        tsv_writer,
+                        tid_to_name,
+                        chrom_to_seq,
+                        with_motifs,
+                    )?
+                    .with_row_options(row_options);
+                    Box::new(writer)
+                }
+                out_path
+                    if self.input_args.out_format
+                        == ExtractOutFormat::parquet =>
+                {
+                    let tsv_writer = TsvWriter::new_parquet(
+                        out_path,
+                        self.input_args.force,
+                        "extract",
+                        &sqlite_columns(&ModProfile::header(
+                            with_motifs,
+                            self.input_args.with_cigar_context(),
+                            with_features,
+                        )),
+                    )?;
+                    let writer = TsvWriterWithContigNames::new(
+                        tsv_writer,
+                        tid_to_name,
+                        chrom_to_seq,
+                        with_motifs,
+                    )?
+                    .with_row_options(row_options);
+                    Box::new(writer)
+                }
                 "stdout" | "-" => {
                     let tsv_writer = TsvWriter::new_stdout(output_header);
                     let writer = TsvWriterWithContigNames::new(
@@ -270,7 +381,8 @@ impl EntryExtractFull {
                         tid_to_name,
                         chrom_to_seq,
                         with_motifs,
-                    )?;
+                    )?
+                    .with_row_options(row_options);
                     Box::new(writer)
                 }
                 _ => {
@@ -286,7 +398,8 @@ impl EntryExtractFull {
                             tid_to_name,
                             chrom_to_seq,
                             with_motifs,
-                        )?;
+                        )?
+                        .with_row_options(row_options);
                         Box::new(writer)
                     } else {
                         let tsv_writer = TsvWriter::new_file(
@@ -299,18 +412,21 @@ impl EntryExtractFull {
                             tid_to_name,
                             chrom_to_seq,
                             with_motifs,
-                        )?;
+                        )?
+                        .with_row_options(row_options);
                         Box::new(writer)
                     }
                 }
             };
 
+        let mut n_mm_mn_mismatch = 0usize;
         for result in rcv {
             match result {
                 Ok(mod_profile) => {
                     n_used.inc(mod_profile.num_reads() as u64);
                     n_failed.inc(mod_profile.num_fails as u64);
                     n_skipped.inc(mod_profile.num_skips as u64);
+                    n_mm_mn_mismatch += mod_profile.num_mm_mn_mismatch;
                     match writer
                         .write(mod_profile, motif_position_lookup.as_ref())
                     {
@@ -329,6 +445,13 @@ impl EntryExtractFull {
             }
         }
 
+        writer.finish()?;
+        run_summary::record_reads(
+            n_used.position(),
+            n_skipped.position(),
+            n_failed.position(),
+        );
+        report_mm_mn_mismatches(n_mm_mn_mismatch);
         n_failed.finish_and_clear();
         n_skipped.finish_and_clear();
         n_used.finish_and_clear();
@@ -351,7 +474,7 @@ pub struct EntryExtractCalls {
     input_args: InputArgs,
     /// Path to reference FASTA to extract reference context information from.
     /// If no reference is provided, `ref_kmer` column will be "." in the
-    /// output. (alias: ref)
+    /// output. Also used to decode CRAM input. (alias: ref)
     #[arg(long, alias = "ref")]
     pub reference: Option<PathBuf>,
 
@@ -360,6 +483,14 @@ pub struct EntryExtractCalls {
     #[clap(help_heading = "Selection Options")]
     #[arg(long, alias = "pass", default_value_t = false)]
     pass_only: bool,
+    /// Also write the reads to this BAM with the thresholded calls applied to
+    /// the MM and ML tags, the same as `modkit call-mods`, in the same pass
+    /// over the modBAM as the table. Only the reads used for the table are
+    /// written and the output is not sorted. Use `-` or `stdout` to stream
+    /// the BAM when the table is written to a file.
+    #[clap(help_heading = "Output Options")]
+    #[arg(long)]
+    out_bam: Option<String>,
     // sampling and filtering
     /// Specify the filter threshold globally or per-base. Global filter
     /// threshold can be specified with by a decimal number (e.g. 0.75).
@@ -480,6 +611,16 @@ impl EntryExtractCalls {
         if self.input_args.kmer_size > KMER_SIZE {
             bail!("kmer size must be less than or equal to {KMER_SIZE}")
         }
+        self.input_args.check_output()?;
+        if let Some(out_bam) = self.out_bam.as_ref() {
+            if using_stream(out_bam) {
+                if stream_out {
+                    bail!("cannot stream both the table and the BAM")
+                }
+            } else if Path::new(out_bam).exists() && !self.input_args.force {
+                bail!("refusing to overwrite {out_bam}")
+            }
+        }
 
         let multi_prog = MultiProgress::new();
         if self.input_args.suppress_progress || stream_out {
@@ -507,6 +648,9 @@ impl EntryExtractCalls {
             .transpose()?;
 
         let mut reader = get_serial_reader(&self.input_args.in_bam)?;
+        if let Some(reference) = self.reference.as_ref() {
+            reader.set_reference(reference)?;
+        }
         let header = reader.header().to_owned();
 
         let tid_to_name = (0..header.target_count())
@@ -540,7 +684,12 @@ impl EntryExtractCalls {
                     .filter_map(|r| r.ok())
                     .filter(|record| name_to_tid.get(record.id()).is_some())
                     .map(|record| {
-                        (record.id().to_owned(), record.seq().to_vec())
+                        let seq = record
+                            .seq()
+                            .iter()
+                            .map(|&b| uracil_to_thymine(b))
+                            .collect::<Vec<u8>>();
+                        (record.id().to_owned(), seq)
                     })
                     .collect::<HashMap<String, Vec<u8>>>()
             }
@@ -575,18 +724,54 @@ impl EntryExtractCalls {
             &multi_prog,
             &pool,
         )?;
+        let feature_lookup = self.input_args.feature_lookup()?;
+        let with_features = feature_lookup.is_some();
+        let alignment_filter = AlignmentIdentityFilter::from_options(
+            self.input_args.min_identity,
+            self.input_args.max_nm,
+        )?;
 
-        let caller = if !self.no_filtering {
-            // stdin input and want a threshold, not allowed
-            if self.using_stdin() && self.filter_threshold.is_none() {
-                bail!(
-                    "\
-                        cannot use stdin and estimate a filter threshold, set \
-                     the threshold on the command line with \
-                     --filter-threshold and/or --mod-threshold (or set \
-                     --no-filtering)."
+        // stdin input and want a threshold, not allowed
+        if !self.no_filtering
+            && self.using_stdin()
+            && self.filter_threshold.is_none()
+        {
+            bail!(
+                "\
+                    cannot use stdin and estimate a filter threshold, set the \
+                 threshold on the command line with --filter-threshold \
+                 and/or --mod-threshold (or set --no-filtering)."
+            )
+        }
+        if self.input_args.dry_run {
+            let mut plan = self.input_args.dry_run_plan(
+                "extract calls",
+                self.reference.as_ref(),
+                references_and_intervals,
+            )?;
+            let thresholds = if self.no_filtering {
+                "no filtering".to_string()
+            } else if let Some(raw_threshold) = &self.filter_threshold {
+                parse_thresholds(raw_threshold, per_mod_thresholds)?.describe()
+            } else {
+                format!(
+                    "estimated from ~{} sampled reads with filter percentile \
+                     {}",
+                    self.sample_num_reads, self.filter_percentile
                 )
+            };
+            plan.add_step("thresholds", thresholds);
+            if let Some(out_bam) = self.out_bam.as_ref() {
+                if using_stream(out_bam) {
+                    plan.add_output("BAM to stdout".to_string());
+                } else {
+                    plan.add_output(out_bam.clone());
+                }
             }
+            return plan.print();
+        }
+
+        let caller = if !self.no_filtering {
             if let Some(raw_threshold) = &self.filter_threshold {
                 parse_thresholds(raw_threshold, per_mod_thresholds)?
             } else {
@@ -600,6 +785,7 @@ impl EntryExtractCalls {
                 pool.install(|| {
                     get_threshold_from_options(
                         &in_bam,
+                        self.reference.as_ref(),
                         self.input_args.threads,
                         self.sampling_interval_size,
                         self.sampling_frac,
@@ -611,8 +797,10 @@ impl EntryExtractCalls {
                         per_mod_thresholds,
                         edge_filter.as_ref(),
                         collapse_method.as_ref(),
-                        reference_position_filter.include_pos.as_ref(),
+                        reference_position_filter.sampling_positions(),
                         reference_position_filter.only_mapped_positions(),
+                        self.input_args.contig_quotas,
+                        false,
                         self.input_args.suppress_progress,
                     )
                 })?
@@ -621,14 +809,73 @@ impl EntryExtractCalls {
             MultipleThresholdModCaller::new_passthrough()
         };
 
+        // the BAM gets the same calls as the table, clone the caller before
+        // the table writer takes it
+        let bam_caller = self.out_bam.as_ref().map(|_| caller.clone());
         let with_motifs = self.input_args.motif.is_some();
         let output_header = if self.input_args.no_headers {
             None
         } else {
-            Some(PositionModCalls::header(with_motifs))
+            Some(PositionModCalls::header(
+                with_motifs,
+                self.input_args.with_cigar_context(),
+                with_features,
+            ))
         };
+        let row_options = self.input_args.row_options(feature_lookup);
         let mut writer: Box<dyn OutwriterWithMemory<ReadsBaseModProfile>> =
             match self.input_args.out_path.as_str() {
+                out_path
+                    if self.input_args.out_format
+                        == ExtractOutFormat::sqlite =>
+                {
+                    let tsv_writer = TsvWriter::new_sqlite(
+                        out_path,
+                        self.input_args.force,
+                        "calls",
+                        &sqlite_columns(&PositionModCalls::header(
+                            with_motifs,
+                            self.input_args.with_cigar_context(),
+                            with_features,
+                        )),
+                        SQLITE_INDICES,
+                    )?;
+                    let writer = TsvWriterWithContigNames::new_with_caller(
+                        tsv_writer,
+                        tid_to_name,
+                        chrom_to_seq,
+                        caller,
+                        self.pass_only,
+                        with_motifs,
+                    )?
+                    .with_row_options(row_options);
+                    Box::new(writer)
+                }
+                out_path
+                    if self.input_args.out_format
+                        == ExtractOutFormat::parquet =>
+                {
+                    let tsv_writer = TsvWriter::new_parquet(
+                        out_path,
+                        self.input_args.force,
+                        "calls",
+                        &sqlite_columns(&PositionModCalls::header(
+                            with_motifs,
+                            self.input_args.with_cigar_context(),
+                            with_features,
+                        )),
+                    )?;
+                    let writer = TsvWriterWithContigNames::new_with_caller(
+                        tsv_writer,
+                        tid_to_name,
+                        chrom_to_seq,
+                        caller,
+                        self.pass_only,
+                        with_motifs,
+                    )?
+                    .with_row_options(row_options);
+                    Box::new(writer)
+                }
                 "stdout" | "-" => {
                     let tsv_writer = TsvWriter::new_stdout(output_header);
                     let writer = TsvWriterWithContigNames::new_with_caller(
@@ -638,7 +885,8 @@ impl EntryExtractCalls {
                         caller,
                         self.pass_only,
                         with_motifs,
-                    )?;
+                    )?
+                    .with_row_options(row_options);
                     Box::new(writer)
                 }
                 _ => {
@@ -656,7 +904,8 @@ impl EntryExtractCalls {
                             caller,
                             self.pass_only,
                             with_motifs,
-                        )?;
+                        )?
+                        .with_row_options(row_options);
                         Box::new(writer)
                     } else {
                         let tsv_writer = TsvWriter::new_file(
@@ -671,22 +920,45 @@ impl EntryExtractCalls {
                             caller,
                             self.pass_only,
                             with_motifs,
-                        )?;
+                        )?
+                        .with_row_options(row_options);
                         Box::new(writer)
                     }
                 }
             };
 
+        let mut bam_writer = self
+            .out_bam
+            .as_ref()
+            .map(|out_bam| -> anyhow::Result<_> {
+                let mut bam_header = bam::Header::from_template(&header);
+                add_modkit_pg_records(&mut bam_header);
+                let mut bam_writer =
+                    get_bam_writer(out_bam, &bam_header, false)?;
+                bam_writer.set_threads(self.input_args.out_threads)?;
+                Ok(bam_writer)
+            })
+            .transpose()?;
+        let keep_records = bam_writer.is_some();
+        let bam_collapse_methods =
+            collapse_method.iter().cloned().collect::<Vec<CollapseMethod>>();
+        let bam_edge_filter = edge_filter.clone();
+
         let schedule = match (self.input_args.num_reads, self.using_stdin()) {
             (_, true) | (None, false) => None,
             (Some(num_reads), false) => {
-                match bam::IndexedReader::from_path(&self.input_args.in_bam) {
+                match get_indexed_reader(
+                    &self.input_args.in_bam,
+                    self.reference.as_ref(),
+                ) {
                     Ok(_) => Some(SamplingSchedule::from_num_reads(
                         &self.input_args.in_bam,
+                        self.reference.as_ref(),
                         num_reads,
                         region.as_ref(),
-                        reference_position_filter.include_pos.as_ref(),
+                        reference_position_filter.sampling_positions(),
                         reference_position_filter.include_unmapped_reads,
+                        self.input_args.contig_quotas,
                     )?),
                     Err(_) => {
                         debug!(
@@ -710,12 +982,17 @@ impl EntryExtractCalls {
         n_used.set_message("~records used");
         let n_rows = multi_prog.add(get_ticker());
         n_rows.set_message("rows written");
+        let n_bam_records = multi_prog.add(get_ticker());
+        if keep_records {
+            n_bam_records.set_message("BAM records written");
+        }
 
         reader.set_threads(self.input_args.threads)?;
         let n_reads = self.input_args.num_reads;
         let threads = self.input_args.threads;
         let mapped_only = self.input_args.mapped_only;
         let in_bam = self.input_args.in_bam.clone();
+        let reference = self.reference.clone();
         let kmer_size = self.input_args.kmer_size;
         let allow_non_primary = self.input_args.allow_non_primary;
         let remove_inferred = self.input_args.ignore_implicit;
@@ -724,13 +1001,17 @@ impl EntryExtractCalls {
             super::util::run_extract_reads(
                 reader,
                 in_bam,
+                reference,
                 references_and_intervals,
                 schedule,
                 collapse_method,
                 edge_filter,
+                alignment_filter,
+                keep_records,
                 allow_non_primary,
                 kmer_size,
                 remove_inferred,
+                None,
                 reference_position_filter,
                 snd,
                 queue_size,
@@ -741,12 +1022,16 @@ impl EntryExtractCalls {
             );
         });
 
+        let mut n_mm_mn_mismatch = 0usize;
+        let mut n_bam_call_failures = 0usize;
         for result in rcv {
             match result {
-                Ok(mod_profile) => {
+                Ok(mut mod_profile) => {
                     n_used.inc(mod_profile.num_reads() as u64);
                     n_failed.inc(mod_profile.num_fails as u64);
                     n_skipped.inc(mod_profile.num_skips as u64);
+                    n_mm_mn_mismatch += mod_profile.num_mm_mn_mismatch;
+                    let records = mod_profile.take_records();
                     match writer
                         .write(mod_profile, motif_position_lookup.as_ref())
                     {
@@ -755,6 +1040,31 @@ impl EntryExtractCalls {
                             error!("failed to write {}", e.to_string());
                         }
                     }
+                    if let (Some(bam_writer), Some(caller)) =
+                        (bam_writer.as_mut(), bam_caller.as_ref())
+                    {
+                        for record in records {
+                            match call_mods_on_record(
+                                record,
+                                &bam_collapse_methods,
+                                caller,
+                                bam_edge_filter.as_ref(),
+                            ) {
+                                Ok(record) => {
+                                    bam_writer.write(&record).with_context(
+                                        || "failed to write BAM record",
+                                    )?;
+                                    n_bam_records.inc(1);
+                                }
+                                Err(e) => {
+                                    debug!(
+                                        "failed to call mods on record, {e}"
+                                    );
+                                    n_bam_call_failures += 1;
+                                }
+                            }
+                        }
+                    }
                 }
                 Err(e) => {
                     debug!(
@@ -765,10 +1075,18 @@ impl EntryExtractCalls {
             }
         }
 
+        writer.finish()?;
+        run_summary::record_reads(
+            n_used.position(),
+            n_skipped.position(),
+            n_failed.position(),
+        );
+        report_mm_mn_mismatches(n_mm_mn_mismatch);
         n_failed.finish_and_clear();
         n_skipped.finish_and_clear();
         n_used.finish_and_clear();
         n_rows.finish_and_clear();
+        n_bam_records.finish_and_clear();
         info!(
             "processed {} reads, {} rows, skipped ~{} reads, failed ~{} reads",
             writer.num_reads(),
@@ -776,6 +1094,18 @@ impl EntryExtractCalls {
             n_skipped.position(),
             n_failed.position()
         );
+        if let Some(out_bam) = self.out_bam.as_ref() {
+            info!(
+                "wrote {} records with calls to {out_bam}",
+                n_bam_records.position()
+            );
+            if n_bam_call_failures > 0 {
+                warn!(
+                    "failed to call mods on {n_bam_call_failures} records, \
+                     they were not written to {out_bam}"
+                );
+            }
+        }
         Ok(())
     }
 }
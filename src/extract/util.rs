@@ -1,24 +1,31 @@
+use crate::bam_pipeline::run_read_ahead;
 use crate::extract::args::InputArgs;
 use crate::interval_chunks::{
     ReferenceIntervalsFeeder, TotalLength, WithPrevEnd,
 };
-use crate::mod_bam::{CollapseMethod, EdgeFilter, TrackingModRecordIter};
+use crate::mod_bam::{
+    CollapseMethod, EdgeFilter, ModRecordCounts, ModRecordCriteria,
+};
+use crate::mod_base_code::ModCodeRepr;
 use crate::monoid::Moniod;
 use crate::motifs::motif_bed::{
     find_motif_hits, MotifPositionLookup, RegexMotif,
 };
 use crate::position_filter::{GenomeIntervals, Iv, StrandedPositionFilter};
 use crate::read_ids_to_base_mod_probs::{
-    ModProfile, ReadBaseModProfile, ReadsBaseModProfile,
+    CigarOpContext, ModProfile, ReadBaseModProfile, ReadsBaseModProfile,
 };
 use crate::reads_sampler::record_sampler::RecordSampler;
 use crate::reads_sampler::sample_reads_from_interval;
 use crate::reads_sampler::sampling_schedule::SamplingSchedule;
 use crate::record_processor::WithRecords;
 use crate::util::{
-    get_guage, get_master_progress_bar, get_reference_mod_strand,
-    get_subroutine_progress_bar, get_targets, get_ticker, Region, Strand,
+    get_guage, get_indexed_reader, get_master_progress_bar,
+    get_reference_mod_strand, get_subroutine_progress_bar, get_targets,
+    get_ticker, normalize_reference_seq, AlignmentIdentityFilter, Region,
+    Strand,
 };
+use anyhow::bail;
 use derive_new::new;
 use indicatif::{MultiProgress, ParallelProgressIterator};
 use itertools::Itertools;
@@ -26,9 +33,9 @@ use log::{debug, error, info};
 use rayon::prelude::*;
 use rayon::ThreadPool;
 use rust_htslib::bam::{self, FetchDefinition, Read};
-use rustc_hash::FxHashMap;
+use rustc_hash::{FxHashMap, FxHashSet};
 use std::collections::HashMap;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
 #[derive(new)]
 pub(super) struct ReferencePositionFilter {
@@ -36,13 +43,49 @@ pub(super) struct ReferencePositionFilter {
     pub(super) exclude_pos: Option<StrandedPositionFilter<()>>,
     pub(super) include_unmapped_reads: bool,
     pub(super) include_unmapped_positions: bool,
+    /// Regions (`--regions`) the BAM traversal is restricted to, these do
+    /// not filter positions.
+    pub(super) traversal_regions: Option<StrandedPositionFilter<()>>,
+    /// K-mer size for the rows of motif positions without a call, set when
+    /// these are requested (`--include-no-calls`).
+    #[new(default)]
+    no_calls_kmer_size: Option<usize>,
+    /// Keep calls on soft-clipped bases of mapped reads even when only
+    /// mapped positions are kept (`--include-softclipped`).
+    #[new(default)]
+    include_soft_clipped: bool,
 }
 
 impl ReferencePositionFilter {
+    /// Also find the motif positions each read is aligned to without a
+    /// call, requires the reads be kept with their records.
+    pub(super) fn with_no_calls(self, kmer_size: usize) -> Self {
+        Self { no_calls_kmer_size: Some(kmer_size), ..self }
+    }
+
+    /// Also keep calls on soft-clipped bases when only mapped positions are
+    /// kept, they don't have a reference position.
+    pub(super) fn with_soft_clipped(self) -> Self {
+        Self { include_soft_clipped: true, ..self }
+    }
+
+    pub(super) fn finds_no_calls(&self) -> bool {
+        self.no_calls_kmer_size.is_some()
+    }
+
     pub(super) fn only_mapped_positions(&self) -> bool {
         !self.include_unmapped_positions
     }
 
+    /// Positions used to pick the contigs (and sites, when estimating
+    /// thresholds) to sample from, the include positions if given otherwise
+    /// the traversal regions.
+    pub(super) fn sampling_positions(
+        &self,
+    ) -> Option<&StrandedPositionFilter<()>> {
+        self.include_pos.as_ref().or(self.traversal_regions.as_ref())
+    }
+
     fn keep(
         &self,
         chrom_id: u32,
@@ -52,6 +95,15 @@ impl ReferencePositionFilter {
     ) -> bool {
         let reference_mod_strand =
             get_reference_mod_strand(mod_strand, alignment_strand);
+        self.keep_reference_position(chrom_id, position, reference_mod_strand)
+    }
+
+    fn keep_reference_position(
+        &self,
+        chrom_id: u32,
+        position: u64,
+        reference_mod_strand: Strand,
+    ) -> bool {
         let include_hit = self
             .include_pos
             .as_ref()
@@ -75,6 +127,7 @@ impl ReferencePositionFilter {
         reads_base_mods_profile: ReadsBaseModProfile,
     ) -> ReadsBaseModProfile {
         let mut n_skipped = reads_base_mods_profile.num_skips;
+        let n_mm_mn_mismatch = reads_base_mods_profile.num_mm_mn_mismatch;
         let n_failed = reads_base_mods_profile.num_fails;
         let profiles = reads_base_mods_profile
             .profiles
@@ -85,6 +138,7 @@ impl ReferencePositionFilter {
                 let flag = read_base_mod_profile.flag;
                 let alignment_start = read_base_mod_profile.alignment_start;
                 let alignment_end = read_base_mod_profile.alignment_end;
+                let record = read_base_mod_profile.record;
                 let profile = read_base_mod_profile
                     .profile
                     .into_par_iter()
@@ -102,11 +156,16 @@ impl ReferencePositionFilter {
                                     mod_profile.mod_strand,
                                 )
                             }
-                            _ => self.include_unmapped_positions,
+                            _ => {
+                                self.include_unmapped_positions
+                                    || (self.include_soft_clipped
+                                        && mod_profile.cigar_context.op
+                                            == CigarOpContext::SoftClip)
+                            }
                         }
                     })
                     .collect::<Vec<ModProfile>>();
-                ReadBaseModProfile::new(
+                let mut read_base_mod_profile = ReadBaseModProfile::new(
                     read_name,
                     chrom_id,
                     flag,
@@ -114,16 +173,100 @@ impl ReferencePositionFilter {
                     alignment_end,
                     profile,
                 )
+                .with_record(record);
+                if let (Some(kmer_size), Some(record)) = (
+                    self.no_calls_kmer_size,
+                    read_base_mod_profile.record.as_ref(),
+                ) {
+                    read_base_mod_profile.no_calls = read_base_mod_profile
+                        .find_no_calls(
+                            record,
+                            kmer_size,
+                            |tid, pos, strand| {
+                                self.keep_reference_position(tid, pos, strand)
+                            },
+                        );
+                }
+                read_base_mod_profile
             })
             .collect::<Vec<ReadBaseModProfile>>();
         let empty = profiles
             .iter()
             .filter(|read_base_mod_profile| {
                 read_base_mod_profile.profile.is_empty()
+                    && read_base_mod_profile.no_calls.is_empty()
             })
             .count();
         n_skipped += empty;
-        ReadsBaseModProfile::new(profiles, n_skipped, n_failed)
+        ReadsBaseModProfile {
+            profiles,
+            num_skips: n_skipped,
+            num_fails: n_failed,
+            num_mm_mn_mismatch: n_mm_mn_mismatch,
+        }
+    }
+}
+
+/// Keep or drop `extract full` rows by modification code (`--mod-codes` and
+/// `--exclude-mod-codes`), applied to each read as it's processed so that
+/// the dropped rows are never sent to the writer.
+pub(super) enum ModCodeFilter {
+    Include(FxHashSet<ModCodeRepr>),
+    Exclude(FxHashSet<ModCodeRepr>),
+}
+
+impl ModCodeFilter {
+    pub(super) fn from_options(
+        include: Option<&Vec<String>>,
+        exclude: Option<&Vec<String>>,
+    ) -> anyhow::Result<Option<Self>> {
+        let parse = |raw_codes: &Vec<String>| {
+            raw_codes
+                .iter()
+                .map(|raw| ModCodeRepr::parse(raw.trim()))
+                .collect::<anyhow::Result<FxHashSet<ModCodeRepr>>>()
+        };
+        match (include, exclude) {
+            (Some(_), Some(_)) => {
+                bail!("cannot use both --mod-codes and --exclude-mod-codes")
+            }
+            (Some(raw_codes), None) => {
+                let codes = parse(raw_codes)?;
+                info!(
+                    "only outputting mod codes {}",
+                    codes.iter().sorted().join(",")
+                );
+                Ok(Some(Self::Include(codes)))
+            }
+            (None, Some(raw_codes)) => {
+                let codes = parse(raw_codes)?;
+                info!(
+                    "excluding mod codes {}",
+                    codes.iter().sorted().join(",")
+                );
+                Ok(Some(Self::Exclude(codes)))
+            }
+            (None, None) => Ok(None),
+        }
+    }
+
+    fn keep(&self, mod_code: &ModCodeRepr) -> bool {
+        match self {
+            Self::Include(codes) => codes.contains(mod_code),
+            Self::Exclude(codes) => !codes.contains(mod_code),
+        }
+    }
+
+    /// Reads left without any rows are counted as skipped by
+    /// [`ReferencePositionFilter::filter_read_base_mod_probs`].
+    pub(super) fn filter_read_base_mod_probs(
+        &self,
+        mut reads_base_mod_profile: ReadsBaseModProfile,
+    ) -> ReadsBaseModProfile {
+        reads_base_mod_profile.profiles.iter_mut().for_each(|p| {
+            p.profile.retain(|mod_profile| self.keep(&mod_profile.raw_mod_code))
+        });
+        reads_base_mod_profile
     }
 }
 
@@ -140,6 +283,12 @@ pub(super) fn load_regions(
     ReferencePositionFilter,
     Option<MotifPositionLookup>,
 )> {
+    if input_args.include_no_calls
+        && input_args.motif.is_none()
+        && !input_args.cpg
+    {
+        bail!("--include-no-calls requires --motif or --cpg")
+    }
     let (include_unmapped_reads, include_unmapped_positions) = if input_args
         .include_bed
         .is_some()
@@ -149,7 +298,7 @@ pub(super) fn load_regions(
     } else if input_args.motif.is_some() || input_args.cpg {
         info!("specifying a motif (including --cpg) outputs only mapped sites");
         (false, false)
-    } else if region.is_some() {
+    } else if region.is_some() || input_args.regions.is_some() {
         info!("specifying a region outputs only mapped reads");
         if input_args.mapped_only {
             info!("including only mapped positions");
@@ -167,7 +316,14 @@ pub(super) fn load_regions(
         Some(vec![RegexMotif::parse_string("CG", 0).unwrap()])
     } else {
         None
-    };
+    }
+    .map(|motifs| {
+        motifs
+            .into_iter()
+            .map(|m| m.with_ambiguous_bases(input_args.ambiguous_bases))
+            .collect::<anyhow::Result<Vec<RegexMotif>>>()
+    })
+    .transpose()?;
 
     let include_positions = input_args
         .include_bed
@@ -181,6 +337,18 @@ pub(super) fn load_regions(
         })
         .transpose()?;
 
+    let traversal_regions = input_args
+        .regions
+        .as_ref()
+        .map(|fp| {
+            StrandedPositionFilter::from_bed_file(
+                fp,
+                name_to_tid,
+                input_args.suppress_progress,
+            )
+        })
+        .transpose()?;
+
     let exclude_positions = input_args
         .exclude_bed
         .as_ref()
@@ -193,8 +361,11 @@ pub(super) fn load_regions(
         })
         .transpose()?;
 
-    // extract the motif positions, if given
-    let tid_motif_to_positions = motifs.as_ref().map(|motifs| {
+    // extract the motif positions, if given, a dry run only checks the
+    // motifs
+    let motifs_to_find =
+        if input_args.dry_run { None } else { motifs.as_ref() };
+    let tid_motif_to_positions = motifs_to_find.map(|motifs| {
         let pb =
             master_progress_bar.add(get_subroutine_progress_bar(contigs.len()));
         master_progress_bar.suspend(|| {
@@ -215,11 +386,7 @@ pub(super) fn load_regions(
                 .map(|(tid, raw_seq)| {
                     let seq =
                         raw_seq.iter().map(|&b| b as char).collect::<String>();
-                    let seq = if input_args.mask {
-                        seq
-                    } else {
-                        seq.to_ascii_uppercase()
-                    };
+                    let seq = normalize_reference_seq(seq, input_args.mask);
                     motifs
                         .par_iter()
                         .enumerate()
@@ -319,7 +486,12 @@ pub(super) fn load_regions(
                 );
                 let reference_records = get_targets(reader.header(), region);
                 let reference_records =
-                    if let Some(pf) = include_positions.as_ref() {
+                    if let Some(regions) = traversal_regions.as_ref() {
+                        regions.traversal_reference_records(
+                            reference_records,
+                            input_args.interval_size,
+                        )
+                    } else if let Some(pf) = include_positions.as_ref() {
                         pf.optimize_reference_records(
                             reference_records,
                             input_args.interval_size,
@@ -338,6 +510,9 @@ pub(super) fn load_regions(
                 )?;
                 Some(feeder)
             }
+            Err(_) if traversal_regions.is_some() => {
+                bail!("cannot use --regions without an indexed modBAM")
+            }
             Err(_) => {
                 info!(
                     "did not find index to modBAM, defaulting to serial scan"
@@ -345,6 +520,8 @@ pub(super) fn load_regions(
                 None
             }
         }
+    } else if traversal_regions.is_some() {
+        bail!("cannot use --regions when streaming the modBAM")
     } else {
         None
     };
@@ -361,7 +538,20 @@ pub(super) fn load_regions(
         exclude_positions,
         include_unmapped_reads,
         include_unmapped_positions,
+        traversal_regions,
     );
+    let reference_position_filter = if input_args.include_softclipped {
+        info!("including calls on soft-clipped bases");
+        reference_position_filter.with_soft_clipped()
+    } else {
+        reference_position_filter
+    };
+    let reference_position_filter = if input_args.include_no_calls {
+        info!("including rows for motif positions without a call");
+        reference_position_filter.with_no_calls(input_args.kmer_size)
+    } else {
+        reference_position_filter
+    };
 
     Ok((reference_and_intervals, reference_position_filter, motif_lookup))
 }
@@ -369,13 +559,17 @@ pub(super) fn load_regions(
 pub(super) fn run_extract_reads(
     mut reader: bam::Reader,
     in_bam: String,
+    reference: Option<PathBuf>,
     references_and_intervals: Option<ReferenceIntervalsFeeder>,
     schedule: Option<SamplingSchedule>,
     collapse_method: Option<CollapseMethod>,
     edge_filter: Option<EdgeFilter>,
+    alignment_filter: Option<AlignmentIdentityFilter>,
+    keep_records: bool,
     allow_non_primary: bool,
     kmer_size: usize,
     remove_inferred: bool,
+    mod_code_filter: Option<ModCodeFilter>,
     reference_position_filter: ReferencePositionFilter,
     snd: crossbeam::channel::Sender<anyhow::Result<ReadsBaseModProfile>>,
     queue_size: usize,
@@ -384,6 +578,10 @@ pub(super) fn run_extract_reads(
     mapped_only: bool,
     multi_prog: MultiProgress,
 ) {
+    // finding no-calls needs the records, drop them after unless they were
+    // asked for
+    let drop_records =
+        !keep_records && reference_position_filter.finds_no_calls();
     let gauge = multi_prog.add(get_guage(queue_size));
     gauge.set_message("enqueued processed reads");
     gauge.set_position(snd.len() as u64);
@@ -434,11 +632,16 @@ pub(super) fn run_extract_reads(
                                 })
                                 .unwrap_or_else(|| {
                                     RecordSampler::new_passthrough()
-                                });
+                                })
+                                .with_alignment_filter(alignment_filter)
+                                .with_keep_records(
+                                    keep_records || drop_records,
+                                );
                             let batch_result = sample_reads_from_interval::<
                                 ReadsBaseModProfile,
                             >(
                                 &bam_fp,
+                                reference.as_ref(),
                                 cc.chrom_tid(),
                                 cc.start_pos(),
                                 cc.end_pos(),
@@ -458,11 +661,26 @@ pub(super) fn run_extract_reads(
                                     reads_base_mod_profile
                                 }
                             })
+                            .map(|reads_base_mod_profile| match mod_code_filter
+                                .as_ref()
+                            {
+                                Some(filter) => filter
+                                    .filter_read_base_mod_probs(
+                                        reads_base_mod_profile,
+                                    ),
+                                None => reads_base_mod_profile,
+                            })
                             .map(|reads_base_mod_profile| {
                                 reference_position_filter
                                     .filter_read_base_mod_probs(
                                         reads_base_mod_profile,
                                     )
+                            })
+                            .map(|mut reads_base_mod_profile| {
+                                if drop_records {
+                                    reads_base_mod_profile.take_records();
+                                }
+                                reads_base_mod_profile
                             });
 
                             let num_reads_success = batch_result
@@ -501,7 +719,7 @@ pub(super) fn run_extract_reads(
             } else {
                 debug!("processing unmapped reads");
             }
-            let reader = bam::IndexedReader::from_path(&bam_fp)
+            let reader = get_indexed_reader(&bam_fp, reference.as_ref())
                 .and_then(|mut reader| {
                     reader.fetch(FetchDefinition::Unmapped).map(|_| reader)
                 })
@@ -510,24 +728,23 @@ pub(super) fn run_extract_reads(
                 });
             match reader {
                 Ok(mut reader) => {
-                    let (skip, fail) = process_records_to_chan(
+                    let counts = process_records_to_chan(
                         reader.records(),
                         &multi_prog,
                         &reference_position_filter,
+                        mod_code_filter.as_ref(),
                         snd.clone(),
                         n_unmapped_reads,
                         collapse_method.as_ref(),
                         edge_filter.as_ref(),
+                        alignment_filter,
+                        keep_records,
                         false,
                         false,
                         "unmapped ",
                         kmer_size,
                     );
-                    let _ = snd.send(Ok(ReadsBaseModProfile::new(
-                        Vec::new(),
-                        skip,
-                        fail,
-                    )));
+                    let _ = snd.send(Ok(counts));
                 }
                 Err(e) => {
                     error!(
@@ -539,59 +756,94 @@ pub(super) fn run_extract_reads(
             }
         }
     } else {
-        let (skip, fail) = process_records_to_chan(
+        let counts = process_records_to_chan(
             reader.records(),
             &multi_prog,
             &reference_position_filter,
+            mod_code_filter.as_ref(),
             snd.clone(),
             n_reads,
             collapse_method.as_ref(),
             edge_filter.as_ref(),
+            alignment_filter,
+            keep_records,
             mapped_only,
             allow_non_primary,
             "",
             kmer_size,
         );
-        let _ = snd.send(Ok(ReadsBaseModProfile::new(Vec::new(), skip, fail)));
+        let _ = snd.send(Ok(counts));
     }
 }
 
-fn process_records_to_chan<'a, T: Read>(
+/// Records are read ahead and processed in batches on the current thread
+/// pool, streamed and unindexed input can only be read serially.
+fn process_records_to_chan<T: Read + Send>(
     records: bam::Records<T>,
     multi_pb: &MultiProgress,
     reference_position_filter: &ReferencePositionFilter,
+    mod_code_filter: Option<&ModCodeFilter>,
     snd: crossbeam::channel::Sender<anyhow::Result<ReadsBaseModProfile>>,
     n_reads: Option<usize>,
     collapse_method: Option<&CollapseMethod>,
     edge_filter: Option<&EdgeFilter>,
+    alignment_filter: Option<AlignmentIdentityFilter>,
+    keep_records: bool,
     only_mapped: bool,
     allow_non_primary: bool,
     message: &'static str,
     kmer_size: usize,
-) -> (usize, usize) {
-    let mut mod_iter =
-        TrackingModRecordIter::new(records, false, allow_non_primary);
+) -> ReadsBaseModProfile {
+    let criteria = ModRecordCriteria::new(false, allow_non_primary)
+        .with_alignment_filter(alignment_filter);
+    let mut counts = ModRecordCounts::default();
     let pb = multi_pb.add(get_ticker());
     pb.set_message(format!("{message}records processed"));
-    for (record, read_id, mod_base_info) in &mut mod_iter {
-        if record.is_unmapped() && only_mapped {
-            continue;
-        }
-        let mod_profile = match ReadBaseModProfile::process_record(
-            &record,
-            &read_id,
-            mod_base_info,
-            collapse_method,
-            edge_filter,
-            kmer_size,
-        ) {
-            Ok(mod_profile) => {
-                ReadsBaseModProfile::new(vec![mod_profile], 0, 0)
-            }
-            Err(_) => ReadsBaseModProfile::new(Vec::new(), 0, 1),
+    let drop_records =
+        !keep_records && reference_position_filter.finds_no_calls();
+    let process_record = |result| {
+        let (outcome, used) = criteria.check(result);
+        let mod_profile = used
+            .filter(|(record, _, _)| !(record.is_unmapped() && only_mapped))
+            .map(|(record, read_id, mod_base_info)| {
+                let mod_profile = match ReadBaseModProfile::process_record(
+                    &record,
+                    &read_id,
+                    mod_base_info,
+                    collapse_method,
+                    edge_filter,
+                    kmer_size,
+                ) {
+                    Ok(mod_profile) => {
+                        let mod_profile = if keep_records || drop_records {
+                            mod_profile.with_record(Some(record))
+                        } else {
+                            mod_profile
+                        };
+                        ReadsBaseModProfile::new(vec![mod_profile], 0, 0)
+                    }
+                    Err(_) => ReadsBaseModProfile::new(Vec::new(), 0, 1),
+                };
+                let mod_profile = match mod_code_filter {
+                    Some(filter) => {
+                        filter.filter_read_base_mod_probs(mod_profile)
+                    }
+                    None => mod_profile,
+                };
+                let mut mod_profile = reference_position_filter
+                    .filter_read_base_mod_probs(mod_profile);
+                if drop_records {
+                    mod_profile.take_records();
+                }
+                mod_profile
+            });
+        (outcome, mod_profile)
+    };
+    let sink = |(outcome, mod_profile): (_, Option<ReadsBaseModProfile>)| {
+        counts.add(outcome);
+        let Some(mod_profile) = mod_profile else {
+            return Ok(true);
         };
-        let mod_profile =
-            reference_position_filter.filter_read_base_mod_probs(mod_profile);
         match snd.send(Ok(mod_profile)) {
             Ok(_) => {
                 pb.inc(1);
@@ -607,9 +859,17 @@ fn process_records_to_chan<'a, T: Read>(
             n_reads.map(|nr| pb.position() as usize >= nr).unwrap_or(false);
         if done {
             debug!("stopping after processing {} reads", pb.position());
-            break;
         }
+        Ok(!done)
+    };
+    if let Err(e) = run_read_ahead(records, process_record, sink) {
+        error!("failed to process records, {e}");
     }
     pb.finish_and_clear();
-    (mod_iter.num_skipped, mod_iter.num_failed)
+    ReadsBaseModProfile {
+        profiles: Vec::new(),
+        num_skips: counts.num_skipped,
+        num_fails: counts.num_failed,
+        num_mm_mn_mismatch: counts.num_mm_mn_mismatch,
+    }
 }
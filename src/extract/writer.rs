@@ -1,19 +1,27 @@
 use std::collections::HashMap;
 use std::io::Write;
 
+use crate::extract::features::FeatureLookup;
 use crate::mod_bam::BaseModCall;
-use crate::motifs::motif_bed::MotifPositionLookup;
+use crate::motifs::motif_bed::{AmbiguousBases, MotifPositionLookup};
 use crate::read_ids_to_base_mod_probs::{
-    PositionModCalls, ReadBaseModProfile, ReadsBaseModProfile,
+    CigarContext, NoCallSite, PositionModCalls, ReadBaseModProfile,
+    ReadsBaseModProfile,
 };
+use crate::sqlite::ColumnType;
 use crate::threshold_mod_caller::MultipleThresholdModCaller;
 use crate::util::{
-    get_reference_mod_strand, Kmer, Strand, MISSING_SYMBOL, TAB,
+    get_reference_mod_strand, thymine_to_uracil_label, Kmer, Strand,
+    MISSING_SYMBOL, TAB,
 };
-use crate::writers::TsvWriter;
+use crate::writers::{FinishWrite, TsvWriter};
 
 impl PositionModCalls {
-    pub(super) fn header(with_motifs: bool) -> String {
+    pub(super) fn header(
+        with_motifs: bool,
+        with_cigar_context: bool,
+        with_features: bool,
+    ) -> String {
         let mut fields = vec![
             "read_id",
             "forward_read_position",
@@ -42,6 +50,12 @@ impl PositionModCalls {
         if with_motifs {
             fields.push("motifs")
         }
+        if with_cigar_context {
+            fields.extend(CigarContext::COLUMNS)
+        }
+        if with_features {
+            fields.extend(FeatureLookup::COLUMNS)
+        }
         fields.join("\t")
     }
 
@@ -55,6 +69,10 @@ impl PositionModCalls {
         skip_inferred: bool,
         motif_position_lookup: Option<&MotifPositionLookup>,
         with_motifs: bool,
+        rna_labels: bool,
+        ambiguous_bases: AmbiguousBases,
+        with_cigar_context: bool,
+        feature_lookup: Option<&FeatureLookup>,
     ) -> Option<String> {
         let filtered = caller.call(&self.canonical_base, &self.base_mod_probs)
             == BaseModCall::Filtered;
@@ -109,8 +127,14 @@ impl PositionModCalls {
                 None
             } else {
                 reference_seqs.get(&chrom_name_label).map(|s| {
-                    Kmer::from_seq(s, ref_pos as usize, self.query_kmer.size)
-                        .to_string()
+                    ambiguous_bases.ref_kmer(
+                        Kmer::from_seq(
+                            s,
+                            ref_pos as usize,
+                            self.query_kmer.size,
+                        )
+                        .to_string(),
+                    )
                 })
             }
         } else {
@@ -124,6 +148,22 @@ impl PositionModCalls {
             self.canonical_base.char()
         };
         let within_alignment = chrom_name.is_some() && self.within_alignment();
+        let (ref_kmer_rep, query_kmer, canonical_base, modified_primary_base) =
+            if rna_labels {
+                (
+                    thymine_to_uracil_label(ref_kmer_rep),
+                    thymine_to_uracil_label(&query_kmer),
+                    thymine_to_uracil_label(&canonical_base.to_string()),
+                    thymine_to_uracil_label(&modified_primary_base.to_string()),
+                )
+            } else {
+                (
+                    ref_kmer_rep.to_owned(),
+                    query_kmer,
+                    canonical_base.to_string(),
+                    modified_primary_base.to_string(),
+                )
+            };
 
         let mut s = format!(
             "\
@@ -164,11 +204,178 @@ impl PositionModCalls {
                 s.push_str(MISSING_SYMBOL);
             }
         }
+        if with_cigar_context {
+            s.push(TAB);
+            s.push_str(&self.cigar_context.to_columns());
+        }
+        if let Some(feature_lookup) = feature_lookup {
+            s.push(TAB);
+            s.push_str(
+                &feature_lookup.to_columns(&chrom_name_label, ref_position),
+            );
+        }
         s.push_str("\n");
         Some(s)
     }
 }
 
+impl NoCallSite {
+    /// Row for a motif position without a call, with the columns of the
+    /// `calls` table when `calls_columns` is true otherwise those of the
+    /// `full` table. The probability and code columns are ".".
+    pub(super) fn to_row(
+        &self,
+        profile: &ReadBaseModProfile,
+        chrom_name: &str,
+        reference_seqs: &HashMap<String, Vec<u8>>,
+        motif_position_lookup: Option<&MotifPositionLookup>,
+        with_motifs: bool,
+        rna_labels: bool,
+        ambiguous_bases: AmbiguousBases,
+        with_cigar_context: bool,
+        feature_lookup: Option<&FeatureLookup>,
+        calls_columns: bool,
+    ) -> String {
+        let ref_mod_strand =
+            get_reference_mod_strand(self.mod_strand, self.alignment_strand);
+        let motif_hits = motif_position_lookup.zip(profile.chrom_id).and_then(
+            |(lu, tid)| {
+                lu.get_motif_hits(
+                    tid,
+                    self.ref_position as usize,
+                    ref_mod_strand,
+                )
+            },
+        );
+        let ref_kmer = reference_seqs
+            .get(chrom_name)
+            .map(|s| {
+                ambiguous_bases.ref_kmer(
+                    Kmer::from_seq(
+                        s,
+                        self.ref_position as usize,
+                        self.query_kmer.size,
+                    )
+                    .to_string(),
+                )
+            })
+            .unwrap_or(MISSING_SYMBOL.to_string());
+        let query_kmer = self.query_kmer.to_string();
+        let canonical_base = self.canonical_base.char().to_string();
+        let modified_primary_base = if self.mod_strand == Strand::Negative {
+            self.canonical_base.complement().char().to_string()
+        } else {
+            canonical_base.clone()
+        };
+        let (ref_kmer, query_kmer, canonical_base, modified_primary_base) =
+            if rna_labels {
+                (
+                    thymine_to_uracil_label(&ref_kmer),
+                    thymine_to_uracil_label(&query_kmer),
+                    thymine_to_uracil_label(&canonical_base),
+                    thymine_to_uracil_label(&modified_primary_base),
+                )
+            } else {
+                (ref_kmer, query_kmer, canonical_base, modified_primary_base)
+            };
+        // fail, inferred, and within_alignment for calls, only inferred for
+        // full
+        let flags = if calls_columns {
+            format!("false{TAB}false{TAB}true")
+        } else {
+            "false".to_string()
+        };
+
+        let mut s = format!(
+            "\
+            {}{TAB}\
+            {}{TAB}\
+            {}{TAB}\
+            {chrom_name}{TAB}\
+            {}{TAB}\
+            {}{TAB}\
+            {}{TAB}\
+            {}{TAB}\
+            {}{TAB}\
+            {}{TAB}\
+            {}{TAB}\
+            {}{TAB}\
+            {MISSING_SYMBOL}{TAB}\
+            {MISSING_SYMBOL}{TAB}\
+            {}{TAB}\
+            {ref_kmer}{TAB}\
+            {query_kmer}{TAB}\
+            {canonical_base}{TAB}\
+            {modified_primary_base}{TAB}\
+            {flags}{TAB}\
+            {}",
+            &profile.record_name,
+            self.query_position,
+            self.ref_position,
+            self.mod_strand.to_char(),
+            self.alignment_strand.to_char(),
+            ref_mod_strand.to_char(),
+            self.num_soft_clipped_start,
+            self.num_soft_clipped_end,
+            profile.alignment_start.map(|x| x as i64).unwrap_or(-1i64),
+            profile.alignment_end.map(|x| x as i64).unwrap_or(-1i64),
+            self.read_length,
+            self.q_base,
+            profile.flag,
+        );
+        if with_motifs {
+            s.push(TAB);
+            s.push_str(motif_hits.as_deref().unwrap_or(MISSING_SYMBOL));
+        }
+        if with_cigar_context {
+            s.push(TAB);
+            s.push_str(&self.cigar_context.to_columns());
+        }
+        if let Some(feature_lookup) = feature_lookup {
+            s.push(TAB);
+            s.push_str(
+                &feature_lookup.to_columns(chrom_name, self.ref_position),
+            );
+        }
+        s.push('\n');
+        s
+    }
+}
+
+/// Indices made on the tables written with `--out-format sqlite`.
+pub(super) const SQLITE_INDICES: &[&[&str]] =
+    &[&["chrom", "ref_position"], &["read_id"]];
+
+/// Columns of the table written with `--out-format sqlite` (and `parquet`),
+/// taken from the TSV header.
+pub(super) fn sqlite_columns(header: &str) -> Vec<(String, ColumnType)> {
+    header
+        .split(TAB)
+        .map(|name| {
+            let typ = match name {
+                "mod_qual" | "call_prob" => ColumnType::Real,
+                "forward_read_position"
+                | "ref_position"
+                | "fw_soft_clipped_start"
+                | "fw_soft_clipped_end"
+                | "alignment_start"
+                | "alignment_end"
+                | "read_length"
+                | "base_qual"
+                | "fail"
+                | "inferred"
+                | "within_alignment"
+                | "flag"
+                | "indel_distance"
+                | "feature_start_offset"
+                | "feature_center_offset" => ColumnType::Integer,
+                _ => ColumnType::Text,
+            };
+            (name.to_string(), typ)
+        })
+        .collect()
+}
+
 pub(crate) trait OutwriterWithMemory<T> {
     fn write(
         &mut self,
@@ -176,6 +383,8 @@ pub(crate) trait OutwriterWithMemory<T> {
         motif_position_lookup: Option<&MotifPositionLookup>,
     ) -> anyhow::Result<u64>;
     fn num_reads(&self) -> usize;
+    /// Called once after the last item has been written.
+    fn finish(&mut self) -> anyhow::Result<()>;
 }
 
 pub struct TsvWriterWithContigNames<W: Write, C> {
@@ -186,6 +395,29 @@ pub struct TsvWriterWithContigNames<W: Write, C> {
     caller: C,
     pass_only: bool,
     with_motifs: bool,
+    row_options: RowOptions,
+}
+
+/// How the columns of each row are labelled and which optional columns are
+/// added, the same for every output format.
+#[derive(Default)]
+pub(crate) struct RowOptions {
+    /// Report thymine as uracil in the kmer and base columns, for direct RNA
+    /// data.
+    pub(crate) rna_labels: bool,
+    /// How ambiguous reference bases are reported in the ref_kmer column.
+    pub(crate) ambiguous_bases: AmbiguousBases,
+    /// Add the CIGAR-derived alignment context columns to each row.
+    pub(crate) with_cigar_context: bool,
+    /// Add the position of each call relative to the feature it's in, see
+    /// [`FeatureLookup`].
+    pub(crate) feature_lookup: Option<FeatureLookup>,
+}
+
+impl<W: Write, C> TsvWriterWithContigNames<W, C> {
+    pub(crate) fn with_row_options(self, row_options: RowOptions) -> Self {
+        Self { row_options, ..self }
+    }
 }
 
 impl<W: Write> TsvWriterWithContigNames<W, ()> {
@@ -203,11 +435,12 @@ impl<W: Write> TsvWriterWithContigNames<W, ()> {
             caller: (),
             pass_only: false,
             with_motifs,
+            row_options: RowOptions::default(),
         })
     }
 }
 
-impl<W: Write> OutwriterWithMemory<ReadsBaseModProfile>
+impl<W: FinishWrite> OutwriterWithMemory<ReadsBaseModProfile>
     for TsvWriterWithContigNames<W, ()>
 {
     fn write(
@@ -233,6 +466,26 @@ impl<W: Write> OutwriterWithMemory<ReadsBaseModProfile>
                     profile.flag,
                     motif_position_lookup,
                     self.with_motifs,
+                    self.row_options.rna_labels,
+                    self.row_options.ambiguous_bases,
+                    self.row_options.with_cigar_context,
+                    self.row_options.feature_lookup.as_ref(),
+                );
+                self.tsv_writer.write(row.as_bytes())?;
+                rows_written += 1;
+            }
+            for no_call in profile.no_calls.iter() {
+                let row = no_call.to_row(
+                    profile,
+                    chrom_name,
+                    &self.name_to_seq,
+                    motif_position_lookup,
+                    self.with_motifs,
+                    self.row_options.rna_labels,
+                    self.row_options.ambiguous_bases,
+                    self.row_options.with_cigar_context,
+                    self.row_options.feature_lookup.as_ref(),
+                    false,
                 );
                 self.tsv_writer.write(row.as_bytes())?;
                 rows_written += 1;
@@ -245,6 +498,10 @@ impl<W: Write> OutwriterWithMemory<ReadsBaseModProfile>
     fn num_reads(&self) -> usize {
         self.number_of_written_reads
     }
+
+    fn finish(&mut self) -> anyhow::Result<()> {
+        self.tsv_writer.finish()
+    }
 }
 
 impl<W: Write> TsvWriterWithContigNames<W, MultipleThresholdModCaller> {
@@ -264,11 +521,12 @@ impl<W: Write> TsvWriterWithContigNames<W, MultipleThresholdModCaller> {
             caller,
             pass_only,
             with_motifs,
+            row_options: RowOptions::default(),
         })
     }
 }
 
-impl<W: Write> OutwriterWithMemory<ReadsBaseModProfile>
+impl<W: FinishWrite> OutwriterWithMemory<ReadsBaseModProfile>
     for TsvWriterWithContigNames<W, MultipleThresholdModCaller>
 {
     fn write(
@@ -292,11 +550,31 @@ impl<W: Write> OutwriterWithMemory<ReadsBaseModProfile>
                     false,
                     motif_position_lookup,
                     self.with_motifs,
+                    self.row_options.rna_labels,
+                    self.row_options.ambiguous_bases,
+                    self.row_options.with_cigar_context,
+                    self.row_options.feature_lookup.as_ref(),
                 )
                 .map(|s| self.tsv_writer.write(s.as_bytes()))
                 .transpose()?;
                 rows_written += 1;
             }
+            for no_call in profile.no_calls.iter() {
+                let row = no_call.to_row(
+                    profile,
+                    chrom_name.map(|x| x.as_str()).unwrap_or(MISSING_SYMBOL),
+                    &self.name_to_seq,
+                    motif_position_lookup,
+                    self.with_motifs,
+                    self.row_options.rna_labels,
+                    self.row_options.ambiguous_bases,
+                    self.row_options.with_cigar_context,
+                    self.row_options.feature_lookup.as_ref(),
+                    true,
+                );
+                self.tsv_writer.write(row.as_bytes())?;
+                rows_written += 1;
+            }
             self.number_of_written_reads += 1;
         }
         Ok(rows_written)
@@ -305,4 +583,8 @@ impl<W: Write> OutwriterWithMemory<ReadsBaseModProfile>
     fn num_reads(&self) -> usize {
         self.number_of_written_reads
     }
+
+    fn finish(&mut self) -> anyhow::Result<()> {
+        self.tsv_writer.finish()
+    }
 }
@@ -1,21 +1,168 @@
 use std::collections::BTreeMap;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
-use anyhow::{bail, Context};
+use anyhow::{anyhow, bail, Context};
 use bio::io::fasta::IndexedReader as FastaReader;
 use log::debug;
 use rayon::prelude::*;
 use rust_lapper::{Interval, Lapper};
-use rustc_hash::FxHashMap;
+use rustc_hash::{FxHashMap, FxHashSet};
 
 use crate::motifs::motif_bed::{
     find_motif_hits, MotifLocations, MultipleMotifLocations, RegexMotif,
 };
 use crate::position_filter::StrandedPositionFilter;
-use crate::util::StrandRule;
+use crate::util::{normalize_reference_seq, StrandRule};
 
-pub struct MotifLocationsLookup {
+/// Reference sequences loaded one contig at a time, inputs are expected to
+/// be sorted so each contig is loaded once.
+pub(crate) struct ReferenceSequences {
     reader: FastaReader<std::fs::File>,
+    contigs: FxHashSet<String>,
+    curr_contig: Option<(String, String)>,
+}
+
+impl ReferenceSequences {
+    pub(crate) fn from_path(fasta_fp: &Path) -> anyhow::Result<Self> {
+        let reader = FastaReader::from_file(&fasta_fp).with_context(|| {
+            format!("failed to open indexed FASTA at {fasta_fp:?}")
+        })?;
+        let contigs = reader
+            .index
+            .sequences()
+            .into_iter()
+            .map(|s| s.name)
+            .collect::<FxHashSet<String>>();
+        Ok(Self { reader, contigs, curr_contig: None })
+    }
+
+    /// Names of the contigs in the order of the FASTA index.
+    pub(crate) fn contig_names(&self) -> Vec<String> {
+        self.reader.index.sequences().into_iter().map(|s| s.name).collect()
+    }
+
+    pub(crate) fn get_sequence(
+        &mut self,
+        contig: &str,
+    ) -> anyhow::Result<Option<&str>> {
+        if !self.contigs.contains(contig) {
+            return Ok(None);
+        }
+        let load = self
+            .curr_contig
+            .as_ref()
+            .map(|(name, _)| name != contig)
+            .unwrap_or(true);
+        if load {
+            let seq = self.load_sequence(contig)?;
+            self.curr_contig = seq.map(|seq| (contig.to_owned(), seq));
+        }
+        Ok(self.curr_contig.as_ref().map(|(_, seq)| seq.as_str()))
+    }
+
+    /// Read the sequence of a contig without keeping it, for callers that
+    /// cache sequences themselves.
+    pub(crate) fn load_sequence(
+        &mut self,
+        contig: &str,
+    ) -> anyhow::Result<Option<String>> {
+        if !self.contigs.contains(contig) {
+            return Ok(None);
+        }
+        debug!("loading reference sequence for {contig}");
+        self.reader.fetch_all(contig)?;
+        let mut seq = Vec::new();
+        self.reader.read(&mut seq)?;
+        Ok(Some(normalize_reference_seq(String::from_utf8(seq)?, false)))
+    }
+}
+
+/// Maximum number of contig names listed in errors about contigs missing
+/// from the references.
+const MAX_CONTIGS_IN_ERROR: usize = 10;
+
+/// Which of one or more indexed FASTAs each contig is in, so that the
+/// references for a mixed assembly (e.g. host and pathogen) can be given
+/// separately instead of concatenated.
+pub(crate) struct ReferenceContigs {
+    fasta_fps: Vec<PathBuf>,
+    contig_to_fasta: FxHashMap<String, usize>,
+}
+
+impl ReferenceContigs {
+    pub(crate) fn from_paths(fasta_fps: &[PathBuf]) -> anyhow::Result<Self> {
+        if fasta_fps.is_empty() {
+            bail!("need at least one reference FASTA")
+        }
+        let mut contig_to_fasta = FxHashMap::default();
+        for (idx, fasta_fp) in fasta_fps.iter().enumerate() {
+            let reader =
+                FastaReader::from_file(fasta_fp).with_context(|| {
+                    format!("failed to open indexed FASTA at {fasta_fp:?}")
+                })?;
+            for seq in reader.index.sequences() {
+                if let Some(other) =
+                    contig_to_fasta.insert(seq.name.clone(), idx)
+                {
+                    bail!(
+                        "contig {} is in more than one reference, {:?} and \
+                         {:?}",
+                        seq.name,
+                        fasta_fps[other],
+                        fasta_fp
+                    )
+                }
+            }
+        }
+        debug!(
+            "{} reference contig(s) in {} FASTA(s)",
+            contig_to_fasta.len(),
+            fasta_fps.len()
+        );
+        Ok(Self { fasta_fps: fasta_fps.to_vec(), contig_to_fasta })
+    }
+
+    /// Fail, listing the missing contigs, when any of `contigs` aren't in
+    /// the references.
+    pub(crate) fn check_contigs<'a>(
+        &self,
+        contigs: impl IntoIterator<Item = &'a str>,
+    ) -> anyhow::Result<()> {
+        let missing = contigs
+            .into_iter()
+            .filter(|contig| !self.contig_to_fasta.contains_key(*contig))
+            .collect::<Vec<&str>>();
+        if missing.is_empty() {
+            return Ok(());
+        }
+        let mut listed = missing
+            .iter()
+            .take(MAX_CONTIGS_IN_ERROR)
+            .copied()
+            .collect::<Vec<&str>>()
+            .join(", ");
+        if missing.len() > MAX_CONTIGS_IN_ERROR {
+            listed.push_str(&format!(
+                " and {} more",
+                missing.len() - MAX_CONTIGS_IN_ERROR
+            ));
+        }
+        bail!(
+            "{} contig(s) are not in any of the reference FASTAs ({}): \
+             {listed}",
+            missing.len(),
+            self.fasta_fps
+                .iter()
+                .map(|fp| fp.to_string_lossy())
+                .collect::<Vec<_>>()
+                .join(", ")
+        )
+    }
+}
+
+pub struct MotifLocationsLookup {
+    readers: Vec<FastaReader<std::fs::File>>,
+    contig_to_reader: FxHashMap<String, usize>,
     mask: bool,
     motifs: Vec<RegexMotif>,
     longest_motif_length: u64,
@@ -23,7 +170,7 @@ pub struct MotifLocationsLookup {
 
 impl MotifLocationsLookup {
     pub fn from_paths(
-        fasta_fp: &PathBuf,
+        fasta_fps: &[PathBuf],
         mask: bool,
         _index_fp: Option<&PathBuf>,
         motifs: Vec<RegexMotif>,
@@ -31,11 +178,31 @@ impl MotifLocationsLookup {
         if motifs.is_empty() {
             bail!("motifs is empty, are you sure you want to make a lookup?");
         }
-        let reader = FastaReader::from_file(fasta_fp)?;
+        let reference_contigs = ReferenceContigs::from_paths(fasta_fps)?;
+        let readers = fasta_fps
+            .iter()
+            .map(FastaReader::from_file)
+            .collect::<anyhow::Result<Vec<_>>>()?;
         let longest_motif_length =
             motifs.iter().map(|m| m.length() as u64).max().unwrap();
 
-        Ok(Self { reader, motifs, mask, longest_motif_length })
+        Ok(Self {
+            readers,
+            contig_to_reader: reference_contigs.contig_to_fasta,
+            motifs,
+            mask,
+            longest_motif_length,
+        })
+    }
+
+    fn reader_for(
+        &mut self,
+        contig: &str,
+    ) -> anyhow::Result<&mut FastaReader<std::fs::File>> {
+        let idx = self.contig_to_reader.get(contig).ok_or_else(|| {
+            anyhow!("contig {contig} is not in any of the reference FASTAs")
+        })?;
+        Ok(&mut self.readers[*idx])
     }
 
     #[inline]
@@ -103,17 +270,18 @@ impl MotifLocationsLookup {
         let mut too_close =
             end_w_buffer.saturating_sub(self.longest_motif_length);
         'fetch_loop: loop {
-            self.reader.fetch(contig, range.start, end_w_buffer)?;
+            let reader = self.reader_for(contig)?;
+            reader.fetch(contig, range.start, end_w_buffer)?;
             let l = end_w_buffer
                 .checked_sub(range.start)
                 .expect("end should be >= start") as usize;
             let mut buff = Vec::<u8>::with_capacity(l);
-            self.reader.read(&mut buff)?;
+            reader.read(&mut buff)?;
             buff.shrink_to_fit();
             debug_assert_eq!(buff.len(), l);
             let seq = String::from_utf8(buff)
                 .context("got illegal characters in sequence")?;
-            let seq = if self.mask { seq } else { seq.to_ascii_uppercase() };
+            let seq = normalize_reference_seq(seq, self.mask);
             let motif_locations = self.get_motifs_on_seq(
                 &seq,
                 range.start,
@@ -187,6 +355,88 @@ impl MotifLocationsLookup {
         }
     }
 
+    fn fetch_sequence(
+        &mut self,
+        contig: &str,
+        start: u64,
+        end: u64,
+    ) -> anyhow::Result<String> {
+        let reader = self.reader_for(contig)?;
+        reader.fetch(contig, start, end)?;
+        let l =
+            end.checked_sub(start).expect("end should be >= start") as usize;
+        let mut buff = Vec::<u8>::with_capacity(l);
+        reader.read(&mut buff)?;
+        buff.shrink_to_fit();
+        debug_assert_eq!(buff.len(), l);
+        let seq = String::from_utf8(buff)
+            .context("got illegal characters in sequence")?;
+        Ok(normalize_reference_seq(seq, self.mask))
+    }
+
+    /// Find the motif positions in `range`, the sequence is padded so that
+    /// motif occurrences that start before the range are found and the end
+    /// of the range is moved past any motif occurrence straddling it. The
+    /// returned end is the start of the next range, so no motif occurrence is
+    /// split across two chunks.
+    fn get_motif_positions_stranded(
+        &mut self,
+        contig: &str,
+        tid: u32,
+        ref_end: u64,
+        range: std::ops::Range<u64>,
+        stranded_position_filter: Option<&StrandedPositionFilter<()>>,
+    ) -> anyhow::Result<(MultipleMotifLocations, u32)> {
+        let pad = self.longest_motif_length.saturating_sub(1);
+        let fetch_start = range.start.saturating_sub(pad);
+        let mut buffer_size = self.longest_motif_length * 5;
+        loop {
+            let fetch_end = std::cmp::min(range.end + buffer_size, ref_end);
+            let seq = self.fetch_sequence(contig, fetch_start, fetch_end)?;
+            let motif_locations = self.get_motifs_on_seq(
+                &seq,
+                fetch_start,
+                tid,
+                stranded_position_filter,
+            );
+            let end = snap_end_to_motifs(&motif_locations, tid, range.end);
+            if end + pad <= fetch_end || fetch_end >= ref_end {
+                let end = std::cmp::min(end, ref_end);
+                let motif_locations = motif_locations
+                    .motif_locations
+                    .into_iter()
+                    .map(|mls| {
+                        let locations = mls
+                            .tid_to_motif_positions
+                            .into_iter()
+                            .map(|(tid, poss)| {
+                                let filt = poss
+                                    .into_iter()
+                                    .filter(|(p, _)| {
+                                        (range.start..end)
+                                            .contains(&(*p as u64))
+                                    })
+                                    .collect::<_>();
+                                (tid, filt)
+                            })
+                            .collect();
+                        MotifLocations::new(locations, mls.motif)
+                    })
+                    .collect();
+                return Ok((
+                    MultipleMotifLocations::new(motif_locations),
+                    end as u32,
+                ));
+            } else {
+                debug!(
+                    "motif occurrence extends past fetched sequence, \
+                     re-fetching"
+                );
+                buffer_size *= 2;
+            }
+        }
+    }
+
     pub fn get_motif_positions(
         &mut self,
         contig: &str,
@@ -205,25 +455,163 @@ impl MotifLocationsLookup {
                 stranded_position_filter,
             )
         } else {
-            self.reader.fetch(contig, range.start, range.end)?;
-            let l = range
-                .end
-                .checked_sub(range.start)
-                .expect("end should be >= start") as usize;
-            let mut buff = Vec::<u8>::with_capacity(l);
-            self.reader.read(&mut buff)?;
-            buff.shrink_to_fit();
-            debug_assert_eq!(buff.len(), l);
-            let seq = String::from_utf8(buff)
-                .context("got illegal characters in sequence")?;
-            let seq = if self.mask { seq } else { seq.to_ascii_uppercase() };
-            let multiple_motif_locations = self.get_motifs_on_seq(
-                &seq,
-                range.start,
+            self.get_motif_positions_stranded(
+                contig,
                 tid,
+                ref_length as u64,
+                range,
                 stranded_position_filter,
-            );
-            Ok((multiple_motif_locations, range.end as u32))
+            )
+        }
+    }
+}
+
+/// Move `end` past any motif occurrence that starts before it and finishes
+/// after it.
+pub(crate) fn snap_end_to_motifs(
+    motif_locations: &MultipleMotifLocations,
+    tid: u32,
+    end: u64,
+) -> u64 {
+    let mut occurrences = motif_locations
+        .motif_locations
+        .iter()
+        .flat_map(|mls| {
+            let motif = mls.motif();
+            let length = motif.length() as u64;
+            mls.get_locations_unchecked(tid).iter().flat_map(
+                move |(pos, strand_rule)| {
+                    let offsets = match strand_rule {
+                        StrandRule::Positive => vec![motif.forward_offset()],
+                        StrandRule::Negative => vec![motif.reverse_offset()],
+                        StrandRule::Both => {
+                            vec![motif.forward_offset(), motif.reverse_offset()]
+                        }
+                    };
+                    offsets.into_iter().filter_map(move |offset| {
+                        (*pos as u64)
+                            .checked_sub(offset as u64)
+                            .map(|start| (start, start + length))
+                    })
+                },
+            )
+        })
+        .collect::<Vec<(u64, u64)>>();
+    occurrences.sort();
+    occurrences.into_iter().fold(end, |end, (start, stop)| {
+        if start < end && stop > end {
+            stop
+        } else {
+            end
+        }
+    })
+}
+
+#[cfg(test)]
+mod fasta_tests {
+    use std::path::PathBuf;
+
+    use crate::fasta::{MotifLocationsLookup, ReferenceContigs};
+    use crate::motifs::motif_bed::RegexMotif;
+
+    #[test]
+    fn test_reference_contigs() {
+        let fasta_fp = PathBuf::from("tests/resources/CGI_ladder_3.6kb_ref.fa");
+        let reference_contigs =
+            ReferenceContigs::from_paths(std::slice::from_ref(&fasta_fp))
+                .unwrap();
+        assert!(reference_contigs
+            .check_contigs(["oligo_1512_adapters", "oligo_741_adapters"])
+            .is_ok());
+        let missing = (0..12).map(|i| format!("chr{i}")).collect::<Vec<_>>();
+        let err = reference_contigs
+            .check_contigs(
+                std::iter::once("oligo_1512_adapters")
+                    .chain(missing.iter().map(|c| c.as_str())),
+            )
+            .unwrap_err()
+            .to_string();
+        assert!(err.starts_with("12 contig(s) are not in any"), "{err}");
+        let listed = (0..10).map(|i| format!("chr{i}")).collect::<Vec<_>>();
+        assert!(
+            err.ends_with(&format!("{} and 2 more", listed.join(", "))),
+            "{err}"
+        );
+
+        assert!(ReferenceContigs::from_paths(&[]).is_err());
+        // the same contig can't come from two references
+        assert!(ReferenceContigs::from_paths(&[
+            fasta_fp.clone(),
+            fasta_fp.clone()
+        ])
+        .is_err());
+    }
+
+    #[test]
+    fn test_motif_chunks_do_not_split_motifs() {
+        let fasta_fp =
+            std::path::PathBuf::from("tests/resources/CGI_ladder_3.6kb_ref.fa");
+        let contig = "oligo_1512_adapters";
+        let motifs = vec![
+            RegexMotif::parse_string("CG", 0).unwrap(),
+            RegexMotif::parse_string("GATC", 1).unwrap(),
+        ];
+        let mut lookup = MotifLocationsLookup::from_paths(
+            std::slice::from_ref(&fasta_fp),
+            false,
+            None,
+            motifs,
+        )
+        .unwrap();
+        let contig_length = 150u32;
+        let (whole, whole_end) = lookup
+            .get_motif_positions(
+                contig,
+                0,
+                contig_length,
+                0..contig_length as u64,
+                None,
+                false,
+            )
+            .unwrap();
+        assert_eq!(whole_end, contig_length);
+
+        for interval_size in [3u64, 7, 10, 25] {
+            let mut start = 0u64;
+            let mut chunked = vec![Vec::new(); whole.motif_locations.len()];
+            while start < contig_length as u64 {
+                let end =
+                    std::cmp::min(start + interval_size, contig_length as u64);
+                let (locations, snapped_end) = lookup
+                    .get_motif_positions(
+                        contig,
+                        0,
+                        contig_length,
+                        start..end,
+                        None,
+                        false,
+                    )
+                    .unwrap();
+                assert!(snapped_end as u64 >= end);
+                for (i, mls) in locations.motif_locations.iter().enumerate() {
+                    chunked[i].extend(
+                        mls.get_locations_unchecked(0)
+                            .iter()
+                            .map(|(p, s)| (*p, *s)),
+                    );
+                }
+                start = snapped_end as u64;
+            }
+            for (mls, chunked) in
+                whole.motif_locations.iter().zip(chunked.into_iter())
+            {
+                let expected = mls
+                    .get_locations_unchecked(0)
+                    .iter()
+                    .map(|(p, s)| (*p, *s))
+                    .collect::<Vec<_>>();
+                assert_eq!(expected, chunked, "interval size {interval_size}");
+            }
         }
     }
 }
@@ -5,12 +5,13 @@ use std::ops::Range;
 use std::path::PathBuf;
 
 use bio::io::fasta::Reader as FastaReader;
+use bitvec::prelude::*;
 use indicatif::{MultiProgress, ProgressIterator};
 use log::debug;
 use rustc_hash::{FxHashMap, FxHashSet};
 
 use crate::mod_base_code::DnaBase;
-use crate::util::{get_ticker, Strand, StrandRule};
+use crate::util::{get_ticker, uracil_to_thymine, Strand, StrandRule};
 
 #[derive(Debug, Eq, PartialEq, Hash, Ord, PartialOrd)]
 pub(crate) struct StrandedPosition<T>
@@ -22,16 +23,93 @@ where
     pub(crate) value: T,
 }
 
+/// Positions of a single base on one strand of a contig, one bit per
+/// reference position.
+struct BasePositions {
+    strand: Strand,
+    base: DnaBase,
+    bits: BitVec<usize, Lsb0>,
+}
+
+/// The positions on a contig that should be used, a bitset per strand and
+/// base so the whole sequence doesn't need to be kept in memory.
+struct ContigPositions {
+    length: usize,
+    base_positions: Vec<BasePositions>,
+    /// Soft-masked (lowercase) runs of the sequence, only kept when masking
+    /// so that masked positions aren't mistaken for reference mismatches.
+    masked: Vec<Range<usize>>,
+}
+
+impl ContigPositions {
+    fn new(
+        seq: &[u8],
+        positive_strand_bases: &FxHashSet<char>,
+        negative_strand_bases: &FxHashSet<char>,
+        mask: bool,
+    ) -> Self {
+        let mut base_positions = positive_strand_bases
+            .iter()
+            .map(|b| (Strand::Positive, b))
+            .chain(negative_strand_bases.iter().map(|b| (Strand::Negative, b)))
+            .filter_map(|(strand, b)| {
+                DnaBase::parse(*b).ok().map(|base| BasePositions {
+                    strand,
+                    base,
+                    bits: bitvec![usize, Lsb0; 0; seq.len()],
+                })
+            })
+            .collect::<Vec<BasePositions>>();
+        let mut masked = Vec::<Range<usize>>::new();
+        for (position, b) in seq.iter().enumerate() {
+            if mask && b.is_ascii_lowercase() {
+                match masked.last_mut() {
+                    Some(run) if run.end == position => run.end += 1,
+                    _ => masked.push(position..position + 1),
+                }
+            }
+            let b = uracil_to_thymine(*b);
+            let base =
+                char::from(if mask { b } else { b.to_ascii_uppercase() });
+            // a position can only be on one strand, positive takes precedence
+            let strand = if positive_strand_bases.contains(&base) {
+                Strand::Positive
+            } else if negative_strand_bases.contains(&base) {
+                Strand::Negative
+            } else {
+                continue;
+            };
+            if let Some(base_positions) = base_positions
+                .iter_mut()
+                .find(|x| x.strand == strand && x.base.char() == base)
+            {
+                base_positions.bits.set(position, true);
+            }
+        }
+        base_positions.retain(|x| x.bits.any());
+
+        Self { length: seq.len(), base_positions, masked }
+    }
+
+    fn is_masked(&self, idx: usize) -> bool {
+        let i = self.masked.partition_point(|run| run.end <= idx);
+        self.masked.get(i).map(|run| run.contains(&idx)).unwrap_or(false)
+    }
+
+    fn clamp(&self, interval: &Range<u64>) -> Range<usize> {
+        let end = (interval.end as usize).min(self.length);
+        let start = (interval.start as usize).min(end);
+        start..end
+    }
+}
+
 /// A struct to quickly check if a specific position on a strand should be used.
 /// Assumes that a position can only be on either the (+) or (-) strand
 pub(crate) struct GenomePositions {
-    /// these are the bases we care to compare, i.e. C and A for 5mC and
-    /// 6mA, respectively.
-    pub positive_strand_bases: FxHashSet<char>,
-    pub negative_strand_bases: FxHashSet<char>,
-    /// this is the reference genome - we'll search it on the fly to
-    /// reduce memory consumption.
-    contigs: FxHashMap<String, Vec<char>>,
+    /// bitsets of the positions with the bases we care to compare, i.e. C
+    /// and A for 5mC and 6mA, respectively. The reference sequence itself
+    /// isn't kept to reduce memory consumption.
+    contigs: FxHashMap<String, ContigPositions>,
 }
 
 impl GenomePositions {
@@ -64,28 +142,17 @@ impl GenomePositions {
             .filter(|record| all_contigs.contains(record.id()))
             .map(|record| {
                 let contig_name = record.id().to_string();
-                let seq = record
-                    .seq()
-                    .iter()
-                    .map(|b| {
-                        let base = char::from(*b);
-                        if mask {
-                            base
-                        } else {
-                            base.to_ascii_uppercase()
-                        }
-                    })
-                    .collect::<Vec<char>>();
-
-                (contig_name, seq)
+                let positions = ContigPositions::new(
+                    record.seq(),
+                    &pos_bases,
+                    &neg_bases,
+                    mask,
+                );
+                (contig_name, positions)
             })
-            .collect::<FxHashMap<String, Vec<char>>>();
+            .collect::<FxHashMap<String, ContigPositions>>();
 
-        Ok(Self {
-            positive_strand_bases: pos_bases,
-            negative_strand_bases: neg_bases,
-            contigs,
-        })
+        Ok(Self { contigs })
     }
 
     pub(crate) fn get_positions(
@@ -94,41 +161,223 @@ impl GenomePositions {
         dmr_interval: &Range<u64>,
         strand_rule: StrandRule,
     ) -> Option<Vec<StrandedPosition<DnaBase>>> {
-        let interval =
-            (dmr_interval.start as usize)..(dmr_interval.end as usize);
-        self.contigs.get(chrom_name).map(|seq| {
-            seq[interval.start..interval.end]
+        self.contigs.get(chrom_name).map(|contig| {
+            let interval = contig.clamp(dmr_interval);
+            let mut positions = contig
+                .base_positions
                 .iter()
-                .enumerate()
-                .filter_map(|(i, base)| {
-                    let position = i + interval.start;
-                    if self.positive_strand_bases.contains(base)
-                        && strand_rule.covers(Strand::Positive)
-                    {
-                        Some(StrandedPosition {
-                            position: position as u64,
-                            strand: Strand::Positive,
-                            value: DnaBase::parse(*base).unwrap(),
-                        })
-                    } else if self.negative_strand_bases.contains(base)
-                        && strand_rule.covers(Strand::Negative)
-                    {
-                        Some(StrandedPosition {
-                            position: position as u64,
-                            strand: Strand::Negative,
-                            value: DnaBase::parse(*base).unwrap(),
-                        })
-                    } else {
-                        None
-                    }
+                .filter(|x| strand_rule.covers(x.strand))
+                .flat_map(|x| {
+                    x.bits[interval.clone()].iter_ones().map(|i| {
+                        StrandedPosition {
+                            position: (i + interval.start) as u64,
+                            strand: x.strand,
+                            value: x.base,
+                        }
+                    })
                 })
-                .collect::<Vec<StrandedPosition<DnaBase>>>()
+                .collect::<Vec<StrandedPosition<DnaBase>>>();
+            positions.sort_by_key(|p| p.position);
+            positions
         })
     }
 
+    /// Check if a single stranded position (and base) should be used.
+    pub(crate) fn contains(
+        &self,
+        chrom_name: &str,
+        position: &StrandedPosition<DnaBase>,
+    ) -> bool {
+        let idx = position.position as usize;
+        self.contigs
+            .get(chrom_name)
+            .filter(|contig| idx < contig.length)
+            .map(|contig| {
+                contig.base_positions.iter().any(|x| {
+                    x.strand == position.strand
+                        && x.base == position.value
+                        && x.bits[idx]
+                })
+            })
+            .unwrap_or(false)
+    }
+
+    /// Check if a stranded position (and base) from a bedMethyl record
+    /// disagrees with the reference, either the reference doesn't have the
+    /// base at the position or the position is past the end of the contig.
+    /// Soft-masked positions and contigs that aren't in the reference are not
+    /// mismatches.
+    pub(crate) fn is_reference_mismatch(
+        &self,
+        chrom_name: &str,
+        position: &StrandedPosition<DnaBase>,
+    ) -> bool {
+        let Some(contig) = self.contigs.get(chrom_name) else {
+            return false;
+        };
+        let idx = position.position as usize;
+        if idx >= contig.length {
+            return true;
+        }
+        !(contig.is_masked(idx) || self.contains(chrom_name, position))
+    }
+
+    /// Number of positions in the interval, without collecting them.
+    pub(crate) fn count_positions(
+        &self,
+        chrom_name: &str,
+        interval: &Range<u64>,
+        strand_rule: StrandRule,
+    ) -> usize {
+        self.contigs
+            .get(chrom_name)
+            .map(|contig| {
+                let interval = contig.clamp(interval);
+                contig
+                    .base_positions
+                    .iter()
+                    .filter(|x| strand_rule.covers(x.strand))
+                    .map(|x| x.bits[interval.clone()].count_ones())
+                    .sum::<usize>()
+            })
+            .unwrap_or(0)
+    }
+
     pub(crate) fn contig_sizes(
         &self,
     ) -> impl Iterator<Item = (&String, usize)> {
-        self.contigs.iter().map(|(name, seq)| (name, seq.len()))
+        self.contigs.iter().map(|(name, contig)| (name, contig.length))
+    }
+}
+
+#[cfg(test)]
+mod genome_positions_tests {
+    use std::collections::HashSet;
+    use std::path::Path;
+
+    use bio::io::fasta::Reader as FastaReader;
+
+    use crate::genome_positions::{
+        ContigPositions, GenomePositions, StrandedPosition,
+    };
+    use crate::mod_base_code::DnaBase;
+    use crate::util::{Strand, StrandRule};
+    use rustc_hash::FxHashSet;
+
+    #[test]
+    fn test_genome_positions_bitsets_match_sequence() {
+        let fasta_fp =
+            Path::new("tests/resources/CGI_ladder_3.6kb_ref.fa").to_path_buf();
+        let record = FastaReader::from_file(&fasta_fp)
+            .unwrap()
+            .records()
+            .next()
+            .unwrap()
+            .unwrap();
+        let name = record.id().to_string();
+        let seq = record.seq().to_ascii_uppercase();
+        let mp = indicatif::MultiProgress::new();
+        mp.set_draw_target(indicatif::ProgressDrawTarget::hidden());
+        let genome_positions = GenomePositions::new_from_sequences(
+            &[DnaBase::C],
+            &fasta_fp,
+            false,
+            &HashSet::from([name.clone()]),
+            &mp,
+        )
+        .unwrap();
+        assert_eq!(
+            genome_positions.contig_sizes().collect::<Vec<_>>(),
+            vec![(&name, seq.len())]
+        );
+
+        let interval = 10u64..100u64;
+        let positions = genome_positions
+            .get_positions(&name, &interval, StrandRule::Both)
+            .unwrap();
+        let expected = seq[10..100]
+            .iter()
+            .enumerate()
+            .filter_map(|(i, b)| match b {
+                b'C' => Some(((i + 10) as u64, Strand::Positive, DnaBase::C)),
+                b'G' => Some(((i + 10) as u64, Strand::Negative, DnaBase::G)),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            positions
+                .iter()
+                .map(|p| (p.position, p.strand, p.value))
+                .collect::<Vec<_>>(),
+            expected
+        );
+        assert_eq!(
+            genome_positions.count_positions(
+                &name,
+                &interval,
+                StrandRule::Both
+            ),
+            expected.len()
+        );
+        let n_positive =
+            expected.iter().filter(|x| x.1 == Strand::Positive).count();
+        assert_eq!(
+            genome_positions.count_positions(
+                &name,
+                &interval,
+                StrandRule::Positive
+            ),
+            n_positive
+        );
+        for (position, strand, value) in expected {
+            let stranded = StrandedPosition { position, strand, value };
+            assert!(genome_positions.contains(&name, &stranded));
+            let opposite = StrandedPosition {
+                position,
+                strand: strand.opposite(),
+                value: value.complement(),
+            };
+            assert!(!genome_positions.contains(&name, &opposite));
+            assert!(!genome_positions.is_reference_mismatch(&name, &stranded));
+            assert!(genome_positions.is_reference_mismatch(&name, &opposite));
+        }
+        let past_end = StrandedPosition {
+            position: 1_000_000,
+            strand: Strand::Positive,
+            value: DnaBase::C,
+        };
+        assert!(!genome_positions.contains(&name, &past_end));
+        assert!(genome_positions.is_reference_mismatch(&name, &past_end));
+        assert!(!genome_positions.is_reference_mismatch("missing", &past_end));
+        assert!(genome_positions
+            .get_positions(&name, &(0..1_000_000), StrandRule::Both)
+            .is_some());
+        assert!(genome_positions
+            .get_positions("missing", &interval, StrandRule::Both)
+            .is_none());
+    }
+
+    #[test]
+    fn test_contig_positions_masked_runs() {
+        let contig = ContigPositions::new(
+            b"CCggcaCTcg",
+            &FxHashSet::from_iter(['C']),
+            &FxHashSet::from_iter(['G']),
+            true,
+        );
+        assert_eq!(contig.masked, vec![2..6, 8..10]);
+        let masked = (0..contig.length)
+            .filter(|i| contig.is_masked(*i))
+            .collect::<Vec<usize>>();
+        assert_eq!(masked, vec![2, 3, 4, 5, 8, 9]);
+
+        let unmasked = ContigPositions::new(
+            b"CCggcaCTcg",
+            &FxHashSet::from_iter(['C']),
+            &FxHashSet::from_iter(['G']),
+            false,
+        );
+        assert!(unmasked.masked.is_empty());
+        assert!(!unmasked.is_masked(2));
     }
 }
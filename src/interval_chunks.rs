@@ -296,6 +296,26 @@ impl FocusPositions {
         }
     }
 
+    /// Focus on the motif positions in `start..end`.
+    pub(crate) fn from_motif_locations(
+        motif_positions: &MultipleMotifLocations,
+        chrom_tid: u32,
+        start: u32,
+        end: u32,
+        combine_strands: bool,
+    ) -> Self {
+        if combine_strands {
+            Self::new_motif_combine_strands(
+                motif_positions,
+                chrom_tid,
+                start,
+                end,
+            )
+        } else {
+            Self::new_motif(motif_positions, chrom_tid, start, end)
+        }
+    }
+
     fn new_regions(
         stranded_position_filter: &StrandedPositionFilter<()>,
         chrom_id: u32,
@@ -428,17 +448,13 @@ impl ChromCoordinates {
         // have  been pre-filtered so that the position filter can be
         // ignored..
         let focus_positions = match (motif_positions, position_filter) {
-            (Some(motif), _) => {
-                if combine_strands {
-                    FocusPositions::new_motif_combine_strands(
-                        motif, chrom_tid, start_pos, end_pos,
-                    )
-                } else {
-                    FocusPositions::new_motif(
-                        motif, chrom_tid, start_pos, end_pos,
-                    )
-                }
-            }
+            (Some(motif), _) => FocusPositions::from_motif_locations(
+                motif,
+                chrom_tid,
+                start_pos,
+                end_pos,
+                combine_strands,
+            ),
             (_, Some(spf)) => {
                 FocusPositions::new_regions(spf, chrom_tid, start_pos, end_pos)
             }
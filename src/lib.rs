@@ -2,25 +2,35 @@ extern crate core;
 
 pub mod adjust;
 pub mod bedmethyl_util;
+pub mod comethyl;
 pub mod commands;
+mod compare_pileups;
+mod coverage;
 pub mod entropy;
 pub mod errs;
 pub mod extract;
 pub mod interval_chunks;
 pub mod logging;
+pub mod metagene;
 pub mod mod_bam;
 pub mod mod_base_code;
+pub mod mod_code_registry;
 pub mod modbam_util;
 pub mod monoid;
+mod motif_stats;
 pub mod motifs;
 pub mod pileup;
 pub mod position_filter;
+pub mod provenance;
+pub mod serve;
 pub mod summarize;
 pub mod threshold_mod_caller;
 pub mod thresholds;
 pub mod validate;
 pub mod writers;
 
+pub(crate) mod bam_pipeline;
+pub(crate) mod bigwig;
 pub(crate) mod command_utils;
 pub mod dmr;
 mod fasta;
@@ -28,6 +38,7 @@ mod fasta;
 pub(crate) mod genome_positions;
 mod hmm;
 mod localise;
+mod parquet_table;
 pub(crate) mod parsing_utils;
 mod read_cache;
 mod read_ids_to_base_mod_probs;
@@ -36,6 +47,8 @@ mod read_ids_to_base_mod_probs;
 mod reads_sampler;
 mod record_processor;
 mod repair_tags;
+pub mod run_summary;
+mod sqlite;
 mod stats;
 mod tabix;
 mod util;
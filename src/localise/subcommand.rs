@@ -1,6 +1,6 @@
 use std::collections::HashMap;
 use std::fs::File;
-use std::io::{stdout, BufRead, BufReader, BufWriter, Write};
+use std::io::{stdout, BufRead, BufWriter, Write};
 use std::path::PathBuf;
 use std::sync::Arc;
 
@@ -16,6 +16,7 @@ use crate::dmr::bedmethyl::BedMethylLine;
 use crate::localise::util::{LocalizedModCounts, StrandedFeatures};
 use crate::logging::init_logging;
 use crate::monoid::Moniod;
+use crate::parsing_utils::open_text_input;
 use crate::tabix::HtsTabixHandler;
 use crate::util::{
     get_master_progress_bar, get_ticker, read_sequence_lengths_file,
@@ -27,7 +28,7 @@ use crate::util::{
 pub struct EntryLocalize {
     /// Input bedMethyl table. Should be bgzip-compressed and have an
     /// associated Tabix index. The tabix index will be assumed to be
-    /// $this_file.tbi
+    /// $this_file.tbi or $this_file.csi
     in_bedmethyl: PathBuf,
     /// BED file of regions to calculate enrichment around. These BED records
     /// serve as the points from which the `--window` number of bases is
@@ -110,7 +111,7 @@ impl EntryLocalize {
     ) -> anyhow::Result<Vec<GenomeRegion>> {
         let pb = multi_progress.add(get_ticker());
         pb.set_message("regions parsed");
-        let mut reader = BufReader::new(File::open(&self.regions)?)
+        let mut reader = open_text_input(&self.regions)?
             .lines()
             .skip_while(|r| {
                 r.as_ref().map(|l| l.starts_with('#')).unwrap_or(true)
@@ -129,7 +130,7 @@ impl EntryLocalize {
             None => bail!("failed to inspect regions BED, no valid lines"),
         };
 
-        let (regions, errs) = BufReader::new(File::open(&self.regions)?)
+        let (regions, errs) = open_text_input(&self.regions)?
             .lines()
             .progress_with(pb)
             .map(|r| {
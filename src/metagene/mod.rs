@@ -0,0 +1,626 @@
+use std::fmt::{Display, Formatter};
+use std::io::BufRead;
+use std::ops::Range;
+use std::path::Path;
+
+use anyhow::{anyhow, bail, Context};
+use clap::ValueEnum;
+use itertools::Itertools;
+use log::debug;
+use rustc_hash::FxHashMap;
+
+use crate::dmr::bedmethyl::BedMethylLine;
+use crate::mod_base_code::ModCodeRepr;
+use crate::monoid::Moniod;
+use crate::parsing_utils::open_text_input;
+use crate::tabix::HtsTabixHandler;
+use crate::util::StrandRule;
+
+pub mod subcommand;
+
+/// How to divide each feature into scaled segments.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, ValueEnum)]
+#[allow(non_camel_case_types)]
+pub(super) enum MetageneMode {
+    /// Upstream flank, gene body (including introns), downstream flank.
+    body,
+    /// Upstream flank, 5'UTR, CDS, 3'UTR, downstream flank. Transcripts are
+    /// spliced, so only exonic positions are used.
+    utr_cds,
+}
+
+impl Display for MetageneMode {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::body => write!(f, "body"),
+            Self::utr_cds => write!(f, "utr-cds"),
+        }
+    }
+}
+
+/// A single line from a GTF file, coordinates are converted to 0-based,
+/// half-open.
+#[derive(Debug, PartialEq, Eq)]
+struct GtfRecord {
+    chrom: String,
+    feature: String,
+    start: u64,
+    end: u64,
+    strand: StrandRule,
+    attributes: FxHashMap<String, String>,
+}
+
+impl GtfRecord {
+    fn parse(line: &str) -> anyhow::Result<Self> {
+        let fields = line.split('\t').collect::<Vec<&str>>();
+        if fields.len() < 9 {
+            bail!("expected 9 GTF fields, got {}", fields.len())
+        }
+        let start = fields[3]
+            .parse::<u64>()
+            .map_err(|e| anyhow!("invalid start {}, {e}", fields[3]))?;
+        let end = fields[4]
+            .parse::<u64>()
+            .map_err(|e| anyhow!("invalid end {}, {e}", fields[4]))?;
+        if start == 0 || end < start {
+            bail!("invalid GTF interval {start}-{end}")
+        }
+        let strand = fields[6]
+            .chars()
+            .next()
+            .ok_or_else(|| anyhow!("missing strand"))
+            .and_then(StrandRule::try_from)?;
+        let attributes = fields[8]
+            .split(';')
+            .filter_map(|attr| {
+                attr.trim().split_once(' ').map(|(key, value)| {
+                    (
+                        key.to_string(),
+                        value.trim().trim_matches('"').to_string(),
+                    )
+                })
+            })
+            .collect::<FxHashMap<String, String>>();
+
+        Ok(Self {
+            chrom: fields[0].to_string(),
+            feature: fields[2].to_string(),
+            start: start - 1,
+            end,
+            strand,
+            attributes,
+        })
+    }
+
+    fn attribute(&self, key: &str) -> Option<&String> {
+        self.attributes.get(key)
+    }
+}
+
+/// Part of a feature that is scaled into a fixed number of bins. The
+/// intervals are in the direction of transcription and may be negative for
+/// flanks that extend past the start of the contig.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Segment {
+    label: &'static str,
+    intervals: Vec<Range<i64>>,
+    length: i64,
+    first_bin: usize,
+    n_bins: usize,
+}
+
+impl Segment {
+    fn new(
+        label: &'static str,
+        mut intervals: Vec<Range<i64>>,
+        reverse: bool,
+        first_bin: usize,
+        n_bins: usize,
+    ) -> Self {
+        intervals.retain(|iv| !iv.is_empty());
+        if reverse {
+            intervals.sort_by(|a, b| b.start.cmp(&a.start));
+        } else {
+            intervals.sort_by(|a, b| a.start.cmp(&b.start));
+        }
+        let length = intervals.iter().map(|iv| iv.end - iv.start).sum();
+        Self { label, intervals, length, first_bin, n_bins }
+    }
+
+    /// Global bin of a position in this segment, if it's contained.
+    fn bin(&self, pos: i64, reverse: bool) -> Option<usize> {
+        let mut offset = 0i64;
+        for iv in self.intervals.iter() {
+            if iv.contains(&pos) {
+                let within =
+                    if reverse { iv.end - 1 - pos } else { pos - iv.start };
+                let bin = ((offset + within) as usize * self.n_bins)
+                    / self.length as usize;
+                return Some(self.first_bin + bin);
+            }
+            offset += iv.end - iv.start;
+        }
+        None
+    }
+}
+
+/// A gene or transcript divided into segments that are each scaled to a
+/// fixed number of bins.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(super) struct MetageneFeature {
+    chrom: String,
+    strand: StrandRule,
+    segments: Vec<Segment>,
+}
+
+impl MetageneFeature {
+    fn new(
+        chrom: String,
+        strand: StrandRule,
+        span: Range<u64>,
+        inner: Vec<(&'static str, Vec<Range<u64>>)>,
+        flank: u64,
+        flank_bins: usize,
+        bins: usize,
+    ) -> Self {
+        let reverse = strand == StrandRule::Negative;
+        let (start, end, flank) =
+            (span.start as i64, span.end as i64, flank as i64);
+        let (upstream, downstream) = if reverse {
+            (end..end + flank, start - flank..start)
+        } else {
+            (start - flank..start, end..end + flank)
+        };
+        let mut segments = Vec::with_capacity(inner.len() + 2);
+        let mut first_bin = 0usize;
+        if flank > 0 {
+            segments.push(Segment::new(
+                "upstream",
+                vec![upstream],
+                reverse,
+                first_bin,
+                flank_bins,
+            ));
+            first_bin += flank_bins;
+        }
+        for (label, intervals) in inner {
+            let intervals = intervals
+                .into_iter()
+                .map(|iv| iv.start as i64..iv.end as i64)
+                .collect();
+            segments
+                .push(Segment::new(label, intervals, reverse, first_bin, bins));
+            first_bin += bins;
+        }
+        if flank > 0 {
+            segments.push(Segment::new(
+                "downstream",
+                vec![downstream],
+                reverse,
+                first_bin,
+                flank_bins,
+            ));
+        }
+        Self { chrom, strand, segments }
+    }
+
+    fn fetch_range(&self) -> Range<u64> {
+        let start = self
+            .segments
+            .iter()
+            .flat_map(|s| s.intervals.iter().map(|iv| iv.start))
+            .min()
+            .unwrap_or(0);
+        let end = self
+            .segments
+            .iter()
+            .flat_map(|s| s.intervals.iter().map(|iv| iv.end))
+            .max()
+            .unwrap_or(0);
+        (start.max(0) as u64)..(end.max(0) as u64)
+    }
+
+    fn bin(&self, pos: u64) -> Option<usize> {
+        let reverse = self.strand == StrandRule::Negative;
+        self.segments.iter().find_map(|s| s.bin(pos as i64, reverse))
+    }
+
+    /// Labels of each bin, the segment and bin within the segment.
+    pub(super) fn bin_labels(&self) -> Vec<(&'static str, usize)> {
+        self.segments
+            .iter()
+            .flat_map(|s| (0..s.n_bins).map(|i| (s.label, i)))
+            .collect()
+    }
+
+    pub(super) fn into_metagene_counts(
+        self,
+        index: &HtsTabixHandler<BedMethylLine>,
+        stranded: bool,
+        min_coverage: u64,
+        io_threads: usize,
+    ) -> anyhow::Result<MetageneCounts> {
+        let range = self.fetch_range();
+        let strand_rule = if stranded { self.strand } else { StrandRule::Both };
+        let records =
+            index.fetch_region(&self.chrom, &range, strand_rule, io_threads)?;
+        let mut feature_counts =
+            FxHashMap::<(ModCodeRepr, usize), BinCounts>::default();
+        for record in
+            records.iter().filter(|bm| bm.valid_coverage >= min_coverage)
+        {
+            if let Some(bin) = self.bin(record.start()) {
+                let counts = feature_counts
+                    .entry((record.raw_mod_code, bin))
+                    .or_default();
+                counts.n_sites += 1;
+                counts.n_valid += record.valid_coverage;
+                counts.n_mod += record.count_methylated;
+            }
+        }
+        let bins = feature_counts
+            .into_iter()
+            .map(|(key, mut counts)| {
+                // each feature contributes once to the mean, regardless of the
+                // number of sites in the bin
+                counts.n_features = 1;
+                counts.sum_feature_frac_modified =
+                    counts.n_mod as f64 / counts.n_valid as f64;
+                (key, counts)
+            })
+            .collect();
+        Ok(MetageneCounts { bins })
+    }
+}
+
+#[derive(Default, Debug, Copy, Clone, PartialEq)]
+pub(super) struct BinCounts {
+    n_features: u64,
+    n_sites: u64,
+    n_valid: u64,
+    n_mod: u64,
+    sum_feature_frac_modified: f64,
+}
+
+impl BinCounts {
+    fn op_mut(&mut self, other: &Self) {
+        self.n_features += other.n_features;
+        self.n_sites += other.n_sites;
+        self.n_valid += other.n_valid;
+        self.n_mod += other.n_mod;
+        self.sum_feature_frac_modified += other.sum_feature_frac_modified;
+    }
+}
+
+#[derive(Default, Debug)]
+pub(super) struct MetageneCounts {
+    bins: FxHashMap<(ModCodeRepr, usize), BinCounts>,
+}
+
+impl MetageneCounts {
+    pub(super) fn header() -> String {
+        [
+            "mod_code",
+            "segment",
+            "segment_bin",
+            "bin",
+            "n_features",
+            "n_sites",
+            "n_valid",
+            "n_mod",
+            "percent_modified",
+            "mean_feature_percent_modified",
+        ]
+        .join("\t")
+    }
+
+    /// Rows of the output table, sorted by modification code then bin.
+    pub(super) fn rows(
+        &self,
+        bin_labels: &[(&'static str, usize)],
+    ) -> Vec<String> {
+        self.bins
+            .iter()
+            .sorted_by(|((a_code, a_bin), _), ((b_code, b_bin), _)| {
+                a_code.cmp(b_code).then(a_bin.cmp(b_bin))
+            })
+            .map(|((mod_code, bin), counts)| {
+                let (segment, segment_bin) = bin_labels[*bin];
+                let percent_modified =
+                    counts.n_mod as f64 / counts.n_valid as f64 * 100f64;
+                let mean_feature_percent_modified =
+                    counts.sum_feature_frac_modified / counts.n_features as f64
+                        * 100f64;
+                let tab = '\t';
+                format!(
+                    "{mod_code}{tab}{segment}{tab}{segment_bin}{tab}{bin}{tab}\
+                     {}{tab}{}{tab}{}{tab}{}{tab}{:.2}{tab}{:.2}",
+                    counts.n_features,
+                    counts.n_sites,
+                    counts.n_valid,
+                    counts.n_mod,
+                    percent_modified,
+                    mean_feature_percent_modified,
+                )
+            })
+            .collect()
+    }
+}
+
+impl Moniod for MetageneCounts {
+    fn zero() -> Self {
+        Self::default()
+    }
+
+    fn op(self, other: Self) -> Self {
+        let mut this = self;
+        this.op_mut(other);
+        this
+    }
+
+    fn op_mut(&mut self, other: Self) {
+        for (key, counts) in other.bins {
+            self.bins.entry(key).or_default().op_mut(&counts);
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.bins.len()
+    }
+}
+
+fn read_gtf_records(gtf_fp: &Path) -> anyhow::Result<Vec<GtfRecord>> {
+    let reader = open_text_input(gtf_fp)
+        .with_context(|| format!("failed to open GTF {gtf_fp:?}"))?;
+    let mut records = Vec::new();
+    let mut n_failed = 0usize;
+    for line in reader.lines() {
+        let line = line?;
+        if line.starts_with('#') || line.trim().is_empty() {
+            continue;
+        }
+        match GtfRecord::parse(&line) {
+            Ok(record) => records.push(record),
+            Err(e) => {
+                debug!("failed to parse GTF line {line}, {e}");
+                n_failed += 1;
+            }
+        }
+    }
+    if n_failed > 0 {
+        debug!("failed to parse {n_failed} GTF line(s)");
+    }
+    Ok(records)
+}
+
+/// Load features for the gene-body mode, one feature per GTF record of type
+/// `feature_type`.
+pub(super) fn load_body_features(
+    gtf_fp: &Path,
+    feature_type: &str,
+    flank: u64,
+    flank_bins: usize,
+    bins: usize,
+) -> anyhow::Result<Vec<MetageneFeature>> {
+    let features = read_gtf_records(gtf_fp)?
+        .into_iter()
+        .filter(|record| record.feature == feature_type)
+        .map(|record| {
+            let body = record.start..record.end;
+            MetageneFeature::new(
+                record.chrom,
+                record.strand,
+                body.clone(),
+                vec![("body", vec![body])],
+                flank,
+                flank_bins,
+                bins,
+            )
+        })
+        .collect::<Vec<MetageneFeature>>();
+    if features.is_empty() {
+        bail!("zero {feature_type} records found in {gtf_fp:?}")
+    }
+    Ok(features)
+}
+
+type ExonSplit = (Vec<Range<u64>>, Vec<Range<u64>>, Vec<Range<u64>>);
+
+/// Split exons into the parts before, within, and after the CDS in genomic
+/// coordinates.
+fn split_exons(exons: &[Range<u64>], cds: &Range<u64>) -> ExonSplit {
+    let mut before = Vec::new();
+    let mut within = Vec::new();
+    let mut after = Vec::new();
+    for exon in exons.iter() {
+        if exon.start < cds.start {
+            before.push(exon.start..exon.end.min(cds.start));
+        }
+        if exon.end > cds.end {
+            after.push(exon.start.max(cds.end)..exon.end);
+        }
+        let (start, end) = (exon.start.max(cds.start), exon.end.min(cds.end));
+        if start < end {
+            within.push(start..end);
+        }
+    }
+    (before, within, after)
+}
+
+/// Load features for the 5'UTR/CDS/3'UTR mode, one feature per transcript
+/// with a CDS. The UTRs are the exonic parts of the transcript outside of the
+/// CDS (including the stop codon).
+pub(super) fn load_utr_cds_features(
+    gtf_fp: &Path,
+    flank: u64,
+    flank_bins: usize,
+    bins: usize,
+) -> anyhow::Result<Vec<MetageneFeature>> {
+    let mut transcripts = FxHashMap::<
+        String,
+        (String, StrandRule, Vec<Range<u64>>, Option<Range<u64>>),
+    >::default();
+    for record in read_gtf_records(gtf_fp)? {
+        let is_exon = record.feature == "exon";
+        let is_coding =
+            record.feature == "CDS" || record.feature == "stop_codon";
+        if !(is_exon || is_coding) {
+            continue;
+        }
+        let Some(transcript_id) = record.attribute("transcript_id") else {
+            continue;
+        };
+        let entry =
+            transcripts.entry(transcript_id.to_owned()).or_insert_with(|| {
+                (record.chrom.clone(), record.strand, Vec::new(), None)
+            });
+        if is_exon {
+            entry.2.push(record.start..record.end);
+        } else {
+            entry.3 = match entry.3.take() {
+                Some(cds) => {
+                    Some(cds.start.min(record.start)..cds.end.max(record.end))
+                }
+                None => Some(record.start..record.end),
+            };
+        }
+    }
+
+    let mut n_non_coding = 0usize;
+    let features = transcripts
+        .into_iter()
+        .sorted_by(|(a, _), (b, _)| a.cmp(b))
+        .filter_map(|(_, (chrom, strand, exons, cds))| match cds {
+            Some(cds) if !exons.is_empty() => Some((chrom, strand, exons, cds)),
+            _ => {
+                n_non_coding += 1;
+                None
+            }
+        })
+        .map(|(chrom, strand, exons, cds)| {
+            let span = exons.iter().map(|e| e.start).min().unwrap()
+                ..exons.iter().map(|e| e.end).max().unwrap();
+            let (before, within, after) = split_exons(&exons, &cds);
+            let (five_prime, three_prime) = if strand == StrandRule::Negative {
+                (after, before)
+            } else {
+                (before, after)
+            };
+            MetageneFeature::new(
+                chrom,
+                strand,
+                span,
+                vec![
+                    ("5utr", five_prime),
+                    ("cds", within),
+                    ("3utr", three_prime),
+                ],
+                flank,
+                flank_bins,
+                bins,
+            )
+        })
+        .collect::<Vec<MetageneFeature>>();
+    if n_non_coding > 0 {
+        debug!("skipped {n_non_coding} transcript(s) without CDS or exons");
+    }
+    if features.is_empty() {
+        bail!("zero transcripts with exons and CDS found in {gtf_fp:?}")
+    }
+    Ok(features)
+}
+
+#[cfg(test)]
+mod metagene_tests {
+    use crate::metagene::{split_exons, GtfRecord, MetageneFeature};
+    use crate::util::StrandRule;
+
+    #[test]
+    fn test_parse_gtf_record() {
+        let line = "chr1\tHAVANA\texon\t11\t20\t.\t-\t.\tgene_id \"G1\"; \
+                    transcript_id \"T1\"; exon_number 1;";
+        let record = GtfRecord::parse(line).unwrap();
+        assert_eq!(record.chrom, "chr1");
+        assert_eq!(record.feature, "exon");
+        assert_eq!((record.start, record.end), (10, 20));
+        assert_eq!(record.strand, StrandRule::Negative);
+        assert_eq!(record.attribute("transcript_id").unwrap(), "T1");
+        assert_eq!(record.attribute("exon_number").unwrap(), "1");
+        assert!(GtfRecord::parse("chr1\tsrc\texon\t0\t20\t.\t+\t.\t").is_err());
+        assert!(GtfRecord::parse("chr1\tsrc\texon").is_err());
+    }
+
+    #[test]
+    fn test_body_feature_bins() {
+        // 10 bp flanks in 2 bins, 100 bp body in 4 bins
+        let feature = MetageneFeature::new(
+            "chr1".to_string(),
+            StrandRule::Positive,
+            100..200,
+            vec![("body", vec![100..200])],
+            10,
+            2,
+            4,
+        );
+        assert_eq!(feature.fetch_range(), 90..210);
+        assert_eq!(feature.bin(89), None);
+        assert_eq!(feature.bin(90), Some(0));
+        assert_eq!(feature.bin(99), Some(1));
+        assert_eq!(feature.bin(100), Some(2));
+        assert_eq!(feature.bin(199), Some(5));
+        assert_eq!(feature.bin(205), Some(7));
+        assert_eq!(feature.bin(210), None);
+        assert_eq!(feature.bin_labels()[2], ("body", 0));
+
+        let feature = MetageneFeature::new(
+            "chr1".to_string(),
+            StrandRule::Negative,
+            100..200,
+            vec![("body", vec![100..200])],
+            10,
+            2,
+            4,
+        );
+        // upstream is to the right for the negative strand
+        assert_eq!(feature.bin(209), Some(0));
+        assert_eq!(feature.bin(199), Some(2));
+        assert_eq!(feature.bin(100), Some(5));
+        assert_eq!(feature.bin(90), Some(7));
+
+        // flanks past the start of the contig keep their scale
+        let feature = MetageneFeature::new(
+            "chr1".to_string(),
+            StrandRule::Positive,
+            5..105,
+            vec![("body", vec![5..105])],
+            10,
+            2,
+            4,
+        );
+        assert_eq!(feature.fetch_range(), 0..115);
+        assert_eq!(feature.bin(0), Some(1));
+    }
+
+    #[test]
+    fn test_utr_cds_split() {
+        let exons = vec![0..10, 20..30, 40..50];
+        let (before, within, after) = split_exons(&exons, &(5..45));
+        assert_eq!(before, vec![0..5]);
+        assert_eq!(within, vec![5..10, 20..30, 40..45]);
+        assert_eq!(after, vec![45..50]);
+
+        // spliced, so the intron is skipped and the segment is 20 bp long
+        let feature = MetageneFeature::new(
+            "chr1".to_string(),
+            StrandRule::Positive,
+            0..50,
+            vec![("cds", vec![0..10, 40..50])],
+            0,
+            0,
+            2,
+        );
+        assert_eq!(feature.bin(9), Some(0));
+        assert_eq!(feature.bin(15), None);
+        assert_eq!(feature.bin(40), Some(1));
+    }
+}
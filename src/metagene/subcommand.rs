@@ -0,0 +1,157 @@
+use std::fs::File;
+use std::io::{stdout, BufWriter, Write};
+use std::path::PathBuf;
+
+use anyhow::bail;
+use clap::Args;
+use indicatif::{MultiProgress, ParallelProgressIterator};
+use log::{debug, info};
+use rayon::prelude::*;
+
+use crate::dmr::bedmethyl::BedMethylLine;
+use crate::logging::init_logging;
+use crate::metagene::{
+    load_body_features, load_utr_cds_features, MetageneCounts, MetageneMode,
+};
+use crate::monoid::Moniod;
+use crate::tabix::HtsTabixHandler;
+use crate::util::{create_out_directory, get_master_progress_bar};
+
+#[derive(Args)]
+#[command(arg_required_else_help = true)]
+pub struct EntryMetagene {
+    /// Input bedMethyl table. Should be bgzip-compressed and have an
+    /// associated Tabix index. The tabix index will be assumed to be
+    /// $this_file.tbi or $this_file.csi
+    in_bedmethyl: PathBuf,
+    /// GTF file of gene annotations.
+    #[arg(long)]
+    gtf: PathBuf,
+    /// How to divide each feature. body: upstream flank, gene body, and
+    /// downstream flank. utr-cds: upstream flank, 5'UTR, CDS, 3'UTR, and
+    /// downstream flank of each transcript, using only exonic positions.
+    #[arg(long, default_value_t = MetageneMode::body)]
+    mode: MetageneMode,
+    /// GTF feature type to use as the gene body with `--mode body`, for
+    /// example "gene" or "transcript".
+    #[arg(long, default_value = "gene")]
+    feature: String,
+    /// Number of bins to scale each gene body, UTR, or CDS into.
+    #[arg(long, default_value_t = 40)]
+    bins: usize,
+    /// Number of base pairs upstream and downstream of each feature to
+    /// include, set to 0 to omit the flanks.
+    #[arg(long, default_value_t = 2000)]
+    flank: u64,
+    /// Number of bins to divide each flank into.
+    #[arg(long, default_value_t = 20)]
+    flank_bins: usize,
+    /// Only use bedMethyl records on the same strand as the feature, default
+    /// is to use records from both strands. Useful for stranded data such as
+    /// direct RNA.
+    #[arg(long, default_value_t = false)]
+    stranded: bool,
+    /// Minimum valid coverage to use a bedMethyl record.
+    #[arg(long, default_value_t = 3)]
+    min_coverage: u64,
+    /// Optionally specify a file to write output to, default is stdout.
+    #[clap(help_heading = "Output Options")]
+    #[arg(long, short = 'o')]
+    out_file: Option<PathBuf>,
+    /// Force overwrite of existing output file.
+    #[clap(help_heading = "Output Options")]
+    #[arg(long, short = 'f', default_value_t = false)]
+    force: bool,
+    /// Specify a file to write debug logs to.
+    #[clap(help_heading = "Logging Options")]
+    #[arg(long, alias = "log")]
+    log_filepath: Option<PathBuf>,
+    /// Number of threads to use.
+    #[clap(help_heading = "Compute Options")]
+    #[arg(short = 't', long, default_value_t = 4)]
+    threads: usize,
+    /// Number of tabix/bgzf IO threads to use.
+    #[clap(help_heading = "Compute Options")]
+    #[arg(long, default_value_t = 2)]
+    io_threads: usize,
+}
+
+impl EntryMetagene {
+    pub fn run(&self) -> anyhow::Result<()> {
+        let _handle = init_logging(self.log_filepath.as_ref());
+        if self.bins == 0 {
+            bail!("--bins must be greater than 0")
+        }
+        if self.flank > 0 && self.flank_bins == 0 {
+            bail!("--flank-bins must be greater than 0 when using flanks")
+        }
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(self.threads)
+            .build()?;
+        let mut writer: Box<dyn Write> = match self.out_file.as_ref() {
+            Some(out_fp) => {
+                create_out_directory(out_fp)?;
+                if out_fp.exists() && !self.force {
+                    bail!("refusing to overwrite existing file {out_fp:?}")
+                }
+                Box::new(BufWriter::new(File::create(out_fp)?))
+            }
+            None => Box::new(BufWriter::new(stdout())),
+        };
+
+        let tabix_index =
+            HtsTabixHandler::<BedMethylLine>::from_path(&self.in_bedmethyl)?;
+        let features = match self.mode {
+            MetageneMode::body => load_body_features(
+                &self.gtf,
+                &self.feature,
+                self.flank,
+                self.flank_bins,
+                self.bins,
+            )?,
+            MetageneMode::utr_cds => load_utr_cds_features(
+                &self.gtf,
+                self.flank,
+                self.flank_bins,
+                self.bins,
+            )?,
+        };
+        let bin_labels = features[0].bin_labels();
+        info!("loaded {} features", features.len());
+
+        let multi_progress = MultiProgress::new();
+        let pb = multi_progress.add(get_master_progress_bar(features.len()));
+        pb.set_message("features processed");
+        let counts = pool.install(|| {
+            features
+                .into_par_iter()
+                .progress_with(pb)
+                .map(|feature| {
+                    feature.into_metagene_counts(
+                        &tabix_index,
+                        self.stranded,
+                        self.min_coverage,
+                        self.io_threads,
+                    )
+                })
+                .fold(MetageneCounts::zero, |counts, next| match next {
+                    Ok(mc) => counts.op(mc),
+                    Err(e) => {
+                        debug!("feature failed, {e}");
+                        counts
+                    }
+                })
+                .reduce(MetageneCounts::zero, |a, b| a.op(b))
+        });
+        multi_progress.clear()?;
+
+        writeln!(writer, "{}", MetageneCounts::header())?;
+        for row in counts.rows(&bin_labels) {
+            writeln!(writer, "{row}")?;
+        }
+        writer.flush()?;
+        info!("finished, aggregated {} bins", counts.len());
+
+        Ok(())
+    }
+}
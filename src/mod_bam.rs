@@ -6,7 +6,7 @@ use std::hash::Hash;
 use anyhow::bail;
 use derive_new::new;
 use itertools::{Itertools, PeekingNext};
-use log::debug;
+use log::{debug, warn};
 use nom::bytes::complete::tag;
 use nom::character::complete::{digit1, multispace0};
 use nom::multi::separated_list1;
@@ -19,18 +19,17 @@ use rustc_hash::FxHashMap;
 use crate::errs::{ConflictError, MkError, MkResult};
 use crate::mod_base_code::{DnaBase, ModCodeRepr, ParseChar};
 use crate::motifs::iupac::nt_bytes;
+use crate::run_summary;
 use crate::util::{
-    get_forward_sequence, get_tag, record_is_not_primary, Strand,
+    get_forward_sequence, get_tag, record_is_not_primary,
+    AlignmentIdentityFilter, Strand,
 };
 
 const MAX_PROB: f32 = 1.01f32;
 pub(crate) struct TrackingModRecordIter<'a, T: bam::Read> {
     records: bam::Records<'a, T>,
-    skip_unmapped: bool,
-    allow_non_primary: bool,
-    pub(crate) num_used: usize,
-    pub(crate) num_skipped: usize,
-    pub(crate) num_failed: usize,
+    criteria: ModRecordCriteria,
+    pub(crate) counts: ModRecordCounts,
 }
 
 impl<'a, T: bam::Read> TrackingModRecordIter<'a, T> {
@@ -41,11 +40,19 @@ impl<'a, T: bam::Read> TrackingModRecordIter<'a, T> {
     ) -> Self {
         Self {
             records,
-            skip_unmapped,
-            allow_non_primary,
-            num_used: 0,
-            num_skipped: 0,
-            num_failed: 0,
+            criteria: ModRecordCriteria::new(skip_unmapped, allow_non_primary),
+            counts: ModRecordCounts::default(),
+        }
+    }
+
+    /// Skip mapped records that don't pass the alignment identity filter.
+    pub(crate) fn with_alignment_filter(
+        self,
+        alignment_filter: Option<AlignmentIdentityFilter>,
+    ) -> Self {
+        Self {
+            criteria: self.criteria.with_alignment_filter(alignment_filter),
+            ..self
         }
     }
 }
@@ -54,70 +61,138 @@ impl<'a, T: bam::Read> Iterator for &mut TrackingModRecordIter<'a, T> {
     type Item = (bam::Record, String, ModBaseInfo);
 
     fn next(&mut self) -> Option<Self::Item> {
-        let mut ret: Option<Self::Item> = None;
         while let Some(result) = self.records.next() {
-            match result {
-                Ok(record) => {
-                    let record_name =
-                        String::from_utf8(record.qname().to_vec())
-                            .unwrap_or("utf-decode-failed".to_string());
-                    let should_skip = {
-                        let based_on_primary = record_is_not_primary(&record)
-                            && !self.allow_non_primary;
-                        let based_on_unmapped =
-                            record.is_unmapped() && self.skip_unmapped;
-                        based_on_primary || based_on_unmapped
-                    };
-                    if should_skip {
-                        self.num_skipped += 1;
-                        continue;
-                    } else {
-                        if record.seq_len() == 0 {
-                            debug!(
-                                "{record_name}: {}",
-                                MkError::EmptyReadSequence
-                            );
-                            self.num_failed += 1;
-                            continue;
-                        } else {
-                            match ModBaseInfo::new_from_record(&record) {
-                                Ok(modbase_info) => {
-                                    if modbase_info.is_empty() {
-                                        self.num_skipped += 1;
-                                        debug!(
-                                            "record {record_name} has no base \
-                                             modification information, \
-                                             skipping"
-                                        );
-                                        continue;
-                                    } else {
-                                        self.num_used += 1;
-                                        ret = Some((
-                                            record,
-                                            record_name,
-                                            modbase_info,
-                                        ));
-                                        break;
-                                    }
-                                }
-                                Err(e) => {
-                                    debug!("{record_name}: {e}");
-                                    self.num_failed += 1;
-                                }
-                            }
-                        }
-                    }
+            let (outcome, used) = self.criteria.check(result);
+            self.counts.add(outcome);
+            if used.is_some() {
+                return used;
+            }
+        }
+        None
+    }
+}
+
+/// How a record was counted by [`TrackingModRecordIter`].
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub(crate) enum ModRecordOutcome {
+    Used,
+    Skipped,
+    Failed { mm_mn_mismatch: bool },
+}
+
+/// Tallies of [`ModRecordOutcome`]s.
+#[derive(Debug, Copy, Clone, Default)]
+pub(crate) struct ModRecordCounts {
+    pub(crate) num_used: usize,
+    pub(crate) num_skipped: usize,
+    pub(crate) num_failed: usize,
+    /// Records with an MN tag where the MM tag doesn't match the sequence,
+    /// these are also counted in `num_failed`.
+    pub(crate) num_mm_mn_mismatch: usize,
+}
+
+impl ModRecordCounts {
+    pub(crate) fn add(&mut self, outcome: ModRecordOutcome) {
+        match outcome {
+            ModRecordOutcome::Used => self.num_used += 1,
+            ModRecordOutcome::Skipped => self.num_skipped += 1,
+            ModRecordOutcome::Failed { mm_mn_mismatch } => {
+                self.num_failed += 1;
+                if mm_mn_mismatch {
+                    self.num_mm_mn_mismatch += 1;
                 }
-                Err(e) => {
+            }
+        }
+    }
+}
+
+/// The records used by [`TrackingModRecordIter`]. Checking a record doesn't
+/// need the iterator, so records can be checked (and their tags parsed) on
+/// a thread pool with the outcomes counted afterwards.
+#[derive(Debug, Copy, Clone)]
+pub(crate) struct ModRecordCriteria {
+    skip_unmapped: bool,
+    allow_non_primary: bool,
+    alignment_filter: Option<AlignmentIdentityFilter>,
+}
+
+impl ModRecordCriteria {
+    pub(crate) fn new(skip_unmapped: bool, allow_non_primary: bool) -> Self {
+        Self { skip_unmapped, allow_non_primary, alignment_filter: None }
+    }
+
+    /// Skip mapped records that don't pass the alignment identity filter.
+    pub(crate) fn with_alignment_filter(
+        self,
+        alignment_filter: Option<AlignmentIdentityFilter>,
+    ) -> Self {
+        Self { alignment_filter, ..self }
+    }
+
+    /// The outcome for the record, and the record with its name and parsed
+    /// base modification information when it's used.
+    pub(crate) fn check(
+        &self,
+        result: Result<bam::Record, rust_htslib::errors::Error>,
+    ) -> (ModRecordOutcome, Option<(bam::Record, String, ModBaseInfo)>) {
+        let record = match result {
+            Ok(record) => record,
+            Err(e) => {
+                debug!(
+                    "failed to read record from bam information, {}",
+                    e.to_string()
+                );
+                return (
+                    ModRecordOutcome::Failed { mm_mn_mismatch: false },
+                    None,
+                );
+            }
+        };
+        let record_name = String::from_utf8(record.qname().to_vec())
+            .unwrap_or("utf-decode-failed".to_string());
+        let should_skip = {
+            let based_on_primary =
+                record_is_not_primary(&record) && !self.allow_non_primary;
+            let based_on_unmapped = record.is_unmapped() && self.skip_unmapped;
+            let based_on_identity = self
+                .alignment_filter
+                .as_ref()
+                .is_some_and(|filter| !filter.passes(&record));
+            based_on_primary || based_on_unmapped || based_on_identity
+        };
+        if should_skip {
+            return (ModRecordOutcome::Skipped, None);
+        }
+        if record.seq_len() == 0 {
+            debug!("{record_name}: {}", MkError::EmptyReadSequence);
+            return (ModRecordOutcome::Failed { mm_mn_mismatch: false }, None);
+        }
+        match ModBaseInfo::new_from_record(&record) {
+            Ok(modbase_info) => {
+                if modbase_info.is_empty() {
                     debug!(
-                        "failed to read record from bam information, {}",
-                        e.to_string()
+                        "record {record_name} has no base modification \
+                         information, skipping"
                     );
-                    self.num_failed += 1;
+                    (ModRecordOutcome::Skipped, None)
+                } else {
+                    (
+                        ModRecordOutcome::Used,
+                        Some((record, record_name, modbase_info)),
+                    )
                 }
             }
+            Err(e) => {
+                debug!("{record_name}: {e}");
+                let mm_mn_mismatch = if let MkError::MmMnMismatch(reason) = &e {
+                    debug!("{record_name}: {reason}");
+                    true
+                } else {
+                    false
+                };
+                (ModRecordOutcome::Failed { mm_mn_mismatch }, None)
+            }
         }
-        ret
     }
 }
 
@@ -158,31 +233,10 @@ impl<I: Iterator<Item = rust_htslib::errors::Result<bam::record::Record>>>
 
     fn next(&mut self) -> Option<Self::Item> {
         while let Some(r) = self.records.next() {
-            match r {
-                Ok(record) => {
-                    if record_is_not_primary(&record) || record.seq_len() == 0 {
-                        continue;
-                    }
-                    match ModBaseInfo::new_from_record(&record) {
-                        Ok(modbase_info) => {
-                            if modbase_info.is_empty() {
-                                continue;
-                            } else {
-                                return Some((record, modbase_info));
-                            }
-                        }
-                        Err(e) => match e {
-                            MkError::AuxMissing
-                            | MkError::NoModifiedBaseInformation
-                            | MkError::EmptyReadSequence => continue,
-                            _ => {
-                                self.num_errors += 1;
-                                continue;
-                            }
-                        },
-                    }
-                }
-                Err(_e) => {
+            match record_with_mod_base_info(r) {
+                Ok(Some(item)) => return Some(item),
+                Ok(None) => continue,
+                Err(()) => {
                     self.num_errors += 1;
                     continue;
                 }
@@ -198,6 +252,33 @@ impl<I: Iterator<Item = rust_htslib::errors::Result<bam::record::Record>>>
     }
 }
 
+/// The record and its base modification information as used by
+/// [`ModBaseInfoRecordTracker`], `None` for records that are passed over
+/// and an error for records that are counted as failed.
+pub(crate) fn record_with_mod_base_info(
+    r: rust_htslib::errors::Result<bam::Record>,
+) -> Result<Option<(bam::Record, ModBaseInfo)>, ()> {
+    let record = r.map_err(|_| ())?;
+    if record_is_not_primary(&record) || record.seq_len() == 0 {
+        return Ok(None);
+    }
+    match ModBaseInfo::new_from_record(&record) {
+        Ok(modbase_info) => {
+            if modbase_info.is_empty() {
+                Ok(None)
+            } else {
+                Ok(Some((record, modbase_info)))
+            }
+        }
+        Err(e) => match e {
+            MkError::AuxMissing
+            | MkError::NoModifiedBaseInformation
+            | MkError::EmptyReadSequence => Ok(None),
+            _ => Err(()),
+        },
+    }
+}
+
 // todo deprecate this function or move it into the tracking iterator above
 #[cfg(test)]
 pub(crate) fn filter_records_iter<T: bam::Read>(
@@ -1003,6 +1084,33 @@ impl MmTagInfo {
         self.mod_base_codes.len()
     }
 
+    /// When the record has an MN tag the sequence is the one the MM tag was
+    /// calculated on, so the delta list can't refer to more bases than the
+    /// sequence has. Without this check a mismatched MM tag is only caught
+    /// when it runs off the end of the sequence.
+    fn check_consistent_with_sequence(
+        &self,
+        converter: &DeltaListConverter,
+        seq_len: usize,
+    ) -> MkResult<()> {
+        let n_referenced =
+            self.delta_list.iter().map(|d| *d as usize + 1).sum::<usize>();
+        let n_bases = if self.fundamental_base == FundamentalBase::N {
+            seq_len
+        } else {
+            converter.cumulative_counts.last().copied().unwrap_or(0) as usize
+        };
+        if n_referenced > n_bases {
+            Err(MkError::MmMnMismatch(format!(
+                "MM tag refers to {n_referenced} {} bases, sequence (length \
+                 {seq_len}) has {n_bases}",
+                self.fundamental_base.char()
+            )))
+        } else {
+            Ok(())
+        }
+    }
+
     fn size(&self) -> usize {
         self.delta_list.len() * self.mod_base_codes.len()
     }
@@ -1412,6 +1520,24 @@ pub fn get_ml_tag_from_record(
     get_tag::<Vec<u16>>(&record, &ML_TAGS, &parse_ml_tag)
 }
 
+/// Log and add to the run summary the number of records that were skipped
+/// because their MM tag doesn't match the sequence given by their MN tag.
+pub(crate) fn report_mm_mn_mismatches(n_records: usize) {
+    if n_records > 0 {
+        warn!(
+            "~{n_records} records have an MM tag that doesn't match the \
+             sequence length given by their MN tag and were skipped, the MM \
+             tags may have been calculated on a different (e.g. trimmed) \
+             sequence"
+        );
+        let error_counts = FxHashMap::from_iter([(
+            MkError::MmMnMismatch(String::new()).to_string(),
+            n_records,
+        )]);
+        run_summary::record_errors(&error_counts);
+    }
+}
+
 #[inline]
 fn get_mn_tag_from_record(record: &bam::Record) -> MkResult<Option<usize>> {
     match record.aux(MN_TAG.as_bytes()) {
@@ -1510,6 +1636,13 @@ impl ModBaseInfo {
                     )
                 });
 
+            if raw_mod_tags.mn_length.is_some() {
+                mm_tag_info.check_consistent_with_sequence(
+                    converter,
+                    forward_seq.len(),
+                )?;
+            }
+
             // implicit probs are added here!
             let base_mod_probs = get_base_mod_probs(
                 &mm_tag_info,
@@ -1631,7 +1764,7 @@ pub fn base_mod_probs_from_record(
     extract_mod_probs(record, &forward_seq, &mm, &ml, &converter)
 }
 
-#[derive(new, Debug)]
+#[derive(new, Debug, Clone)]
 pub struct EdgeFilter {
     edge_filter_start: usize,
     edge_filter_end: usize,
@@ -2848,6 +2981,40 @@ mod mod_bam_tests {
         // }
     }
 
+    #[test]
+    fn test_mm_tag_checked_against_mn() {
+        // 3 Cs in the sequence, the MM tag refers to 4
+        let dna = "CATCACA";
+        let mm = "C+m?,0,0,0,0;";
+        let mm_tag_infos = MmTagInfo::parse_mm_tag(mm).unwrap();
+        let mut raw_mm_tags = RawModTags::new(mm, &vec![255u16; 4], true);
+        raw_mm_tags.mn_length = Some(dna.len());
+        let parse_result =
+            ModBaseInfo::new(&mm_tag_infos, &raw_mm_tags, dna.as_bytes());
+        assert!(matches!(parse_result, Err(MkError::MmMnMismatch(_))));
+        assert_eq!(
+            parse_result.err().unwrap().to_string(),
+            "MM-MN-mismatch".to_string()
+        );
+
+        // N refers to positions, not a base
+        let mm = "N+b?,3,3;";
+        let mm_tag_infos = MmTagInfo::parse_mm_tag(mm).unwrap();
+        let mut raw_mm_tags = RawModTags::new(mm, &vec![255u16; 2], true);
+        raw_mm_tags.mn_length = Some(dna.len());
+        let parse_result =
+            ModBaseInfo::new(&mm_tag_infos, &raw_mm_tags, dna.as_bytes());
+        assert!(matches!(parse_result, Err(MkError::MmMnMismatch(_))));
+
+        // consistent tags are fine
+        let mm = "C+m?,0,1;";
+        let mm_tag_infos = MmTagInfo::parse_mm_tag(mm).unwrap();
+        let mut raw_mm_tags = RawModTags::new(mm, &vec![255u16; 2], true);
+        raw_mm_tags.mn_length = Some(dna.len());
+        assert!(ModBaseInfo::new(&mm_tag_infos, &raw_mm_tags, dna.as_bytes())
+            .is_ok());
+    }
+
     #[test]
     fn test_generic_mm_tags_mixed_modes() {
         let dna = "CATCACA";
@@ -3,13 +3,13 @@ use std::collections::{BTreeMap, HashMap};
 use std::fmt::{Display, Formatter};
 
 use crate::errs::{MkError, MkResult};
+use crate::mod_code_registry::ModCodeRegistry;
 use crate::motifs::iupac::nt_bytes;
 use anyhow::anyhow;
 use clap::ValueEnum;
 use common_macros::hash_map;
 use derive_new::new;
 use lazy_static::lazy_static;
-use rustc_hash::FxHashMap;
 
 pub trait ParseChar {
     fn parse_char(c: char) -> MkResult<Self>
@@ -66,37 +66,6 @@ pub const SUPPORTED_CODES: [ModCodeRepr; 17] = [
 ];
 
 lazy_static! {
-    pub static ref MOD_CODE_TO_DNA_BASE: FxHashMap<ModCodeRepr, DnaBase> = {
-        let hm = hash_map! {
-            METHYL_CYTOSINE => DnaBase::C,
-            HYDROXY_METHYL_CYTOSINE => DnaBase::C,
-            FORMYL_CYTOSINE => DnaBase::C,
-            CARBOXY_CYTOSINE => DnaBase::C,
-            FOUR_METHYL_CYTOSINE => DnaBase::C,
-            ANY_CYTOSINE => DnaBase::C,
-            SIX_METHYL_ADENINE => DnaBase::A,
-            ANY_ADENINE => DnaBase::A,
-            INOSINE => DnaBase::A,
-            HYDROXY_METHYL_URACIL => DnaBase::T,
-            FORMYL_URACIL => DnaBase::T,
-            CARBOXY_URACIL => DnaBase::T,
-            PSEUDOURIDINE => DnaBase::T,
-            ANY_THYMINE => DnaBase::T,
-            OXO_GUANINE => DnaBase::G,
-            ANY_GUANINE => DnaBase::G,
-            DEOXY_URACIL => DnaBase::T,
-        };
-        hm.into_iter().collect()
-    };
-}
-
-lazy_static! {
-    pub static ref MOD_COLORS: HashMap<ModCodeRepr, String> = hash_map! {
-            METHYL_CYTOSINE => "#FF0000".to_string(),
-            HYDROXY_METHYL_CYTOSINE => "#FF00FF".to_string(),
-            SIX_METHYL_ADENINE => "#0084A9".to_string(),
-            FOUR_METHYL_CYTOSINE => "#FFA100".to_string()
-    };
     pub static ref DNA_BASE_COLORS: HashMap<DnaBase, String> = hash_map! {
             DnaBase::C => "#0000FF".to_string(),
             DnaBase::A => "#009600".to_string(),
@@ -123,11 +92,7 @@ impl ModCodeRepr {
     }
 
     pub fn check_base(&self, dna_base: DnaBase) -> bool {
-        if let Some(self_base) = MOD_CODE_TO_DNA_BASE.get(self) {
-            *self_base == dna_base
-        } else {
-            false
-        }
+        ModCodeRegistry::global().primary_base(self) == Some(dna_base)
     }
 
     pub fn is_any(&self) -> bool {
@@ -0,0 +1,248 @@
+//! Modification codes known to modkit, the primary base each one modifies,
+//! a name for display, and optionally a color used in plots and bedMethyl
+//! tracks. The registry starts with the codes in the SAM specification and
+//! can be extended with a user file passed with `--mod-code-file`.
+
+use std::io::BufRead;
+use std::path::Path;
+use std::sync::OnceLock;
+
+use anyhow::{anyhow, bail, Context};
+use rustc_hash::FxHashMap;
+
+use crate::mod_base_code::{
+    DnaBase, ModCodeRepr, ANY_ADENINE, ANY_CYTOSINE, ANY_GUANINE, ANY_THYMINE,
+    CARBOXY_CYTOSINE, CARBOXY_URACIL, DEOXY_URACIL, FORMYL_CYTOSINE,
+    FORMYL_URACIL, FOUR_METHYL_CYTOSINE, HYDROXY_METHYL_CYTOSINE,
+    HYDROXY_METHYL_URACIL, INOSINE, METHYL_CYTOSINE, OXO_GUANINE,
+    PSEUDOURIDINE, SIX_METHYL_ADENINE,
+};
+use crate::parsing_utils::open_text_input;
+
+static MOD_CODE_REGISTRY: OnceLock<ModCodeRegistry> = OnceLock::new();
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ModCodeInfo {
+    pub code: ModCodeRepr,
+    pub primary_base: DnaBase,
+    pub name: String,
+    /// Hex color, `#RRGGBB`.
+    pub color: Option<String>,
+}
+
+impl ModCodeInfo {
+    fn new(
+        code: ModCodeRepr,
+        primary_base: DnaBase,
+        name: &str,
+        color: Option<&str>,
+    ) -> Self {
+        Self {
+            code,
+            primary_base,
+            name: name.to_string(),
+            color: color.map(|c| c.to_string()),
+        }
+    }
+
+    /// Parse a line of a mod codes file, tab-separated code, primary base,
+    /// name, and an optional color.
+    fn parse_line(line: &str) -> anyhow::Result<Self> {
+        let parts = line.split('\t').map(|x| x.trim()).collect::<Vec<&str>>();
+        if !(3..=4).contains(&parts.len()) {
+            bail!(
+                "expected 3 or 4 tab-separated columns (code, primary base, \
+                 name, color), got {}",
+                parts.len()
+            )
+        }
+        let code = ModCodeRepr::parse(parts[0])?;
+        let primary_base = parts[1]
+            .parse::<char>()
+            .map_err(|e| anyhow!("invalid primary base {}, {e}", parts[1]))
+            .and_then(|c| {
+                DnaBase::parse(c.to_ascii_uppercase()).map_err(|e| e.into())
+            })?;
+        let name = parts[2];
+        if name.is_empty() {
+            bail!("name for modification code {code} is empty")
+        }
+        let color = match parts.get(3).filter(|c| !c.is_empty()) {
+            Some(color) => {
+                let is_hex = color.len() == 7
+                    && color.starts_with('#')
+                    && color[1..].chars().all(|c| c.is_ascii_hexdigit());
+                if !is_hex {
+                    bail!("invalid color {color}, should be #RRGGBB")
+                }
+                Some(*color)
+            }
+            None => None,
+        };
+        Ok(Self::new(code, primary_base, name, color))
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ModCodeRegistry {
+    codes: FxHashMap<ModCodeRepr, ModCodeInfo>,
+}
+
+impl ModCodeRegistry {
+    fn builtin() -> Self {
+        let codes = [
+            (METHYL_CYTOSINE, DnaBase::C, "5mC", Some("#FF0000")),
+            (HYDROXY_METHYL_CYTOSINE, DnaBase::C, "5hmC", Some("#FF00FF")),
+            (FORMYL_CYTOSINE, DnaBase::C, "5fC", None),
+            (CARBOXY_CYTOSINE, DnaBase::C, "5caC", None),
+            (FOUR_METHYL_CYTOSINE, DnaBase::C, "4mC", Some("#FFA100")),
+            (ANY_CYTOSINE, DnaBase::C, "modified C", None),
+            (SIX_METHYL_ADENINE, DnaBase::A, "6mA", Some("#0084A9")),
+            (ANY_ADENINE, DnaBase::A, "modified A", None),
+            (INOSINE, DnaBase::A, "inosine", None),
+            (HYDROXY_METHYL_URACIL, DnaBase::T, "5hmU", None),
+            (FORMYL_URACIL, DnaBase::T, "5fU", None),
+            (CARBOXY_URACIL, DnaBase::T, "5caU", None),
+            (PSEUDOURIDINE, DnaBase::T, "pseudouridine", None),
+            (ANY_THYMINE, DnaBase::T, "modified T", None),
+            (OXO_GUANINE, DnaBase::G, "8oxoG", None),
+            (ANY_GUANINE, DnaBase::G, "modified G", None),
+            (DEOXY_URACIL, DnaBase::T, "dU", None),
+        ]
+        .into_iter()
+        .map(|(code, base, name, color)| {
+            (code, ModCodeInfo::new(code, base, name, color))
+        })
+        .collect();
+        Self { codes }
+    }
+
+    /// Add the codes in a tab-separated file with columns code, primary base,
+    /// name, and (optionally) color. Lines starting with `#` are skipped. An
+    /// entry for a code that is already in the registry replaces its name
+    /// and color, but cannot change its primary base.
+    fn extend_from_file(&mut self, fp: &Path) -> anyhow::Result<()> {
+        let reader = open_text_input(fp).with_context(|| {
+            format!("failed to read modification codes file at {fp:?}")
+        })?;
+        for (i, line) in reader.lines().enumerate() {
+            let line = line?;
+            if line.trim().is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let info = ModCodeInfo::parse_line(&line)
+                .with_context(|| format!("invalid line {} in {fp:?}", i + 1))?;
+            if let Some(existing) = self.codes.get(&info.code) {
+                if existing.primary_base != info.primary_base {
+                    bail!(
+                        "modification code {} is for primary base {}, cannot \
+                         change it to {}",
+                        info.code,
+                        existing.primary_base,
+                        info.primary_base
+                    )
+                }
+            }
+            self.codes.insert(info.code, info);
+        }
+        Ok(())
+    }
+
+    /// The registry used by all commands, the built-in codes plus the codes
+    /// from the `--mod-code-file` when one was given.
+    pub fn global() -> &'static Self {
+        MOD_CODE_REGISTRY.get_or_init(Self::builtin)
+    }
+
+    pub fn get(&self, code: &ModCodeRepr) -> Option<&ModCodeInfo> {
+        self.codes.get(code)
+    }
+
+    pub fn primary_base(&self, code: &ModCodeRepr) -> Option<DnaBase> {
+        self.codes.get(code).map(|info| info.primary_base)
+    }
+
+    pub fn color(&self, code: &ModCodeRepr) -> Option<&str> {
+        self.codes.get(code).and_then(|info| info.color.as_deref())
+    }
+
+    /// The code along with its name when it has one, e.g. `m (5mC)`.
+    pub fn describe(&self, code: &ModCodeRepr) -> String {
+        match self.codes.get(code) {
+            Some(info) => format!("{code} ({})", info.name),
+            None => code.to_string(),
+        }
+    }
+
+    /// Mapping of each code to the primary base it modifies.
+    pub fn code_to_base(&self) -> FxHashMap<ModCodeRepr, DnaBase> {
+        self.codes
+            .iter()
+            .map(|(code, info)| (*code, info.primary_base))
+            .collect()
+    }
+
+    /// Codes with a color, for plots and bedMethyl tracks.
+    pub fn colors(&self) -> impl Iterator<Item = (&ModCodeRepr, &str)> {
+        self.codes.iter().filter_map(|(code, info)| {
+            info.color.as_deref().map(|color| (code, color))
+        })
+    }
+}
+
+/// Extend the registry with the codes in `fp`, must be called before the
+/// registry is used.
+pub fn init_mod_code_registry(fp: &Path) -> anyhow::Result<()> {
+    let mut registry = ModCodeRegistry::builtin();
+    registry.extend_from_file(fp)?;
+    MOD_CODE_REGISTRY
+        .set(registry)
+        .map_err(|_| anyhow!("modification code registry already initialized"))
+}
+
+#[cfg(test)]
+mod mod_code_registry_tests {
+    use std::io::Write;
+
+    use crate::mod_base_code::{DnaBase, ModCodeRepr, METHYL_CYTOSINE};
+    use crate::mod_code_registry::ModCodeRegistry;
+
+    #[test]
+    fn test_mod_code_registry_extend_from_file() {
+        let mut fp = tempfile::NamedTempFile::new().unwrap();
+        writeln!(fp, "# code\tprimary_base\tname\tcolor").unwrap();
+        writeln!(fp, "x\tA\tmy mod\t#00FF00").unwrap();
+        writeln!(fp, "12345\tc\tchebi mod").unwrap();
+        writeln!(fp, "m\tC\t5-methylcytosine\t#000000").unwrap();
+        fp.flush().unwrap();
+
+        let mut registry = ModCodeRegistry::builtin();
+        registry.extend_from_file(fp.path()).unwrap();
+        let x = ModCodeRepr::Code('x');
+        assert_eq!(registry.primary_base(&x), Some(DnaBase::A));
+        assert_eq!(registry.color(&x), Some("#00FF00"));
+        assert_eq!(registry.describe(&x), "x (my mod)");
+        let chebi = ModCodeRepr::ChEbi(12345);
+        assert_eq!(registry.primary_base(&chebi), Some(DnaBase::C));
+        assert_eq!(registry.color(&chebi), None);
+        assert_eq!(registry.describe(&METHYL_CYTOSINE), "m (5-methylcytosine)");
+        assert_eq!(registry.color(&METHYL_CYTOSINE), Some("#000000"));
+        assert_eq!(registry.describe(&ModCodeRepr::Code('z')), "z");
+    }
+
+    #[test]
+    fn test_mod_code_registry_invalid_file() {
+        let check = |contents: &str| {
+            let mut fp = tempfile::NamedTempFile::new().unwrap();
+            write!(fp, "{contents}").unwrap();
+            fp.flush().unwrap();
+            ModCodeRegistry::builtin().extend_from_file(fp.path())
+        };
+        // built-in code with a different primary base
+        assert!(check("m\tA\tnot 5mC\n").is_err());
+        assert!(check("x\tN\tmy mod\n").is_err());
+        assert!(check("x\tA\n").is_err());
+        assert!(check("x\tA\tmy mod\tred\n").is_err());
+        assert!(check("x\tA\tmy mod\t#00FF00\n").is_ok());
+    }
+}
@@ -157,10 +157,12 @@ impl EntryCheckTags {
                         }
                         Some(SamplingSchedule::from_num_reads(
                             &self.in_bam,
+                            None,
                             num_reads,
                             region.as_ref(),
                             None,
                             !self.only_mapped,
+                            None,
                         )?)
                     }
                     Err(_) => {
@@ -294,6 +296,7 @@ impl EntryCheckTags {
                                     });
                                 sample_reads_from_interval::<ModTagViews>(
                                     &bam_fp1,
+                                    None,
                                     cc.chrom_tid(),
                                     cc.start_pos(),
                                     cc.end_pos(),
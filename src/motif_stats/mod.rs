@@ -0,0 +1,272 @@
+use std::fmt::{Display, Formatter};
+use std::io::BufRead;
+use std::path::Path;
+
+use anyhow::bail;
+use clap::ValueEnum;
+use indicatif::ProgressBar;
+use log::debug;
+use log_once::warn_once;
+use rustc_hash::FxHashMap;
+
+use crate::dmr::bedmethyl::BedMethylLine;
+use crate::fasta::ReferenceSequences;
+use crate::mod_base_code::ModCodeRepr;
+use crate::motifs::motif_bed::RegexMotif;
+use crate::util::{Strand, StrandRule};
+
+pub mod subcommand;
+
+/// Commonly reported sequence contexts.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, ValueEnum)]
+#[allow(non_camel_case_types)]
+pub(super) enum MotifPreset {
+    /// CG, offset 0.
+    cpg,
+    /// CHG, offset 0.
+    chg,
+    /// CHH, offset 0.
+    chh,
+    /// GATC, offset 1 (the adenine).
+    gatc,
+}
+
+impl MotifPreset {
+    fn motif_and_offset(&self) -> (&'static str, usize) {
+        match self {
+            Self::cpg => ("CG", 0),
+            Self::chg => ("CHG", 0),
+            Self::chh => ("CHH", 0),
+            Self::gatc => ("GATC", 1),
+        }
+    }
+
+    pub(super) fn to_motif(self) -> anyhow::Result<RegexMotif> {
+        let (motif, offset) = self.motif_and_offset();
+        RegexMotif::parse_string(motif, offset)
+    }
+}
+
+impl Display for MotifPreset {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::cpg => write!(f, "cpg"),
+            Self::chg => write!(f, "chg"),
+            Self::chh => write!(f, "chh"),
+            Self::gatc => write!(f, "gatc"),
+        }
+    }
+}
+
+/// Counts aggregated over all bedMethyl records at a motif for one
+/// modification code.
+#[derive(Debug, Default, Copy, Clone, PartialEq)]
+pub(super) struct MotifModCounts {
+    n_sites: u64,
+    n_modified: u64,
+    n_valid: u64,
+    sum_site_fraction: f64,
+}
+
+impl MotifModCounts {
+    fn add(&mut self, record: &BedMethylLine) {
+        self.n_sites += 1;
+        self.n_modified += record.count_methylated;
+        self.n_valid += record.valid_coverage;
+        self.sum_site_fraction += record.frac_modified() as f64;
+    }
+
+    /// Modified fraction pooled over all reads at the motif sites.
+    fn fraction_modified(&self) -> f64 {
+        if self.n_valid == 0 {
+            0f64
+        } else {
+            self.n_modified as f64 / self.n_valid as f64
+        }
+    }
+
+    /// Mean of the per-site modified fractions.
+    fn mean_site_fraction(&self) -> f64 {
+        if self.n_sites == 0 {
+            0f64
+        } else {
+            self.sum_site_fraction / self.n_sites as f64
+        }
+    }
+}
+
+/// Aggregates bedMethyl records into per-motif, per-modification code counts.
+pub(super) struct MotifStatsAggregator {
+    motifs: Vec<RegexMotif>,
+    reference: ReferenceSequences,
+    min_coverage: u64,
+    max_coverage: Option<u64>,
+    counts: FxHashMap<(usize, ModCodeRepr), MotifModCounts>,
+    n_records: usize,
+    n_skipped: usize,
+}
+
+impl MotifStatsAggregator {
+    pub(super) fn new(
+        motifs: Vec<RegexMotif>,
+        reference_fp: &Path,
+        min_coverage: u64,
+        max_coverage: Option<u64>,
+    ) -> anyhow::Result<Self> {
+        if motifs.is_empty() {
+            bail!("need at least one motif")
+        }
+        let reference = ReferenceSequences::from_path(reference_fp)?;
+        Ok(Self {
+            motifs,
+            reference,
+            min_coverage,
+            max_coverage,
+            counts: FxHashMap::default(),
+            n_records: 0,
+            n_skipped: 0,
+        })
+    }
+
+    fn passes_coverage(&self, record: &BedMethylLine) -> bool {
+        // records without valid coverage have no modified fraction
+        record.valid_coverage >= self.min_coverage.max(1)
+            && self
+                .max_coverage
+                .map(|max| record.valid_coverage <= max)
+                .unwrap_or(true)
+    }
+
+    fn add_record(&mut self, record: &BedMethylLine) -> anyhow::Result<()> {
+        self.n_records += 1;
+        if !self.passes_coverage(record) {
+            return Ok(());
+        }
+        let Some(seq) = self.reference.get_sequence(&record.chrom)? else {
+            warn_once!(
+                "contig {} is not in the reference, skipping",
+                &record.chrom
+            );
+            self.n_skipped += 1;
+            return Ok(());
+        };
+        let pos = record.start() as usize;
+        let strands: &[Strand] = match record.strand {
+            StrandRule::Positive => &[Strand::Positive],
+            StrandRule::Negative => &[Strand::Negative],
+            StrandRule::Both => &[Strand::Positive, Strand::Negative],
+        };
+        for (idx, motif) in self.motifs.iter().enumerate() {
+            if strands.iter().any(|s| motif.matches_at(seq, pos, *s)) {
+                self.counts
+                    .entry((idx, record.raw_mod_code))
+                    .or_default()
+                    .add(record);
+            }
+        }
+        Ok(())
+    }
+
+    pub(super) fn add_stream<T: BufRead>(
+        &mut self,
+        stream: T,
+        pb: &ProgressBar,
+    ) -> anyhow::Result<()> {
+        for line in stream.lines() {
+            let line = line?;
+            if line.starts_with('#') || line.starts_with("track") {
+                continue;
+            }
+            match BedMethylLine::parse(&line) {
+                Ok(record) => self.add_record(&record)?,
+                Err(e) => {
+                    debug!("failed to parse bedMethyl line, {e}");
+                    self.n_skipped += 1;
+                }
+            }
+            pb.inc(1);
+        }
+        Ok(())
+    }
+
+    pub(super) fn n_records(&self) -> usize {
+        self.n_records
+    }
+
+    pub(super) fn n_skipped(&self) -> usize {
+        self.n_skipped
+    }
+
+    pub(super) fn header() -> String {
+        [
+            "motif",
+            "offset",
+            "mod_code",
+            "n_sites",
+            "count_modified",
+            "count_valid",
+            "fraction_modified",
+            "mean_site_fraction_modified",
+        ]
+        .join("\t")
+    }
+
+    /// Table rows in the order the motifs were given, then by modification
+    /// code.
+    pub(super) fn rows(&self) -> Vec<String> {
+        let mut keys = self.counts.keys().collect::<Vec<_>>();
+        keys.sort();
+        keys.into_iter()
+            .map(|key @ (idx, mod_code)| {
+                let motif = &self.motifs[*idx];
+                let counts = &self.counts[key];
+                format!(
+                    "{}\t{}\t{mod_code}\t{}\t{}\t{}\t{:.6}\t{:.6}",
+                    motif.motif_label(),
+                    motif.motif_info.forward_offset,
+                    counts.n_sites,
+                    counts.n_modified,
+                    counts.n_valid,
+                    counts.fraction_modified(),
+                    counts.mean_site_fraction(),
+                )
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod motif_stats_tests {
+    use crate::dmr::bedmethyl::BedMethylLine;
+    use crate::motif_stats::MotifModCounts;
+
+    #[test]
+    fn test_motif_mod_counts() {
+        let mut counts = MotifModCounts::default();
+        for (start, n_valid, n_mod) in [(10u64, 10u64, 10u64), (20, 30, 0)] {
+            let line = [
+                "chr1".to_string(),
+                start.to_string(),
+                (start + 1).to_string(),
+                "m".to_string(),
+                n_valid.to_string(),
+                "+".to_string(),
+                start.to_string(),
+                (start + 1).to_string(),
+                "255,0,0".to_string(),
+                n_valid.to_string(),
+                format!("{:.2}", n_mod as f32 / n_valid as f32 * 100f32),
+                n_mod.to_string(),
+                (n_valid - n_mod).to_string(),
+                "0\t0\t0\t0\t0".to_string(),
+            ]
+            .join("\t");
+            counts.add(&BedMethylLine::parse(&line).unwrap());
+        }
+        assert_eq!(counts.n_sites, 2);
+        assert_eq!(counts.n_modified, 10);
+        assert_eq!(counts.n_valid, 40);
+        assert_eq!(counts.fraction_modified(), 0.25);
+        assert_eq!(counts.mean_site_fraction(), 0.5);
+    }
+}
@@ -0,0 +1,147 @@
+use std::fs::File;
+use std::io::{stdout, BufRead, BufReader, BufWriter, Write};
+use std::path::PathBuf;
+
+use anyhow::{bail, Context};
+use clap::Args;
+use indicatif::{MultiProgress, ProgressDrawTarget};
+use itertools::Itertools;
+use log::info;
+
+use crate::logging::init_logging;
+use crate::motif_stats::{MotifPreset, MotifStatsAggregator};
+use crate::motifs::motif_bed::RegexMotif;
+use crate::util::{create_out_directory, get_ticker};
+
+#[derive(Args)]
+#[command(arg_required_else_help = true)]
+pub struct EntryMotifStats {
+    /// Input bedMethyl table, can be plain text or bgzip-compressed. Use "-"
+    /// or "stdin" to read from standard input.
+    in_bedmethyl: String,
+    /// Reference sequence in FASTA format, must have an associated FAI
+    /// index.
+    #[arg(long, alias = "ref")]
+    reference: PathBuf,
+    /// Sequence contexts to report, can be passed multiple times or as a
+    /// comma-separated list. When neither --preset nor --motif are given the
+    /// default is cpg, chg, and chh.
+    #[arg(long, value_delimiter = ',', action = clap::ArgAction::Append)]
+    preset: Vec<MotifPreset>,
+    /// Additional motif to report, the first argument is the sequence motif
+    /// and the second argument is the 0-based offset to the modified base in
+    /// the motif. For example, --motif CCWGG 1. Can be passed multiple times.
+    #[arg(long, action = clap::ArgAction::Append, num_args = 2)]
+    motif: Option<Vec<String>>,
+    /// Only use bedMethyl records with at least this much valid coverage.
+    #[arg(short = 'm', long, alias = "min-cov", default_value_t = 3)]
+    min_coverage: u64,
+    /// Only use bedMethyl records with at most this much valid coverage,
+    /// useful to remove collapsed repeats.
+    #[arg(long, alias = "max-cov")]
+    max_coverage: Option<u64>,
+    /// Optionally specify a file to write output to, default is stdout.
+    #[clap(help_heading = "Output Options")]
+    #[arg(long, short = 'o')]
+    out_file: Option<PathBuf>,
+    /// Force overwrite of existing output file.
+    #[clap(help_heading = "Output Options")]
+    #[arg(long, short = 'f', default_value_t = false)]
+    force: bool,
+    /// Don't add the header describing the columns to the output.
+    #[clap(help_heading = "Output Options")]
+    #[arg(long, default_value_t = false)]
+    no_header: bool,
+    /// Hide the progress bar.
+    #[clap(help_heading = "Logging Options")]
+    #[arg(long, default_value_t = false, hide_short_help = true)]
+    suppress_progress: bool,
+    /// Specify a file to write debug logs to.
+    #[clap(help_heading = "Logging Options")]
+    #[arg(long, alias = "log")]
+    log_filepath: Option<PathBuf>,
+}
+
+impl EntryMotifStats {
+    fn motifs(&self) -> anyhow::Result<Vec<RegexMotif>> {
+        let presets = if self.preset.is_empty() && self.motif.is_none() {
+            vec![MotifPreset::cpg, MotifPreset::chg, MotifPreset::chh]
+        } else {
+            self.preset.iter().copied().unique().collect()
+        };
+        let mut motifs = presets
+            .iter()
+            .map(|preset| preset.to_motif())
+            .collect::<anyhow::Result<Vec<RegexMotif>>>()?;
+        if let Some(raw_motifs) = self.motif.as_ref() {
+            motifs.extend(RegexMotif::from_raw_parts(raw_motifs, false)?);
+        }
+        if motifs.iter().map(|m| m.to_string()).unique().count() != motifs.len()
+        {
+            bail!("cannot have the same motif more than once")
+        }
+        Ok(motifs)
+    }
+
+    pub fn run(&self) -> anyhow::Result<()> {
+        let _handle = init_logging(self.log_filepath.as_ref());
+        if let Some(max_coverage) = self.max_coverage {
+            if max_coverage < self.min_coverage {
+                bail!("--max-coverage must be at least --min-coverage")
+            }
+        }
+        let motifs = self.motifs()?;
+        info!(
+            "calculating modification levels for motifs: {}",
+            motifs.iter().join(" ")
+        );
+        let mut writer: Box<dyn Write> = match self.out_file.as_ref() {
+            Some(out_fp) => {
+                create_out_directory(out_fp)?;
+                if out_fp.exists() && !self.force {
+                    bail!("refusing to overwrite existing file {out_fp:?}")
+                }
+                Box::new(BufWriter::new(File::create(out_fp)?))
+            }
+            None => Box::new(BufWriter::new(stdout())),
+        };
+        let in_stream: Box<dyn BufRead> = match self.in_bedmethyl.as_str() {
+            "-" | "stdin" => Box::new(BufReader::new(std::io::stdin().lock())),
+            p => {
+                let reader = rust_htslib::bgzf::Reader::from_path(p)
+                    .with_context(|| format!("failed to open {p}"))?;
+                Box::new(BufReader::new(reader))
+            }
+        };
+
+        let mut aggregator = MotifStatsAggregator::new(
+            motifs,
+            &self.reference,
+            self.min_coverage,
+            self.max_coverage,
+        )?;
+        let mpb = MultiProgress::new();
+        if self.suppress_progress {
+            mpb.set_draw_target(ProgressDrawTarget::hidden());
+        }
+        let pb = mpb.add(get_ticker());
+        pb.set_message("bedMethyl records processed");
+        aggregator.add_stream(in_stream, &pb)?;
+        pb.finish_and_clear();
+
+        if !self.no_header {
+            writeln!(writer, "{}", MotifStatsAggregator::header())?;
+        }
+        for row in aggregator.rows() {
+            writeln!(writer, "{row}")?;
+        }
+        writer.flush()?;
+        info!(
+            "finished, processed {} records, skipped {}",
+            aggregator.n_records(),
+            aggregator.n_skipped()
+        );
+
+        Ok(())
+    }
+}
@@ -23,12 +23,15 @@ use tracing::debug;
 
 use crate::dmr::bedmethyl::BedMethylLine;
 use crate::errs::MkError;
-use crate::mod_base_code::{DnaBase, ModCodeRepr, MOD_CODE_TO_DNA_BASE};
+use crate::mod_base_code::{DnaBase, ModCodeRepr};
+use crate::mod_code_registry::ModCodeRegistry;
 use crate::motifs::args::KnownMotifsArgs;
 use crate::motifs::iupac::nt_bytes::BASES;
 use crate::motifs::iupac::IupacBase;
-use crate::parsing_utils::consume_string;
-use crate::util::{get_subroutine_progress_bar, get_ticker, StrandRule};
+use crate::parsing_utils::{consume_string, open_text_input};
+use crate::util::{
+    get_subroutine_progress_bar, get_ticker, uracil_to_thymine, StrandRule,
+};
 
 mod args;
 pub(crate) mod iupac;
@@ -831,15 +834,16 @@ impl KmerModificationDb {
                 "inferred {prim_base:?} associated with modification code \
                  {mod_code}"
             );
-            if let Some(expected_prim_base) = MOD_CODE_TO_DNA_BASE.get(mod_code)
+            if let Some(expected_prim_base) =
+                ModCodeRegistry::global().primary_base(mod_code)
             {
-                if expected_prim_base != prim_base && force_specification {
+                if expected_prim_base != *prim_base && force_specification {
                     bail!(
                         "modification code {mod_code} should be associated \
                          with {expected_prim_base:?}, use \
                          --force-override-spec to override."
                     )
-                } else if expected_prim_base != prim_base {
+                } else if expected_prim_base != *prim_base {
                     warn!(
                         "modification code {mod_code} is normally associated \
                          with {expected_prim_base:?}, inferred to be \
@@ -1075,7 +1079,7 @@ fn load_references_from_fasta(
                 let seq = r
                     .seq()
                     .iter()
-                    .map(|&nt| nt.to_ascii_uppercase())
+                    .map(|&nt| uracil_to_thymine(nt.to_ascii_uppercase()))
                     .collect::<Vec<u8>>();
                 agg.insert(record_name, seq);
                 pb.inc(1);
@@ -1135,7 +1139,7 @@ fn parse_motifs_from_table(
         Ok((rest, (raw_mod_code, raw_motif_seq, raw_offset)))
     }
 
-    let reader = BufReader::new(std::fs::File::open(table_fp)?);
+    let reader = open_text_input(table_fp)?;
 
     reader
         .lines()
@@ -5,11 +5,12 @@ use std::path::PathBuf;
 use crate::monoid::Moniod;
 use crate::position_filter::StrandedPositionFilter;
 use crate::util::{
-    get_master_progress_bar, get_spinner, get_ticker, ReferenceRecord, Strand,
-    StrandRule,
+    get_master_progress_bar, get_spinner, get_ticker, normalize_reference_seq,
+    thymine_to_uracil_label, ReferenceRecord, Strand, StrandRule,
 };
 use anyhow::{anyhow, bail, Context, Result as AnyhowResult};
 use bio::io::fasta::Reader as FastaReader;
+use clap::ValueEnum;
 use derive_new::new;
 use indicatif::{MultiProgress, ProgressIterator};
 use itertools::Itertools;
@@ -18,49 +19,132 @@ use rayon::prelude::*;
 use regex::{Match, Regex};
 use rustc_hash::FxHashMap;
 
-fn iupac_to_regex(pattern: &str) -> anyhow::Result<String> {
+/// How ambiguous (non-ACGT) bases in the reference are handled when finding
+/// motifs and reporting reference kmers.
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq, ValueEnum)]
+#[allow(non_camel_case_types)]
+pub enum AmbiguousBases {
+    /// Ambiguous reference bases never match a motif, they are reported
+    /// as-is in reference kmers.
+    #[default]
+    skip,
+    /// Ambiguous reference bases match any motif base, they are reported as
+    /// N in reference kmers.
+    match_any,
+    /// IUPAC codes in the reference match a motif base when the code
+    /// includes that base, e.g. R matches A and G, they are reported as-is
+    /// in reference kmers.
+    expand,
+}
+
+impl Display for AmbiguousBases {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let label = match self {
+            Self::skip => "skip",
+            Self::match_any => "match-any",
+            Self::expand => "expand",
+        };
+        write!(f, "{label}")
+    }
+}
+
+impl AmbiguousBases {
+    /// Apply the handling to a reference kmer.
+    pub(crate) fn ref_kmer(&self, kmer: String) -> String {
+        let is_ambiguous =
+            |b: u8| iupac_bases(b.to_ascii_uppercase()).len() > 1;
+        match self {
+            Self::match_any if kmer.bytes().any(is_ambiguous) => kmer
+                .bytes()
+                .map(|b| if is_ambiguous(b) { 'N' } else { b as char })
+                .collect(),
+            _ => kmer,
+        }
+    }
+
+    fn base_class(&self, bases: &[u8]) -> String {
+        let class = IUPAC_ORDER
+            .iter()
+            .filter(|&&code| {
+                let expansion = iupac_bases(code);
+                match self {
+                    Self::skip => bases.contains(&code),
+                    Self::match_any => {
+                        bases.contains(&code) || expansion.len() > 1
+                    }
+                    Self::expand => expansion.iter().any(|b| bases.contains(b)),
+                }
+            })
+            .map(|&code| code as char)
+            .collect::<String>();
+        if class.len() == 1 {
+            class
+        } else {
+            format!("[{class}]")
+        }
+    }
+}
+
+const IUPAC_ORDER: [u8; 16] = *b"ACGTMRWSYKVHDBNX";
+
+/// The DNA bases represented by an (uppercase) IUPAC code, empty for
+/// unknown codes.
+fn iupac_bases(code: u8) -> &'static [u8] {
+    match code {
+        b'A' => b"A",
+        b'C' => b"C",
+        b'G' => b"G",
+        b'T' | b'U' => b"T",
+        b'M' => b"AC",
+        b'R' => b"AG",
+        b'W' => b"AT",
+        b'S' => b"CG",
+        b'Y' => b"CT",
+        b'K' => b"GT",
+        b'V' => b"ACG",
+        b'H' => b"ACT",
+        b'D' => b"AGT",
+        b'B' => b"CGT",
+        b'X' | b'N' => b"ACGT",
+        _ => b"",
+    }
+}
+
+fn iupac_complement(code: char) -> char {
+    match code {
+        'A' => 'T',
+        'C' => 'G',
+        'G' => 'C',
+        'T' | 'U' => 'A',
+        'M' => 'K',
+        'K' => 'M',
+        'R' => 'Y',
+        'Y' => 'R',
+        'V' => 'B',
+        'B' => 'V',
+        'H' => 'D',
+        'D' => 'H',
+        _ => code,
+    }
+}
+
+fn iupac_to_regex(
+    pattern: &str,
+    ambiguous_bases: AmbiguousBases,
+) -> anyhow::Result<String> {
     let mut regex = String::new();
     for c in pattern.chars() {
-        regex.push_str(match c {
-            'A' => "A",
-            'C' => "C",
-            'G' => "G",
-            'T' => "T",
-            'U' => "U",
-            'M' => "[AC]",
-            'R' => "[AG]",
-            'W' => "[AT]",
-            'S' => "[CG]",
-            'Y' => "[CT]",
-            'K' => "[GT]",
-            'V' => "[ACG]",
-            'H' => "[ACT]",
-            'D' => "[AGT]",
-            'B' => "[CGT]",
-            'X' => "[ACGT]",
-            'N' => "[ACGT]",
-            _ => bail!("Invalid IUPAC code: {}", c),
-        });
+        let bases = iupac_bases(c as u8);
+        if !c.is_ascii() || bases.is_empty() {
+            bail!("Invalid IUPAC code: {}", c)
+        }
+        regex.push_str(&ambiguous_bases.base_class(bases));
     }
     Ok(regex)
 }
 
 fn motif_rev_comp(motif: &str) -> String {
-    let mut reverse_complement = motif.chars().rev().collect::<String>();
-    reverse_complement = reverse_complement
-        .chars()
-        .map(|c| match c {
-            'A' => 'T',
-            'C' => 'G',
-            'G' => 'C',
-            'T' => 'A',
-            'U' => 'A',
-            '[' => ']',
-            ']' => '[',
-            _ => c,
-        })
-        .collect();
-    reverse_complement
+    motif.chars().rev().map(iupac_complement).collect()
 }
 
 pub(crate) struct OverlappingPatternIterator<'a> {
@@ -106,6 +190,14 @@ impl OverlappingRegex {
     ) -> OverlappingPatternIterator<'a> {
         OverlappingPatternIterator { text: &text, re: &self.inner, start: 0 }
     }
+
+    /// Whether the pattern matches all of `text`.
+    fn is_full_match(&self, text: &str) -> bool {
+        self.inner
+            .find(text)
+            .map(|m| m.start() == 0 && m.end() == text.len())
+            .unwrap_or(false)
+    }
 }
 
 #[derive(Debug, new, Copy, Clone)]
@@ -146,6 +238,14 @@ pub struct RegexMotif {
     pub(crate) reverse_pattern: OverlappingRegex,
     pub motif_info: MotifInfo,
     pub raw_motif: String,
+    #[new(default)]
+    ambiguous_bases: AmbiguousBases,
+    /// Label the motif with uracil (U) in place of thymine (T).
+    #[new(default)]
+    rna_label: bool,
+    /// Only find the motif on the positive strand.
+    #[new(default)]
+    positive_strand_only: bool,
 }
 
 impl RegexMotif {
@@ -195,6 +295,23 @@ impl RegexMotif {
     }
 
     pub fn parse_string(raw_motif: &str, offset: usize) -> AnyhowResult<Self> {
+        Self::parse_string_with_ambiguous_bases(
+            raw_motif,
+            offset,
+            AmbiguousBases::default(),
+        )
+    }
+
+    pub fn parse_string_with_ambiguous_bases(
+        raw_motif: &str,
+        offset: usize,
+        ambiguous_bases: AmbiguousBases,
+    ) -> AnyhowResult<Self> {
+        // RNA motifs are matched against references with the DNA alphabet,
+        // but keep the uracil in the label
+        let rna_label = raw_motif.contains('U');
+        let raw_motif = raw_motif.replace('U', "T");
+        let raw_motif = raw_motif.as_str();
         let length = raw_motif.len();
         if length == 1 {
             match raw_motif {
@@ -205,9 +322,10 @@ impl RegexMotif {
                 ),
             };
         };
-        let motif = iupac_to_regex(raw_motif)?;
+        let motif = iupac_to_regex(raw_motif, ambiguous_bases)?;
         let re = OverlappingRegex::new(&motif)?;
-        let rc_motif = motif_rev_comp(&motif);
+        let rc_motif =
+            iupac_to_regex(&motif_rev_comp(raw_motif), ambiguous_bases)?;
         let rc_re = OverlappingRegex::new(&rc_motif)?;
         let rc_offset = raw_motif
             .len()
@@ -219,7 +337,58 @@ impl RegexMotif {
             length,
             re.as_str() == rc_re.as_str(),
         );
-        Ok(Self::new(re, rc_re, motif_info, raw_motif.to_owned()))
+        Ok(Self {
+            forward_pattern: re,
+            reverse_pattern: rc_re,
+            motif_info,
+            raw_motif: raw_motif.to_owned(),
+            ambiguous_bases,
+            rna_label,
+            positive_strand_only: false,
+        })
+    }
+
+    /// Re-make the motif patterns with a different handling of ambiguous
+    /// reference bases.
+    pub fn with_ambiguous_bases(
+        self,
+        ambiguous_bases: AmbiguousBases,
+    ) -> AnyhowResult<Self> {
+        if self.ambiguous_bases == ambiguous_bases {
+            Ok(self)
+        } else {
+            Self::parse_string_with_ambiguous_bases(
+                &self.raw_motif,
+                self.forward_offset(),
+                ambiguous_bases,
+            )
+            .map(|motif| Self {
+                rna_label: self.rna_label,
+                positive_strand_only: self.positive_strand_only,
+                ..motif
+            })
+        }
+    }
+
+    /// Label the motif with uracil (U) in place of thymine (T), for direct
+    /// RNA data. Motifs given with U are always labelled this way.
+    pub fn with_rna_label(self, rna_label: bool) -> Self {
+        Self { rna_label: self.rna_label || rna_label, ..self }
+    }
+
+    /// Only find the motif on the positive strand, e.g. for reads aligned to
+    /// transcripts.
+    pub fn with_positive_strand_only(self) -> Self {
+        Self { positive_strand_only: true, ..self }
+    }
+
+    /// The motif as given, without the offset.
+    pub fn motif_label(&self) -> String {
+        if self.rna_label {
+            thymine_to_uracil_label(&self.raw_motif)
+        } else {
+            self.raw_motif.to_owned()
+        }
     }
 
     pub(crate) fn is_palendrome(&self) -> bool {
@@ -249,11 +418,29 @@ impl RegexMotif {
     pub(crate) fn find_hits(&self, seq: &str) -> Vec<(usize, Strand)> {
         find_motif_hits(seq, &self)
     }
+
+    /// Whether `pos` in `seq` is the focus base of this motif on `strand`.
+    pub(crate) fn matches_at(
+        &self,
+        seq: &str,
+        pos: usize,
+        strand: Strand,
+    ) -> bool {
+        let (pattern, offset) = match strand {
+            Strand::Positive => (&self.forward_pattern, self.forward_offset()),
+            Strand::Negative if self.positive_strand_only => return false,
+            Strand::Negative => (&self.reverse_pattern, self.reverse_offset()),
+        };
+        pos.checked_sub(offset)
+            .and_then(|start| seq.get(start..start + self.length()))
+            .map(|window| pattern.is_full_match(window))
+            .unwrap_or(false)
+    }
 }
 
 impl Display for RegexMotif {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{},{}", self.raw_motif, self.forward_offset())
+        write!(f, "{},{}", self.motif_label(), self.forward_offset())
     }
 }
 
@@ -314,7 +501,9 @@ pub(crate) fn find_motif_hits(
                 ));
             }
         }
-    } else if regex_motif.length() == 1 {
+    } else if regex_motif.length() == 1
+        && regex_motif.ambiguous_bases == AmbiguousBases::skip
+    {
         let mut single_base_sites = find_single_bases(seq, regex_motif);
         motif_hits.append(&mut single_base_sites);
     } else {
@@ -332,6 +521,9 @@ pub(crate) fn find_motif_hits(
         }
         motif_hits.sort_by(|(x_pos, _), (y_pos, _)| x_pos.cmp(&y_pos));
     }
+    if regex_motif.positive_strand_only {
+        motif_hits.retain(|(_, strand)| *strand == Strand::Positive);
+    }
 
     motif_hits
 }
@@ -356,26 +548,13 @@ pub fn motif_bed(
     motif_raw: &str,
     offset: usize,
     mask: bool,
+    ambiguous_bases: AmbiguousBases,
 ) -> AnyhowResult<()> {
-    let motif = iupac_to_regex(&motif_raw)?;
-    let re = OverlappingRegex::new(&motif)
-        .context("failed to make forward regex pattern")?;
-    let rc_motif = motif_rev_comp(&motif);
-    let rc_re = OverlappingRegex::new(&rc_motif)
-        .context("failed to make reverse complement regex pattern")?;
-    let rc_offset = motif_raw
-        .len()
-        .checked_sub(offset + 1)
-        .ok_or(anyhow!("invalid offset for motif"))?;
-
-    let motif_info = MotifInfo::new(
+    let regex_motif = RegexMotif::parse_string_with_ambiguous_bases(
+        motif_raw,
         offset,
-        rc_offset,
-        motif_raw.len(),
-        re.as_str() == rc_re.as_str(),
-    );
-    let regex_motif =
-        RegexMotif::new(re, rc_re, motif_info, motif_raw.to_owned());
+        ambiguous_bases,
+    )?;
 
     let reader =
         FastaReader::from_file(path).context("failed to open FASTA")?;
@@ -416,7 +595,7 @@ pub fn motif_bed(
             }
         })
         .for_each(|(seq, record)| {
-            let seq = if mask { seq } else { seq.to_ascii_uppercase() };
+            let seq = normalize_reference_seq(seq, mask);
             let n_hits = process_record(record.id(), &seq, &regex_motif);
             motifs_progress.inc(n_hits as u64);
         });
@@ -527,7 +706,7 @@ pub fn get_masked_sequences(
         })
         .filter_map(|(record, tid)| {
             String::from_utf8(record.seq().to_vec())
-                .map(|s| if mask { s } else { s.to_ascii_uppercase() })
+                .map(|s| normalize_reference_seq(s, mask))
                 .ok()
                 .map(|s| (s, tid))
         })
@@ -709,7 +888,9 @@ impl MotifPositionLookup {
 
 #[cfg(test)]
 mod motif_bed_tests {
-    use crate::motifs::motif_bed::{find_motif_hits, RegexMotif};
+    use crate::motifs::motif_bed::{
+        find_motif_hits, AmbiguousBases, RegexMotif,
+    };
     use crate::util::Strand;
 
     #[test]
@@ -798,4 +979,106 @@ mod motif_bed_tests {
         let gatc = RegexMotif::parse_string("GATC", 1).unwrap();
         assert!(gatc.is_palendrome());
     }
+
+    #[test]
+    fn test_motif_uracil_as_thymine() {
+        let seq = "AAGATCAAGATCTT";
+        let dna = RegexMotif::parse_string("GATC", 1).unwrap();
+        let rna = RegexMotif::parse_string("GAUC", 1).unwrap();
+        assert_eq!(find_motif_hits(seq, &dna), find_motif_hits(seq, &rna));
+        let single = RegexMotif::parse_string("U", 0).unwrap();
+        assert_eq!(single.length(), 1);
+        let hits = find_motif_hits("ATTA", &single);
+        assert!(hits.contains(&(1, Strand::Positive)));
+        assert!(hits.contains(&(0, Strand::Negative)));
+        assert_eq!(format!("{rna}"), "GAUC,1");
+        assert_eq!(format!("{dna}"), "GATC,1");
+        assert_eq!(format!("{}", dna.with_rna_label(true)), "GAUC,1");
+        let rna = rna.with_ambiguous_bases(AmbiguousBases::match_any).unwrap();
+        assert_eq!(rna.motif_label(), "GAUC");
+    }
+
+    #[test]
+    fn test_motif_positive_strand_only() {
+        let seq = "GGACTAAAGTCCA";
+        let drach = RegexMotif::parse_string("DRACH", 2).unwrap();
+        assert_eq!(
+            find_motif_hits(seq, &drach),
+            vec![(2, Strand::Positive), (9, Strand::Negative)]
+        );
+        assert!(drach.matches_at(seq, 9, Strand::Negative));
+        let drach = drach.with_positive_strand_only();
+        assert_eq!(find_motif_hits(seq, &drach), vec![(2, Strand::Positive)]);
+        assert!(drach.matches_at(seq, 2, Strand::Positive));
+        assert!(!drach.matches_at(seq, 9, Strand::Negative));
+    }
+
+    #[test]
+    fn test_motif_ambiguous_reference_bases() {
+        let seq = "AACNAACRAAYGAA";
+        let skip = RegexMotif::parse_string("CG", 0).unwrap();
+        assert!(find_motif_hits(seq, &skip).is_empty());
+        assert_eq!(skip.forward_pattern.as_str(), "CG");
+
+        let match_any = skip
+            .clone()
+            .with_ambiguous_bases(AmbiguousBases::match_any)
+            .unwrap();
+        assert!(match_any.is_palendrome());
+        let hits = find_motif_hits(seq, &match_any);
+        // CN, CR, and YG all match
+        assert_eq!(hits.len(), 6);
+
+        let expand = skip.with_ambiguous_bases(AmbiguousBases::expand).unwrap();
+        assert!(expand.is_palendrome());
+        let hits = find_motif_hits(seq, &expand);
+        // CN, CR (R includes G), and YG (Y includes C) match
+        assert_eq!(hits.len(), 6);
+        let hits = find_motif_hits("AACWAA", &expand);
+        assert!(hits.is_empty());
+        let hits = find_motif_hits("AACWAA", &match_any);
+        assert_eq!(hits.len(), 2);
+
+        let single = RegexMotif::parse_string_with_ambiguous_bases(
+            "A",
+            0,
+            AmbiguousBases::expand,
+        )
+        .unwrap();
+        let hits = find_motif_hits("CRCY", &single);
+        assert_eq!(hits, vec![(1, Strand::Positive), (3, Strand::Negative)]);
+    }
+
+    #[test]
+    fn test_ambiguous_bases_ref_kmer() {
+        let kmer = "ACGNR".to_string();
+        assert_eq!(AmbiguousBases::skip.ref_kmer(kmer.clone()), kmer);
+        assert_eq!(
+            AmbiguousBases::match_any.ref_kmer(kmer.clone()),
+            "ACGNN".to_string()
+        );
+        assert_eq!(AmbiguousBases::expand.ref_kmer(kmer.clone()), kmer);
+        let kmer = "--ACG".to_string();
+        assert_eq!(AmbiguousBases::match_any.ref_kmer(kmer.clone()), kmer);
+    }
+
+    #[test]
+    fn test_motif_matches_at() {
+        let seq = "ACGTTCAGGATCAAA";
+        let cg = RegexMotif::parse_string("CG", 0).unwrap();
+        assert!(cg.matches_at(seq, 1, Strand::Positive));
+        assert!(cg.matches_at(seq, 2, Strand::Negative));
+        assert!(!cg.matches_at(seq, 2, Strand::Positive));
+        assert!(!cg.matches_at(seq, 0, Strand::Negative));
+        let chg = RegexMotif::parse_string("CHG", 0).unwrap();
+        assert!(chg.matches_at(seq, 5, Strand::Positive));
+        assert!(!chg.matches_at(seq, 1, Strand::Positive));
+        let gatc = RegexMotif::parse_string("GATC", 1).unwrap();
+        assert!(gatc.matches_at(seq, 9, Strand::Positive));
+        assert!(gatc.matches_at(seq, 10, Strand::Negative));
+        // motif would extend past the end of the sequence
+        let chh = RegexMotif::parse_string("CHH", 0).unwrap();
+        assert!(!chh.matches_at("AAC", 2, Strand::Positive));
+        assert!(chh.matches_at("CAA", 0, Strand::Positive));
+    }
 }
@@ -13,7 +13,7 @@ use rustc_hash::FxHashMap;
 
 use crate::logging::{init_logging, init_tracing};
 use crate::mod_base_code::{DnaBase, ModCodeRepr};
-use crate::motifs::motif_bed::motif_bed;
+use crate::motifs::motif_bed::{motif_bed, AmbiguousBases};
 use crate::motifs::{
     find_motifs_for_mod, load_bedmethyl_and_references, make_tables,
     merge_motifs, parse_known_motifs, parse_motifs_from_table,
@@ -1049,11 +1049,23 @@ pub struct EntryMotifBed {
     /// Respect soft masking in the reference FASTA.
     #[arg(long, short = 'k', default_value_t = false)]
     mask: bool,
+    /// How to handle ambiguous (non-ACGT) bases in the reference. skip:
+    /// ambiguous bases never match the motif. match-any: ambiguous bases
+    /// match any base in the motif. expand: IUPAC codes match the motif
+    /// when the code includes the motif base, e.g. R matches A or G.
+    #[arg(long, default_value_t = AmbiguousBases::skip)]
+    ambiguous_bases: AmbiguousBases,
 }
 
 impl EntryMotifBed {
     fn run(&self) -> anyhow::Result<()> {
         let _handle = init_logging(None);
-        motif_bed(&self.fasta, &self.motif, self.offset, self.mask)
+        motif_bed(
+            &self.fasta,
+            &self.motif,
+            self.offset,
+            self.mask,
+            self.ambiguous_bases,
+        )
     }
 }
@@ -0,0 +1,322 @@
+use std::fs::File;
+use std::io::Write;
+use std::path::Path;
+use std::sync::Arc;
+
+use anyhow::{bail, Context};
+use arrow_array::builder::{Float64Builder, Int64Builder, StringBuilder};
+use arrow_array::{ArrayRef, RecordBatch};
+use arrow_schema::{DataType, Field as ArrowField, Schema, SchemaRef};
+use parquet::arrow::ArrowWriter;
+use parquet::basic::Compression;
+use parquet::file::properties::WriterProperties;
+
+use crate::sqlite::ColumnType;
+use crate::util::{MISSING_SYMBOL, TAB};
+use crate::writers::FinishWrite;
+
+/// Number of rows to buffer before writing a record batch.
+const ROWS_PER_BATCH: usize = 65_536;
+
+enum Field<'a> {
+    Null,
+    Integer(i64),
+    Real(f64),
+    Text(&'a str),
+}
+
+enum ColumnBuilder {
+    Integer(Int64Builder),
+    Real(Float64Builder),
+    Text(StringBuilder),
+}
+
+impl ColumnBuilder {
+    fn new(typ: ColumnType) -> Self {
+        match typ {
+            ColumnType::Integer => Self::Integer(Int64Builder::new()),
+            ColumnType::Real => Self::Real(Float64Builder::new()),
+            ColumnType::Text => Self::Text(StringBuilder::new()),
+        }
+    }
+
+    fn data_type(typ: ColumnType) -> DataType {
+        match typ {
+            ColumnType::Integer => DataType::Int64,
+            ColumnType::Real => DataType::Float64,
+            ColumnType::Text => DataType::Utf8,
+        }
+    }
+
+    /// Parse a field from a tab-separated row, missing values (".") become
+    /// nulls and booleans are stored as 0/1 (the same as the SQLite output).
+    fn parse<'a>(&self, raw: &'a str) -> anyhow::Result<Field<'a>> {
+        if raw == MISSING_SYMBOL {
+            return Ok(Field::Null);
+        }
+        let field = match self {
+            Self::Integer(_) => match raw {
+                "true" => Field::Integer(1),
+                "false" => Field::Integer(0),
+                _ => Field::Integer(
+                    raw.parse::<i64>()
+                        .with_context(|| format!("invalid integer {raw}"))?,
+                ),
+            },
+            Self::Real(_) => Field::Real(
+                raw.parse::<f64>()
+                    .with_context(|| format!("invalid number {raw}"))?,
+            ),
+            Self::Text(_) => Field::Text(raw),
+        };
+        Ok(field)
+    }
+
+    fn append(&mut self, field: Field) {
+        match (self, field) {
+            (Self::Integer(builder), Field::Integer(x)) => {
+                builder.append_value(x)
+            }
+            (Self::Real(builder), Field::Real(x)) => builder.append_value(x),
+            (Self::Text(builder), Field::Text(x)) => builder.append_value(x),
+            (Self::Integer(builder), _) => builder.append_null(),
+            (Self::Real(builder), _) => builder.append_null(),
+            (Self::Text(builder), _) => builder.append_null(),
+        }
+    }
+
+    fn finish(&mut self) -> ArrayRef {
+        match self {
+            Self::Integer(builder) => Arc::new(builder.finish()),
+            Self::Real(builder) => Arc::new(builder.finish()),
+            Self::Text(builder) => Arc::new(builder.finish()),
+        }
+    }
+}
+
+/// Writes tab-separated rows into a Parquet file with typed columns, the
+/// counterpart of [crate::sqlite::SqliteTableWriter]. Rows are buffered into
+/// record batches of [ROWS_PER_BATCH] and the file footer is written by
+/// [FinishWrite::finish_write], without it the file can't be read.
+pub(crate) struct ParquetTableWriter {
+    writer: Option<ArrowWriter<File>>,
+    schema: SchemaRef,
+    builders: Vec<ColumnBuilder>,
+    table: String,
+    buffer: Vec<u8>,
+    rows_in_batch: usize,
+}
+
+impl ParquetTableWriter {
+    pub(crate) fn new(
+        path: &Path,
+        force: bool,
+        table: &str,
+        columns: &[(String, ColumnType)],
+    ) -> anyhow::Result<Self> {
+        let fh =
+            if force { File::create(path) } else { File::create_new(path) }
+                .with_context(|| {
+                    format!("failed to create {path:?}, refusing to overwrite")
+                })?;
+        let schema = Arc::new(Schema::new(
+            columns
+                .iter()
+                .map(|(name, typ)| {
+                    ArrowField::new(name, ColumnBuilder::data_type(*typ), true)
+                })
+                .collect::<Vec<ArrowField>>(),
+        ));
+        let props = WriterProperties::builder()
+            .set_compression(Compression::SNAPPY)
+            .build();
+        let writer = ArrowWriter::try_new(fh, schema.clone(), Some(props))
+            .with_context(|| {
+                format!("failed to make parquet writer for {table}")
+            })?;
+        let builders =
+            columns.iter().map(|(_, typ)| ColumnBuilder::new(*typ)).collect();
+
+        Ok(Self {
+            writer: Some(writer),
+            schema,
+            builders,
+            table: table.to_string(),
+            buffer: Vec::new(),
+            rows_in_batch: 0,
+        })
+    }
+
+    fn insert_row(&mut self, line: &str) -> anyhow::Result<()> {
+        let fields = line.split(TAB).collect::<Vec<&str>>();
+        if fields.len() != self.builders.len() {
+            bail!(
+                "expected {} fields for {}, got {}",
+                self.builders.len(),
+                self.table,
+                fields.len()
+            )
+        }
+        // parse the whole row first so that a bad field doesn't leave the
+        // columns with different lengths
+        let parsed = fields
+            .into_iter()
+            .zip(self.builders.iter())
+            .zip(self.schema.fields().iter())
+            .map(|((raw, builder), field)| {
+                builder
+                    .parse(raw)
+                    .with_context(|| format!("in column {}", field.name()))
+            })
+            .collect::<anyhow::Result<Vec<Field>>>()?;
+        for (field, builder) in parsed.into_iter().zip(self.builders.iter_mut())
+        {
+            builder.append(field);
+        }
+        self.rows_in_batch += 1;
+        if self.rows_in_batch >= ROWS_PER_BATCH {
+            self.write_batch()?;
+        }
+        Ok(())
+    }
+
+    fn write_batch(&mut self) -> anyhow::Result<()> {
+        if self.rows_in_batch == 0 {
+            return Ok(());
+        }
+        let arrays = self
+            .builders
+            .iter_mut()
+            .map(|builder| builder.finish())
+            .collect::<Vec<ArrayRef>>();
+        let batch = RecordBatch::try_new(self.schema.clone(), arrays)?;
+        if let Some(writer) = self.writer.as_mut() {
+            writer.write(&batch)?;
+        }
+        self.rows_in_batch = 0;
+        Ok(())
+    }
+
+    /// Write any remaining rows and the file footer, must be called after the
+    /// last row has been written.
+    fn finish(&mut self) -> anyhow::Result<()> {
+        if !self.buffer.is_empty() {
+            let remaining = std::mem::take(&mut self.buffer);
+            self.insert_row(std::str::from_utf8(&remaining)?)?;
+        }
+        self.write_batch()?;
+        if let Some(writer) = self.writer.take() {
+            writer.close()?;
+        }
+        Ok(())
+    }
+}
+
+impl Write for ParquetTableWriter {
+    /// Inserts each complete line, a line that fails to insert is skipped
+    /// (and the first error returned) without losing the rows after it.
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.buffer.extend_from_slice(buf);
+        let buffer = std::mem::take(&mut self.buffer);
+        let mut start = 0usize;
+        let mut result = Ok(buf.len());
+        while let Some(offset) = memchr::memchr(b'\n', &buffer[start..]) {
+            let inserted = std::str::from_utf8(&buffer[start..start + offset])
+                .map_err(|e| {
+                    std::io::Error::new(std::io::ErrorKind::InvalidData, e)
+                })
+                .and_then(|line| {
+                    self.insert_row(line).map_err(|e| {
+                        std::io::Error::new(
+                            std::io::ErrorKind::Other,
+                            format!("failed to insert row, {e:#}"),
+                        )
+                    })
+                });
+            if let (Err(e), Ok(_)) = (inserted, result.as_ref()) {
+                result = Err(e);
+            }
+            start += offset + 1;
+        }
+        self.buffer = buffer[start..].to_vec();
+        result
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl FinishWrite for ParquetTableWriter {
+    fn finish_write(&mut self) -> anyhow::Result<()> {
+        if self.writer.is_none() {
+            return Ok(());
+        }
+        self.finish().with_context(|| {
+            format!("failed to finish writing table {}", self.table)
+        })
+    }
+}
+
+#[cfg(test)]
+mod parquet_table_tests {
+    use std::fs::File;
+    use std::io::Write;
+
+    use arrow_array::{Array, Float64Array, Int64Array, StringArray};
+    use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+
+    use crate::parquet_table::ParquetTableWriter;
+    use crate::sqlite::ColumnType;
+    use crate::writers::FinishWrite;
+
+    #[test]
+    fn test_parquet_table_writer() {
+        let dir = tempfile::tempdir().unwrap();
+        let fp = dir.path().join("test.parquet");
+        let columns = vec![
+            ("chrom".to_string(), ColumnType::Text),
+            ("start".to_string(), ColumnType::Integer),
+            ("score".to_string(), ColumnType::Real),
+            ("pass".to_string(), ColumnType::Integer),
+        ];
+        {
+            let mut writer =
+                ParquetTableWriter::new(&fp, false, "test", &columns).unwrap();
+            // rows can be split over multiple writes
+            writer.write_all(b"chr1\t10\t0.5\ttrue\nchr1\t2").unwrap();
+            writer.write_all(b"0\t.\tfalse\n").unwrap();
+            assert!(writer.write_all(b"chr1\t30\n").is_err());
+            assert!(writer.write_all(b"chr1\tx\t0.1\t1\n").is_err());
+            // rows after a bad row in the same write are kept
+            assert!(writer
+                .write_all(b"chr1\t40\nchr1\t50\t.\tfalse\n")
+                .is_err());
+            writer.finish_write().unwrap();
+        }
+        assert!(ParquetTableWriter::new(&fp, false, "test", &columns).is_err());
+
+        let reader =
+            ParquetRecordBatchReaderBuilder::try_new(File::open(&fp).unwrap())
+                .unwrap()
+                .build()
+                .unwrap();
+        let batches = reader.collect::<Result<Vec<_>, _>>().unwrap();
+        assert_eq!(batches.len(), 1);
+        let batch = &batches[0];
+        assert_eq!(batch.num_rows(), 3);
+        let chroms =
+            batch.column(0).as_any().downcast_ref::<StringArray>().unwrap();
+        assert_eq!(chroms.value(0), "chr1");
+        let starts =
+            batch.column(1).as_any().downcast_ref::<Int64Array>().unwrap();
+        assert_eq!(starts.values().to_vec(), vec![10, 20, 50]);
+        let scores =
+            batch.column(2).as_any().downcast_ref::<Float64Array>().unwrap();
+        assert_eq!(scores.value(0), 0.5);
+        assert!(scores.is_null(1));
+        let pass =
+            batch.column(3).as_any().downcast_ref::<Int64Array>().unwrap();
+        assert_eq!(pass.values().to_vec(), vec![1, 0, 0]);
+    }
+}
@@ -1,3 +1,7 @@
+use std::io::BufReader;
+use std::path::Path;
+
+use anyhow::Context;
 use nom::bytes::complete::tag;
 use nom::character::complete::{
     alphanumeric1, anychar, multispace1, none_of, u64 as nomu64,
@@ -6,6 +10,18 @@ use nom::multi::{fold_many1, separated_list0};
 use nom::number::complete::float;
 use nom::IResult;
 
+/// Open a text input (BED, regions, sizes, etc.) to be read line by line.
+/// Gzip and bgzip compressed files are decompressed transparently, the
+/// compression is detected from the contents so the extension doesn't matter.
+pub(crate) fn open_text_input<P: AsRef<Path>>(
+    fp: P,
+) -> anyhow::Result<BufReader<rust_htslib::bgzf::Reader>> {
+    let fp = fp.as_ref();
+    let reader = rust_htslib::bgzf::Reader::from_path(fp)
+        .with_context(|| format!("failed to open {fp:?}"))?;
+    Ok(BufReader::new(reader))
+}
+
 pub(crate) fn consume_digit(l: &str) -> IResult<&str, u64> {
     multispace1(l).and_then(|(r, _)| nomu64(r))
 }
@@ -51,3 +67,60 @@ pub(crate) fn consume_string(l: &str) -> IResult<&str, String> {
 pub(crate) fn consume_char(l: &str) -> IResult<&str, char> {
     multispace1(l).and_then(|(r, _)| anychar(r))
 }
+
+#[cfg(test)]
+mod parsing_utils_tests {
+    use std::io::{BufRead, Write};
+
+    use crate::parsing_utils::open_text_input;
+
+    #[test]
+    fn test_open_text_input_compressed() {
+        let expected =
+            vec!["chr1\t0\t10".to_string(), "chr2\t5\t6".to_string()];
+        let read_lines = |name: &str, bytes: &[u8]| -> Vec<String> {
+            let fp = std::env::temp_dir().join(name);
+            std::fs::write(&fp, bytes).unwrap();
+            open_text_input(&fp).unwrap().lines().map(|l| l.unwrap()).collect()
+        };
+
+        let plain = read_lines(
+            "test_open_text_input.bed",
+            expected
+                .iter()
+                .map(|l| format!("{l}\n"))
+                .collect::<String>()
+                .as_bytes(),
+        );
+        assert_eq!(plain, expected);
+
+        // `gzip` output (not block-compressed)
+        let gzip = [
+            0x1f, 0x8b, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02, 0x03, 0x4b,
+            0xce, 0x28, 0x32, 0xe4, 0x34, 0xe0, 0x34, 0x34, 0xe0, 0x4a, 0xce,
+            0x28, 0x32, 0xe2, 0x34, 0xe5, 0x34, 0xe3, 0x02, 0x00, 0x20, 0x89,
+            0x1d, 0x1f, 0x13, 0x00, 0x00, 0x00,
+        ];
+        // the extension shouldn't matter
+        assert_eq!(
+            read_lines("test_open_text_input.gzip.bed", &gzip),
+            expected
+        );
+
+        let bgzip_fp = std::env::temp_dir().join("test_open_text_input.bed.gz");
+        let mut writer =
+            rust_htslib::bgzf::Writer::from_path(&bgzip_fp).unwrap();
+        for line in expected.iter() {
+            writeln!(writer, "{line}").unwrap();
+        }
+        drop(writer);
+        let bgzip = open_text_input(&bgzip_fp)
+            .unwrap()
+            .lines()
+            .map(|l| l.unwrap())
+            .collect::<Vec<String>>();
+        assert_eq!(bgzip, expected);
+
+        assert!(open_text_input("tests/resources/does_not_exist.bed").is_err());
+    }
+}
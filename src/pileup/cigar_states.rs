@@ -0,0 +1,131 @@
+use std::io::Write;
+
+use rust_htslib::bam::record::Cigar;
+use rust_htslib::bam::{self, FetchDefinition, Read};
+use rustc_hash::FxHashMap;
+
+use crate::util::{record_is_not_primary, TAB};
+
+pub(super) const CIGAR_STATES_COLUMNS: [&str; 8] = [
+    "chrom",
+    "start",
+    "end",
+    "n_match_call",
+    "n_match_nocall",
+    "n_delete",
+    "n_refskip",
+    "n_softclip",
+];
+
+/// Number of reads in each alignment (CIGAR) state at a reference position,
+/// used to explain the coverage counts in the bedMethyl. Only primary
+/// alignments are counted, the same as in the pileup.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub(crate) struct CigarStateCounts {
+    /// Aligned base with a base modification call, counted in the valid
+    /// coverage or `N_fail`.
+    pub(crate) n_match_call: u32,
+    /// Aligned base without a base modification call, counted in `N_nocall`
+    /// or `N_diff`.
+    pub(crate) n_match_nocall: u32,
+    /// Deletion in the read, counted in `N_delete`.
+    pub(crate) n_delete: u32,
+    /// Reference skip (N CIGAR operation), not counted in the bedMethyl.
+    pub(crate) n_refskip: u32,
+    /// Soft-clipped read bases that would cover this position if they were
+    /// aligned, not counted in the bedMethyl.
+    pub(crate) n_softclip: u32,
+}
+
+impl CigarStateCounts {
+    pub(super) fn to_row(self, chrom: &str, pos: u32) -> String {
+        [
+            chrom.to_string(),
+            pos.to_string(),
+            (pos + 1).to_string(),
+            self.n_match_call.to_string(),
+            self.n_match_nocall.to_string(),
+            self.n_delete.to_string(),
+            self.n_refskip.to_string(),
+            self.n_softclip.to_string(),
+        ]
+        .join(&TAB.to_string())
+    }
+}
+
+/// Add the soft-clipped bases of the primary records overlapping the interval
+/// to the positions in `states`. Positions that are not already in `states`
+/// (i.e. not covered by any aligned read) are not added.
+pub(super) fn add_softclip_counts(
+    bam_reader: &mut bam::IndexedReader,
+    chrom_tid: u32,
+    start_pos: u32,
+    end_pos: u32,
+    states: &mut FxHashMap<u32, CigarStateCounts>,
+) -> Result<(), String> {
+    bam_reader
+        .fetch(FetchDefinition::Region(
+            chrom_tid as i32,
+            start_pos as i64,
+            end_pos as i64,
+        ))
+        .map_err(|e| e.to_string())?;
+    let mut add_clipped = |clipped_start: i64, clipped_end: i64| {
+        let clipped_start = std::cmp::max(clipped_start, 0) as u32;
+        let clipped_end = std::cmp::max(clipped_end, 0) as u32;
+        for pos in clipped_start..clipped_end {
+            if let Some(state) = states.get_mut(&pos) {
+                state.n_softclip += 1;
+            }
+        }
+    };
+    for record in bam_reader.records().filter_map(|r| r.ok()) {
+        if record.is_unmapped()
+            || record_is_not_primary(&record)
+            || record.seq_len() == 0
+        {
+            continue;
+        }
+        let cigar = record.cigar();
+        let leading_clip = cigar
+            .iter()
+            .take_while(|op| {
+                matches!(op, Cigar::SoftClip(_) | Cigar::HardClip(_))
+            })
+            .filter_map(|op| match op {
+                Cigar::SoftClip(l) => Some(*l as i64),
+                _ => None,
+            })
+            .sum::<i64>();
+        let trailing_clip = cigar
+            .iter()
+            .rev()
+            .take_while(|op| {
+                matches!(op, Cigar::SoftClip(_) | Cigar::HardClip(_))
+            })
+            .filter_map(|op| match op {
+                Cigar::SoftClip(l) => Some(*l as i64),
+                _ => None,
+            })
+            .sum::<i64>();
+        let (reference_start, reference_end) = (cigar.pos(), cigar.end_pos());
+        add_clipped(reference_start - leading_clip, reference_start);
+        add_clipped(reference_end, reference_end + trailing_clip);
+    }
+
+    Ok(())
+}
+
+/// Write the counts of each position, sorted by position.
+pub(super) fn write_cigar_states<W: Write>(
+    writer: &mut W,
+    chrom: &str,
+    states: &FxHashMap<u32, CigarStateCounts>,
+) -> std::io::Result<u64> {
+    let mut positions = states.keys().copied().collect::<Vec<u32>>();
+    positions.sort();
+    for pos in positions.iter() {
+        writeln!(writer, "{}", states[pos].to_row(chrom, *pos))?;
+    }
+    Ok(positions.len() as u64)
+}
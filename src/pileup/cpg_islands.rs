@@ -0,0 +1,423 @@
+use std::fs::File;
+use std::io::{BufRead, BufWriter, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context};
+use bio::io::fasta::Reader as FastaReader;
+use itertools::Itertools;
+use log::{debug, info};
+use rust_lapper::{Interval, Lapper};
+use rustc_hash::{FxHashMap, FxHashSet};
+
+use crate::mod_base_code::ModCodeRepr;
+use crate::parsing_utils::open_text_input;
+use crate::pileup::{ModBasePileup, PartitionKey};
+use crate::util::{create_out_directory, GenomeRegion};
+use crate::writers::BedScore;
+
+/// Gardiner-Garden and Frommer (1987) criteria, islands are at least 200 bp
+/// with a G+C content over 50% and an observed/expected CpG ratio over 0.6.
+const MIN_ISLAND_LENGTH: usize = 200;
+const MIN_GC_CONTENT: f32 = 0.5;
+const MIN_OBS_EXP_CPG: f32 = 0.6;
+
+#[derive(Default, Debug, Copy, Clone, PartialEq, Eq)]
+struct Composition {
+    n_c: usize,
+    n_g: usize,
+    n_cpg: usize,
+    length: usize,
+}
+
+fn is_cpg(seq: &[u8], i: usize) -> bool {
+    seq[i].eq_ignore_ascii_case(&b'C')
+        && seq.get(i + 1).is_some_and(|b| b.eq_ignore_ascii_case(&b'G'))
+}
+
+impl Composition {
+    /// Composition of a sequence, only CpGs with both bases in the sequence
+    /// are counted.
+    fn of(seq: &[u8]) -> Self {
+        let mut composition =
+            Composition { length: seq.len(), ..Default::default() };
+        for (i, base) in seq.iter().enumerate() {
+            composition.add_base(*base, 1);
+            composition.n_cpg += is_cpg(seq, i) as usize;
+        }
+        composition
+    }
+
+    /// Move the window this is the composition of one base to the right, so
+    /// that it starts at `start`.
+    fn slide(&mut self, seq: &[u8], start: usize) {
+        let (removed, added) = (start - 1, start + self.length - 1);
+        self.add_base(seq[removed], -1);
+        self.n_cpg -= is_cpg(seq, removed) as usize;
+        self.add_base(seq[added], 1);
+        self.n_cpg += is_cpg(seq, added - 1) as usize;
+    }
+
+    fn add_base(&mut self, base: u8, delta: isize) {
+        match base.to_ascii_uppercase() {
+            b'C' => self.n_c = self.n_c.wrapping_add_signed(delta),
+            b'G' => self.n_g = self.n_g.wrapping_add_signed(delta),
+            _ => {}
+        }
+    }
+
+    fn gc_content(&self) -> f32 {
+        (self.n_c + self.n_g) as f32 / self.length as f32
+    }
+
+    fn obs_exp_cpg(&self) -> f32 {
+        if self.n_c == 0 || self.n_g == 0 {
+            0f32
+        } else {
+            (self.n_cpg * self.length) as f32 / (self.n_c * self.n_g) as f32
+        }
+    }
+
+    fn is_island(&self) -> bool {
+        self.length >= MIN_ISLAND_LENGTH
+            && self.gc_content() > MIN_GC_CONTENT
+            && self.obs_exp_cpg() > MIN_OBS_EXP_CPG
+    }
+}
+
+/// Find CpG islands in a sequence by sliding a 200 bp window along the
+/// sequence, merging the windows that meet the criteria, then keeping the
+/// merged regions that meet the criteria as a whole.
+pub(crate) fn find_cpg_islands(seq: &[u8]) -> Vec<Range<usize>> {
+    if seq.len() < MIN_ISLAND_LENGTH {
+        return Vec::new();
+    }
+    // the window's composition is updated as it slides, so memory doesn't
+    // grow with the length of the contig
+    let mut window = Composition::of(&seq[..MIN_ISLAND_LENGTH]);
+    let mut merged = Vec::<Range<usize>>::new();
+    for start in 0..=(seq.len() - MIN_ISLAND_LENGTH) {
+        let end = start + MIN_ISLAND_LENGTH;
+        if start > 0 {
+            window.slide(seq, start);
+        }
+        if !window.is_island() {
+            continue;
+        }
+        match merged.last_mut() {
+            Some(prev) if prev.end >= start => prev.end = end,
+            _ => merged.push(start..end),
+        }
+    }
+    merged
+        .into_iter()
+        .filter(|region| Composition::of(&seq[region.clone()]).is_island())
+        .collect()
+}
+
+pub(crate) fn count_cpgs(seq: &[u8]) -> usize {
+    seq.windows(2)
+        .filter(|w| {
+            w[0].eq_ignore_ascii_case(&b'C') && w[1].eq_ignore_ascii_case(&b'G')
+        })
+        .count()
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct CpgIsland {
+    chrom: String,
+    start: u64,
+    end: u64,
+    name: String,
+    n_cpgs: usize,
+}
+
+fn parse_islands_bed(
+    islands_bed: &Path,
+) -> anyhow::Result<FxHashMap<String, Vec<GenomeRegion>>> {
+    let reader = open_text_input(islands_bed).with_context(|| {
+        format!("failed to open CpG islands BED {islands_bed:?}")
+    })?;
+    let mut regions = FxHashMap::<String, Vec<GenomeRegion>>::default();
+    for line in reader.lines() {
+        let line = line?;
+        if line.starts_with('#') || line.trim().is_empty() {
+            continue;
+        }
+        let region = GenomeRegion::parse_unstranded_bed_line(&line)?;
+        regions
+            .entry(region.chrom.clone())
+            .or_insert_with(Vec::new)
+            .push(region);
+    }
+    if regions.is_empty() {
+        bail!("zero CpG islands in {islands_bed:?}")
+    }
+    Ok(regions)
+}
+
+/// Aggregated counts for a single modification code over an island.
+#[derive(Default, Debug, Copy, Clone, PartialEq)]
+struct IslandCounts {
+    n_sites: u32,
+    valid_coverage: u64,
+    n_modified: u64,
+    n_canonical: u64,
+    n_other_modified: u64,
+    sum_fraction_modified: f64,
+}
+
+/// Sums the pileup counts at CpG sites within CpG islands so that island-level
+/// methylation can be reported in the same pass as the per-site bedMethyl.
+pub(super) struct CpgIslandAggregator {
+    islands: Vec<CpgIsland>,
+    lookup: FxHashMap<String, Lapper<u64, usize>>,
+    counts: Vec<FxHashMap<ModCodeRepr, IslandCounts>>,
+}
+
+impl CpgIslandAggregator {
+    /// Load the CpG islands for the `contigs`, either detected from the
+    /// reference sequences or read from `islands_bed`.
+    pub(super) fn new(
+        reference_fastas: &[PathBuf],
+        islands_bed: Option<&Path>,
+        contigs: &FxHashSet<String>,
+    ) -> anyhow::Result<Self> {
+        let mut user_regions =
+            islands_bed.map(parse_islands_bed).transpose()?;
+        let mut records = Vec::new();
+        for reference_fasta in reference_fastas {
+            let reader =
+                FastaReader::from_file(reference_fasta).with_context(|| {
+                    format!("failed to open reference at {reference_fasta:?}")
+                })?;
+            records.push(reader.records());
+        }
+        let mut islands = Vec::new();
+        for record in records.into_iter().flatten() {
+            let record = record.context("failed to parse FASTA record")?;
+            let chrom = record.id();
+            if !contigs.contains(chrom) {
+                continue;
+            }
+            let seq = record.seq();
+            let mut contig_islands = match user_regions.as_mut() {
+                Some(regions) => regions
+                    .remove(chrom)
+                    .unwrap_or_default()
+                    .into_iter()
+                    .map(|region| {
+                        let end = std::cmp::min(region.end as usize, seq.len());
+                        let start = std::cmp::min(region.start as usize, end);
+                        let n_cpgs = count_cpgs(&seq[start..end]);
+                        let name = region
+                            .name
+                            .unwrap_or_else(|| format!("CpG:{n_cpgs}"));
+                        CpgIsland {
+                            chrom: chrom.to_string(),
+                            start: start as u64,
+                            end: end as u64,
+                            name,
+                            n_cpgs,
+                        }
+                    })
+                    .collect::<Vec<CpgIsland>>(),
+                None => find_cpg_islands(seq)
+                    .into_iter()
+                    .map(|range| {
+                        let n_cpgs = count_cpgs(&seq[range.clone()]);
+                        CpgIsland {
+                            chrom: chrom.to_string(),
+                            start: range.start as u64,
+                            end: range.end as u64,
+                            name: format!("CpG:{n_cpgs}"),
+                            n_cpgs,
+                        }
+                    })
+                    .collect::<Vec<CpgIsland>>(),
+            };
+            debug!("{} CpG island(s) on {chrom}", contig_islands.len());
+            contig_islands.sort_by_key(|island| (island.start, island.end));
+            islands.extend(contig_islands);
+        }
+        if let Some(regions) = user_regions {
+            for chrom in regions.keys().filter(|c| contigs.contains(*c)) {
+                debug!("CpG island sequence {chrom} not found in reference");
+            }
+        }
+        if islands.is_empty() {
+            bail!("zero CpG islands to aggregate over")
+        }
+        info!("aggregating methylation over {} CpG island(s)", islands.len());
+
+        let mut intervals =
+            FxHashMap::<String, Vec<Interval<u64, usize>>>::default();
+        for (idx, island) in islands.iter().enumerate() {
+            intervals
+                .entry(island.chrom.clone())
+                .or_insert_with(Vec::new)
+                .push(Interval {
+                    start: island.start,
+                    stop: island.end,
+                    val: idx,
+                });
+        }
+        let lookup = intervals
+            .into_iter()
+            .map(|(chrom, ivs)| (chrom, Lapper::new(ivs)))
+            .collect();
+
+        let counts = vec![FxHashMap::default(); islands.len()];
+        Ok(Self { islands, lookup, counts })
+    }
+
+    pub(super) fn add(&mut self, pileup: &ModBasePileup) {
+        let Some(lapper) = self.lookup.get(&pileup.chrom_name) else {
+            return;
+        };
+        for (pos, feature_counts) in pileup.iter_counts_sorted() {
+            let Some(feature_counts) = feature_counts.get(&PartitionKey::NoKey)
+            else {
+                continue;
+            };
+            let pos = *pos as u64;
+            for iv in lapper.find(pos, pos + 1) {
+                for fc in feature_counts.iter() {
+                    let counts =
+                        self.counts[iv.val].entry(fc.raw_mod_code).or_default();
+                    counts.n_sites += 1;
+                    counts.valid_coverage += fc.filtered_coverage as u64;
+                    counts.n_modified += fc.n_modified as u64;
+                    counts.n_canonical += fc.n_canonical as u64;
+                    counts.n_other_modified += fc.n_other_modified as u64;
+                    counts.sum_fraction_modified += fc.fraction_modified as f64;
+                }
+            }
+        }
+    }
+
+    fn header() -> String {
+        [
+            "chrom",
+            "start",
+            "end",
+            "name",
+            "score",
+            "strand",
+            "mod_code",
+            "n_cpgs",
+            "n_sites",
+            "valid_coverage",
+            "n_mod",
+            "n_canonical",
+            "n_other_mod",
+            "percent_modified",
+            "mean_site_percent_modified",
+        ]
+        .join("\t")
+    }
+
+    /// Write one row per island and modification code with at least one
+    /// covered site, returns the number of rows written.
+    pub(super) fn write(&self, out_fp: &Path) -> anyhow::Result<usize> {
+        create_out_directory(out_fp)?;
+        let mut writer =
+            BufWriter::new(File::create(out_fp).with_context(|| {
+                format!("failed to make CpG island output {out_fp:?}")
+            })?);
+        writeln!(writer, "#{}", Self::header())?;
+        let mut rows_written = 0usize;
+        for (island, island_counts) in self.islands.iter().zip(&self.counts) {
+            for (mod_code, counts) in
+                island_counts.iter().sorted_by(|(a, _), (b, _)| a.cmp(b))
+            {
+                let percent_modified = if counts.valid_coverage == 0 {
+                    0f64
+                } else {
+                    counts.n_modified as f64 / counts.valid_coverage as f64
+                        * 100f64
+                };
+                let mean_site_percent_modified = counts.sum_fraction_modified
+                    / counts.n_sites as f64
+                    * 100f64;
+                let tab = '\t';
+                writeln!(
+                    writer,
+                    "{}{tab}{}{tab}{}{tab}{}{tab}{}{tab}.\
+                     {tab}{}{tab}{}{tab}{}{tab}{}{tab}{}{tab}{}{tab}{}{tab}{:.\
+                     2}{tab}{:.2}",
+                    island.chrom,
+                    island.start,
+                    island.end,
+                    island.name,
+                    BedScore::capped_coverage
+                        .score(counts.valid_coverage as usize, 0f32),
+                    mod_code,
+                    island.n_cpgs,
+                    counts.n_sites,
+                    counts.valid_coverage,
+                    counts.n_modified,
+                    counts.n_canonical,
+                    counts.n_other_modified,
+                    percent_modified,
+                    mean_site_percent_modified,
+                )?;
+                rows_written += 1;
+            }
+        }
+        writer.flush()?;
+        Ok(rows_written)
+    }
+}
+
+#[cfg(test)]
+mod cpg_islands_tests {
+    use crate::pileup::cpg_islands::{
+        count_cpgs, find_cpg_islands, Composition, MIN_ISLAND_LENGTH,
+    };
+
+    #[test]
+    fn test_composition() {
+        let seq = b"ACGTCGGA";
+        let comp = Composition::of(seq);
+        assert_eq!(comp.n_c, 2);
+        assert_eq!(comp.n_g, 3);
+        assert_eq!(comp.n_cpg, 2);
+        // CpG split by the end of the range isn't counted
+        assert_eq!(Composition::of(&seq[0..5]).n_cpg, 1);
+        assert_eq!(Composition::of(&seq[2..8]).n_cpg, 1);
+        assert_eq!(count_cpgs(b"cgCGtaCg"), 3);
+    }
+
+    #[test]
+    fn test_sliding_window_composition() {
+        // the window updated base-by-base matches counting each window
+        let seq = "ACGTTCGCGAAGCGCTTACGGCGAT".repeat(20);
+        let seq = seq.as_bytes();
+        let width = MIN_ISLAND_LENGTH;
+        let mut window = Composition::of(&seq[..width]);
+        for start in 1..=(seq.len() - width) {
+            window.slide(seq, start);
+            assert_eq!(window, Composition::of(&seq[start..start + width]));
+        }
+    }
+
+    #[test]
+    fn test_find_cpg_islands() {
+        let at_rich = "AT".repeat(300);
+        let cpg_rich = "CG".repeat(150);
+        let seq = format!("{at_rich}{cpg_rich}{at_rich}");
+        let islands = find_cpg_islands(seq.as_bytes());
+        assert_eq!(islands.len(), 1);
+        let island = &islands[0];
+        // windows overlapping the flanks can still meet the criteria
+        assert!(island.start <= 600 && island.end >= 900);
+        assert!(island.start > 400 && island.end < 1100);
+
+        assert!(find_cpg_islands(at_rich.as_bytes()).is_empty());
+        assert!(find_cpg_islands(b"CGCG").is_empty());
+        // GC-rich but CpG-poor sequence is not an island
+        let gc_rich = "GGGCCCA".repeat(100);
+        assert!(find_cpg_islands(gc_rich.as_bytes()).is_empty());
+    }
+}
@@ -1,11 +1,11 @@
 use std::cmp::Ordering;
 use std::collections::HashMap;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
 use anyhow::bail;
 use derive_new::new;
 use log::debug;
-use rust_htslib::bam::{self, FetchDefinition, Read};
+use rust_htslib::bam::{FetchDefinition, Read};
 use rustc_hash::FxHashMap;
 
 use crate::interval_chunks::{FocusPositions, MultiChromCoordinates};
@@ -13,7 +13,7 @@ use crate::mod_bam::{DuplexModCall, DuplexPattern, EdgeFilter};
 use crate::pileup::{get_forward_read_base, PileupIter, PileupNumericOptions};
 use crate::read_cache::DuplexReadCache;
 use crate::threshold_mod_caller::MultipleThresholdModCaller;
-use crate::util::record_is_not_primary;
+use crate::util::{get_indexed_reader, record_is_not_primary};
 
 /// Summarizes the duplex (hemi) methylation patterns for
 /// a genomic interval
@@ -27,6 +27,8 @@ pub struct DuplexModBasePileup {
     pub processed_records: usize,
     /// number of records skipped
     pub skipped_records: usize,
+    /// number of skipped records whose MM tag doesn't match their MN tag
+    pub mm_mn_mismatch_records: usize,
 }
 
 #[derive(new, Debug, Eq, PartialEq)]
@@ -209,6 +211,7 @@ impl DuplexFeatureVector {
 pub fn process_region_duplex_batch<T: AsRef<Path> + Copy>(
     chromosome_coordintes: &MultiChromCoordinates,
     bam_fp: T,
+    reference: &PathBuf,
     caller: &MultipleThresholdModCaller,
     pileup_numeric_options: &PileupNumericOptions,
     force_allow: bool,
@@ -221,6 +224,7 @@ pub fn process_region_duplex_batch<T: AsRef<Path> + Copy>(
         .map(|chrom_coords| {
             process_region_duplex(
                 bam_fp,
+                reference,
                 chrom_coords.chrom_tid,
                 chrom_coords.start_pos,
                 chrom_coords.end_pos,
@@ -237,6 +241,7 @@ pub fn process_region_duplex_batch<T: AsRef<Path> + Copy>(
 
 fn process_region_duplex<T: AsRef<Path>>(
     bam_fp: T,
+    reference: &PathBuf,
     chrom_tid: u32,
     start_pos: u32,
     end_pos: u32,
@@ -254,7 +259,7 @@ fn process_region_duplex<T: AsRef<Path>>(
         _ => bail!("duplex requires a motif"),
     };
 
-    let mut bam_reader = bam::IndexedReader::from_path(bam_fp)?;
+    let mut bam_reader = get_indexed_reader(bam_fp, Some(reference))?;
     let chrom_name =
         String::from_utf8_lossy(bam_reader.header().tid2name(chrom_tid))
             .to_string();
@@ -330,10 +335,12 @@ fn process_region_duplex<T: AsRef<Path>>(
 
     let (processed_records, skipped_records) =
         read_cache.get_records_used_and_skipped();
+    let mm_mn_mismatch_records = read_cache.get_mm_mn_mismatches();
     Ok(DuplexModBasePileup {
         chrom_name,
         pileup_counts: position_feature_counts,
         processed_records,
         skipped_records,
+        mm_mn_mismatch_records,
     })
 }
@@ -0,0 +1,122 @@
+use rust_htslib::bam;
+use rustc_hash::FxHashSet;
+
+use crate::util::{get_query_name_string, get_stringable_aux, SamTag};
+
+/// Recognizes reads that come from the same fragment (e.g. a duplex read and
+/// its simplex parents) so that each fragment is only counted once at a
+/// position.
+#[derive(Debug, Copy, Clone)]
+pub struct FragmentIds {
+    /// Tag with the fragment (or parent read) ID, reads without the tag use
+    /// their name.
+    tag: Option<SamTag>,
+}
+
+impl FragmentIds {
+    pub(crate) fn new(tag: Option<SamTag>) -> Self {
+        Self { tag }
+    }
+
+    /// IDs of the fragment a read is from, the tag value when there is one,
+    /// otherwise the read name. Duplex read names are
+    /// `<template>;<complement>`, so the name is split on `;` to get an ID for
+    /// each parent.
+    fn ids(&self, record: &bam::Record) -> Vec<String> {
+        let raw = self
+            .tag
+            .as_ref()
+            .and_then(|tag| get_stringable_aux(record, tag))
+            .or_else(|| get_query_name_string(record).ok());
+        match raw {
+            Some(raw) => raw
+                .split(';')
+                .filter(|id| !id.is_empty())
+                .map(|id| id.to_string())
+                .collect(),
+            None => Vec::new(),
+        }
+    }
+}
+
+/// Fragments that have been counted at a position.
+#[derive(Debug, Default)]
+pub(crate) struct CountedFragments {
+    ids: FxHashSet<String>,
+}
+
+impl CountedFragments {
+    /// Returns false if a read from the same fragment has already been
+    /// counted, otherwise counts the read. Reads without an ID are always
+    /// counted. All of the IDs of the read are marked as seen either way, so
+    /// that a duplex read rejected because of one parent still blocks the
+    /// other parent.
+    pub(crate) fn insert(
+        &mut self,
+        record: &bam::Record,
+        fragment_ids: &FragmentIds,
+    ) -> bool {
+        let ids = fragment_ids.ids(record);
+        let seen = ids.iter().any(|id| self.ids.contains(id));
+        self.ids.extend(ids);
+        !seen
+    }
+}
+
+#[cfg(test)]
+mod fragments_tests {
+    use rust_htslib::bam::record::Aux;
+    use rust_htslib::bam::Record;
+
+    use crate::pileup::fragments::{CountedFragments, FragmentIds};
+    use crate::util::SamTag;
+
+    fn record(name: &str, parent: Option<&str>) -> Record {
+        let mut record = Record::new();
+        record.set(name.as_bytes(), None, b"ACGT", &[30, 30, 30, 30]);
+        if let Some(parent) = parent {
+            record.push_aux(b"pi", Aux::String(parent)).unwrap();
+        }
+        record
+    }
+
+    #[test]
+    fn test_counted_fragments_by_name() {
+        let fragment_ids = FragmentIds::new(None);
+        let mut counted = CountedFragments::default();
+        assert!(counted.insert(&record("a;b", None), &fragment_ids));
+        // simplex parents of the duplex read
+        assert!(!counted.insert(&record("a", None), &fragment_ids));
+        assert!(!counted.insert(&record("b", None), &fragment_ids));
+        assert!(counted.insert(&record("c", None), &fragment_ids));
+        assert!(!counted.insert(&record("c", None), &fragment_ids));
+    }
+
+    #[test]
+    fn test_counted_fragments_parent_first() {
+        let fragment_ids = FragmentIds::new(None);
+        let mut counted = CountedFragments::default();
+        assert!(counted.insert(&record("a", None), &fragment_ids));
+        // duplex read of "a" is rejected, but its other parent is still seen
+        assert!(!counted.insert(&record("a;b", None), &fragment_ids));
+        assert!(!counted.insert(&record("b", None), &fragment_ids));
+
+        // the complement parent first
+        let mut counted = CountedFragments::default();
+        assert!(counted.insert(&record("b", None), &fragment_ids));
+        assert!(!counted.insert(&record("a;b", None), &fragment_ids));
+        assert!(!counted.insert(&record("a", None), &fragment_ids));
+    }
+
+    #[test]
+    fn test_counted_fragments_by_tag() {
+        let fragment_ids = FragmentIds::new(Some(SamTag::parse(['p', 'i'])));
+        let mut counted = CountedFragments::default();
+        assert!(counted.insert(&record("a", Some("p1")), &fragment_ids));
+        assert!(!counted.insert(&record("b", Some("p1")), &fragment_ids));
+        assert!(counted.insert(&record("c", Some("p2")), &fragment_ids));
+        // no tag, falls back to the read name
+        assert!(counted.insert(&record("d", None), &fragment_ids));
+        assert!(!counted.insert(&record("d", None), &fragment_ids));
+    }
+}
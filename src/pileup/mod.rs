@@ -1,6 +1,6 @@
 use std::cmp::Ordering;
 use std::collections::{BTreeMap, HashMap, HashSet};
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
 use derive_new::new;
 use indexmap::IndexSet;
@@ -9,21 +9,30 @@ use log::{debug, error};
 use rayon::prelude::*;
 use rust_htslib::bam;
 use rust_htslib::bam::{FetchDefinition, Read};
-use rustc_hash::FxHashMap;
+use rustc_hash::{FxHashMap, FxHashSet};
 
 use crate::dmr::bedmethyl::BedMethylLine;
+use crate::errs::{hts_io, IoRetry};
 use crate::interval_chunks::{FocusPositions, MultiChromCoordinates};
 use crate::mod_bam::{BaseModCall, CollapseMethod, EdgeFilter};
 use crate::mod_base_code::{BaseState, DnaBase, ModCodeRepr};
 use crate::motifs::motif_bed::MotifInfo;
+use crate::pileup::cigar_states::{add_softclip_counts, CigarStateCounts};
+use crate::pileup::fragments::{CountedFragments, FragmentIds};
+use crate::pileup::phasing::PhasedVariants;
 use crate::read_cache::ReadCache;
 use crate::threshold_mod_caller::MultipleThresholdModCaller;
 use crate::util::{
-    get_query_name_string, get_stringable_aux, record_is_not_primary, SamTag,
-    Strand, StrandRule,
+    get_indexed_reader, get_query_name_string, get_stringable_aux,
+    record_is_not_primary, SamTag, Strand, StrandRule,
 };
 
+mod cigar_states;
+pub(crate) mod cpg_islands;
 pub(crate) mod duplex;
+pub mod fragments;
+pub mod phasing;
+mod qc;
 pub mod subcommand;
 
 #[derive(Debug, Copy, Clone)]
@@ -623,14 +632,18 @@ fn get_forward_read_base(
     })
 }
 
+fn get_tag_values(
+    record: &bam::Record,
+    tags: &[SamTag],
+) -> Vec<Option<String>> {
+    tags.iter().map(|tag| get_stringable_aux(&record, tag)).collect()
+}
+
 fn parse_tags_from_record(
     record: &bam::Record,
     tags: &[SamTag],
 ) -> Option<String> {
-    let values = tags
-        .iter()
-        .map(|tag| get_stringable_aux(&record, tag))
-        .collect::<Vec<Option<String>>>();
+    let values = get_tag_values(record, tags);
     let got_match = values.iter().any(|b| b.is_some());
     if !got_match {
         return None;
@@ -642,13 +655,35 @@ fn parse_tags_from_record(
     Some(key)
 }
 
+/// Reads in a partition, counted when partitioning by tag.
+pub(crate) struct PartitionReads {
+    /// Value of each partition tag, in the order the tags were given, `None`
+    /// when the reads don't have the tag.
+    pub(crate) tag_values: Vec<Option<String>>,
+    pub(crate) n_reads: usize,
+}
+
 pub struct ModBasePileup {
     pub chrom_name: String,
     position_feature_counts:
         HashMap<u32, HashMap<PartitionKey, Vec<PileupFeatureCounts>>>,
     pub(crate) skipped_records: usize,
     pub(crate) processed_records: usize,
+    /// Skipped records whose MM tag doesn't match their MN tag.
+    pub(crate) mm_mn_mismatch_records: usize,
     pub(crate) partition_keys: IndexSet<String>,
+    /// Value of each partition tag for each of `partition_keys`, in the same
+    /// order.
+    pub(crate) partition_tag_values: Vec<Vec<Option<String>>>,
+    /// Reads with an alignment starting in the processed interval for each
+    /// partition, so that reads spanning intervals are only counted once.
+    /// Empty unless partitioning by tag.
+    pub(crate) partition_reads: FxHashMap<PartitionKey, PartitionReads>,
+    /// Reads in each CIGAR state at each position, when requested.
+    pub(crate) cigar_states: Option<FxHashMap<u32, CigarStateCounts>>,
+    /// Reads not counted at a position because a read from the same fragment
+    /// was already counted there.
+    pub(crate) duplicate_fragment_reads: usize,
 }
 
 impl ModBasePileup {
@@ -662,6 +697,30 @@ impl ModBasePileup {
     {
         self.position_feature_counts.iter().sorted_by(|(x, _), (y, _)| x.cmp(y))
     }
+
+    /// Take the counts, grouped by partition and sorted by position within
+    /// each partition.
+    pub(crate) fn take_counts_by_partition(
+        &mut self,
+    ) -> FxHashMap<PartitionKey, Vec<(u32, Vec<PileupFeatureCounts>)>> {
+        let mut by_partition = FxHashMap::<
+            PartitionKey,
+            Vec<(u32, Vec<PileupFeatureCounts>)>,
+        >::default();
+        for (pos, partitioned_feature_counts) in self
+            .position_feature_counts
+            .drain()
+            .sorted_by(|(x, _), (y, _)| x.cmp(y))
+        {
+            for (partition_key, feature_counts) in partitioned_feature_counts {
+                by_partition
+                    .entry(partition_key)
+                    .or_default()
+                    .push((pos, feature_counts));
+            }
+        }
+        by_partition
+    }
 }
 
 pub enum PileupNumericOptions {
@@ -679,18 +738,28 @@ impl PileupNumericOptions {
     }
 }
 
+/// Options shared by every region processed in a pileup run.
+pub struct PileupRegionOptions<'a> {
+    pub reference: Option<&'a PathBuf>,
+    pub caller: &'a MultipleThresholdModCaller,
+    pub numeric_options: &'a PileupNumericOptions,
+    pub force_allow: bool,
+    pub combine_strands: bool,
+    pub max_depth: u32,
+    pub edge_filter: Option<&'a EdgeFilter>,
+    pub partition_tags: Option<&'a Vec<SamTag>>,
+    pub phased_variants: Option<&'a PhasedVariants>,
+    pub with_cigar_states: bool,
+    pub fragment_ids: Option<&'a FragmentIds>,
+    pub io_retry: IoRetry,
+}
+
 // todo make this function generic so it can be used for duplex
 //  as well.
 pub fn process_region_batch<T: AsRef<Path> + Copy + Sync>(
     chromosome_coordintes: &MultiChromCoordinates,
     bam_fp: T,
-    caller: &MultipleThresholdModCaller,
-    pileup_numeric_options: &PileupNumericOptions,
-    force_allow: bool,
-    combine_strands: bool,
-    max_depth: u32,
-    edge_filter: Option<&EdgeFilter>,
-    partition_tags: Option<&Vec<SamTag>>,
+    options: &PileupRegionOptions,
 ) -> Vec<Result<ModBasePileup, String>> {
     // todo make this anyhow::Result
     chromosome_coordintes
@@ -702,45 +771,82 @@ pub fn process_region_batch<T: AsRef<Path> + Copy + Sync>(
                 chrom_coords.chrom_tid,
                 chrom_coords.start_pos,
                 chrom_coords.end_pos,
-                caller,
-                pileup_numeric_options,
-                force_allow,
-                combine_strands,
-                max_depth,
                 &chrom_coords.focus_positions,
-                edge_filter,
-                partition_tags,
+                options,
             )
         })
         .collect()
 }
 
-fn process_region<T: AsRef<Path>>(
+pub(crate) fn process_region<T: AsRef<Path>>(
     bam_fp: T,
     chrom_tid: u32,
     start_pos: u32,
     end_pos: u32,
-    caller: &MultipleThresholdModCaller,
-    pileup_numeric_options: &PileupNumericOptions,
-    force_allow: bool,
-    combine_strands: bool,
-    max_depth: u32,
     focus_positions: &FocusPositions,
-    edge_filter: Option<&EdgeFilter>,
-    partition_tags: Option<&Vec<SamTag>>,
+    options: &PileupRegionOptions,
 ) -> Result<ModBasePileup, String> {
-    let mut bam_reader =
-        bam::IndexedReader::from_path(bam_fp).map_err(|e| e.to_string())?;
+    let mut bam_reader = options
+        .io_retry
+        .run(
+            || {
+                format!(
+                    "failed to fetch {chrom_tid}:{start_pos}-{end_pos} from {:?}",
+                    bam_fp.as_ref()
+                )
+            },
+            || {
+                let mut reader = hts_io(|| {
+                    get_indexed_reader(bam_fp.as_ref(), options.reference)
+                })?;
+                hts_io(|| {
+                    reader.fetch(FetchDefinition::Region(
+                        chrom_tid as i32,
+                        start_pos as i64,
+                        end_pos as i64,
+                    ))
+                })?;
+                Ok(reader)
+            },
+        )
+        .map_err(|e| e.to_string())?;
+    process_fetched_region(
+        &mut bam_reader,
+        chrom_tid,
+        start_pos,
+        end_pos,
+        focus_positions,
+        options,
+    )
+}
+
+/// Pileup a region from a reader that has already been fetched to
+/// `chrom_tid:start_pos-end_pos`, lets callers keep readers (and their
+/// loaded index) between regions.
+pub(crate) fn process_fetched_region(
+    bam_reader: &mut bam::IndexedReader,
+    chrom_tid: u32,
+    start_pos: u32,
+    end_pos: u32,
+    focus_positions: &FocusPositions,
+    options: &PileupRegionOptions,
+) -> Result<ModBasePileup, String> {
+    let PileupRegionOptions {
+        caller,
+        numeric_options: pileup_numeric_options,
+        force_allow,
+        combine_strands,
+        max_depth,
+        edge_filter,
+        partition_tags,
+        phased_variants,
+        with_cigar_states,
+        fragment_ids,
+        ..
+    } = *options;
     let chrom_name =
         String::from_utf8_lossy(bam_reader.header().tid2name(chrom_tid))
             .to_string();
-    bam_reader
-        .fetch(FetchDefinition::Region(
-            chrom_tid as i32,
-            start_pos as i64,
-            end_pos as i64,
-        ))
-        .map_err(|e| e.to_string())?;
 
     let mut read_cache = ReadCache::new(
         pileup_numeric_options.get_collapse_method(),
@@ -752,6 +858,14 @@ fn process_region<T: AsRef<Path>>(
     // collection of all partition keys encountered, ordered so
     // we can can use their index
     let mut partition_keys = IndexSet::new();
+    let mut partition_tag_values = Vec::new();
+    let partitioning = partition_tags.is_some() || phased_variants.is_some();
+    let mut haplotypes = FxHashMap::<Vec<u8>, Option<u8>>::default();
+    let mut partition_reads =
+        FxHashMap::<PartitionKey, PartitionReads>::default();
+    let mut counted_read_ids = FxHashSet::default();
+    let mut cigar_states = FxHashMap::<u32, CigarStateCounts>::default();
+    let mut duplicate_fragment_reads = 0usize;
     let hts_pileup = {
         let mut tmp_pileup = bam_reader.pileup();
         tmp_pileup.set_max_depth(max_depth);
@@ -779,7 +893,22 @@ fn process_region<T: AsRef<Path>>(
         // used for warning about dupes, could make this a bloom filter for
         // better perf?
         let mut observed_read_ids_to_pos = HashMap::new(); // optimize
-
+        let mut counted_fragments =
+            FxHashMap::<PartitionKey, CountedFragments>::default();
+
+        let mut cigar_state = CigarStateCounts::default();
+        if with_cigar_states {
+            cigar_state.n_refskip = pileup
+                .bam_pileup
+                .alignments()
+                .filter(|alignment| {
+                    let record = alignment.record();
+                    alignment.is_refskip()
+                        && !(record_is_not_primary(&record)
+                            || record.seq_len() == 0)
+                })
+                .count() as u32;
+        }
         let alignment_iter =
             pileup.bam_pileup.alignments().filter(|alignment| {
                 if alignment.is_refskip() {
@@ -792,14 +921,37 @@ fn process_region<T: AsRef<Path>>(
         for alignment in alignment_iter {
             assert!(!alignment.is_refskip());
             let record = alignment.record();
-            let partition_key = if let Some(tags) = partition_tags {
-                match parse_tags_from_record(&record, tags) {
+            // reads are assigned to a haplotype once per interval
+            let haplotype = phased_variants.map(|variants| {
+                match haplotypes.get(record.qname()) {
+                    Some(haplotype) => *haplotype,
+                    None => {
+                        let haplotype =
+                            variants.assign_haplotype(&chrom_name, &record);
+                        haplotypes.insert(record.qname().to_vec(), haplotype);
+                        haplotype
+                    }
+                }
+            });
+            let read_tag_values = || match (haplotype, partition_tags) {
+                (Some(haplotype), _) => vec![haplotype.map(|h| h.to_string())],
+                (None, Some(tags)) => get_tag_values(&record, tags),
+                (None, None) => Vec::new(),
+            };
+            let key_name = match (haplotype, partition_tags) {
+                (Some(haplotype), _) => haplotype.map(|h| h.to_string()),
+                (None, Some(tags)) => parse_tags_from_record(&record, tags),
+                (None, None) => None,
+            };
+            let partition_key = if partitioning {
+                match key_name {
                     Some(s) => {
                         if let Some(idx) = partition_keys.get_index_of(&s) {
                             PartitionKey::Key(idx)
                         } else {
                             let inserted = partition_keys.insert(s);
                             debug_assert!(inserted);
+                            partition_tag_values.push(read_tag_values());
                             debug_assert!(partition_keys.len() > 0);
                             PartitionKey::Key(
                                 partition_keys
@@ -814,6 +966,29 @@ fn process_region<T: AsRef<Path>>(
             } else {
                 PartitionKey::NoKey
             };
+            if partitioning
+                && record.pos() >= start_pos as i64
+                && counted_read_ids
+                    .insert((partition_key, record.qname().to_vec()))
+            {
+                partition_reads
+                    .entry(partition_key)
+                    .or_insert_with(|| PartitionReads {
+                        tag_values: read_tag_values(),
+                        n_reads: 0,
+                    })
+                    .n_reads += 1;
+            }
+            if let Some(fragment_ids) = fragment_ids {
+                if !counted_fragments
+                    .entry(partition_key)
+                    .or_default()
+                    .insert(&record, fragment_ids)
+                {
+                    duplicate_fragment_reads += 1;
+                    continue;
+                }
+            }
 
             // data structures we update per alignment/read
             let mut pos_strand_mod_codes_for_key =
@@ -849,6 +1024,7 @@ fn process_region<T: AsRef<Path>>(
             };
 
             if alignment.is_del() {
+                cigar_state.n_delete += 1;
                 feature_vector.add_feature(
                     alignment_strand,
                     Feature::Delete,
@@ -873,7 +1049,13 @@ fn process_region<T: AsRef<Path>>(
                 continue;
             };
 
-            match read_cache.get_mod_call(&record, pos, read_base) {
+            let mod_calls = read_cache.get_mod_call(&record, pos, read_base);
+            if matches!(mod_calls, (None, None)) {
+                cigar_state.n_match_nocall += 1;
+            } else {
+                cigar_state.n_match_call += 1;
+            }
+            match mod_calls {
                 // a read can report on the read-positive or read-negative
                 // strand (see the docs for .get_mod_call above) so the
                 // pos_call and neg_call below are _read oriented_, the
@@ -964,6 +1146,12 @@ fn process_region<T: AsRef<Path>>(
             })
             .collect::<HashMap<PartitionKey, Vec<PileupFeatureCounts>>>();
 
+        // only positions with bedMethyl records get a row in the CIGAR states
+        let has_records =
+            pileup_feature_counts.values().any(|counts| !counts.is_empty());
+        if with_cigar_states && has_records {
+            cigar_states.insert(pos, cigar_state);
+        }
         position_feature_counts.insert(pos, pileup_feature_counts);
         observed_read_ids_to_pos
             .into_iter()
@@ -994,6 +1182,19 @@ fn process_region<T: AsRef<Path>>(
 
     let (processed_records, skipped_records) =
         read_cache.get_records_used_and_skipped();
+    let mm_mn_mismatch_records = read_cache.get_mm_mn_mismatches();
+    let cigar_states = if with_cigar_states {
+        add_softclip_counts(
+            bam_reader,
+            chrom_tid,
+            start_pos,
+            end_pos,
+            &mut cigar_states,
+        )?;
+        Some(cigar_states)
+    } else {
+        None
+    };
 
     let should_warn = !dupe_reads.is_empty();
     for (read_id, counts) in dupe_reads {
@@ -1015,7 +1216,12 @@ fn process_region<T: AsRef<Path>>(
         position_feature_counts,
         processed_records,
         skipped_records,
+        mm_mn_mismatch_records,
         partition_keys,
+        partition_tag_values,
+        partition_reads,
+        cigar_states,
+        duplicate_fragment_reads,
     })
 }
 
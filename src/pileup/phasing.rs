@@ -0,0 +1,231 @@
+use std::path::Path;
+
+use anyhow::{anyhow, bail, Context};
+use log::{debug, info};
+use rust_htslib::bam;
+use rust_htslib::bam::ext::BamRecordExtensions;
+use rust_htslib::bcf::record::GenotypeAllele;
+use rust_htslib::bcf::{self, Read};
+use rustc_hash::FxHashMap;
+
+/// Name of the partition the haplotypes are written under, the same as the
+/// tag set by `whatshap haplotag` so that the outputs match partitioning a
+/// haplotagged BAM with `--partition-tag HP`.
+pub(crate) const HAPLOTYPE_PARTITION_NAME: &str = "HP";
+
+/// A heterozygous SNV with the base on each haplotype.
+#[derive(Debug, Copy, Clone)]
+struct PhasedSnv {
+    /// 0-based reference position.
+    pos: u32,
+    /// Base on haplotype 1 and 2, from the first and second alleles of the
+    /// phased genotype.
+    bases: [u8; 2],
+}
+
+/// Phased heterozygous SNVs from a VCF, used to assign reads to haplotypes
+/// by the bases they have at these positions. As with `whatshap haplotag`,
+/// haplotypes are only consistent within a phase block.
+pub struct PhasedVariants {
+    contig_to_snvs: FxHashMap<String, Vec<PhasedSnv>>,
+}
+
+impl PhasedVariants {
+    /// Load the phased heterozygous SNVs for `sample` (the first sample when
+    /// not given) from a VCF or BCF. Records that aren't SNVs, don't pass
+    /// filters, or have an unphased or homozygous genotype are skipped.
+    pub(crate) fn from_vcf(
+        vcf_fp: &Path,
+        sample: Option<&str>,
+    ) -> anyhow::Result<Self> {
+        let mut reader = bcf::Reader::from_path(vcf_fp)
+            .with_context(|| format!("failed to open VCF at {vcf_fp:?}"))?;
+        let header = reader.header().clone();
+        let sample_idx = match sample {
+            Some(name) => {
+                header.sample_id(name.as_bytes()).ok_or_else(|| {
+                    anyhow!("sample {name} is not in the VCF {vcf_fp:?}")
+                })?
+            }
+            None if header.sample_count() == 0 => {
+                bail!("VCF {vcf_fp:?} doesn't have any samples")
+            }
+            None => 0,
+        };
+        debug!(
+            "using genotypes for sample {}",
+            String::from_utf8_lossy(header.samples()[sample_idx])
+        );
+
+        let mut contig_to_snvs = FxHashMap::<String, Vec<PhasedSnv>>::default();
+        let mut n_unphased = 0usize;
+        for result in reader.records() {
+            let record = result.context("failed to read VCF record")?;
+            if !record.has_filter("PASS".as_bytes()) {
+                continue;
+            }
+            let alleles = record.alleles();
+            if alleles.iter().any(|allele| allele.len() != 1) {
+                continue;
+            }
+            let genotype = record.genotypes()?.get(sample_idx);
+            let (first, second) = match genotype.as_slice() {
+                [first, second] => (*first, *second),
+                _ => continue,
+            };
+            let (Some(first_idx), Some(second_idx)) =
+                (first.index(), second.index())
+            else {
+                continue;
+            };
+            if first_idx == second_idx {
+                continue;
+            }
+            // htslib stores the phase on the second allele
+            if !matches!(second, GenotypeAllele::Phased(_)) {
+                n_unphased += 1;
+                continue;
+            }
+            let (Some(first_base), Some(second_base)) = (
+                alleles.get(first_idx as usize),
+                alleles.get(second_idx as usize),
+            ) else {
+                continue;
+            };
+            let rid = record
+                .rid()
+                .ok_or_else(|| anyhow!("VCF record is missing a contig"))?;
+            let contig =
+                String::from_utf8_lossy(header.rid2name(rid)?).to_string();
+            contig_to_snvs.entry(contig).or_default().push(PhasedSnv {
+                pos: record.pos() as u32,
+                bases: [
+                    first_base[0].to_ascii_uppercase(),
+                    second_base[0].to_ascii_uppercase(),
+                ],
+            });
+        }
+        for snvs in contig_to_snvs.values_mut() {
+            snvs.sort_by_key(|snv| snv.pos);
+        }
+        let n_snvs =
+            contig_to_snvs.values().map(|snvs| snvs.len()).sum::<usize>();
+        if n_unphased > 0 {
+            info!("skipped {n_unphased} unphased heterozygous SNVs");
+        }
+        if n_snvs == 0 {
+            bail!("did not find any phased heterozygous SNVs in {vcf_fp:?}")
+        }
+        info!(
+            "loaded {n_snvs} phased heterozygous SNVs on {} contigs",
+            contig_to_snvs.len()
+        );
+
+        Ok(Self { contig_to_snvs })
+    }
+
+    pub(crate) fn num_snvs(&self) -> usize {
+        self.contig_to_snvs.values().map(|snvs| snvs.len()).sum()
+    }
+
+    /// Haplotype (1 or 2) with the most matching bases at the SNVs the read
+    /// covers, `None` when the read doesn't cover any of the SNVs or the
+    /// bases are split evenly between the haplotypes.
+    pub(crate) fn assign_haplotype(
+        &self,
+        contig: &str,
+        record: &bam::Record,
+    ) -> Option<u8> {
+        let snvs = self.contig_to_snvs.get(contig)?;
+        let start = record.pos();
+        let end = record.reference_end();
+        let first = snvs.partition_point(|snv| (snv.pos as i64) < start);
+        let overlapping = snvs[first..]
+            .iter()
+            .take_while(|snv| (snv.pos as i64) < end)
+            .collect::<Vec<&PhasedSnv>>();
+        if overlapping.is_empty() {
+            return None;
+        }
+
+        let seq = record.seq();
+        let mut votes = [0usize; 2];
+        let mut snvs = overlapping.into_iter().peekable();
+        for [q_pos, r_pos] in record.aligned_pairs() {
+            while snvs.next_if(|snv| (snv.pos as i64) < r_pos).is_some() {}
+            let Some(snv) = snvs.next_if(|snv| snv.pos as i64 == r_pos) else {
+                if snvs.peek().is_none() {
+                    break;
+                }
+                continue;
+            };
+            let base = seq[q_pos as usize].to_ascii_uppercase();
+            if base == snv.bases[0] {
+                votes[0] += 1;
+            } else if base == snv.bases[1] {
+                votes[1] += 1;
+            }
+        }
+        match votes[0].cmp(&votes[1]) {
+            std::cmp::Ordering::Greater => Some(1),
+            std::cmp::Ordering::Less => Some(2),
+            std::cmp::Ordering::Equal => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod phasing_tests {
+    use rust_htslib::bam::record::{Cigar, CigarString};
+    use rust_htslib::bam::Record;
+    use rustc_hash::FxHashMap;
+
+    use crate::pileup::phasing::{PhasedSnv, PhasedVariants};
+
+    fn record(pos: i64, seq: &[u8], cigar: Vec<Cigar>) -> Record {
+        let mut record = Record::new();
+        let quals = vec![30u8; seq.len()];
+        record.set(b"read", Some(&CigarString(cigar)), seq, &quals);
+        record.set_pos(pos);
+        record
+    }
+
+    #[test]
+    fn test_assign_haplotype() {
+        let snvs = vec![
+            PhasedSnv { pos: 12, bases: [b'A', b'G'] },
+            PhasedSnv { pos: 15, bases: [b'C', b'T'] },
+            PhasedSnv { pos: 30, bases: [b'G', b'A'] },
+        ];
+        let variants = PhasedVariants {
+            contig_to_snvs: FxHashMap::from_iter([("chr1".to_string(), snvs)]),
+        };
+        // read at 10..20, has the haplotype 1 bases at 12 and 15
+        let hap1 = record(10, b"TTATTCTTTT", vec![Cigar::Match(10)]);
+        assert_eq!(variants.assign_haplotype("chr1", &hap1), Some(1));
+        assert_eq!(variants.assign_haplotype("chr2", &hap1), None);
+        // haplotype 2 at both, with a deletion before the second SNV
+        let hap2 = record(
+            10,
+            b"TTGTTTTT",
+            vec![Cigar::Match(4), Cigar::Del(1), Cigar::Match(4)],
+        );
+        assert_eq!(variants.assign_haplotype("chr1", &hap2), Some(2));
+        // one base for each haplotype is a tie
+        let tie = record(10, b"TTATTTTTTT", vec![Cigar::Match(10)]);
+        assert_eq!(variants.assign_haplotype("chr1", &tie), None);
+        // the SNV at 15 is deleted, and a base that's on neither haplotype
+        // doesn't count
+        let deleted = record(
+            10,
+            b"TTGTTTTT",
+            vec![Cigar::Match(5), Cigar::Del(2), Cigar::Match(3)],
+        );
+        assert_eq!(variants.assign_haplotype("chr1", &deleted), Some(2));
+        let other = record(10, b"TTCTTGTTTT", vec![Cigar::Match(10)]);
+        assert_eq!(variants.assign_haplotype("chr1", &other), None);
+        // doesn't cover any SNVs
+        let uncovered = record(16, b"TTTTTTTTTT", vec![Cigar::Match(10)]);
+        assert_eq!(variants.assign_haplotype("chr1", &uncovered), None);
+    }
+}
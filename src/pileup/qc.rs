@@ -0,0 +1,208 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::bail;
+use log::{debug, info};
+use rust_htslib::bam::{self, Read};
+use rustc_hash::FxHashMap;
+
+use crate::errs::MkError;
+use crate::mod_bam::ModBaseInfo;
+use crate::util::{get_indexed_reader, record_is_not_primary, Region};
+
+/// Optional guards checked on the first records of the input before
+/// starting a pileup, so that pathological inputs (e.g. a BAM without MM/ML
+/// tags) fail fast instead of producing a near-empty bedMethyl.
+pub(super) struct QcGuardrails {
+    max_fail_rate: Option<f32>,
+    min_mod_tag_rate: Option<f32>,
+    num_reads: usize,
+}
+
+/// Counts of the records checked by the guardrails.
+#[derive(Debug, Default)]
+pub(super) struct QcCounts {
+    pub(super) num_records: usize,
+    pub(super) num_with_mod_tags: usize,
+    pub(super) num_failed: usize,
+    fail_reasons: FxHashMap<String, usize>,
+}
+
+impl QcCounts {
+    fn add(&mut self, record: &bam::Record) {
+        self.num_records += 1;
+        match ModBaseInfo::new_from_record(record) {
+            Ok(_) => self.num_with_mod_tags += 1,
+            Err(MkError::MmMissing | MkError::MlMissing) => {}
+            Err(e) => {
+                self.num_with_mod_tags += 1;
+                self.num_failed += 1;
+                *self.fail_reasons.entry(e.to_string()).or_insert(0) += 1;
+            }
+        }
+    }
+
+    pub(super) fn mod_tag_rate(&self) -> f32 {
+        self.num_with_mod_tags as f32 / self.num_records as f32
+    }
+
+    pub(super) fn fail_rate(&self) -> f32 {
+        self.num_failed as f32 / self.num_records as f32
+    }
+
+    fn fail_reasons_string(&self) -> String {
+        let mut reasons = self.fail_reasons.iter().collect::<Vec<_>>();
+        reasons.sort_by(|(a, n), (b, m)| m.cmp(n).then(a.cmp(b)));
+        reasons
+            .into_iter()
+            .map(|(reason, n)| format!("{reason}: {n}"))
+            .collect::<Vec<String>>()
+            .join(", ")
+    }
+}
+
+impl QcGuardrails {
+    pub(super) fn new(
+        max_fail_rate: Option<f32>,
+        min_mod_tag_rate: Option<f32>,
+        num_reads: usize,
+    ) -> anyhow::Result<Option<Self>> {
+        for (name, rate) in [
+            ("--max-fail-rate", max_fail_rate),
+            ("--min-mod-tag-rate", min_mod_tag_rate),
+        ] {
+            if let Some(rate) = rate {
+                if !(0f32..=1f32).contains(&rate) {
+                    bail!("{name} must be between 0 and 1, got {rate}")
+                }
+            }
+        }
+        if max_fail_rate.is_none() && min_mod_tag_rate.is_none() {
+            return Ok(None);
+        }
+        if num_reads == 0 {
+            bail!("--qc-num-reads must be at least 1")
+        }
+        Ok(Some(Self { max_fail_rate, min_mod_tag_rate, num_reads }))
+    }
+
+    /// Count the primary records with and without modified base tags, and
+    /// those that fail to parse, in the first `num_reads` records.
+    pub(super) fn count_records(
+        &self,
+        bam_fp: &Path,
+        reference: Option<&PathBuf>,
+        region: Option<&Region>,
+    ) -> anyhow::Result<QcCounts> {
+        let mut reader = get_indexed_reader(bam_fp, reference)?;
+        let fetch_definition = match region {
+            Some(region) => region.get_fetch_definition(reader.header())?,
+            None => bam::FetchDefinition::All,
+        };
+        reader.fetch(fetch_definition)?;
+        let mut counts = QcCounts::default();
+        for record in reader.records() {
+            if counts.num_records >= self.num_reads {
+                break;
+            }
+            let record = record?;
+            if record.is_unmapped() || record_is_not_primary(&record) {
+                continue;
+            }
+            counts.add(&record);
+        }
+        Ok(counts)
+    }
+
+    /// Check the guards against the first records of the input, returns an
+    /// error describing the problem when any of them fail.
+    pub(super) fn check(
+        &self,
+        bam_fp: &Path,
+        reference: Option<&PathBuf>,
+        region: Option<&Region>,
+    ) -> anyhow::Result<()> {
+        let counts = self.count_records(bam_fp, reference, region)?;
+        self.check_counts(&counts)
+    }
+
+    fn check_counts(&self, counts: &QcCounts) -> anyhow::Result<()> {
+        if counts.num_records == 0 {
+            debug!("no primary mapped records to check QC guardrails");
+            return Ok(());
+        }
+        let n = counts.num_records;
+        info!(
+            "QC on the first {n} records, {:.2}% have modified base tags, \
+             {:.2}% failed",
+            counts.mod_tag_rate() * 100f32,
+            counts.fail_rate() * 100f32
+        );
+        if let Some(min_rate) = self.min_mod_tag_rate {
+            if counts.mod_tag_rate() < min_rate {
+                bail!(
+                    "only {} of the first {n} records have modified base \
+                     (MM/ML) tags, less than --min-mod-tag-rate {min_rate}. \
+                     Check that the modBAM was basecalled with a modified \
+                     base model and that the tags were kept through alignment",
+                    counts.num_with_mod_tags
+                )
+            }
+        }
+        if let Some(max_rate) = self.max_fail_rate {
+            if counts.fail_rate() > max_rate {
+                bail!(
+                    "{} of the first {n} records failed to parse modified \
+                     base information, more than --max-fail-rate {max_rate}. \
+                     Reasons: {}",
+                    counts.num_failed,
+                    counts.fail_reasons_string()
+                )
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod pileup_qc_tests {
+    use std::path::Path;
+
+    use crate::pileup::qc::{QcCounts, QcGuardrails};
+
+    #[test]
+    fn test_pileup_qc_guardrails() {
+        assert!(QcGuardrails::new(None, None, 100).unwrap().is_none());
+        assert!(QcGuardrails::new(Some(1.5), None, 100).is_err());
+        assert!(QcGuardrails::new(Some(0.5), None, 0).is_err());
+
+        let guards =
+            QcGuardrails::new(Some(0.1), Some(0.9), 100).unwrap().unwrap();
+        let counts = guards
+            .count_records(
+                Path::new("tests/resources/bc_anchored_10_reads.sorted.bam"),
+                None,
+                None,
+            )
+            .unwrap();
+        assert_eq!(counts.num_records, 10);
+        assert_eq!(counts.num_with_mod_tags, 10);
+        assert_eq!(counts.num_failed, 0);
+        assert!(guards.check_counts(&counts).is_ok());
+
+        let missing_tags = QcCounts {
+            num_records: 10,
+            num_with_mod_tags: 4,
+            ..Default::default()
+        };
+        assert!(guards.check_counts(&missing_tags).is_err());
+        let mut failing = QcCounts {
+            num_records: 10,
+            num_with_mod_tags: 10,
+            num_failed: 6,
+            ..Default::default()
+        };
+        failing.fail_reasons.insert("invalid-mm".to_string(), 6);
+        let err = guards.check_counts(&failing).unwrap_err();
+        assert!(err.to_string().contains("invalid-mm: 6"));
+    }
+}
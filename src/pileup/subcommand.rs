@@ -1,37 +1,54 @@
-use std::collections::HashMap;
-use std::io::BufWriter;
-use std::path::PathBuf;
+use std::collections::{HashMap, HashSet};
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
 
 use anyhow::{anyhow, bail, Context};
 use clap::{Args, ValueEnum};
 use crossbeam_channel::bounded;
 use indicatif::{MultiProgress, ParallelProgressIterator};
+use itertools::Itertools;
 use log::{debug, error, info, warn};
 use rayon::prelude::*;
-use rust_htslib::bam::{self, Read};
+use rust_htslib::bam::Read;
+use rustc_hash::FxHashSet;
 
 use crate::command_utils::{
     calculate_chunk_size, get_threshold_from_options, parse_edge_filter_input,
-    parse_per_mod_thresholds, parse_thresholds,
+    parse_per_mod_thresholds, parse_read_group_thresholds, parse_thresholds,
+    ContigQuotaArgs, DryRunPlan, IoRetryArgs,
 };
-use crate::fasta::MotifLocationsLookup;
+use crate::fasta::{MotifLocationsLookup, ReferenceContigs};
 use crate::interval_chunks::{ReferenceIntervalsFeeder, TotalLength};
 use crate::logging::init_logging;
-use crate::mod_bam::CollapseMethod;
+use crate::mod_bam::{report_mm_mn_mismatches, CollapseMethod};
 use crate::mod_base_code::{ModCodeRepr, HYDROXY_METHYL_CYTOSINE};
-use crate::motifs::motif_bed::RegexMotif;
+use crate::mod_code_registry::ModCodeRegistry;
+use crate::motifs::motif_bed::{AmbiguousBases, RegexMotif};
+use crate::parquet_table::ParquetTableWriter;
+use crate::pileup::cigar_states::{write_cigar_states, CIGAR_STATES_COLUMNS};
+use crate::pileup::cpg_islands::CpgIslandAggregator;
 use crate::pileup::duplex::{process_region_duplex_batch, DuplexModBasePileup};
+use crate::pileup::fragments::FragmentIds;
+use crate::pileup::phasing::{PhasedVariants, HAPLOTYPE_PARTITION_NAME};
+use crate::pileup::qc::QcGuardrails;
 use crate::pileup::{
     process_region_batch, ModBasePileup, PileupNumericOptions,
+    PileupRegionOptions,
 };
 use crate::position_filter::StrandedPositionFilter;
+use crate::provenance::BasecallProvenance;
 use crate::reads_sampler::sampling_schedule::IdxStats;
+use crate::run_summary;
+use crate::sqlite::SqliteTableWriter;
+use crate::tabix::{build_bed_tabix_index, TBI_MAX_POSITION};
 use crate::util::{
-    create_out_directory, get_master_progress_bar, get_subroutine_progress_bar,
-    get_targets, get_ticker, parse_partition_tags, reader_is_bam, Region,
+    create_out_directory, get_indexed_reader, get_master_progress_bar,
+    get_subroutine_progress_bar, get_targets, get_ticker, parse_partition_tags,
+    reader_is_bam, standard_output_path, ReferenceRecord, Region,
 };
 use crate::writers::{
-    BedGraphWriter, BedMethylWriter, PartitioningBedMethylWriter, PileupWriter,
+    bedmethyl_sqlite_columns, BedGraphWriter, BedMethylWriter, BedScore,
+    BigWigPileupWriter, ModColorMap, PartitioningBedMethylWriter, PileupWriter,
 };
 
 #[derive(Args)]
@@ -41,8 +58,18 @@ pub struct ModBamPileup {
     /// Input BAM, should be sorted and have associated index available.
     in_bam: PathBuf,
     /// Output file (or directory with --bedgraph option) to write results
-    /// into. Specify "-" or "stdout" to direct output to stdout.
-    out_bed: String,
+    /// into. Specify "-" or "stdout" to direct output to stdout. May be
+    /// omitted when using `--out-dir`.
+    #[arg(required_unless_present = "out_dir")]
+    out_bed: Option<String>,
+    /// Write outputs into this directory with standardized names instead of
+    /// giving an output path. A single bedMethyl is written to
+    /// `<prefix>_pileup.bed` (`<prefix>_pileup.sqlite` or
+    /// `<prefix>_pileup.parquet` with `--out-format`), `--bedgraph` and
+    /// `--partition-tag` outputs are written into the directory as usual.
+    #[clap(help_heading = "Output Options")]
+    #[arg(long, conflicts_with = "out_bed")]
+    out_dir: Option<PathBuf>,
     /// Specify a file for debug logs to be written to, otherwise ignore them.
     /// Setting a file is recommended. (alias: log)
     #[clap(help_heading = "Logging Options")]
@@ -81,6 +108,8 @@ pub struct ModBamPileup {
     #[clap(help_heading = "Compute Options")]
     #[arg(long, hide_short_help = true, default_value_t = 1000)]
     queue_size: usize,
+    #[clap(flatten)]
+    io_retry_args: IoRetryArgs,
 
     /// Break contigs into chunks containing this many intervals (see
     /// `interval_size`). This option can be used to help prevent excessive
@@ -92,6 +121,13 @@ pub struct ModBamPileup {
     #[clap(help_heading = "Compute Options")]
     #[arg(long, hide_short_help = true)]
     chunk_size: Option<usize>,
+    /// Validate the inputs (BAM index, references, BED files, regions, and
+    /// thresholds), print the planned intervals, batches, and output files,
+    /// then exit without writing anything. Filter thresholds are not
+    /// estimated in a dry run.
+    #[clap(help_heading = "Compute Options")]
+    #[arg(long, default_value_t = false)]
+    dry_run: bool,
     /// Hide the progress bar.
     #[clap(help_heading = "Logging Options")]
     #[arg(long, default_value_t = false, hide_short_help = true)]
@@ -135,6 +171,8 @@ pub struct ModBamPileup {
         hide_short_help = true
     )]
     seed: Option<u64>,
+    #[clap(flatten)]
+    contig_quota_args: ContigQuotaArgs,
     /// Do not perform any filtering, include all mod base calls in output. See
     /// filtering.md for details on filtering.
     #[clap(help_heading = "Filtering Options")]
@@ -184,6 +222,32 @@ pub struct ModBamPileup {
     action = clap::ArgAction::Append
     )]
     mod_thresholds: Option<Vec<String>>,
+    /// Specify the filter threshold for reads in a read group (RG tag), for
+    /// BAMs that mix chemistries or basecaller versions. Takes the read group
+    /// followed by a threshold in the same format as --filter-threshold, for
+    /// example `--rg-filter-threshold run1 C:0.8`. Can be repeated for more
+    /// read groups or more bases. Thresholds that aren't specified for a
+    /// read group are the same as for all other reads.
+    #[clap(help_heading = "Filtering Options")]
+    #[arg(
+        long,
+        action = clap::ArgAction::Append,
+        num_args = 2,
+        value_names = ["RG", "THRESHOLD"],
+        hide_short_help = true
+    )]
+    rg_filter_threshold: Option<Vec<String>>,
+    /// Estimate a separate pass threshold for each read group (RG tag) in the
+    /// sampled reads, using the same --filter-percentile. Reads without a
+    /// read group use the threshold estimated from all sampled reads.
+    #[clap(help_heading = "Filtering Options")]
+    #[arg(
+        long,
+        default_value_t = false,
+        conflicts_with_all = ["filter_threshold", "no_filtering"],
+        hide_short_help = true
+    )]
+    per_rg_thresholds: bool,
     /// Specify a region for sampling reads from when estimating the threshold
     /// probability. If this option is not provided, but --region is
     /// provided, the genomic interval passed to --region will be used.
@@ -197,6 +261,23 @@ pub struct ModBamPileup {
     #[clap(help_heading = "Filtering Options")]
     #[arg(long, default_value_t = 1_000_000, hide_short_help = true)]
     sampling_interval_size: u32,
+    /// Abort the run when more than this fraction of the first
+    /// --qc-num-reads primary records have modified base tags that fail to
+    /// parse (e.g. 0.5). By default no check is performed.
+    #[clap(help_heading = "Filtering Options")]
+    #[arg(long, hide_short_help = true)]
+    max_fail_rate: Option<f32>,
+    /// Abort the run when less than this fraction of the first
+    /// --qc-num-reads primary records have modified base (MM/ML) tags (e.g.
+    /// 0.5). By default no check is performed.
+    #[clap(help_heading = "Filtering Options")]
+    #[arg(long, hide_short_help = true)]
+    min_mod_tag_rate: Option<f32>,
+    /// Number of primary records to inspect for --max-fail-rate and
+    /// --min-mod-tag-rate.
+    #[clap(help_heading = "Filtering Options")]
+    #[arg(long, default_value_t = 10_000, hide_short_help = true)]
+    qc_num_reads: usize,
     /// BED file that will restrict threshold estimation and pileup results to
     /// positions overlapping intervals in the file. (alias: include-positions)
     #[clap(help_heading = "Selection Options")]
@@ -221,6 +302,20 @@ pub struct ModBamPileup {
     #[clap(help_heading = "Modified Base Options")]
     #[arg(long, group = "combine_args", hide_short_help = true)]
     ignore: Option<String>,
+    /// Convert one modification code to another per read before counting,
+    /// given as FROM:TO, e.g. `--convert 76792:h`. The probability of the
+    /// converted code is added to the retained code. May be given more than
+    /// once to convert several codes, all conversions must have the same
+    /// target code; use `modkit adjust-mods --convert` for more complex
+    /// conversions.
+    #[clap(help_heading = "Modified Base Options")]
+    #[arg(
+        long,
+        action = clap::ArgAction::Append,
+        conflicts_with_all = ["combine_mods", "ignore"],
+        hide_short_help = true
+    )]
+    convert: Option<Vec<String>>,
     /// Force allow implicit-canonical mode. By default modkit does not allow
     /// pileup with the implicit mode (e.g. C+m, no '.' or '?'). The
     /// `update-tags` subcommand is provided to update tags to the new
@@ -259,10 +354,19 @@ pub struct ModBamPileup {
     #[arg(long, requires = "reference_fasta", default_value_t = false)]
     cpg: bool,
     /// Reference sequence in FASTA format. Required for motif (e.g. CpG)
-    /// filtering, requires FAI fasta index to be pre-generated.
+    /// filtering, requires FAI fasta index to be pre-generated. Also used to
+    /// decode CRAM input. Can be passed multiple times when the contigs come
+    /// from more than one assembly (e.g. host and pathogen), each contig is
+    /// looked up in the FASTA that contains it. CRAM input can only be
+    /// decoded with a single reference.
     #[clap(help_heading = "Modified Base Options")]
-    #[arg(long = "ref", alias = "reference", short = 'r')]
-    reference_fasta: Option<PathBuf>,
+    #[arg(
+        long = "ref",
+        alias = "reference",
+        short = 'r',
+        action = clap::ArgAction::Append
+    )]
+    reference_fasta: Vec<PathBuf>,
     #[clap(help_heading = "Modified Base Options")]
     /// Respect soft masking in the reference FASTA.
     #[arg(
@@ -273,14 +377,33 @@ pub struct ModBamPileup {
         hide_short_help = true
     )]
     mask: bool,
+    /// How to handle ambiguous (non-ACGT) bases in the reference when
+    /// finding motifs. skip: ambiguous bases never match a motif. match-any:
+    /// ambiguous bases match any motif base. expand: IUPAC codes match a
+    /// motif base when the code includes that base, e.g. R matches A or G.
+    #[clap(help_heading = "Modified Base Options")]
+    #[arg(
+        long,
+        requires = "reference_fasta",
+        default_value_t = AmbiguousBases::skip,
+        hide_short_help = true
+    )]
+    ambiguous_bases: AmbiguousBases,
     /// Optional preset options for specific applications.
     /// traditional: Prepares bedMethyl analogous to that generated from other
     /// technologies for the analysis of 5mC modified bases. Shorthand for
     /// --cpg --combine-strands --ignore h.
+    /// cpg-islands: Same as traditional, and also aggregates methylation over
+    /// CpG islands, written to --cpg-islands-out. Islands are detected from
+    /// the reference unless --cpg-islands-bed is provided.
+    /// m6A-DRACH: For direct RNA data, pileup base modification calls at
+    /// the A in DRACH motifs on the positive (transcript) strand only, the
+    /// same as --motif DRACH 2 --rna without negative-strand motifs. Uracil
+    /// (U) in the reference is treated as T.
     #[arg(
     long,
     requires = "reference_fasta",
-    conflicts_with_all = ["combine_mods", "cpg", "combine_strands", "ignore", "motif"],
+    conflicts_with_all = ["combine_mods", "cpg", "combine_strands", "ignore", "convert", "motif"],
     )]
     preset: Option<Presets>,
     /// Combine base modification calls, all counts of modified bases are
@@ -315,6 +438,20 @@ pub struct ModBamPileup {
     /// first 4 and last 8 bases.
     #[arg(long, requires = "edge_filter", default_value_t = false)]
     invert_edge_filter: bool,
+    /// Count each fragment once at each position. When more than one read
+    /// from the same fragment covers a position (e.g. a duplex read and its
+    /// simplex parents, or reads with the same name) only the first is
+    /// counted. Reads are from the same fragment when they have the same
+    /// name, duplex read names (`<template>;<complement>`) share a fragment
+    /// with each parent. Use `--fragment-tag` to use a tag instead.
+    #[clap(help_heading = "Selection Options")]
+    #[arg(long, default_value_t = false, hide_short_help = true)]
+    dedup_fragments: bool,
+    /// Tag with the fragment (or parent read) ID to use with
+    /// `--dedup-fragments`, reads without the tag use their name.
+    #[clap(help_heading = "Selection Options")]
+    #[arg(long, requires = "dedup_fragments", hide_short_help = true)]
+    fragment_tag: Option<String>,
 
     // output args
     /// **Deprecated** The default output has all tab-delimiters.
@@ -341,6 +478,13 @@ pub struct ModBamPileup {
         hide_short_help = true
     )]
     mixed_delimiters: bool,
+    /// Label output for direct RNA data, thymine (T) is reported as uracil
+    /// (U) in the motif labels of the name column (used when more than one
+    /// motif is given). Motifs given with U are always labelled with U, and
+    /// U in the reference is always treated as T.
+    #[clap(help_heading = "Output Options")]
+    #[arg(long, default_value_t = false, hide_short_help = true)]
+    rna: bool,
     /// Output bedGraph format, see https://genome.ucsc.edu/goldenPath/help/bedgraph.html.
     /// For this setting, specify a directory for output files to be make in.
     /// Two files for each modification will be produced, one for the positive
@@ -354,18 +498,71 @@ pub struct ModBamPileup {
         hide_short_help = true
     )]
     bedgraph: bool,
+    /// With --bedgraph, also write a coverage bedGraph for each fraction
+    /// modified bedGraph with the valid coverage at each position. These
+    /// files are named like the fraction modified bedGraphs with a
+    /// `_coverage` suffix.
+    #[clap(help_heading = "Output Options")]
+    #[arg(
+        long,
+        requires = "bedgraph",
+        default_value_t = false,
+        hide_short_help = true
+    )]
+    bedgraph_coverage: bool,
+    /// With --bedgraph, write bigWig tracks (`.bw`) instead of bedGraph
+    /// files, named the same way, so they don't need to be converted with
+    /// bedGraphToBigWig. The tracks are written when the pileup is finished.
+    /// Coverage tracks are also written as bigWig with --bedgraph-coverage.
+    #[clap(help_heading = "Output Options")]
+    #[arg(
+        long,
+        requires = "bedgraph",
+        conflicts_with_all = ["partition_tag", "phased_vcf", "one_based"],
+        default_value_t = false,
+        hide_short_help = true
+    )]
+    bigwig: bool,
+    /// Write 1-based, closed coordinates (start and end are both the 1-based
+    /// position) instead of 0-based, half-open BED coordinates, for tools
+    /// that expect VCF-style positions. Applies to bedMethyl and bedGraph
+    /// output. BedGraph files and bedMethyl written with `--header` start
+    /// with a comment line documenting the convention.
+    #[clap(help_heading = "Output Options")]
+    #[arg(long, default_value_t = false, hide_short_help = true)]
+    one_based: bool,
+    /// What the score column (column 5) of bedMethyl output encodes.
+    /// `coverage` is the valid coverage, `capped-coverage` is the valid
+    /// coverage capped at 1000 (the maximum score in the BED
+    /// specification), and `percent` is the percent modified multiplied by
+    /// 10 and rounded (0-1000).
+    #[clap(help_heading = "Output Options")]
+    #[arg(
+        long,
+        value_enum,
+        default_value_t = BedScore::coverage,
+        conflicts_with = "bedgraph",
+        hide_short_help = true
+    )]
+    score: BedScore,
     /// Output a header with the bedMethyl
     #[clap(help_heading = "Output Options")]
     #[arg(
         long = "header",
         alias = "with-header",
         alias = "include_header",
-        conflicts_with_all = ["bedgraph", "partition_tag", "mixed_delimiters"],
+        conflicts_with_all = [
+            "bedgraph",
+            "partition_tag",
+            "phased_vcf",
+            "mixed_delimiters"
+        ],
         default_value_t = false,
     )]
     with_header: bool,
     /// Prefix to prepend on bedgraph output file names. Without this option
-    /// the files will be <mod_code>_<strand>.bedgraph
+    /// the files will be <mod_code>_<strand>.bedgraph. With `--out-dir`, also
+    /// prefixes the single bedMethyl output file name.
     #[clap(help_heading = "Output Options")]
     #[arg(long)]
     prefix: Option<String>,
@@ -376,9 +573,332 @@ pub struct ModBamPileup {
     #[clap(help_heading = "Output Options")]
     #[arg(long)]
     partition_tag: Option<Vec<String>>,
+    /// With `--partition-tag`, write each partition into nested
+    /// directories, one level per tag in the order the tags are given, e.g.
+    /// `--partition-tag HP --partition-tag RG` writes
+    /// `HP=<value>/RG=<value>/<prefix>_pileup.bed`. Reads missing a tag are
+    /// written under `<tag>=missing`. The manifest lists the path of each
+    /// partition relative to the output directory.
+    #[clap(help_heading = "Output Options")]
+    #[arg(long, requires = "partition_tag", conflicts_with = "bedgraph")]
+    nested_partitions: bool,
+    /// Assign reads to haplotypes with the phased heterozygous SNVs in this
+    /// VCF (or BCF) instead of using HP tags, e.g. for a BAM that hasn't been
+    /// haplotagged. Each read is assigned to the haplotype with the most
+    /// matching bases at the SNVs it covers, and the outputs are the same as
+    /// `--partition-tag HP` with a haplotagged BAM: `<prefix>_1.bed` and
+    /// `<prefix>_2.bed` in the output directory, with reads that don't cover
+    /// a SNV (or match both haplotypes equally) in `<prefix>_ungrouped.bed`.
+    /// Only SNVs that pass filters with a phased heterozygous genotype (e.g.
+    /// 0|1) are used, haplotypes are only consistent within a phase block.
+    #[clap(help_heading = "Output Options")]
+    #[arg(long, conflicts_with = "partition_tag")]
+    phased_vcf: Option<PathBuf>,
+    /// Sample in `--phased-vcf` to take genotypes from, the first sample is
+    /// used by default.
+    #[clap(help_heading = "Output Options")]
+    #[arg(long, requires = "phased_vcf", hide_short_help = true)]
+    vcf_sample: Option<String>,
+    /// Output format. With `sqlite` the output file will be a SQLite
+    /// database with a single table, "pileup", with the bedMethyl columns
+    /// and an index on (chrom, chromStart). With `parquet` the output file
+    /// will be a Parquet file with the bedMethyl columns, typed so that it
+    /// can be loaded directly with polars/pandas.
+    #[clap(help_heading = "Output Options")]
+    #[arg(
+        long,
+        value_enum,
+        default_value_t = PileupOutFormat::bedmethyl,
+        conflicts_with_all = [
+            "bedgraph",
+            "partition_tag",
+            "phased_vcf",
+            "mixed_delimiters"
+        ],
+        hide_short_help = true
+    )]
+    out_format: PileupOutFormat,
+    /// Overwrite an existing SQLite or Parquet output file (with
+    /// `--out-format`).
+    #[clap(help_heading = "Output Options")]
+    #[arg(long, default_value_t = false, hide_short_help = true)]
+    force: bool,
+    /// Write the bedMethyl bgzip-compressed and build a tabix index next to
+    /// it (`.tbi`, or `.csi` when a contig is longer than 512 Mb), ready to
+    /// be used with `modkit dmr`. The output is sorted by position within
+    /// each contig. With `--out-dir` the bedMethyl is written to
+    /// `<prefix>_pileup.bed.gz`.
+    #[clap(help_heading = "Output Options")]
+    #[arg(
+        long,
+        conflicts_with_all = [
+            "bedgraph",
+            "partition_tag",
+            "phased_vcf",
+            "one_based"
+        ],
+        default_value_t = false
+    )]
+    bgzf: bool,
+    /// File to write island-level aggregated methylation to when using
+    /// `--preset cpg-islands`.
+    #[clap(help_heading = "Output Options")]
+    #[arg(
+        long,
+        requires = "preset",
+        conflicts_with_all = ["partition_tag", "phased_vcf"]
+    )]
+    cpg_islands_out: Option<PathBuf>,
+    /// BED file of CpG islands to aggregate over with `--preset
+    /// cpg-islands`, instead of detecting them from the reference with the
+    /// Gardiner-Garden and Frommer criteria.
+    #[clap(help_heading = "Output Options")]
+    #[arg(long, requires = "cpg_islands_out")]
+    cpg_islands_bed: Option<PathBuf>,
+    /// Write a table with the number of reads in each CIGAR state (aligned
+    /// with a call, aligned without a call, deletion, reference skip, and
+    /// soft-clip) at each position to this file. Useful for debugging
+    /// differences between the coverage and the N_delete and N_nocall
+    /// counts.
+    #[clap(help_heading = "Output Options")]
+    #[arg(long, hide_short_help = true)]
+    cigar_states: Option<PathBuf>,
+    /// Color the bedMethyl `color` column by modification code, using the
+    /// same colors as the `sample-probs` plots (e.g. m is 255,0,0 and h is
+    /// 255,0,255). Codes without a color are written as 255,0,0.
+    #[clap(help_heading = "Output Options")]
+    #[arg(
+        long,
+        conflicts_with_all = ["bedgraph"],
+        default_value_t = false,
+        hide_short_help = true
+    )]
+    color_by_code: bool,
+    /// Set the bedMethyl `color` column for a modification code, e.g.
+    /// `--mod-color h #FF00FF` or `--mod-color a 0,132,169`. Can be passed
+    /// multiple times, overrides --mod-color-file and --color-by-code.
+    #[clap(help_heading = "Output Options")]
+    #[arg(
+        long = "mod-color",
+        num_args = 2,
+        action = clap::ArgAction::Append,
+        conflicts_with = "bedgraph",
+        hide_short_help = true
+    )]
+    mod_colors: Vec<String>,
+    /// File of bedMethyl colors for modification codes, two tab-separated
+    /// columns: code and color (as "R,G,B" or "#RRGGBB"). Overrides
+    /// --color-by-code.
+    #[clap(help_heading = "Output Options")]
+    #[arg(long, conflicts_with = "bedgraph", hide_short_help = true)]
+    mod_color_file: Option<PathBuf>,
 }
 
 impl ModBamPileup {
+    /// Reference used to read the input, only needed to decode CRAM, which
+    /// must have a single reference.
+    fn cram_reference(&self) -> Option<&PathBuf> {
+        self.reference_fasta.first()
+    }
+
+    /// Names of the tags the outputs are partitioned by, `HP` when reads are
+    /// assigned to haplotypes with `--phased-vcf`.
+    fn partition_tag_names(&self) -> Option<Vec<String>> {
+        if self.phased_vcf.is_some() {
+            Some(vec![HAPLOTYPE_PARTITION_NAME.to_string()])
+        } else {
+            self.partition_tag.clone()
+        }
+    }
+
+    /// The output location, either as given or the standardized path in
+    /// `--out-dir`.
+    fn out_path(&self) -> String {
+        match (self.out_dir.as_ref(), self.out_bed.as_ref()) {
+            (Some(out_dir), _)
+                if self.bedgraph || self.partition_tag_names().is_some() =>
+            {
+                out_dir.to_string_lossy().to_string()
+            }
+            (Some(out_dir), _) => {
+                let name = match self.out_format {
+                    PileupOutFormat::sqlite => "pileup.sqlite",
+                    PileupOutFormat::parquet => "pileup.parquet",
+                    PileupOutFormat::bedmethyl if self.bgzf => "pileup.bed.gz",
+                    PileupOutFormat::bedmethyl => "pileup.bed",
+                };
+                standard_output_path(out_dir, self.prefix.as_ref(), name)
+                    .to_string_lossy()
+                    .to_string()
+            }
+            (None, Some(out_bed)) => out_bed.clone(),
+            (None, None) => unreachable!("clap requires out_bed or out_dir"),
+        }
+    }
+
+    /// Check the output before doing any work, SQLite and Parquet outputs
+    /// can't be written to stdout and are only overwritten with `--force`.
+    fn check_output(&self) -> anyhow::Result<()> {
+        if self.bedgraph || self.partition_tag_names().is_some() {
+            return Ok(());
+        }
+        let out_path = self.out_path();
+        let stdout = matches!(out_path.as_str(), "stdout" | "-");
+        let table_format = match self.out_format {
+            PileupOutFormat::sqlite => "SQLite",
+            PileupOutFormat::parquet => "Parquet",
+            PileupOutFormat::bedmethyl if self.bgzf && stdout => {
+                bail!("--bgzf requires an output file")
+            }
+            PileupOutFormat::bedmethyl => return Ok(()),
+        };
+        if stdout {
+            bail!("cannot write {table_format} output to stdout")
+        }
+        if Path::new(&out_path).exists() && !self.force {
+            bail!(
+                "refusing to write over existing file {out_path}, use --force"
+            )
+        }
+        Ok(())
+    }
+
+    /// Files a run would write, the names of bedGraph and partitioned
+    /// outputs depend on the modification codes and tag values in the reads
+    /// so these are given as patterns.
+    fn planned_outputs(&self, csi: bool) -> Vec<String> {
+        let out_path = self.out_path();
+        let prefix =
+            self.prefix.as_ref().map(|p| format!("{p}_")).unwrap_or_default();
+        let partition_tags = self.partition_tag_names();
+        let mut outputs = Vec::new();
+        if self.bedgraph {
+            let partition =
+                if partition_tags.is_some() { "<partition>_" } else { "" };
+            let extension = if self.bigwig { "bw" } else { "bedgraph" };
+            outputs.push(format!(
+                "{out_path}/{prefix}{partition}<mod_code>_<strand>.{extension}"
+            ));
+            if self.bedgraph_coverage {
+                outputs.push(format!(
+                    "{out_path}/{prefix}{partition}<mod_code>_<strand>\
+                     _coverage.{extension}"
+                ));
+            }
+        } else if let Some(tags) = partition_tags.as_ref() {
+            if self.nested_partitions {
+                let dirs =
+                    tags.iter().map(|tag| format!("{tag}=<value>")).join("/");
+                outputs.push(format!("{out_path}/{dirs}/{prefix}pileup.bed"));
+            } else {
+                let values =
+                    tags.iter().map(|tag| format!("<{tag}>")).join("_");
+                outputs.push(format!("{out_path}/{prefix}{values}.bed"));
+            }
+            outputs.push(format!("{out_path}/{prefix}ungrouped.bed"));
+        } else {
+            match out_path.as_str() {
+                "stdout" | "-" => outputs.push("stdout".to_string()),
+                _ if self.bgzf => {
+                    let index = if csi { "csi" } else { "tbi" };
+                    outputs.push(out_path.clone());
+                    outputs.push(format!("{out_path}.{index}"));
+                }
+                _ => outputs.push(out_path.clone()),
+            }
+        }
+        if partition_tags.is_some() {
+            let manifest = match self.prefix.as_ref() {
+                Some(p) => format!("{p}_manifest.json"),
+                None => "manifest.json".to_string(),
+            };
+            outputs.push(format!("{out_path}/{manifest}"));
+        }
+        for fp in [self.cpg_islands_out.as_ref(), self.cigar_states.as_ref()]
+            .into_iter()
+            .flatten()
+        {
+            outputs.push(fp.to_string_lossy().to_string());
+        }
+        outputs
+    }
+
+    /// Check the thresholds that can be checked without sampling reads, then
+    /// print what a run would do, see `--dry-run`.
+    fn print_dry_run_plan(
+        &self,
+        reference_records: Vec<ReferenceRecord>,
+        position_filter: Option<StrandedPositionFilter<()>>,
+        chunk_size: usize,
+        per_mod_thresholds: Option<HashMap<ModCodeRepr, f32>>,
+        phased_variants: Option<&PhasedVariants>,
+        csi: bool,
+    ) -> anyhow::Result<()> {
+        let mut plan = DryRunPlan::new("pileup");
+        plan.add_step("input", self.in_bam.to_string_lossy().to_string());
+        if !self.reference_fasta.is_empty() {
+            plan.add_step(
+                "references",
+                self.reference_fasta
+                    .iter()
+                    .map(|fp| fp.to_string_lossy())
+                    .join(", "),
+            );
+        }
+        if let Some(phased_variants) = phased_variants {
+            plan.add_step(
+                "phased variants",
+                format!("{} heterozygous SNVs", phased_variants.num_snvs()),
+            );
+        }
+        let n_contigs =
+            reference_records.iter().map(|r| r.tid).unique().count();
+        let reference_records = if let Some(pf) = position_filter.as_ref() {
+            pf.optimize_reference_records(reference_records, self.interval_size)
+        } else {
+            reference_records
+        };
+        let feeder = ReferenceIntervalsFeeder::new(
+            reference_records,
+            chunk_size,
+            self.interval_size,
+            false,
+            None,
+            position_filter,
+        )?;
+        plan.add_step("contigs", n_contigs.to_string());
+        plan.add_intervals(feeder, self.interval_size, chunk_size)?;
+
+        let thresholds = if self.no_filtering {
+            "no filtering".to_string()
+        } else if let Some(raw_threshold) = &self.filter_threshold {
+            let caller = parse_thresholds(raw_threshold, per_mod_thresholds)?;
+            if let Some(raw_rg_thresholds) = &self.rg_filter_threshold {
+                for (read_group, rg_caller) in
+                    parse_read_group_thresholds(raw_rg_thresholds, &caller)?
+                        .into_iter()
+                        .sorted_by(|(a, _), (b, _)| a.cmp(b))
+                {
+                    plan.add_step(
+                        "read group thresholds",
+                        format!("{read_group} {}", rg_caller.describe()),
+                    );
+                }
+            }
+            caller.describe()
+        } else {
+            format!(
+                "estimated from ~{} sampled reads with filter percentile {}",
+                self.num_reads, self.filter_percentile
+            )
+        };
+        plan.add_step("thresholds", thresholds);
+        for output in self.planned_outputs(csi) {
+            plan.add_output(output);
+        }
+        plan.print()
+    }
+
     pub fn run(&self) -> anyhow::Result<()> {
         let _handle = init_logging(self.log_filepath.as_ref());
         if self.only_tabs {
@@ -389,19 +909,41 @@ impl ModBamPileup {
                  --mixed-delim"
             );
         }
+        match self.out_format {
+            PileupOutFormat::sqlite if self.bgzf => {
+                bail!("--bgzf cannot be used with SQLite output")
+            }
+            PileupOutFormat::parquet if self.bgzf => {
+                bail!("--bgzf cannot be used with Parquet output")
+            }
+            _ => {}
+        }
+        self.check_output()?;
 
         // do this first so we fail when the file isn't readable
-        let header =
-            bam::IndexedReader::from_path(&self.in_bam).map(|reader| {
+        let header = get_indexed_reader(&self.in_bam, self.cram_reference())
+            .map_err(anyhow::Error::from)
+            .and_then(|reader| {
                 if !reader_is_bam(&reader) {
+                    if self.reference_fasta.len() > 1 {
+                        bail!(
+                            "CRAM input can only be decoded with a single \
+                             reference, got {} --ref FASTAs",
+                            self.reference_fasta.len()
+                        )
+                    }
                     info!(
                         "\
                     detected non-BAM input format, please consider using BAM, \
                          CRAM may be unstable"
                     );
                 }
-                reader.header().to_owned()
+                Ok(reader.header().to_owned())
             })?;
+        let provenance = BasecallProvenance::from_header_view(&header);
+        for (name, value) in provenance.fields() {
+            info!("input {name}: {value}");
+        }
 
         // options parsing below
         let region = self
@@ -439,7 +981,19 @@ impl ModBamPileup {
             .as_ref()
             .map(|raw_tags| parse_partition_tags(raw_tags))
             .transpose()?;
+        let phased_variants = self
+            .phased_vcf
+            .as_ref()
+            .map(|vcf_fp| {
+                PhasedVariants::from_vcf(vcf_fp, self.vcf_sample.as_deref())
+            })
+            .transpose()?;
         let reference_records = get_targets(&header, region.as_ref());
+        // contigs past the tabix (.tbi) limit need a CSI index
+        let csi = reference_records.iter().any(|reference_record| {
+            reference_record.start as u64 + reference_record.length as u64
+                > TBI_MAX_POSITION
+        });
         let position_filter = self
             .include_bed
             .as_ref()
@@ -461,6 +1015,7 @@ impl ModBamPileup {
         // potentially changing mutable internal state of the reader.
         IdxStats::check_any_mapped_reads(
             &self.in_bam,
+            self.cram_reference(),
             region.as_ref(),
             position_filter.as_ref(),
         )
@@ -469,6 +1024,15 @@ impl ModBamPileup {
             did not find any mapped reads, perform alignment first or use \
              modkit extract and/or modkit summary to inspect unaligned modBAMs",
         )?;
+        if let Some(guardrails) = QcGuardrails::new(
+            self.max_fail_rate,
+            self.min_mod_tag_rate,
+            self.qc_num_reads,
+        )? {
+            guardrails
+                .check(&self.in_bam, self.cram_reference(), region.as_ref())
+                .context("failed QC checks on the input modBAM")?;
+        }
         let chunk_size = calculate_chunk_size(
             self.chunk_size,
             self.interval_size,
@@ -483,8 +1047,16 @@ impl ModBamPileup {
         }
         let (pileup_options, combine_strands, threshold_collapse_method) =
             match self.preset {
-                Some(Presets::traditional) => {
-                    info!("ignoring mod code {}", HYDROXY_METHYL_CYTOSINE);
+                Some(Presets::m6a_drach) => {
+                    info!("using m6A DRACH preset");
+                    (PileupNumericOptions::Passthrough, false, None)
+                }
+                Some(Presets::traditional) | Some(Presets::cpg_islands) => {
+                    info!(
+                        "ignoring mod code {}",
+                        ModCodeRegistry::global()
+                            .describe(&HYDROXY_METHYL_CYTOSINE)
+                    );
                     (
                         PileupNumericOptions::Collapse(
                             CollapseMethod::ReDistribute(
@@ -500,14 +1072,30 @@ impl ModBamPileup {
                 None => {
                     let (options, collapse_method) =
                         match (self.combine_mods, &self.ignore) {
-                            (false, None) => {
-                                (PileupNumericOptions::Passthrough, None)
-                            }
+                            (false, None) => match self.convert.as_ref() {
+                                Some(raw_conversions) => {
+                                    let method =
+                                        parse_conversions(raw_conversions)?;
+                                    (
+                                        PileupNumericOptions::Collapse(
+                                            method.clone(),
+                                        ),
+                                        Some(method),
+                                    )
+                                }
+                                None => {
+                                    (PileupNumericOptions::Passthrough, None)
+                                }
+                            },
                             (true, _) => (PileupNumericOptions::Combine, None),
                             (_, Some(raw_mod_code)) => {
                                 let mod_code =
                                     ModCodeRepr::parse(raw_mod_code)?;
-                                info!("ignoring mod code {}", raw_mod_code);
+                                info!(
+                                    "ignoring mod code {}",
+                                    ModCodeRegistry::global()
+                                        .describe(&mod_code)
+                                );
                                 let method =
                                     CollapseMethod::ReDistribute(mod_code);
                                 (
@@ -531,16 +1119,56 @@ impl ModBamPileup {
                 bail!("illegal number of parts for motif")
             }
             Some(RegexMotif::from_raw_parts(raw_motif_parts, self.cpg)?)
-        } else if self.preset == Some(Presets::traditional) || self.cpg {
+        } else if self.preset == Some(Presets::m6a_drach) {
+            info!("filtering to only positive-strand DRACH motifs");
+            Some(vec![RegexMotif::parse_string("DRACH", 2)
+                .unwrap()
+                .with_positive_strand_only()])
+        } else if self.preset.is_some() || self.cpg {
             info!("filtering to only CpG motifs");
             Some(vec![RegexMotif::parse_string("CG", 0).unwrap()])
         } else {
             None
-        };
+        }
+        .map(|motifs| {
+            motifs
+                .into_iter()
+                .map(|m| {
+                    m.with_ambiguous_bases(self.ambiguous_bases).map(|m| {
+                        m.with_rna_label(
+                            self.rna || self.preset == Some(Presets::m6a_drach),
+                        )
+                    })
+                })
+                .collect::<anyhow::Result<Vec<RegexMotif>>>()
+        })
+        .transpose()?;
+
+        let mut cpg_island_aggregator =
+            match (self.preset, self.cpg_islands_out.as_ref()) {
+                (Some(Presets::cpg_islands), Some(_)) => {
+                    if self.reference_fasta.is_empty() {
+                        bail!("reference fasta is required for CpG islands")
+                    }
+                    let contigs = reference_records
+                        .iter()
+                        .map(|r| r.name.to_owned())
+                        .collect::<FxHashSet<String>>();
+                    Some(CpgIslandAggregator::new(
+                        &self.reference_fasta,
+                        self.cpg_islands_bed.as_deref(),
+                        &contigs,
+                    )?)
+                }
+                (Some(Presets::cpg_islands), None) => {
+                    bail!("--preset cpg-islands requires --cpg-islands-out")
+                }
+                (_, Some(_)) => {
+                    bail!("--cpg-islands-out requires --preset cpg-islands")
+                }
+                (_, None) => None,
+            };
 
-        // setup the writer here so we fail before doing any work (if there are
-        // problems).
-        let out_fp_str = self.out_bed.clone();
         let motif_labels = regex_motifs
             .as_ref()
             .map(|regex_motifs| {
@@ -550,37 +1178,215 @@ impl ModBamPileup {
                     .collect::<Vec<String>>()
             })
             .unwrap_or(Vec::new());
+        let checked_motifs = if let Some(motifs) = regex_motifs {
+            if self.reference_fasta.is_empty() {
+                bail!(
+                    "reference fasta is required for using --motif or --cpg \
+                     options"
+                )
+            }
+            ReferenceContigs::from_paths(&self.reference_fasta)?
+                .check_contigs(
+                    reference_records.iter().map(|r| r.name.as_str()),
+                )
+                .context("cannot find motifs on every contig")?;
+            if combine_strands {
+                if motifs.iter().any(|rm| !rm.is_palendrome()) {
+                    bail!(
+                        "cannot combine strands with a motif that is not a \
+                         palindrome"
+                    )
+                }
+                debug!("combining + and - strand counts");
+            }
+            Some(motifs)
+        } else {
+            None
+        };
+
+        if self.dry_run {
+            return self.print_dry_run_plan(
+                reference_records,
+                position_filter,
+                chunk_size,
+                per_mod_thresholds,
+                phased_variants.as_ref(),
+                csi,
+            );
+        }
+        // finding the motifs reads every reference, so wait until the
+        // inputs and outputs have been checked
+        let motif_lookup = checked_motifs
+            .map(|motifs| {
+                MotifLocationsLookup::from_paths(
+                    &self.reference_fasta,
+                    self.mask,
+                    None,
+                    motifs,
+                )
+            })
+            .transpose()?;
+
+        // setup the writer here so we fail before doing any work (if there are
+        // problems).
+        let out_fp_str = self.out_path();
+        let colors = ModColorMap::new(
+            self.color_by_code,
+            self.mod_color_file.as_ref(),
+            &self.mod_colors,
+        )?;
+        let partition_tag_names =
+            self.partition_tag_names().unwrap_or_default();
+        let mut cigar_states_writer = self
+            .cigar_states
+            .as_ref()
+            .map(|fp| -> anyhow::Result<BufWriter<std::fs::File>> {
+                create_out_directory(fp)?;
+                let mut writer = BufWriter::new(
+                    std::fs::File::create(fp)
+                        .context("failed to make CIGAR states file")?,
+                );
+                writeln!(writer, "{}", CIGAR_STATES_COLUMNS.join("\t"))?;
+                Ok(writer)
+            })
+            .transpose()?;
         let mut writer: Box<dyn PileupWriter<ModBasePileup>> =
-            match (self.bedgraph, partition_tags.is_some()) {
-                (true, _) => Box::new(BedGraphWriter::new(
-                    &out_fp_str,
-                    self.prefix.as_ref(),
-                    partition_tags.is_some(),
-                )?),
-                (false, true) => Box::new(PartitioningBedMethylWriter::new(
-                    &self.out_bed,
-                    !self.mixed_delimiters,
-                    self.prefix.as_ref(),
-                )?),
+            match (self.bedgraph, !partition_tag_names.is_empty()) {
+                (true, _) if self.bigwig => {
+                    let chrom_sizes = (0..header.target_count())
+                        .filter_map(|tid| {
+                            let name =
+                                String::from_utf8_lossy(header.tid2name(tid))
+                                    .to_string();
+                            let length = header.target_len(tid)?;
+                            Some((name, length as u32))
+                        })
+                        .collect::<HashMap<String, u32>>();
+                    Box::new(
+                        BigWigPileupWriter::new(
+                            &out_fp_str,
+                            self.prefix.as_ref(),
+                            chrom_sizes,
+                            self.threads,
+                        )?
+                        .with_coverage_tracks(self.bedgraph_coverage),
+                    )
+                }
+                (true, _) => Box::new(
+                    BedGraphWriter::new(
+                        &out_fp_str,
+                        self.prefix.as_ref(),
+                        !partition_tag_names.is_empty(),
+                    )?
+                    .with_coverage_tracks(self.bedgraph_coverage)
+                    .with_one_based(self.one_based)
+                    .with_manifest(&partition_tag_names),
+                ),
+                (false, true) => {
+                    let writer = PartitioningBedMethylWriter::new(
+                        &out_fp_str,
+                        !self.mixed_delimiters,
+                        self.prefix.as_ref(),
+                    )?
+                    .with_colors(colors)
+                    .with_one_based(self.one_based)
+                    .with_score(self.score)
+                    .with_manifest(&partition_tag_names)
+                    .with_threads(self.threads);
+                    if self.nested_partitions {
+                        Box::new(
+                            writer.with_nested_partitions(&partition_tag_names),
+                        )
+                    } else {
+                        Box::new(writer)
+                    }
+                }
                 (false, false) => match out_fp_str.as_str() {
+                    _ if self.out_format == PileupOutFormat::sqlite => {
+                        create_out_directory(&out_fp_str)?;
+                        let writer = SqliteTableWriter::new(
+                            Path::new(&out_fp_str),
+                            self.force,
+                            "pileup",
+                            &bedmethyl_sqlite_columns(),
+                            &[&["chrom", "chromStart"]],
+                        )?;
+                        Box::new(
+                            BedMethylWriter::new(
+                                BufWriter::new(writer),
+                                false,
+                                false,
+                            )?
+                            .with_colors(colors)
+                            .with_score(self.score)
+                            .with_one_based(self.one_based)?,
+                        )
+                    }
+                    _ if self.out_format == PileupOutFormat::parquet => {
+                        create_out_directory(&out_fp_str)?;
+                        let writer = ParquetTableWriter::new(
+                            Path::new(&out_fp_str),
+                            self.force,
+                            "pileup",
+                            &bedmethyl_sqlite_columns(),
+                        )?;
+                        Box::new(
+                            BedMethylWriter::new(
+                                BufWriter::new(writer),
+                                false,
+                                false,
+                            )?
+                            .with_colors(colors)
+                            .with_score(self.score)
+                            .with_one_based(self.one_based)?,
+                        )
+                    }
+                    _ if self.bgzf => {
+                        create_out_directory(&out_fp_str)?;
+                        Box::new(
+                            BedMethylWriter::new_bgzf(
+                                Path::new(&out_fp_str),
+                                self.threads,
+                                self.mixed_delimiters,
+                                self.with_header,
+                            )
+                            .context("failed to make bgzf output file")?
+                            .with_colors(colors)
+                            .with_score(self.score)
+                            .with_one_based(self.one_based)?
+                            .with_provenance(&provenance)?,
+                        )
+                    }
                     "stdout" | "-" => {
                         let writer = BufWriter::new(std::io::stdout());
-                        Box::new(BedMethylWriter::new(
-                            writer,
-                            self.mixed_delimiters,
-                            self.with_header,
-                        )?)
+                        Box::new(
+                            BedMethylWriter::new(
+                                writer,
+                                self.mixed_delimiters,
+                                self.with_header,
+                            )?
+                            .with_colors(colors)
+                            .with_score(self.score)
+                            .with_one_based(self.one_based)?
+                            .with_provenance(&provenance)?,
+                        )
                     }
                     _ => {
                         create_out_directory(&out_fp_str)?;
-                        let fh = std::fs::File::create(out_fp_str)
+                        let fh = std::fs::File::create(&out_fp_str)
                             .context("failed to make output file")?;
                         let writer = BufWriter::new(fh);
-                        Box::new(BedMethylWriter::new(
-                            writer,
-                            self.mixed_delimiters,
-                            self.with_header,
-                        )?)
+                        Box::new(
+                            BedMethylWriter::new(
+                                writer,
+                                self.mixed_delimiters,
+                                self.with_header,
+                            )?
+                            .with_colors(colors)
+                            .with_score(self.score)
+                            .with_one_based(self.one_based)?
+                            .with_provenance(&provenance)?,
+                        )
                     }
                 },
             };
@@ -589,27 +1395,6 @@ impl ModBamPileup {
             .num_threads(self.threads)
             .build()
             .with_context(|| "failed to make threadpool")?;
-        let motif_lookup = if let Some(motifs) = regex_motifs {
-            let fasta_fp = self.reference_fasta.as_ref().ok_or(anyhow!(
-                "reference fasta is required for using --motif or --cpg \
-                 options"
-            ))?;
-            if combine_strands {
-                if motifs.iter().any(|rm| !rm.is_palendrome()) {
-                    bail!(
-                        "cannot combine strands with a motif that is not a \
-                         palindrome"
-                    )
-                }
-                debug!("combining + and - strand counts");
-            }
-
-            Some(MotifLocationsLookup::from_paths(
-                fasta_fp, self.mask, None, motifs,
-            )?)
-        } else {
-            None
-        };
 
         // start the actual work here
         let threshold_caller =
@@ -619,6 +1404,7 @@ impl ModBamPileup {
                 pool.install(|| {
                     get_threshold_from_options(
                         &self.in_bam,
+                        self.cram_reference(),
                         self.threads,
                         self.sampling_interval_size,
                         self.sampling_frac,
@@ -632,10 +1418,22 @@ impl ModBamPileup {
                         threshold_collapse_method.as_ref(),
                         position_filter.as_ref(),
                         !self.include_unmapped,
+                        self.contig_quota_args.contig_quotas(),
+                        self.per_rg_thresholds,
                         self.suppress_progress,
                     )
                 })?
             };
+        let threshold_caller =
+            if let Some(raw_rg_thresholds) = &self.rg_filter_threshold {
+                let read_group_callers = parse_read_group_thresholds(
+                    raw_rg_thresholds,
+                    &threshold_caller,
+                )?;
+                threshold_caller.with_read_group_callers(read_group_callers)
+            } else {
+                threshold_caller
+            };
 
         if !self.no_filtering {
             for (base, threshold) in threshold_caller.iter_thresholds() {
@@ -676,6 +1474,12 @@ impl ModBamPileup {
                 }
             }
         }
+        for (read_group, caller) in threshold_caller.iter_read_group_callers() {
+            info!(
+                "Using filter thresholds {} for read group {read_group}.",
+                caller.describe()
+            );
+        }
 
         let (snd, rx) = bounded(self.queue_size);
         let reference_records = if let Some(pf) = position_filter.as_ref() {
@@ -693,6 +1497,7 @@ impl ModBamPileup {
         )?;
 
         let in_bam_fp = self.in_bam.clone();
+        let reference_fp = self.cram_reference().cloned();
         let master_progress = MultiProgress::new();
         if self.suppress_progress {
             master_progress
@@ -710,8 +1515,37 @@ impl ModBamPileup {
 
         let force_allow = self.force_allow_implicit;
         let max_depth = self.max_depth;
+        let with_cigar_states = self.cigar_states.is_some();
+        let io_retry = self.io_retry_args.io_retry()?;
+        let fragment_ids = if self.dedup_fragments {
+            let tag = self
+                .fragment_tag
+                .as_ref()
+                .map(|raw| {
+                    parse_partition_tags(std::slice::from_ref(raw))
+                        .map(|tags| tags[0])
+                })
+                .transpose()?;
+            Some(FragmentIds::new(tag))
+        } else {
+            None
+        };
 
         std::thread::spawn(move || {
+            let region_options = PileupRegionOptions {
+                reference: reference_fp.as_ref(),
+                caller: &threshold_caller,
+                numeric_options: &pileup_options,
+                force_allow,
+                combine_strands,
+                max_depth,
+                edge_filter: edge_filter.as_ref(),
+                partition_tags: partition_tags.as_ref(),
+                phased_variants: phased_variants.as_ref(),
+                with_cigar_states,
+                fragment_ids: fragment_ids.as_ref(),
+                io_retry,
+            };
             pool.install(|| {
                 for multi_chrom_coords in feeder.into_iter()
                     .inspect(|x| match x {
@@ -739,13 +1573,7 @@ impl ModBamPileup {
                                         process_region_batch(
                                             multi_chrom_coords,
                                             &in_bam_fp,
-                                            &threshold_caller,
-                                            &pileup_options,
-                                            force_allow,
-                                            combine_strands,
-                                            max_depth,
-                                            edge_filter.as_ref(),
-                                            partition_tags.as_ref(),
+                                            &region_options,
                                         )
                                     })
                                     .flatten()
@@ -782,12 +1610,30 @@ impl ModBamPileup {
             });
         });
 
+        let mut n_mm_mn_mismatch = 0usize;
+        let mut n_duplicate_fragment_reads = 0usize;
         for result in rx.into_iter() {
             match result {
                 Ok(mod_base_pileup) => {
                     processed_reads
                         .inc(mod_base_pileup.processed_records as u64);
                     skipped_reads.inc(mod_base_pileup.skipped_records as u64);
+                    n_mm_mn_mismatch += mod_base_pileup.mm_mn_mismatch_records;
+                    n_duplicate_fragment_reads +=
+                        mod_base_pileup.duplicate_fragment_reads;
+                    if let Some(aggregator) = cpg_island_aggregator.as_mut() {
+                        aggregator.add(&mod_base_pileup);
+                    }
+                    if let (Some(cigar_writer), Some(states)) = (
+                        cigar_states_writer.as_mut(),
+                        mod_base_pileup.cigar_states.as_ref(),
+                    ) {
+                        write_cigar_states(
+                            cigar_writer,
+                            &mod_base_pileup.chrom_name,
+                            states,
+                        )?;
+                    }
                     let rows_written =
                         writer.write(mod_base_pileup, &motif_labels)?;
                     write_progress.inc(rows_written);
@@ -797,6 +1643,16 @@ impl ModBamPileup {
                 }
             }
         }
+        // finishes the bgzf stream so that it can be indexed
+        writer.finish()?;
+        if self.bgzf {
+            build_bed_tabix_index(Path::new(&out_fp_str), csi).with_context(
+                || format!("failed to index bedMethyl output {out_fp_str}"),
+            )?;
+        }
+        if let Some(mut cigar_writer) = cigar_states_writer {
+            cigar_writer.flush()?;
+        }
         let rows_processed = write_progress.position();
         let n_skipped_reads = skipped_reads.position();
         let n_skipped_message = if n_skipped_reads == 0 {
@@ -805,9 +1661,24 @@ impl ModBamPileup {
             format!("~{n_skipped_reads} reads")
         };
         let n_processed_reads = processed_reads.position();
+        run_summary::record_reads(n_processed_reads, n_skipped_reads, 0);
+        report_mm_mn_mismatches(n_mm_mn_mismatch);
+        if self.dedup_fragments {
+            info!(
+                "{n_duplicate_fragment_reads} read observation(s) not counted \
+                 because a read from the same fragment was already counted at \
+                 the position"
+            );
+        }
         write_progress.finish_and_clear();
         processed_reads.finish_and_clear();
         skipped_reads.finish_and_clear();
+        if let (Some(aggregator), Some(out_fp)) =
+            (cpg_island_aggregator, self.cpg_islands_out.as_ref())
+        {
+            let island_rows = aggregator.write(out_fp)?;
+            info!("wrote {island_rows} CpG island rows to {out_fp:?}");
+        }
         info!(
             "Done, processed {rows_processed} rows. Processed \
              ~{n_processed_reads} reads and skipped {n_skipped_message}."
@@ -820,6 +1691,66 @@ impl ModBamPileup {
 #[allow(non_camel_case_types)]
 enum Presets {
     traditional,
+    cpg_islands,
+    #[value(name = "m6A-DRACH")]
+    m6a_drach,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
+#[allow(non_camel_case_types)]
+enum PileupOutFormat {
+    bedmethyl,
+    sqlite,
+    parquet,
+}
+
+/// Parse `--convert` arguments of the form FROM:TO into a single
+/// [`CollapseMethod::Convert`].
+fn parse_conversions(
+    raw_conversions: &[String],
+) -> anyhow::Result<CollapseMethod> {
+    let mut to_code = None;
+    let mut from_codes = HashSet::new();
+    for raw in raw_conversions {
+        let (raw_from, raw_to) = raw.split_once(':').ok_or_else(|| {
+            anyhow!("invalid --convert {raw}, should be FROM:TO, e.g. 76792:h")
+        })?;
+        let from = ModCodeRepr::parse(raw_from)?;
+        let to = ModCodeRepr::parse(raw_to)?;
+        let registry = ModCodeRegistry::global();
+        if let (Some(from_base), Some(to_base)) =
+            (registry.primary_base(&from), registry.primary_base(&to))
+        {
+            if from_base != to_base {
+                bail!(
+                    "invalid --convert {raw}, {} is a modification of \
+                     {from_base} and {} is a modification of {to_base}",
+                    registry.describe(&from),
+                    registry.describe(&to),
+                )
+            }
+        }
+        match to_code {
+            Some(existing) if existing != to => bail!(
+                "all --convert options must have the same target code, got \
+                 {existing} and {to}, use adjust-mods to convert to multiple \
+                 codes"
+            ),
+            _ => to_code = Some(to),
+        }
+        from_codes.insert(from);
+    }
+    let to = to_code.ok_or_else(|| anyhow!("no --convert options given"))?;
+    if from_codes.contains(&to) {
+        bail!("invalid --convert, cannot convert {to} to itself")
+    }
+    let registry = ModCodeRegistry::global();
+    info!(
+        "converting {} to {}",
+        from_codes.iter().sorted().map(|x| registry.describe(x)).join(","),
+        registry.describe(&to)
+    );
+    Ok(CollapseMethod::Convert { from: from_codes, to })
 }
 
 #[derive(Args)]
@@ -845,9 +1776,11 @@ pub struct DuplexModBamPileup {
     /// to generate pattern counts for the C on the top strand
     /// and the following C (opposite to G) on the negative strand. The motif
     /// must be reverse-complement palindromic or an error will be raised.
-    /// See the documentation for more examples and details.
+    /// Multiple motifs can be used by repeating this option, for example
+    /// --motif GATC 1 --motif CCWGG 1. See the documentation for more examples
+    /// and details.
     #[clap(help_heading = "Modified Base Options")]
-    #[arg(long, group = "motif_options", num_args = 2)]
+    #[arg(long, group = "motif_options", num_args = 2, action = clap::ArgAction::Append)]
     motif: Option<Vec<String>>,
     /// Reference sequence in FASTA format.
     #[clap(help_heading = "Modified Base Options")]
@@ -945,6 +1878,8 @@ pub struct DuplexModBamPileup {
         hide_short_help = true
     )]
     seed: Option<u64>,
+    #[clap(flatten)]
+    contig_quota_args: ContigQuotaArgs,
     /// Do not perform any filtering, include all mod base calls in output. See
     /// filtering.md for details on filtering.
     #[clap(help_heading = "Filtering Options")]
@@ -994,6 +1929,32 @@ pub struct DuplexModBamPileup {
     action = clap::ArgAction::Append
     )]
     mod_thresholds: Option<Vec<String>>,
+    /// Specify the filter threshold for reads in a read group (RG tag), for
+    /// BAMs that mix chemistries or basecaller versions. Takes the read group
+    /// followed by a threshold in the same format as --filter-threshold, for
+    /// example `--rg-filter-threshold run1 C:0.8`. Can be repeated for more
+    /// read groups or more bases. Thresholds that aren't specified for a
+    /// read group are the same as for all other reads.
+    #[clap(help_heading = "Filtering Options")]
+    #[arg(
+        long,
+        action = clap::ArgAction::Append,
+        num_args = 2,
+        value_names = ["RG", "THRESHOLD"],
+        hide_short_help = true
+    )]
+    rg_filter_threshold: Option<Vec<String>>,
+    /// Estimate a separate pass threshold for each read group (RG tag) in the
+    /// sampled reads, using the same --filter-percentile. Reads without a
+    /// read group use the threshold estimated from all sampled reads.
+    #[clap(help_heading = "Filtering Options")]
+    #[arg(
+        long,
+        default_value_t = false,
+        conflicts_with_all = ["filter_threshold", "no_filtering"],
+        hide_short_help = true
+    )]
+    per_rg_thresholds: bool,
     /// Specify a region for sampling reads from when estimating the threshold
     /// probability. If this option is not provided, but --region is
     /// provided, the genomic interval passed to --region will be used.
@@ -1007,6 +1968,23 @@ pub struct DuplexModBamPileup {
     #[clap(help_heading = "Filtering Options")]
     #[arg(long, default_value_t = 1_000_000, hide_short_help = true)]
     sampling_interval_size: u32,
+    /// Abort the run when more than this fraction of the first
+    /// --qc-num-reads primary records have modified base tags that fail to
+    /// parse (e.g. 0.5). By default no check is performed.
+    #[clap(help_heading = "Filtering Options")]
+    #[arg(long, hide_short_help = true)]
+    max_fail_rate: Option<f32>,
+    /// Abort the run when less than this fraction of the first
+    /// --qc-num-reads primary records have modified base (MM/ML) tags (e.g.
+    /// 0.5). By default no check is performed.
+    #[clap(help_heading = "Filtering Options")]
+    #[arg(long, hide_short_help = true)]
+    min_mod_tag_rate: Option<f32>,
+    /// Number of primary records to inspect for --max-fail-rate and
+    /// --min-mod-tag-rate.
+    #[clap(help_heading = "Filtering Options")]
+    #[arg(long, default_value_t = 10_000, hide_short_help = true)]
+    qc_num_reads: usize,
     /// BED file that will restrict threshold estimation and pileup results to
     /// positions overlapping intervals in the file. (alias: include-positions)
     #[clap(help_heading = "Selection Options")]
@@ -1132,16 +2110,18 @@ impl DuplexModBamPileup {
         }
         // do this first so we fail when the file isn't readable
         let header =
-            bam::IndexedReader::from_path(&self.in_bam).map(|reader| {
-                if !reader_is_bam(&reader) {
-                    info!(
-                        "\
+            get_indexed_reader(&self.in_bam, Some(&self.reference_fasta)).map(
+                |reader| {
+                    if !reader_is_bam(&reader) {
+                        info!(
+                            "\
                     detected non-BAM input format, please consider using BAM, \
                          CRAM may be unstable"
-                    );
-                }
-                reader.header().to_owned()
-            })?;
+                        );
+                    }
+                    reader.header().to_owned()
+                },
+            )?;
 
         // options parsing below
         let region = self
@@ -1194,6 +2174,7 @@ impl DuplexModBamPileup {
         // potentially changing mutable internal state of the reader.
         IdxStats::check_any_mapped_reads(
             &self.in_bam,
+            Some(&self.reference_fasta),
             region.as_ref(),
             position_filter.as_ref(),
         )
@@ -1202,6 +2183,19 @@ impl DuplexModBamPileup {
             did not find any mapped reads, perform alignment first or use \
              modkit extract and/or modkit summary to inspect unaligned modBAMs",
         )?;
+        if let Some(guardrails) = QcGuardrails::new(
+            self.max_fail_rate,
+            self.min_mod_tag_rate,
+            self.qc_num_reads,
+        )? {
+            guardrails
+                .check(
+                    &self.in_bam,
+                    Some(&self.reference_fasta),
+                    region.as_ref(),
+                )
+                .context("failed QC checks on the input modBAM")?;
+        }
         let chunk_size = if let Some(chunk_size) = self.chunk_size {
             if chunk_size < self.threads {
                 warn!(
@@ -1231,7 +2225,10 @@ impl DuplexModBamPileup {
                 (true, _) => (PileupNumericOptions::Combine, None),
                 (_, Some(raw_mod_code)) => {
                     let mod_code = ModCodeRepr::parse(&raw_mod_code)?;
-                    info!("ignoring mod code {}", raw_mod_code);
+                    info!(
+                        "ignoring mod code {}",
+                        ModCodeRegistry::global().describe(&mod_code)
+                    );
                     let method = CollapseMethod::ReDistribute(mod_code);
                     (
                         PileupNumericOptions::Collapse(method.clone()),
@@ -1241,35 +2238,22 @@ impl DuplexModBamPileup {
             };
 
         // motif handling
-        let regex_motif = {
-            if self.cpg {
-                RegexMotif::parse_string("CG", 0)?
-            } else {
-                if self.motif.is_none() {
-                    bail!(
-                        "either --cpg or a --motif must be provided for \
-                         pileup-hemi"
-                    )
-                }
-                let raw_motif = self.motif.as_ref().unwrap();
-                if raw_motif.len() != 2 {
-                    bail!("motif arg should be length 2, eg. CG 0")
-                }
-                let motif_seq = &raw_motif[0];
-                let focus_base =
-                    raw_motif[1].parse::<usize>().map_err(|e| {
-                        anyhow!(
-                            "couldn't parse focus position, {}",
-                            e.to_string()
-                        )
-                    })?;
-                let regex_motif =
-                    RegexMotif::parse_string(motif_seq, focus_base)?;
-                regex_motif
+        let regex_motifs = if self.cpg {
+            vec![RegexMotif::parse_string("CG", 0)?]
+        } else {
+            let raw_motif_parts = self.motif.as_ref().ok_or_else(|| {
+                anyhow!(
+                    "either --cpg or a --motif must be provided for \
+                     pileup-hemi"
+                )
+            })?;
+            if raw_motif_parts.len() % 2 != 0 {
+                bail!("motif arg should be length 2, eg. CG 0")
             }
+            RegexMotif::from_raw_parts(raw_motif_parts, false)?
         };
-        if !regex_motif.is_palendrome() {
-            bail!("motif must be palindromic for pileup-hemi")
+        if let Some(motif) = regex_motifs.iter().find(|m| !m.is_palendrome()) {
+            bail!("motif {motif} must be palindromic for pileup-hemi")
         }
 
         let mut writer: Box<dyn PileupWriter<DuplexModBasePileup>> =
@@ -1299,10 +2283,10 @@ impl DuplexModBamPileup {
 
         // put this into it's own function
         let motif_lookup = MotifLocationsLookup::from_paths(
-            &self.reference_fasta,
+            std::slice::from_ref(&self.reference_fasta),
             self.mask,
             None,
-            vec![regex_motif],
+            regex_motifs,
         )?;
 
         // start the actual work here
@@ -1313,6 +2297,7 @@ impl DuplexModBamPileup {
                 pool.install(|| {
                     get_threshold_from_options(
                         &self.in_bam,
+                        Some(&self.reference_fasta),
                         self.threads,
                         self.sampling_interval_size,
                         self.sampling_frac,
@@ -1326,10 +2311,22 @@ impl DuplexModBamPileup {
                         collapse_method.as_ref(),
                         position_filter.as_ref(),
                         !self.include_unmapped,
+                        self.contig_quota_args.contig_quotas(),
+                        self.per_rg_thresholds,
                         self.suppress_progress,
                     )
                 })?
             };
+        let threshold_caller =
+            if let Some(raw_rg_thresholds) = &self.rg_filter_threshold {
+                let read_group_callers = parse_read_group_thresholds(
+                    raw_rg_thresholds,
+                    &threshold_caller,
+                )?;
+                threshold_caller.with_read_group_callers(read_group_callers)
+            } else {
+                threshold_caller
+            };
 
         if !self.no_filtering {
             for (base, threshold) in threshold_caller.iter_thresholds() {
@@ -1373,6 +2370,12 @@ impl DuplexModBamPileup {
                 }
             }
         }
+        for (read_group, caller) in threshold_caller.iter_read_group_callers() {
+            info!(
+                "Using filter thresholds {} for read group {read_group}.",
+                caller.describe()
+            );
+        }
 
         let (snd, rx) = bounded(self.queue_size);
         let reference_records = if let Some(pf) = position_filter.as_ref() {
@@ -1390,6 +2393,7 @@ impl DuplexModBamPileup {
         )?;
 
         let in_bam_fp = self.in_bam.clone();
+        let reference_fp = self.reference_fasta.clone();
 
         let master_progress = MultiProgress::new();
         if self.suppress_progress {
@@ -1440,6 +2444,7 @@ impl DuplexModBamPileup {
                                     process_region_duplex_batch(
                                         multi_chrom_coords,
                                         &in_bam_fp,
+                                        &reference_fp,
                                         &threshold_caller,
                                         &pileup_options,
                                         force_allow,
@@ -1480,12 +2485,14 @@ impl DuplexModBamPileup {
             tid_progress.finish_and_clear();
         });
 
+        let mut n_mm_mn_mismatch = 0usize;
         for result in rx.into_iter() {
             match result {
                 Ok(mod_base_pileup) => {
                     processed_reads
                         .inc(mod_base_pileup.processed_records as u64);
                     skipped_reads.inc(mod_base_pileup.skipped_records as u64);
+                    n_mm_mn_mismatch += mod_base_pileup.mm_mn_mismatch_records;
                     let rows_written = writer.write(mod_base_pileup, &[])?;
                     write_progress.inc(rows_written);
                 }
@@ -1494,6 +2501,7 @@ impl DuplexModBamPileup {
                 }
             }
         }
+        writer.finish()?;
         let rows_processed = write_progress.position();
         let n_skipped_reads = skipped_reads.position();
         let n_skipped_message = if n_skipped_reads == 0 {
@@ -1502,6 +2510,8 @@ impl DuplexModBamPileup {
             format!("~{n_skipped_reads} reads")
         };
         let n_processed_reads = processed_reads.position();
+        run_summary::record_reads(n_processed_reads, n_skipped_reads, 0);
+        report_mm_mn_mismatches(n_mm_mn_mismatch);
         write_progress.finish_and_clear();
         processed_reads.finish_and_clear();
         skipped_reads.finish_and_clear();
@@ -1,6 +1,5 @@
 use std::collections::{HashMap, HashSet, VecDeque};
-use std::fs::File;
-use std::io::{BufRead, BufReader};
+use std::io::BufRead;
 use std::path::PathBuf;
 
 use anyhow::bail;
@@ -12,6 +11,7 @@ use rust_lapper as lapper;
 use rustc_hash::FxHashMap;
 
 use crate::mod_base_code::DnaBase;
+use crate::parsing_utils::open_text_input;
 use crate::util::{get_targets, get_ticker, ReferenceRecord, Strand};
 
 pub(crate) type Iv = lapper::Interval<u64, ()>;
@@ -144,6 +144,71 @@ impl<T: Send + Sync + Eq + Clone> StrandedPositionFilter<T> {
             .collect()
     }
 
+    /// Make fetch definitions that cover only the intervals in this filter,
+    /// used to traverse an indexed BAM region-by-region (`--regions`)
+    /// instead of over whole contigs. Unlike `optimize_reference_records`
+    /// neighboring intervals are never merged across gaps, so only the BGZF
+    /// blocks overlapping the intervals are read. Strand is ignored,
+    /// overlapping intervals are merged and intervals longer than
+    /// `interval_size` are split.
+    pub(crate) fn traversal_reference_records(
+        &self,
+        reference_records: Vec<ReferenceRecord>,
+        interval_size: u32,
+    ) -> Vec<ReferenceRecord> {
+        let interval_size = std::cmp::max(interval_size, 1);
+        reference_records
+            .into_iter()
+            .sorted_by_key(|rec| rec.tid)
+            .flat_map(|ref_record| {
+                let tid = &ref_record.tid;
+                let mut ivs = self
+                    .pos_positions
+                    .get(tid)
+                    .map(|ivs| ivs.intervals.clone())
+                    .unwrap_or_default();
+                let mut neg = self
+                    .neg_positions
+                    .get(tid)
+                    .map(|ivs| ivs.intervals.clone())
+                    .unwrap_or_default();
+                ivs.append(&mut neg);
+                let mut genome_intervals = GenomeIntervals::new(ivs);
+                genome_intervals.merge_overlaps();
+                let contig_start = ref_record.start as u64;
+                let contig_end = ref_record.end() as u64;
+                genome_intervals
+                    .intervals
+                    .into_iter()
+                    .filter_map(|iv| {
+                        let start = std::cmp::max(iv.start, contig_start);
+                        let stop = std::cmp::min(iv.stop, contig_end);
+                        (start < stop).then_some((start, stop))
+                    })
+                    .flat_map(|(start, stop)| {
+                        (start..stop).step_by(interval_size as usize).map(
+                            move |chunk_start| {
+                                let chunk_stop = std::cmp::min(
+                                    chunk_start + interval_size as u64,
+                                    stop,
+                                );
+                                (chunk_start, chunk_stop)
+                            },
+                        )
+                    })
+                    .map(|(start, stop)| {
+                        ReferenceRecord::new(
+                            ref_record.tid,
+                            start as u32,
+                            (stop - start) as u32,
+                            ref_record.name.clone(),
+                        )
+                    })
+                    .collect::<Vec<ReferenceRecord>>()
+            })
+            .collect()
+    }
+
     pub(crate) fn optimize_reference_records(
         &self,
         reference_records: Vec<ReferenceRecord>,
@@ -234,7 +299,6 @@ impl StrandedPositionFilter<()> {
     ) -> anyhow::Result<Self> {
         info!("parsing BED at {}", bed_fp.to_str().unwrap_or("invalid-UTF-8"));
 
-        let fh = File::open(bed_fp)?;
         let mut pos_positions = FxHashMap::default();
         let mut neg_positions = FxHashMap::default();
         let lines_processed = get_ticker();
@@ -245,7 +309,7 @@ impl StrandedPositionFilter<()> {
         lines_processed.set_message("rows processed");
         let mut warned = HashSet::new();
 
-        let reader = BufReader::new(fh);
+        let reader = open_text_input(bed_fp)?;
         for line in
             reader.lines().filter_map(|l| l.ok()).filter(|l| !l.is_empty())
         {
@@ -0,0 +1,204 @@
+use std::collections::BTreeSet;
+use std::path::Path;
+
+use anyhow::Context;
+use itertools::Itertools;
+use log::debug;
+use rust_htslib::bam::{self, Read};
+
+use crate::monoid::Moniod;
+use crate::util::header_to_hashmap;
+
+/// Programs in @PG records that make basecalls, a record with the ID
+/// `basecaller` is also counted.
+const BASECALLER_PROGRAMS: [&str; 3] = ["dorado", "guppy", "bonito"];
+
+/// Basecaller and model provenance of the modification calls in a modBAM,
+/// parsed from the read group (@RG) and program (@PG) header records.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct BasecallProvenance {
+    /// Basecallers from the @PG records, the program name and version.
+    pub basecallers: BTreeSet<String>,
+    /// Basecalling models, the `basecall_model` key in the `DS` tag of the
+    /// read groups.
+    pub basecall_models: BTreeSet<String>,
+    /// Modified base models, the comma-separated `modbase_models` key in
+    /// the `DS` tag of the read groups.
+    pub modbase_models: BTreeSet<String>,
+}
+
+impl BasecallProvenance {
+    pub fn from_header(header: &bam::Header) -> Self {
+        let header_map = match header_to_hashmap(header) {
+            Ok(header_map) => header_map,
+            Err(e) => {
+                debug!("failed to parse header for provenance, {e}");
+                return Self::default();
+            }
+        };
+        let mut provenance = Self::default();
+        let descriptions = header_map
+            .get("RG")
+            .into_iter()
+            .flatten()
+            .filter_map(|read_group| read_group.get("DS"));
+        for description in descriptions {
+            // dorado writes space-separated key=value pairs, e.g.
+            // runid=... basecall_model=dna_r10.4.1_e8.2_400bps_hac@v4.2.0
+            for (key, value) in description
+                .split_whitespace()
+                .filter_map(|key_value| key_value.split_once('='))
+            {
+                match key {
+                    "basecall_model" => {
+                        provenance.basecall_models.insert(value.to_string());
+                    }
+                    "modbase_models" => provenance.modbase_models.extend(
+                        value
+                            .split(',')
+                            .filter(|model| !model.is_empty())
+                            .map(|model| model.to_string()),
+                    ),
+                    _ => {}
+                }
+            }
+        }
+        for program in header_map.get("PG").into_iter().flatten() {
+            let id = program.get("ID").map(|id| id.as_str());
+            let Some(name) = program.get("PN").map(|pn| pn.as_str()).or(id)
+            else {
+                continue;
+            };
+            let is_basecaller = id == Some("basecaller")
+                || BASECALLER_PROGRAMS.iter().any(|basecaller| {
+                    name.to_lowercase().starts_with(basecaller)
+                });
+            if is_basecaller {
+                let basecaller = match program.get("VN") {
+                    Some(version) => format!("{name} {version}"),
+                    None => name.to_string(),
+                };
+                provenance.basecallers.insert(basecaller);
+            }
+        }
+        provenance
+    }
+
+    pub fn from_header_view(header: &bam::HeaderView) -> Self {
+        Self::from_header(&bam::Header::from_template(header))
+    }
+
+    /// Read the provenance from the header of a BAM or CRAM.
+    pub fn from_path<P: AsRef<Path>>(fp: P) -> anyhow::Result<Self> {
+        let fp = fp.as_ref();
+        let reader = bam::Reader::from_path(fp)
+            .with_context(|| format!("failed to read header of {fp:?}"))?;
+        Ok(Self::from_header_view(reader.header()))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The name and value of each kind of provenance that was found, when
+    /// there is more than one basecaller or model they are comma-separated.
+    pub(crate) fn fields(&self) -> Vec<(&'static str, String)> {
+        [
+            ("basecaller", &self.basecallers),
+            ("basecall_model", &self.basecall_models),
+            ("modbase_models", &self.modbase_models),
+        ]
+        .into_iter()
+        .filter(|(_, values)| !values.is_empty())
+        .map(|(name, values)| (name, values.iter().join(",")))
+        .collect()
+    }
+
+    /// Comment lines for the top of an output file, e.g.
+    /// `#basecall_model: dna_r10.4.1_e8.2_400bps_hac@v4.2.0`. Empty when no
+    /// provenance was found.
+    pub(crate) fn header_comment(&self) -> String {
+        self.fields().into_iter().fold(
+            String::new(),
+            |mut comment, (name, value)| {
+                comment.push_str(&format!("#{name}: {value}\n"));
+                comment
+            },
+        )
+    }
+}
+
+impl Moniod for BasecallProvenance {
+    fn zero() -> Self {
+        Self::default()
+    }
+
+    fn op(mut self, other: Self) -> Self {
+        self.op_mut(other);
+        self
+    }
+
+    fn op_mut(&mut self, other: Self) {
+        self.basecallers.extend(other.basecallers);
+        self.basecall_models.extend(other.basecall_models);
+        self.modbase_models.extend(other.modbase_models);
+    }
+
+    fn len(&self) -> usize {
+        self.basecallers.len()
+            + self.basecall_models.len()
+            + self.modbase_models.len()
+    }
+}
+
+#[cfg(test)]
+mod provenance_tests {
+    use std::collections::BTreeSet;
+
+    use rust_htslib::bam::{self, header::HeaderRecord};
+
+    use crate::provenance::BasecallProvenance;
+
+    #[test]
+    fn test_provenance_from_header() {
+        let mut header = bam::Header::new();
+        let mut read_group = HeaderRecord::new(b"RG");
+        read_group.push_tag(b"ID", &"run_1");
+        read_group.push_tag(
+            b"DS",
+            &"runid=run_1 basecall_model=dna_r10.4.1_e8.2_400bps_sup@v4.2.0 \
+              modbase_models=dna_r10.4.1_e8.2_400bps_sup@v4.2.0_5mCG_5hmCG@v2,\
+              dna_r10.4.1_e8.2_400bps_sup@v4.2.0_6mA@v2",
+        );
+        header.push_record(&read_group);
+        let mut basecaller = HeaderRecord::new(b"PG");
+        basecaller.push_tag(b"ID", &"basecaller");
+        basecaller.push_tag(b"PN", &"dorado");
+        basecaller.push_tag(b"VN", &"0.5.0");
+        header.push_record(&basecaller);
+        let mut aligner = HeaderRecord::new(b"PG");
+        aligner.push_tag(b"ID", &"aligner");
+        aligner.push_tag(b"PN", &"minimap2");
+        aligner.push_tag(b"VN", &"2.24");
+        header.push_record(&aligner);
+
+        let provenance = BasecallProvenance::from_header(&header);
+        assert_eq!(
+            provenance.basecallers,
+            BTreeSet::from(["dorado 0.5.0".to_string()])
+        );
+        assert_eq!(
+            provenance.basecall_models,
+            BTreeSet::from(["dna_r10.4.1_e8.2_400bps_sup@v4.2.0".to_string()])
+        );
+        assert_eq!(provenance.modbase_models.len(), 2);
+        assert_eq!(
+            provenance.header_comment().lines().next(),
+            Some("#basecaller: dorado 0.5.0")
+        );
+
+        let empty = BasecallProvenance::from_header(&bam::Header::new());
+        assert!(empty.is_empty());
+        assert!(empty.header_comment().is_empty());
+    }
+}
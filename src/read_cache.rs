@@ -30,6 +30,9 @@ pub(crate) struct ReadCache<'a> {
     /// these reads don't have mod tags or should be skipped for some other
     /// reason
     skip_set: HashSet<String>,
+    /// number of records in the skip set because their MM tag doesn't match
+    /// the sequence given by their MN tag
+    n_mm_mn_mismatch: usize,
     /// mapping of read_id (query_name) to the mod codes contained in that read
     pos_mod_codes: FxHashMap<String, PrimaryBaseToModCodes>,
     neg_mod_codes: FxHashMap<String, PrimaryBaseToModCodes>,
@@ -53,6 +56,7 @@ impl<'a> ReadCache<'a> {
             pos_reads: FxHashMap::default(),
             neg_reads: FxHashMap::default(),
             skip_set: HashSet::new(),
+            n_mm_mn_mismatch: 0,
             pos_mod_codes: FxHashMap::default(),
             neg_mod_codes: FxHashMap::default(),
             method,
@@ -80,6 +84,7 @@ impl<'a> ReadCache<'a> {
         let aligned_pairs = util::get_aligned_pairs_forward(&record)
             .filter_map(|ap| ap.ok())
             .collect::<FxHashMap<usize, u64>>();
+        let caller = self.caller.for_read_group(util::get_read_group(record));
 
         let ref_pos_base_mod_calls = seq_pos_base_mod_probs
             .pos_to_base_mod_probs
@@ -88,7 +93,7 @@ impl<'a> ReadCache<'a> {
             .flat_map(|(q_pos, bmp)| {
                 if let Some(r_pos) = aligned_pairs.get(&q_pos) {
                     // filtering happens here.
-                    let call = self.caller.call(&threshold_base, &bmp);
+                    let call = caller.call(&threshold_base, &bmp);
                     Some((*r_pos, call))
                 } else {
                     None
@@ -273,6 +278,10 @@ impl<'a> ReadCache<'a> {
                         Ok(_) => {}
                         Err(e) => {
                             debug!("{read_id}: {e}",);
+                            if let MkError::MmMnMismatch(reason) = &e {
+                                debug!("{read_id}: {reason}");
+                                self.n_mm_mn_mismatch += 1;
+                            }
                             self.skip_set.insert(read_id.clone());
                         }
                     }
@@ -327,6 +336,10 @@ impl<'a> ReadCache<'a> {
                         Ok(_) => {}
                         Err(e) => {
                             debug!("{read_id}: {e}",);
+                            if let MkError::MmMnMismatch(reason) = &e {
+                                debug!("{read_id}: {reason}");
+                                self.n_mm_mn_mismatch += 1;
+                            }
                             self.skip_set.insert(read_id.clone());
                         }
                     }
@@ -354,6 +367,12 @@ impl<'a> ReadCache<'a> {
         }
     }
 
+    /// Number of skipped records whose MM tag doesn't match the sequence
+    /// given by their MN tag.
+    pub(crate) fn get_mm_mn_mismatches(&self) -> usize {
+        self.n_mm_mn_mismatch
+    }
+
     pub(crate) fn get_records_used_and_skipped(&self) -> (usize, usize) {
         let used = self
             .pos_reads
@@ -465,6 +484,10 @@ impl<'a> DuplexReadCache<'a> {
     pub(crate) fn get_records_used_and_skipped(&self) -> (usize, usize) {
         self.read_cache.get_records_used_and_skipped()
     }
+
+    pub(crate) fn get_mm_mn_mismatches(&self) -> usize {
+        self.read_cache.n_mm_mn_mismatch
+    }
 }
 
 #[cfg(test)]
@@ -12,24 +12,27 @@ use rust_htslib::bam::record::Cigar;
 use rust_htslib::bam::{self, Read, Records};
 use rustc_hash::{FxHashMap, FxHashSet};
 
+use crate::bam_pipeline::run_read_ahead;
 use crate::errs::{MkError, MkResult};
+use crate::extract::features::FeatureLookup;
 use crate::mod_bam::{
-    prob_to_qual, BaseModCall, BaseModProbs, CollapseMethod, EdgeFilter,
-    ModBaseInfo, SeqPosBaseModProbs, SkipMode, TrackingModRecordIter,
-    WithModBaseInfos,
+    prob_to_qual, record_with_mod_base_info, BaseModCall, BaseModProbs,
+    CollapseMethod, EdgeFilter, ModBaseInfo, SeqPosBaseModProbs, SkipMode,
+    TrackingModRecordIter, WithModBaseInfos,
 };
 use crate::mod_base_code::{
     BaseAndState, BaseState, DnaBase, ModCodeRepr, ProbHistogram,
 };
 use crate::monoid::Moniod;
-use crate::motifs::motif_bed::MotifPositionLookup;
+use crate::motifs::motif_bed::{AmbiguousBases, MotifPositionLookup};
 use crate::position_filter::StrandedPositionFilter;
 use crate::reads_sampler::record_sampler::{Indicator, RecordSampler};
 use crate::record_processor::{RecordProcessor, WithRecords};
 use crate::util::{
     self, get_aligned_pairs_forward, get_master_progress_bar,
-    get_query_name_string, get_reference_mod_strand, get_ticker,
-    record_is_primary, Kmer, Strand, MISSING_SYMBOL, TAB,
+    get_query_name_string, get_read_group, get_reference_mod_strand,
+    get_ticker, record_is_primary, thymine_to_uracil_label, Kmer, Strand,
+    MISSING_SYMBOL, TAB,
 };
 
 /// Read IDs mapped to their base modification probabilities, organized
@@ -41,6 +44,12 @@ pub(crate) struct ReadIdsToBaseModProbs {
     // mapping of read id to canonical base mapped to a vec
     // of base mod calls on that canonical base
     pub(crate) inner: HashMap<String, HashMap<DnaBase, Vec<BaseModProbs>>>,
+    // for mapped reads, the reference strand of the calls in `inner` as
+    // runs of (strand, number of calls) in the same order as the calls
+    pub(crate) ref_strands:
+        HashMap<String, HashMap<DnaBase, Vec<(Strand, usize)>>>,
+    // read group (RG tag) of each read that has one
+    pub(crate) read_groups: HashMap<String, String>,
 }
 
 impl ReadIdsToBaseModProbs {
@@ -52,8 +61,17 @@ impl ReadIdsToBaseModProbs {
         &mut self,
         read_id: &str,
         canonical_base: DnaBase,
+        ref_strand: Option<Strand>,
         mod_probs: Vec<BaseModProbs>,
     ) {
+        if let Some(ref_strand) = ref_strand {
+            self.ref_strands
+                .entry(read_id.to_owned())
+                .or_default()
+                .entry(canonical_base)
+                .or_default()
+                .push((ref_strand, mod_probs.len()));
+        }
         self.inner
             .entry(read_id.to_owned())
             .or_insert(HashMap::new())
@@ -62,6 +80,32 @@ impl ReadIdsToBaseModProbs {
             .extend(mod_probs)
     }
 
+    /// Most likely probabilities for the base modifications on each
+    /// canonical base of one read.
+    fn read_mle_probs(
+        can_base_to_base_mod_probs: &HashMap<DnaBase, Vec<BaseModProbs>>,
+    ) -> HashMap<DnaBase, Vec<f32>> {
+        can_base_to_base_mod_probs
+            .iter()
+            .map(|(canonical_base, base_mod_probs)| {
+                let probs = base_mod_probs
+                    .iter()
+                    .map(|bmc| match bmc.argmax_base_mod_call() {
+                        BaseModCall::Modified(f, _) => f,
+                        BaseModCall::Canonical(f) => f,
+                        BaseModCall::Filtered => {
+                            unreachable!(
+                                "argmax base mod call should not return \
+                                 Filtered"
+                            )
+                        }
+                    })
+                    .collect::<Vec<f32>>();
+                (*canonical_base, probs)
+            })
+            .collect::<HashMap<DnaBase, Vec<f32>>>()
+    }
+
     #[inline]
     /// Returns most likely probabilities for base modifications predicted for
     /// each canonical base.
@@ -78,29 +122,35 @@ impl ReadIdsToBaseModProbs {
             .par_iter()
             .progress_with(pb)
             .map(|(_, can_base_to_base_mod_probs)| {
-                can_base_to_base_mod_probs
-                    .iter()
-                    .map(|(canonical_base, base_mod_probs)| {
-                        let probs = base_mod_probs
-                            .iter()
-                            .map(|bmc| match bmc.argmax_base_mod_call() {
-                                BaseModCall::Modified(f, _) => f,
-                                BaseModCall::Canonical(f) => f,
-                                BaseModCall::Filtered => {
-                                    unreachable!(
-                                        "argmax base mod call should not \
-                                         return Filtered"
-                                    )
-                                }
-                            })
-                            .collect::<Vec<f32>>();
-                        (*canonical_base, probs)
-                    })
-                    .collect::<HashMap<DnaBase, Vec<f32>>>()
+                Self::read_mle_probs(can_base_to_base_mod_probs)
             })
             .reduce(|| HashMap::zero(), |a, b| a.op(b))
     }
 
+    /// Same as [`Self::mle_probs_per_base`] but grouped by the read group of
+    /// each read, reads without a read group are not included.
+    pub(crate) fn mle_probs_per_base_per_read_group(
+        &self,
+    ) -> HashMap<String, HashMap<DnaBase, Vec<f32>>> {
+        self.inner
+            .par_iter()
+            .filter_map(|(read_id, can_base_to_base_mod_probs)| {
+                self.read_groups.get(read_id).map(|read_group| {
+                    HashMap::from([(
+                        read_group.to_owned(),
+                        Self::read_mle_probs(can_base_to_base_mod_probs),
+                    )])
+                })
+            })
+            .reduce(HashMap::new, |mut a, b| {
+                for (read_group, probs) in b {
+                    let agg = a.entry(read_group).or_default();
+                    agg.op_mut(probs);
+                }
+                a
+            })
+    }
+
     /// return argmax probs for each mod-code
     pub(crate) fn mle_probs_per_base_mod(
         &self,
@@ -190,11 +240,32 @@ impl ReadIdsToBaseModProbs {
     pub(crate) fn seen(&self, record_name: &str) -> bool {
         self.inner.contains_key(record_name)
     }
+
+    /// The reference strand of each of the base modification calls on
+    /// `canonical_base` for a read, in the same order as the calls. Returns
+    /// `None` when the read is unmapped.
+    pub(crate) fn iter_ref_strands<'a>(
+        &'a self,
+        read_id: &str,
+        canonical_base: &DnaBase,
+    ) -> Option<impl Iterator<Item = Strand> + 'a> {
+        self.ref_strands
+            .get(read_id)
+            .and_then(|per_base| per_base.get(canonical_base))
+            .map(|runs| {
+                runs.iter()
+                    .flat_map(|(strand, n)| std::iter::repeat(*strand).take(*n))
+            })
+    }
 }
 
 impl Moniod for ReadIdsToBaseModProbs {
     fn zero() -> Self {
-        Self { inner: HashMap::new() }
+        Self {
+            inner: HashMap::new(),
+            ref_strands: HashMap::new(),
+            read_groups: HashMap::new(),
+        }
     }
 
     fn op(self, other: Self) -> Self {
@@ -204,10 +275,18 @@ impl Moniod for ReadIdsToBaseModProbs {
     }
 
     fn op_mut(&mut self, other: Self) {
+        let mut other_ref_strands = other.ref_strands;
+        let mut other_read_groups = other.read_groups;
         for (read_id, base_mod_calls) in other.inner {
             if self.inner.contains_key(&read_id) {
                 continue;
             } else {
+                if let Some(ref_strands) = other_ref_strands.remove(&read_id) {
+                    self.ref_strands.insert(read_id.clone(), ref_strands);
+                }
+                if let Some(read_group) = other_read_groups.remove(&read_id) {
+                    self.read_groups.insert(read_id.clone(), read_group);
+                }
                 self.inner.insert(read_id, base_mod_calls);
             }
         }
@@ -218,6 +297,125 @@ impl Moniod for ReadIdsToBaseModProbs {
     }
 }
 
+/// The base modification probabilities of a record, filtered and collapsed,
+/// ready to be added to a [`ReadIdsToBaseModProbs`].
+struct RecordModProbs {
+    record_name: String,
+    read_group: Option<String>,
+    /// Probabilities by canonical base and reference strand, `None` when
+    /// the record has no base modification information.
+    mod_probs: Option<Vec<(DnaBase, Option<Strand>, Vec<BaseModProbs>)>>,
+}
+
+impl RecordModProbs {
+    fn new(
+        record: &bam::Record,
+        mod_base_info: ModBaseInfo,
+        collapse_method: Option<&CollapseMethod>,
+        edge_filter: Option<&EdgeFilter>,
+        position_filter: Option<&StrandedPositionFilter<()>>,
+        only_mapped: bool,
+    ) -> Option<Self> {
+        let Ok(record_name) = get_query_name_string(record) else {
+            debug!("record name failed UTF-8 decode");
+            return None;
+        };
+        let read_group = get_read_group(record).map(|rg| rg.to_owned());
+        if mod_base_info.is_empty() {
+            return Some(Self { record_name, read_group, mod_probs: None });
+        }
+        let aligned_pairs = if only_mapped {
+            get_aligned_pairs_forward(record)
+                .filter_map(|pair| pair.ok())
+                .collect::<FxHashMap<usize, u64>>()
+        } else {
+            FxHashMap::default()
+        };
+        let (_, base_mod_probs_iter) = mod_base_info.into_iter_base_mod_probs();
+        let alignment_strand = if record.is_unmapped() {
+            None
+        } else if record.is_reverse() {
+            Some(Strand::Negative)
+        } else {
+            Some(Strand::Positive)
+        };
+        let mod_probs = base_mod_probs_iter
+            .filter_map(|(dna_base, strand, seq_pos_base_mod_probs)| {
+                let canonical_base = match strand {
+                    Strand::Positive => dna_base,
+                    Strand::Negative => dna_base.complement(),
+                };
+                // must stay such that mod_probs will not be empty if
+                // seq_pos_base_mod_probs is Some, otherwise the record
+                // would count as used without any probabilities
+                let seq_pos_base_mod_probs = seq_pos_base_mod_probs
+                    .filter_positions(
+                        edge_filter,
+                        position_filter,
+                        only_mapped,
+                        &aligned_pairs,
+                        strand,
+                        record,
+                    )?;
+                let mod_probs = seq_pos_base_mod_probs
+                    .pos_to_base_mod_probs
+                    .into_iter()
+                    .map(|(_q_pos, base_mod_probs)| {
+                        if let Some(method) = collapse_method {
+                            base_mod_probs.into_collapsed(method)
+                        } else {
+                            base_mod_probs
+                        }
+                    })
+                    .collect::<Vec<BaseModProbs>>();
+                let ref_strand = alignment_strand
+                    .map(|s| get_reference_mod_strand(strand, s));
+                Some((canonical_base, ref_strand, mod_probs))
+            })
+            .collect::<Vec<_>>();
+        Some(Self { record_name, read_group, mod_probs: Some(mod_probs) })
+    }
+}
+
+impl ReadIdsToBaseModProbs {
+    /// Returns `None` when the record was already added or has no base
+    /// modification information, otherwise whether any probabilities were
+    /// added for it.
+    fn add_record_mod_probs(
+        &mut self,
+        record_mod_probs: RecordModProbs,
+    ) -> Option<bool> {
+        let RecordModProbs { record_name, read_group, mod_probs } =
+            record_mod_probs;
+        if self.seen(&record_name) {
+            debug!(
+                "record: {record_name}, already processed, consider \
+                 de-duplicating alignments."
+            );
+            return None;
+        }
+        let Some(mod_probs) = mod_probs else {
+            // the record iterators should filter these out, but leaving this
+            // check here in case that changes
+            self.add_read_without_probs(&record_name);
+            return None;
+        };
+        if let Some(read_group) = read_group {
+            self.read_groups.insert(record_name.clone(), read_group);
+        }
+        let added_probs_for_record = !mod_probs.is_empty();
+        for (canonical_base, ref_strand, mod_probs) in mod_probs {
+            self.add_mod_probs_for_read(
+                &record_name,
+                canonical_base,
+                ref_strand,
+                mod_probs,
+            );
+        }
+        Some(added_probs_for_record)
+    }
+}
+
 impl RecordProcessor for ReadIdsToBaseModProbs {
     type Output = Self;
 
@@ -258,101 +456,119 @@ impl RecordProcessor for ReadIdsToBaseModProbs {
         for (record, mod_base_info) in mod_base_info_iter {
             match record_sampler.ask() {
                 Indicator::Use(token) => {
-                    let record_name = get_query_name_string(&record);
-                    let aligned_pairs = if only_mapped {
-                        get_aligned_pairs_forward(&record)
-                            .filter_map(|pair| pair.ok())
-                            .collect::<FxHashMap<usize, u64>>()
-                    } else {
-                        FxHashMap::default()
-                    };
-                    if record_name.is_err() {
-                        debug!("record name failed UTF-8 decode");
-                        continue;
-                    }
-                    let record_name = record_name.unwrap();
-                    if read_ids_to_mod_base_probs.seen(&record_name) {
-                        debug!(
-                            "record: {record_name}, already processed, \
-                             consider de-duplicating alignments."
-                        );
+                    let Some(record_mod_probs) = RecordModProbs::new(
+                        &record,
+                        mod_base_info,
+                        collapse_method,
+                        edge_filter,
+                        position_filter,
+                        only_mapped,
+                    ) else {
                         continue;
-                    }
-                    if mod_base_info.is_empty() {
-                        // the current iterator should filter these out, but
-                        // leaving this check
-                        // here in case that changes..
-                        // add count of unused/no calls
-                        // debug!("record {record_name} contains no mod-base
-                        // information");
+                    };
+                    if let Some(added_probs_for_record) =
                         read_ids_to_mod_base_probs
-                            .add_read_without_probs(&record_name);
-                        continue;
+                            .add_record_mod_probs(record_mod_probs)
+                    {
+                        if let Some(pb) = &spinner {
+                            pb.inc(1);
+                        }
+                        if added_probs_for_record {
+                            record_sampler.used(token);
+                        }
                     }
+                }
+                Indicator::Skip => continue,
+                Indicator::Done => break,
+            }
+        }
 
-                    let (_, base_mod_probs_iter) =
-                        mod_base_info.into_iter_base_mod_probs();
-                    let mut added_probs_for_record = false;
-                    for (dna_base, strand, seq_pos_base_mod_probs) in
-                        base_mod_probs_iter
-                    {
-                        let canonical_base = match strand {
-                            Strand::Positive => dna_base,
-                            Strand::Negative => dna_base.complement(),
-                        };
+        if let Some(pb) = &spinner {
+            pb.finish_and_clear();
+        }
 
-                        let seq_pos_base_mod_probs = seq_pos_base_mod_probs
-                            .filter_positions(
-                                edge_filter,
-                                position_filter,
-                                only_mapped,
-                                &aligned_pairs,
-                                strand,
-                                &record,
-                            );
+        Ok(read_ids_to_mod_base_probs)
+    }
 
-                        // must stay such that mod_probs will not be empty if
-                        // seq_pos_base_mod_probs
-                        // is Some otherwise added_mod_probs_for_record should
-                        // not be flipped to true
-                        if let Some(seq_pos_base_mod_probs) =
-                            seq_pos_base_mod_probs
-                        {
-                            let mod_probs = seq_pos_base_mod_probs
-                                .pos_to_base_mod_probs
-                                .into_iter()
-                                .map(|(_q_pos, base_mod_probs)| {
-                                    if let Some(method) = collapse_method {
-                                        base_mod_probs.into_collapsed(method)
-                                    } else {
-                                        base_mod_probs
-                                    }
-                                })
-                                .collect::<Vec<BaseModProbs>>();
-                            read_ids_to_mod_base_probs.add_mod_probs_for_read(
-                                &record_name,
-                                canonical_base,
-                                mod_probs,
-                            );
-                            added_probs_for_record = true;
-                        } else {
-                            // trace!("all base mod positions were removed by
-                            // filtering \
-                            //     for {record_name} and base
-                            // {raw_canonical_base}");
-                            continue;
+    fn process_records_read_ahead<T: Read + Send>(
+        records: Records<T>,
+        with_progress: bool,
+        mut record_sampler: RecordSampler,
+        collapse_method: Option<&CollapseMethod>,
+        edge_filter: Option<&EdgeFilter>,
+        position_filter: Option<&StrandedPositionFilter<()>>,
+        only_mapped: bool,
+        allow_non_primary: bool,
+    ) -> anyhow::Result<Self::Output> {
+        let spinner = if with_progress {
+            Some(record_sampler.get_progress_bar())
+        } else {
+            None
+        };
+        // the same filters as `process_records`, the records are decoded
+        // before asking the sampler so that they can be decoded in parallel,
+        // but they're still asked in input order so the same records are
+        // sampled
+        let decode = |result| {
+            let (record, mod_base_info) =
+                match record_with_mod_base_info(result) {
+                    Ok(Some(item)) => item,
+                    Ok(None) => return Ok(None),
+                    Err(()) => return Err(()),
+                };
+            if (only_mapped || edge_filter.is_some()) && record.is_unmapped() {
+                return Ok(None);
+            }
+            if !allow_non_primary && !record_is_primary(&record) {
+                return Ok(None);
+            }
+            Ok(Some(RecordModProbs::new(
+                &record,
+                mod_base_info,
+                collapse_method,
+                edge_filter,
+                position_filter,
+                only_mapped,
+            )))
+        };
+        let mut num_errors = 0usize;
+        let mut read_ids_to_mod_base_probs = Self::zero();
+        let sink = |decoded: Result<Option<Option<RecordModProbs>>, ()>| {
+            let record_mod_probs = match decoded {
+                Ok(Some(record_mod_probs)) => record_mod_probs,
+                Ok(None) => return Ok(true),
+                Err(()) => {
+                    num_errors += 1;
+                    return Ok(true);
+                }
+            };
+            match record_sampler.ask() {
+                Indicator::Use(token) => {
+                    let Some(record_mod_probs) = record_mod_probs else {
+                        return Ok(true);
+                    };
+                    if let Some(added_probs_for_record) =
+                        read_ids_to_mod_base_probs
+                            .add_record_mod_probs(record_mod_probs)
+                    {
+                        if let Some(pb) = &spinner {
+                            pb.inc(1);
+                        }
+                        if added_probs_for_record {
+                            record_sampler.used(token);
                         }
                     }
-                    if let Some(pb) = &spinner {
-                        pb.inc(1);
-                    }
-                    if added_probs_for_record {
-                        record_sampler.used(token);
-                    }
+                    Ok(true)
                 }
-                Indicator::Skip => continue,
-                Indicator::Done => break,
+                Indicator::Skip => Ok(true),
+                Indicator::Done => Ok(false),
             }
+        };
+        run_read_ahead(records, decode, sink)?;
+        if num_errors > 0 {
+            debug!(
+                "{num_errors} records failed, consider checking mod base tags"
+            );
         }
 
         if let Some(pb) = &spinner {
@@ -380,6 +596,128 @@ impl WithRecords for ReadIdsToBaseModProbs {
     }
 }
 
+/// The kind of CIGAR operation a base modification call is in.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub(crate) enum CigarOpContext {
+    /// Aligned to the reference (M, =, or X).
+    Match,
+    Insertion,
+    SoftClip,
+    #[default]
+    Unmapped,
+}
+
+impl CigarOpContext {
+    fn label(&self) -> &'static str {
+        match self {
+            Self::Match => "match",
+            Self::Insertion => "insertion",
+            Self::SoftClip => "soft_clip",
+            Self::Unmapped => MISSING_SYMBOL,
+        }
+    }
+}
+
+/// Where a base modification call sits in the alignment, derived from the
+/// CIGAR so that calls near alignment artifacts can be excluded.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub(crate) struct CigarContext {
+    pub(crate) op: CigarOpContext,
+    /// Distance, in read bases, to the nearest insertion or deletion. 0 when
+    /// the call is in an insertion and 1 when it is next to an insertion or
+    /// deletion. `None` when the read has no indels or is unmapped.
+    pub(crate) indel_distance: Option<usize>,
+}
+
+impl CigarContext {
+    pub(crate) const COLUMNS: [&'static str; 2] =
+        ["cigar_context", "indel_distance"];
+
+    pub(crate) fn to_columns(self) -> String {
+        format!(
+            "{}{TAB}{}",
+            self.op.label(),
+            self.indel_distance.map(|d| d as i64).unwrap_or(-1i64)
+        )
+    }
+}
+
+/// The CIGAR operations and indels of an alignment in (alignment-oriented)
+/// query coordinates. Deletions are empty ranges at the query position
+/// following the deletion.
+struct CigarContextLookup {
+    ops: Vec<(std::ops::Range<usize>, CigarOpContext)>,
+    indels: Vec<std::ops::Range<usize>>,
+}
+
+impl CigarContextLookup {
+    fn from_record(record: &bam::Record) -> Option<Self> {
+        if record.is_unmapped() {
+            return None;
+        }
+        Some(Self::from_cigar(record.cigar().iter()))
+    }
+
+    fn from_cigar<'a>(cigar: impl IntoIterator<Item = &'a Cigar>) -> Self {
+        let mut ops = Vec::new();
+        let mut indels = Vec::new();
+        let mut qpos = 0usize;
+        for op in cigar {
+            let len = op.len() as usize;
+            let (op_context, is_indel) = match op {
+                Cigar::Match(_) | Cigar::Equal(_) | Cigar::Diff(_) => {
+                    (CigarOpContext::Match, false)
+                }
+                Cigar::Ins(_) => (CigarOpContext::Insertion, true),
+                Cigar::SoftClip(_) => (CigarOpContext::SoftClip, false),
+                Cigar::Del(_) => {
+                    indels.push(qpos..qpos);
+                    continue;
+                }
+                Cigar::RefSkip(_) | Cigar::HardClip(_) | Cigar::Pad(_) => {
+                    continue
+                }
+            };
+            if is_indel {
+                indels.push(qpos..(qpos + len));
+            }
+            ops.push((qpos..(qpos + len), op_context));
+            qpos += len;
+        }
+        Self { ops, indels }
+    }
+
+    /// Context of the base at `qpos`, in alignment-oriented query
+    /// coordinates.
+    fn get(&self, qpos: usize) -> CigarContext {
+        let op = self
+            .ops
+            .get(self.ops.partition_point(|(r, _)| r.end <= qpos))
+            .filter(|(r, _)| r.contains(&qpos))
+            .map(|(_, op)| *op)
+            .unwrap_or_default();
+        // indels don't overlap and are sorted, so the nearest one is either
+        // the last one that ends at or before qpos or the next one
+        let idx = self.indels.partition_point(|r| r.end <= qpos);
+        let distance = |r: &std::ops::Range<usize>| {
+            if r.contains(&qpos) {
+                0
+            } else if qpos < r.start {
+                r.start - qpos
+            } else {
+                qpos - r.end + 1
+            }
+        };
+        let indel_distance = [idx.checked_sub(1), Some(idx)]
+            .into_iter()
+            .flatten()
+            .filter_map(|i| self.indels.get(i))
+            .map(distance)
+            .min();
+        CigarContext { op, indel_distance }
+    }
+}
+
 #[derive(new, Debug)]
 pub(crate) struct ModProfile {
     pub(crate) query_position: usize,
@@ -395,10 +733,16 @@ pub(crate) struct ModProfile {
     pub(crate) alignment_strand: Option<Strand>,
     pub(crate) canonical_base: DnaBase,
     pub(crate) inferred: bool,
+    #[new(default)]
+    pub(crate) cigar_context: CigarContext,
 }
 
 impl ModProfile {
-    pub(crate) fn header(with_motifs: bool) -> String {
+    pub(crate) fn header(
+        with_motifs: bool,
+        with_cigar_context: bool,
+        with_features: bool,
+    ) -> String {
         let mut fields = vec![
             "read_id",
             "forward_read_position",
@@ -425,6 +769,12 @@ impl ModProfile {
         if with_motifs {
             fields.push("motifs")
         }
+        if with_cigar_context {
+            fields.extend(CigarContext::COLUMNS)
+        }
+        if with_features {
+            fields.extend(FeatureLookup::COLUMNS)
+        }
         fields.join(&TAB.to_string())
     }
 
@@ -448,6 +798,10 @@ impl ModProfile {
         flag: u16,
         motif_positions_lookup: Option<&MotifPositionLookup>,
         with_motifs: bool,
+        rna_labels: bool,
+        ambiguous_bases: AmbiguousBases,
+        with_cigar_context: bool,
+        feature_lookup: Option<&FeatureLookup>,
     ) -> String {
         let query_kmer = format!("{}", self.query_kmer);
         let motif_hits = motif_positions_lookup.and_then(|lu| {
@@ -473,8 +827,10 @@ impl ModProfile {
                 reference_seqs
                     .get(chrom_name)
                     .map(|s| {
-                        Kmer::from_seq(s, ref_pos as usize, kmer_size)
-                            .to_string()
+                        ambiguous_bases.ref_kmer(
+                            Kmer::from_seq(s, ref_pos as usize, kmer_size)
+                                .to_string(),
+                        )
                     })
                     .unwrap_or(".".to_string())
             }
@@ -487,6 +843,24 @@ impl ModProfile {
             self.canonical_base.char()
         };
 
+        let (ref_kmer, query_kmer, canonical_base, modified_primary_base) =
+            if rna_labels {
+                (
+                    thymine_to_uracil_label(&ref_kmer),
+                    thymine_to_uracil_label(&query_kmer),
+                    thymine_to_uracil_label(
+                        &self.canonical_base.char().to_string(),
+                    ),
+                    thymine_to_uracil_label(&modified_primary_base.to_string()),
+                )
+            } else {
+                (
+                    ref_kmer,
+                    query_kmer,
+                    self.canonical_base.char().to_string(),
+                    modified_primary_base.to_string(),
+                )
+            };
         let _within_alignment = self.within_alignment();
         let mut s = format!(
             "\
@@ -528,7 +902,7 @@ impl ModProfile {
             self.q_base,
             ref_kmer,
             query_kmer,
-            self.canonical_base.char(),
+            canonical_base,
             modified_primary_base,
             self.inferred,
             flag,
@@ -543,6 +917,17 @@ impl ModProfile {
                 s.push_str(MISSING_SYMBOL);
             }
         }
+        if with_cigar_context {
+            s.push(TAB);
+            s.push_str(&self.cigar_context.to_columns());
+        }
+        if let Some(feature_lookup) = feature_lookup {
+            s.push(TAB);
+            s.push_str(
+                &feature_lookup
+                    .to_columns(chrom_name, self.ref_position.unwrap_or(-1)),
+            );
+        }
 
         s.push_str("\n");
         s
@@ -557,9 +942,37 @@ pub(crate) struct ReadBaseModProfile {
     pub(crate) alignment_start: Option<u64>,
     pub(crate) alignment_end: Option<u64>,
     pub(crate) profile: Vec<ModProfile>,
+    /// The record the profile was made from, only kept when requested.
+    #[new(default)]
+    pub(crate) record: Option<bam::Record>,
+    /// Motif positions the read is aligned to without a call, only found
+    /// when requested.
+    #[new(default)]
+    pub(crate) no_calls: Vec<NoCallSite>,
+}
+
+/// A reference motif position that a read is aligned to, at a base the read
+/// has calls for, but without a base modification call.
+#[derive(Debug)]
+pub(crate) struct NoCallSite {
+    pub(crate) query_position: usize,
+    pub(crate) ref_position: i64,
+    pub(crate) num_soft_clipped_start: usize,
+    pub(crate) num_soft_clipped_end: usize,
+    pub(crate) read_length: usize,
+    pub(crate) q_base: u8,
+    pub(crate) query_kmer: Kmer,
+    pub(crate) mod_strand: Strand,
+    pub(crate) alignment_strand: Strand,
+    pub(crate) canonical_base: DnaBase,
+    pub(crate) cigar_context: CigarContext,
 }
 
 impl ReadBaseModProfile {
+    pub(crate) fn with_record(self, record: Option<bam::Record>) -> Self {
+        Self { record, ..self }
+    }
+
     #[cfg(test)]
     pub(crate) fn from_record(
         record: &bam::Record,
@@ -606,12 +1019,13 @@ impl ReadBaseModProfile {
         alignment_strand: Option<Strand>,
         num_clip_start: usize,
         num_clip_end: usize,
+        cigar_context: CigarContext,
     ) -> Vec<ModProfile> {
         let inferred = base_mod_probs.inferred_unmodified;
         base_mod_probs
             .iter_probs()
             .map(|(raw_mod_code, prob)| {
-                ModProfile::new(
+                let mut mod_profile = ModProfile::new(
                     query_pos_forward,
                     ref_pos,
                     num_clip_start,
@@ -625,7 +1039,9 @@ impl ReadBaseModProfile {
                     alignment_strand,
                     primary_base,
                     inferred,
-                )
+                );
+                mod_profile.cigar_context = cigar_context;
+                mod_profile
             })
             .collect::<Vec<ModProfile>>()
     }
@@ -705,6 +1121,8 @@ impl ReadBaseModProfile {
                 .collect::<HashMap<usize, (usize, Option<i64>)>>()
         };
 
+        let cigar_contexts = CigarContextLookup::from_record(record);
+
         let quals = if record.is_reverse() {
             record.qual().to_vec().into_iter().rev().collect()
         } else {
@@ -768,6 +1186,17 @@ impl ReadBaseModProfile {
                                 );
                                 0u8
                             });
+                        let cigar_context = cigar_contexts
+                            .as_ref()
+                            .map(|lookup| {
+                                let qpos = if record.is_reverse() {
+                                    seq_len.saturating_sub(forward_pos + 1)
+                                } else {
+                                    forward_pos
+                                };
+                                lookup.get(qpos)
+                            })
+                            .unwrap_or_default();
                         Self::base_mod_probs_to_mod_profile(
                             forward_pos,
                             primary_base,
@@ -780,6 +1209,7 @@ impl ReadBaseModProfile {
                             alignment_strand,
                             num_clip_start,
                             num_clip_end,
+                            cigar_context,
                         )
                     })
                     .collect::<Vec<ModProfile>>()
@@ -810,9 +1240,133 @@ impl ReadBaseModProfile {
             alignment_start,
             alignment_end,
             profile: mod_profiles,
+            record: None,
+            no_calls: Vec::new(),
         })
     }
 
+    /// Find the positions in `record` where `is_site` is true for the
+    /// reference position and strand that don't have a row in the profile.
+    /// Only aligned bases that the read has calls for (on the same strand)
+    /// are considered, e.g. only C's for a read with 5mC calls.
+    pub(crate) fn find_no_calls(
+        &self,
+        record: &bam::Record,
+        kmer_size: usize,
+        is_site: impl Fn(u32, u64, Strand) -> bool,
+    ) -> Vec<NoCallSite> {
+        let Some(chrom_id) = self.chrom_id.filter(|_| !record.is_unmapped())
+        else {
+            return Vec::new();
+        };
+        let called_bases = match ModBaseInfo::new_from_record(record) {
+            Ok(mod_base_info) => mod_base_info
+                .pos_seq_base_mod_probs
+                .keys()
+                .map(|base| (*base, Strand::Positive))
+                .chain(
+                    mod_base_info
+                        .neg_seq_base_mod_probs
+                        .keys()
+                        .map(|base| (*base, Strand::Negative)),
+                )
+                .collect::<FxHashSet<(DnaBase, Strand)>>(),
+            Err(e) => {
+                debug!(
+                    "record: {}, failed to parse mod tags for no-calls, {e}",
+                    self.record_name
+                );
+                return Vec::new();
+            }
+        };
+        let covered = self
+            .profile
+            .iter()
+            .filter_map(|p| p.ref_position.map(|pos| (pos, p.mod_strand)))
+            .collect::<FxHashSet<(i64, Strand)>>();
+
+        let read_length = record.seq_len();
+        let Ok((clip_start, clip_end)) =
+            ReadsBaseModProfile::get_soft_clipped(record)
+        else {
+            return Vec::new();
+        };
+        let (alignment_strand, num_clip_start, num_clip_end) =
+            if record.is_reverse() {
+                (Strand::Negative, clip_end, clip_start)
+            } else {
+                (Strand::Positive, clip_start, clip_end)
+            };
+        let quals = if record.is_reverse() {
+            record.qual().iter().rev().copied().collect()
+        } else {
+            record.qual().to_vec()
+        };
+        let forward_sequence = if record.is_reverse() {
+            revcomp(record.seq().as_bytes())
+        } else {
+            record.seq().as_bytes()
+        };
+        let cigar_contexts = CigarContextLookup::from_record(record);
+
+        record
+            .aligned_pairs()
+            .flat_map(|[qpos, ref_pos]| {
+                [Strand::Positive, Strand::Negative]
+                    .map(|strand| (qpos as usize, ref_pos, strand))
+            })
+            .filter_map(|(qpos, ref_pos, ref_strand)| {
+                if !is_site(chrom_id, ref_pos as u64, ref_strand) {
+                    return None;
+                }
+                // the mapping between read and reference strands is its own
+                // inverse
+                let mod_strand =
+                    get_reference_mod_strand(ref_strand, alignment_strand);
+                if covered.contains(&(ref_pos, mod_strand)) {
+                    return None;
+                }
+                let forward_pos = if record.is_reverse() {
+                    read_length.checked_sub(qpos + 1)?
+                } else {
+                    qpos
+                };
+                let read_base =
+                    DnaBase::parse(*forward_sequence.get(forward_pos)? as char)
+                        .ok()?;
+                let canonical_base = if mod_strand == Strand::Negative {
+                    read_base.complement()
+                } else {
+                    read_base
+                };
+                if !called_bases.contains(&(canonical_base, mod_strand)) {
+                    return None;
+                }
+                Some(NoCallSite {
+                    query_position: forward_pos,
+                    ref_position: ref_pos,
+                    num_soft_clipped_start: num_clip_start,
+                    num_soft_clipped_end: num_clip_end,
+                    read_length,
+                    q_base: quals.get(forward_pos).copied().unwrap_or(0u8),
+                    query_kmer: Self::get_kmer_from_sequence(
+                        &forward_sequence,
+                        forward_pos,
+                        mod_strand,
+                        kmer_size,
+                    ),
+                    mod_strand,
+                    alignment_strand,
+                    canonical_base,
+                    cigar_context: cigar_contexts
+                        .as_ref()
+                        .map(|lookup| lookup.get(qpos))
+                        .unwrap_or_default(),
+                })
+            })
+            .collect()
+    }
+
     pub(crate) fn remove_inferred(self) -> Self {
         let profile =
             self.profile.into_iter().filter(|p| !p.inferred).collect();
@@ -824,6 +1378,7 @@ impl ReadBaseModProfile {
             self.alignment_end,
             profile,
         )
+        .with_record(self.record)
     }
 
     fn primary_alignment(&self) -> bool {
@@ -850,6 +1405,10 @@ pub(crate) struct ReadsBaseModProfile {
     pub(crate) profiles: Vec<ReadBaseModProfile>,
     pub(crate) num_skips: usize,
     pub(crate) num_fails: usize,
+    /// Records that failed because their MM tag doesn't match the sequence
+    /// described by their MN tag, included in `num_fails`.
+    #[new(default)]
+    pub(crate) num_mm_mn_mismatch: usize,
 }
 
 impl ReadsBaseModProfile {
@@ -878,13 +1437,24 @@ impl ReadsBaseModProfile {
     pub(crate) fn remove_inferred(self) -> Self {
         let profiles =
             self.profiles.into_iter().map(|p| p.remove_inferred()).collect();
-        Self::new(profiles, self.num_skips, self.num_fails)
+        Self { profiles, ..self }
+    }
+
+    /// Take the records kept alongside the profiles, see
+    /// `RecordSampler::with_keep_records`.
+    pub(crate) fn take_records(&mut self) -> Vec<bam::Record> {
+        self.profiles.iter_mut().filter_map(|p| p.record.take()).collect()
     }
 }
 
 impl Moniod for ReadsBaseModProfile {
     fn zero() -> Self {
-        Self { profiles: Vec::new(), num_skips: 0, num_fails: 0 }
+        Self {
+            profiles: Vec::new(),
+            num_skips: 0,
+            num_fails: 0,
+            num_mm_mn_mismatch: 0,
+        }
     }
 
     fn op(self, other: Self) -> Self {
@@ -904,7 +1474,9 @@ impl Moniod for ReadsBaseModProfile {
 
         let num_skips = self.num_skips + other.num_skips;
         let num_fails = self.num_fails + other.num_fails;
-        Self { profiles, num_skips, num_fails }
+        let num_mm_mn_mismatch =
+            self.num_mm_mn_mismatch + other.num_mm_mn_mismatch;
+        Self { profiles, num_skips, num_fails, num_mm_mn_mismatch }
     }
 
     fn op_mut(&mut self, other: Self) {
@@ -923,6 +1495,7 @@ impl Moniod for ReadsBaseModProfile {
 
         self.num_skips += other.num_skips;
         self.num_fails += other.num_fails;
+        self.num_mm_mn_mismatch += other.num_mm_mn_mismatch;
     }
 
     fn len(&self) -> usize {
@@ -946,12 +1519,14 @@ impl RecordProcessor for ReadsBaseModProfile {
         kmer_size: Option<usize>,
     ) -> anyhow::Result<Self::Output> {
         let mut mod_iter =
-            TrackingModRecordIter::new(records, false, allow_non_primary);
+            TrackingModRecordIter::new(records, false, allow_non_primary)
+                .with_alignment_filter(record_sampler.alignment_filter());
         let mut agg = Vec::new();
         let mut seen = HashSet::new();
         let pb = if with_progress { Some(get_ticker()) } else { None };
 
         let mut n_fails = 0usize;
+        let keep_records = record_sampler.keep_records();
         for (record, record_name, modbase_info) in &mut mod_iter {
             if let Some(cut) = cut {
                 if record.reference_start() < cut as i64 {
@@ -978,6 +1553,11 @@ impl RecordProcessor for ReadsBaseModProfile {
                             } else {
                                 seen.insert(record_name);
                             }
+                            let read_base_mod_profile = if keep_records {
+                                read_base_mod_profile.with_record(Some(record))
+                            } else {
+                                read_base_mod_profile
+                            };
                             agg.push(read_base_mod_profile);
 
                             if let Some(pb) = &pb {
@@ -993,13 +1573,14 @@ impl RecordProcessor for ReadsBaseModProfile {
             }
         }
 
-        let num_failed = mod_iter.num_failed + n_fails;
-        let num_skipped = mod_iter.num_skipped;
+        let num_failed = mod_iter.counts.num_failed + n_fails;
+        let num_skipped = mod_iter.counts.num_skipped;
 
         Ok(ReadsBaseModProfile {
             profiles: agg,
             num_skips: num_skipped,
             num_fails: num_failed,
+            num_mm_mn_mismatch: mod_iter.counts.num_mm_mn_mismatch,
         })
     }
 }
@@ -1134,6 +1715,8 @@ pub(crate) struct PositionModCalls {
     pub(crate) mod_strand: Strand,
     pub(crate) alignment_strand: Option<Strand>,
     pub(crate) canonical_base: DnaBase,
+    #[new(default)]
+    pub(crate) cigar_context: CigarContext,
 }
 
 impl PositionModCalls {
@@ -1195,7 +1778,7 @@ impl PositionModCalls {
                     let kmer = template.query_kmer;
                     let alignment_strand = template.alignment_strand;
 
-                    let pos_mod_calls = PositionModCalls::new(
+                    let mut pos_mod_calls = PositionModCalls::new(
                         query_pos,
                         ref_position,
                         num_clip_start,
@@ -1208,6 +1791,7 @@ impl PositionModCalls {
                         alignment_strand,
                         base,
                     );
+                    pos_mod_calls.cigar_context = template.cigar_context;
                     acc.push(pos_mod_calls);
 
                     acc
@@ -1249,7 +1833,67 @@ mod read_ids_to_base_mod_probs_tests {
 
     use crate::mod_bam::filter_records_iter;
     use crate::position_filter::StrandedPositionFilter;
+    use crate::read_ids_to_base_mod_probs::{
+        CigarContext, CigarContextLookup, CigarOpContext,
+    };
     use crate::util::get_aligned_pairs_forward;
+    use rust_htslib::bam::record::Cigar;
+
+    #[test]
+    fn test_cigar_context_lookup() {
+        // 2S 3M 2I 3M 1D 2M 1S
+        let cigar = [
+            Cigar::SoftClip(2),
+            Cigar::Match(3),
+            Cigar::Ins(2),
+            Cigar::Match(3),
+            Cigar::Del(1),
+            Cigar::Match(2),
+            Cigar::SoftClip(1),
+        ];
+        let lookup = CigarContextLookup::from_cigar(cigar.iter());
+        let expected = [
+            (CigarOpContext::SoftClip, 5),
+            (CigarOpContext::SoftClip, 4),
+            (CigarOpContext::Match, 3),
+            (CigarOpContext::Match, 2),
+            (CigarOpContext::Match, 1),
+            (CigarOpContext::Insertion, 0),
+            (CigarOpContext::Insertion, 0),
+            (CigarOpContext::Match, 1),
+            (CigarOpContext::Match, 2),
+            (CigarOpContext::Match, 1),
+            (CigarOpContext::Match, 1),
+            (CigarOpContext::Match, 2),
+            (CigarOpContext::SoftClip, 3),
+        ];
+        for (qpos, (op, dist)) in expected.into_iter().enumerate() {
+            assert_eq!(
+                lookup.get(qpos),
+                CigarContext { op, indel_distance: Some(dist) },
+                "qpos {qpos}"
+            );
+        }
+        assert_eq!(
+            lookup.get(expected.len()),
+            CigarContext {
+                op: CigarOpContext::Unmapped,
+                indel_distance: Some(4)
+            }
+        );
+
+        let lookup = CigarContextLookup::from_cigar(
+            [Cigar::SoftClip(1), Cigar::Match(4)].iter(),
+        );
+        assert_eq!(
+            lookup.get(0),
+            CigarContext { op: CigarOpContext::SoftClip, indel_distance: None }
+        );
+        assert_eq!(
+            lookup.get(3),
+            CigarContext { op: CigarOpContext::Match, indel_distance: None }
+        );
+    }
 
     #[test]
     fn test_seq_pos_base_mod_probs_filter_positions() {
@@ -16,11 +16,12 @@ use crate::mod_bam::{CollapseMethod, EdgeFilter};
 use crate::monoid::Moniod;
 use crate::position_filter::StrandedPositionFilter;
 use crate::reads_sampler::sampling_schedule::{
-    CountOrSample, SamplingSchedule,
+    ContigQuotas, CountOrSample, SamplingSchedule,
 };
 use crate::record_processor::{RecordProcessor, WithRecords};
 use crate::util::{
-    get_master_progress_bar, get_targets, get_ticker, ReferenceRecord, Region,
+    get_indexed_reader, get_master_progress_bar, get_targets, get_ticker,
+    ReferenceRecord, Region,
 };
 use record_sampler::RecordSampler;
 
@@ -29,6 +30,7 @@ pub(crate) mod sampling_schedule;
 
 pub(crate) fn get_sampled_read_ids_to_base_mod_probs<P: RecordProcessor>(
     bam_fp: &PathBuf,
+    reference: Option<&PathBuf>,
     reader_threads: usize,
     interval_size: u32,
     sample_frac: Option<f64>,
@@ -38,13 +40,17 @@ pub(crate) fn get_sampled_read_ids_to_base_mod_probs<P: RecordProcessor>(
     collapse_method: Option<&CollapseMethod>,
     edge_filter: Option<&EdgeFilter>,
     position_filter: Option<&StrandedPositionFilter<()>>,
+    traversal_regions: Option<&StrandedPositionFilter<()>>,
     only_mapped: bool,
+    contig_quotas: Option<ContigQuotas>,
     suppress_progress: bool,
 ) -> anyhow::Result<P::Output>
 where
     P::Output: Moniod + WithRecords,
 {
-    let use_regions = bam::IndexedReader::from_path(&bam_fp).is_ok();
+    let use_regions = get_indexed_reader(&bam_fp, reference).is_ok();
+    // contigs are chosen for sampling by the regions when they are given
+    let schedule_filter = position_filter.or(traversal_regions);
     if use_regions {
         debug!(
             "found BAM index, sampling reads in {interval_size} base pair \
@@ -53,35 +59,41 @@ where
         let schedule = match (sample_frac, num_reads) {
             (_, Some(num_reads)) => SamplingSchedule::from_num_reads(
                 bam_fp,
+                reference,
                 num_reads,
                 region,
-                position_filter,
+                schedule_filter,
                 !only_mapped,
+                contig_quotas,
             ),
             (Some(frac), _) => SamplingSchedule::from_sample_frac(
                 bam_fp,
+                reference,
                 frac as f32,
                 region,
-                position_filter,
+                schedule_filter,
                 !only_mapped,
             ),
             (None, None) => SamplingSchedule::from_sample_frac(
                 bam_fp,
+                reference,
                 1.0,
                 region,
-                position_filter,
+                schedule_filter,
                 !only_mapped,
             ),
         }?;
         let mut read_ids_to_base_mod_calls =
             sample_reads_base_mod_calls_over_regions::<P>(
                 bam_fp,
+                reference,
                 interval_size,
                 (reader_threads as f32 * 1.5).floor() as usize,
                 region,
                 edge_filter,
                 collapse_method,
                 position_filter,
+                traversal_regions,
                 &schedule,
                 only_mapped,
                 suppress_progress,
@@ -93,7 +105,7 @@ where
                 "sampled {} mapped records, sampling unmapped records",
                 read_ids_to_base_mod_calls.len()
             );
-            let mut reader = bam::IndexedReader::from_path(bam_fp)?;
+            let mut reader = get_indexed_reader(bam_fp, reference)?;
             reader.set_threads(reader_threads)?;
             reader.fetch(bam::FetchDefinition::Unmapped)?;
             let num_reads_unmapped = num_reads.map(|nr| {
@@ -131,6 +143,9 @@ where
         if region.is_some() {
             return Err(anyhow!("cannot use region without indexed BAM"));
         }
+        if traversal_regions.is_some() {
+            return Err(anyhow!("cannot use regions without indexed BAM"));
+        }
         if position_filter.is_some() {
             debug!(
                 "using include-bed with an indexed bam would improve \
@@ -138,10 +153,13 @@ where
             );
         }
         let mut reader = bam::Reader::from_path(bam_fp)?;
+        if let Some(reference) = reference {
+            reader.set_reference(reference)?;
+        }
         reader.set_threads(reader_threads)?;
         let record_sampler =
             RecordSampler::new_from_options(sample_frac, num_reads, seed);
-        let read_ids_to_base_mod_probs = P::process_records(
+        let read_ids_to_base_mod_probs = P::process_records_read_ahead(
             reader.records(),
             !suppress_progress,
             record_sampler,
@@ -150,8 +168,6 @@ where
             position_filter,
             only_mapped,
             false,
-            None,
-            None,
         )?;
         debug!("sampled {} records", read_ids_to_base_mod_probs.len());
         Ok(read_ids_to_base_mod_probs)
@@ -162,12 +178,14 @@ where
 /// an entire sorted, aligned BAM. Only uses primary alignments
 fn sample_reads_base_mod_calls_over_regions<P: RecordProcessor>(
     bam_fp: &PathBuf,
+    reference: Option<&PathBuf>,
     interval_size: u32,
     batch_size: usize,
     region: Option<&Region>,
     edge_filter: Option<&EdgeFilter>,
     collapse_method: Option<&CollapseMethod>,
     position_filter: Option<&StrandedPositionFilter<()>>,
+    traversal_regions: Option<&StrandedPositionFilter<()>>,
     sampling_schedule: &SamplingSchedule,
     only_mapped: bool,
     suppress_progress: bool,
@@ -175,20 +193,31 @@ fn sample_reads_base_mod_calls_over_regions<P: RecordProcessor>(
 where
     P::Output: Moniod + WithRecords,
 {
-    let reader = bam::IndexedReader::from_path(bam_fp)?;
+    let reader = get_indexed_reader(bam_fp, reference)?;
     let header = reader.header();
 
-    let contigs = get_targets(header, region)
+    let targets = get_targets(header, region);
+    let targets = if let Some(regions) = traversal_regions {
+        regions.traversal_reference_records(targets, interval_size)
+    } else {
+        targets
+    };
+    let contigs = targets
         .into_iter()
         .filter(|reference_record| {
             sampling_schedule.chrom_has_reads(reference_record.tid)
         })
         .collect::<Vec<ReferenceRecord>>();
 
-    let contig_sizes = contigs
-        .iter()
-        .map(|rec| (rec.tid, rec.length))
-        .collect::<FxHashMap<u32, u32>>();
+    // with regions there can be multiple records per contig, the samples are
+    // spread over the total length of the regions on each contig
+    let contig_sizes = contigs.iter().fold(
+        FxHashMap::<u32, u32>::default(),
+        |mut acc, rec| {
+            *acc.entry(rec.tid).or_insert(0) += rec.length;
+            acc
+        },
+    );
 
     let feeder = ReferenceIntervalsFeeder::new(
         contigs,
@@ -232,6 +261,7 @@ where
                 .map(|multi_coords| {
                     run_batch::<P>(
                         bam_fp,
+                        reference,
                         multi_coords,
                         sampling_schedule,
                         collapse_method,
@@ -258,6 +288,7 @@ where
 
 fn run_batch<P: RecordProcessor>(
     bam_fp: &PathBuf,
+    reference: Option<&PathBuf>,
     batch: Vec<(ChromCoordinates, CountOrSample)>,
     sampling_schedule: &SamplingSchedule,
     collapse_method: Option<&CollapseMethod>,
@@ -296,6 +327,7 @@ where
 
             match sample_reads_from_interval::<P>(
                 bam_fp,
+                reference,
                 cc.chrom_tid,
                 cc.start_pos,
                 cc.end_pos,
@@ -339,6 +371,7 @@ where
 
 pub(crate) fn sample_reads_from_interval<P: RecordProcessor>(
     bam_fp: &PathBuf,
+    reference: Option<&PathBuf>,
     chrom_tid: u32,
     start: u32,
     end: u32,
@@ -354,7 +387,7 @@ pub(crate) fn sample_reads_from_interval<P: RecordProcessor>(
 where
     P::Output: Moniod,
 {
-    let mut bam_reader = bam::IndexedReader::from_path(bam_fp)?;
+    let mut bam_reader = get_indexed_reader(bam_fp, reference)?;
     bam_reader.fetch(bam::FetchDefinition::Region(
         chrom_tid as i32,
         start as i64,
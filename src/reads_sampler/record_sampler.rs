@@ -1,4 +1,6 @@
-use crate::util::{get_master_progress_bar, get_ticker};
+use crate::util::{
+    get_master_progress_bar, get_ticker, AlignmentIdentityFilter,
+};
 
 use indicatif::ProgressBar;
 
@@ -14,6 +16,8 @@ pub struct RecordSampler {
     pub(crate) sample_frac: Option<f64>,
     rng: StdRng,
     reads_sampled: usize,
+    alignment_filter: Option<AlignmentIdentityFilter>,
+    keep_records: bool,
 }
 
 impl RecordSampler {
@@ -23,6 +27,8 @@ impl RecordSampler {
             sample_frac: None,
             rng: StdRng::from_entropy(),
             reads_sampled: 0,
+            alignment_filter: None,
+            keep_records: false,
         }
     }
 
@@ -35,6 +41,8 @@ impl RecordSampler {
             sample_frac: Some(sample_frac),
             rng,
             reads_sampled: 0,
+            alignment_filter: None,
+            keep_records: false,
         }
     }
 
@@ -44,6 +52,8 @@ impl RecordSampler {
             sample_frac: None,
             rng: StdRng::from_entropy(),
             reads_sampled: 0,
+            alignment_filter: None,
+            keep_records: false,
         }
     }
 
@@ -59,6 +69,28 @@ impl RecordSampler {
         }
     }
 
+    /// Only use mapped records that pass the alignment identity filter.
+    pub(crate) fn with_alignment_filter(
+        self,
+        alignment_filter: Option<AlignmentIdentityFilter>,
+    ) -> Self {
+        Self { alignment_filter, ..self }
+    }
+
+    pub(crate) fn alignment_filter(&self) -> Option<AlignmentIdentityFilter> {
+        self.alignment_filter
+    }
+
+    /// Keep the records that are used alongside the processed output, for
+    /// example to also write them to a BAM.
+    pub(crate) fn with_keep_records(self, keep_records: bool) -> Self {
+        Self { keep_records, ..self }
+    }
+
+    pub(crate) fn keep_records(&self) -> bool {
+        self.keep_records
+    }
+
     pub(crate) fn get_progress_bar(&self) -> ProgressBar {
         let spinner = if let Some(num) = self.num_reads {
             get_master_progress_bar(num)
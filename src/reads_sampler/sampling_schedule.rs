@@ -5,6 +5,7 @@ use std::ops::Range;
 use std::path::{Path, PathBuf};
 
 use anyhow::{anyhow, bail, Context};
+use clap::ValueEnum;
 use derive_new::new;
 use indexmap::IndexSet;
 use indicatif::{MultiProgress, ProgressIterator};
@@ -19,7 +20,35 @@ use crate::interval_chunks::{ChromCoordinates, MultiChromCoordinates};
 use crate::monoid::Moniod;
 use crate::position_filter::StrandedPositionFilter;
 use crate::reads_sampler::record_sampler::RecordSampler;
-use crate::util::{get_ticker, reader_is_bam, ReferenceRecord, Region};
+use crate::util::{
+    get_indexed_reader, get_ticker, normalize_reference_seq, reader_is_bam,
+    ReferenceRecord, Region,
+};
+
+/// How the number of reads to sample is divided between contigs.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, ValueEnum)]
+#[allow(non_camel_case_types)]
+pub enum ContigQuotas {
+    /// Proportional to the number of reads mapped to each contig. With a
+    /// CRAM the reads are counted first, which requires reading the whole
+    /// file.
+    reads,
+    /// Proportional to the length of each contig with mapped reads.
+    length,
+    /// The same number of reads from each contig with mapped reads. With a
+    /// CRAM the reads are counted first, as with `reads`.
+    uniform,
+}
+
+impl Display for ContigQuotas {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::reads => write!(f, "reads"),
+            Self::length => write!(f, "length"),
+            Self::uniform => write!(f, "uniform"),
+        }
+    }
+}
 
 /// Count is an exact count, Sample is a fraction to sample
 #[derive(Debug, PartialEq, Copy, Clone)]
@@ -82,9 +111,13 @@ struct CountsFrac {
 }
 
 impl SamplingSchedule {
+    /// When `contig_weights` is given (e.g. contig lengths), the mapped reads'
+    /// share of the samples is divided between contigs by weight instead of by
+    /// the number of mapped reads.
     fn get_contig_to_counts_frac(
         index_stats: IdxStats,
         include_unmapped: bool,
+        contig_weights: Option<&FxHashMap<i64, u64>>,
     ) -> anyhow::Result<FxHashMap<i64, CountsFrac>> {
         debug_assert!(index_stats.is_bam, "should be BAM-index based");
         let total = if include_unmapped {
@@ -96,11 +129,31 @@ impl SamplingSchedule {
             bail!("zero reads found in bam index")
         }
         let total = total as f32;
+        let mapped_frac = index_stats.mapped_read_count as f32 / total;
+        let total_weight = contig_weights
+            .map(|weights| {
+                index_stats
+                    .tid_to_mapped_read_count
+                    .iter()
+                    .filter(|(_, &n)| n > 0)
+                    .filter_map(|(target_id, _)| weights.get(target_id))
+                    .sum::<u64>()
+            })
+            .unwrap_or(0) as f32;
         let mut contig_to_counts_frac = index_stats
             .tid_to_mapped_read_count
             .into_iter()
             .map(|(target_id, n)| {
-                (target_id, CountsFrac::new(n as usize, n as f32 / total))
+                let frac = match contig_weights {
+                    Some(_) if n == 0 || total_weight == 0f32 => 0f32,
+                    Some(weights) => {
+                        let weight =
+                            weights.get(&target_id).copied().unwrap_or(0);
+                        mapped_frac * (weight as f32 / total_weight)
+                    }
+                    None => n as f32 / total,
+                };
+                (target_id, CountsFrac::new(n as usize, frac))
             })
             .collect::<FxHashMap<i64, CountsFrac>>();
         if include_unmapped {
@@ -170,19 +223,53 @@ impl SamplingSchedule {
 
     pub fn from_num_reads<T: AsRef<Path>>(
         bam_fp: T,
+        reference: Option<&PathBuf>,
         num_reads: usize,
         region: Option<&Region>,
         position_filter: Option<&StrandedPositionFilter<()>>,
         include_unmapped: bool,
+        contig_quotas: Option<ContigQuotas>,
     ) -> anyhow::Result<Self> {
-        let mut reader = bam::IndexedReader::from_path(bam_fp)?;
+        let mut reader = get_indexed_reader(bam_fp, reference)?;
         let header = reader.header().to_owned();
-        let index_stats =
+        let mut index_stats =
             IdxStats::new_from_reader(&mut reader, region, position_filter)?;
+        // default to what the index can tell us without reading the records
+        let contig_quotas = contig_quotas.unwrap_or(if index_stats.is_bam {
+            ContigQuotas::reads
+        } else {
+            ContigQuotas::length
+        });
+        if !index_stats.is_bam && contig_quotas != ContigQuotas::length {
+            debug!("counting reads per contig in CRAM");
+            index_stats = index_stats.count_cram_records(&mut reader)?;
+        }
         drop(reader); // mostly as a safety because we leave the reader in a mutated state
         if index_stats.is_bam {
-            let contig_to_counts_frac =
-                Self::get_contig_to_counts_frac(index_stats, include_unmapped)?;
+            let contig_weights = match contig_quotas {
+                ContigQuotas::reads => None,
+                ContigQuotas::length => Some(
+                    (0..header.target_count())
+                        .filter_map(|tid| {
+                            header
+                                .target_len(tid)
+                                .map(|length| (tid as i64, length))
+                        })
+                        .collect::<FxHashMap<i64, u64>>(),
+                ),
+                ContigQuotas::uniform => Some(
+                    index_stats
+                        .tid_to_mapped_read_count
+                        .keys()
+                        .map(|&tid| (tid, 1u64))
+                        .collect::<FxHashMap<i64, u64>>(),
+                ),
+            };
+            let contig_to_counts_frac = Self::get_contig_to_counts_frac(
+                index_stats,
+                include_unmapped,
+                contig_weights.as_ref(),
+            )?;
             let mut total_to_sample = 0usize;
 
             let mut counts_for_chroms = contig_to_counts_frac
@@ -320,6 +407,7 @@ impl SamplingSchedule {
 
     pub fn from_sample_frac<T: AsRef<Path>>(
         bam_fp: T,
+        reference: Option<&PathBuf>,
         sample_frac: f32,
         region: Option<&Region>,
         position_filter: Option<&StrandedPositionFilter<()>>,
@@ -328,13 +416,16 @@ impl SamplingSchedule {
         if sample_frac > 1.0 {
             bail!("sample fraction must be <= 1")
         }
-        let mut reader = bam::IndexedReader::from_path(bam_fp)?;
+        let mut reader = get_indexed_reader(bam_fp, reference)?;
         let index_stats =
             IdxStats::new_from_reader(&mut reader, region, position_filter)?;
         drop(reader);
         if index_stats.is_bam {
-            let contig_to_counts_frac =
-                Self::get_contig_to_counts_frac(index_stats, include_unmapped)?;
+            let contig_to_counts_frac = Self::get_contig_to_counts_frac(
+                index_stats,
+                include_unmapped,
+                None,
+            )?;
             let mut total_to_sample = 0usize;
             let counts_for_chroms = contig_to_counts_frac
                 .iter()
@@ -629,19 +720,21 @@ pub(crate) struct IdxStats {
 impl IdxStats {
     pub(crate) fn check_any_mapped_reads(
         bam_fp: &PathBuf,
+        reference: Option<&PathBuf>,
         region: Option<&Region>,
         position_filter: Option<&StrandedPositionFilter<()>>,
     ) -> anyhow::Result<bool> {
-        Self::new_from_path(bam_fp, region, position_filter)
+        Self::new_from_path(bam_fp, reference, region, position_filter)
             .map(|idx_stats| idx_stats.mapped_read_count > 0)
     }
 
     pub(crate) fn new_from_path(
         bam_fp: &PathBuf,
+        reference: Option<&PathBuf>,
         region: Option<&Region>,
         position_filter: Option<&StrandedPositionFilter<()>>,
     ) -> anyhow::Result<Self> {
-        let mut reader = bam::IndexedReader::from_path(bam_fp)
+        let mut reader = get_indexed_reader(bam_fp, reference)
             .context("could not create reader for getting mapping stats")?;
         Self::new_from_reader(&mut reader, region, position_filter)
     }
@@ -779,6 +872,44 @@ impl IdxStats {
         }
     }
 
+    /// A CRAM index only tells us which contigs have reads, count the records
+    /// on each of those contigs so the counts are exact, the same as from a
+    /// BAM index.
+    fn count_cram_records(
+        self,
+        reader: &mut bam::IndexedReader,
+    ) -> anyhow::Result<Self> {
+        let mut count_records =
+            |fetch_definition: FetchDefinition| -> anyhow::Result<u64> {
+                reader.fetch(fetch_definition)?;
+                Ok(reader.records().filter(|r| r.is_ok()).count() as u64)
+            };
+        let mut mapped_read_count = 0u64;
+        let tid_to_mapped_read_count = self
+            .tid_to_mapped_read_count
+            .keys()
+            .map(|&target_id| {
+                let n = count_records(FetchDefinition::CompleteTid(
+                    target_id as i32,
+                ))?;
+                mapped_read_count += n;
+                Ok((target_id, n))
+            })
+            .collect::<anyhow::Result<FxHashMap<i64, u64>>>()?;
+        let unmapped_read_count = if self.unmapped_read_count > 0 {
+            count_records(FetchDefinition::Unmapped)?
+        } else {
+            0
+        };
+
+        Ok(Self {
+            tid_to_mapped_read_count,
+            is_bam: true,
+            unmapped_read_count,
+            mapped_read_count,
+        })
+    }
+
     pub(crate) fn total(&self) -> u64 {
         self.mapped_read_count + self.unmapped_read_count
     }
@@ -842,7 +973,14 @@ impl ReferenceSequencesLookup {
         let mut tid_to_id = HashMap::new();
         let idxs = bam_fps
             .iter()
-            .map(|fp| IdxStats::new_from_path(fp, None, None))
+            .map(|fp| {
+                IdxStats::new_from_path(
+                    fp,
+                    Some(reference_fasta_fp),
+                    None,
+                    None,
+                )
+            })
             .collect::<anyhow::Result<Vec<IdxStats>>>()?;
         let reader = bam::IndexedReader::from_path(&bam_fps[0])?;
         let header = reader.header();
@@ -863,36 +1001,33 @@ impl ReferenceSequencesLookup {
 
         let records_progress = multi_progress.add(get_ticker());
         records_progress.set_message("reference records processed");
-        let reference_sequences =
-            fasta_reader
-                .records()
-                .progress_with(records_progress)
-                .filter_map(|res| res.ok())
-                .filter(|record| reference_sequence_names.contains(record.id()))
-                .filter_map(|record| {
-                    let name = record.id();
-                    let seq = String::from_utf8(record.seq().to_vec())
-                        .map(|s| if mask { s } else { s.to_ascii_uppercase() });
-                    match seq {
-                        Ok(s) => {
-                            if let Some(id) =
-                                reference_sequence_names.get_index_of(name)
-                            {
-                                let nts = s.chars().collect::<Vec<char>>();
-                                Some((id, nts))
-                            } else {
-                                None
-                            }
-                        }
-                        Err(e) => {
-                            debug!(
-                                "failed to parse FASTA sequence {name}, {e}"
-                            );
+        let reference_sequences = fasta_reader
+            .records()
+            .progress_with(records_progress)
+            .filter_map(|res| res.ok())
+            .filter(|record| reference_sequence_names.contains(record.id()))
+            .filter_map(|record| {
+                let name = record.id();
+                let seq = String::from_utf8(record.seq().to_vec())
+                    .map(|s| normalize_reference_seq(s, mask));
+                match seq {
+                    Ok(s) => {
+                        if let Some(id) =
+                            reference_sequence_names.get_index_of(name)
+                        {
+                            let nts = s.chars().collect::<Vec<char>>();
+                            Some((id, nts))
+                        } else {
                             None
                         }
                     }
-                })
-                .collect::<HashMap<usize, Vec<char>>>();
+                    Err(e) => {
+                        debug!("failed to parse FASTA sequence {name}, {e}");
+                        None
+                    }
+                }
+            })
+            .collect::<HashMap<usize, Vec<char>>>();
         if reference_sequences.is_empty() {
             bail!("must have at least 1 valid reference sequence")
         }
@@ -919,6 +1054,18 @@ impl ReferenceSequencesLookup {
             .collect()
     }
 
+    /// Length of each reference sequence, keyed by name.
+    pub(crate) fn get_contig_sizes(&self) -> HashMap<String, u32> {
+        self.reference_sequences
+            .iter()
+            .filter_map(|(id, seq)| {
+                self.reference_sequence_names
+                    .get_index(*id)
+                    .map(|name| (name.to_owned(), seq.len() as u32))
+            })
+            .collect()
+    }
+
     pub(crate) fn name_to_chrom_id(&self, name: &str) -> Option<u32> {
         self.reference_sequence_names
             .get_index_of(name)
@@ -932,6 +1079,14 @@ impl ReferenceSequencesLookup {
     ) -> anyhow::Result<Vec<char>> {
         if let Some(id) = self.reference_sequence_names.get_index_of(name) {
             let seq = &self.reference_sequences.get(&id).unwrap();
+            if interval.end > seq.len() || interval.start > interval.end {
+                bail!(
+                    "interval {}-{} is out of bounds for {name} (length {})",
+                    interval.start,
+                    interval.end,
+                    seq.len()
+                )
+            }
             let subseq = seq[interval].iter().copied().collect::<Vec<char>>();
             Ok(subseq)
         } else {
@@ -939,6 +1094,20 @@ impl ReferenceSequencesLookup {
         }
     }
 
+    /// Iterate over the reference sequences, in the order they were loaded,
+    /// with their names.
+    pub(crate) fn iter_reference_sequences(
+        &self,
+    ) -> impl Iterator<Item = (&str, &[char])> + '_ {
+        self.reference_sequence_names.iter().enumerate().filter_map(
+            |(id, name)| {
+                self.reference_sequences
+                    .get(&id)
+                    .map(|seq| (name.as_str(), seq.as_slice()))
+            },
+        )
+    }
+
     pub(crate) fn into_reference_sequences(
         self,
     ) -> VecDeque<(ReferenceRecord, Vec<char>)> {
@@ -961,18 +1130,24 @@ impl ReferenceSequencesLookup {
 
 #[cfg(test)]
 mod record_sampler_tests {
+    use std::path::PathBuf;
+
+    use rustc_hash::FxHashMap;
+
     use crate::reads_sampler::sampling_schedule::{
-        CountOrSample, SamplingSchedule,
+        CountOrSample, IdxStats, SamplingSchedule,
     };
 
     #[test]
     fn test_record_sampler_sampling_schedule() {
         let sched = SamplingSchedule::from_num_reads(
             "tests/resources/bc_anchored_10_reads.sorted.bam",
+            None,
             1023,
             None,
             None,
             false,
+            None,
         )
         .unwrap();
         assert_eq!(
@@ -981,10 +1156,12 @@ mod record_sampler_tests {
         );
         let sched = SamplingSchedule::from_num_reads(
             "tests/resources/bc_anchored_10_reads.sorted.bam",
+            None,
             5,
             None,
             None,
             false,
+            None,
         )
         .unwrap();
         assert_eq!(
@@ -993,6 +1170,7 @@ mod record_sampler_tests {
         );
         let sched = SamplingSchedule::from_sample_frac(
             "tests/resources/bc_anchored_10_reads.sorted.bam",
+            None,
             1f32,
             None,
             None,
@@ -1003,6 +1181,7 @@ mod record_sampler_tests {
 
         let sched = SamplingSchedule::from_sample_frac(
             "tests/resources/bc_anchored_10_reads.sorted.bam",
+            None,
             0.5f32,
             None,
             None,
@@ -1020,10 +1199,12 @@ mod record_sampler_tests {
     fn test_record_sampler_sampling_schedule_cram() {
         let sched = SamplingSchedule::from_num_reads(
             "tests/resources/bc_anchored_10_reads.sorted.cram",
+            Some(&PathBuf::from("tests/resources/CGI_ladder_3.6kb_ref.fa")),
             1023,
             None,
             None,
             false,
+            None,
         )
         .unwrap();
         assert_eq!(
@@ -1034,6 +1215,7 @@ mod record_sampler_tests {
         assert_eq!(sched.counts_for_chroms.len(), 1);
         let sched = SamplingSchedule::from_sample_frac(
             "tests/resources/bc_anchored_10_reads.sorted.cram",
+            Some(&PathBuf::from("tests/resources/CGI_ladder_3.6kb_ref.fa")),
             1.0,
             None,
             None,
@@ -1052,15 +1234,18 @@ mod record_sampler_tests {
     fn test_record_sampler_sampling_schedule_cram_unmapped() {
         let sched = SamplingSchedule::from_num_reads(
             "tests/resources/bc_anchored_10_reads_unmapped.sorted.cram",
+            Some(&PathBuf::from("tests/resources/CGI_ladder_3.6kb_ref.fa")),
             1023,
             None,
             None,
             true,
+            None,
         )
         .unwrap();
         assert_eq!(sched.unmapped_count, Some(CountOrSample::Count(0)));
         let sched = SamplingSchedule::from_sample_frac(
             "tests/resources/bc_anchored_10_reads_unmapped.sorted.cram",
+            Some(&PathBuf::from("tests/resources/CGI_ladder_3.6kb_ref.fa")),
             0.05,
             None,
             None,
@@ -1069,4 +1254,52 @@ mod record_sampler_tests {
         .unwrap();
         assert_eq!(sched.unmapped_count, Some(CountOrSample::Sample(0.05)));
     }
+
+    #[test]
+    fn test_record_sampler_contig_quotas() {
+        let index_stats = || IdxStats {
+            tid_to_mapped_read_count: [(0i64, 90u64), (1, 10), (2, 0)]
+                .into_iter()
+                .collect(),
+            is_bam: true,
+            unmapped_read_count: 0,
+            mapped_read_count: 100,
+        };
+        let by_reads = SamplingSchedule::get_contig_to_counts_frac(
+            index_stats(),
+            false,
+            None,
+        )
+        .unwrap();
+        assert!((by_reads.get(&0).unwrap().frac - 0.9).abs() < 1e-6);
+        assert!((by_reads.get(&1).unwrap().frac - 0.1).abs() < 1e-6);
+
+        let lengths = [(0i64, 1000u64), (1, 3000), (2, 6000)]
+            .into_iter()
+            .collect::<FxHashMap<i64, u64>>();
+        let by_length = SamplingSchedule::get_contig_to_counts_frac(
+            index_stats(),
+            false,
+            Some(&lengths),
+        )
+        .unwrap();
+        // contig 2 has no mapped reads so doesn't take a share
+        assert!((by_length.get(&0).unwrap().frac - 0.25).abs() < 1e-6);
+        assert!((by_length.get(&1).unwrap().frac - 0.75).abs() < 1e-6);
+        assert_eq!(by_length.get(&2).unwrap().frac, 0f32);
+        assert_eq!(by_length.get(&1).unwrap().counts, 10);
+
+        let uniform = [(0i64, 1u64), (1, 1), (2, 1)]
+            .into_iter()
+            .collect::<FxHashMap<i64, u64>>();
+        let by_uniform = SamplingSchedule::get_contig_to_counts_frac(
+            index_stats(),
+            false,
+            Some(&uniform),
+        )
+        .unwrap();
+        assert!((by_uniform.get(&0).unwrap().frac - 0.5).abs() < 1e-6);
+        assert!((by_uniform.get(&1).unwrap().frac - 0.5).abs() < 1e-6);
+        assert_eq!(by_uniform.get(&2).unwrap().frac, 0f32);
+    }
 }
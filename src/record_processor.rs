@@ -19,6 +19,34 @@ pub(crate) trait RecordProcessor {
         prev_end: Option<u32>,
         kmer_size: Option<usize>,
     ) -> anyhow::Result<Self::Output>;
+
+    /// [`Self::process_records`] for records from stdin or an unindexed
+    /// BAM, which can't be split into regions and processed in parallel.
+    /// Implementations can read records ahead and process them on the
+    /// current thread pool.
+    fn process_records_read_ahead<T: bam::Read + Send>(
+        records: bam::Records<T>,
+        with_progress: bool,
+        record_sampler: RecordSampler,
+        collapse_method: Option<&CollapseMethod>,
+        edge_filter: Option<&EdgeFilter>,
+        position_filter: Option<&StrandedPositionFilter<()>>,
+        only_mapped: bool,
+        allow_non_primary: bool,
+    ) -> anyhow::Result<Self::Output> {
+        Self::process_records(
+            records,
+            with_progress,
+            record_sampler,
+            collapse_method,
+            edge_filter,
+            position_filter,
+            only_mapped,
+            allow_non_primary,
+            None,
+            None,
+        )
+    }
 }
 
 pub(crate) trait WithRecords {
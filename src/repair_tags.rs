@@ -1,3 +1,6 @@
+use crate::bam_pipeline::{
+    get_threaded_bam_writer_for_path, OrderedRecordPipeline,
+};
 use crate::logging::init_logging;
 use crate::mod_bam::{
     format_mm_ml_tag, BaseModProbs, DeltaListConverter, ModBaseInfo,
@@ -12,7 +15,6 @@ use clap::Args;
 use derive_new::new;
 use indicatif::{MultiProgress, ProgressBar};
 use log::{debug, error, info, warn};
-use rayon::prelude::*;
 use rust_htslib::bam::record::{Aux, AuxArray};
 use rust_htslib::bam::{self, Read};
 use rustc_hash::FxHashMap;
@@ -58,8 +60,8 @@ impl RepairTags {
         let pool_threads =
             self.threads.checked_sub(reader_threads).unwrap_or(1);
         debug!(
-            "assigning {threads_per_reader} to each reader and using \
-             {pool_threads} to process records"
+            "assigning {threads_per_reader} to each reader and the writer and \
+             using {pool_threads} to process records"
         );
 
         let (pair_snd, pair_rcv) = std::sync::mpsc::sync_channel(1000);
@@ -68,10 +70,10 @@ impl RepairTags {
         let mut acceptor_records = bam::Reader::from_path(&self.acceptor_bam)?;
         acceptor_records.set_threads(threads_per_reader)?;
         let header = bam::Header::from_template(acceptor_records.header());
-        let mut writer = bam::Writer::from_path(
+        let mut writer = get_threaded_bam_writer_for_path(
             &self.output_bam,
             &header,
-            bam::Format::Bam,
+            threads_per_reader,
         )?;
         info!(
             "repairing records in {} with base modification information in {}",
@@ -110,47 +112,42 @@ impl RepairTags {
             }
         });
 
-        let (repair_snd, repair_rcv) = std::sync::mpsc::sync_channel(1000);
         let pool = rayon::ThreadPoolBuilder::new()
             .num_threads(pool_threads)
             .build()
             .context("failed to make thread pool")?;
-        std::thread::spawn(move || {
-            pool.install(|| {
-                pair_rcv.into_iter().par_bridge().for_each(|record_pair| {
-                    let repaired = repair_record_pair(record_pair);
-                    match repair_snd.send(repaired) {
-                        Ok(_) => repaired_ticker.inc(1),
-                        Err(e) => {
-                            error!(
-                                "failed to send repaired record on channel, {}",
-                                e.to_string()
-                            );
-                        }
-                    }
-                })
-            })
-        });
-
         let mut n_repaired = 0usize;
         let mut n_failed = 0usize;
-        for res in repair_rcv {
-            match res {
-                Ok(record) => {
-                    if let Err(e) = writer.write(&record) {
-                        error!("failed to write record {}", e.to_string());
+        // repaired records are written in the same order as the acceptor
+        OrderedRecordPipeline::new(&pool).run(
+            pair_rcv.into_iter(),
+            |record_pair| {
+                let repaired = repair_record_pair(record_pair);
+                repaired_ticker.inc(1);
+                repaired
+            },
+            |res| {
+                match res {
+                    Ok(record) => {
+                        if let Err(e) = writer.write(&record) {
+                            error!("failed to write record {}", e.to_string());
+                            n_failed += 1;
+                        } else {
+                            written_ticker.inc(1);
+                            n_repaired += 1;
+                        }
+                    }
+                    Err(e) => {
+                        debug!(
+                            "record failed to be repaired: {}",
+                            e.to_string()
+                        );
                         n_failed += 1;
-                    } else {
-                        written_ticker.inc(1);
-                        n_repaired += 1;
                     }
                 }
-                Err(e) => {
-                    debug!("record failed to be repaired: {}", e.to_string());
-                    n_failed += 1;
-                }
-            }
-        }
+                Ok(())
+            },
+        )?;
 
         info!("finished, repaired {n_repaired} records, {n_failed} failed.");
         Ok(())
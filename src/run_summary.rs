@@ -0,0 +1,138 @@
+//! Machine-readable summary of a run, written at the end of any subcommand
+//! when `--run-summary` is passed. Subcommands add the error counts they
+//! report with `format_errors_table` and, when they track them, how many
+//! reads were used, skipped, and failed.
+
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::sync::Mutex;
+
+use itertools::Itertools;
+use rustc_hash::FxHashMap;
+
+use crate::util::json::{json_object, json_string};
+
+#[derive(Default)]
+struct RunSummary {
+    error_counts: FxHashMap<String, usize>,
+    reads_used: Option<u64>,
+    reads_skipped: Option<u64>,
+    reads_failed: Option<u64>,
+}
+
+impl RunSummary {
+    fn add_errors(&mut self, error_counts: &FxHashMap<String, usize>) {
+        for (reason, count) in error_counts {
+            *self.error_counts.entry(reason.clone()).or_insert(0) += count;
+        }
+    }
+
+    fn add_reads(&mut self, used: u64, skipped: u64, failed: u64) {
+        *self.reads_used.get_or_insert(0) += used;
+        *self.reads_skipped.get_or_insert(0) += skipped;
+        *self.reads_failed.get_or_insert(0) += failed;
+    }
+
+    fn to_json(&self, command: &str, error: Option<&anyhow::Error>) -> String {
+        let json_count = |count: Option<u64>| {
+            count.map(|c| c.to_string()).unwrap_or_else(|| "null".to_string())
+        };
+        let total_errors = self.error_counts.values().sum::<usize>();
+        let errors = self
+            .error_counts
+            .iter()
+            .sorted_by(|(a, _), (b, _)| a.cmp(b))
+            .map(|(reason, count)| format!("{}:{count}", json_string(reason)))
+            .join(",");
+        json_object(&[
+            ("command", json_string(command)),
+            ("success", error.is_none().to_string()),
+            (
+                "error",
+                error
+                    .map(|e| json_string(&format!("{e:#}")))
+                    .unwrap_or_else(|| "null".to_string()),
+            ),
+            (
+                "reads",
+                json_object(&[
+                    ("used", json_count(self.reads_used)),
+                    ("skipped", json_count(self.reads_skipped)),
+                    ("failed", json_count(self.reads_failed)),
+                ]),
+            ),
+            ("total_errors", total_errors.to_string()),
+            ("errors", format!("{{{errors}}}")),
+        ])
+    }
+}
+
+static RUN_SUMMARY: Mutex<Option<RunSummary>> = Mutex::new(None);
+
+fn with_summary<F: FnOnce(&mut RunSummary)>(f: F) {
+    if let Ok(mut guard) = RUN_SUMMARY.lock() {
+        f(guard.get_or_insert_with(RunSummary::default))
+    }
+}
+
+/// Add error (or skip) counts by category to the run summary.
+pub(crate) fn record_errors(error_counts: &FxHashMap<String, usize>) {
+    with_summary(|summary| summary.add_errors(error_counts))
+}
+
+/// Add read counts to the run summary.
+pub(crate) fn record_reads(used: u64, skipped: u64, failed: u64) {
+    with_summary(|summary| summary.add_reads(used, skipped, failed))
+}
+
+/// Write the run summary as JSON, `error` is the error the subcommand
+/// failed with, if any.
+pub fn write_run_summary(
+    out_fp: &Path,
+    command: &str,
+    error: Option<&anyhow::Error>,
+) -> anyhow::Result<()> {
+    let json = match RUN_SUMMARY.lock() {
+        Ok(guard) => guard
+            .as_ref()
+            .unwrap_or(&RunSummary::default())
+            .to_json(command, error),
+        Err(_) => RunSummary::default().to_json(command, error),
+    };
+    let mut writer = BufWriter::new(File::create(out_fp)?);
+    writeln!(writer, "{json}")?;
+    writer.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod run_summary_tests {
+    use rustc_hash::FxHashMap;
+
+    use crate::run_summary::RunSummary;
+
+    #[test]
+    fn test_run_summary_json() {
+        let mut summary = RunSummary::default();
+        assert_eq!(
+            summary.to_json("entropy", None),
+            r#"{"command":"entropy","success":true,"error":null,"reads":{"used":null,"skipped":null,"failed":null},"total_errors":0,"errors":{}}"#
+        );
+        let mut counts = FxHashMap::default();
+        counts.insert("zero-reads".to_string(), 2usize);
+        counts.insert("insufficient-coverage".to_string(), 3usize);
+        summary.add_errors(&counts);
+        summary.add_errors(&counts);
+        summary.add_reads(10, 2, 1);
+        summary.add_reads(5, 0, 0);
+        assert_eq!(
+            summary.to_json("pileup", None),
+            r#"{"command":"pileup","success":true,"error":null,"reads":{"used":15,"skipped":2,"failed":1},"total_errors":10,"errors":{"insufficient-coverage":6,"zero-reads":4}}"#
+        );
+        let err = anyhow::anyhow!("bad \"input\"");
+        let json = summary.to_json("pileup", Some(&err));
+        assert!(json.contains(r#""success":false"#));
+        assert!(json.contains(r#""error":"bad \"input\"""#));
+    }
+}
@@ -0,0 +1,1110 @@
+use std::collections::BTreeMap;
+use std::io::{BufRead, BufReader, Read as IoRead, Write};
+use std::net::TcpStream;
+use std::path::PathBuf;
+use std::ops::Range;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, Context};
+use log::debug;
+use rust_htslib::bam::{self, FetchDefinition, Read};
+use rust_htslib::tbx::Reader as TbxReader;
+use rustc_hash::FxHashMap;
+
+use crate::dmr::bedmethyl::BedMethylLine;
+use crate::entropy::calculate_region_window_entropies;
+use crate::errs::IoRetry;
+use crate::fasta::snap_end_to_motifs;
+use crate::interval_chunks::FocusPositions;
+use crate::mod_bam::ModBaseInfo;
+use crate::motifs::motif_bed::{
+    find_motif_hits, MotifLocations, MultipleMotifLocations, RegexMotif,
+};
+use crate::pileup::{
+    process_fetched_region, PartitionKey, PileupNumericOptions,
+    PileupRegionOptions,
+};
+use crate::read_ids_to_base_mod_probs::ReadBaseModProfile;
+use crate::reads_sampler::sampling_schedule::ReferenceSequencesLookup;
+use crate::tabix::BedMethylTbxIndex;
+use crate::threshold_mod_caller::MultipleThresholdModCaller;
+use crate::util::json::{json_float, json_object, json_string};
+use crate::util::{get_query_name_string, record_is_not_primary, StrandRule};
+
+pub mod subcommand;
+
+/// Longest request line + headers we'll read before giving up.
+const MAX_REQUEST_BYTES: usize = 16_384;
+/// How long to wait on a client before dropping the connection.
+const CONNECTION_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug)]
+pub(crate) enum QueryError {
+    BadRequest(String),
+    NotFound(String),
+    Internal(anyhow::Error),
+}
+
+impl QueryError {
+    fn status(&self) -> (u16, &'static str) {
+        match self {
+            Self::BadRequest(_) => (400, "Bad Request"),
+            Self::NotFound(_) => (404, "Not Found"),
+            Self::Internal(_) => (500, "Internal Server Error"),
+        }
+    }
+
+    fn message(&self) -> String {
+        match self {
+            Self::BadRequest(m) | Self::NotFound(m) => m.to_owned(),
+            Self::Internal(e) => format!("{e:#}"),
+        }
+    }
+}
+
+impl From<anyhow::Error> for QueryError {
+    fn from(value: anyhow::Error) -> Self {
+        Self::Internal(value)
+    }
+}
+
+type QueryResult<T> = Result<T, QueryError>;
+
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct Request {
+    pub(crate) path: String,
+    pub(crate) params: FxHashMap<String, String>,
+}
+
+fn percent_decode(raw: &str) -> String {
+    let bytes = raw.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0usize;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' => decoded.push(b' '),
+            b'%' if i + 2 < bytes.len() => {
+                let hex = std::str::from_utf8(&bytes[i + 1..i + 3])
+                    .ok()
+                    .and_then(|h| u8::from_str_radix(h, 16).ok());
+                match hex {
+                    Some(b) => {
+                        decoded.push(b);
+                        i += 2;
+                    }
+                    None => decoded.push(b'%'),
+                }
+            }
+            b => decoded.push(b),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&decoded).to_string()
+}
+
+impl Request {
+    /// Parse the request line, e.g. `GET /pileup?region=chr1:0-100 HTTP/1.1`.
+    pub(crate) fn parse(request_line: &str) -> QueryResult<Self> {
+        let mut parts = request_line.split_ascii_whitespace();
+        let (method, target) = match (parts.next(), parts.next()) {
+            (Some(method), Some(target)) => (method, target),
+            _ => {
+                return Err(QueryError::BadRequest(format!(
+                    "invalid request line {request_line}"
+                )))
+            }
+        };
+        if method != "GET" {
+            return Err(QueryError::BadRequest(format!(
+                "unsupported method {method}, only GET is allowed"
+            )));
+        }
+        let (path, query) = target.split_once('?').unwrap_or((target, ""));
+        let params = query
+            .split('&')
+            .filter(|kv| !kv.is_empty())
+            .map(|kv| {
+                let (k, v) = kv.split_once('=').unwrap_or((kv, ""));
+                (percent_decode(k), percent_decode(v))
+            })
+            .collect::<FxHashMap<String, String>>();
+        Ok(Self { path: path.trim_end_matches('/').to_string(), params })
+    }
+
+    fn get_param<T: std::str::FromStr>(
+        &self,
+        key: &str,
+        default: T,
+    ) -> QueryResult<T> {
+        match self.params.get(key) {
+            Some(raw) => raw.parse::<T>().map_err(|_| {
+                QueryError::BadRequest(format!(
+                    "invalid value for {key}, {raw}"
+                ))
+            }),
+            None => Ok(default),
+        }
+    }
+
+    fn get_region(&self) -> QueryResult<(String, u64, u64)> {
+        let raw = self.params.get("region").ok_or_else(|| {
+            QueryError::BadRequest("region is required".to_string())
+        })?;
+        let (chrom, coords) = raw.rsplit_once(':').ok_or_else(|| {
+            QueryError::BadRequest(format!(
+                "region should be <chrom>:<start>-<end>, got {raw}"
+            ))
+        })?;
+        let coords = coords.replace(',', "");
+        let (start, end) = coords
+            .split_once('-')
+            .and_then(|(s, e)| s.parse::<u64>().ok().zip(e.parse::<u64>().ok()))
+            .filter(|(s, e)| s < e)
+            .ok_or_else(|| {
+                QueryError::BadRequest(format!(
+                    "region should be <chrom>:<start>-<end>, got {raw}"
+                ))
+            })?;
+        Ok((chrom.to_string(), start, end))
+    }
+}
+
+/// Queries clamp the end of the region to the contig length, a region that
+/// starts at or beyond the end of the contig is then empty.
+fn check_clamped_region(start: u64, end: u64, length: u64) -> QueryResult<()> {
+    if start >= end {
+        Err(QueryError::BadRequest(format!(
+            "region starts at {start}, beyond the end of the contig ({length})"
+        )))
+    } else {
+        Ok(())
+    }
+}
+
+fn json_error(message: &str) -> String {
+    format!("{{\"error\":{}}}", json_string(message))
+}
+
+fn json_records(
+    chrom: &str,
+    start: u64,
+    end: u64,
+    records: &[String],
+) -> String {
+    format!(
+        "{{\"chrom\":{},\"start\":{start},\"end\":{end},\"records\":[{}]}}",
+        json_string(chrom),
+        records.join(",")
+    )
+}
+
+fn bedmethyl_record_json(line: &BedMethylLine) -> String {
+    json_object(&[
+        ("chrom", json_string(&line.chrom)),
+        ("start", line.start().to_string()),
+        ("end", line.stop().to_string()),
+        ("mod_code", json_string(&line.raw_mod_code.to_string())),
+        ("strand", json_string(&line.strand.to_string())),
+        ("valid_coverage", line.valid_coverage.to_string()),
+        ("percent_modified", json_float(line.frac_modified() * 100f32)),
+        ("count_modified", line.count_methylated.to_string()),
+        ("count_canonical", line.count_canonical.to_string()),
+        ("count_other_mod", line.count_other.to_string()),
+        ("count_delete", line.count_delete.to_string()),
+        ("count_fail", line.count_fail.to_string()),
+        ("count_diff", line.count_diff.to_string()),
+        ("count_nocall", line.count_nocall.to_string()),
+    ])
+}
+
+/// Readers kept between requests so that each query doesn't have to open the
+/// file and load its index again. A query takes a reader from the pool, or
+/// opens one when they're all in use, and puts it back when done, so there
+/// are at most as many readers as worker threads.
+struct ReaderPool<R> {
+    readers: Mutex<Vec<R>>,
+}
+
+impl<R> ReaderPool<R> {
+    fn new() -> Self {
+        Self { readers: Mutex::new(Vec::new()) }
+    }
+
+    fn from_reader(reader: R) -> Self {
+        Self { readers: Mutex::new(vec![reader]) }
+    }
+
+    fn with_reader<T>(
+        &self,
+        open: impl FnOnce() -> anyhow::Result<R>,
+        f: impl FnOnce(&mut R) -> QueryResult<T>,
+    ) -> QueryResult<T> {
+        let pooled = self
+            .readers
+            .lock()
+            .map_err(|_| anyhow!("reader pool poisoned"))?
+            .pop();
+        let mut reader = match pooled {
+            Some(reader) => reader,
+            None => open()?,
+        };
+        let result = f(&mut reader);
+        // a reader that failed could be in a bad state, let it drop
+        if result.is_ok() {
+            if let Ok(mut readers) = self.readers.lock() {
+                readers.push(reader);
+            }
+        }
+        result
+    }
+}
+
+pub(crate) struct ServedBam {
+    path: PathBuf,
+    /// Mapping of contig name to (tid, length)
+    contigs: FxHashMap<String, (u32, u64)>,
+    io_threads: usize,
+    readers: ReaderPool<bam::IndexedReader>,
+}
+
+impl ServedBam {
+    pub(crate) fn from_path(
+        path: &PathBuf,
+        io_threads: usize,
+    ) -> anyhow::Result<Self> {
+        let reader = Self::open_reader(path, io_threads)?;
+        let header = reader.header();
+        let contigs = (0..header.target_count())
+            .map(|tid| {
+                let name =
+                    String::from_utf8_lossy(header.tid2name(tid)).to_string();
+                let length = header.target_len(tid).unwrap_or(0);
+                (name, (tid, length))
+            })
+            .collect();
+        Ok(Self {
+            path: path.to_owned(),
+            contigs,
+            io_threads,
+            readers: ReaderPool::from_reader(reader),
+        })
+    }
+
+    fn open_reader(
+        path: &PathBuf,
+        io_threads: usize,
+    ) -> anyhow::Result<bam::IndexedReader> {
+        let mut reader =
+            bam::IndexedReader::from_path(path).with_context(|| {
+                format!("failed to open indexed modBAM at {path:?}")
+            })?;
+        reader.set_threads(io_threads)?;
+        Ok(reader)
+    }
+
+    fn get_contig(&self, chrom: &str) -> QueryResult<(u32, u64)> {
+        self.contigs.get(chrom).copied().ok_or_else(|| {
+            QueryError::NotFound(format!("{chrom} not in modBAM header"))
+        })
+    }
+
+    fn with_reader<T>(
+        &self,
+        f: impl FnOnce(&mut bam::IndexedReader) -> QueryResult<T>,
+    ) -> QueryResult<T> {
+        self.readers
+            .with_reader(|| Self::open_reader(&self.path, self.io_threads), f)
+    }
+
+    /// Run `f` with a reader fetched to `tid:start-end`.
+    fn with_fetched_reader<T>(
+        &self,
+        tid: u32,
+        start: u64,
+        end: u64,
+        f: impl FnOnce(&mut bam::IndexedReader) -> QueryResult<T>,
+    ) -> QueryResult<T> {
+        self.with_reader(|reader| {
+            reader
+                .fetch(FetchDefinition::Region(
+                    tid as i32,
+                    start as i64,
+                    end as i64,
+                ))
+                .map_err(|e| QueryError::Internal(e.into()))?;
+            f(reader)
+        })
+    }
+}
+
+pub(crate) struct ServedBedMethyl {
+    index: BedMethylTbxIndex,
+    io_threads: usize,
+    readers: ReaderPool<TbxReader>,
+}
+
+impl ServedBedMethyl {
+    pub(crate) fn from_path(
+        path: &PathBuf,
+        io_threads: usize,
+    ) -> anyhow::Result<Self> {
+        let index = BedMethylTbxIndex::from_path(path).with_context(|| {
+            format!("failed to load tabix index for {path:?}")
+        })?;
+        Ok(Self { index, io_threads, readers: ReaderPool::new() })
+    }
+
+    fn read_bedmethyl(
+        &self,
+        chrom: &str,
+        range: &Range<u64>,
+    ) -> QueryResult<Vec<BedMethylLine>> {
+        self.readers.with_reader(
+            || {
+                self.index.open_reader(self.io_threads).with_context(|| {
+                    format!("failed to open {:?}", self.index.indexed_fp)
+                })
+            },
+            |reader| {
+                self.index
+                    .fetch_region_from(reader, chrom, range, StrandRule::Both)
+                    .map_err(|e| QueryError::Internal(anyhow!("{e}")))
+            },
+        )
+    }
+}
+
+/// Everything loaded once at start-up and shared between requests.
+pub(crate) struct ServerState {
+    pub(crate) bams: FxHashMap<String, ServedBam>,
+    pub(crate) bedmethyls: FxHashMap<String, ServedBedMethyl>,
+    pub(crate) reference_lookup: Option<Arc<ReferenceSequencesLookup>>,
+    pub(crate) caller: Arc<MultipleThresholdModCaller>,
+    pub(crate) max_depth: u32,
+    pub(crate) max_region_length: u64,
+}
+
+impl ServerState {
+    fn get_named<'a, T>(
+        files: &'a FxHashMap<String, T>,
+        request: &Request,
+        what: &str,
+    ) -> QueryResult<(&'a String, &'a T)> {
+        match request.params.get("name") {
+            Some(name) => files.get_key_value(name).ok_or_else(|| {
+                QueryError::NotFound(format!("no {what} named {name}"))
+            }),
+            None if files.len() == 1 => Ok(files.iter().next().unwrap()),
+            None => Err(QueryError::BadRequest(format!(
+                "name is required when more than one {what} is loaded"
+            ))),
+        }
+    }
+
+    fn check_region_length(&self, start: u64, end: u64) -> QueryResult<()> {
+        if end - start > self.max_region_length {
+            Err(QueryError::BadRequest(format!(
+                "region is longer than the maximum allowed ({})",
+                self.max_region_length
+            )))
+        } else {
+            Ok(())
+        }
+    }
+
+    fn get_reference_lookup(
+        &self,
+        what: &str,
+    ) -> QueryResult<&Arc<ReferenceSequencesLookup>> {
+        self.reference_lookup.as_ref().ok_or_else(|| {
+            QueryError::BadRequest(format!(
+                "{what} require the server to be started with --ref"
+            ))
+        })
+    }
+
+    /// Parse the `motif` and `offset` parameters, e.g. `motif=CG&offset=0`.
+    fn get_motif(&self, request: &Request) -> QueryResult<Option<RegexMotif>> {
+        let offset = request.get_param("offset", 0usize)?;
+        request
+            .params
+            .get("motif")
+            .map(|raw| {
+                RegexMotif::parse_string(raw, offset).map_err(|e| {
+                    QueryError::BadRequest(format!("invalid motif, {e}"))
+                })
+            })
+            .transpose()
+    }
+
+    /// Find the positions of `motif` in `start..end`. The returned end is
+    /// moved past any motif occurrence straddling the end of the region so
+    /// that both strands of every occurrence are counted.
+    fn motif_focus_positions(
+        &self,
+        motif: RegexMotif,
+        chrom: &str,
+        tid: u32,
+        (start, end, length): (u64, u64, u64),
+        combine_strands: bool,
+    ) -> QueryResult<(FocusPositions, u64)> {
+        let reference_lookup = self.get_reference_lookup("motif queries")?;
+        let pad = (motif.length() as u64).saturating_sub(1);
+        let fetch_start = start.saturating_sub(pad);
+        let fetch_end = std::cmp::min(end + pad, length);
+        let seq = reference_lookup
+            .get_subsequence_by_name(
+                chrom,
+                (fetch_start as usize)..(fetch_end as usize),
+            )?
+            .into_iter()
+            .collect::<String>();
+        let positions = find_motif_hits(&seq, &motif).into_iter().fold(
+            BTreeMap::<u32, StrandRule>::new(),
+            |mut acc, (pos, strand)| {
+                let pos = (pos as u64 + fetch_start) as u32;
+                match acc.get_mut(&pos) {
+                    Some(rule) => *rule = rule.absorb(strand),
+                    None => {
+                        acc.insert(pos, strand.into());
+                    }
+                }
+                acc
+            },
+        );
+        let motif_locations =
+            MultipleMotifLocations::new(vec![MotifLocations::new(
+                FxHashMap::from_iter([(tid, positions)]),
+                motif,
+            )]);
+        let end = std::cmp::min(
+            snap_end_to_motifs(&motif_locations, tid, end),
+            length,
+        );
+        let focus_positions = FocusPositions::from_motif_locations(
+            &motif_locations,
+            tid,
+            start as u32,
+            end as u32,
+            combine_strands,
+        );
+        Ok((focus_positions, end))
+    }
+
+    fn files(&self) -> String {
+        let mut bams =
+            self.bams.keys().map(|k| json_string(k)).collect::<Vec<_>>();
+        bams.sort();
+        let mut bedmethyls =
+            self.bedmethyls.keys().map(|k| json_string(k)).collect::<Vec<_>>();
+        bedmethyls.sort();
+        format!(
+            "{{\"modbams\":[{}],\"bedmethyls\":[{}],\"reference\":{}}}",
+            bams.join(","),
+            bedmethyls.join(","),
+            self.reference_lookup.is_some()
+        )
+    }
+
+    fn query_bedmethyl(&self, request: &Request) -> QueryResult<String> {
+        let (_, served_bedmethyl) =
+            Self::get_named(&self.bedmethyls, request, "bedMethyl")?;
+        let (chrom, start, end) = request.get_region()?;
+        self.check_region_length(start, end)?;
+        let records = served_bedmethyl
+            .read_bedmethyl(&chrom, &(start..end))?
+            .iter()
+            .map(bedmethyl_record_json)
+            .collect::<Vec<String>>();
+        Ok(json_records(&chrom, start, end, &records))
+    }
+
+    fn query_pileup(&self, request: &Request) -> QueryResult<String> {
+        let (_, served_bam) = Self::get_named(&self.bams, request, "modBAM")?;
+        let (chrom, start, end) = request.get_region()?;
+        self.check_region_length(start, end)?;
+        let (tid, length) = served_bam.get_contig(&chrom)?;
+        let end = std::cmp::min(end, length);
+        check_clamped_region(start, end, length)?;
+        let combine_strands = request.get_param("combine_strands", false)?;
+        let (focus_positions, process_end) = match self.get_motif(request)? {
+            Some(motif) => {
+                if combine_strands && !motif.is_palendrome() {
+                    return Err(QueryError::BadRequest(
+                        "cannot combine strands with a motif that is not a \
+                         palindrome"
+                            .to_string(),
+                    ));
+                }
+                self.motif_focus_positions(
+                    motif,
+                    &chrom,
+                    tid,
+                    (start, end, length),
+                    combine_strands,
+                )?
+            }
+            None if combine_strands => {
+                return Err(QueryError::BadRequest(
+                    "combine_strands requires a motif".to_string(),
+                ));
+            }
+            None => (FocusPositions::AllPositions, end),
+        };
+        let region_options = PileupRegionOptions {
+            reference: None,
+            caller: &self.caller,
+            numeric_options: &PileupNumericOptions::Passthrough,
+            force_allow: false,
+            combine_strands,
+            max_depth: self.max_depth,
+            edge_filter: None,
+            partition_tags: None,
+            phased_variants: None,
+            with_cigar_states: false,
+            fragment_ids: None,
+            io_retry: IoRetry::default(),
+        };
+        let pileup =
+            served_bam.with_fetched_reader(tid, start, process_end, |reader| {
+                process_fetched_region(
+                    reader,
+                    tid,
+                    start as u32,
+                    process_end as u32,
+                    &focus_positions,
+                    &region_options,
+                )
+                .map_err(|e| QueryError::Internal(anyhow!("{e}")))
+            })?;
+        let chrom_name = json_string(&pileup.chrom_name);
+        let records = pileup
+            .iter_counts_sorted()
+            .filter_map(|(pos, counts)| {
+                counts.get(&PartitionKey::NoKey).map(|c| (pos, c))
+            })
+            .flat_map(|(pos, feature_counts)| {
+                let chrom_name = &chrom_name;
+                feature_counts.iter().map(move |fc| {
+                    json_object(&[
+                        ("chrom", chrom_name.clone()),
+                        ("start", pos.to_string()),
+                        ("end", (pos + 1).to_string()),
+                        ("mod_code", json_string(&fc.raw_mod_code.to_string())),
+                        ("strand", json_string(&fc.raw_strand.to_string())),
+                        ("valid_coverage", fc.filtered_coverage.to_string()),
+                        (
+                            "percent_modified",
+                            json_float(fc.fraction_modified * 100f32),
+                        ),
+                        ("count_modified", fc.n_modified.to_string()),
+                        ("count_canonical", fc.n_canonical.to_string()),
+                        ("count_other_mod", fc.n_other_modified.to_string()),
+                        ("count_delete", fc.n_delete.to_string()),
+                        ("count_fail", fc.n_filtered.to_string()),
+                        ("count_diff", fc.n_diff.to_string()),
+                        ("count_nocall", fc.n_nocall.to_string()),
+                    ])
+                })
+            })
+            .collect::<Vec<String>>();
+        Ok(json_records(&chrom, start, end, &records))
+    }
+
+    fn query_extract(&self, request: &Request) -> QueryResult<String> {
+        let (_, served_bam) = Self::get_named(&self.bams, request, "modBAM")?;
+        let (chrom, start, end) = request.get_region()?;
+        self.check_region_length(start, end)?;
+        let (tid, _length) = served_bam.get_contig(&chrom)?;
+        let max_reads = request.get_param("max_reads", 1_000usize)?;
+        let records = served_bam.with_fetched_reader(tid, start, end, |reader| {
+            let mut records = Vec::new();
+            let mut n_reads = 0usize;
+            for record in reader.records().filter_map(|r| r.ok()) {
+                if record.is_unmapped() || record_is_not_primary(&record) {
+                    continue;
+                }
+                if n_reads >= max_reads {
+                    break;
+                }
+                let profile = get_query_name_string(&record).and_then(|name| {
+                    let mod_base_info = ModBaseInfo::new_from_record(&record)?;
+                    ReadBaseModProfile::process_record(
+                        &record,
+                        &name,
+                        mod_base_info,
+                        None,
+                        None,
+                        1,
+                    )
+                });
+                let profile = match profile {
+                    Ok(profile) => profile,
+                    Err(e) => {
+                        debug!("failed to extract read, {e}");
+                        continue;
+                    }
+                };
+                n_reads += 1;
+                let read_id = json_string(&profile.record_name);
+                for mod_profile in profile.profile.iter().filter(|p| {
+                    p.ref_position
+                        .map(|rp| rp >= start as i64 && rp < end as i64)
+                        .unwrap_or(false)
+                }) {
+                    records.push(json_object(&[
+                        ("read_id", read_id.clone()),
+                        (
+                            "forward_read_position",
+                            mod_profile.query_position.to_string(),
+                        ),
+                        (
+                            "ref_position",
+                            mod_profile.ref_position.unwrap_or(-1).to_string(),
+                        ),
+                        (
+                            "mod_strand",
+                            json_string(
+                                &mod_profile.mod_strand.to_char().to_string(),
+                            ),
+                        ),
+                        (
+                            "ref_strand",
+                            mod_profile
+                                .alignment_strand
+                                .map(|s| json_string(&s.to_char().to_string()))
+                                .unwrap_or("null".to_string()),
+                        ),
+                        (
+                            "mod_code",
+                            json_string(&mod_profile.raw_mod_code.to_string()),
+                        ),
+                        ("mod_qual", json_float(mod_profile.q_mod)),
+                        (
+                            "canonical_base",
+                            json_string(
+                                &mod_profile.canonical_base.char().to_string(),
+                            ),
+                        ),
+                        ("inferred", mod_profile.inferred.to_string()),
+                    ]));
+                }
+            }
+            Ok(records)
+        })?;
+        Ok(json_records(&chrom, start, end, &records))
+    }
+
+    fn query_entropy(&self, request: &Request) -> QueryResult<String> {
+        let reference_lookup = self.get_reference_lookup("entropy queries")?;
+        let (_, served_bam) = Self::get_named(&self.bams, request, "modBAM")?;
+        let (chrom, start, end) = request.get_region()?;
+        self.check_region_length(start, end)?;
+        let (_tid, length) = served_bam.get_contig(&chrom)?;
+        let end = std::cmp::min(end, length);
+        check_clamped_region(start, end, length)?;
+        let num_positions = request.get_param("num_positions", 4usize)?;
+        let window_size = request.get_param("window_size", 50usize)?;
+        let min_coverage = request.get_param("min_coverage", 3u32)?;
+        if num_positions == 0 || min_coverage == 0 {
+            return Err(QueryError::BadRequest(
+                "num_positions and min_coverage must be at least 1".to_string(),
+            ));
+        }
+        let max_filtered = request.get_param(
+            "max_filtered_positions",
+            (num_positions as f32 * 0.5f32).floor() as usize,
+        )?;
+        let motif = match self.get_motif(request)? {
+            Some(motif) => motif,
+            None => RegexMotif::parse_string("CG", 0)?,
+        };
+        let (windows, failures) = served_bam.with_reader(|reader| {
+            calculate_region_window_entropies(
+                reference_lookup,
+                &chrom,
+                (start as usize)..(end as usize),
+                vec![motif],
+                true,
+                num_positions,
+                window_size,
+                min_coverage,
+                max_filtered,
+                self.caller.clone(),
+                reader,
+            )
+            .map_err(|e| QueryError::BadRequest(format!("{e:#}")))
+        })?;
+        let records = windows
+            .iter()
+            .map(|w| {
+                json_object(&[
+                    ("chrom", json_string(&chrom)),
+                    ("start", w.start.to_string()),
+                    ("end", w.end.to_string()),
+                    ("entropy", json_float(w.entropy)),
+                    ("strand", json_string(&w.strand.to_char().to_string())),
+                    ("num_reads", w.num_reads.to_string()),
+                ])
+            })
+            .collect::<Vec<String>>();
+        let failures = failures
+            .iter()
+            .map(|(reason, count)| format!("{}:{count}", json_string(reason)))
+            .collect::<Vec<String>>();
+        Ok(json_object(&[
+            ("chrom", json_string(&chrom)),
+            ("start", start.to_string()),
+            ("end", end.to_string()),
+            ("records", format!("[{}]", records.join(","))),
+            ("failed_windows", format!("{{{}}}", failures.join(","))),
+        ]))
+    }
+
+    /// Route a request to the appropriate query, returns the JSON body.
+    pub(crate) fn handle(&self, request: &Request) -> QueryResult<String> {
+        match request.path.as_str() {
+            "" | "/health" => Ok("{\"status\":\"ok\"}".to_string()),
+            "/files" => Ok(self.files()),
+            "/bedmethyl" => self.query_bedmethyl(request),
+            "/pileup" => self.query_pileup(request),
+            "/extract" => self.query_extract(request),
+            "/entropy" => self.query_entropy(request),
+            p => Err(QueryError::NotFound(format!("unknown endpoint {p}"))),
+        }
+    }
+
+    pub(crate) fn handle_connection(
+        &self,
+        mut stream: TcpStream,
+    ) -> anyhow::Result<()> {
+        stream.set_read_timeout(Some(CONNECTION_TIMEOUT))?;
+        stream.set_write_timeout(Some(CONNECTION_TIMEOUT))?;
+        let mut reader =
+            BufReader::new(stream.try_clone()?.take(MAX_REQUEST_BYTES as u64));
+        // a line cut short by the byte limit means the request is too large
+        let mut read_line = |line: &mut String| -> anyhow::Result<usize> {
+            let n = reader.read_line(line)?;
+            if !line.ends_with('\n') && reader.get_ref().limit() == 0 {
+                bail!("request too large");
+            }
+            Ok(n)
+        };
+        let mut request_line = String::new();
+        read_line(&mut request_line)?;
+        // drain the headers, we don't use them
+        loop {
+            let mut header = String::new();
+            let n = read_line(&mut header)?;
+            if n == 0 || header.trim().is_empty() {
+                break;
+            }
+        }
+
+        let (status, body) =
+            match Request::parse(&request_line).and_then(|r| self.handle(&r)) {
+                Ok(body) => ((200, "OK"), body),
+                Err(e) => {
+                    debug!(
+                        "request {} failed, {}",
+                        request_line.trim(),
+                        e.message()
+                    );
+                    (e.status(), json_error(&e.message()))
+                }
+            };
+        let (code, reason) = status;
+        write!(
+            stream,
+            "HTTP/1.1 {code} {reason}\r\nContent-Type: \
+             application/json\r\nContent-Length: {}\r\nConnection: \
+             close\r\n\r\n{body}",
+            body.len()
+        )?;
+        stream.flush()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod serve_tests {
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::path::PathBuf;
+    use std::sync::Arc;
+
+    use rustc_hash::FxHashMap;
+
+    use crate::reads_sampler::sampling_schedule::ReferenceSequencesLookup;
+    use crate::serve::{
+        percent_decode, QueryError, Request, ServedBam, ServedBedMethyl,
+        ServerState, MAX_REQUEST_BYTES,
+    };
+    use crate::threshold_mod_caller::MultipleThresholdModCaller;
+    use crate::util::json::{json_float, json_object, json_string};
+
+    #[test]
+    fn test_serve_request_parsing() {
+        let request =
+            Request::parse("GET /pileup?region=chr20%3A10-20&name=a HTTP/1.1")
+                .unwrap();
+        assert_eq!(request.path, "/pileup");
+        assert_eq!(request.params.get("region").unwrap(), "chr20:10-20");
+        assert_eq!(request.params.get("name").unwrap(), "a");
+        assert_eq!(
+            request.get_region().unwrap(),
+            ("chr20".to_string(), 10, 20)
+        );
+        let request =
+            Request::parse("GET /pileup?region=chr20:20-10 HTTP/1.1").unwrap();
+        assert!(matches!(request.get_region(), Err(QueryError::BadRequest(_))));
+        assert!(matches!(
+            Request::parse("POST /pileup HTTP/1.1"),
+            Err(QueryError::BadRequest(_))
+        ));
+        assert_eq!(percent_decode("a%2Cb+c%"), "a,b c%");
+    }
+
+    #[test]
+    fn test_serve_queries() {
+        let bam_fp =
+            PathBuf::from("tests/resources/bc_anchored_10_reads.sorted.bam");
+        let bed_fp = PathBuf::from(
+            "tests/resources/\
+             lung_00733-m_adjacent-normal_5mc-5hmc_chr20_cpg_pileup.bed.gz",
+        );
+        let mpb = indicatif::MultiProgress::new();
+        mpb.set_draw_target(indicatif::ProgressDrawTarget::hidden());
+        let state = ServerState {
+            bams: FxHashMap::from_iter([(
+                "reads".to_string(),
+                ServedBam::from_path(&bam_fp, 1).unwrap(),
+            )]),
+            bedmethyls: FxHashMap::from_iter([(
+                "lung".to_string(),
+                ServedBedMethyl::from_path(&bed_fp, 1).unwrap(),
+            )]),
+            reference_lookup: Some(Arc::new(
+                ReferenceSequencesLookup::new(
+                    &[bam_fp.clone()],
+                    &PathBuf::from("tests/resources/CGI_ladder_3.6kb_ref.fa"),
+                    false,
+                    &mpb,
+                )
+                .unwrap(),
+            )),
+            caller: Arc::new(MultipleThresholdModCaller::new_passthrough()),
+            max_depth: 8000,
+            max_region_length: 1_000_000,
+        };
+        let files = state
+            .handle(&Request::parse("GET /files HTTP/1.1").unwrap())
+            .unwrap();
+        assert_eq!(
+            files,
+            json_object(&[
+                ("modbams", "[\"reads\"]".to_string()),
+                ("bedmethyls", "[\"lung\"]".to_string()),
+                ("reference", "true".to_string()),
+            ])
+        );
+        let query = |target: &str| {
+            state.handle(
+                &Request::parse(&format!("GET {target} HTTP/1.1")).unwrap(),
+            )
+        };
+        let bedmethyl =
+            query("/bedmethyl?region=chr20:9681998-9682014").unwrap();
+        assert_eq!(bedmethyl.matches("\"mod_code\"").count(), 2);
+        assert!(bedmethyl.contains(&json_object(&[
+            ("chrom", json_string("chr20")),
+            ("start", "9682013".to_string()),
+            ("end", "9682014".to_string()),
+            ("mod_code", json_string("C")),
+            ("strand", json_string("-")),
+            ("valid_coverage", "1".to_string()),
+            ("percent_modified", "100".to_string()),
+            ("count_modified", "1".to_string()),
+            ("count_canonical", "0".to_string()),
+            ("count_other_mod", "0".to_string()),
+            ("count_delete", "0".to_string()),
+            ("count_fail", "0".to_string()),
+            ("count_diff", "0".to_string()),
+            ("count_nocall", "0".to_string()),
+        ])));
+
+        let contig = "oligo_1512_adapters";
+        let pileup_record =
+            |start: u64, mod_code: &str, strand: &str, counts: [u32; 7]| {
+                let [valid, modified, canonical, other, delete, diff, nocall] =
+                    counts;
+                json_object(&[
+                    ("chrom", json_string(contig)),
+                    ("start", start.to_string()),
+                    ("end", (start + 1).to_string()),
+                    ("mod_code", json_string(mod_code)),
+                    ("strand", json_string(strand)),
+                    ("valid_coverage", valid.to_string()),
+                    (
+                        "percent_modified",
+                        json_float(modified as f32 / valid as f32 * 100f32),
+                    ),
+                    ("count_modified", modified.to_string()),
+                    ("count_canonical", canonical.to_string()),
+                    ("count_other_mod", other.to_string()),
+                    ("count_delete", delete.to_string()),
+                    ("count_fail", "0".to_string()),
+                    ("count_diff", diff.to_string()),
+                    ("count_nocall", nocall.to_string()),
+                ])
+            };
+        let pileup =
+            query(&format!("/pileup?name=reads&region={contig}:60-64"))
+                .unwrap();
+        assert_eq!(pileup.matches("\"mod_code\"").count(), 4);
+        assert!(pileup.contains(&pileup_record(
+            63,
+            "m",
+            "+",
+            [6, 5, 0, 1, 0, 0, 0]
+        )));
+        assert!(pileup.contains(&pileup_record(
+            63,
+            "h",
+            "-",
+            [1, 1, 0, 0, 0, 3, 0]
+        )));
+        // the CpG at 63 straddles the end of the region, both strands are
+        // counted and combined
+        let pileup = query(&format!(
+            "/pileup?name=reads&region={contig}:60-64&motif=CG&combine_strands=true"
+        ))
+        .unwrap();
+        assert_eq!(pileup.matches("\"mod_code\"").count(), 2);
+        assert!(pileup.contains(&pileup_record(
+            63,
+            "h",
+            ".",
+            [8, 2, 0, 6, 1, 1, 0]
+        )));
+        assert!(pileup.contains(&pileup_record(
+            63,
+            "m",
+            ".",
+            [8, 6, 0, 2, 1, 1, 0]
+        )));
+        assert!(matches!(
+            query(&format!(
+                "/pileup?name=reads&region={contig}:60-64&combine_strands=true"
+            )),
+            Err(QueryError::BadRequest(_))
+        ));
+        assert!(matches!(
+            query(&format!(
+                "/pileup?name=reads&region={contig}:60-64&motif=GATC&offset=1&combine_strands=true"
+            )),
+            Ok(_)
+        ));
+        assert!(matches!(
+            query(&format!(
+                "/pileup?name=reads&region={contig}:60-64&motif=CH&combine_strands=true"
+            )),
+            Err(QueryError::BadRequest(_))
+        ));
+
+        let extract =
+            query(&format!("/extract?name=reads&region={contig}:60-64"))
+                .unwrap();
+        assert_eq!(extract.matches("\"read_id\"").count(), 14);
+        assert!(extract.contains(&json_object(&[
+            ("read_id", json_string("0d6c61d1-493e-4e9b-9232-d878ce53ac39")),
+            ("forward_read_position", "100".to_string()),
+            ("ref_position", "63".to_string()),
+            ("mod_strand", json_string("+")),
+            ("ref_strand", json_string("-")),
+            ("mod_code", json_string("m")),
+            ("mod_qual", json_float(0.15429688)),
+            ("canonical_base", json_string("C")),
+            ("inferred", "false".to_string()),
+        ])));
+
+        let entropy = query(&format!(
+            "/entropy?name=reads&region={contig}:0-200&min_coverage=1"
+        ))
+        .unwrap();
+        assert!(entropy.starts_with(&format!(
+            "{{\"chrom\":\"{contig}\",\"start\":0,\"end\":156,\"records\":[{}",
+            json_object(&[
+                ("chrom", json_string(contig)),
+                ("start", "63".to_string()),
+                ("end", "92".to_string()),
+                ("entropy", json_float(0.555482)),
+                ("strand", json_string("+")),
+                ("num_reads", "10".to_string()),
+            ])
+        )));
+        assert_eq!(entropy.matches("\"entropy\"").count(), 6);
+        assert!(entropy.ends_with("\"failed_windows\":{}}"));
+        // there are no GATC motifs on this contig
+        assert!(matches!(
+            query(&format!(
+                "/entropy?name=reads&region={contig}:0-200&motif=GATC&offset=1"
+            )),
+            Err(QueryError::BadRequest(_))
+        ));
+        assert!(matches!(
+            query("/pileup?name=nope&region=chr1:0-1"),
+            Err(QueryError::NotFound(_))
+        ));
+        // regions starting past the end of the contig (156) are empty
+        assert!(matches!(
+            query(&format!("/pileup?name=reads&region={contig}:156-200")),
+            Err(QueryError::BadRequest(_))
+        ));
+        assert!(matches!(
+            query(&format!(
+                "/entropy?name=reads&region={contig}:200-300&min_coverage=1"
+            )),
+            Err(QueryError::BadRequest(_))
+        ));
+    }
+
+    #[test]
+    fn test_serve_connection_limits() {
+        let state = ServerState {
+            bams: FxHashMap::default(),
+            bedmethyls: FxHashMap::default(),
+            reference_lookup: None,
+            caller: Arc::new(MultipleThresholdModCaller::new_passthrough()),
+            max_depth: 8000,
+            max_region_length: 1_000_000,
+        };
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let send = |request: String| {
+            let mut client = TcpStream::connect(address).unwrap();
+            client.write_all(request.as_bytes()).unwrap();
+            let (stream, _) = listener.accept().unwrap();
+            let result = state.handle_connection(stream);
+            let mut response = String::new();
+            let _ = client.read_to_string(&mut response);
+            (result, response)
+        };
+        let (result, response) =
+            send("GET /health HTTP/1.1\r\nHost: x\r\n\r\n".to_string());
+        assert!(result.is_ok());
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+        assert!(response.ends_with("{\"status\":\"ok\"}"));
+        let (result, _) = send(format!(
+            "GET /health?pad={} HTTP/1.1\r\n\r\n",
+            "a".repeat(MAX_REQUEST_BYTES)
+        ));
+        assert!(result.is_err());
+        let (result, _) = send(format!(
+            "GET /health HTTP/1.1\r\n{}\r\n",
+            "Header: value\r\n".repeat(MAX_REQUEST_BYTES / 10)
+        ));
+        assert!(result.is_err());
+    }
+}
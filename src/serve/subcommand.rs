@@ -0,0 +1,183 @@
+use std::net::TcpListener;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use anyhow::{bail, Context};
+use clap::Args;
+use indicatif::{MultiProgress, ProgressDrawTarget};
+use log::{debug, info};
+use rustc_hash::FxHashMap;
+
+use crate::logging::init_logging;
+use crate::reads_sampler::sampling_schedule::ReferenceSequencesLookup;
+use crate::serve::{ServedBam, ServedBedMethyl, ServerState};
+use crate::threshold_mod_caller::MultipleThresholdModCaller;
+
+#[derive(Args)]
+#[command(arg_required_else_help = true)]
+pub struct EntryServe {
+    /// Indexed modBAM to serve pileup, extract, and entropy queries from. Can
+    /// be given as <name>=<path> to name the file in queries, otherwise the
+    /// file name is used. May be repeated.
+    #[arg(short = 's', long = "modbam", alias = "in-bam")]
+    modbams: Vec<String>,
+    /// bgzip-compressed and tabix-indexed bedMethyl to serve region queries
+    /// from. Can be given as <name>=<path>, may be repeated.
+    #[arg(short = 'b', long = "bedmethyl")]
+    bedmethyls: Vec<String>,
+    /// Reference sequence in FASTA format, required for entropy queries and
+    /// pileup queries with a motif. The reference sequences used by the
+    /// modBAMs will be loaded into memory.
+    #[arg(long = "ref")]
+    reference_fasta: Option<PathBuf>,
+
+    /// Address to listen on.
+    #[clap(help_heading = "Server Options")]
+    #[arg(long, default_value = "127.0.0.1")]
+    host: String,
+    /// Port to listen on.
+    #[clap(help_heading = "Server Options")]
+    #[arg(short = 'p', long, default_value_t = 8_050)]
+    port: u16,
+    /// Maximum length of a queried region, longer regions will be rejected.
+    #[clap(help_heading = "Server Options")]
+    #[arg(long, default_value_t = 1_000_000)]
+    max_region_length: u64,
+
+    /// Filter threshold to use for all base modification calls, base
+    /// modification calls with probabilities less than this number will be
+    /// filtered out. Default is to not filter any calls.
+    #[clap(help_heading = "Filtering Options")]
+    #[arg(long)]
+    filter_threshold: Option<f32>,
+    /// Maximum number of records to use when calculating pileup.
+    #[clap(help_heading = "Selection Options")]
+    #[arg(long, default_value_t = 8000, hide_short_help = true)]
+    max_depth: u32,
+
+    /// Number of requests to handle concurrently.
+    #[clap(help_heading = "Compute Options")]
+    #[arg(short = 't', long, default_value_t = 4)]
+    threads: usize,
+    /// Number of BAM/bgzf-reading threads to use per request.
+    #[clap(help_heading = "Compute Options")]
+    #[arg(long, default_value_t = 1)]
+    io_threads: usize,
+    /// Specify a file for debug logs to be written to, otherwise ignore them.
+    /// Setting a file is recommended.
+    #[clap(help_heading = "Logging Options")]
+    #[arg(long, alias = "log")]
+    log_filepath: Option<PathBuf>,
+}
+
+fn parse_named_path(raw: &str) -> anyhow::Result<(String, PathBuf)> {
+    let (name, path) = match raw.split_once('=') {
+        Some((name, path)) => (name.to_string(), PathBuf::from(path)),
+        None => {
+            let path = PathBuf::from(raw);
+            let name = path
+                .file_name()
+                .map(|n| n.to_string_lossy().to_string())
+                .with_context(|| format!("invalid file path {raw}"))?;
+            (name, path)
+        }
+    };
+    if name.is_empty() {
+        bail!("name cannot be empty, got {raw}")
+    }
+    Ok((name, path))
+}
+
+impl EntryServe {
+    fn load_state(&self) -> anyhow::Result<ServerState> {
+        let mut bams = FxHashMap::default();
+        for raw in self.modbams.iter() {
+            let (name, path) = parse_named_path(raw)?;
+            let served_bam = ServedBam::from_path(&path, self.io_threads)?;
+            if bams.insert(name.clone(), served_bam).is_some() {
+                bail!("duplicate modBAM name {name}")
+            }
+            info!("loaded modBAM {name} from {path:?}");
+        }
+        let mut bedmethyls = FxHashMap::default();
+        for raw in self.bedmethyls.iter() {
+            let (name, path) = parse_named_path(raw)?;
+            let served_bedmethyl =
+                ServedBedMethyl::from_path(&path, self.io_threads)?;
+            if bedmethyls.insert(name.clone(), served_bedmethyl).is_some() {
+                bail!("duplicate bedMethyl name {name}")
+            }
+            info!("loaded bedMethyl {name} from {path:?}");
+        }
+        if bams.is_empty() && bedmethyls.is_empty() {
+            bail!("need at least one modBAM or bedMethyl to serve")
+        }
+
+        let reference_lookup = match self.reference_fasta.as_ref() {
+            Some(fasta_fp) => {
+                if bams.is_empty() {
+                    bail!("--ref requires at least one modBAM")
+                }
+                let bam_fps = self
+                    .modbams
+                    .iter()
+                    .map(|raw| parse_named_path(raw).map(|(_, p)| p))
+                    .collect::<anyhow::Result<Vec<PathBuf>>>()?;
+                let mpb = MultiProgress::new();
+                mpb.set_draw_target(ProgressDrawTarget::hidden());
+                Some(Arc::new(ReferenceSequencesLookup::new(
+                    &bam_fps, fasta_fp, false, &mpb,
+                )?))
+            }
+            None => None,
+        };
+
+        let caller = match self.filter_threshold {
+            Some(threshold) => MultipleThresholdModCaller::new(
+                Default::default(),
+                Default::default(),
+                threshold,
+            ),
+            None => MultipleThresholdModCaller::new_passthrough(),
+        };
+
+        Ok(ServerState {
+            bams,
+            bedmethyls,
+            reference_lookup,
+            caller: Arc::new(caller),
+            max_depth: self.max_depth,
+            max_region_length: self.max_region_length,
+        })
+    }
+
+    pub fn run(&self) -> anyhow::Result<()> {
+        let _handle = init_logging(self.log_filepath.as_ref());
+        let state = Arc::new(self.load_state()?);
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(self.threads)
+            .build()?;
+
+        let address = format!("{}:{}", self.host, self.port);
+        let listener = TcpListener::bind(&address)
+            .with_context(|| format!("failed to listen on {address}"))?;
+        info!("listening on http://{address}");
+
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    let state = state.clone();
+                    pool.spawn(move || {
+                        if let Err(e) = state.handle_connection(stream) {
+                            debug!("failed to handle connection, {e}");
+                        }
+                    });
+                }
+                Err(e) => {
+                    debug!("failed to accept connection, {e}");
+                }
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,350 @@
+use std::io::Write;
+use std::path::Path;
+
+use anyhow::{anyhow, bail, Context};
+use log::debug;
+use rusqlite::types::Value;
+use rusqlite::{params_from_iter, Connection};
+
+use crate::util::{MISSING_SYMBOL, TAB};
+use crate::writers::FinishWrite;
+
+/// Number of rows to insert before committing the current transaction.
+const ROWS_PER_TRANSACTION: usize = 100_000;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub(crate) enum ColumnType {
+    Integer,
+    Real,
+    Text,
+}
+
+impl ColumnType {
+    fn sql_name(&self) -> &'static str {
+        match self {
+            Self::Integer => "INTEGER",
+            Self::Real => "REAL",
+            Self::Text => "TEXT",
+        }
+    }
+
+    /// Convert a field from a tab-separated row into a SQLite value, missing
+    /// values (".") become NULL and booleans are stored as 0/1.
+    fn parse_field(&self, raw: &str) -> Value {
+        if raw == MISSING_SYMBOL {
+            return Value::Null;
+        }
+        match self {
+            Self::Integer => match raw {
+                "true" => Value::Integer(1),
+                "false" => Value::Integer(0),
+                _ => raw
+                    .parse::<i64>()
+                    .map(Value::Integer)
+                    .unwrap_or_else(|_| Value::Text(raw.to_string())),
+            },
+            Self::Real => raw
+                .parse::<f64>()
+                .map(Value::Real)
+                .unwrap_or_else(|_| Value::Text(raw.to_string())),
+            Self::Text => Value::Text(raw.to_string()),
+        }
+    }
+}
+
+fn quote_identifier(name: &str) -> String {
+    format!("\"{}\"", name.replace('"', "\"\""))
+}
+
+/// Writes tab-separated rows into a single table of a SQLite database. The
+/// rows are the same as those written to TSV/bedMethyl outputs so this
+/// writer can be used anywhere a [Write] is expected. Rows are inserted in
+/// batched transactions and the indices are made once all rows have been
+/// written.
+pub(crate) struct SqliteTableWriter {
+    connection: Connection,
+    insert_statement: String,
+    column_types: Vec<ColumnType>,
+    indices: Vec<(String, Vec<String>)>,
+    table: String,
+    buffer: Vec<u8>,
+    rows_in_transaction: usize,
+    failed_rows: usize,
+    first_error: Option<String>,
+    finished: bool,
+}
+
+impl SqliteTableWriter {
+    pub(crate) fn new(
+        path: &Path,
+        force: bool,
+        table: &str,
+        columns: &[(String, ColumnType)],
+        indices: &[&[&str]],
+    ) -> anyhow::Result<Self> {
+        if path.exists() {
+            if force {
+                std::fs::remove_file(path).with_context(|| {
+                    format!("failed to remove existing database {path:?}")
+                })?;
+            } else {
+                bail!("refusing to write over existing file {path:?}")
+            }
+        }
+        for index_columns in indices.iter() {
+            if let Some(missing) = index_columns
+                .iter()
+                .find(|c| !columns.iter().any(|(name, _)| name == *c))
+            {
+                bail!("cannot index {table} on unknown column {missing}")
+            }
+        }
+        let connection = Connection::open(path).with_context(|| {
+            format!("failed to create database at {path:?}")
+        })?;
+        connection.execute_batch(
+            "PRAGMA journal_mode = OFF; PRAGMA synchronous = OFF;",
+        )?;
+        let column_defs = columns
+            .iter()
+            .map(|(name, typ)| {
+                format!("{} {}", quote_identifier(name), typ.sql_name())
+            })
+            .collect::<Vec<String>>();
+        connection
+            .execute_batch(&format!(
+                "CREATE TABLE {} ({});",
+                quote_identifier(table),
+                column_defs.join(", ")
+            ))
+            .with_context(|| format!("failed to create table {table}"))?;
+        let placeholders =
+            (1..=columns.len()).map(|i| format!("?{i}")).collect::<Vec<_>>();
+        let insert_statement = format!(
+            "INSERT INTO {} VALUES ({})",
+            quote_identifier(table),
+            placeholders.join(", ")
+        );
+        let indices = indices
+            .iter()
+            .map(|index_columns| {
+                let name = format!("{table}_{}_idx", index_columns.join("_"));
+                let index_columns = index_columns
+                    .iter()
+                    .map(|c| c.to_string())
+                    .collect::<Vec<String>>();
+                (name, index_columns)
+            })
+            .collect::<Vec<(String, Vec<String>)>>();
+        connection.execute_batch("BEGIN;")?;
+
+        Ok(Self {
+            connection,
+            insert_statement,
+            column_types: columns.iter().map(|(_, typ)| *typ).collect(),
+            indices,
+            table: table.to_string(),
+            buffer: Vec::new(),
+            rows_in_transaction: 0,
+            failed_rows: 0,
+            first_error: None,
+            finished: false,
+        })
+    }
+
+    fn insert_row(&mut self, line: &str) -> anyhow::Result<()> {
+        let fields = line.split(TAB).collect::<Vec<&str>>();
+        if fields.len() != self.column_types.len() {
+            bail!(
+                "expected {} fields for {}, got {}",
+                self.column_types.len(),
+                self.table,
+                fields.len()
+            )
+        }
+        let values = fields
+            .into_iter()
+            .zip(self.column_types.iter())
+            .map(|(raw, typ)| typ.parse_field(raw));
+        self.connection
+            .prepare_cached(&self.insert_statement)?
+            .execute(params_from_iter(values))?;
+        self.rows_in_transaction += 1;
+        if self.rows_in_transaction >= ROWS_PER_TRANSACTION {
+            self.commit()?;
+        }
+        Ok(())
+    }
+
+    fn record_failed_row(&mut self, error: anyhow::Error) {
+        self.failed_rows += 1;
+        if self.first_error.is_none() {
+            self.first_error = Some(error.to_string());
+        }
+    }
+
+    fn commit(&mut self) -> anyhow::Result<()> {
+        self.connection.execute_batch("COMMIT; BEGIN;")?;
+        self.rows_in_transaction = 0;
+        Ok(())
+    }
+
+    /// Commit any remaining rows and make the indices, must be called after
+    /// the last row has been written. Fails if any row could not be
+    /// inserted, the rows that could be are still committed.
+    fn finish(&mut self) -> anyhow::Result<()> {
+        if self.finished {
+            return Ok(());
+        }
+        self.finished = true;
+        if !self.buffer.is_empty() {
+            let remaining = std::mem::take(&mut self.buffer);
+            let inserted = std::str::from_utf8(&remaining)
+                .map_err(|e| anyhow!(e))
+                .and_then(|line| self.insert_row(line));
+            if let Err(e) = inserted {
+                self.record_failed_row(e);
+            }
+        }
+        self.connection.execute_batch("COMMIT;")?;
+        for (name, columns) in self.indices.iter() {
+            debug!("making index {name} on {}", self.table);
+            let columns = columns
+                .iter()
+                .map(|c| quote_identifier(c))
+                .collect::<Vec<String>>();
+            self.connection.execute_batch(&format!(
+                "CREATE INDEX {} ON {} ({});",
+                quote_identifier(name),
+                quote_identifier(&self.table),
+                columns.join(", ")
+            ))?;
+        }
+        if let Some(e) = self.first_error.as_ref() {
+            bail!(
+                "failed to insert {} row(s), first error: {e}",
+                self.failed_rows
+            )
+        }
+        Ok(())
+    }
+}
+
+impl Write for SqliteTableWriter {
+    /// Inserts each complete line and always consumes all of `buf`, a line
+    /// that fails to insert is skipped and counted, the first such error is
+    /// reported by [FinishWrite::finish_write].
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.buffer.extend_from_slice(buf);
+        let buffer = std::mem::take(&mut self.buffer);
+        let mut start = 0usize;
+        while let Some(offset) = memchr::memchr(b'\n', &buffer[start..]) {
+            let inserted = std::str::from_utf8(&buffer[start..start + offset])
+                .map_err(|e| anyhow!(e))
+                .and_then(|line| self.insert_row(line));
+            if let Err(e) = inserted {
+                self.record_failed_row(e);
+            }
+            start += offset + 1;
+        }
+        self.buffer = buffer[start..].to_vec();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl FinishWrite for SqliteTableWriter {
+    fn finish_write(&mut self) -> anyhow::Result<()> {
+        self.finish().with_context(|| {
+            format!("failed to finish writing table {}", self.table)
+        })
+    }
+}
+
+#[cfg(test)]
+mod sqlite_tests {
+    use std::io::Write;
+
+    use rusqlite::Connection;
+
+    use crate::sqlite::{ColumnType, SqliteTableWriter};
+    use crate::writers::FinishWrite;
+
+    #[test]
+    fn test_sqlite_table_writer() {
+        let dir = tempfile::tempdir().unwrap();
+        let db_fp = dir.path().join("test.sqlite");
+        let columns = vec![
+            ("chrom".to_string(), ColumnType::Text),
+            ("start".to_string(), ColumnType::Integer),
+            ("score".to_string(), ColumnType::Real),
+            ("pass".to_string(), ColumnType::Integer),
+        ];
+        {
+            let mut writer = SqliteTableWriter::new(
+                &db_fp,
+                false,
+                "test",
+                &columns,
+                &[&["chrom", "start"]],
+            )
+            .unwrap();
+            // rows can be split over multiple writes
+            writer.write_all(b"chr1\t10\t0.5\ttrue\nchr1\t2").unwrap();
+            writer.write_all(b"0\t.\tfalse\n").unwrap();
+            // bad rows are consumed and reported when finishing
+            writer.write_all(b"chr1\t30\n").unwrap();
+            // rows after a bad row in the same write are kept
+            writer.write_all(b"chr1\t40\nchr1\t50\t.\tfalse\n").unwrap();
+            let err = writer.finish_write().unwrap_err();
+            assert!(format!("{err:#}").contains("failed to insert 2 row(s)"));
+        }
+        assert!(SqliteTableWriter::new(&db_fp, false, "test", &columns, &[])
+            .is_err());
+        assert!(SqliteTableWriter::new(
+            &db_fp,
+            false,
+            "test",
+            &columns,
+            &[&["nope"]]
+        )
+        .is_err());
+
+        let connection = Connection::open(&db_fp).unwrap();
+        let rows = connection
+            .prepare(
+                "SELECT chrom, start, score, pass FROM test ORDER BY start",
+            )
+            .unwrap()
+            .query_map([], |row| {
+                Ok((
+                    row.get::<_, String>(0)?,
+                    row.get::<_, i64>(1)?,
+                    row.get::<_, Option<f64>>(2)?,
+                    row.get::<_, i64>(3)?,
+                ))
+            })
+            .unwrap()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(
+            rows,
+            vec![
+                ("chr1".to_string(), 10, Some(0.5), 1),
+                ("chr1".to_string(), 20, None, 0),
+                ("chr1".to_string(), 50, None, 0)
+            ]
+        );
+        let n_indices: i64 = connection
+            .query_row(
+                "SELECT count(*) FROM sqlite_master WHERE type = 'index'",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(n_indices, 1);
+    }
+}
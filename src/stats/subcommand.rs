@@ -1,5 +1,5 @@
 use std::fs::File;
-use std::io::{stdout, BufRead, BufReader, BufWriter, Write};
+use std::io::{stdout, BufRead, BufWriter, Write};
 use std::path::PathBuf;
 
 use anyhow::{anyhow, bail};
@@ -15,6 +15,7 @@ use crate::dmr::bedmethyl::BedMethylLine;
 use crate::logging::init_logging;
 use crate::mod_base_code::ModCodeRepr;
 use crate::monoid::Moniod;
+use crate::parsing_utils::open_text_input;
 use crate::stats::MethylationStats;
 use crate::tabix::HtsTabixHandler;
 use crate::util::{get_subroutine_progress_bar, get_ticker, GenomeRegion};
@@ -24,7 +25,7 @@ use crate::util::{get_subroutine_progress_bar, get_ticker, GenomeRegion};
 pub struct EntryStats {
     /// Input bedMethyl table. Should be bgzip-compressed and have an
     /// associated Tabix index. The tabix index will be assumed to be
-    /// $this_file.tbi
+    /// $this_file.tbi or $this_file.csi
     in_bedmethyl: PathBuf,
     /// BED file of regions to aggregate base modification over.
     #[arg(long)]
@@ -83,7 +84,7 @@ impl EntryStats {
             .num_threads(self.threads)
             .build()?;
         let mpb = indicatif::MultiProgress::new();
-        let mut reader = BufReader::new(File::open(&self.regions)?)
+        let mut reader = open_text_input(&self.regions)?
             .lines()
             .skip_while(|r| {
                 r.as_ref().map(|l| l.starts_with('#')).unwrap_or(true)
@@ -103,7 +104,7 @@ impl EntryStats {
         };
         let parse_pb = mpb.add(get_ticker());
         parse_pb.set_message("parsing regions");
-        let genome_regions = BufReader::new(File::open(&self.regions)?)
+        let genome_regions = open_text_input(&self.regions)?
             .lines()
             .progress_with(parse_pb)
             .map(|r| {
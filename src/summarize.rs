@@ -1,7 +1,6 @@
 use std::collections::{HashMap, HashSet};
 use std::path::PathBuf;
 
-use derive_new::new;
 use indicatif::ParallelProgressIterator;
 
 use log::{debug, error, info};
@@ -11,16 +10,17 @@ use crate::mod_bam::{BaseModCall, CollapseMethod, EdgeFilter};
 use crate::mod_base_code::{BaseState, DnaBase, ModCodeRepr};
 use crate::monoid::Moniod;
 use crate::position_filter::StrandedPositionFilter;
+use crate::provenance::BasecallProvenance;
 use crate::read_ids_to_base_mod_probs::ReadIdsToBaseModProbs;
 use crate::reads_sampler::get_sampled_read_ids_to_base_mod_probs;
 use crate::record_processor::WithRecords;
 use crate::threshold_mod_caller::MultipleThresholdModCaller;
 
 use crate::thresholds::calc_thresholds_per_base;
-use crate::util::{get_master_progress_bar, Region};
+use crate::util::{get_master_progress_bar, Region, Strand};
 
 /// Count statistics from a modBAM.
-#[derive(Debug, new, PartialEq)]
+#[derive(Debug, PartialEq)]
 pub struct ModSummary<'a> {
     /// For each canonical base, how many reads had
     /// base modification calls for this base.
@@ -42,6 +42,24 @@ pub struct ModSummary<'a> {
     pub region: Option<&'a Region>,
     /// Mapping of which modcodes were observed for each base
     pub per_base_mod_codes: HashMap<DnaBase, HashSet<ModCodeRepr>>,
+    /// When requested, the base modification call counts split by the
+    /// reference strand of the calls, only calls on mapped reads are counted.
+    pub strand_call_counts: Option<StrandCallCounts>,
+    /// Basecallers and models that made the calls, from the modBAM header.
+    pub provenance: BasecallProvenance,
+}
+
+/// Base modification call counts for each canonical base and reference
+/// strand.
+#[derive(Debug, Default, PartialEq)]
+pub struct StrandCallCounts {
+    /// How many of each base modification code were observed and not
+    /// filtered out.
+    pub mod_call_counts: HashMap<(DnaBase, Strand), HashMap<BaseState, u64>>,
+    /// How many of each base modification code were observed but filtered
+    /// out.
+    pub filtered_mod_call_counts:
+        HashMap<(DnaBase, Strand), HashMap<BaseState, u64>>,
 }
 
 impl<'a> ModSummary<'a> {
@@ -71,11 +89,13 @@ pub fn summarize_modbam<'a>(
     edge_filter: Option<&EdgeFilter>,
     position_filter: Option<&StrandedPositionFilter<()>>,
     only_mapped: bool,
+    by_strand: bool,
     suppress_progress: bool,
 ) -> anyhow::Result<ModSummary<'a>> {
     let read_ids_to_base_mod_calls =
         get_sampled_read_ids_to_base_mod_probs::<ReadIdsToBaseModProbs>(
             bam_fp,
+            None,
             threads,
             interval_size,
             sample_frac,
@@ -85,7 +105,9 @@ pub fn summarize_modbam<'a>(
             collapse_method,
             edge_filter,
             position_filter,
+            None,
             only_mapped,
+            None,
             suppress_progress,
         )?;
 
@@ -105,10 +127,14 @@ pub fn summarize_modbam<'a>(
         )?
     };
 
+    let provenance = BasecallProvenance::from_path(bam_fp)?;
+
     sampled_reads_to_summary(
         read_ids_to_base_mod_calls,
         &threshold_caller,
         region,
+        by_strand,
+        provenance,
         suppress_progress,
     )
 }
@@ -117,6 +143,8 @@ pub(crate) fn sampled_reads_to_summary<'a>(
     read_ids_to_mod_calls: ReadIdsToBaseModProbs,
     threshold_caller: &MultipleThresholdModCaller,
     region: Option<&'a Region>,
+    by_strand: bool,
+    provenance: BasecallProvenance,
     suppress_progress: bool,
 ) -> anyhow::Result<ModSummary<'a>> {
     let total_reads_used = read_ids_to_mod_calls.num_reads();
@@ -131,9 +159,11 @@ pub(crate) fn sampled_reads_to_summary<'a>(
         .inner
         .par_iter()
         .progress_with(pb)
-        .map(|(_read_id, canonical_base_to_calls)| {
+        .map(|(read_id, canonical_base_to_calls)| {
             let mut mod_call_counts = HashMap::new();
             let mut filtered_mod_call_counts = HashMap::new();
+            let mut strand_mod_call_counts = HashMap::new();
+            let mut strand_filtered_mod_call_counts = HashMap::new();
             let mut reads_with_mod_calls = HashMap::new();
             let mut observed_mods = HashMap::new();
             for (&canonical_base, base_modification_probs) in
@@ -147,6 +177,12 @@ pub(crate) fn sampled_reads_to_summary<'a>(
                     filtered_mod_call_counts
                         .entry(canonical_base)
                         .or_insert(HashMap::new());
+                let mut ref_strands = if by_strand {
+                    read_ids_to_mod_calls
+                        .iter_ref_strands(read_id, &canonical_base)
+                } else {
+                    None
+                };
 
                 base_modification_probs
                     .iter()
@@ -196,37 +232,60 @@ pub(crate) fn sampled_reads_to_summary<'a>(
                         // }
                     })
                     .for_each(|(threshold_call, argmax_call)| {
-                        let agg = match (threshold_call, argmax_call) {
-                            (BaseModCall::Canonical(_), _) => {
-                                canonical_base_mod_counts
-                                    .entry(BaseState::Canonical(canonical_base))
-                                    .or_insert(0)
-                            }
-                            (BaseModCall::Modified(_, mod_code_repr), _) => {
-                                canonical_base_mod_counts
-                                    .entry(BaseState::Modified(mod_code_repr))
-                                    .or_insert(0)
-                            }
-                            (
-                                BaseModCall::Filtered,
-                                BaseModCall::Canonical(_),
-                            ) => canonical_base_filtered_mod_counts
-                                .entry(BaseState::Canonical(canonical_base))
-                                .or_insert(0),
-                            (
-                                BaseModCall::Filtered,
-                                BaseModCall::Modified(_, mod_code_repr),
-                            ) => canonical_base_filtered_mod_counts
-                                .entry(BaseState::Modified(mod_code_repr))
-                                .or_insert(0),
-                            (BaseModCall::Filtered, BaseModCall::Filtered) => {
-                                error!("should not get filtered argmax calls");
-                                unreachable!(
-                                    "should not get filtered argmax calls"
-                                );
-                            }
+                        let (base_state, passed) =
+                            match (threshold_call, argmax_call) {
+                                (BaseModCall::Canonical(_), _) => {
+                                    (BaseState::Canonical(canonical_base), true)
+                                }
+                                (
+                                    BaseModCall::Modified(_, mod_code_repr),
+                                    _,
+                                ) => (BaseState::Modified(mod_code_repr), true),
+                                (
+                                    BaseModCall::Filtered,
+                                    BaseModCall::Canonical(_),
+                                ) => (
+                                    BaseState::Canonical(canonical_base),
+                                    false,
+                                ),
+                                (
+                                    BaseModCall::Filtered,
+                                    BaseModCall::Modified(_, mod_code_repr),
+                                ) => {
+                                    (BaseState::Modified(mod_code_repr), false)
+                                }
+                                (
+                                    BaseModCall::Filtered,
+                                    BaseModCall::Filtered,
+                                ) => {
+                                    error!(
+                                        "should not get filtered argmax calls"
+                                    );
+                                    unreachable!(
+                                        "should not get filtered argmax calls"
+                                    );
+                                }
+                            };
+                        let counts = if passed {
+                            &mut *canonical_base_mod_counts
+                        } else {
+                            &mut *canonical_base_filtered_mod_counts
                         };
-                        *agg += 1u64;
+                        *counts.entry(base_state).or_insert(0) += 1u64;
+                        if let Some(strand) =
+                            ref_strands.as_mut().and_then(|it| it.next())
+                        {
+                            let strand_counts = if passed {
+                                &mut strand_mod_call_counts
+                            } else {
+                                &mut strand_filtered_mod_call_counts
+                            };
+                            *strand_counts
+                                .entry((canonical_base, strand))
+                                .or_insert(HashMap::new())
+                                .entry(base_state)
+                                .or_insert(0) += 1u64;
+                        }
                     });
             }
             ReadSummaryChunk {
@@ -234,6 +293,8 @@ pub(crate) fn sampled_reads_to_summary<'a>(
                 mod_call_counts,
                 filtered_mod_call_counts,
                 observed_mods,
+                strand_mod_call_counts,
+                strand_filtered_mod_call_counts,
             }
         })
         .reduce(|| ReadSummaryChunk::zero(), |a, b| a.op(b));
@@ -246,15 +307,21 @@ pub(crate) fn sampled_reads_to_summary<'a>(
         .map(|(b, t)| (*b, *t))
         .collect::<HashMap<DnaBase, f32>>();
 
-    Ok(ModSummary::new(
-        read_summary_chunk.reads_with_mod_calls,
-        read_summary_chunk.mod_call_counts,
-        read_summary_chunk.filtered_mod_call_counts,
+    Ok(ModSummary {
+        reads_with_mod_calls: read_summary_chunk.reads_with_mod_calls,
+        mod_call_counts: read_summary_chunk.mod_call_counts,
+        filtered_mod_call_counts: read_summary_chunk.filtered_mod_call_counts,
         total_reads_used,
         per_base_thresholds,
         region,
-        read_summary_chunk.observed_mods,
-    ))
+        per_base_mod_codes: read_summary_chunk.observed_mods,
+        strand_call_counts: by_strand.then_some(StrandCallCounts {
+            mod_call_counts: read_summary_chunk.strand_mod_call_counts,
+            filtered_mod_call_counts: read_summary_chunk
+                .strand_filtered_mod_call_counts,
+        }),
+        provenance,
+    })
 }
 
 #[derive(Debug)]
@@ -263,6 +330,9 @@ struct ReadSummaryChunk {
     mod_call_counts: HashMap<DnaBase, HashMap<BaseState, u64>>,
     filtered_mod_call_counts: HashMap<DnaBase, HashMap<BaseState, u64>>,
     observed_mods: HashMap<DnaBase, HashSet<ModCodeRepr>>,
+    strand_mod_call_counts: HashMap<(DnaBase, Strand), HashMap<BaseState, u64>>,
+    strand_filtered_mod_call_counts:
+        HashMap<(DnaBase, Strand), HashMap<BaseState, u64>>,
 }
 
 impl Moniod for ReadSummaryChunk {
@@ -272,26 +342,15 @@ impl Moniod for ReadSummaryChunk {
             mod_call_counts: HashMap::new(),
             filtered_mod_call_counts: HashMap::new(),
             observed_mods: HashMap::new(),
+            strand_mod_call_counts: HashMap::new(),
+            strand_filtered_mod_call_counts: HashMap::new(),
         }
     }
 
     fn op(self, other: Self) -> Self {
-        let mut mod_call_counts = self.mod_call_counts;
-        let mut filtered_mod_call_counts = self.filtered_mod_call_counts;
-        let mut total = self.reads_with_mod_calls;
-        let mut observed_mods = self.observed_mods;
-
-        total.op_mut(other.reads_with_mod_calls);
-        mod_call_counts.op_mut(other.mod_call_counts);
-        filtered_mod_call_counts.op_mut(other.filtered_mod_call_counts);
-        observed_mods.op_mut(other.observed_mods);
-
-        Self {
-            reads_with_mod_calls: total,
-            mod_call_counts,
-            filtered_mod_call_counts,
-            observed_mods,
-        }
+        let mut this = self;
+        this.op_mut(other);
+        this
     }
 
     fn op_mut(&mut self, other: Self) {
@@ -299,6 +358,9 @@ impl Moniod for ReadSummaryChunk {
         self.mod_call_counts.op_mut(other.mod_call_counts);
         self.filtered_mod_call_counts.op_mut(other.filtered_mod_call_counts);
         self.observed_mods.op_mut(other.observed_mods);
+        self.strand_mod_call_counts.op_mut(other.strand_mod_call_counts);
+        self.strand_filtered_mod_call_counts
+            .op_mut(other.strand_filtered_mod_call_counts);
     }
 
     fn len(&self) -> usize {
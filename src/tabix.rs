@@ -1,15 +1,18 @@
+use std::ffi::CString;
 use std::marker::PhantomData;
 use std::ops::Range;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
-use anyhow::Context;
+use anyhow::{bail, Context};
 use itertools::Itertools;
 use log_once::debug_once;
+use rust_htslib::htslib;
 use rust_htslib::tbx::{Read, Reader as TbxReader};
 use rustc_hash::FxHashMap;
+use url::Url;
 
-use crate::dmr::bedmethyl::BedMethylLine;
-use crate::errs::{MkError, MkResult};
+use crate::dmr::bedmethyl::{BedMethylLine, DmrInputFormat};
+use crate::errs::{hts_io, IoRetry, MkError, MkResult};
 use crate::mod_base_code::{DnaBase, ModCodeRepr};
 use crate::util::StrandRule;
 
@@ -79,12 +82,70 @@ pub(crate) struct HtsTabixHandler<T: ParseBedLine> {
     pub(crate) indexed_fp: PathBuf,
     /// Mapping of name to tid
     contigs: FxHashMap<String, u64>,
+    retry: IoRetry,
     _t: PhantomData<T>,
 }
 
+/// Largest position a tabix (.tbi) index can address, contigs longer than this
+/// (e.g. some plant chromosomes) require a CSI index.
+pub(crate) const TBI_MAX_POSITION: u64 = (1 << 29) - 1;
+
+/// Largest position htslib can address (`HTS_POS_MAX`), used to fetch whole
+/// contigs from either kind of index.
+const HTS_POS_MAX: u64 = ((i32::MAX as u64) << 32) | i32::MAX as u64;
+
+/// Find the index next to a bgzip-compressed file, either `$fp.tbi` or
+/// `$fp.csi`, htslib will prefer the CSI when both are present.
+pub(crate) fn find_tabix_index(fp: &Path) -> Option<PathBuf> {
+    ["csi", "tbi"].into_iter().find_map(|ext| {
+        let mut index_fp = fp.as_os_str().to_owned();
+        index_fp.push(format!(".{ext}"));
+        let index_fp = PathBuf::from(index_fp);
+        index_fp.exists().then_some(index_fp)
+    })
+}
+
+/// Check if the path is a URL that htslib should fetch remotely (with
+/// libcurl) rather than a local file.
+pub(crate) fn is_remote_path(path: &Path) -> bool {
+    path.to_str()
+        .map(|s| {
+            ["https://", "http://", "ftp://"]
+                .iter()
+                .any(|scheme| s.starts_with(scheme))
+        })
+        .unwrap_or(false)
+}
+
+/// Open a tabix reader for a local file or a remote URL. For remote files
+/// htslib downloads the index (`.tbi` or `.csi`) into the working directory
+/// and reuses it on subsequent opens, only the requested blocks of the
+/// bgzip-compressed file itself are fetched.
+fn open_tbx_reader(path: &Path) -> MkResult<TbxReader> {
+    if is_remote_path(path) {
+        let url = path.to_str().and_then(|s| Url::parse(s).ok()).ok_or_else(
+            || MkError::InvalidBedMethyl(format!("invalid URL {path:?}")),
+        )?;
+        hts_io(|| TbxReader::from_url(&url))
+    } else {
+        hts_io(|| TbxReader::from_path(path))
+    }
+}
+
 impl<T: ParseBedLine> HtsTabixHandler<T> {
     pub(crate) fn from_path(path: &PathBuf) -> anyhow::Result<Self> {
-        let reader = TbxReader::from_path(path)?;
+        if !is_remote_path(path)
+            && path.exists()
+            && find_tabix_index(path).is_none()
+        {
+            bail!(
+                "did not find a tabix index for {path:?}, expected \
+                 {path:?}.tbi or {path:?}.csi"
+            )
+        }
+        let reader = open_tbx_reader(path).with_context(|| {
+            format!("failed to open tabix index for {path:?}")
+        })?;
         let contigs = reader
             .seqnames()
             .into_iter()
@@ -93,35 +154,83 @@ impl<T: ParseBedLine> HtsTabixHandler<T> {
             .context(
                 "failed to collect contig IDs and names, invalid tabix header?",
             )?;
-        Ok(Self { indexed_fp: path.to_owned(), contigs, _t: PhantomData })
+        Ok(Self {
+            indexed_fp: path.to_owned(),
+            contigs,
+            retry: IoRetry::default(),
+            _t: PhantomData,
+        })
+    }
+
+    /// Retry fetching records after transient IO errors.
+    pub(crate) fn with_retry(self, retry: IoRetry) -> Self {
+        Self { retry, ..self }
+    }
+
+    fn describe_fetch(&self, chrom: &str, range: &Range<u64>) -> String {
+        format!(
+            "failed to read {chrom}:{}-{} from {:?}",
+            range.start, range.end, self.indexed_fp
+        )
     }
 
     pub(crate) fn has_contig(&self, contig: &str) -> bool {
         self.contigs.contains_key(contig)
     }
 
+    #[inline]
+    fn fetch_lines_it(
+        reader: &mut TbxReader,
+    ) -> impl Iterator<Item = MkResult<String>> + '_ {
+        reader.records().map(|r| {
+            r.map_err(MkError::HtsLibError).and_then(|bs| {
+                String::from_utf8(bs).map_err(|e| {
+                    MkError::InvalidBedMethyl(format!(
+                        "record not valid Utf8, {e}"
+                    ))
+                })
+            })
+        })
+    }
+
     #[inline]
     fn fetch_region_it<'a>(
         &self,
         reader: &'a mut TbxReader,
         strand_rule: StrandRule,
     ) -> MkResult<impl Iterator<Item = MkResult<T>> + 'a> {
-        Ok(reader
-            .records()
-            .map(|r| {
-                r.map_err(|e| MkError::HtsLibError(e))
-                    .and_then(|bs| {
-                        String::from_utf8(bs).map_err(|e| {
-                            MkError::InvalidBedMethyl(format!(
-                                "record not valid Utf8, {e}"
-                            ))
-                        })
-                    })
-                    .and_then(|s| T::parse(&s))
-            })
+        Ok(Self::fetch_lines_it(reader)
+            .map(|r| r.and_then(|s| T::parse(&s)))
             .filter_ok(move |t| t.overlaps(strand_rule)))
     }
 
+    /// The first record of the first contig (in index order) that has any,
+    /// header lines are skipped by the index.
+    pub(crate) fn first_line(&self) -> MkResult<Option<String>> {
+        self.retry.run(
+            || format!("failed to read first record of {:?}", self.indexed_fp),
+            || {
+                let mut reader = open_tbx_reader(&self.indexed_fp)?;
+                for tid in self.contigs.values().copied().sorted() {
+                    hts_io(|| reader.fetch(tid, 0, TBI_MAX_POSITION))?;
+                    if let Some(line) = Self::fetch_lines_it(&mut reader).next()
+                    {
+                        return line.map(Some);
+                    }
+                }
+                Ok(None)
+            },
+        )
+    }
+
+    /// Open a reader that can be kept and used for many fetches with
+    /// [Self::fetch_region_from], so the index is only loaded once.
+    pub(crate) fn open_reader(&self, threads: usize) -> MkResult<TbxReader> {
+        let mut reader = open_tbx_reader(&self.indexed_fp)?;
+        reader.set_threads(threads)?;
+        Ok(reader)
+    }
+
     fn get_reader(
         &self,
         chrom: &str,
@@ -129,9 +238,8 @@ impl<T: ParseBedLine> HtsTabixHandler<T> {
         threads: usize,
     ) -> MkResult<Option<TbxReader>> {
         if let Some(&tid) = self.contigs.get(chrom) {
-            let mut reader = TbxReader::from_path(&self.indexed_fp)?;
-            reader.set_threads(threads)?;
-            reader.fetch(tid, range.start, range.end)?;
+            let mut reader = self.open_reader(threads)?;
+            hts_io(|| reader.fetch(tid, range.start, range.end))?;
             Ok(Some(reader))
         } else {
             debug_once!("{:?} does not contain {chrom}", self.indexed_fp);
@@ -146,17 +254,88 @@ impl<T: ParseBedLine> HtsTabixHandler<T> {
         strand_rule: StrandRule,
         io_threads: usize,
     ) -> MkResult<Vec<T>> {
-        if let Some(mut reader) = self.get_reader(chrom, range, io_threads)? {
-            let it = self.fetch_region_it(&mut reader, strand_rule)?;
-            it.collect()
-        } else {
-            Ok(Vec::new())
-        }
+        self.retry.run(
+            || self.describe_fetch(chrom, range),
+            || {
+                if let Some(mut reader) =
+                    self.get_reader(chrom, range, io_threads)?
+                {
+                    let it = self.fetch_region_it(&mut reader, strand_rule)?;
+                    it.collect()
+                } else {
+                    Ok(Vec::new())
+                }
+            },
+        )
+    }
+
+    /// Same as [Self::fetch_region] but reads with a reader from
+    /// [Self::open_reader] instead of opening a new one.
+    pub(crate) fn fetch_region_from(
+        &self,
+        reader: &mut TbxReader,
+        chrom: &str,
+        range: &Range<u64>,
+        strand_rule: StrandRule,
+    ) -> MkResult<Vec<T>> {
+        self.retry.run(
+            || self.describe_fetch(chrom, range),
+            || {
+                if let Some(&tid) = self.contigs.get(chrom) {
+                    hts_io(|| reader.fetch(tid, range.start, range.end))?;
+                    let it = self.fetch_region_it(reader, strand_rule)?;
+                    it.collect()
+                } else {
+                    Ok(Vec::new())
+                }
+            },
+        )
+    }
+
+    /// Stream the records of a contig, records are parsed as they are read
+    /// so the whole contig is never held in memory. Empty when the index
+    /// doesn't have the contig. Only opening the contig is retried.
+    pub(crate) fn iter_contig(
+        &self,
+        chrom: &str,
+        io_threads: usize,
+    ) -> MkResult<impl Iterator<Item = MkResult<T>>> {
+        let range = 0..HTS_POS_MAX;
+        let mut reader = self.retry.run(
+            || self.describe_fetch(chrom, &range),
+            || self.get_reader(chrom, &range, io_threads),
+        )?;
+        let mut buf = Vec::new();
+        Ok(std::iter::from_fn(move || {
+            let reader = reader.as_mut()?;
+            match reader.read(&mut buf) {
+                Ok(true) => Some(
+                    String::from_utf8(std::mem::take(&mut buf))
+                        .map_err(|e| {
+                            MkError::InvalidBedMethyl(format!(
+                                "record not valid Utf8, {e}"
+                            ))
+                        })
+                        .and_then(|l| T::parse(&l)),
+                ),
+                Ok(false) => None,
+                Err(e) => Some(Err(MkError::HtsLibError(e))),
+            }
+        }))
     }
 
     pub fn get_contigs(&self) -> Vec<String> {
         self.contigs.keys().map(|x| x.to_owned()).collect()
     }
+
+    /// Contig names in the order they appear in the index.
+    pub(crate) fn get_contigs_in_order(&self) -> Vec<String> {
+        self.contigs
+            .iter()
+            .sorted_by_key(|(_, &tid)| tid)
+            .map(|(name, _)| name.to_owned())
+            .collect()
+    }
 }
 pub type BedMethylTbxIndex = HtsTabixHandler<BedMethylLine>;
 
@@ -168,11 +347,39 @@ impl HtsTabixHandler<BedMethylLine> {
         min_coverage: u64,
         code_lookup: &FxHashMap<ModCodeRepr, DnaBase>,
         io_threads: usize,
+        input_format: DmrInputFormat,
+    ) -> MkResult<Vec<BedMethylLine>> {
+        // the whole region is read again after a transient error, partial
+        // results are dropped
+        self.retry.run(
+            || self.describe_fetch(chrom, range),
+            || {
+                self.read_bedmethyl_check_code_once(
+                    chrom,
+                    range,
+                    min_coverage,
+                    code_lookup,
+                    io_threads,
+                    input_format,
+                )
+            },
+        )
+    }
+
+    fn read_bedmethyl_check_code_once(
+        &self,
+        chrom: &str,
+        range: &Range<u64>,
+        min_coverage: u64,
+        code_lookup: &FxHashMap<ModCodeRepr, DnaBase>,
+        io_threads: usize,
+        input_format: DmrInputFormat,
     ) -> MkResult<Vec<BedMethylLine>> {
         // fail when we can't get the reader, but None means we're missing this
         // chrom - which is OK
         if let Some(mut reader) = self.get_reader(chrom, range, io_threads)? {
-            let it = self.fetch_region_it(&mut reader, StrandRule::Both)?;
+            let it = Self::fetch_lines_it(&mut reader)
+                .map(|r| r.and_then(|s| input_format.parse_line(&s)));
             // do the filtering here.
             it.filter_ok(|bml| bml.valid_coverage >= min_coverage)
                 .filter_ok(|bml| {
@@ -194,6 +401,23 @@ impl HtsTabixHandler<BedMethylLine> {
         }
     }
 
+    /// Resolve `auto` to the format of this file by inspecting its first
+    /// record.
+    pub(crate) fn resolve_input_format(
+        &self,
+        input_format: DmrInputFormat,
+    ) -> anyhow::Result<DmrInputFormat> {
+        if input_format != DmrInputFormat::auto {
+            return Ok(input_format);
+        }
+        match self.first_line()? {
+            Some(line) => DmrInputFormat::detect(&line).with_context(|| {
+                format!("failed to detect format of {:?}", self.indexed_fp)
+            }),
+            None => Ok(DmrInputFormat::bedmethyl),
+        }
+    }
+
     pub(crate) fn read_bedmethyl(
         &self,
         chrom: &str,
@@ -208,3 +432,87 @@ impl HtsTabixHandler<BedMethylLine> {
         }
     }
 }
+
+/// Build a tabix index (`$bgzf_fp.tbi`), or a CSI index (`$bgzf_fp.csi`) when
+/// `csi` is true, for a bgzip-compressed, sorted, BED-like file (bedMethyl,
+/// bedGraph, etc.). Lines starting with '#' are treated as headers.
+pub(crate) fn build_bed_tabix_index(
+    bgzf_fp: &Path,
+    csi: bool,
+) -> anyhow::Result<()> {
+    let c_fp =
+        bgzf_fp.to_str().and_then(|s| CString::new(s).ok()).with_context(
+            || format!("invalid path for tabix index, {bgzf_fp:?}"),
+        )?;
+    // a min_shift of 0 makes a .tbi, otherwise a .csi with the default
+    // min_shift used by `tabix --csi`
+    let min_shift = if csi { 14 } else { 0 };
+    let ret = unsafe {
+        htslib::tbx_index_build(c_fp.as_ptr(), min_shift, &htslib::tbx_conf_bed)
+    };
+    if ret != 0 {
+        bail!(
+            "failed to build tabix index for {bgzf_fp:?} (return code {ret}), \
+             is the file bgzip-compressed and sorted?"
+        )
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tabix_tests {
+    use std::path::PathBuf;
+
+    use crate::dmr::bedmethyl::BedMethylLine;
+    use std::path::Path;
+
+    use crate::tabix::{
+        build_bed_tabix_index, find_tabix_index, is_remote_path,
+        HtsTabixHandler,
+    };
+    use crate::util::StrandRule;
+
+    #[test]
+    fn test_tabix_csi_index() {
+        let bed_fp = PathBuf::from(
+            "tests/resources/\
+             lung_00733-m_adjacent-normal_5mc-5hmc_chr20_cpg_pileup.bed.gz",
+        );
+        let temp_dir = tempfile::tempdir().unwrap();
+        let csi_bed_fp = temp_dir.path().join("pileup.bed.gz");
+        std::fs::copy(&bed_fp, &csi_bed_fp).unwrap();
+        assert!(
+            HtsTabixHandler::<BedMethylLine>::from_path(&csi_bed_fp).is_err()
+        );
+        build_bed_tabix_index(&csi_bed_fp, true).unwrap();
+        assert_eq!(
+            find_tabix_index(&csi_bed_fp),
+            Some(temp_dir.path().join("pileup.bed.gz.csi"))
+        );
+
+        let tbi_index =
+            HtsTabixHandler::<BedMethylLine>::from_path(&bed_fp).unwrap();
+        let csi_index =
+            HtsTabixHandler::<BedMethylLine>::from_path(&csi_bed_fp).unwrap();
+        assert_eq!(tbi_index.get_contigs(), csi_index.get_contigs());
+        let range = 9_680_000..9_800_000u64;
+        let expected = tbi_index
+            .fetch_region("chr20", &range, StrandRule::Both, 1)
+            .unwrap();
+        let observed = csi_index
+            .fetch_region("chr20", &range, StrandRule::Both, 1)
+            .unwrap();
+        assert!(!expected.is_empty());
+        assert_eq!(expected, observed);
+    }
+
+    #[test]
+    fn test_tabix_remote_paths() {
+        assert!(is_remote_path(Path::new(
+            "https://example.com/cohort/sample.bed.gz"
+        )));
+        assert!(is_remote_path(Path::new("http://example.com/sample.bed.gz")));
+        assert!(!is_remote_path(Path::new("sample.bed.gz")));
+        assert!(!is_remote_path(Path::new("/data/https/sample.bed.gz")));
+    }
+}
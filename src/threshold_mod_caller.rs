@@ -1,15 +1,21 @@
 use crate::mod_bam::{BaseModCall, BaseModProbs, SeqPosBaseModProbs, SkipMode};
 use crate::mod_base_code::{DnaBase, ModCodeRepr};
 use derive_new::new;
+use itertools::Itertools;
 use rustc_hash::FxHashMap;
 use std::collections::HashMap;
 
-#[derive(new)]
+#[derive(new, Clone)]
 pub struct MultipleThresholdModCaller {
     per_base_thresholds: HashMap<DnaBase, f32>,
     // todo maybe allow this per primary base?
     per_mod_thresholds: HashMap<ModCodeRepr, f32>,
     default_threshold: f32,
+    /// Thresholds to use for records with a read group (RG tag), records
+    /// without a read group or with a read group not in this map use the
+    /// thresholds above.
+    #[new(default)]
+    read_group_callers: HashMap<String, MultipleThresholdModCaller>,
 }
 
 impl MultipleThresholdModCaller {
@@ -18,9 +24,51 @@ impl MultipleThresholdModCaller {
             per_base_thresholds: HashMap::new(),
             per_mod_thresholds: HashMap::new(),
             default_threshold: 0f32,
+            read_group_callers: HashMap::new(),
         }
     }
 
+    /// Use separate thresholds for records in each read group, replaces the
+    /// thresholds for read groups that already have them.
+    pub(crate) fn with_read_group_callers(
+        mut self,
+        read_group_callers: HashMap<String, MultipleThresholdModCaller>,
+    ) -> Self {
+        self.read_group_callers.extend(read_group_callers);
+        self
+    }
+
+    /// Get the caller to use for a record with the read group, falls back to
+    /// these thresholds when there aren't any for the read group.
+    #[inline]
+    pub(crate) fn for_read_group(&self, read_group: Option<&str>) -> &Self {
+        read_group
+            .and_then(|rg| self.read_group_callers.get(rg))
+            .unwrap_or(self)
+    }
+
+    /// Fill in the thresholds that are missing from these (read group)
+    /// thresholds with the ones in `global`. Per-base thresholds are only
+    /// inherited when there isn't a default threshold.
+    pub(crate) fn inherit_missing(mut self, global: &Self) -> Self {
+        if self.default_threshold == 0f32 {
+            for (base, threshold) in global.per_base_thresholds.iter() {
+                self.per_base_thresholds.entry(*base).or_insert(*threshold);
+            }
+            self.default_threshold = global.default_threshold;
+        }
+        for (code, threshold) in global.per_mod_thresholds.iter() {
+            self.per_mod_thresholds.entry(*code).or_insert(*threshold);
+        }
+        self
+    }
+
+    pub(crate) fn iter_read_group_callers(
+        &self,
+    ) -> impl Iterator<Item = (&String, &MultipleThresholdModCaller)> {
+        self.read_group_callers.iter().sorted_by(|(a, _), (b, _)| a.cmp(b))
+    }
+
     /// Make a base modification call from the probabilities of each
     /// modification class. Result will be Err if the raw mod code cannot be
     /// parsed (this will change in the future, when BaseModProbs don't need
@@ -156,6 +204,34 @@ impl MultipleThresholdModCaller {
     ) -> impl Iterator<Item = (&ModCodeRepr, &f32)> {
         self.per_mod_thresholds.iter()
     }
+
+    /// Thresholds in use as comma-separated `<base or code>:<threshold>`
+    /// pairs (e.g. `C:0.7646,h:0.8`), "none" when calls aren't filtered.
+    pub(crate) fn describe(&self) -> String {
+        let per_base = self
+            .per_base_thresholds
+            .iter()
+            .sorted_by_key(|(base, _)| base.char())
+            .map(|(base, threshold)| format!("{}:{threshold}", base.char()));
+        let per_mod = self
+            .per_mod_thresholds
+            .iter()
+            .sorted_by_key(|(code, _)| **code)
+            .map(|(code, threshold)| format!("{code}:{threshold}"));
+        let mut thresholds = per_base.chain(per_mod).collect::<Vec<String>>();
+        if self.per_base_thresholds.is_empty() && self.default_threshold > 0f32
+        {
+            thresholds.push(format!("default:{}", self.default_threshold));
+        }
+        for (read_group, caller) in self.iter_read_group_callers() {
+            thresholds.push(format!("RG:{read_group}[{}]", caller.describe()));
+        }
+        if thresholds.is_empty() {
+            "none".to_string()
+        } else {
+            thresholds.join(",")
+        }
+    }
 }
 
 #[cfg(test)]
@@ -473,4 +549,81 @@ mod threshold_mod_caller_tests {
         expected_base_mod_probs.add_base_mod_prob('h'.into(), 0f32).unwrap();
         assert_eq!(call, expected_base_mod_probs);
     }
+
+    #[test]
+    fn test_multi_threshold_describe() {
+        assert_eq!(
+            MultipleThresholdModCaller::new_passthrough().describe(),
+            "none"
+        );
+        let caller = MultipleThresholdModCaller::new(
+            HashMap::from([(DnaBase::C, 0.75), (DnaBase::A, 0.5)]),
+            HashMap::from([('h'.into(), 0.8)]),
+            0f32,
+        );
+        assert_eq!(caller.describe(), "A:0.5,C:0.75,h:0.8");
+        let caller = MultipleThresholdModCaller::new(
+            HashMap::new(),
+            HashMap::new(),
+            0.7f32,
+        );
+        assert_eq!(caller.describe(), "default:0.7");
+    }
+
+    #[test]
+    fn test_multi_threshold_read_group_callers() {
+        let global = MultipleThresholdModCaller::new(
+            HashMap::from([(DnaBase::C, 0.75), (DnaBase::A, 0.5)]),
+            HashMap::from([('h'.into(), 0.8)]),
+            0f32,
+        );
+        let rg1 = MultipleThresholdModCaller::new(
+            HashMap::from([(DnaBase::C, 0.9)]),
+            HashMap::new(),
+            0f32,
+        )
+        .inherit_missing(&global);
+        assert_eq!(rg1.describe(), "A:0.5,C:0.9,h:0.8");
+        let rg2 = MultipleThresholdModCaller::new(
+            HashMap::new(),
+            HashMap::new(),
+            0.6,
+        )
+        .inherit_missing(&global);
+        assert_eq!(rg2.describe(), "h:0.8,default:0.6");
+        let caller = global.with_read_group_callers(HashMap::from([
+            ("rg1".to_string(), rg1),
+            ("rg2".to_string(), rg2),
+        ]));
+
+        let base_mod_probs = BaseModProbs::new_init('m', 0.85);
+        assert_eq!(
+            caller.call(&DnaBase::C, &base_mod_probs),
+            BaseModCall::Modified(0.85, 'm'.into())
+        );
+        assert_eq!(
+            caller
+                .for_read_group(Some("rg3"))
+                .call(&DnaBase::C, &base_mod_probs),
+            BaseModCall::Modified(0.85, 'm'.into())
+        );
+        assert_eq!(
+            caller
+                .for_read_group(Some("rg1"))
+                .call(&DnaBase::C, &base_mod_probs),
+            BaseModCall::Filtered
+        );
+        let base_mod_probs = BaseModProbs::new_init('a', 0.65);
+        assert_eq!(
+            caller
+                .for_read_group(Some("rg2"))
+                .call(&DnaBase::A, &base_mod_probs),
+            BaseModCall::Modified(0.65, 'a'.into())
+        );
+        assert_eq!(
+            caller.describe(),
+            "A:0.5,C:0.75,h:0.8,RG:rg1[A:0.5,C:0.9,h:0.8],RG:rg2[h:0.8,\
+             default:0.6]"
+        );
+    }
 }
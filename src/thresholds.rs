@@ -9,9 +9,10 @@ use crate::mod_base_code::{DnaBase, ModCodeRepr};
 use crate::position_filter::StrandedPositionFilter;
 use crate::read_ids_to_base_mod_probs::ReadIdsToBaseModProbs;
 use crate::reads_sampler::get_sampled_read_ids_to_base_mod_probs;
+use crate::reads_sampler::sampling_schedule::ContigQuotas;
 use crate::threshold_mod_caller::MultipleThresholdModCaller;
 use crate::util::Region;
-use log::{debug, info};
+use log::{debug, info, warn};
 use rayon::prelude::*;
 
 pub(crate) fn percentile_linear_interp(xs: &[f32], q: f32) -> MkResult<f32> {
@@ -37,8 +38,14 @@ pub(crate) fn percentile_linear_interp(xs: &[f32], q: f32) -> MkResult<f32> {
     }
 }
 
+/// Number of points in the full percentile curve, one every half percentile.
+const CURVE_POINTS: usize = 201;
+
 pub struct Percentiles {
     pub(crate) qs: Vec<(f32, f32)>,
+    /// Thresholds at evenly spaced percentiles from 0 to 100, only
+    /// calculated when requested.
+    pub(crate) curve: Option<Vec<(f32, f32)>>,
 }
 
 impl Percentiles {
@@ -47,11 +54,31 @@ impl Percentiles {
         desired_percentiles: &[f32],
     ) -> AnyhowResult<Self> {
         probs.par_sort_by(|x, y| x.partial_cmp(y).unwrap());
-        let qs = desired_percentiles
+        let qs = Self::interpolate(probs, desired_percentiles)?;
+        Ok(Self { qs, curve: None })
+    }
+
+    /// Also calculate the full percentile curve, `probs` must be the sorted
+    /// probabilities used to make these percentiles.
+    pub(crate) fn with_curve(self, probs: &[f32]) -> AnyhowResult<Self> {
+        let qs = (0..CURVE_POINTS)
+            .map(|i| i as f32 / (CURVE_POINTS - 1) as f32)
+            .collect::<Vec<f32>>();
+        let curve = Self::interpolate(probs, &qs)?;
+        Ok(Self { curve: Some(curve), ..self })
+    }
+
+    fn interpolate(
+        sorted_probs: &[f32],
+        qs: &[f32],
+    ) -> AnyhowResult<Vec<(f32, f32)>> {
+        let points = qs
             .iter()
-            .map(|q| percentile_linear_interp(&probs, *q).map(|p| (*q, p)))
+            .map(|q| {
+                percentile_linear_interp(sorted_probs, *q).map(|p| (*q, p))
+            })
             .collect::<Result<Vec<(f32, f32)>, _>>()?;
-        Ok(Self { qs })
+        Ok(points)
     }
 
     pub fn report(&self) -> String {
@@ -118,8 +145,59 @@ pub(crate) fn calc_thresholds_per_base(
     ))
 }
 
+/// Estimated thresholds for each primary sequence base in each read group.
+pub type ReadGroupThresholds = HashMap<String, HashMap<DnaBase, f32>>;
+
+fn percentile_thresholds(
+    mut can_base_probs: HashMap<DnaBase, Vec<f32>>,
+    filter_percentile: f32,
+) -> AnyhowResult<HashMap<DnaBase, f32>> {
+    can_base_probs
+        .iter_mut()
+        .map(|(dna_base, mod_base_probs)| {
+            mod_base_probs.par_sort_by(|x, y| x.partial_cmp(y).unwrap());
+            let threshold =
+                percentile_linear_interp(&mod_base_probs, filter_percentile)?;
+            Ok((*dna_base, threshold))
+        })
+        .collect()
+}
+
+/// Estimate the thresholds for each read group separately, bases without
+/// enough calls in a read group are left out (and should use the thresholds
+/// estimated with all reads).
+fn calc_read_group_thresholds(
+    read_ids_to_base_mod_probs: &ReadIdsToBaseModProbs,
+    filter_percentile: f32,
+) -> ReadGroupThresholds {
+    read_ids_to_base_mod_probs
+        .mle_probs_per_base_per_read_group()
+        .into_iter()
+        .map(|(read_group, can_base_probs)| {
+            let thresholds = can_base_probs
+                .into_iter()
+                .filter_map(|(dna_base, mut probs)| {
+                    probs.par_sort_by(|x, y| x.partial_cmp(y).unwrap());
+                    percentile_linear_interp(&probs, filter_percentile)
+                        .map_err(|e| {
+                            warn!(
+                                "failed to estimate threshold for base {} in \
+                                 read group {read_group}, {e}",
+                                dna_base.char()
+                            )
+                        })
+                        .ok()
+                        .map(|threshold| (dna_base, threshold))
+                })
+                .collect::<HashMap<DnaBase, f32>>();
+            (read_group, thresholds)
+        })
+        .collect()
+}
+
 pub fn calc_threshold_from_bam(
     bam_fp: &PathBuf,
+    reference: Option<&PathBuf>,
     threads: usize,
     interval_size: u32,
     sample_frac: Option<f64>,
@@ -131,35 +209,45 @@ pub fn calc_threshold_from_bam(
     collapse_method: Option<&CollapseMethod>,
     position_filter: Option<&StrandedPositionFilter<()>>,
     only_mapped: bool,
+    contig_quotas: Option<ContigQuotas>,
+    per_read_group: bool,
     suppress_progress: bool,
-) -> AnyhowResult<HashMap<DnaBase, f32>> {
-    let mut can_base_probs = get_modbase_probs_from_bam(
-        bam_fp,
-        threads,
-        interval_size,
-        sample_frac,
-        num_reads,
-        seed,
-        region,
-        collapse_method,
-        edge_filter,
-        position_filter,
-        only_mapped,
-        suppress_progress,
-    )?;
-    can_base_probs
-        .iter_mut()
-        .map(|(dna_base, mod_base_probs)| {
-            mod_base_probs.par_sort_by(|x, y| x.partial_cmp(y).unwrap());
-            let threshold =
-                percentile_linear_interp(&mod_base_probs, filter_percentile)?;
-            Ok((*dna_base, threshold))
-        })
-        .collect()
+) -> AnyhowResult<(HashMap<DnaBase, f32>, ReadGroupThresholds)> {
+    let read_ids_to_base_mod_probs =
+        get_sampled_read_ids_to_base_mod_probs::<ReadIdsToBaseModProbs>(
+            bam_fp,
+            reference,
+            threads,
+            interval_size,
+            sample_frac,
+            num_reads,
+            seed,
+            region,
+            collapse_method,
+            edge_filter,
+            position_filter,
+            None,
+            only_mapped,
+            contig_quotas,
+            suppress_progress,
+        )?;
+    let read_group_thresholds = if per_read_group {
+        calc_read_group_thresholds(
+            &read_ids_to_base_mod_probs,
+            filter_percentile,
+        )
+    } else {
+        HashMap::new()
+    };
+    let can_base_probs =
+        read_ids_to_base_mod_probs.mle_probs_per_base(suppress_progress);
+    let thresholds = percentile_thresholds(can_base_probs, filter_percentile)?;
+    Ok((thresholds, read_group_thresholds))
 }
 
 pub fn get_modbase_probs_from_bam(
     bam_fp: &PathBuf,
+    reference: Option<&PathBuf>,
     threads: usize,
     interval_size: u32,
     sample_frac: Option<f64>,
@@ -170,10 +258,12 @@ pub fn get_modbase_probs_from_bam(
     edge_filter: Option<&EdgeFilter>,
     position_filter: Option<&StrandedPositionFilter<()>>,
     only_mapped: bool,
+    contig_quotas: Option<ContigQuotas>,
     suppress_progress: bool,
 ) -> AnyhowResult<HashMap<DnaBase, Vec<f32>>> {
     get_sampled_read_ids_to_base_mod_probs::<ReadIdsToBaseModProbs>(
         bam_fp,
+        reference,
         threads,
         interval_size,
         sample_frac,
@@ -183,7 +273,9 @@ pub fn get_modbase_probs_from_bam(
         collapse_method,
         edge_filter,
         position_filter,
+        None,
         only_mapped,
+        contig_quotas,
         suppress_progress,
     )
     .map(|x| x.mle_probs_per_base(suppress_progress))
@@ -191,7 +283,7 @@ pub fn get_modbase_probs_from_bam(
 
 #[cfg(test)]
 mod thresolds_tests {
-    use super::percentile_linear_interp;
+    use super::{percentile_linear_interp, Percentiles, CURVE_POINTS};
 
     #[test]
     fn test_thresholds_oob() {
@@ -199,4 +291,19 @@ mod thresolds_tests {
         percentile_linear_interp(&xs, 0.95)
             .expect("should calculate percentile");
     }
+
+    #[test]
+    fn test_thresholds_percentile_curve() {
+        let mut xs =
+            (0..=100usize).rev().map(|i| i as f32).collect::<Vec<f32>>();
+        let percentiles = Percentiles::new(&mut xs, &[0.1, 0.5]).unwrap();
+        assert!(percentiles.curve.is_none());
+        let percentiles = percentiles.with_curve(&xs).unwrap();
+        assert_eq!(percentiles.qs, vec![(0.1, 10.0), (0.5, 50.0)]);
+        let curve = percentiles.curve.unwrap();
+        assert_eq!(curve.len(), CURVE_POINTS);
+        assert_eq!(curve[0], (0.0, 0.0));
+        assert_eq!(curve[1], (0.005, 0.5));
+        assert_eq!(curve[CURVE_POINTS - 1], (1.0, 100.0));
+    }
 }
@@ -0,0 +1,59 @@
+//! Small helpers for writing JSON by hand, values are encoded as strings and
+//! combined into objects with [`json_object`].
+
+/// Encode a string as a quoted and escaped JSON string.
+pub(crate) fn json_string(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len() + 2);
+    escaped.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                escaped.push_str(&format!("\\u{:04x}", c as u32))
+            }
+            c => escaped.push(c),
+        }
+    }
+    escaped.push('"');
+    escaped
+}
+
+/// Encode a float, NaN and infinities aren't valid JSON so they become
+/// `null`.
+pub(crate) fn json_float(x: f32) -> String {
+    if x.is_finite() {
+        format!("{x}")
+    } else {
+        "null".to_string()
+    }
+}
+
+/// Make a JSON object from keys and already-encoded values.
+pub(crate) fn json_object(fields: &[(&str, String)]) -> String {
+    let fields = fields
+        .iter()
+        .map(|(k, v)| format!("{}:{v}", json_string(k)))
+        .collect::<Vec<String>>();
+    format!("{{{}}}", fields.join(","))
+}
+
+#[cfg(test)]
+mod json_tests {
+    use crate::util::json::{json_float, json_object, json_string};
+
+    #[test]
+    fn test_json_encoding() {
+        assert_eq!(json_string("a\"b\\\n"), "\"a\\\"b\\\\\\n\"");
+        assert_eq!(json_string("\u{1}"), "\"\\u0001\"");
+        assert_eq!(json_float(0.5), "0.5");
+        assert_eq!(json_float(f32::NAN), "null");
+        assert_eq!(
+            json_object(&[("a", json_string("x")), ("b", "1".to_string())]),
+            "{\"a\":\"x\",\"b\":1}"
+        );
+    }
+}
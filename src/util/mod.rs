@@ -1,6 +1,6 @@
 use std::collections::{HashMap, HashSet};
 use std::fmt::{Debug, Display, Formatter};
-use std::io::{BufRead, BufReader};
+use std::io::BufRead;
 use std::path::{Path, PathBuf};
 use std::str;
 
@@ -34,9 +34,11 @@ use crate::mod_base_code::{DnaBase, ParseChar};
 use crate::monoid::Moniod;
 use crate::parsing_utils::{
     consume_char, consume_digit, consume_dot, consume_float, consume_string,
-    consume_string_spaces,
+    consume_string_spaces, open_text_input,
 };
 
+pub(crate) mod json;
+
 pub(crate) const TAB: char = '\t';
 pub(crate) const MISSING_SYMBOL: &'static str = ".";
 
@@ -52,6 +54,19 @@ pub(crate) fn create_out_directory<T: AsRef<std::ffi::OsStr>>(
     Ok(())
 }
 
+/// Standardized output file path used with `--out-dir`, the file is
+/// `<out_dir>/<prefix>_<name>` or `<out_dir>/<name>` without a prefix.
+pub(crate) fn standard_output_path(
+    out_dir: &Path,
+    prefix: Option<&String>,
+    name: &str,
+) -> PathBuf {
+    match prefix {
+        Some(p) => out_dir.join(format!("{p}_{name}")),
+        None => out_dir.join(name),
+    }
+}
+
 pub(crate) fn get_ticker() -> ProgressBar {
     let ticker = ProgressBar::new_spinner();
     ticker.set_style(ProgressStyle::with_template("> {pos} {msg}").unwrap());
@@ -153,6 +168,33 @@ pub(crate) fn get_query_name_string(record: &bam::Record) -> MkResult<String> {
         .map_err(|_e| MkError::InvalidRecordName)
 }
 
+/// Map uracil to thymine, RNA references and motifs are handled with the DNA
+/// alphabet internally.
+#[inline]
+pub(crate) fn uracil_to_thymine(base: u8) -> u8 {
+    match base {
+        b'U' => b'T',
+        b'u' => b't',
+        _ => base,
+    }
+}
+
+/// Uppercase a reference sequence, unless respecting soft-masking, and map
+/// uracil to thymine.
+pub(crate) fn normalize_reference_seq(seq: String, mask: bool) -> String {
+    let seq = if mask { seq } else { seq.to_ascii_uppercase() };
+    if seq.contains(['U', 'u']) {
+        seq.replace('U', "T").replace('u', "t")
+    } else {
+        seq
+    }
+}
+
+/// Label thymine as uracil, used when reporting direct RNA data.
+pub(crate) fn thymine_to_uracil_label(seq: &str) -> String {
+    seq.replace('T', "U").replace('t', "u")
+}
+
 #[inline]
 pub(crate) fn get_forward_sequence(record: &bam::Record) -> Vec<u8> {
     if record.is_reverse() {
@@ -191,6 +233,14 @@ pub(crate) fn get_tag<T>(
     parser(&tag).map(|v| (v, t))
 }
 
+/// The read group (RG tag) of a record, if it has one.
+pub(crate) fn get_read_group(record: &bam::Record) -> Option<&str> {
+    match record.aux("RG".as_bytes()) {
+        Ok(Aux::String(read_group)) => Some(read_group),
+        _ => None,
+    }
+}
+
 pub(crate) fn parse_nm(record: &bam::Record) -> anyhow::Result<u32> {
     let nm_tag = record.aux("NM".as_bytes())?;
     match nm_tag {
@@ -204,6 +254,85 @@ pub(crate) fn parse_nm(record: &bam::Record) -> anyhow::Result<u32> {
     }
 }
 
+/// Filter reads by how well they align to the reference using the NM tag.
+/// Identity is the fraction of alignment columns (M, =, X, I, and D
+/// operations) that aren't edits, `1 - NM / columns`. Unmapped records
+/// always pass.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub(crate) struct AlignmentIdentityFilter {
+    min_identity: Option<f32>,
+    max_nm: Option<u32>,
+}
+
+impl AlignmentIdentityFilter {
+    pub(crate) fn from_options(
+        min_identity: Option<f32>,
+        max_nm: Option<u32>,
+    ) -> anyhow::Result<Option<Self>> {
+        if let Some(min_identity) = min_identity {
+            if !(0f32..=1f32).contains(&min_identity) {
+                bail!("--min-identity must be between 0 and 1")
+            }
+        }
+        if min_identity.is_none() && max_nm.is_none() {
+            Ok(None)
+        } else {
+            Ok(Some(Self { min_identity, max_nm }))
+        }
+    }
+
+    pub(crate) fn identity(record: &bam::Record) -> anyhow::Result<f32> {
+        let nm = parse_nm(record)?;
+        let columns = record
+            .cigar()
+            .iter()
+            .filter_map(|op| match op {
+                bam::record::Cigar::Match(l)
+                | bam::record::Cigar::Equal(l)
+                | bam::record::Cigar::Diff(l)
+                | bam::record::Cigar::Ins(l)
+                | bam::record::Cigar::Del(l) => Some(*l),
+                _ => None,
+            })
+            .sum::<u32>();
+        if columns == 0 {
+            bail!("alignment has zero aligned columns")
+        }
+        Ok(1f32 - (nm.min(columns) as f32 / columns as f32))
+    }
+
+    /// Whether the record passes the filter, records without a valid NM tag
+    /// do not pass.
+    pub(crate) fn passes(&self, record: &bam::Record) -> bool {
+        if record.is_unmapped() {
+            return true;
+        }
+        let check = || -> anyhow::Result<bool> {
+            if let Some(max_nm) = self.max_nm {
+                if parse_nm(record)? > max_nm {
+                    return Ok(false);
+                }
+            }
+            if let Some(min_identity) = self.min_identity {
+                if Self::identity(record)? < min_identity {
+                    return Ok(false);
+                }
+            }
+            Ok(true)
+        };
+        match check() {
+            Ok(passes) => passes,
+            Err(e) => {
+                debug!(
+                    "record {} failed alignment identity check, {e}",
+                    String::from_utf8_lossy(record.qname())
+                );
+                false
+            }
+        }
+    }
+}
+
 // Regex split into three possible elements
 // (\d+) - matches
 // (\^[A-Z]+) - deletions
@@ -571,7 +700,7 @@ impl Region {
 
 // shouldn't need this once it's fixed in rust-htslib or the repo moves to
 // noodles..
-fn header_to_hashmap(
+pub(crate) fn header_to_hashmap(
     header: &bam::Header,
 ) -> anyhow::Result<HashMap<String, Vec<LinearMap<String, String>>>> {
     let mut header_map = HashMap::default();
@@ -726,6 +855,36 @@ pub(crate) fn parse_partition_tags(
     Ok(tags)
 }
 
+/// Parse `TAG:VALUE` pairs (e.g. `HP:0`) used to select reads by the value
+/// of a SAM tag.
+pub(crate) fn parse_tag_values(
+    raw_tag_values: &[String],
+) -> anyhow::Result<Vec<(SamTag, String)>> {
+    raw_tag_values
+        .iter()
+        .map(|raw| {
+            let (raw_tag, value) = raw.split_once(':').ok_or_else(|| {
+                anyhow!("illegal tag value {raw}, should be TAG:VALUE")
+            })?;
+            let tag = parse_partition_tags(&[raw_tag.to_string()])?[0];
+            if value.is_empty() {
+                bail!("illegal tag value {raw}, missing value")
+            }
+            Ok((tag, value.to_string()))
+        })
+        .collect()
+}
+
+/// Check if a record has any of the tag values.
+pub(crate) fn record_has_tag_value(
+    record: &bam::Record,
+    tag_values: &[(SamTag, String)],
+) -> bool {
+    tag_values.iter().any(|(tag, value)| {
+        get_stringable_aux(record, tag).is_some_and(|v| &v == value)
+    })
+}
+
 #[inline]
 pub fn get_reference_mod_strand(
     read_mod_strand: Strand,
@@ -747,6 +906,20 @@ pub(crate) fn reader_is_bam(reader: &bam::IndexedReader) -> bool {
     }
 }
 
+/// Open an indexed BAM or CRAM. CRAM records are decoded with the `reference`
+/// when it's given, otherwise htslib looks for the reference named in the
+/// header (`UR`/`M5` tags, `REF_PATH`).
+pub(crate) fn get_indexed_reader<T: AsRef<Path>>(
+    fp: T,
+    reference: Option<&PathBuf>,
+) -> rust_htslib::errors::Result<bam::IndexedReader> {
+    let mut reader = bam::IndexedReader::from_path(fp)?;
+    if let Some(reference) = reference {
+        reader.set_reference(reference)?;
+    }
+    Ok(reader)
+}
+
 pub(crate) const KMER_SIZE: usize = 50;
 
 #[derive(Copy, Clone)]
@@ -989,7 +1162,7 @@ pub(crate) fn read_sequence_lengths_file(
         Ok((rest, (chrom, length)))
     }
 
-    BufReader::new(std::fs::File::open(p)?)
+    open_text_input(p)?
         .lines()
         .map(|l| {
             l.map_err(|e| anyhow!("failed to read from sizes, {e}")).and_then(
@@ -1031,12 +1204,61 @@ mod utils_tests {
 
     use crate::errs::MkError;
     use crate::util::{
-        get_query_name_string, get_stringable_aux, parse_partition_tags,
-        GenomeRegion, Region, SamTag, StrandRule,
+        get_query_name_string, get_stringable_aux, normalize_reference_seq,
+        parse_partition_tags, thymine_to_uracil_label, uracil_to_thymine,
+        AlignmentIdentityFilter, GenomeRegion, Region, SamTag, StrandRule,
     };
 
     use super::Kmer;
 
+    fn sam_record(cigar: &str, nm: Option<u32>) -> bam::Record {
+        let mut header = bam::Header::new();
+        header.push_record(
+            bam::header::HeaderRecord::new(b"SQ")
+                .push_tag(b"SN", "chr1")
+                .push_tag(b"LN", 1000),
+        );
+        let header = bam::HeaderView::from_header(&header);
+        let tag = nm.map(|nm| format!("\tNM:i:{nm}")).unwrap_or_default();
+        let line = format!(
+            "read1\t0\tchr1\t10\t60\t{cigar}\t*\t0\t0\tACGTACGTACGT\t*{tag}"
+        );
+        bam::Record::from_sam(&header, line.as_bytes()).unwrap()
+    }
+
+    #[test]
+    fn test_util_alignment_identity_filter() {
+        assert!(AlignmentIdentityFilter::from_options(None, None)
+            .unwrap()
+            .is_none());
+        assert!(AlignmentIdentityFilter::from_options(Some(1.5), None).is_err());
+
+        // 8 matches, 2 inserted, 2 deleted -> 12 columns
+        let record = sam_record("4M2I4M2D2S", Some(3));
+        let identity = AlignmentIdentityFilter::identity(&record).unwrap();
+        assert!((identity - 0.75).abs() < 1e-6);
+
+        let filter = AlignmentIdentityFilter::from_options(Some(0.7), None)
+            .unwrap()
+            .unwrap();
+        assert!(filter.passes(&record));
+        let filter = AlignmentIdentityFilter::from_options(Some(0.8), None)
+            .unwrap()
+            .unwrap();
+        assert!(!filter.passes(&record));
+        let filter = AlignmentIdentityFilter::from_options(None, Some(2))
+            .unwrap()
+            .unwrap();
+        assert!(!filter.passes(&record));
+        let filter = AlignmentIdentityFilter::from_options(None, Some(3))
+            .unwrap()
+            .unwrap();
+        assert!(filter.passes(&record));
+        // missing NM tag fails
+        let record = sam_record("4M2I4M2D2S", None);
+        assert!(!filter.passes(&record));
+    }
+
     #[test]
     fn test_util_get_stringable_tag() {
         let bam_fp = "tests/resources/bc_anchored_10_reads.sorted.bam";
@@ -1231,4 +1453,20 @@ mod utils_tests {
             e @ _ => assert!(false, "incorrect error {e}"),
         }
     }
+
+    #[test]
+    fn test_util_uracil_normalization() {
+        assert_eq!(uracil_to_thymine(b'U'), b'T');
+        assert_eq!(uracil_to_thymine(b'u'), b't');
+        assert_eq!(uracil_to_thymine(b'A'), b'A');
+        assert_eq!(
+            normalize_reference_seq("acguACGU".to_string(), false),
+            "ACGTACGT".to_string()
+        );
+        assert_eq!(
+            normalize_reference_seq("acguACGU".to_string(), true),
+            "acgtACGT".to_string()
+        );
+        assert_eq!(thymine_to_uracil_label("GGACT"), "GGACU".to_string());
+    }
 }
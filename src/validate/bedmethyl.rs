@@ -0,0 +1,333 @@
+use std::io::BufRead;
+use std::path::Path;
+
+use anyhow::{anyhow, bail};
+use log::{debug, info};
+use prettytable::{row, Table};
+use rustc_hash::FxHashMap;
+
+use crate::dmr::bedmethyl::BedMethylLine;
+use crate::mod_base_code::ModCodeRepr;
+use crate::parsing_utils::open_text_input;
+use crate::util::get_ticker;
+
+/// Truth fraction modified keyed by contig and 0-based start.
+pub(super) type TruthLookup = FxHashMap<String, FxHashMap<u64, f64>>;
+
+/// Load a truth table (for example WGBS beta values), the first three columns
+/// are chrom, start, and end, `value_column` is the 1-based column with the
+/// fraction (or percent) modified.
+pub(super) fn load_truth_table(
+    fp: &Path,
+    value_column: usize,
+    percent: bool,
+) -> anyhow::Result<TruthLookup> {
+    if value_column < 4 {
+        bail!("truth value column must be 4 or greater, got {value_column}")
+    }
+    let scale = if percent { 100f64 } else { 1f64 };
+    let reader = open_text_input(fp)?;
+    let mut lookup = TruthLookup::default();
+    let mut n_skipped = 0usize;
+    for line in reader.lines() {
+        let line = line?;
+        if line.starts_with('#') || line.starts_with("track") {
+            continue;
+        }
+        let parse = || -> anyhow::Result<(String, u64, f64)> {
+            let fields = line.split_whitespace().collect::<Vec<&str>>();
+            let chrom = fields.first().ok_or_else(|| anyhow!("empty line"))?;
+            let start = fields
+                .get(1)
+                .ok_or_else(|| anyhow!("missing start"))?
+                .parse::<u64>()?;
+            let value = fields
+                .get(value_column - 1)
+                .ok_or_else(|| anyhow!("missing column {value_column}"))?
+                .parse::<f64>()?
+                / scale;
+            if !(0f64..=1f64).contains(&value) {
+                bail!("truth value {value} out of range")
+            }
+            Ok((chrom.to_string(), start, value))
+        };
+        match parse() {
+            Ok((chrom, start, value)) => {
+                lookup.entry(chrom).or_default().insert(start, value);
+            }
+            Err(e) => {
+                debug!("skipping truth line {line}, {e}");
+                n_skipped += 1;
+            }
+        }
+    }
+    if lookup.is_empty() {
+        bail!("zero valid sites parsed from truth table {fp:?}")
+    }
+    let n_sites = lookup.values().map(|x| x.len()).sum::<usize>();
+    info!("loaded {n_sites} truth sites, skipped {n_skipped} lines");
+    Ok(lookup)
+}
+
+/// Coverage strata given by their lower edges, e.g. 1,5,10 makes the strata
+/// 1-4, 5-9, and 10+.
+pub(super) struct CoverageStrata {
+    edges: Vec<u64>,
+}
+
+impl CoverageStrata {
+    pub(super) fn parse(raw: &str) -> anyhow::Result<Self> {
+        let edges = raw
+            .split(',')
+            .map(|x| {
+                x.trim()
+                    .parse::<u64>()
+                    .map_err(|e| anyhow!("invalid coverage bin edge {x}, {e}"))
+            })
+            .collect::<anyhow::Result<Vec<u64>>>()?;
+        if edges.is_empty() || edges[0] == 0 {
+            bail!("coverage bin edges must start at 1 or greater")
+        }
+        if edges.windows(2).any(|w| w[0] >= w[1]) {
+            bail!("coverage bin edges must be strictly increasing")
+        }
+        Ok(Self { edges })
+    }
+
+    fn stratum(&self, coverage: u64) -> Option<usize> {
+        self.edges.iter().rposition(|&edge| coverage >= edge)
+    }
+
+    fn label(&self, idx: usize) -> String {
+        match self.edges.get(idx + 1) {
+            Some(next) => format!("{}-{}", self.edges[idx], next - 1),
+            None => format!("{}+", self.edges[idx]),
+        }
+    }
+}
+
+/// Running sums to calculate the agreement between observed and truth
+/// fraction modified.
+#[derive(Debug, Default, Copy, Clone)]
+pub(super) struct AgreementStats {
+    n: u64,
+    sum_obs: f64,
+    sum_truth: f64,
+    sum_obs_sq: f64,
+    sum_truth_sq: f64,
+    sum_obs_truth: f64,
+}
+
+impl AgreementStats {
+    fn add(&mut self, observed: f64, truth: f64) {
+        self.n += 1;
+        self.sum_obs += observed;
+        self.sum_truth += truth;
+        self.sum_obs_sq += observed * observed;
+        self.sum_truth_sq += truth * truth;
+        self.sum_obs_truth += observed * truth;
+    }
+
+    /// Pearson correlation, NaN when there are fewer than 2 sites or either
+    /// set of values is constant.
+    fn pearson_r(&self) -> f64 {
+        if self.n < 2 {
+            return f64::NAN;
+        }
+        let n = self.n as f64;
+        let cov = self.sum_obs_truth - self.sum_obs * self.sum_truth / n;
+        let var_obs = self.sum_obs_sq - self.sum_obs * self.sum_obs / n;
+        let var_truth = self.sum_truth_sq - self.sum_truth * self.sum_truth / n;
+        if var_obs <= 0f64 || var_truth <= 0f64 {
+            f64::NAN
+        } else {
+            cov / (var_obs * var_truth).sqrt()
+        }
+    }
+
+    fn rmse(&self) -> f64 {
+        let n = self.n as f64;
+        let sse =
+            self.sum_obs_sq - 2f64 * self.sum_obs_truth + self.sum_truth_sq;
+        (sse.max(0f64) / n).sqrt()
+    }
+
+    /// Mean of observed minus truth.
+    fn bias(&self) -> f64 {
+        (self.sum_obs - self.sum_truth) / self.n as f64
+    }
+
+    fn row(&self, label: &str) -> String {
+        let n = self.n as f64;
+        format!(
+            "{label}\t{}\t{:.6}\t{:.6}\t{:.6}\t{:.6}\t{:.6}",
+            self.n,
+            self.pearson_r(),
+            self.rmse(),
+            self.bias(),
+            self.sum_obs / n,
+            self.sum_truth / n,
+        )
+    }
+}
+
+pub(super) struct TruthComparison {
+    strata: Vec<AgreementStats>,
+    overall: AgreementStats,
+    labels: Vec<String>,
+}
+
+impl TruthComparison {
+    pub(super) fn header() -> &'static str {
+        "coverage\tn_sites\tpearson_r\trmse\tbias\tmean_observed\tmean_truth"
+    }
+
+    pub(super) fn rows(&self) -> Vec<String> {
+        self.strata
+            .iter()
+            .zip(self.labels.iter())
+            .filter(|(stats, _)| stats.n > 0)
+            .map(|(stats, label)| stats.row(label))
+            .chain(std::iter::once(self.overall.row("all")))
+            .collect()
+    }
+
+    pub(super) fn table(&self) -> Table {
+        let mut table = Table::new();
+        table.set_format(*prettytable::format::consts::FORMAT_CLEAN);
+        table.set_titles(row![
+            "coverage",
+            "n_sites",
+            "pearson_r",
+            "rmse",
+            "bias",
+            "mean_observed",
+            "mean_truth"
+        ]);
+        for row in self.rows() {
+            table.add_row(row.split('\t').collect());
+        }
+        table
+    }
+}
+
+/// Compare the fraction modified in a bedMethyl to the truth, records with
+/// more than one modification code at a site are summed unless `mod_code` is
+/// given.
+pub(super) fn compare_bedmethyl_to_truth(
+    bedmethyl_fp: &Path,
+    truth: &TruthLookup,
+    strata: &CoverageStrata,
+    mod_code: Option<ModCodeRepr>,
+    suppress_progress: bool,
+) -> anyhow::Result<TruthComparison> {
+    let reader = open_text_input(bedmethyl_fp)?;
+    let lines_processed = get_ticker();
+    if suppress_progress {
+        lines_processed
+            .set_draw_target(indicatif::ProgressDrawTarget::hidden());
+    }
+    lines_processed.set_message("bedMethyl records processed");
+
+    let mut comparison = TruthComparison {
+        strata: vec![AgreementStats::default(); strata.edges.len()],
+        overall: AgreementStats::default(),
+        labels: (0..strata.edges.len()).map(|i| strata.label(i)).collect(),
+    };
+    let mut add_site = |site: &BedMethylLine, n_mod: u64| {
+        let Some(truth_value) =
+            truth.get(&site.chrom).and_then(|x| x.get(&site.start()))
+        else {
+            return;
+        };
+        if site.valid_coverage == 0 {
+            return;
+        }
+        let Some(idx) = strata.stratum(site.valid_coverage) else {
+            return;
+        };
+        let observed = n_mod as f64 / site.valid_coverage as f64;
+        comparison.strata[idx].add(observed, *truth_value);
+        comparison.overall.add(observed, *truth_value);
+    };
+
+    let mut n_failed = 0usize;
+    let mut current: Option<(BedMethylLine, u64)> = None;
+    for line in reader.lines() {
+        let line = line?;
+        let record = match BedMethylLine::parse(&line) {
+            Ok(record) => record,
+            Err(e) => {
+                debug!("{e}");
+                n_failed += 1;
+                continue;
+            }
+        };
+        lines_processed.inc(1);
+        if mod_code.map(|c| c != record.raw_mod_code).unwrap_or(false) {
+            continue;
+        }
+        current = match current.take() {
+            Some((site, n_mod))
+                if site.same_position_and_strand_as(&record) =>
+            {
+                Some((site, n_mod + record.count_methylated))
+            }
+            Some((site, n_mod)) => {
+                add_site(&site, n_mod);
+                let n_mod = record.count_methylated;
+                Some((record, n_mod))
+            }
+            None => {
+                let n_mod = record.count_methylated;
+                Some((record, n_mod))
+            }
+        };
+    }
+    if let Some((site, n_mod)) = current {
+        add_site(&site, n_mod);
+    }
+    lines_processed.finish_and_clear();
+    info!(
+        "processed {} bedMethyl records, {n_failed} failed to parse",
+        lines_processed.position()
+    );
+    if comparison.overall.n == 0 {
+        bail!("zero bedMethyl sites overlapped the truth table")
+    }
+
+    Ok(comparison)
+}
+
+#[cfg(test)]
+mod validate_bedmethyl_tests {
+    use crate::validate::bedmethyl::{AgreementStats, CoverageStrata};
+
+    #[test]
+    fn test_coverage_strata() {
+        let strata = CoverageStrata::parse("1,5,10").unwrap();
+        assert_eq!(strata.stratum(0), None);
+        assert_eq!(strata.stratum(1), Some(0));
+        assert_eq!(strata.stratum(9), Some(1));
+        assert_eq!(strata.stratum(100), Some(2));
+        assert_eq!(strata.label(0), "1-4");
+        assert_eq!(strata.label(2), "10+");
+        assert!(CoverageStrata::parse("5,1").is_err());
+        assert!(CoverageStrata::parse("0,1").is_err());
+    }
+
+    #[test]
+    fn test_agreement_stats() {
+        let mut stats = AgreementStats::default();
+        for (obs, truth) in [(0.1, 0.0), (0.6, 0.5), (1.0, 0.9)] {
+            stats.add(obs, truth);
+        }
+        assert!((stats.pearson_r() - 1.0).abs() < 1e-9);
+        assert!((stats.bias() - 0.1).abs() < 1e-9);
+        assert!((stats.rmse() - 0.1).abs() < 1e-9);
+        let mut constant = AgreementStats::default();
+        constant.add(0.5, 0.5);
+        constant.add(0.5, 0.2);
+        assert!(constant.pearson_r().is_nan());
+    }
+}
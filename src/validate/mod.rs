@@ -1 +1,2 @@
+pub(crate) mod bedmethyl;
 pub mod subcommand;
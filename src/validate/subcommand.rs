@@ -2,22 +2,26 @@ use rustc_hash::FxHashMap;
 use std::collections::{BTreeMap, HashMap, HashSet};
 use std::fmt::{Debug, Display, Formatter};
 use std::fs::File;
-use std::io::{BufRead, BufReader, Write};
-use std::path::PathBuf;
+use std::io::{BufRead, Write};
+use std::path::{Path, PathBuf};
 use std::string::FromUtf8Error;
 
 use crate::command_utils::parse_edge_filter_input;
 use crate::logging::init_logging;
 use crate::mod_bam::BaseModCall;
 use crate::mod_bam::{CollapseMethod, EdgeFilter, ModBaseInfo};
-use crate::mod_base_code::{
-    DnaBase, ModCodeRepr, ANY_MOD_CODES, MOD_CODE_TO_DNA_BASE,
-};
+use crate::mod_base_code::{DnaBase, ModCodeRepr, ANY_MOD_CODES};
+use crate::mod_code_registry::ModCodeRegistry;
+use crate::parsing_utils::open_text_input;
 use crate::read_ids_to_base_mod_probs::{PositionModCalls, ReadBaseModProfile};
 use crate::thresholds::percentile_linear_interp;
 use crate::util::{
-    format_int_with_commas, get_reference_mod_strand, get_ticker, parse_nm,
-    record_is_not_primary, Strand,
+    create_out_directory, format_int_with_commas, get_reference_mod_strand,
+    get_ticker, parse_nm, record_is_not_primary, standard_output_path, Strand,
+};
+use crate::validate::bedmethyl::{
+    compare_bedmethyl_to_truth, load_truth_table, CoverageStrata,
+    TruthComparison,
 };
 use ansi_term::Style;
 use anyhow::{anyhow, bail};
@@ -190,7 +194,7 @@ fn parse_ground_truth_bed_file(
     }
     lines_processed.set_message("rows processed");
 
-    let reader = BufReader::new(File::open(file_path)?);
+    let reader = open_text_input(file_path)?;
     for ground_truth_site in reader.lines().filter_map(|r| {
         r.map_err(|e| anyhow!("failed to read, {}", e.to_string()))
             .and_then(|line| parse_ground_truth_bed_line(&line))
@@ -232,24 +236,21 @@ fn derive_canonical_base(
         match base_status {
             BaseStatus::Modified(mod_code) => {
                 if let Some(existing_can_base) = &can_base {
-                    let expected_can_base = *MOD_CODE_TO_DNA_BASE
-                        .get(&mod_code)
-                        .unwrap_or(existing_can_base);
+                    let expected_can_base = ModCodeRegistry::global()
+                        .primary_base(&mod_code)
+                        .unwrap_or(*existing_can_base);
                     if *existing_can_base != expected_can_base {
                         bail!(
                             "Multiple canonical bases represented in ground \
                              truth BED files: {} {}",
                             existing_can_base.char(),
-                            MOD_CODE_TO_DNA_BASE
-                                .get(&mod_code)
-                                .unwrap_or(existing_can_base)
-                                .char()
+                            expected_can_base.char()
                         )
                     }
                 } else {
-                    match MOD_CODE_TO_DNA_BASE.get(&mod_code) {
+                    match ModCodeRegistry::global().primary_base(&mod_code) {
                         Some(extracted_can_base) => {
-                            can_base = Some(*extracted_can_base);
+                            can_base = Some(extracted_can_base);
                         }
                         None => {
                             continue;
@@ -812,6 +813,45 @@ pub struct ValidateFromModBam {
 	value_names = ["BAM", "BED"]
     )]
     bam_and_bed: Vec<PathBuf>,
+    /// Argument accepts 2 values. The first value is a bedMethyl from `modkit
+    /// pileup`, the second is a truth table (for example WGBS beta values)
+    /// with chrom, 0-based start, and end columns followed by the fraction
+    /// modified. Reports site-level correlation, RMSE, and bias stratified by
+    /// coverage instead of read-level accuracy. Sites are matched on chrom
+    /// and start, so the bedMethyl should usually be made with
+    /// `--combine-strands`.
+    #[clap(help_heading = "bedMethyl Options")]
+    #[arg(
+        long,
+        num_args = 2,
+        value_names = ["BEDMETHYL", "TRUTH"],
+        conflicts_with = "bam_and_bed"
+    )]
+    bedmethyl_and_truth: Option<Vec<PathBuf>>,
+    /// 1-based column in the truth table with the fraction modified.
+    #[clap(help_heading = "bedMethyl Options")]
+    #[arg(long, requires = "bedmethyl_and_truth", default_value_t = 4)]
+    truth_column: usize,
+    /// Values in the truth table are percentages (0-100) instead of fractions
+    /// (0-1).
+    #[clap(help_heading = "bedMethyl Options")]
+    #[arg(long, requires = "bedmethyl_and_truth", default_value_t = false)]
+    truth_percent: bool,
+    /// Only use bedMethyl records with this modification code, default is to
+    /// sum the counts of all modification codes at each site, for example 5mC
+    /// and 5hmC for comparison to bisulfite sequencing.
+    #[clap(help_heading = "bedMethyl Options")]
+    #[arg(long, requires = "bedmethyl_and_truth")]
+    mod_code: Option<String>,
+    /// Comma-separated lower edges of the valid coverage strata, sites with
+    /// valid coverage below the first edge are ignored.
+    #[clap(help_heading = "bedMethyl Options")]
+    #[arg(
+        long,
+        requires = "bedmethyl_and_truth",
+        default_value = "1,5,10,20,50"
+    )]
+    coverage_bins: String,
 
     // todo make argument groups
     // args for BAM record manipulation
@@ -882,6 +922,17 @@ pub struct ValidateFromModBam {
     #[clap(help_heading = "Output Options")]
     #[arg(short = 'o', long, alias = "out")]
     out_filepath: Option<PathBuf>,
+    /// Write the machine parseable output into this directory instead of
+    /// using `--out-filepath`. The output is written to
+    /// `<prefix>_validate.txt`, or `<prefix>_validate_truth.tsv` with
+    /// `--bedmethyl-and-truth`.
+    #[clap(help_heading = "Output Options")]
+    #[arg(long, conflicts_with = "out_filepath")]
+    out_dir: Option<PathBuf>,
+    /// Prefix the output file name in `--out-dir` with this string.
+    #[clap(help_heading = "Output Options")]
+    #[arg(long, requires = "out_dir")]
+    prefix: Option<String>,
     /// Specify a file for debug logs to be written to, otherwise ignore them.
     /// Setting a file is recommended. (alias: log)
     #[clap(help_heading = "Logging Options")]
@@ -890,10 +941,62 @@ pub struct ValidateFromModBam {
 }
 
 impl ValidateFromModBam {
+    /// Path of the machine parseable output, either `--out-filepath` or
+    /// `name` in `--out-dir`.
+    fn out_path(&self, name: &str) -> anyhow::Result<Option<PathBuf>> {
+        let fp = match self.out_dir.as_ref() {
+            Some(out_dir) => {
+                Some(standard_output_path(out_dir, self.prefix.as_ref(), name))
+            }
+            None => self.out_filepath.clone(),
+        };
+        if let Some(fp) = fp.as_ref() {
+            create_out_directory(fp)?;
+        }
+        Ok(fp)
+    }
+
+    fn run_bedmethyl_vs_truth(
+        &self,
+        bedmethyl_fp: &Path,
+        truth_fp: &Path,
+    ) -> anyhow::Result<()> {
+        let strata = CoverageStrata::parse(&self.coverage_bins)?;
+        let mod_code = self
+            .mod_code
+            .as_ref()
+            .map(|x| ModCodeRepr::parse(x))
+            .transpose()?;
+        let truth =
+            load_truth_table(truth_fp, self.truth_column, self.truth_percent)?;
+        let comparison = compare_bedmethyl_to_truth(
+            bedmethyl_fp,
+            &truth,
+            &strata,
+            mod_code,
+            self.suppress_progress,
+        )?;
+        info!("bedMethyl agreement with truth\n{}", comparison.table());
+        if let Some(out_fp) = self.out_path("validate_truth.tsv")? {
+            let mut writer = File::create(out_fp)?;
+            writeln!(writer, "{}", TruthComparison::header())?;
+            for row in comparison.rows() {
+                writeln!(writer, "{row}")?;
+            }
+        }
+        Ok(())
+    }
+
     pub fn run(&self) -> anyhow::Result<()> {
         let _handle = init_logging(self.log_filepath.as_ref());
+        if let Some(paths) = self.bedmethyl_and_truth.as_ref() {
+            return self.run_bedmethyl_vs_truth(&paths[0], &paths[1]);
+        }
+        if self.bam_and_bed.is_empty() {
+            bail!("must provide --bam-and-bed or --bedmethyl-and-truth")
+        }
         let mut out_handle: Option<File> = None;
-        if let Some(file_path) = self.out_filepath.clone() {
+        if let Some(file_path) = self.out_path("validate.txt")? {
             out_handle = Some(File::create(&file_path)?);
         }
         let collapse_method = match &self.ignore {
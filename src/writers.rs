@@ -1,8 +1,10 @@
 use std::cmp::Ordering;
-use std::collections::{BTreeMap, HashMap};
+use std::collections::hash_map::Entry;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 use std::fs::File;
-use std::io::{BufWriter, Stdout, Write};
+use std::io::{BufRead, BufWriter, Stdout, Write};
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
 use anyhow::{anyhow, bail, Context, Result as AnyhowResult};
 use charming::component::{
@@ -14,35 +16,228 @@ use charming::element::{
 };
 use charming::series::Bar;
 use charming::{Chart, HtmlRenderer};
+use clap::ValueEnum;
 use derive_new::new;
 use gzp::deflate::Bgzf;
 use gzp::par::compress::{ParCompress, ParCompressBuilder};
+use gzp::ZWriter;
 use itertools::Itertools;
 use log::{debug, info, warn};
-use prettytable::format::FormatBuilder;
+use prettytable::format::{
+    FormatBuilder, LinePosition, LineSeparator, TableFormat as PrettyFormat,
+};
 use prettytable::{row, Table};
 use random_color::RandomColor;
 use rustc_hash::FxHashMap;
 
+use crate::bigwig::BigWigTrack;
 use crate::mod_base_code::{
-    BaseState, DnaBase, ModCodeRepr, ProbHistogram, DNA_BASE_COLORS, MOD_COLORS,
+    BaseState, DnaBase, ModCodeRepr, ProbHistogram, DNA_BASE_COLORS,
 };
+use crate::mod_code_registry::ModCodeRegistry;
+use crate::parquet_table::ParquetTableWriter;
+use crate::parsing_utils::open_text_input;
 use crate::pileup::duplex::DuplexModBasePileup;
 use crate::pileup::{ModBasePileup, PartitionKey, PileupFeatureCounts};
-use crate::summarize::ModSummary;
+use crate::provenance::BasecallProvenance;
+use crate::sqlite::{ColumnType, SqliteTableWriter};
+use crate::summarize::{ModSummary, StrandCallCounts};
 use crate::thresholds::Percentiles;
+use crate::util::json::{json_float, json_object, json_string};
+use crate::util::Strand;
 
 pub trait PileupWriter<T> {
     fn write(&mut self, item: T, motif_labels: &[String]) -> AnyhowResult<u64>;
+
+    /// Called once after the last item has been written.
+    fn finish(&mut self) -> AnyhowResult<()> {
+        Ok(())
+    }
 }
 
 pub trait OutWriter<T> {
     fn write(&mut self, item: T) -> AnyhowResult<u64>;
 }
 
+/// Output that has to be closed explicitly, e.g. to commit the last rows of
+/// a database, so that errors at the end of the output are returned instead
+/// of being lost when the writer is dropped.
+pub trait FinishWrite: Write {
+    fn finish_write(&mut self) -> AnyhowResult<()> {
+        self.flush().context("failed to flush output")
+    }
+}
+
+impl FinishWrite for File {}
+
+impl FinishWrite for Stdout {}
+
+impl FinishWrite for std::io::Sink {}
+
+impl FinishWrite for ParCompress<Bgzf> {
+    /// Write the remaining blocks and the bgzf EOF marker, without this
+    /// gzp finishes the stream on drop and panics on an IO error.
+    fn finish_write(&mut self) -> AnyhowResult<()> {
+        ZWriter::finish(self)
+            .map_err(|e| anyhow!("failed to finish bgzf output, {e}"))
+    }
+}
+
+impl<W: FinishWrite> FinishWrite for BufWriter<W> {
+    fn finish_write(&mut self) -> AnyhowResult<()> {
+        self.flush().context("failed to flush output")?;
+        self.get_mut().finish_write()
+    }
+}
+
 pub struct BedMethylWriter<T: Write> {
     buf_writer: BufWriter<T>,
     tabs_and_spaces: bool,
+    colors: ModColorMap,
+    with_header: bool,
+    one_based: bool,
+    score: BedScore,
+}
+
+/// Comment line written at the top of output in `--one-based` mode.
+pub(crate) const ONE_BASED_COMMENT: &str =
+    "#coordinates: 1-based, closed (start and end are the 1-based position)\n";
+
+/// The (start, end) columns for a 0-based reference position. By default
+/// these are 0-based, half-open (BED) coordinates, with `one_based` they are
+/// 1-based, closed coordinates so start and end are the same.
+#[inline]
+pub(crate) fn position_interval(pos: u32, one_based: bool) -> (u32, u32) {
+    if one_based {
+        (pos + 1, pos + 1)
+    } else {
+        (pos, pos + 1)
+    }
+}
+
+const DEFAULT_BEDMETHYL_COLOR: &str = "255,0,0";
+
+/// Colors for the `color` (itemRgb) column of bedMethyl output, keyed by
+/// modification code. Codes without a color are written as `255,0,0`.
+#[derive(Debug, Default, Clone)]
+pub struct ModColorMap {
+    colors: HashMap<ModCodeRepr, String>,
+}
+
+impl ModColorMap {
+    /// Parse a color as either "R,G,B" or a hex string "#RRGGBB" into the
+    /// "R,G,B" form used in BED files.
+    fn parse_color(raw: &str) -> AnyhowResult<String> {
+        let raw = raw.trim();
+        let rgb = if let Some(hex) = raw.strip_prefix('#') {
+            if hex.len() != 6 {
+                bail!("invalid hex color {raw}, should be #RRGGBB")
+            }
+            (0..3)
+                .map(|i| u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16))
+                .collect::<Result<Vec<u8>, _>>()
+                .map_err(|e| anyhow!("invalid hex color {raw}, {e}"))?
+        } else {
+            let rgb = raw
+                .split(',')
+                .map(|x| x.trim().parse::<u8>())
+                .collect::<Result<Vec<u8>, _>>()
+                .map_err(|e| anyhow!("invalid RGB color {raw}, {e}"))?;
+            if rgb.len() != 3 {
+                bail!("invalid RGB color {raw}, should be R,G,B")
+            }
+            rgb
+        };
+        Ok(rgb.into_iter().join(","))
+    }
+
+    /// Make a color map. `use_defaults` starts with the colors in the
+    /// modification code registry (also used in plots), then colors from
+    /// `color_file` (two tab-separated columns, code and color) are added,
+    /// then `color_pairs` (code, color) from the command line.
+    pub fn new(
+        use_defaults: bool,
+        color_file: Option<&PathBuf>,
+        color_pairs: &[String],
+    ) -> AnyhowResult<Self> {
+        let mut colors = HashMap::new();
+        if use_defaults {
+            for (code, color) in ModCodeRegistry::global().colors() {
+                colors.insert(*code, Self::parse_color(color)?);
+            }
+        }
+        if let Some(fp) = color_file {
+            let reader = open_text_input(fp).with_context(|| {
+                format!("failed to read color file at {fp:?}")
+            })?;
+            for line in reader.lines() {
+                let line = line?;
+                if line.trim().is_empty() || line.starts_with('#') {
+                    continue;
+                }
+                let (code, color) = line.split_once('\t').ok_or_else(|| {
+                    anyhow!("invalid line in color file, {line}")
+                })?;
+                colors.insert(
+                    ModCodeRepr::parse(code.trim())?,
+                    Self::parse_color(color)?,
+                );
+            }
+        }
+        if color_pairs.len() % 2 != 0 {
+            bail!("invalid number of arguments for mod colors")
+        }
+        for pair in color_pairs.chunks(2) {
+            colors.insert(
+                ModCodeRepr::parse(&pair[0])?,
+                Self::parse_color(&pair[1])?,
+            );
+        }
+        Ok(Self { colors })
+    }
+
+    #[inline]
+    pub(crate) fn get(&self, mod_code: &ModCodeRepr) -> &str {
+        self.colors
+            .get(mod_code)
+            .map(|c| c.as_str())
+            .unwrap_or(DEFAULT_BEDMETHYL_COLOR)
+    }
+}
+
+/// What the `score` column (column 5) of bedMethyl output encodes.
+/// Browsers differ in what they expect here, the BED specification limits
+/// the score to 0-1000.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, ValueEnum)]
+#[allow(non_camel_case_types)]
+pub enum BedScore {
+    /// The valid coverage, the same as the `valid_coverage` column.
+    #[default]
+    coverage,
+    /// The valid coverage, capped at 1000.
+    capped_coverage,
+    /// The percent modified multiplied by 10 and rounded, 0-1000.
+    percent,
+}
+
+impl BedScore {
+    /// Maximum score allowed by the BED specification.
+    const MAX_SCORE: usize = 1000;
+
+    #[inline]
+    pub(crate) fn score(
+        &self,
+        valid_coverage: usize,
+        fraction_modified: f32,
+    ) -> usize {
+        match self {
+            Self::coverage => valid_coverage,
+            Self::capped_coverage => valid_coverage.min(Self::MAX_SCORE),
+            Self::percent => {
+                (fraction_modified * Self::MAX_SCORE as f32).round() as usize
+            }
+        }
+    }
 }
 
 pub fn bedmethyl_header() -> String {
@@ -70,6 +265,24 @@ pub fn bedmethyl_header() -> String {
     format!("#{fields}\n")
 }
 
+/// Columns of the table written by `pileup --out-format sqlite` (and
+/// `parquet`), the same as the bedMethyl header.
+pub(crate) fn bedmethyl_sqlite_columns() -> Vec<(String, ColumnType)> {
+    bedmethyl_header()
+        .trim_start_matches('#')
+        .trim_end()
+        .split('\t')
+        .map(|name| {
+            let typ = match name {
+                "chrom" | "name" | "strand" | "color" => ColumnType::Text,
+                "percent_modified" => ColumnType::Real,
+                _ => ColumnType::Integer,
+            };
+            (name.to_string(), typ)
+        })
+        .collect()
+}
+
 impl<T: Write + Sized> BedMethylWriter<T> {
     fn header() -> String {
         bedmethyl_header()
@@ -84,17 +297,60 @@ impl<T: Write + Sized> BedMethylWriter<T> {
             buf_writer.write(Self::header().as_bytes())?;
         }
 
-        Ok(Self { buf_writer, tabs_and_spaces })
+        Ok(Self {
+            buf_writer,
+            tabs_and_spaces,
+            colors: ModColorMap::default(),
+            with_header,
+            one_based: false,
+            score: BedScore::default(),
+        })
+    }
+
+    /// Set the colors written to the `color` column for each modification
+    /// code.
+    pub fn with_colors(self, colors: ModColorMap) -> Self {
+        Self { colors, ..self }
+    }
+
+    /// Write 1-based, closed coordinates instead of BED coordinates. When
+    /// the writer was made with a header, a comment line documenting the
+    /// convention follows it.
+    pub fn with_one_based(mut self, one_based: bool) -> anyhow::Result<Self> {
+        if one_based && self.with_header {
+            self.buf_writer.write_all(ONE_BASED_COMMENT.as_bytes())?;
+        }
+        Ok(Self { one_based, ..self })
+    }
+
+    /// Set what the `score` column encodes, see [`BedScore`].
+    pub fn with_score(self, score: BedScore) -> Self {
+        Self { score, ..self }
+    }
+
+    /// When the writer was made with a header, follow it with comment lines
+    /// recording the basecallers and models that made the calls.
+    pub fn with_provenance(
+        mut self,
+        provenance: &BasecallProvenance,
+    ) -> anyhow::Result<Self> {
+        if self.with_header {
+            self.buf_writer
+                .write_all(provenance.header_comment().as_bytes())?;
+        }
+        Ok(self)
     }
 
     #[inline]
     fn write_feature_counts(
-        pos: u32,
+        (start, end): (u32, u32),
         chrom_name: &str,
         feature_counts: &[PileupFeatureCounts],
         writer: &mut BufWriter<T>,
         tabs_and_spaces: bool,
         motif_labels: &[String],
+        colors: &ModColorMap,
+        score: BedScore,
     ) -> AnyhowResult<u64> {
         let tab = '\t';
         let space = if tabs_and_spaces { ' ' } else { tab };
@@ -132,14 +388,17 @@ impl<T: Write + Sized> BedMethylWriter<T> {
                  {}{space}\
                  {}\n",
                 chrom_name,
-                pos,
-                pos + 1,
+                start,
+                end,
                 name,
-                feature_count.filtered_coverage,
+                score.score(
+                    feature_count.filtered_coverage as usize,
+                    feature_count.fraction_modified
+                ),
                 feature_count.raw_strand,
-                pos,
-                pos + 1,
-                "255,0,0",
+                start,
+                end,
+                colors.get(&feature_count.raw_mod_code),
                 feature_count.filtered_coverage,
                 format!("{:.2}", feature_count.fraction_modified * 100f32),
                 feature_count.n_modified,
@@ -160,7 +419,21 @@ impl<T: Write + Sized> BedMethylWriter<T> {
     }
 }
 
-impl<T: Write> PileupWriter<ModBasePileup> for BedMethylWriter<T> {
+impl BedMethylWriter<ParCompress<Bgzf>> {
+    /// Write the bedMethyl bgzip-compressed so that it can be indexed with
+    /// tabix once the writer is finished.
+    pub fn new_bgzf(
+        out_fp: &Path,
+        threads: usize,
+        tabs_and_spaces: bool,
+        with_header: bool,
+    ) -> anyhow::Result<Self> {
+        let writer = new_bgzf_writer(out_fp, true, threads)?;
+        Self::new(BufWriter::new(writer), tabs_and_spaces, with_header)
+    }
+}
+
+impl<T: FinishWrite> PileupWriter<ModBasePileup> for BedMethylWriter<T> {
     fn write(
         &mut self,
         item: ModBasePileup,
@@ -171,12 +444,14 @@ impl<T: Write> PileupWriter<ModBasePileup> for BedMethylWriter<T> {
             match feature_counts.get(&PartitionKey::NoKey) {
                 Some(feature_counts) => {
                     rows_written += BedMethylWriter::write_feature_counts(
-                        *pos,
+                        position_interval(*pos, self.one_based),
                         &item.chrom_name,
                         &feature_counts,
                         &mut self.buf_writer,
                         self.tabs_and_spaces,
                         motif_labels,
+                        &self.colors,
+                        self.score,
                     )?;
                 }
                 None => {}
@@ -184,9 +459,13 @@ impl<T: Write> PileupWriter<ModBasePileup> for BedMethylWriter<T> {
         }
         Ok(rows_written)
     }
+
+    fn finish(&mut self) -> AnyhowResult<()> {
+        self.buf_writer.finish_write()
+    }
 }
 
-impl<T: Write> PileupWriter<DuplexModBasePileup> for BedMethylWriter<T> {
+impl<T: FinishWrite> PileupWriter<DuplexModBasePileup> for BedMethylWriter<T> {
     fn write(
         &mut self,
         item: DuplexModBasePileup,
@@ -207,6 +486,7 @@ impl<T: Write> PileupWriter<DuplexModBasePileup> for BedMethylWriter<T> {
                 .iter()
                 .sorted_by(|(a, _), (b, _)| a.cmp(b))
             {
+                let (start, end) = position_interval(*pos, self.one_based);
                 for pattern in patterns.iter().sorted() {
                     let name = pattern.pattern_string(*base);
                     let row = format!(
@@ -229,14 +509,17 @@ impl<T: Write> PileupWriter<DuplexModBasePileup> for BedMethylWriter<T> {
                          {}{space}\
                          {}\n",
                         item.chrom_name,
-                        pos,
-                        pos + 1,
+                        start,
+                        end,
                         name,
-                        pattern.valid_coverage(),
+                        self.score.score(
+                            pattern.valid_coverage(),
+                            pattern.frac_pattern()
+                        ),
                         '.',
-                        pos,
-                        pos + 1,
-                        "255,0,0",
+                        start,
+                        end,
+                        DEFAULT_BEDMETHYL_COLOR,
                         pattern.valid_coverage(),
                         format!("{:.2}", pattern.frac_pattern() * 100f32),
                         pattern.count,
@@ -256,6 +539,10 @@ impl<T: Write> PileupWriter<DuplexModBasePileup> for BedMethylWriter<T> {
         }
         Ok(rows_written)
     }
+
+    fn finish(&mut self) -> AnyhowResult<()> {
+        self.buf_writer.finish_write()
+    }
 }
 
 #[derive(new, Hash, Eq, PartialEq, Copy, Clone)]
@@ -269,7 +556,13 @@ pub struct BedGraphWriter {
     prefix: Option<String>,
     out_dir: PathBuf,
     router: HashMap<(BedGraphFileKey, String), BufWriter<File>>,
+    /// Writers for the companion coverage tracks, routed the same way as
+    /// the fraction modified tracks.
+    coverage_router:
+        Option<HashMap<(BedGraphFileKey, String), BufWriter<File>>>,
     use_groupings: bool,
+    one_based: bool,
+    manifest: Option<PartitionManifest>,
 }
 
 impl BedGraphWriter {
@@ -287,10 +580,98 @@ impl BedGraphWriter {
             prefix: prefix.map(|s| s.to_owned()),
             out_dir: out_dir_fp,
             router: HashMap::new(),
+            coverage_router: None,
             use_groupings,
+            one_based: false,
+            manifest: None,
         })
     }
 
+    /// Write a manifest of the partitions when finished, only used when
+    /// partitioning by tag, see [`PartitionManifest`].
+    pub(crate) fn with_manifest(self, tags: &[String]) -> Self {
+        let manifest = self.use_groupings.then(|| PartitionManifest::new(tags));
+        Self { manifest, ..self }
+    }
+
+    /// Write 1-based, closed coordinates instead of BED coordinates, each
+    /// bedGraph starts with a comment line documenting the convention.
+    pub fn with_one_based(self, one_based: bool) -> Self {
+        Self { one_based, ..self }
+    }
+
+    /// Also write a coverage bedGraph (valid coverage at each position) for
+    /// each fraction modified bedGraph, named with a `_coverage` suffix.
+    pub fn with_coverage_tracks(self, coverage_tracks: bool) -> Self {
+        let coverage_router =
+            if coverage_tracks { Some(HashMap::new()) } else { None };
+        Self { coverage_router, ..self }
+    }
+
+    fn bedgraph_filepath(
+        out_dir: &Path,
+        prefix: Option<&String>,
+        key: BedGraphFileKey,
+        key_name: &str,
+        label: &str,
+        suffix: &str,
+    ) -> PathBuf {
+        Self::track_filepath(
+            out_dir, prefix, key, key_name, label, suffix, "bedgraph",
+        )
+    }
+
+    /// Path of the track for a modification code and strand (and motif),
+    /// `<prefix>_<partition>_<label>_<strand><suffix>.<extension>`.
+    fn track_filepath(
+        out_dir: &Path,
+        prefix: Option<&String>,
+        key: BedGraphFileKey,
+        key_name: &str,
+        label: &str,
+        suffix: &str,
+        extension: &str,
+    ) -> PathBuf {
+        let strand = key.strand;
+        let delim = if key_name == "" { "" } else { "_" };
+        let strand_label = match strand {
+            '+' => "positive",
+            '-' => "negative",
+            '.' => "combined",
+            _ => "_unknown",
+        };
+        let filename = if let Some(p) = prefix {
+            format!(
+                "{p}_{key_name}{delim}{label}_{strand_label}{suffix}.\
+                 {extension}"
+            )
+        } else {
+            format!(
+                "{key_name}{delim}{label}_{strand_label}{suffix}.{extension}"
+            )
+        };
+        out_dir.join(filename)
+    }
+
+    /// Label of the track for a modification code, with the motif when the
+    /// pileup is over multiple motifs, e.g. `m_CG0`.
+    fn track_label(
+        key: BedGraphFileKey,
+        motif_idx: Option<usize>,
+        motif_labels: &[String],
+    ) -> String {
+        if let Some(idx) = motif_idx {
+            motif_labels
+                .get(idx)
+                .map(|l| {
+                    format!("{}_{}", key.mod_code_repr, l.replace(",", ""))
+                })
+                .unwrap_or(format!("{}", key.mod_code_repr))
+        } else {
+            format!("{}", key.mod_code_repr)
+        }
+    }
+
     fn get_writer_for_modstrand(
         &mut self,
         key: BedGraphFileKey,
@@ -298,25 +679,65 @@ impl BedGraphWriter {
         label: String,
     ) -> &mut BufWriter<File> {
         self.router.entry((key, label.clone())).or_insert_with(|| {
-            let strand = key.strand;
-            let delim = if key_name == "" { "" } else { "_" };
-            let strand_label = match strand {
-                '+' => "positive",
-                '-' => "negative",
-                '.' => "combined",
-                _ => "_unknown",
-            };
-            let filename = if let Some(p) = &self.prefix {
-                format!("{p}_{key_name}{delim}{label}_{strand_label}.bedgraph")
-            } else {
-                format!("{key_name}{delim}{label}_{strand_label}.bedgraph")
-            };
-            let fp = self.out_dir.join(filename);
+            let fp = Self::bedgraph_filepath(
+                &self.out_dir,
+                self.prefix.as_ref(),
+                key,
+                key_name,
+                &label,
+                "",
+            );
+            if let Some(manifest) = self.manifest.as_mut() {
+                manifest.add_rows(key_name, &self.out_dir, &fp, 0);
+            }
             // todo(arand) danger, should remove this unwrap
             let fh = File::create(fp).unwrap();
-            BufWriter::new(fh)
+            let mut writer = BufWriter::new(fh);
+            if self.one_based {
+                writer.write_all(ONE_BASED_COMMENT.as_bytes()).unwrap();
+            }
+            writer
         })
     }
+
+    fn get_coverage_writer_for_modstrand(
+        &mut self,
+        key: BedGraphFileKey,
+        key_name: &str,
+        label: String,
+    ) -> AnyhowResult<Option<&mut BufWriter<File>>> {
+        let one_based = self.one_based;
+        let Some(router) = self.coverage_router.as_mut() else {
+            return Ok(None);
+        };
+        let writer = match router.entry((key, label)) {
+            std::collections::hash_map::Entry::Occupied(entry) => {
+                entry.into_mut()
+            }
+            std::collections::hash_map::Entry::Vacant(entry) => {
+                let fp = Self::bedgraph_filepath(
+                    &self.out_dir,
+                    self.prefix.as_ref(),
+                    key,
+                    key_name,
+                    &entry.key().1,
+                    "_coverage",
+                );
+                if let Some(manifest) = self.manifest.as_mut() {
+                    manifest.add_rows(key_name, &self.out_dir, &fp, 0);
+                }
+                let fh = File::create(&fp).with_context(|| {
+                    format!("failed to create coverage bedGraph at {fp:?}")
+                })?;
+                let mut writer = BufWriter::new(fh);
+                if one_based {
+                    writer.write_all(ONE_BASED_COMMENT.as_bytes())?;
+                }
+                entry.insert(writer)
+            }
+        };
+        Ok(Some(writer))
+    }
 }
 
 impl PileupWriter<ModBasePileup> for BedGraphWriter {
@@ -326,9 +747,11 @@ impl PileupWriter<ModBasePileup> for BedGraphWriter {
         motif_labels: &[String],
     ) -> AnyhowResult<u64> {
         let mut rows_written = 0;
+        let mut rows_per_key = FxHashMap::<&str, u64>::default();
         let tab = '\t';
         // let raw_code_only = motif_labels.len() < 2;
         for (pos, feature_counts) in item.iter_counts_sorted() {
+            let (start, end) = position_interval(*pos, self.one_based);
             for (partition_key, pileup_feature_counts) in feature_counts {
                 let key_name = match partition_key {
                     PartitionKey::NoKey => {
@@ -350,174 +773,622 @@ impl PileupWriter<ModBasePileup> for BedGraphWriter {
                         feature_count.raw_strand,
                         feature_count.raw_mod_code,
                     );
-                    let label = if let Some(idx) = feature_count.motif_idx {
-                        motif_labels
-                            .get(idx)
-                            .map(|l| {
-                                format!(
-                                    "{}_{}",
-                                    key.mod_code_repr,
-                                    l.replace(",", "")
-                                )
-                            })
-                            .unwrap_or(format!("{}", key.mod_code_repr))
-                    } else {
-                        format!("{}", key.mod_code_repr)
-                    };
+                    let label = Self::track_label(
+                        key,
+                        feature_count.motif_idx,
+                        motif_labels,
+                    );
+                    if let Some(fh) = self.get_coverage_writer_for_modstrand(
+                        key,
+                        key_name,
+                        label.clone(),
+                    )? {
+                        let row = format!(
+                            "{}{tab}{}{tab}{}{tab}{}\n",
+                            item.chrom_name,
+                            start,
+                            end,
+                            feature_count.filtered_coverage,
+                        );
+                        fh.write_all(row.as_bytes())?;
+                    }
                     let fh =
                         self.get_writer_for_modstrand(key, key_name, label);
                     let row = format!(
                         "{}{tab}{}{tab}{}{tab}{}{tab}{}\n",
                         item.chrom_name,
-                        pos,
-                        pos + 1,
+                        start,
+                        end,
                         feature_count.fraction_modified,
                         feature_count.filtered_coverage,
                     );
                     fh.write(row.as_bytes()).unwrap();
                     rows_written += 1;
+                    if self.manifest.is_some() {
+                        *rows_per_key.entry(key_name).or_insert(0u64) += 1;
+                    }
                 }
             }
         }
+        if let Some(manifest) = self.manifest.as_mut() {
+            for (key_name, n_rows) in rows_per_key {
+                manifest.add_row_count(key_name, n_rows);
+            }
+            manifest.add_reads(&item);
+        }
+
+        Ok(rows_written)
+    }
+
+    fn finish(&mut self) -> AnyhowResult<()> {
+        for writer in self.router.values_mut().chain(
+            self.coverage_router
+                .iter_mut()
+                .flat_map(|router| router.values_mut()),
+        ) {
+            writer.flush()?;
+        }
+        if let Some(manifest) = self.manifest.as_ref() {
+            manifest.write(&self.out_dir, self.prefix.as_ref())?;
+        }
+        Ok(())
+    }
+}
+
+/// Writes the fraction modified at each position as bigWig tracks, one for
+/// each modification code and strand (and motif), named like the bedGraphs
+/// of [`BedGraphWriter`] with a `.bw` extension. Each track is streamed to
+/// its file a contig at a time with [`BigWigTrack`].
+pub struct BigWigPileupWriter {
+    prefix: Option<String>,
+    out_dir: PathBuf,
+    chrom_sizes: HashMap<String, u32>,
+    tracks: HashMap<(BedGraphFileKey, String), BigWigTrack>,
+    /// Valid coverage tracks, keyed the same way as the fraction modified
+    /// tracks.
+    coverage_tracks: Option<HashMap<(BedGraphFileKey, String), BigWigTrack>>,
+    threads: usize,
+}
+
+impl BigWigPileupWriter {
+    pub fn new(
+        out_dir: &str,
+        prefix: Option<&String>,
+        chrom_sizes: HashMap<String, u32>,
+        threads: usize,
+    ) -> AnyhowResult<Self> {
+        let out_dir_fp = Path::new(out_dir).to_path_buf();
+        if !out_dir_fp.exists() {
+            info!("creating directory for bigWig output at {out_dir}");
+            std::fs::create_dir_all(out_dir_fp.clone())?;
+        }
+        Ok(Self {
+            prefix: prefix.map(|s| s.to_owned()),
+            out_dir: out_dir_fp,
+            chrom_sizes,
+            tracks: HashMap::new(),
+            coverage_tracks: None,
+            threads,
+        })
+    }
+
+    /// Also write a bigWig of the valid coverage at each position for each
+    /// fraction modified track, named with a `_coverage` suffix.
+    pub fn with_coverage_tracks(self, coverage_tracks: bool) -> Self {
+        let coverage_tracks =
+            if coverage_tracks { Some(HashMap::new()) } else { None };
+        Self { coverage_tracks, ..self }
+    }
+
+    /// Add a value to the fraction modified track, or the coverage track
+    /// when `coverage` is set, starting the track if it's new.
+    fn add_value(
+        &mut self,
+        key: BedGraphFileKey,
+        label: &str,
+        chrom: &str,
+        pos: u32,
+        value: f32,
+        coverage: bool,
+    ) -> AnyhowResult<()> {
+        let (tracks, suffix) = if coverage {
+            match self.coverage_tracks.as_mut() {
+                Some(coverage_tracks) => (coverage_tracks, "_coverage"),
+                None => return Ok(()),
+            }
+        } else {
+            (&mut self.tracks, "")
+        };
+        let track = match tracks.entry((key, label.to_string())) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => {
+                let out_fp = BedGraphWriter::track_filepath(
+                    &self.out_dir,
+                    self.prefix.as_ref(),
+                    key,
+                    "",
+                    label,
+                    suffix,
+                    "bw",
+                );
+                entry.insert(BigWigTrack::new(
+                    &out_fp,
+                    self.chrom_sizes.clone(),
+                    self.threads,
+                ))
+            }
+        };
+        track.add(chrom, pos, pos + 1, value)
+    }
+}
 
+impl PileupWriter<ModBasePileup> for BigWigPileupWriter {
+    fn write(
+        &mut self,
+        item: ModBasePileup,
+        motif_labels: &[String],
+    ) -> AnyhowResult<u64> {
+        let mut rows_written = 0;
+        for (pos, feature_counts) in item.iter_counts_sorted() {
+            for (partition_key, pileup_feature_counts) in feature_counts {
+                for feature_count in pileup_feature_counts {
+                    let key = BedGraphFileKey::new(
+                        *partition_key,
+                        feature_count.raw_strand,
+                        feature_count.raw_mod_code,
+                    );
+                    let label = BedGraphWriter::track_label(
+                        key,
+                        feature_count.motif_idx,
+                        motif_labels,
+                    );
+                    self.add_value(
+                        key,
+                        &label,
+                        &item.chrom_name,
+                        *pos,
+                        feature_count.filtered_coverage as f32,
+                        true,
+                    )?;
+                    self.add_value(
+                        key,
+                        &label,
+                        &item.chrom_name,
+                        *pos,
+                        feature_count.fraction_modified,
+                        false,
+                    )?;
+                    rows_written += 1;
+                }
+            }
+        }
         Ok(rows_written)
     }
+
+    fn finish(&mut self) -> AnyhowResult<()> {
+        let mut n_tracks = 0usize;
+        for track in std::mem::take(&mut self.tracks).into_values().chain(
+            self.coverage_tracks
+                .take()
+                .into_iter()
+                .flat_map(|coverage_tracks| coverage_tracks.into_values()),
+        ) {
+            if track.finish()? > 0 {
+                n_tracks += 1;
+            }
+        }
+        info!("wrote {n_tracks} bigWig track(s) to {:?}", self.out_dir);
+        Ok(())
+    }
+}
+
+/// How tables of summary statistics are rendered.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, ValueEnum)]
+#[allow(non_camel_case_types)]
+pub enum TableFormat {
+    /// Whitespace-aligned table.
+    #[default]
+    pretty,
+    /// GitHub-flavored markdown table.
+    markdown,
+    /// Comma-separated values with a header row.
+    csv,
+}
+
+impl TableFormat {
+    /// File extension used when a table is written to a file.
+    pub(crate) fn extension(&self) -> &'static str {
+        match self {
+            Self::pretty => "tsv",
+            Self::markdown => "md",
+            Self::csv => "csv",
+        }
+    }
+
+    fn markdown_format() -> PrettyFormat {
+        FormatBuilder::new()
+            .column_separator('|')
+            .borders('|')
+            .separator(
+                LinePosition::Title,
+                LineSeparator::new('-', '|', '|', '|'),
+            )
+            .padding(1, 1)
+            .build()
+    }
+
+    /// Print the table in this format, `pretty` uses the table's own
+    /// format. Returns the number of lines written.
+    pub(crate) fn print<W: Write>(
+        &self,
+        table: &mut Table,
+        writer: &mut W,
+    ) -> AnyhowResult<usize> {
+        match self {
+            Self::pretty => Ok(table.print(writer)?),
+            Self::markdown => {
+                table.set_format(Self::markdown_format());
+                Ok(table.print(writer)?)
+            }
+            Self::csv => {
+                let mut csv_writer = table.to_csv(writer)?;
+                csv_writer.flush()?;
+                Ok(table.len() + 1)
+            }
+        }
+    }
+}
+
+impl std::fmt::Display for TableFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::pretty => write!(f, "pretty"),
+            Self::markdown => write!(f, "markdown"),
+            Self::csv => write!(f, "csv"),
+        }
+    }
 }
 
 pub struct TableWriter<W: Write> {
     writer: BufWriter<W>,
+    format: TableFormat,
 }
 
 impl TableWriter<Stdout> {
-    pub fn new() -> Self {
+    pub fn new(format: TableFormat) -> Self {
         let out = BufWriter::new(std::io::stdout());
-        Self { writer: out }
+        Self { writer: out, format }
     }
 }
 
-impl<'a, W: Write> OutWriter<ModSummary<'a>> for TableWriter<W> {
-    fn write(&mut self, item: ModSummary<'a>) -> AnyhowResult<u64> {
-        let mut metadata_table = Table::new();
-        let metadata_format =
-            FormatBuilder::new().padding(1, 1).left_border('#').build();
-        metadata_table.set_format(metadata_format);
-        metadata_table.add_row(row!["bases", item.mod_bases()]);
-        metadata_table.add_row(row!["total_reads_used", item.total_reads_used]);
-        for (dna_base, reads_with_calls) in item.reads_with_mod_calls {
-            metadata_table.add_row(row![
-                format!("count_reads_{}", dna_base.char()),
-                reads_with_calls
-            ]);
+impl TableWriter<File> {
+    pub fn new_file(fp: &Path, format: TableFormat) -> AnyhowResult<Self> {
+        let out = BufWriter::new(File::create(fp)?);
+        Ok(Self { writer: out, format })
+    }
+}
+
+/// Build the totals (metadata) and modification calls tables for a
+/// summary, the totals table has no titles. When the summary has per-strand
+/// counts a third table with the calls split by reference strand is built.
+fn summary_tables(item: &ModSummary) -> (Table, Table, Option<Table>) {
+    let mut metadata_table = Table::new();
+    let metadata_format =
+        FormatBuilder::new().padding(1, 1).left_border('#').build();
+    metadata_table.set_format(metadata_format);
+    metadata_table.add_row(row!["bases", item.mod_bases()]);
+    metadata_table.add_row(row!["total_reads_used", item.total_reads_used]);
+    for (dna_base, reads_with_calls) in item.reads_with_mod_calls.iter() {
+        metadata_table.add_row(row![
+            format!("count_reads_{}", dna_base.char()),
+            reads_with_calls
+        ]);
+    }
+    for (dna_base, threshold) in item.per_base_thresholds.iter() {
+        metadata_table.add_row(row![
+            format!("pass_threshold_{}", dna_base.char()),
+            threshold
+        ]);
+    }
+    if let Some(region) = item.region {
+        metadata_table.add_row(row!["region", region.to_string()]);
+    }
+    for (name, value) in item.provenance.fields() {
+        metadata_table.add_row(row![name, value]);
+    }
+
+    let mut report_table = Table::new();
+    report_table.set_format(*prettytable::format::consts::FORMAT_CLEAN);
+    report_table.set_titles(row![
+        "base",
+        "code",
+        "pass_count",
+        "pass_frac",
+        "all_count",
+        "all_frac",
+    ]);
+
+    let iter =
+        item.per_base_mod_codes.iter().map(|(primary_base, mod_codes)| {
+            let pass_counts = item.mod_call_counts.get(primary_base);
+            let filtered_counts =
+                item.filtered_mod_call_counts.get(primary_base);
+            (*primary_base, pass_counts, filtered_counts, mod_codes.clone())
+        });
+    for (canonical_base, pass_mod_to_counts, filtered_counts, mut mod_codes) in
+        iter
+    {
+        let total_pass_calls = pass_mod_to_counts
+            .map(|counts| counts.values().sum::<u64>())
+            .unwrap_or(0);
+        let total_filtered_calls = filtered_counts
+            .map(|counts| counts.values().sum::<u64>())
+            .unwrap_or(0);
+        let total_calls = total_filtered_calls + total_pass_calls;
+
+        let mut seen_canonical = false;
+        if let Some(pass_counts) = pass_mod_to_counts {
+            for (base_state, pass_counts) in
+                pass_counts.iter().sorted_by(|(a, _), (b, _)| a.cmp(b))
+            {
+                let label = match base_state {
+                    BaseState::Canonical(_) => {
+                        seen_canonical = true;
+                        format!("-") // could be a const..
+                    }
+                    BaseState::Modified(repr) => {
+                        mod_codes.remove(repr);
+                        format!("{repr}")
+                    }
+                };
+                let filtered = *item
+                    .filtered_mod_call_counts
+                    .get(&canonical_base)
+                    .and_then(|filtered_counts| {
+                        filtered_counts.get(&base_state)
+                    })
+                    .unwrap_or(&0);
+                let all_counts = *pass_counts + filtered;
+                let all_frac = all_counts as f32 / total_calls as f32;
+                let pass_frac = *pass_counts as f32 / total_pass_calls as f32;
+                report_table.add_row(row![
+                    canonical_base.char(),
+                    label,
+                    pass_counts,
+                    pass_frac,
+                    all_counts,
+                    all_frac,
+                ]);
+            }
         }
-        for (dna_base, threshold) in item.per_base_thresholds {
-            metadata_table.add_row(row![
-                format!("pass_threshold_{}", dna_base.char()),
-                threshold
+
+        if !seen_canonical {
+            report_table.add_row(row![
+                canonical_base.char(),
+                format!("-"),
+                0u64,
+                0f32,
+                0u64,
+                0f32
             ]);
         }
-        if let Some(region) = item.region {
-            metadata_table.add_row(row!["region", region.to_string()]);
+        for mod_code in mod_codes {
+            report_table.add_row(row![
+                canonical_base.char(),
+                format!("{mod_code}"),
+                0u64,
+                0f32,
+                0u64,
+                0f32
+            ]);
         }
-        let emitted = metadata_table.print(&mut self.writer)?;
+    }
 
-        let mut report_table = Table::new();
-        report_table.set_format(*prettytable::format::consts::FORMAT_CLEAN);
-        report_table.set_titles(row![
-            "base",
-            "code",
-            "pass_count",
-            "pass_frac",
-            "all_count",
-            "all_frac",
-        ]);
+    let strand_table = item.strand_call_counts.as_ref().map(strand_table);
 
-        let iter = item.per_base_mod_codes.into_iter().map(
-            |(primary_base, mod_codes)| {
-                let pass_counts = item.mod_call_counts.get(&primary_base);
-                let filtered_counts =
-                    item.filtered_mod_call_counts.get(&primary_base);
-                (primary_base, pass_counts, filtered_counts, mod_codes)
-            },
-        );
-        for (
-            canonical_base,
-            pass_mod_to_counts,
-            filtered_counts,
-            mut mod_codes,
-        ) in iter
-        {
-            let total_pass_calls = pass_mod_to_counts
-                .map(|counts| counts.values().sum::<u64>())
-                .unwrap_or(0);
-            let total_filtered_calls = filtered_counts
-                .map(|counts| counts.values().sum::<u64>())
-                .unwrap_or(0);
-            let total_calls = total_filtered_calls + total_pass_calls;
+    (metadata_table, report_table, strand_table)
+}
 
-            let mut seen_canonical = false;
-            if let Some(pass_counts) = pass_mod_to_counts {
-                for (base_state, pass_counts) in
-                    pass_counts.iter().sorted_by(|(a, _), (b, _)| a.cmp(b))
-                {
-                    let label = match base_state {
-                        BaseState::Canonical(_) => {
-                            seen_canonical = true;
-                            format!("-") // could be a const..
-                        }
-                        BaseState::Modified(repr) => {
-                            mod_codes.remove(repr);
-                            format!("{repr}")
-                        }
-                    };
-                    let filtered = *item
-                        .filtered_mod_call_counts
-                        .get(&canonical_base)
-                        .and_then(|filtered_counts| {
-                            filtered_counts.get(&base_state)
-                        })
-                        .unwrap_or(&0);
-                    let all_counts = *pass_counts + filtered;
-                    let all_frac = all_counts as f32 / total_calls as f32;
-                    let pass_frac =
-                        *pass_counts as f32 / total_pass_calls as f32;
-                    report_table.add_row(row![
-                        canonical_base.char(),
-                        label,
-                        pass_counts,
-                        pass_frac,
-                        all_counts,
-                        all_frac,
-                    ]);
-                }
-            }
+/// Table of the modification calls for each canonical base and reference
+/// strand, fractions are within each base and strand.
+fn strand_table(counts: &StrandCallCounts) -> Table {
+    let mut table = Table::new();
+    table.set_format(*prettytable::format::consts::FORMAT_CLEAN);
+    table.set_titles(row![
+        "base",
+        "strand",
+        "code",
+        "pass_count",
+        "pass_frac",
+        "all_count",
+        "all_frac",
+    ]);
+    let keys = counts
+        .mod_call_counts
+        .keys()
+        .chain(counts.filtered_mod_call_counts.keys())
+        .copied()
+        .unique()
+        .sorted_by(|(a_base, a_strand), (b_base, b_strand)| {
+            a_base.char().cmp(&b_base.char()).then(a_strand.cmp(b_strand))
+        });
+    let empty = HashMap::new();
+    for key in keys {
+        let (canonical_base, strand) = key;
+        let pass_counts = counts.mod_call_counts.get(&key).unwrap_or(&empty);
+        let filtered_counts =
+            counts.filtered_mod_call_counts.get(&key).unwrap_or(&empty);
+        let total_pass_calls = pass_counts.values().sum::<u64>();
+        let total_calls =
+            total_pass_calls + filtered_counts.values().sum::<u64>();
+        for base_state in
+            pass_counts.keys().chain(filtered_counts.keys()).unique().sorted()
+        {
+            let label = match base_state {
+                BaseState::Canonical(_) => "-".to_string(),
+                BaseState::Modified(repr) => format!("{repr}"),
+            };
+            let pass = *pass_counts.get(base_state).unwrap_or(&0);
+            let all = pass + *filtered_counts.get(base_state).unwrap_or(&0);
+            let pass_frac = if total_pass_calls > 0 {
+                pass as f32 / total_pass_calls as f32
+            } else {
+                0f32
+            };
+            table.add_row(row![
+                canonical_base.char(),
+                strand.to_char(),
+                label,
+                pass,
+                pass_frac,
+                all,
+                all as f32 / total_calls as f32,
+            ]);
+        }
+    }
+    table
+}
 
-            if !seen_canonical {
-                report_table.add_row(row![
-                    canonical_base.char(),
-                    format!("-"),
-                    0u64,
-                    0f32,
-                    0u64,
-                    0f32
-                ]);
-            }
-            for mod_code in mod_codes {
-                report_table.add_row(row![
-                    canonical_base.char(),
-                    format!("{mod_code}"),
-                    0u64,
-                    0f32,
-                    0u64,
-                    0f32
-                ]);
-            }
+impl<'a, W: Write> OutWriter<ModSummary<'a>> for TableWriter<W> {
+    fn write(&mut self, item: ModSummary<'a>) -> AnyhowResult<u64> {
+        let (mut metadata_table, mut report_table, strand_table) =
+            summary_tables(&item);
+        if self.format != TableFormat::pretty {
+            metadata_table.set_titles(row!["name", "value"]);
         }
-        let mut report_emitted = report_table.print(&mut self.writer)?;
+        let mut emitted =
+            self.format.print(&mut metadata_table, &mut self.writer)?;
+        if self.format != TableFormat::pretty {
+            // markdown and csv tables need to be separated by a blank line
+            writeln!(self.writer)?;
+            emitted += 1;
+        }
+        let mut report_emitted =
+            self.format.print(&mut report_table, &mut self.writer)?;
+        if let Some(mut strand_table) = strand_table {
+            writeln!(self.writer)?;
+            report_emitted += 1;
+            report_emitted +=
+                self.format.print(&mut strand_table, &mut self.writer)?;
+        }
+        self.writer.flush()?;
         report_emitted += emitted;
         Ok(report_emitted as u64)
     }
 }
 
+fn escape_html(raw: &str) -> String {
+    raw.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;")
+}
+
+/// Render a table as an HTML `<table>`, the first row is used as the header
+/// when `has_titles` is true.
+fn table_to_html(table: &Table, has_titles: bool) -> AnyhowResult<String> {
+    let mut csv_writer = csv::WriterBuilder::new().from_writer(Vec::new());
+    csv_writer = table.to_csv_writer(csv_writer)?;
+    let raw = csv_writer.into_inner().map_err(|e| anyhow!("{e}"))?;
+    let mut reader = csv::ReaderBuilder::new()
+        .has_headers(false)
+        .flexible(true)
+        .from_reader(raw.as_slice());
+    let mut html = String::from("<table>\n");
+    for (i, record) in reader.records().enumerate() {
+        let record = record?;
+        let cell_tag = if i == 0 && has_titles { "th" } else { "td" };
+        let cells = record
+            .iter()
+            .map(|cell| {
+                format!("<{cell_tag}>{}</{cell_tag}>", escape_html(cell))
+            })
+            .join("");
+        html.push_str(&format!("<tr>{cells}</tr>\n"));
+    }
+    html.push_str("</table>\n");
+    Ok(html)
+}
+
+/// Write the histograms of base modification probabilities as a
+/// tab-separated table, the same table as `sample-probs --hist`.
+pub(crate) fn write_histograms_tsv<W: Write>(
+    writer: W,
+    histograms: &ProbHistogram,
+) -> AnyhowResult<()> {
+    let (tab, _, _) =
+        histograms.get_artifacts(&HashMap::new(), &HashMap::new());
+    let csv_writer = csv::WriterBuilder::new()
+        .has_headers(true)
+        .delimiter(b'\t')
+        .from_writer(writer);
+    tab.to_csv_writer(csv_writer)?;
+    Ok(())
+}
+
+/// The echarts library (Apache-2.0), the same version that charming renders
+/// charts for.
+const ECHARTS_JS: &str = include_str!("assets/echarts-5.4.2.min.js");
+
+/// Write a summary, and optionally the histograms of base modification
+/// probabilities, as a single self-contained HTML page.
+pub(crate) fn write_summary_html<W: Write>(
+    writer: &mut W,
+    summary: &ModSummary,
+    histograms: Option<&ProbHistogram>,
+    title: &str,
+) -> AnyhowResult<()> {
+    let (metadata_table, report_table, strand_table) = summary_tables(summary);
+    let mut sections = vec![
+        "<h2>Totals</h2>".to_string(),
+        table_to_html(&metadata_table, false)?,
+        "<h2>Modification calls</h2>".to_string(),
+        table_to_html(&report_table, true)?,
+    ];
+    if let Some(strand_table) = strand_table {
+        sections.push(
+            "<h2>Modification calls by reference strand</h2>".to_string(),
+        );
+        sections.push(table_to_html(&strand_table, true)?);
+    }
+    let mut scripts = Vec::new();
+    if let Some(histograms) = histograms {
+        let (_, counts_chart, prop_chart) =
+            histograms.get_artifacts(&HashMap::new(), &HashMap::new());
+        sections.push("<h2>Base modification probabilities</h2>".to_string());
+        for (chart_id, chart) in
+            [("counts_chart", counts_chart), ("proportion_chart", prop_chart)]
+        {
+            sections.push(format!(
+                "<div id=\"{chart_id}\" style=\"width: 800px; height: \
+                 600px\"></div>"
+            ));
+            scripts.push(format!(
+                "echarts.init(document.getElementById('{chart_id}'), null, {{ \
+                 renderer: 'canvas' }}).setOption({});",
+                chart.to_string()
+            ));
+        }
+    }
+    // embed echarts so the report can be viewed offline
+    let echarts = if scripts.is_empty() {
+        String::new()
+    } else {
+        format!("<script type=\"text/javascript\">\n{ECHARTS_JS}\n</script>")
+    };
+    let title = escape_html(title);
+    let body = sections.join("\n");
+    let scripts = scripts.join("\n");
+    writeln!(
+        writer,
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\" \
+         />\n<title>{title}</title>\n{echarts}\n<style>body {{ font-family: \
+         sans-serif; }} table {{ border-collapse: collapse; }} td, th {{ \
+         border: 1px solid #ccc; padding: 4px 8px; \
+         }}</style>\n</head>\n<body>\n<h1>{title}</h1>\n{body}\n<script \
+         type=\"text/javascript\">\n{scripts}\n</script>\n</body>\n</html>"
+    )?;
+    writer.flush()?;
+    Ok(())
+}
+
 pub struct TsvWriter<W> {
     writer: W,
 }
@@ -528,6 +1399,13 @@ impl<T: Write> TsvWriter<T> {
     }
 }
 
+impl<T: FinishWrite> TsvWriter<T> {
+    /// Flush and close the output, see [`FinishWrite`].
+    pub(crate) fn finish(&mut self) -> AnyhowResult<()> {
+        self.writer.finish_write()
+    }
+}
+
 impl TsvWriter<BufWriter<std::io::Sink>> {
     pub fn new_null() -> Self {
         let out = BufWriter::new(std::io::sink());
@@ -575,6 +1453,58 @@ impl TsvWriter<BufWriter<File>> {
     }
 }
 
+impl TsvWriter<SqliteTableWriter> {
+    pub(crate) fn new_sqlite(
+        fp: &str,
+        force: bool,
+        table: &str,
+        columns: &[(String, ColumnType)],
+        indices: &[&[&str]],
+    ) -> anyhow::Result<Self> {
+        let writer = SqliteTableWriter::new(
+            Path::new(fp),
+            force,
+            table,
+            columns,
+            indices,
+        )?;
+        Ok(Self { writer })
+    }
+}
+
+impl TsvWriter<ParquetTableWriter> {
+    pub(crate) fn new_parquet(
+        fp: &str,
+        force: bool,
+        table: &str,
+        columns: &[(String, ColumnType)],
+    ) -> anyhow::Result<Self> {
+        let writer =
+            ParquetTableWriter::new(Path::new(fp), force, table, columns)?;
+        Ok(Self { writer })
+    }
+}
+
+/// Open a parallel bgzip-compressing writer at `fp`, the output can be
+/// indexed with tabix once it has been finished, see [`FinishWrite`].
+pub(crate) fn new_bgzf_writer(
+    fp: &Path,
+    force: bool,
+    threads: usize,
+) -> anyhow::Result<ParCompress<Bgzf>> {
+    let out_fh = if force {
+        File::create(fp)?
+    } else {
+        File::create_new(fp)
+            .with_context(|| format!("refusing to overwrite {fp:?}"))?
+    };
+    let writer = ParCompressBuilder::<Bgzf>::new()
+        .num_threads(threads)
+        .map_err(|e| anyhow!("failed to make bgzf writer, {e}"))?
+        .from_writer(out_fh);
+    Ok(writer)
+}
+
 impl TsvWriter<ParCompress<Bgzf>> {
     pub fn new_gzip(
         fp: &str,
@@ -582,16 +1512,7 @@ impl TsvWriter<ParCompress<Bgzf>> {
         threads: usize,
         header: Option<String>,
     ) -> anyhow::Result<Self> {
-        let fp = Path::new(fp);
-        let out_fh = if force {
-            File::create(fp)?
-        } else {
-            File::create_new(fp).context("refusing to overwrite {fp:?}")?
-        };
-        let mut writer = ParCompressBuilder::<Bgzf>::new()
-            .num_threads(threads)
-            .unwrap()
-            .from_writer(out_fh);
+        let mut writer = new_bgzf_writer(Path::new(fp), force, threads)?;
         if let Some(header) = header {
             writer.write(header.as_bytes())?;
             writer.write(&['\n' as u8])?;
@@ -620,6 +1541,9 @@ impl<'a, W: Write> OutWriter<ModSummary<'a>> for TsvWriter<W> {
         let mut report = String::new();
         let mod_called_bases = item.mod_bases();
         report.push_str(&format!("mod_bases\t{}\n", mod_called_bases));
+        for (name, value) in item.provenance.fields() {
+            report.push_str(&format!("{name}\t{value}\n"));
+        }
         for (dna_base, read_count) in item.reads_with_mod_calls {
             report.push_str(&format!(
                 "count_reads_{}\t{}\n",
@@ -677,6 +1601,43 @@ impl<'a, W: Write> OutWriter<ModSummary<'a>> for TsvWriter<W> {
             ));
         }
 
+        if let Some(strand_counts) = item.strand_call_counts.as_ref() {
+            for ((canonical_base, strand), mod_counts) in
+                strand_counts.mod_call_counts.iter()
+            {
+                let strand_label = match strand {
+                    Strand::Positive => "pos",
+                    Strand::Negative => "neg",
+                };
+                let total_calls = mod_counts.values().sum::<u64>() as f64;
+                for (base_state, counts) in mod_counts {
+                    let label = match base_state {
+                        BaseState::Canonical(_) => "unmodified".to_string(),
+                        BaseState::Modified(repr) => format!("modified_{repr}"),
+                    };
+                    let filtered = *strand_counts
+                        .filtered_mod_call_counts
+                        .get(&(*canonical_base, *strand))
+                        .and_then(|filtered_counts| {
+                            filtered_counts.get(base_state)
+                        })
+                        .unwrap_or(&0);
+                    let prefix =
+                        format!("{}_{strand_label}", canonical_base.char());
+                    report.push_str(&format!(
+                        "{prefix}_pass_calls_{label}\t{counts}\n"
+                    ));
+                    report.push_str(&format!(
+                        "{prefix}_pass_frac_{label}\t{}\n",
+                        *counts as f64 / total_calls
+                    ));
+                    report.push_str(&format!(
+                        "{prefix}_fail_calls_{label}\t{filtered}\n"
+                    ));
+                }
+            }
+        }
+
         report.push_str(&format!(
             "total_reads_used\t{}\n",
             item.total_reads_used
@@ -699,37 +1660,55 @@ pub(crate) struct SampledProbs {
     prefix: Option<String>,
     primary_base_colors: HashMap<DnaBase, String>,
     mod_base_colors: HashMap<ModCodeRepr, String>,
+    table_format: TableFormat,
 }
 
 impl SampledProbs {
-    fn get_thresholds_filename_prefix(prefix: Option<&String>) -> String {
+    fn get_thresholds_filename_prefix(
+        prefix: Option<&String>,
+        table_format: TableFormat,
+    ) -> String {
+        let ext = table_format.extension();
         if let Some(prefix) = prefix {
-            format!("{prefix}_thresholds.tsv")
+            format!("{prefix}_thresholds.{ext}")
         } else {
-            format!("thresholds.tsv")
+            format!("thresholds.{ext}")
         }
     }
 
     fn get_probabilities_filenames(
         prefix: Option<&String>,
+        table_format: TableFormat,
     ) -> (String, String, String) {
+        let ext = table_format.extension();
         if let Some(prefix) = prefix {
             (
-                format!("{prefix}_probabilities.tsv"),
+                format!("{prefix}_probabilities.{ext}"),
                 format!("{prefix}_counts.html"),
                 format!("{prefix}_proportion.html"),
             )
         } else {
             (
-                "probabilities.tsv".into(),
+                format!("probabilities.{ext}"),
                 "counts.html".into(),
                 "proportion.html".into(),
             )
         }
     }
 
+    fn get_json_filename_prefix(prefix: Option<&String>) -> String {
+        if let Some(prefix) = prefix {
+            format!("{prefix}_thresholds.json")
+        } else {
+            "thresholds.json".to_string()
+        }
+    }
+
     fn get_thresholds_filename(&self) -> String {
-        Self::get_thresholds_filename_prefix(self.prefix.as_ref())
+        Self::get_thresholds_filename_prefix(
+            self.prefix.as_ref(),
+            self.table_format,
+        )
     }
 
     pub(crate) fn check_files(
@@ -737,17 +1716,25 @@ impl SampledProbs {
         prefix: Option<&String>,
         force: bool,
         with_histograms: bool,
+        with_json: bool,
+        table_format: TableFormat,
     ) -> anyhow::Result<()> {
-        let filename = Self::get_thresholds_filename_prefix(prefix);
-        let fp = p.join(filename);
-        if fp.exists() && !force {
-            return Err(anyhow!("refusing to overwrite {:?}", fp));
-        } else if fp.exists() && force {
-            debug!("thresholds file at {:?} will be overwritten", fp);
+        let filename =
+            Self::get_thresholds_filename_prefix(prefix, table_format);
+        let mut threshold_fps = vec![p.join(filename)];
+        if with_json {
+            threshold_fps.push(p.join(Self::get_json_filename_prefix(prefix)));
+        }
+        for fp in threshold_fps {
+            if fp.exists() && !force {
+                return Err(anyhow!("refusing to overwrite {:?}", fp));
+            } else if fp.exists() && force {
+                debug!("thresholds file at {:?} will be overwritten", fp);
+            }
         }
         if with_histograms {
             let (probs_table_fn, counts_plot_fn, prop_plot_fn) =
-                Self::get_probabilities_filenames(prefix);
+                Self::get_probabilities_filenames(prefix, table_format);
             let probs_table_fp = p.join(probs_table_fn);
             let counts_plot_fp = p.join(counts_plot_fn);
             let prop_plot_fp = p.join(prop_plot_fn);
@@ -776,9 +1763,46 @@ impl SampledProbs {
             self.prefix.as_ref(),
             force,
             self.histograms.is_some(),
+            self.percentiles.values().any(|p| p.curve.is_some()),
+            self.table_format,
         )
     }
 
+    fn thresholds_json(&self) -> String {
+        let points_json = |points: &[(f32, f32)]| -> String {
+            let points = points
+                .iter()
+                .map(|(q, p)| {
+                    // round away float noise from the conversion to percent
+                    let percentile = (*q * 100_000f32).round() / 1000f32;
+                    json_object(&[
+                        ("percentile", json_float(percentile)),
+                        ("threshold", json_float(*p)),
+                    ])
+                })
+                .join(",");
+            format!("[{points}]")
+        };
+        let bases = self
+            .percentiles
+            .iter()
+            .sorted_by_key(|(base, _)| **base)
+            .map(|(base, percentiles)| {
+                let mut fields =
+                    vec![("thresholds", points_json(&percentiles.qs))];
+                if let Some(curve) = percentiles.curve.as_ref() {
+                    fields.push(("curve", points_json(curve)));
+                }
+                format!(
+                    "{}:{}",
+                    json_string(&base.char().to_string()),
+                    json_object(&fields)
+                )
+            })
+            .join(",");
+        json_object(&[("bases", format!("{{{bases}}}"))])
+    }
+
     fn thresholds_table(&self) -> Table {
         let mut table = Table::new();
         table.set_format(*prettytable::format::consts::FORMAT_CLEAN);
@@ -877,12 +1901,21 @@ impl ProbHistogram {
         for ((primary_base, base_state), counts) in iter {
             let (label, color) = match base_state {
                 BaseState::Modified(x) => (
-                    format!("{primary_base}:{x}"),
-                    extra_mod_colors.get(x).or(MOD_COLORS.get(x)),
+                    format!(
+                        "{primary_base}:{}",
+                        ModCodeRegistry::global().describe(x)
+                    ),
+                    extra_mod_colors
+                        .get(x)
+                        .map(|c| c.as_str())
+                        .or_else(|| ModCodeRegistry::global().color(x)),
                 ),
                 BaseState::Canonical(x) => (
                     format!("{primary_base}:-"),
-                    extra_dna_colors.get(x).or(DNA_BASE_COLORS.get(x)),
+                    extra_dna_colors
+                        .get(x)
+                        .or(DNA_BASE_COLORS.get(x))
+                        .map(|c| c.as_str()),
                 ),
             };
             // dbg!(label, color);
@@ -951,17 +1984,28 @@ impl ProbHistogram {
 impl OutWriter<SampledProbs> for MultiTableWriter {
     fn write(&mut self, item: SampledProbs) -> AnyhowResult<u64> {
         let mut rows_written = 0u64;
-        let thresh_table = item.thresholds_table();
+        let mut thresh_table = item.thresholds_table();
 
         let threshold_fn = self.out_dir.join(item.get_thresholds_filename());
         let mut fh = File::create(threshold_fn)?;
-        let n_written = thresh_table.print(&mut fh)?;
+        let n_written = item.table_format.print(&mut thresh_table, &mut fh)?;
         rows_written += n_written as u64;
 
+        if item.percentiles.values().any(|p| p.curve.is_some()) {
+            let json_fn = self.out_dir.join(
+                SampledProbs::get_json_filename_prefix(item.prefix.as_ref()),
+            );
+            let mut fh = File::create(json_fn)?;
+            writeln!(fh, "{}", item.thresholds_json())?;
+        }
+
         if let Some(histograms) = &item.histograms {
             let (probs_table_fn, counts_plot_fn, prop_plot_fn) =
-                SampledProbs::get_probabilities_filenames(item.prefix.as_ref());
-            let probs_table_fh =
+                SampledProbs::get_probabilities_filenames(
+                    item.prefix.as_ref(),
+                    item.table_format,
+                );
+            let mut probs_table_fh =
                 File::create(self.out_dir.join(probs_table_fn))?;
             let mut counts_plot_fh = BufWriter::new(File::create(
                 self.out_dir.join(counts_plot_fn),
@@ -969,16 +2013,19 @@ impl OutWriter<SampledProbs> for MultiTableWriter {
             let mut prop_plot_fh =
                 BufWriter::new(File::create(self.out_dir.join(prop_plot_fn))?);
 
-            let csv_writer = csv::WriterBuilder::new()
-                .has_headers(true)
-                .delimiter('\t' as u8)
-                .from_writer(probs_table_fh);
-
-            let (tab, counts_chart, prop_chart) = histograms.get_artifacts(
+            let (mut tab, counts_chart, prop_chart) = histograms.get_artifacts(
                 &item.primary_base_colors,
                 &item.mod_base_colors,
             );
-            tab.to_csv_writer(csv_writer)?;
+            if item.table_format == TableFormat::pretty {
+                let csv_writer = csv::WriterBuilder::new()
+                    .has_headers(true)
+                    .delimiter('\t' as u8)
+                    .from_writer(probs_table_fh);
+                tab.to_csv_writer(csv_writer)?;
+            } else {
+                item.table_format.print(&mut tab, &mut probs_table_fh)?;
+            }
             match HtmlRenderer::new("Counts", 800, 800).render(&counts_chart) {
                 Ok(blob) => {
                     counts_plot_fh.write(blob.as_bytes()).map(|_x| ())?
@@ -996,21 +2043,54 @@ impl OutWriter<SampledProbs> for MultiTableWriter {
     }
 }
 
-impl OutWriter<SampledProbs> for TsvWriter<BufWriter<Stdout>> {
+impl<W: Write> OutWriter<SampledProbs> for TableWriter<W> {
     fn write(&mut self, item: SampledProbs) -> AnyhowResult<u64> {
-        let mut rows_written = 0u64;
-        let thresholds_table = item.thresholds_table();
-        let n_written = thresholds_table.print(&mut self.writer)?;
-        rows_written += n_written as u64;
-        Ok(rows_written)
+        let mut thresholds_table = item.thresholds_table();
+        let n_written =
+            self.format.print(&mut thresholds_table, &mut self.writer)?;
+        self.writer.flush()?;
+        Ok(n_written as u64)
     }
 }
 
+/// Rows of one partition from one pileup chunk, sent to the thread writing
+/// the partition.
+struct PartitionRows {
+    key_name: String,
+    file_path: Arc<Path>,
+    chrom_name: Arc<str>,
+    counts: Vec<(u32, Vec<PileupFeatureCounts>)>,
+}
+
+/// Thread that serializes and writes the partitions assigned to it. Each
+/// partition is only written by one of these, in the order its rows were
+/// sent, so the rows in each file stay sorted.
+struct PartitionWorker {
+    snd: crossbeam_channel::Sender<PartitionRows>,
+    handle: std::thread::JoinHandle<AnyhowResult<()>>,
+}
+
+/// Number of chunks of rows that can be waiting to be written by each
+/// [`PartitionWorker`].
+const PARTITION_QUEUE_SIZE: usize = 64;
+
 pub struct PartitioningBedMethylWriter {
     prefix: Option<String>,
     out_dir: PathBuf,
     tabs_and_spaces: bool,
-    router: FxHashMap<String, BufWriter<File>>,
+    colors: ModColorMap,
+    one_based: bool,
+    score: BedScore,
+    manifest: Option<PartitionManifest>,
+    /// Tags to nest the partitions by, see
+    /// [`PartitioningBedMethylWriter::with_nested_partitions`].
+    nested_tags: Option<Vec<String>>,
+    num_threads: usize,
+    /// Started on the first write.
+    workers: Vec<PartitionWorker>,
+    /// Index of the worker writing each partition and the file it's written
+    /// to.
+    assignments: FxHashMap<String, (usize, Arc<Path>)>,
 }
 
 impl PartitioningBedMethylWriter {
@@ -1026,61 +2106,419 @@ impl PartitioningBedMethylWriter {
         }
         let out_dir = dir_path.to_path_buf();
         let prefix = prefix.cloned();
-        let router = FxHashMap::default();
-        Ok(Self { out_dir, prefix, router, tabs_and_spaces: !only_tabs })
+        Ok(Self {
+            out_dir,
+            prefix,
+            tabs_and_spaces: !only_tabs,
+            colors: ModColorMap::default(),
+            one_based: false,
+            score: BedScore::default(),
+            manifest: None,
+            nested_tags: None,
+            num_threads: 1,
+            workers: Vec::new(),
+            assignments: FxHashMap::default(),
+        })
     }
 
-    fn get_writer_for_key(&mut self, key_name: &str) -> &mut BufWriter<File> {
-        self.router.entry(key_name.to_owned()).or_insert_with(|| {
-            let filename = if let Some(prefix) = self.prefix.as_ref() {
-                format!("{prefix}_{key_name}.bed")
-            } else {
-                format!("{key_name}.bed")
-            };
-            let fp = self.out_dir.join(filename);
-            let fh = File::create(fp).unwrap();
+    /// Set the colors written to the `color` column for each modification
+    /// code.
+    pub fn with_colors(self, colors: ModColorMap) -> Self {
+        Self { colors, ..self }
+    }
 
-            BufWriter::new(fh)
-        })
+    /// Write 1-based, closed coordinates instead of BED coordinates.
+    pub fn with_one_based(self, one_based: bool) -> Self {
+        Self { one_based, ..self }
+    }
+
+    /// Set what the `score` column encodes, see [`BedScore`].
+    pub fn with_score(self, score: BedScore) -> Self {
+        Self { score, ..self }
+    }
+
+    /// Write a manifest of the partitions when finished, see
+    /// [`PartitionManifest`].
+    pub(crate) fn with_manifest(self, tags: &[String]) -> Self {
+        Self { manifest: Some(PartitionManifest::new(tags)), ..self }
+    }
+
+    /// Number of threads serializing and writing the partitions, partitions
+    /// are divided between the threads as they're first seen.
+    pub fn with_threads(self, num_threads: usize) -> Self {
+        Self { num_threads: num_threads.max(1), ..self }
     }
+
+    /// Write each partition into nested directories, one level per tag in
+    /// the order given, e.g. `HP=1/RG=a/<prefix>_pileup.bed`, instead of a
+    /// flat file name joining the tag values. Reads without any of the tags
+    /// are still written to `<prefix>_ungrouped.bed` in the output
+    /// directory.
+    pub fn with_nested_partitions(self, tags: &[String]) -> Self {
+        Self { nested_tags: Some(tags.to_vec()), ..self }
+    }
+
+    fn filepath_for_key(
+        &self,
+        key_name: &str,
+        tag_values: Option<&[Option<String>]>,
+    ) -> PathBuf {
+        match (self.nested_tags.as_ref(), tag_values) {
+            (Some(tags), Some(values)) => nested_partition_filepath(
+                &self.out_dir,
+                self.prefix.as_ref(),
+                tags,
+                values,
+            ),
+            _ => partition_filepath(
+                &self.out_dir,
+                self.prefix.as_ref(),
+                key_name,
+            ),
+        }
+    }
+
+    fn start_workers(&mut self, motif_labels: &[String]) {
+        let motif_labels = Arc::<[String]>::from(motif_labels);
+        self.workers = (0..self.num_threads)
+            .map(|_| {
+                let (snd, rcv) = crossbeam_channel::bounded::<PartitionRows>(
+                    PARTITION_QUEUE_SIZE,
+                );
+                let tabs_and_spaces = self.tabs_and_spaces;
+                let one_based = self.one_based;
+                let score = self.score;
+                let colors = self.colors.clone();
+                let motif_labels = motif_labels.clone();
+                let handle = std::thread::spawn(move || -> AnyhowResult<()> {
+                    let mut router =
+                        FxHashMap::<String, BufWriter<File>>::default();
+                    for rows in rcv {
+                        let writer = match router.entry(rows.key_name) {
+                            Entry::Occupied(entry) => entry.into_mut(),
+                            Entry::Vacant(entry) => {
+                                let fp = rows.file_path;
+                                if let Some(parent) = fp.parent() {
+                                    std::fs::create_dir_all(parent)?;
+                                }
+                                let fh =
+                                    File::create(&fp).with_context(|| {
+                                        format!(
+                                            "failed to create partition \
+                                             output {fp:?}"
+                                        )
+                                    })?;
+                                entry.insert(BufWriter::new(fh))
+                            }
+                        };
+                        for (pos, feature_counts) in rows.counts {
+                            BedMethylWriter::write_feature_counts(
+                                position_interval(pos, one_based),
+                                &rows.chrom_name,
+                                &feature_counts,
+                                writer,
+                                tabs_and_spaces,
+                                &motif_labels,
+                                &colors,
+                                score,
+                            )?;
+                        }
+                    }
+                    for writer in router.values_mut() {
+                        writer.flush()?;
+                    }
+                    Ok(())
+                });
+                PartitionWorker { snd, handle }
+            })
+            .collect();
+    }
+
+    /// Wait for the workers to write everything they've been sent, returns
+    /// the first error from any of them.
+    fn join_workers(&mut self) -> AnyhowResult<()> {
+        let results = self
+            .workers
+            .drain(..)
+            .map(|PartitionWorker { snd, handle }| {
+                drop(snd);
+                handle
+                    .join()
+                    .map_err(|_| anyhow!("partition writer thread panicked"))
+                    .and_then(|result| result)
+            })
+            .collect::<Vec<AnyhowResult<()>>>();
+        results.into_iter().collect()
+    }
+}
+
+fn partition_filepath(
+    out_dir: &Path,
+    prefix: Option<&String>,
+    key_name: &str,
+) -> PathBuf {
+    let filename = if let Some(prefix) = prefix {
+        format!("{prefix}_{key_name}.bed")
+    } else {
+        format!("{key_name}.bed")
+    };
+    out_dir.join(filename)
+}
+
+/// Path of a partition written into nested `<tag>=<value>` directories,
+/// missing values are written as `missing` (as in the flat file names) and
+/// `/` in a value is escaped so that each tag is one directory level.
+fn nested_partition_filepath(
+    out_dir: &Path,
+    prefix: Option<&String>,
+    tags: &[String],
+    tag_values: &[Option<String>],
+) -> PathBuf {
+    let mut fp = out_dir.to_path_buf();
+    for (tag, value) in tags.iter().zip(tag_values.iter()) {
+        let value = value
+            .as_ref()
+            .map(|v| v.replace('/', "%2F"))
+            .unwrap_or("missing".to_string());
+        fp.push(format!("{tag}={value}"));
+    }
+    let filename = if let Some(prefix) = prefix {
+        format!("{prefix}_pileup.bed")
+    } else {
+        "pileup.bed".to_string()
+    };
+    fp.join(filename)
 }
 
 const NOT_FOUND: &str = "not_found";
 const UNGROUPED: &str = "ungrouped";
 
+/// Files written for each partition when partitioning by tag, along with
+/// the tag values and number of reads, written as JSON next to the outputs
+/// so that downstream tools don't need to parse the file names.
+pub(crate) struct PartitionManifest {
+    tags: Vec<String>,
+    partitions: BTreeMap<String, PartitionManifestEntry>,
+}
+
+#[derive(Default)]
+struct PartitionManifestEntry {
+    tag_values: Option<Vec<Option<String>>>,
+    files: BTreeSet<String>,
+    n_reads: usize,
+    n_rows: u64,
+}
+
+impl PartitionManifest {
+    pub(crate) fn new(tags: &[String]) -> Self {
+        Self { tags: tags.to_vec(), partitions: BTreeMap::new() }
+    }
+
+    fn key_name(item: &ModBasePileup, partition_key: &PartitionKey) -> String {
+        match partition_key {
+            PartitionKey::NoKey => UNGROUPED.to_string(),
+            PartitionKey::Key(idx) => item
+                .partition_keys
+                .get_index(*idx)
+                .cloned()
+                .unwrap_or(NOT_FOUND.to_string()),
+        }
+    }
+
+    fn add_reads(&mut self, item: &ModBasePileup) {
+        for (partition_key, partition_reads) in item.partition_reads.iter() {
+            let entry = self
+                .partitions
+                .entry(Self::key_name(item, partition_key))
+                .or_default();
+            if *partition_key != PartitionKey::NoKey {
+                entry
+                    .tag_values
+                    .get_or_insert_with(|| partition_reads.tag_values.clone());
+            }
+            entry.n_reads += partition_reads.n_reads;
+        }
+    }
+
+    /// Add rows written to `file`, recorded relative to `out_dir` so that
+    /// nested partitions can be found from the manifest.
+    fn add_rows(
+        &mut self,
+        key_name: &str,
+        out_dir: &Path,
+        file: &Path,
+        n_rows: u64,
+    ) {
+        let entry = self.partitions.entry(key_name.to_string()).or_default();
+        match file.strip_prefix(out_dir) {
+            Ok(relative) => {
+                entry.files.insert(relative.to_string_lossy().to_string());
+            }
+            Err(_) => {
+                if let Some(file_name) = file.file_name() {
+                    entry.files.insert(file_name.to_string_lossy().to_string());
+                }
+            }
+        }
+        entry.n_rows += n_rows;
+    }
+
+    fn add_row_count(&mut self, key_name: &str, n_rows: u64) {
+        self.partitions.entry(key_name.to_string()).or_default().n_rows +=
+            n_rows;
+    }
+
+    fn to_json(&self) -> String {
+        let partitions = self
+            .partitions
+            .iter()
+            .filter(|(_, entry)| !entry.files.is_empty())
+            .map(|(name, entry)| {
+                let tag_values = entry
+                    .tag_values
+                    .as_ref()
+                    .map(|values| {
+                        let fields = self
+                            .tags
+                            .iter()
+                            .zip(values.iter())
+                            .map(|(tag, value)| {
+                                (
+                                    tag.as_str(),
+                                    value
+                                        .as_ref()
+                                        .map(|v| json_string(v))
+                                        .unwrap_or("null".to_string()),
+                                )
+                            })
+                            .collect::<Vec<(&str, String)>>();
+                        json_object(&fields)
+                    })
+                    .unwrap_or("null".to_string());
+                let files = format!(
+                    "[{}]",
+                    entry.files.iter().map(|f| json_string(f)).join(",")
+                );
+                json_object(&[
+                    ("name", json_string(name)),
+                    ("tag_values", tag_values),
+                    ("files", files),
+                    ("n_reads", entry.n_reads.to_string()),
+                    ("n_rows", entry.n_rows.to_string()),
+                ])
+            })
+            .join(",");
+        let tags =
+            format!("[{}]", self.tags.iter().map(|t| json_string(t)).join(","));
+        json_object(&[
+            ("tags", tags),
+            ("partitions", format!("[{partitions}]")),
+        ])
+    }
+
+    /// Write the manifest to `<prefix>_manifest.json` (or `manifest.json`)
+    /// in `out_dir`.
+    fn write(
+        &self,
+        out_dir: &Path,
+        prefix: Option<&String>,
+    ) -> AnyhowResult<()> {
+        let file_name = match prefix {
+            Some(p) => format!("{p}_manifest.json"),
+            None => "manifest.json".to_string(),
+        };
+        let fp = out_dir.join(file_name);
+        let mut writer =
+            BufWriter::new(File::create(&fp).with_context(|| {
+                format!("failed to create partition manifest at {fp:?}")
+            })?);
+        writeln!(writer, "{}", self.to_json())?;
+        writer.flush()?;
+        info!("wrote partition manifest to {fp:?}");
+        Ok(())
+    }
+}
+
 impl PileupWriter<ModBasePileup> for PartitioningBedMethylWriter {
     fn write(
         &mut self,
-        item: ModBasePileup,
+        mut item: ModBasePileup,
         motif_labels: &[String],
     ) -> AnyhowResult<u64> {
-        let tabs_and_spaces = self.tabs_and_spaces;
+        if self.workers.is_empty() {
+            self.start_workers(motif_labels);
+        }
+        if let Some(manifest) = self.manifest.as_mut() {
+            manifest.add_reads(&item);
+        }
+        let chrom_name = Arc::<str>::from(item.chrom_name.as_str());
         let mut rows_written = 0u64;
-        for (&pos, partitioned_feature_counts) in item.iter_counts_sorted() {
-            for (&partition_key, pileup_feature_counts) in
-                partitioned_feature_counts
-            {
-                let key_name = match partition_key {
-                    PartitionKey::NoKey => UNGROUPED,
-                    PartitionKey::Key(idx) => item
-                        .partition_keys
+        for (partition_key, counts) in item
+            .take_counts_by_partition()
+            .into_iter()
+            .sorted_by_key(|(partition_key, _)| *partition_key)
+        {
+            let (key_name, tag_values) = match partition_key {
+                PartitionKey::NoKey => (UNGROUPED, None),
+                PartitionKey::Key(idx) => (
+                    item.partition_keys
                         .get_index(idx)
                         .map(|s| s.as_str())
                         .unwrap_or(NOT_FOUND),
-                };
+                    item.partition_tag_values.get(idx),
+                ),
+            };
+            let key_name = key_name.to_string();
+            // each feature count is one row
+            let n_rows = counts
+                .iter()
+                .map(|(_, feature_counts)| feature_counts.len() as u64)
+                .sum::<u64>();
+            rows_written += n_rows;
 
-                let writer = self.get_writer_for_key(key_name);
-                rows_written += BedMethylWriter::write_feature_counts(
-                    pos,
-                    &item.chrom_name,
-                    &pileup_feature_counts,
-                    writer,
-                    tabs_and_spaces,
-                    motif_labels,
-                )?;
+            let (worker_idx, file_path) = match self.assignments.get(&key_name)
+            {
+                Some((worker_idx, file_path)) => {
+                    (*worker_idx, file_path.clone())
+                }
+                None => {
+                    let next_worker =
+                        self.assignments.len() % self.workers.len();
+                    let file_path = Arc::<Path>::from(self.filepath_for_key(
+                        &key_name,
+                        tag_values.map(|v| v.as_slice()),
+                    ));
+                    self.assignments.insert(
+                        key_name.clone(),
+                        (next_worker, file_path.clone()),
+                    );
+                    (next_worker, file_path)
+                }
+            };
+            if let Some(manifest) = self.manifest.as_mut() {
+                manifest.add_rows(&key_name, &self.out_dir, &file_path, n_rows);
+            }
+            let rows = PartitionRows {
+                key_name,
+                file_path,
+                chrom_name: chrom_name.clone(),
+                counts,
+            };
+            if self.workers[worker_idx].snd.send(rows).is_err() {
+                // the worker stopped, its error says why
+                self.join_workers()?;
+                bail!("partition writer stopped unexpectedly")
             }
         }
 
         Ok(rows_written)
     }
+
+    fn finish(&mut self) -> AnyhowResult<()> {
+        self.join_workers()?;
+        if let Some(manifest) = self.manifest.as_ref() {
+            manifest.write(&self.out_dir, self.prefix.as_ref())?;
+        }
+        Ok(())
+    }
 }
@@ -53,6 +53,7 @@ fn run_summary<'a>(
             edge_filter,
             None,
             false,
+            false,
             true,
         )
     })
@@ -90,6 +91,7 @@ pub fn run_summary_with_include_positions<'a>(
             None,
             Some(&position_filter),
             true,
+            false,
             true,
         )
     })
@@ -243,3 +245,13 @@ pub fn check_legal_csv<const SEP: u8>(fp: &PathBuf) -> anyhow::Result<()> {
 
     Ok(())
 }
+
+/// Rows of a tab-separated file with a header line, split into fields.
+pub fn read_rows(fp: &Path) -> Vec<Vec<String>> {
+    std::fs::read_to_string(fp)
+        .unwrap()
+        .lines()
+        .skip(1)
+        .map(|l| l.split('\t').map(|x| x.to_string()).collect())
+        .collect()
+}
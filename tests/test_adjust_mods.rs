@@ -481,3 +481,67 @@ fn test_adust_mods_motifs() {
     .context(format!("failed to run adjust"))
     .unwrap();
 }
+
+#[test]
+fn test_adjust_mods_include_bed() {
+    let bam_fp = "tests/resources/bc_anchored_10_reads.sorted.bam";
+    let bed_fp = std::env::temp_dir().join("test_adjust_mods_include_bed.bed");
+    std::fs::write(&bed_fp, "oligo_1512_adapters\t0\t80\n").unwrap();
+    let outbam = std::env::temp_dir().join("test_adjust_mods_include_bed.bam");
+    run_modkit(&[
+        "adjust-mods",
+        bam_fp,
+        outbam.to_str().unwrap(),
+        "--ignore",
+        "h",
+        "--include-bed",
+        bed_fp.to_str().unwrap(),
+        "--ff",
+    ])
+    .context("failed to run adjust with include-bed")
+    .unwrap();
+    bam::index::build(&outbam, None, bam::index::Type::Bai, 1).unwrap();
+
+    let pileup = |in_bam: &str, name: &str| {
+        let out_fp = std::env::temp_dir().join(name);
+        run_modkit(&[
+            "pileup",
+            in_bam,
+            out_fp.to_str().unwrap(),
+            "--no-filtering",
+        ])
+        .unwrap();
+        std::fs::read_to_string(&out_fp)
+            .unwrap()
+            .lines()
+            .map(|l| l.split('\t').map(|x| x.to_string()).collect())
+            .collect::<Vec<Vec<String>>>()
+    };
+    let original = pileup(bam_fp, "test_adjust_mods_include_bed.orig.bed");
+    let adjusted = pileup(
+        outbam.to_str().unwrap(),
+        "test_adjust_mods_include_bed.adjusted.bed",
+    );
+    let in_region = |row: &Vec<String>| row[1].parse::<u64>().unwrap() < 80;
+
+    // calls in the region have 5hmC removed, the reads still have 5hmC calls
+    // outside of the region so the rows remain with zero counts
+    let in_region_hmc = |rows: &[Vec<String>]| {
+        rows.iter()
+            .filter(|row| in_region(row) && row[3] == "h")
+            .map(|row| row[11].parse::<u32>().unwrap())
+            .sum::<u32>()
+    };
+    assert!(adjusted.iter().any(|row| in_region(row)));
+    assert!(in_region_hmc(&original) > 0);
+    assert_eq!(in_region_hmc(&adjusted), 0);
+    // calls outside of the region are untouched
+    let outside = |rows: &[Vec<String>]| {
+        rows.iter()
+            .filter(|row| !in_region(row))
+            .cloned()
+            .collect::<Vec<Vec<String>>>()
+    };
+    assert!(!outside(&adjusted).is_empty());
+    assert_eq!(outside(&adjusted), outside(&original));
+}
@@ -13,6 +13,9 @@ fn test_merge_bedmethyl_help() {
     run_modkit(&["bedmethyl", "--help"]).unwrap();
     run_modkit(&["bedmethyl", "merge", "--help"]).unwrap();
     run_modkit(&["bedmethyl", "tobigwig", "--help"]).unwrap();
+    run_modkit(&["bedmethyl", "check", "--help"]).unwrap();
+    run_modkit(&["bedmethyl", "filter", "--help"]).unwrap();
+    run_modkit(&["bedmethyl", "liftover", "--help"]).unwrap();
 }
 
 #[test]
@@ -75,3 +78,306 @@ fn test_bedmethyl_merge() {
         assert_eq!(x.count_nocall * 2, y.count_nocall);
     }
 }
+
+#[test]
+fn test_bedmethyl_check() {
+    let bed_fp = "tests/resources/\
+                  lung_00733-m_adjacent-normal_5mc-5hmc_chr20_cpg_pileup.bed.\
+                  gz";
+    run_modkit(&[
+        "bedmethyl",
+        "check",
+        bed_fp,
+        "--ref",
+        "tests/resources/GRCh38_chr20.fa",
+        "--suppress-progress",
+    ])
+    .unwrap();
+
+    let mut buff = vec![];
+    let _ = rust_htslib::bgzf::Reader::from_path(bed_fp)
+        .unwrap()
+        .read_to_end(&mut buff);
+    let input_lines = buff.lines().map(|l| l.unwrap()).collect::<Vec<String>>();
+    let unsorted_fp =
+        std::env::temp_dir().join("test_check_bedmethyl_unsorted.bed");
+    {
+        let mut w = BufWriter::new(File::create(&unsorted_fp).unwrap());
+        for line in input_lines.iter().rev() {
+            w.write_all(line.as_bytes()).unwrap();
+            w.write_all(b"\n").unwrap();
+        }
+    }
+    assert!(run_modkit(&[
+        "bedmethyl",
+        "check",
+        unsorted_fp.to_str().unwrap(),
+        "--suppress-progress",
+    ])
+    .is_err());
+
+    let sorted_fp =
+        std::env::temp_dir().join("test_check_bedmethyl_sorted.bed.gz");
+    run_modkit(&[
+        "bedmethyl",
+        "check",
+        unsorted_fp.to_str().unwrap(),
+        "-o",
+        sorted_fp.to_str().unwrap(),
+        "--force",
+        "--suppress-progress",
+    ])
+    .unwrap();
+    assert!(sorted_fp.with_extension("gz.tbi").exists());
+    let mut buff = vec![];
+    let _ = rust_htslib::bgzf::Reader::from_path(&sorted_fp)
+        .unwrap()
+        .read_to_end(&mut buff);
+    let sorted_lines =
+        buff.lines().map(|l| l.unwrap()).collect::<Vec<String>>();
+    assert_eq!(input_lines, sorted_lines);
+}
+
+#[test]
+fn test_bedmethyl_filter() {
+    let bed_fp = "tests/resources/\
+                  lung_00733-m_adjacent-normal_5mc-5hmc_chr20_cpg_pileup.bed.\
+                  gz";
+    let regions = [(9_838_623u64, 9_839_213u64), (10_671_925, 10_674_963)];
+    let regions_fp =
+        std::env::temp_dir().join("test_bedmethyl_filter_regions.bed");
+    {
+        let mut w = BufWriter::new(File::create(&regions_fp).unwrap());
+        for (start, end) in regions {
+            writeln!(w, "chr20\t{start}\t{end}").unwrap();
+        }
+    }
+    let out_fp = std::env::temp_dir().join("test_bedmethyl_filter.bed.gz");
+    run_modkit(&[
+        "bedmethyl",
+        "filter",
+        bed_fp,
+        "--include-bed",
+        regions_fp.to_str().unwrap(),
+        "--mod-codes",
+        "C",
+        "--min-coverage",
+        "10",
+        "--min-percent",
+        "10",
+        "-o",
+        out_fp.to_str().unwrap(),
+        "--index",
+        "--force",
+        "--suppress-progress",
+    ])
+    .unwrap();
+    assert!(out_fp.with_extension("gz.tbi").exists());
+
+    let read_lines = |fp: &std::path::Path| {
+        let mut buff = vec![];
+        let _ = rust_htslib::bgzf::Reader::from_path(fp)
+            .unwrap()
+            .read_to_end(&mut buff);
+        buff.lines().map(|l| l.unwrap()).collect::<Vec<String>>()
+    };
+    let expected = read_lines(std::path::Path::new(bed_fp))
+        .into_iter()
+        .filter(|l| {
+            let record = BedMethylLine::parse(l).unwrap();
+            let pos = record.start();
+            record.raw_mod_code == 'C'.into()
+                && record.valid_coverage >= 10
+                && (record.count_methylated as f32
+                    / record.valid_coverage as f32)
+                    * 100f32
+                    >= 10f32
+                && regions.iter().any(|(s, e)| pos >= *s && pos < *e)
+        })
+        .collect::<Vec<String>>();
+    assert!(!expected.is_empty());
+    assert_eq!(read_lines(&out_fp), expected);
+
+    // an unsorted input can't be indexed
+    let unsorted_fp =
+        std::env::temp_dir().join("test_bedmethyl_filter_unsorted.bed");
+    std::fs::write(&unsorted_fp, format!("{}\n{}\n", expected[1], expected[0]))
+        .unwrap();
+    assert!(run_modkit(&[
+        "bedmethyl",
+        "filter",
+        unsorted_fp.to_str().unwrap(),
+        "-o",
+        out_fp.to_str().unwrap(),
+        "--index",
+        "--force",
+        "--suppress-progress",
+    ])
+    .is_err());
+}
+
+#[test]
+fn test_bedmethyl_liftover() {
+    let source_ref = "tests/resources/CGI_ladder_3.6kb_ref.fa";
+    let bedmethyl = "tests/resources/bc_anchored_10_reads_nofilt_cg_motif.bed";
+    let mut reader =
+        bio::io::fasta::IndexedReader::from_file(&source_ref).unwrap();
+    reader.fetch_all("oligo_1512_adapters").unwrap();
+    let mut seq = Vec::new();
+    reader.read(&mut seq).unwrap();
+    seq.make_ascii_uppercase();
+    assert_eq!(seq.len(), 156);
+
+    // the new assembly is the reverse complement of the contig with the C of
+    // the CpG at position 19 mutated
+    let mut target_seq = bio::alphabets::dna::revcomp(&seq);
+    assert_eq!(target_seq[136], b'G');
+    target_seq[136] = b'A';
+    let target_ref = std::env::temp_dir().join("test_liftover_target.fa");
+    {
+        let mut w = BufWriter::new(File::create(&target_ref).unwrap());
+        w.write_all(b">oligo_rc\n").unwrap();
+        w.write_all(&target_seq).unwrap();
+        w.write_all(b"\n").unwrap();
+        let mut fai =
+            File::create(target_ref.with_extension("fa.fai")).unwrap();
+        writeln!(fai, "oligo_rc\t156\t10\t156\t157").unwrap();
+    }
+    // only the first 140 bases are aligned
+    let chain_fp = std::env::temp_dir().join("test_liftover.chain");
+    std::fs::write(
+        &chain_fp,
+        "chain 1000 oligo_1512_adapters 156 + 0 140 oligo_rc 156 - 0 140 \
+         1\n140\n",
+    )
+    .unwrap();
+
+    let out_fp = std::env::temp_dir().join("test_liftover.bed.gz");
+    let unlifted_fp = std::env::temp_dir().join("test_liftover_unlifted.bed");
+    run_modkit(&[
+        "bedmethyl",
+        "liftover",
+        bedmethyl,
+        "--chain",
+        chain_fp.to_str().unwrap(),
+        "--from-ref",
+        source_ref,
+        "--to-ref",
+        target_ref.to_str().unwrap(),
+        "-o",
+        out_fp.to_str().unwrap(),
+        "--unlifted",
+        unlifted_fp.to_str().unwrap(),
+        "--force",
+        "--suppress-progress",
+    ])
+    .unwrap();
+    assert!(out_fp.with_extension("gz.tbi").exists());
+
+    let mut buff = vec![];
+    let _ = rust_htslib::bgzf::Reader::from_path(&out_fp)
+        .unwrap()
+        .read_to_end(&mut buff);
+    let lifted = buff
+        .lines()
+        .map(|l| BedMethylLine::parse(&l.unwrap()).unwrap())
+        .collect::<Vec<BedMethylLine>>();
+    assert_eq!(lifted.len(), 34);
+    assert!(lifted.windows(2).all(|w| w[0].start() <= w[1].start()));
+    assert!(lifted.iter().all(|r| r.chrom == "oligo_rc"));
+    // source position 9 on the positive strand
+    let last = lifted.last().unwrap();
+    assert_eq!(last.start(), 146);
+    assert_eq!(last.strand.to_string(), "-");
+
+    let reasons = std::fs::read_to_string(&unlifted_fp)
+        .unwrap()
+        .lines()
+        .map(|l| l.rsplit('\t').next().unwrap().to_string())
+        .collect::<Vec<String>>();
+    assert_eq!(
+        reasons,
+        vec!["context_changed", "context_changed", "unmapped", "unmapped"]
+    );
+}
+
+#[test]
+fn test_bedmethyl_matrix() {
+    let normal = "tests/resources/\
+                  lung_00733-m_adjacent-normal_5mc-5hmc_chr20_cpg_pileup.bed.\
+                  gz";
+    let tumour = "tests/resources/\
+                  lung_00733-m_primary-tumour_5mc-5hmc_chr20_cpg_pileup.bed.\
+                  gz";
+    use arrow_array::{Array, Float64Array, Int64Array};
+    use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+
+    let out_fp = std::env::temp_dir().join("test_bedmethyl_matrix.parquet");
+    run_modkit(&[
+        "bedmethyl",
+        "matrix",
+        normal,
+        tumour,
+        "--mod-codes",
+        "C",
+        "--sample-names",
+        "normal,tumour",
+        "--window-size",
+        "1000",
+        "-o",
+        out_fp.to_str().unwrap(),
+        "--force",
+        "--suppress-progress",
+    ])
+    .unwrap();
+    let reader =
+        ParquetRecordBatchReaderBuilder::try_new(File::open(&out_fp).unwrap())
+            .unwrap()
+            .build()
+            .unwrap();
+    let mut n_rows = 0usize;
+    let mut last_start = -1i64;
+    for batch in reader {
+        let batch = batch.unwrap();
+        let column = |name: &str| batch.column_by_name(name).unwrap().clone();
+        let starts = column("start");
+        let starts = starts.as_any().downcast_ref::<Int64Array>().unwrap();
+        let fractions = column("fraction_modified_tumour");
+        let fractions =
+            fractions.as_any().downcast_ref::<Float64Array>().unwrap();
+        let n_mods = column("n_mod_tumour");
+        let n_mods = n_mods.as_any().downcast_ref::<Int64Array>().unwrap();
+        let n_valids = column("n_valid_tumour");
+        let n_valids = n_valids.as_any().downcast_ref::<Int64Array>().unwrap();
+        for i in 0..batch.num_rows() {
+            // windows are sorted and aligned to the window size
+            assert!(starts.value(i) > last_start);
+            assert_eq!(starts.value(i) % 1000, 0);
+            last_start = starts.value(i);
+            if fractions.is_valid(i) {
+                let expected = n_mods.value(i) as f64 / n_valids.value(i) as f64;
+                assert_eq!(fractions.value(i), expected);
+            } else {
+                assert_eq!(n_valids.value(i), 0);
+            }
+        }
+        n_rows += batch.num_rows();
+    }
+    assert_eq!(n_rows, 1115);
+
+    // sample names must match the inputs
+    assert!(run_modkit(&[
+        "bedmethyl",
+        "matrix",
+        normal,
+        tumour,
+        "--mod-codes",
+        "C",
+        "--sample-names",
+        "normal",
+        "-o",
+        out_fp.to_str().unwrap(),
+        "--force",
+    ])
+    .is_err());
+}
@@ -323,3 +323,104 @@ fn test_call_mods_supplementary_secondary() {
     .unwrap();
     check(&out_bam);
 }
+
+#[test]
+fn test_call_mods_alignment_identity_filter() {
+    let out_bam = std::env::temp_dir()
+        .join("test_call_mods_alignment_identity_filter.bam");
+    run_modkit(&[
+        "call-mods",
+        "tests/resources/bc_anchored_10_reads.sorted.bam",
+        out_bam.to_str().unwrap(),
+        "--filter-threshold",
+        "0.8",
+        "--max-nm",
+        "0",
+    ])
+    .unwrap();
+
+    let n_input = bam::Reader::from_path(
+        "tests/resources/bc_anchored_10_reads.sorted.bam",
+    )
+    .unwrap()
+    .records()
+    .count();
+    let mut reader = bam::Reader::from_path(&out_bam).unwrap();
+    let mut n_output = 0usize;
+    for record in reader.records().map(|r| r.unwrap()) {
+        n_output += 1;
+        if !record.is_unmapped() {
+            assert!(record.aux(b"MM").is_err());
+            assert!(record.aux(b"ML").is_err());
+        }
+    }
+    assert_eq!(n_output, n_input);
+}
+
+#[test]
+fn test_extract_calls_out_bam_same_as_call_mods() {
+    let in_bam = "tests/resources/bc_anchored_10_reads.sorted.bam";
+    let call_mods_bam =
+        std::env::temp_dir().join("test_extract_calls_out_bam_call_mods.bam");
+    let extract_bam =
+        std::env::temp_dir().join("test_extract_calls_out_bam_extract.bam");
+    let extract_table =
+        std::env::temp_dir().join("test_extract_calls_out_bam_extract.tsv");
+    run_modkit(&[
+        "call-mods",
+        in_bam,
+        call_mods_bam.to_str().unwrap(),
+        "--filter-threshold",
+        "0.7",
+    ])
+    .unwrap();
+    run_modkit(&[
+        "extract",
+        "calls",
+        in_bam,
+        extract_table.to_str().unwrap(),
+        "--out-bam",
+        extract_bam.to_str().unwrap(),
+        "--filter-threshold",
+        "0.7",
+        "--force",
+    ])
+    .unwrap();
+    assert!(extract_table.exists());
+
+    let tags_by_read = |fp: &PathBuf| {
+        let mut reader = bam::Reader::from_path(fp).unwrap();
+        reader
+            .records()
+            .map(|r| r.unwrap())
+            .map(|rec| {
+                let name = String::from_utf8(rec.qname().to_vec()).unwrap();
+                let tags = ["MM", "ML"]
+                    .iter()
+                    .map(|tag| {
+                        format!("{:?}", rec.aux(tag.as_bytes()).unwrap())
+                    })
+                    .collect::<Vec<String>>();
+                (name, tags)
+            })
+            .collect::<HashMap<String, Vec<String>>>()
+    };
+    let expected = tags_by_read(&call_mods_bam);
+    let observed = tags_by_read(&extract_bam);
+    assert_eq!(observed.len(), 10);
+    assert_eq!(observed, expected);
+
+    // won't overwrite the BAM without --force
+    std::fs::remove_file(&extract_table).unwrap();
+    assert!(run_modkit(&[
+        "extract",
+        "calls",
+        in_bam,
+        extract_table.to_str().unwrap(),
+        "--out-bam",
+        extract_bam.to_str().unwrap(),
+        "--filter-threshold",
+        "0.7",
+    ])
+    .is_err());
+}
@@ -0,0 +1,78 @@
+use crate::common::{read_rows, run_modkit};
+
+mod common;
+
+#[test]
+fn test_comethyl_help() {
+    run_modkit(&["comethyl", "--help"]).expect("comethyl help");
+}
+
+#[test]
+fn test_comethyl_cpg() {
+    let out_fp = std::env::temp_dir().join("test_comethyl_cpg.tsv");
+    run_modkit(&[
+        "comethyl",
+        "tests/resources/bc_anchored_10_reads.sorted.bam",
+        "--ref",
+        "tests/resources/CGI_ladder_3.6kb_ref.fa",
+        "--cpg",
+        "--min-coverage",
+        "3",
+        "--max-distance",
+        "50",
+        "-o",
+        out_fp.to_str().unwrap(),
+        "--force",
+    ])
+    .expect("should run comethyl");
+    let rows = read_rows(&out_fp);
+    assert!(!rows.is_empty());
+    for row in rows {
+        assert_eq!(row.len(), 12);
+        assert_eq!(row[3], ".");
+        let pos_a = row[1].parse::<u64>().unwrap();
+        let pos_b = row[2].parse::<u64>().unwrap();
+        let distance = row[4].parse::<u64>().unwrap();
+        assert!(pos_a < pos_b);
+        assert_eq!(pos_b - pos_a, distance);
+        assert!(distance <= 50);
+        let n_reads = row[5].parse::<u32>().unwrap();
+        let table =
+            row[6..10].iter().map(|x| x.parse::<u32>().unwrap()).sum::<u32>();
+        assert!(n_reads >= 3);
+        assert_eq!(n_reads, table);
+        let mi = row[11].parse::<f64>().unwrap();
+        assert!(mi >= 0f64 && mi <= 1f64);
+    }
+}
+
+#[test]
+fn test_comethyl_interval_size_invariant() {
+    // pairs spanning interval boundaries should be counted exactly once
+    let run = |interval_size: &str, name: &str| {
+        let out_fp = std::env::temp_dir().join(name);
+        run_modkit(&[
+            "comethyl",
+            "tests/resources/HG002_small.ch20._other.sorted.bam",
+            "--ref",
+            "tests/resources/GRCh38_chr20.fa",
+            "--cpg",
+            "--no-filtering",
+            "--min-coverage",
+            "5",
+            "--region",
+            "chr20:80000-120000",
+            "--interval-size",
+            interval_size,
+            "-o",
+            out_fp.to_str().unwrap(),
+            "--force",
+        ])
+        .expect("should run comethyl");
+        read_rows(&out_fp)
+    };
+    let expected = run("100000", "test_comethyl_large_intervals.tsv");
+    let observed = run("777", "test_comethyl_small_intervals.tsv");
+    assert!(!expected.is_empty());
+    assert_eq!(observed, expected);
+}
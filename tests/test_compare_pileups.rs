@@ -0,0 +1,149 @@
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader};
+
+use mod_kit::dmr::bedmethyl::BedMethylLine;
+use rust_htslib::bgzf;
+
+use crate::common::run_modkit;
+
+mod common;
+
+const NORMAL: &str = "tests/resources/\
+                      lung_00733-m_adjacent-normal_5mc-5hmc_chr20_cpg_pileup.\
+                      bed.gz";
+const TUMOUR: &str = "tests/resources/\
+                      lung_00733-m_primary-tumour_5mc-5hmc_chr20_cpg_pileup.\
+                      bed.gz";
+
+fn read_rows(fp: &std::path::Path) -> Vec<HashMap<String, String>> {
+    let contents = std::fs::read_to_string(fp).unwrap();
+    let mut lines = contents.lines();
+    let header = lines.next().unwrap().split('\t').collect::<Vec<&str>>();
+    lines
+        .map(|l| {
+            header
+                .iter()
+                .zip(l.split('\t'))
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect()
+        })
+        .collect()
+}
+
+fn load_fractions(fp: &str, min_coverage: u64) -> HashMap<(u64, char), f64> {
+    BufReader::new(bgzf::Reader::from_path(fp).unwrap())
+        .lines()
+        .map(|l| BedMethylLine::parse(&l.unwrap()).unwrap())
+        .filter(|record| record.valid_coverage >= min_coverage)
+        .map(|record| {
+            let strand = record.strand.to_string().chars().next().unwrap();
+            let frac =
+                record.count_methylated as f64 / record.valid_coverage as f64;
+            ((record.start(), strand), frac)
+        })
+        .collect()
+}
+
+#[test]
+fn test_compare_pileups_help() {
+    let _ = run_modkit(&["compare-pileups", "--help"])
+        .expect("failed to run modkit compare-pileups help");
+}
+
+#[test]
+fn test_compare_pileups_same_bedmethyl() {
+    let out_fp = std::env::temp_dir().join("test_compare_pileups_same.tsv");
+    run_modkit(&[
+        "compare-pileups",
+        NORMAL,
+        NORMAL,
+        "--min-coverage",
+        "5",
+        "-o",
+        out_fp.to_str().unwrap(),
+        "-f",
+    ])
+    .unwrap();
+    let rows = read_rows(&out_fp);
+    assert_eq!(rows.len(), 2);
+    assert_eq!(rows[1]["mod_code"], "all");
+    let n_sites = load_fractions(NORMAL, 5).len().to_string();
+    for row in rows {
+        assert_eq!(row["n_sites_a"], n_sites);
+        assert_eq!(row["n_shared"], n_sites);
+        assert_eq!(row["n_only_a"], "0");
+        assert_eq!(row["n_only_b"], "0");
+        assert_eq!(row["pearson_r"], "1.000000");
+        assert_eq!(row["rmsd"], "0.000000");
+        assert_eq!(row["loa_upper"], "0.000000");
+    }
+}
+
+#[test]
+fn test_compare_pileups_deltas() {
+    let out_fp = std::env::temp_dir().join("test_compare_pileups_deltas.tsv");
+    let sites_fp =
+        std::env::temp_dir().join("test_compare_pileups_deltas_sites.tsv");
+    let bland_altman_fp =
+        std::env::temp_dir().join("test_compare_pileups_deltas_ba.tsv");
+    run_modkit(&[
+        "compare-pileups",
+        NORMAL,
+        TUMOUR,
+        "--min-coverage",
+        "3",
+        "--sites",
+        sites_fp.to_str().unwrap(),
+        "--bland-altman",
+        bland_altman_fp.to_str().unwrap(),
+        "--bins",
+        "4",
+        "-o",
+        out_fp.to_str().unwrap(),
+        "-f",
+    ])
+    .unwrap();
+
+    let normal = load_fractions(NORMAL, 3);
+    let tumour = load_fractions(TUMOUR, 3);
+    let deltas = normal
+        .iter()
+        .filter_map(|(site, a)| tumour.get(site).map(|b| b - a))
+        .collect::<Vec<f64>>();
+    let n_shared = deltas.len();
+    let mean_delta = deltas.iter().sum::<f64>() / n_shared as f64;
+
+    let summary = read_rows(&out_fp);
+    let all = summary.last().unwrap();
+    assert_eq!(all["mod_code"], "all");
+    assert_eq!(all["n_shared"], n_shared.to_string());
+    assert_eq!(all["n_only_a"], (normal.len() - n_shared).to_string());
+    assert_eq!(all["n_only_b"], (tumour.len() - n_shared).to_string());
+    let observed_mean = all["mean_delta"].parse::<f64>().unwrap();
+    assert!((observed_mean - mean_delta).abs() < 1e-5);
+    let pearson_r = all["pearson_r"].parse::<f64>().unwrap();
+    assert!(pearson_r > 0f64 && pearson_r < 1f64);
+    let lower = all["loa_lower"].parse::<f64>().unwrap();
+    let upper = all["loa_upper"].parse::<f64>().unwrap();
+    assert!(lower < observed_mean && observed_mean < upper);
+
+    let sites = read_rows(&sites_fp);
+    assert_eq!(sites.len(), n_shared);
+    let site_mean = sites
+        .iter()
+        .map(|row| row["delta"].parse::<f64>().unwrap())
+        .sum::<f64>()
+        / n_shared as f64;
+    assert!((site_mean - mean_delta).abs() < 1e-5);
+
+    let bins = read_rows(&bland_altman_fp);
+    assert!(bins.len() <= 4);
+    assert_eq!(
+        bins.iter()
+            .map(|row| row["n_sites"].parse::<usize>().unwrap())
+            .sum::<usize>(),
+        n_shared
+    );
+    assert_eq!(bins[0]["bin_start"], "0.000");
+    assert_eq!(bins[0]["bin_end"], "0.250");
+}
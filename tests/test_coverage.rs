@@ -0,0 +1,94 @@
+use std::io::{BufRead, BufReader};
+
+use mod_kit::dmr::bedmethyl::BedMethylLine;
+use rust_htslib::bgzf;
+
+use crate::common::{read_rows, run_modkit};
+
+mod common;
+
+const BEDMETHYL: &str =
+    "tests/resources/lung_00733-m_adjacent-normal_5mc-5hmc_chr20_cpg_pileup.\
+     bed.gz";
+
+#[test]
+fn test_coverage_help() {
+    let _ = run_modkit(&["coverage", "--help"])
+        .expect("failed to run modkit coverage help");
+}
+
+#[test]
+fn test_coverage_bedmethyl() {
+    let out_fp = std::env::temp_dir().join("test_coverage_bedmethyl.tsv");
+    let lorenz_fp = std::env::temp_dir().join("test_coverage_lorenz.tsv");
+    run_modkit(&[
+        "coverage",
+        BEDMETHYL,
+        "--thresholds",
+        "10,1",
+        "--lorenz",
+        lorenz_fp.to_str().unwrap(),
+        "--lorenz-points",
+        "10",
+        "-o",
+        out_fp.to_str().unwrap(),
+        "-f",
+    ])
+    .unwrap();
+    let rows = read_rows(&out_fp);
+
+    let reader = BufReader::new(bgzf::Reader::from_path(BEDMETHYL).unwrap());
+    let coverages = reader
+        .lines()
+        .map(|l| BedMethylLine::parse(&l.unwrap()).unwrap().valid_coverage)
+        .collect::<Vec<u64>>();
+    let n_sites = coverages.len();
+    let n_ge_10 = coverages.iter().filter(|c| **c >= 10).count();
+    let mean = coverages.iter().sum::<u64>() as f64 / n_sites as f64;
+
+    assert_eq!(rows.len(), 2);
+    assert_eq!(rows[0][0], "chr20");
+    assert_eq!(rows[1][0], "genome");
+    assert_eq!(rows[0][1..], rows[1][1..]);
+    let row = &rows[1];
+    assert_eq!(row.len(), 10);
+    assert_eq!(row[1].parse::<usize>().unwrap(), n_sites);
+    assert_eq!(row[2].parse::<usize>().unwrap(), n_sites);
+    assert!((row[3].parse::<f64>().unwrap() - mean).abs() < 1e-3);
+    let gini = row[7].parse::<f64>().unwrap();
+    assert!(gini > 0f64 && gini < 1f64);
+    assert_eq!(row[8], "1.0000");
+    let frac_ge_10 = row[9].parse::<f64>().unwrap();
+    assert!((frac_ge_10 - n_ge_10 as f64 / n_sites as f64).abs() < 1e-4);
+
+    let lorenz = read_rows(&lorenz_fp);
+    assert_eq!(lorenz.len(), 22);
+    assert_eq!(lorenz[10][1..], ["1.0000".to_string(), "1.0000".to_string()]);
+}
+
+#[test]
+fn test_coverage_motif_sites() {
+    let out_fp = std::env::temp_dir().join("test_coverage_motif_sites.tsv");
+    run_modkit(&[
+        "coverage",
+        "tests/resources/bc_anchored_10_reads_nofilt_cg_motif.bed",
+        "--ref",
+        "tests/resources/CGI_ladder_3.6kb_ref.fa",
+        "--cpg",
+        "-o",
+        out_fp.to_str().unwrap(),
+        "-f",
+    ])
+    .unwrap();
+    let rows = read_rows(&out_fp);
+    // every contig in the reference is reported, contigs without records
+    // have all of their CpGs (on both strands) uncovered
+    assert_eq!(rows.len(), 35);
+    let lambda = rows.iter().find(|r| r[0] == "lambda_3-6kb").unwrap();
+    assert_eq!(lambda[1..3], ["366".to_string(), "0".to_string()]);
+    let oligo = rows.iter().find(|r| r[0] == "oligo_1512_adapters").unwrap();
+    assert_eq!(oligo[1..3], ["22".to_string(), "19".to_string()]);
+    let genome = rows.last().unwrap();
+    assert_eq!(genome[0], "genome");
+    assert_eq!(genome[1..3], ["1206".to_string(), "19".to_string()]);
+}
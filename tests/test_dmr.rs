@@ -10,6 +10,8 @@ fn test_dmr_helps() {
         .expect("failed to run modkit dmr pair help");
     let _ = run_modkit(&["dmr", "multi", "--help"])
         .expect("failed to run modkit dmr multi help");
+    let _ = run_modkit(&["dmr", "trend", "--help"])
+        .expect("failed to run modkit dmr trend help");
 }
 
 #[test]
@@ -75,6 +77,706 @@ fn test_dmr_regression() {
     );
 }
 
+#[test]
+fn test_dmr_single_site_bigwig() {
+    let out_bed = std::env::temp_dir().join("test_dmr_single_site_bigwig.bed");
+    let out_bw = std::env::temp_dir().join("test_dmr_single_site_bigwig.bw");
+    let _ = run_modkit(&[
+        "dmr",
+        "pair",
+        "-a",
+        "tests/resources/\
+         lung_00733-m_adjacent-normal_5mc-5hmc_chr20_cpg_pileup.bed.gz",
+        "-b",
+        "tests/resources/\
+         lung_00733-m_primary-tumour_5mc-5hmc_chr20_cpg_pileup.bed.gz",
+        "-o",
+        out_bed.to_str().unwrap(),
+        "--ref",
+        "tests/resources/GRCh38_chr20.fa",
+        "--base",
+        "C",
+        "--max-coverages",
+        "50",
+        "50",
+        "--bigwig",
+        out_bw.to_str().unwrap(),
+        "--bigwig-value",
+        "neg-log10-pval",
+        "-f",
+    ])
+    .expect("failed to run modkit dmr");
+    check_legal_csv::<{ '\t' as u8 }>(&out_bed);
+    assert!(out_bw.metadata().unwrap().len() > 0);
+
+    // bigWig output is only available with single-site analysis
+    let err = run_modkit(&[
+        "dmr",
+        "pair",
+        "-a",
+        "tests/resources/\
+         lung_00733-m_adjacent-normal_5mc-5hmc_chr20_cpg_pileup.bed.gz",
+        "-b",
+        "tests/resources/\
+         lung_00733-m_primary-tumour_5mc-5hmc_chr20_cpg_pileup.bed.gz",
+        "-r",
+        "tests/resources/cpg_chr20_with_orig_names_selection.bed",
+        "--ref",
+        "tests/resources/GRCh38_chr20.fa",
+        "--bigwig",
+        out_bw.to_str().unwrap(),
+    ]);
+    assert!(err.is_err());
+}
+
+#[test]
+fn test_dmr_single_site_combine_strands() {
+    let out_bed =
+        std::env::temp_dir().join("test_dmr_single_site_combine_strands.bed");
+    let _ = run_modkit(&[
+        "dmr",
+        "pair",
+        "-a",
+        "tests/resources/\
+         lung_00733-m_adjacent-normal_5mc-5hmc_chr20_cpg_pileup.bed.gz",
+        "-b",
+        "tests/resources/\
+         lung_00733-m_primary-tumour_5mc-5hmc_chr20_cpg_pileup.bed.gz",
+        "-o",
+        out_bed.to_str().unwrap(),
+        "--ref",
+        "tests/resources/GRCh38_chr20.fa",
+        "--base",
+        "C",
+        "--combine-strands",
+        "-f",
+    ])
+    .expect("failed to run modkit dmr with --combine-strands");
+    check_legal_csv::<{ '\t' as u8 }>(&out_bed);
+    let strands = std::fs::read_to_string(&out_bed)
+        .unwrap()
+        .lines()
+        .map(|l| l.split('\t').nth(5).unwrap().to_string())
+        .collect::<Vec<String>>();
+    assert!(!strands.is_empty());
+    // all of the negative strand records are combined into the positive
+    // strand cytosine of the CpG
+    assert!(strands.iter().all(|s| s == "+"));
+}
+
 // todo
 //  test pair with explicit index
 //  test multi
+
+#[test]
+fn test_dmr_trend_group_order() {
+    let run_trend = |name: &str, group_order: &str| {
+        let out_bed = std::env::temp_dir().join(name);
+        run_modkit(&[
+            "dmr",
+            "trend",
+            "-s",
+            "tests/resources/\
+             lung_00733-m_adjacent-normal_5mc-5hmc_chr20_cpg_pileup.bed.gz",
+            "normal",
+            "-s",
+            "tests/resources/\
+             lung_00733-m_primary-tumour_5mc-5hmc_chr20_cpg_pileup.bed.gz",
+            "tumour",
+            "--group-order",
+            group_order,
+            "-r",
+            "tests/resources/cpg_chr20_with_orig_names_selection.bed",
+            "--ref",
+            "tests/resources/GRCh38_chr20.fa",
+            "--base",
+            "C",
+            "-o",
+            out_bed.to_str().unwrap(),
+            "-f",
+        ])
+        .expect("failed to run modkit dmr trend");
+        check_legal_csv::<{ '\t' as u8 }>(&out_bed);
+        std::fs::read_to_string(&out_bed)
+            .unwrap()
+            .lines()
+            .map(|l| l.split('\t').map(|x| x.to_string()).collect())
+            .collect::<Vec<Vec<String>>>()
+    };
+    let forward = run_trend("test_dmr_trend_forward.bed", "normal,tumour");
+    let reverse = run_trend("test_dmr_trend_reverse.bed", "tumour,normal");
+    // same regions as the pairwise comparison
+    let expected =
+        std::fs::read_to_string("tests/resources/test_output_chr20-2.bed")
+            .unwrap();
+    let expected = expected.lines().skip(1).collect::<Vec<&str>>();
+    assert_eq!(forward.len(), expected.len());
+    assert_eq!(reverse.len(), expected.len());
+    for ((fw, rv), pair) in forward.iter().zip(reverse.iter()).zip(expected) {
+        let pair = pair.split('\t').collect::<Vec<&str>>();
+        assert_eq!(fw[..4], rv[..4]);
+        assert_eq!(fw[1], pair[1]);
+        let (a_total, b_total) = (pair[7], pair[9]);
+        assert_eq!(fw[7], format!("{a_total},{b_total}"));
+        assert_eq!(rv[7], format!("{b_total},{a_total}"));
+        // reversing the order of the groups flips the direction of the trend
+        let fw_z = fw[10].parse::<f64>().unwrap();
+        let rv_z = rv[10].parse::<f64>().unwrap();
+        assert!((fw_z + rv_z).abs() < 1e-9);
+        let fw_p = fw[11].parse::<f64>().unwrap();
+        let rv_p = rv[11].parse::<f64>().unwrap();
+        assert!((fw_p - rv_p).abs() <= fw_p * 1e-9);
+        // effect size in the pairwise output is a - b
+        let effect_size = pair[14].parse::<f64>().unwrap();
+        assert_eq!(fw_z > 0f64, effect_size < 0f64);
+    }
+}
+
+#[test]
+fn test_dmr_regions_raw_counts() {
+    let out_bed = std::env::temp_dir().join("test_dmr_regions_raw_counts.bed");
+    let raw_counts =
+        std::env::temp_dir().join("test_dmr_regions_raw_counts.tsv");
+    let a_sample = "tests/resources/\
+                    lung_00733-m_adjacent-normal_5mc-5hmc_chr20_cpg_pileup.\
+                    bed.gz";
+    let b_sample = "tests/resources/\
+                    lung_00733-m_primary-tumour_5mc-5hmc_chr20_cpg_pileup.bed.\
+                    gz";
+    let _ = run_modkit(&[
+        "dmr",
+        "pair",
+        "-a",
+        a_sample,
+        "-a",
+        a_sample,
+        "-b",
+        b_sample,
+        "-o",
+        out_bed.to_str().unwrap(),
+        "-r",
+        "tests/resources/cpg_chr20_with_orig_names_selection.bed",
+        "--ref",
+        "tests/resources/GRCh38_chr20.fa",
+        "--raw-counts",
+        raw_counts.to_str().unwrap(),
+        "-f",
+        "--base",
+        "C",
+    ])
+    .expect("failed to run modkit dmr");
+    check_legal_csv::<{ '\t' as u8 }>(&raw_counts);
+
+    // (chrom, start, end, condition) -> (n_samples, n_modified, n_valid)
+    let mut raw_totals =
+        std::collections::HashMap::<(String, String, String, String), _>::new();
+    let raw = std::fs::read_to_string(&raw_counts).unwrap();
+    let mut lines = raw.lines();
+    assert!(lines.next().unwrap().starts_with("#chrom\tstart\tend"));
+    for line in lines {
+        let parts = line.split('\t').collect::<Vec<&str>>();
+        assert_eq!(parts.len(), 10);
+        let sample = if parts[5] == "a" { a_sample } else { b_sample };
+        assert_eq!(parts[6], sample);
+        let entry = raw_totals
+            .entry((
+                parts[0].to_string(),
+                parts[1].to_string(),
+                parts[2].to_string(),
+                parts[5].to_string(),
+            ))
+            .or_insert((0usize, 0usize, 0usize));
+        entry.0 += 1;
+        entry.1 += parts[8].parse::<usize>().unwrap();
+        entry.2 += parts[9].parse::<usize>().unwrap();
+    }
+
+    // the per-sample counts add up to the counts that were scored
+    let scored = std::fs::read_to_string(&out_bed).unwrap();
+    assert!(!scored.is_empty());
+    for line in scored.lines() {
+        let parts = line.split('\t').collect::<Vec<&str>>();
+        let key = |condition: &str| {
+            (
+                parts[0].to_string(),
+                parts[1].to_string(),
+                parts[2].to_string(),
+                condition.to_string(),
+            )
+        };
+        let (n_a, _, valid_a) = raw_totals.get(&key("a")).unwrap();
+        let (n_b, _, valid_b) = raw_totals.get(&key("b")).unwrap();
+        assert_eq!(*n_a, 2);
+        assert_eq!(*n_b, 1);
+        assert_eq!(valid_a.to_string(), parts[7]);
+        assert_eq!(valid_b.to_string(), parts[9]);
+    }
+}
+
+#[test]
+fn test_dmr_single_site_raw_counts() {
+    let out_bed =
+        std::env::temp_dir().join("test_dmr_single_site_raw_counts.bed");
+    let raw_counts =
+        std::env::temp_dir().join("test_dmr_single_site_raw_counts.tsv");
+    let a_sample = "tests/resources/\
+                    lung_00733-m_adjacent-normal_5mc-5hmc_chr20_cpg_pileup.\
+                    bed.gz";
+    let b_sample = "tests/resources/\
+                    lung_00733-m_primary-tumour_5mc-5hmc_chr20_cpg_pileup.bed.\
+                    gz";
+    let _ = run_modkit(&[
+        "dmr",
+        "pair",
+        "-a",
+        a_sample,
+        "-a",
+        a_sample,
+        "-b",
+        b_sample,
+        "-o",
+        out_bed.to_str().unwrap(),
+        "--ref",
+        "tests/resources/GRCh38_chr20.fa",
+        "--max-coverages",
+        "50",
+        "50",
+        "--raw-counts",
+        raw_counts.to_str().unwrap(),
+        "-f",
+        "--base",
+        "C",
+    ])
+    .expect("failed to run modkit dmr");
+    check_legal_csv::<{ '\t' as u8 }>(&raw_counts).unwrap();
+
+    // (chrom, start, end, strand, condition) -> (n_samples, n_valid)
+    let mut raw_totals = std::collections::HashMap::<
+        (String, String, String, String, String),
+        _,
+    >::new();
+    let raw = std::fs::read_to_string(&raw_counts).unwrap();
+    let mut lines = raw.lines();
+    assert!(lines.next().unwrap().starts_with("#chrom\tstart\tend"));
+    for line in lines {
+        let parts = line.split('\t').collect::<Vec<&str>>();
+        assert_eq!(parts.len(), 10);
+        assert_eq!(parts[3], ".");
+        let sample = if parts[5] == "a" { a_sample } else { b_sample };
+        assert_eq!(parts[6], sample);
+        let entry = raw_totals
+            .entry((
+                parts[0].to_string(),
+                parts[1].to_string(),
+                parts[2].to_string(),
+                parts[4].to_string(),
+                parts[5].to_string(),
+            ))
+            .or_insert((0usize, 0usize));
+        entry.0 += 1;
+        entry.1 += parts[9].parse::<usize>().unwrap();
+    }
+
+    // one row per sample for each scored site, adding up to the counts that
+    // were scored
+    let scored = std::fs::read_to_string(&out_bed).unwrap();
+    assert!(!scored.is_empty());
+    for line in scored.lines() {
+        let parts = line.split('\t').collect::<Vec<&str>>();
+        let key = |condition: &str| {
+            (
+                parts[0].to_string(),
+                parts[1].to_string(),
+                parts[2].to_string(),
+                parts[5].to_string(),
+                condition.to_string(),
+            )
+        };
+        let (n_a, valid_a) = raw_totals.get(&key("a")).unwrap();
+        let (n_b, valid_b) = raw_totals.get(&key("b")).unwrap();
+        assert_eq!(*n_a, 2);
+        assert_eq!(*n_b, 1);
+        assert_eq!(valid_a.to_string(), parts[7]);
+        assert_eq!(valid_b.to_string(), parts[9]);
+    }
+}
+
+#[test]
+fn test_dmr_regions_run_summary() {
+    let out_bed = std::env::temp_dir().join("test_dmr_regions_run_summary.bed");
+    let summary_fp =
+        std::env::temp_dir().join("test_dmr_regions_run_summary.json");
+    run_modkit(&[
+        "dmr",
+        "pair",
+        "-a",
+        "tests/resources/\
+         lung_00733-m_adjacent-normal_5mc-5hmc_chr20_cpg_pileup.bed.gz",
+        "-b",
+        "tests/resources/\
+         lung_00733-m_primary-tumour_5mc-5hmc_chr20_cpg_pileup.bed.gz",
+        "-o",
+        out_bed.to_str().unwrap(),
+        "-r",
+        "tests/resources/cpg_chr20_with_orig_names_selection.bed",
+        "--ref",
+        "tests/resources/GRCh38_chr20.fa",
+        "--summary",
+        summary_fp.to_str().unwrap(),
+        "-f",
+        "--base",
+        "C",
+    ])
+    .expect("failed to run modkit dmr");
+
+    let n_regions = std::fs::read_to_string(&out_bed).unwrap().lines().count();
+    assert!(n_regions > 0);
+    let summary = std::fs::read_to_string(&summary_fp).unwrap();
+    assert!(summary.starts_with(r#"{"scored":"regions""#), "{summary}");
+    assert!(summary.contains(&format!("\"n\":{n_regions},")), "{summary}");
+    for key in ["significant_at_p", "\"0.05\":", "lambda", "median_p_value"] {
+        assert!(summary.contains(key), "{key} missing from {summary}");
+    }
+    assert!(summary.contains("\"effect_size\":{\"mean\":"), "{summary}");
+}
+
+#[test]
+fn test_dmr_keep_region_columns() {
+    let out_bed = std::env::temp_dir().join("test_dmr_keep_region_columns.bed");
+    run_modkit(&[
+        "dmr",
+        "pair",
+        "-a",
+        "tests/resources/\
+         lung_00733-m_adjacent-normal_5mc-5hmc_chr20_cpg_pileup.bed.gz",
+        "-b",
+        "tests/resources/\
+         lung_00733-m_primary-tumour_5mc-5hmc_chr20_cpg_pileup.bed.gz",
+        "-o",
+        out_bed.to_str().unwrap(),
+        "-r",
+        "tests/resources/sim_cpg_regions_extra_columns.bed",
+        "--ref",
+        "tests/resources/GRCh38_chr20.fa",
+        "--keep-region-columns",
+        "--header",
+        "-f",
+        "--base",
+        "C",
+    ])
+    .expect("failed to run modkit dmr");
+
+    let out = std::fs::read_to_string(&out_bed).unwrap();
+    let mut lines = out.lines();
+    let header = lines.next().unwrap();
+    assert!(header.ends_with("\tcohen_h_high\tgene_id\tcategory"), "{header}");
+    let n_header_fields = header.split('\t').count();
+    let mut n_regions = 0;
+    for line in lines {
+        let parts = line.split('\t').collect::<Vec<&str>>();
+        assert_eq!(parts.len(), n_header_fields, "{line}");
+        let extra = match parts[3] {
+            "r1" => ["ENSG01", "promoter"],
+            "r2" => ["ENSG02", "."],
+            "r3" => ["ENSG03", "enhancer"],
+            name => panic!("unexpected region {name}"),
+        };
+        assert_eq!(parts[n_header_fields - 2..], extra);
+        n_regions += 1;
+    }
+    assert!(n_regions > 0);
+}
+
+/// Write `lines` bgzip-compressed to `fp` and index it with tabix using the
+/// sequence, begin, and end columns (1-based), `zero_based` is like `tabix -0`.
+fn write_tabixed(
+    fp: &std::path::Path,
+    lines: &[String],
+    sc: i32,
+    bc: i32,
+    ec: i32,
+    zero_based: bool,
+    line_skip: i32,
+) {
+    use std::io::Write;
+    let mut writer = rust_htslib::bgzf::Writer::from_path(fp).unwrap();
+    for line in lines {
+        writeln!(writer, "{line}").unwrap();
+    }
+    drop(writer);
+    let conf = rust_htslib::htslib::tbx_conf_t {
+        preset: if zero_based { 0x10000 } else { 0 },
+        sc,
+        bc,
+        ec,
+        meta_char: '#' as i32,
+        line_skip,
+    };
+    let c_fp = std::ffi::CString::new(fp.to_str().unwrap()).unwrap();
+    let ret = unsafe {
+        rust_htslib::htslib::tbx_index_build(c_fp.as_ptr(), 0, &conf)
+    };
+    assert_eq!(ret, 0, "failed to index {fp:?}");
+}
+
+#[test]
+fn test_dmr_bisulfite_input_formats() {
+    use std::io::BufRead;
+    let samples = [
+        ("normal", "lung_00733-m_adjacent-normal_5mc-5hmc_chr20_cpg_pileup"),
+        ("tumor", "lung_00733-m_primary-tumour_5mc-5hmc_chr20_cpg_pileup"),
+    ];
+    let dir = std::env::temp_dir().join("test_dmr_bisulfite_input_formats");
+    std::fs::create_dir_all(&dir).unwrap();
+    // make 5mC-only bedMethyl, Bismark cytosine report, and methylKit
+    // versions of the same counts
+    for (name, resource) in samples {
+        let fp = format!("tests/resources/{resource}.bed.gz");
+        let reader = std::io::BufReader::new(
+            rust_htslib::bgzf::Reader::from_path(&fp).unwrap(),
+        );
+        let (mut bedmethyl, mut bismark, mut methylkit) = (
+            Vec::new(),
+            Vec::new(),
+            vec!["chrBase\tchr\tbase\tstrand\tcoverage\tfreqC\tfreqT"
+                .to_string()],
+        );
+        for line in reader.lines().map(|l| l.unwrap()) {
+            let fields = line.split_whitespace().collect::<Vec<&str>>();
+            let (chrom, start, strand) = (fields[0], fields[1], fields[5]);
+            let pos = start.parse::<u64>().unwrap() + 1;
+            let valid_coverage = fields[9].parse::<u64>().unwrap();
+            let n_mod = fields[11].parse::<u64>().unwrap();
+            let n_canonical = valid_coverage - n_mod;
+            let mut bm_fields = fields.clone();
+            bm_fields[3] = "m";
+            let n_canonical_str = n_canonical.to_string();
+            bm_fields[12] = &n_canonical_str;
+            bm_fields[13] = "0";
+            bedmethyl.push(bm_fields.join("\t"));
+            bismark.push(format!(
+                "{chrom}\t{pos}\t{strand}\t{n_mod}\t{n_canonical}\tCG\tCGN"
+            ));
+            let freq_c = n_mod as f64 / valid_coverage as f64 * 100f64;
+            methylkit.push(format!(
+                "{chrom}.{pos}\t{chrom}\t{pos}\t{}\t{valid_coverage}\t{freq_c:\
+                 .2}\t{:.2}",
+                if strand == "+" { "F" } else { "R" },
+                100f64 - freq_c
+            ));
+        }
+        write_tabixed(
+            &dir.join(format!("{name}.bed.gz")),
+            &bedmethyl,
+            1,
+            2,
+            3,
+            true,
+            0,
+        );
+        write_tabixed(
+            &dir.join(format!("{name}.CpG_report.txt.gz")),
+            &bismark,
+            1,
+            2,
+            2,
+            false,
+            0,
+        );
+        write_tabixed(
+            &dir.join(format!("{name}.methylkit.txt.gz")),
+            &methylkit,
+            2,
+            3,
+            3,
+            false,
+            1,
+        );
+    }
+
+    let run_dmr = |suffix: &str, extra_args: &[&str]| -> String {
+        let out_bed = dir.join(format!("dmr_{}.bed", suffix.replace('.', "_")));
+        let a = dir.join(format!("normal.{suffix}"));
+        let b = dir.join(format!("tumor.{suffix}"));
+        let mut args = vec![
+            "dmr",
+            "pair",
+            "-a",
+            a.to_str().unwrap(),
+            "-b",
+            b.to_str().unwrap(),
+            "-o",
+            out_bed.to_str().unwrap(),
+            "--ref",
+            "tests/resources/GRCh38_chr20.fa",
+            "--base",
+            "C",
+            "-f",
+        ];
+        args.extend_from_slice(extra_args);
+        run_modkit(&args).unwrap();
+        std::fs::read_to_string(&out_bed).unwrap()
+    };
+
+    let expected = run_dmr("bed.gz", &[]);
+    assert!(!expected.is_empty());
+    assert_eq!(run_dmr("CpG_report.txt.gz", &[]), expected);
+    assert_eq!(
+        run_dmr("CpG_report.txt.gz", &["--input-format", "bismark"]),
+        expected
+    );
+    assert_eq!(run_dmr("methylkit.txt.gz", &[]), expected);
+    // the wrong format fails to parse
+    let out_bed = dir.join("dmr_wrong_format.bed");
+    assert!(run_modkit(&[
+        "dmr",
+        "pair",
+        "-a",
+        dir.join("normal.methylkit.txt.gz").to_str().unwrap(),
+        "-b",
+        dir.join("tumor.methylkit.txt.gz").to_str().unwrap(),
+        "-o",
+        out_bed.to_str().unwrap(),
+        "--ref",
+        "tests/resources/GRCh38_chr20.fa",
+        "--base",
+        "C",
+        "--input-format",
+        "bismark",
+        "-f",
+    ])
+    .is_err());
+}
+
+#[test]
+fn test_dmr_reference_mismatches() {
+    use std::io::BufRead;
+    let dir = std::env::temp_dir().join("test_dmr_reference_mismatches");
+    std::fs::create_dir_all(&dir).unwrap();
+    // move every record one base downstream, as if the sample was made with
+    // a different reference, so the records are no longer on their CpGs
+    let reader = std::io::BufReader::new(
+        rust_htslib::bgzf::Reader::from_path(
+            "tests/resources/\
+             lung_00733-m_primary-tumour_5mc-5hmc_chr20_cpg_pileup.bed.gz",
+        )
+        .unwrap(),
+    );
+    let shifted = reader
+        .lines()
+        .map(|l| {
+            let line = l.unwrap();
+            let mut fields = line
+                .split_whitespace()
+                .map(|x| x.to_string())
+                .collect::<Vec<String>>();
+            for idx in [1, 2, 6, 7] {
+                let x = fields[idx].parse::<u64>().unwrap();
+                fields[idx] = (x + 1).to_string();
+            }
+            fields.join("\t")
+        })
+        .collect::<Vec<String>>();
+    let shifted_fp = dir.join("shifted.bed.gz");
+    write_tabixed(&shifted_fp, &shifted, 1, 2, 3, true, 0);
+
+    let a_sample = "tests/resources/\
+                    lung_00733-m_adjacent-normal_5mc-5hmc_chr20_cpg_pileup.\
+                    bed.gz";
+    let out_bed = dir.join("dmr.bed");
+    let summary_fp = dir.join("summary.json");
+    let args = |strict: bool| {
+        let mut args = vec![
+            "dmr",
+            "pair",
+            "-a",
+            a_sample,
+            "-b",
+            shifted_fp.to_str().unwrap(),
+            "-o",
+            out_bed.to_str().unwrap(),
+            "-r",
+            "tests/resources/cpg_chr20_with_orig_names_selection.bed",
+            "--ref",
+            "tests/resources/GRCh38_chr20.fa",
+            "--summary",
+            summary_fp.to_str().unwrap(),
+            "--base",
+            "C",
+            "-f",
+        ];
+        if strict {
+            args.push("--strict");
+        }
+        args
+    };
+    run_modkit(&args(false)).unwrap();
+    let summary = std::fs::read_to_string(&summary_fp).unwrap();
+    let mismatches = summary
+        .split("\"reference_mismatches\":")
+        .nth(1)
+        .expect("missing reference mismatches");
+    assert!(mismatches.contains(&format!("\"{a_sample}\":0")), "{summary}");
+    let n_shifted = mismatches
+        .split(&format!("\"{}\":", shifted_fp.to_str().unwrap()))
+        .nth(1)
+        .and_then(|x| x.split(|c| c == ',' || c == '}').next())
+        .map(|x| x.parse::<usize>().unwrap())
+        .unwrap();
+    assert!(n_shifted > 0, "{summary}");
+
+    assert!(run_modkit(&args(true)).is_err());
+}
+
+#[test]
+fn test_dmr_dry_run() {
+    let tmp_dir = std::env::temp_dir().join("test_dmr_dry_run");
+    if tmp_dir.exists() {
+        std::fs::remove_dir_all(&tmp_dir).unwrap();
+    }
+    let out_bed = tmp_dir.join("dmr.bed");
+    run_modkit(&[
+        "dmr",
+        "pair",
+        "-a",
+        "tests/resources/\
+         lung_00733-m_adjacent-normal_5mc-5hmc_chr20_cpg_pileup.bed.gz",
+        "-b",
+        "tests/resources/\
+         lung_00733-m_primary-tumour_5mc-5hmc_chr20_cpg_pileup.bed.gz",
+        "-o",
+        out_bed.to_str().unwrap(),
+        "-r",
+        "tests/resources/cpg_chr20_with_orig_names_selection.bed",
+        "--ref",
+        "tests/resources/GRCh38_chr20.fa",
+        "--base",
+        "C",
+        "--dry-run",
+    ])
+    .expect("failed to run dmr pair dry run");
+    run_modkit(&[
+        "dmr",
+        "multi",
+        "-s",
+        "tests/resources/\
+         lung_00733-m_adjacent-normal_5mc-5hmc_chr20_cpg_pileup.bed.gz",
+        "norm",
+        "-s",
+        "tests/resources/\
+         lung_00733-m_primary-tumour_5mc-5hmc_chr20_cpg_pileup.bed.gz",
+        "tumor",
+        "-o",
+        tmp_dir.join("multi").to_str().unwrap(),
+        "-r",
+        "tests/resources/cpg_chr20_with_orig_names_selection.bed",
+        "--ref",
+        "tests/resources/GRCh38_chr20.fa",
+        "--base",
+        "C",
+        "--dry-run",
+    ])
+    .expect("failed to run dmr multi dry run");
+    // nothing is written in a dry run
+    assert!(!tmp_dir.exists());
+}
@@ -39,3 +39,1783 @@ fn test_entropy_regression() {
     // check_against_expected_text_file(windows.to_str().unwrap(),
     // "tests/resources/expected_entropy_windows.bed");
 }
+
+#[test]
+fn test_entropy_out_dir() {
+    let td = std::env::temp_dir().join("test_entropy_out_dir");
+    run_modkit(&[
+        "entropy",
+        "-s",
+        "tests/resources/bc_anchored_10_reads.sorted.bam",
+        "--out-dir",
+        td.to_str().unwrap(),
+        "--prefix",
+        "sample",
+        "--min-coverage",
+        "1",
+        "--ref",
+        "tests/resources/CGI_ladder_3.6kb_ref.fa",
+        "--cpg",
+    ])
+    .expect("should run entropy with out-dir");
+    assert!(td.join("sample_entropy.bed").exists());
+    // prefix without an output directory is an error
+    assert!(run_modkit(&[
+        "entropy",
+        "-s",
+        "tests/resources/bc_anchored_10_reads.sorted.bam",
+        "--prefix",
+        "sample",
+        "--ref",
+        "tests/resources/CGI_ladder_3.6kb_ref.fa",
+        "--cpg",
+    ])
+    .is_err());
+}
+
+#[test]
+fn test_entropy_regions_bed12() {
+    let td = std::env::temp_dir().join("test_entropy_regions_bed12");
+    std::fs::create_dir_all(&td).expect("should make temp dir");
+    run_modkit(&[
+        "entropy",
+        "-s",
+        "tests/resources/bc_anchored_10_reads.sorted.bam",
+        "-o",
+        td.to_str().unwrap(),
+        "--min-coverage",
+        "1",
+        "--ref",
+        "tests/resources/CGI_ladder_3.6kb_ref.fa",
+        "--regions",
+        "tests/resources/entropy_test_regions.bed",
+        "--cpg",
+        "--bed12",
+        "--force",
+    ])
+    .expect("should run entropy on regions with bed12");
+    let bed12 = std::fs::read_to_string(td.join("regions.bed12")).unwrap();
+    let regions = std::fs::read_to_string(td.join("regions.bed")).unwrap();
+    assert_eq!(bed12.lines().count(), regions.lines().count());
+    for line in bed12.lines() {
+        let fields = line.split('\t').collect::<Vec<&str>>();
+        assert_eq!(fields.len(), 13, "{line}");
+        let start = fields[1].parse::<u64>().unwrap();
+        let end = fields[2].parse::<u64>().unwrap();
+        let block_count = fields[9].parse::<usize>().unwrap();
+        let parse_list = |raw: &str| {
+            raw.split(',')
+                .map(|x| x.parse::<u64>().unwrap())
+                .collect::<Vec<_>>()
+        };
+        let sizes = parse_list(fields[10]);
+        let starts = parse_list(fields[11]);
+        assert_eq!(sizes.len(), block_count);
+        assert_eq!(starts.len(), block_count);
+        assert_eq!(fields[12].split(',').count(), block_count);
+        assert_eq!(starts[0], 0);
+        assert_eq!(
+            starts[block_count - 1] + sizes[block_count - 1],
+            end - start
+        );
+    }
+}
+
+#[test]
+fn test_entropy_markov_order() {
+    let out_fp = std::env::temp_dir().join("test_entropy_markov_order.bed");
+    run_modkit(&[
+        "entropy",
+        "-s",
+        "tests/resources/bc_anchored_10_reads.sorted.bam",
+        "-o",
+        out_fp.to_str().unwrap(),
+        "--min-coverage",
+        "1",
+        "--ref",
+        "tests/resources/CGI_ladder_3.6kb_ref.fa",
+        "--cpg",
+        "--markov-order",
+        "2",
+        "--force",
+    ])
+    .expect("should run entropy with markov order");
+    let windows = std::fs::read_to_string(&out_fp).unwrap();
+    assert!(windows.lines().count() > 0);
+    for line in windows.lines() {
+        let entropy = line.split('\t').nth(3).unwrap().parse::<f32>().unwrap();
+        // canonical, 5mC, or 5hmC, at most log2(3) bits per position
+        assert!((0f32..=3f32.log2()).contains(&entropy), "{line}");
+    }
+
+    let err = run_modkit(&[
+        "entropy",
+        "-s",
+        "tests/resources/bc_anchored_10_reads.sorted.bam",
+        "-o",
+        out_fp.to_str().unwrap(),
+        "--ref",
+        "tests/resources/CGI_ladder_3.6kb_ref.fa",
+        "--cpg",
+        "--markov-order",
+        "4",
+        "--force",
+    ]);
+    assert!(err.is_err(), "markov order must be less than num positions");
+}
+
+#[test]
+fn test_entropy_failed_windows() {
+    let out_fp = std::env::temp_dir().join("test_entropy_failed_windows.bed");
+    let failed_fp =
+        std::env::temp_dir().join("test_entropy_failed_windows_failed.bed");
+    run_modkit(&[
+        "entropy",
+        "-s",
+        "tests/resources/bc_anchored_10_reads.sorted.bam",
+        "-o",
+        out_fp.to_str().unwrap(),
+        "--min-coverage",
+        "5",
+        "--ref",
+        "tests/resources/CGI_ladder_3.6kb_ref.fa",
+        "--cpg",
+        "--failed-windows",
+        failed_fp.to_str().unwrap(),
+        "--header",
+        "--force",
+    ])
+    .unwrap();
+    let failed = std::fs::read_to_string(&failed_fp).unwrap();
+    let mut lines = failed.lines();
+    assert_eq!(
+        lines.next(),
+        Some(
+            [
+                "#chrom",
+                "start",
+                "end",
+                "reason",
+                "strand",
+                "min_valid_coverage",
+                "max_valid_coverage"
+            ]
+            .join("\t")
+            .as_str()
+        )
+    );
+    let rows =
+        lines.map(|l| l.split('\t').collect::<Vec<&str>>()).collect::<Vec<_>>();
+    assert!(!rows.is_empty());
+    for row in rows {
+        assert_eq!(row.len(), 7);
+        let start = row[1].parse::<u64>().unwrap();
+        let end = row[2].parse::<u64>().unwrap();
+        assert!(start < end);
+        let min_cov = row[5].parse::<u32>().unwrap();
+        let max_cov = row[6].parse::<u32>().unwrap();
+        assert!(min_cov <= max_cov);
+        match row[3] {
+            "insufficient-coverage" => assert!(min_cov < 5),
+            "zero-reads" => assert_eq!(max_cov, 0),
+            reason => panic!("unexpected reason {reason}"),
+        }
+    }
+
+    // refuses to overwrite without --force
+    assert!(run_modkit(&[
+        "entropy",
+        "-s",
+        "tests/resources/bc_anchored_10_reads.sorted.bam",
+        "-o",
+        out_fp.to_str().unwrap(),
+        "--ref",
+        "tests/resources/CGI_ladder_3.6kb_ref.fa",
+        "--cpg",
+        "--failed-windows",
+        failed_fp.to_str().unwrap(),
+    ])
+    .is_err());
+}
+
+#[test]
+fn test_entropy_summary() {
+    let out_fp = std::env::temp_dir().join("test_entropy_summary.bed");
+    let failed_fp =
+        std::env::temp_dir().join("test_entropy_summary_failed.bed");
+    let summary_fp = std::env::temp_dir().join("test_entropy_summary.json");
+    run_modkit(&[
+        "entropy",
+        "-s",
+        "tests/resources/bc_anchored_10_reads.sorted.bam",
+        "-o",
+        out_fp.to_str().unwrap(),
+        "--min-coverage",
+        "5",
+        "--ref",
+        "tests/resources/CGI_ladder_3.6kb_ref.fa",
+        "--cpg",
+        "--failed-windows",
+        failed_fp.to_str().unwrap(),
+        "--summary",
+        summary_fp.to_str().unwrap(),
+        "--force",
+    ])
+    .unwrap();
+    let n_scored = std::fs::read_to_string(&out_fp).unwrap().lines().count();
+    let failed = std::fs::read_to_string(&failed_fp).unwrap();
+    let n_failed = |reason: &str| {
+        failed.lines().filter(|l| l.split('\t').nth(3) == Some(reason)).count()
+    };
+    let summary = std::fs::read_to_string(&summary_fp).unwrap();
+    let windows = format!(
+        "{{\"windows\":{{\"scored\":{n_scored},\"zero_coverage\":{},\
+         \"insufficient_coverage\":{},\"other\":0}}",
+        n_failed("zero-reads"),
+        n_failed("insufficient-coverage")
+    );
+    assert!(summary.starts_with(&windows), "{summary}");
+    assert!(summary.contains("\"contigs\":{\""), "{summary}");
+    assert!(summary.contains("\"coverage_histogram\":{\""), "{summary}");
+}
+
+#[test]
+fn test_entropy_cgi_auto() {
+    let td = std::env::temp_dir().join("test_entropy_cgi_auto");
+    std::fs::create_dir_all(&td).expect("should make temp dir");
+    // the ladder oligos are too short to be islands on their own, extend them
+    // with a GC-rich tail so that islands overlap the aligned reads
+    let ref_fp = td.join("extended_ref.fa");
+    let extended =
+        std::fs::read_to_string("tests/resources/CGI_ladder_3.6kb_ref.fa")
+            .unwrap()
+            .lines()
+            .map(|l| {
+                if l.starts_with('>') {
+                    l.to_string()
+                } else {
+                    format!("{l}{}", "GCGCATGCGC".repeat(10))
+                }
+            })
+            .collect::<Vec<String>>()
+            .join("\n");
+    std::fs::write(&ref_fp, extended).unwrap();
+    let out_dir = td.join("out");
+    run_modkit(&[
+        "entropy",
+        "-s",
+        "tests/resources/bc_anchored_10_reads.sorted.bam",
+        "-o",
+        out_dir.to_str().unwrap(),
+        "--min-coverage",
+        "1",
+        "--ref",
+        ref_fp.to_str().unwrap(),
+        "--cgi-auto",
+        "--cpg",
+        "--force",
+    ])
+    .expect("should run entropy over detected CpG islands");
+    let regions = std::fs::read_to_string(out_dir.join("regions.bed")).unwrap();
+    assert!(regions.lines().count() > 0);
+    assert!(regions.lines().all(|l| l
+        .split('\t')
+        .nth(3)
+        .unwrap()
+        .starts_with("CpG:")));
+    // no islands in the short oligos
+    assert!(run_modkit(&[
+        "entropy",
+        "-s",
+        "tests/resources/bc_anchored_10_reads.sorted.bam",
+        "-o",
+        out_dir.to_str().unwrap(),
+        "--ref",
+        "tests/resources/CGI_ladder_3.6kb_ref.fa",
+        "--cgi-auto",
+        "--cpg",
+        "--force",
+    ])
+    .is_err());
+    // can't pass regions and ask for islands to be found
+    assert!(run_modkit(&[
+        "entropy",
+        "-s",
+        "tests/resources/bc_anchored_10_reads.sorted.bam",
+        "-o",
+        out_dir.to_str().unwrap(),
+        "--ref",
+        ref_fp.to_str().unwrap(),
+        "--regions",
+        "tests/resources/entropy_test_regions.bed",
+        "--cgi-auto",
+        "--cpg",
+        "--force",
+    ])
+    .is_err());
+}
+
+#[test]
+fn test_entropy_header_records_thresholds() {
+    let header_lines = |name: &str, extra_args: &[&str]| {
+        let out_fp = std::env::temp_dir().join(name);
+        let mut args = vec![
+            "entropy",
+            "-s",
+            "tests/resources/bc_anchored_10_reads.sorted.bam",
+            "-o",
+            out_fp.to_str().unwrap(),
+            "--ref",
+            "tests/resources/CGI_ladder_3.6kb_ref.fa",
+            "--cpg",
+            "--header",
+            "--force",
+        ];
+        args.extend_from_slice(extra_args);
+        run_modkit(&args).unwrap();
+        std::fs::read_to_string(&out_fp)
+            .unwrap()
+            .lines()
+            .take(4)
+            .map(|l| l.to_string())
+            .collect::<Vec<String>>()
+    };
+
+    let estimated = header_lines("test_entropy_thresholds_estimated.bed", &[]);
+    let threshold = estimated[0]
+        .strip_prefix("#thresholds: C:")
+        .expect("should have estimated C threshold");
+    let threshold = threshold.parse::<f32>().unwrap();
+    assert!(threshold > 0f32 && threshold <= 1f32);
+    // the basecaller and model are recorded from the BAM header
+    assert_eq!(estimated[1], "#basecaller: bonito 0.6.1");
+    assert_eq!(
+        estimated[2],
+        "#basecall_model: dna_r10.4.1_e8.2_400bps_hac@v3.5.2"
+    );
+    assert!(estimated[3].starts_with("#chrom\tstart\tend\tentropy"));
+
+    let fixed = header_lines(
+        "test_entropy_thresholds_fixed.bed",
+        &["--filter-threshold", "0.7", "--mod-threshold", "h:0.8"],
+    );
+    assert_eq!(fixed[0], "#thresholds: h:0.8,default:0.7");
+
+    let unfiltered = header_lines(
+        "test_entropy_thresholds_unfiltered.bed",
+        &["--no-filtering"],
+    );
+    assert_eq!(unfiltered[0], "#thresholds: none");
+}
+
+#[test]
+fn test_entropy_output_independent_of_threads() {
+    let run = |threads: &str| {
+        let out_fp = std::env::temp_dir()
+            .join(format!("test_entropy_threads_{threads}.bed"));
+        run_modkit(&[
+            "entropy",
+            "-s",
+            "tests/resources/bc_anchored_10_reads.sorted.bam",
+            "-o",
+            out_fp.to_str().unwrap(),
+            "--ref",
+            "tests/resources/CGI_ladder_3.6kb_ref.fa",
+            "--cpg",
+            "--min-coverage",
+            "1",
+            "--threads",
+            threads,
+            "--force",
+        ])
+        .unwrap();
+        std::fs::read_to_string(&out_fp).unwrap()
+    };
+    let single = run("1");
+    assert!(!single.is_empty());
+    assert_eq!(single, run("8"));
+}
+
+#[test]
+fn test_entropy_exclude_tag() {
+    use rust_htslib::bam::{self, record::Aux, Read};
+
+    // tag alternating reads with HP:0 and HP:1, also make a BAM with only the
+    // HP:1 reads
+    let tagged_fp = std::env::temp_dir().join("test_entropy_exclude_tag.bam");
+    let subset_fp =
+        std::env::temp_dir().join("test_entropy_exclude_tag.hp1.bam");
+    {
+        let mut reader = bam::Reader::from_path(
+            "tests/resources/bc_anchored_10_reads.sorted.bam",
+        )
+        .unwrap();
+        let header = bam::Header::from_template(reader.header());
+        let mut tagged =
+            bam::Writer::from_path(&tagged_fp, &header, bam::Format::Bam)
+                .unwrap();
+        let mut subset =
+            bam::Writer::from_path(&subset_fp, &header, bam::Format::Bam)
+                .unwrap();
+        for (i, record) in reader.records().enumerate() {
+            let mut record = record.unwrap();
+            let hp = (i % 2) as u8;
+            record.push_aux(b"HP", Aux::U8(hp)).unwrap();
+            tagged.write(&record).unwrap();
+            if hp == 1 {
+                subset.write(&record).unwrap();
+            }
+        }
+    }
+    for fp in [&tagged_fp, &subset_fp] {
+        bam::index::build(fp, None, bam::index::Type::Bai, 1).unwrap();
+    }
+
+    let run = |bam_fp: &std::path::Path, name: &str, extra: &[&str]| {
+        let out_fp = std::env::temp_dir().join(name);
+        let mut args = vec![
+            "entropy",
+            "-s",
+            bam_fp.to_str().unwrap(),
+            "-o",
+            out_fp.to_str().unwrap(),
+            "--ref",
+            "tests/resources/CGI_ladder_3.6kb_ref.fa",
+            "--cpg",
+            "--min-coverage",
+            "1",
+            "--no-filtering",
+            "--force",
+        ];
+        args.extend_from_slice(extra);
+        run_modkit(&args).unwrap();
+        std::fs::read_to_string(&out_fp).unwrap()
+    };
+    let all = run(&tagged_fp, "test_entropy_exclude_tag.all.bed", &[]);
+    let excluded = run(
+        &tagged_fp,
+        "test_entropy_exclude_tag.excluded.bed",
+        &["--exclude-tag", "HP:0"],
+    );
+    let subset = run(&subset_fp, "test_entropy_exclude_tag.subset.bed", &[]);
+    assert!(!excluded.is_empty());
+    assert_ne!(all, excluded);
+    // entropy values can differ in the last place depending on the order the
+    // patterns are summed
+    assert_eq!(excluded.lines().count(), subset.lines().count());
+    for (a, b) in excluded.lines().zip(subset.lines()) {
+        let a = a.split('\t').collect::<Vec<&str>>();
+        let b = b.split('\t').collect::<Vec<&str>>();
+        assert_eq!(a[..3], b[..3]);
+        assert_eq!(a[4..], b[4..]);
+        let (a_e, b_e) =
+            (a[3].parse::<f32>().unwrap(), b[3].parse::<f32>().unwrap());
+        assert!((a_e - b_e).abs() < 1e-5, "{a_e} != {b_e}");
+    }
+
+    // tag values must be TAG:VALUE
+    assert!(run_modkit(&[
+        "entropy",
+        "-s",
+        tagged_fp.to_str().unwrap(),
+        "--ref",
+        "tests/resources/CGI_ladder_3.6kb_ref.fa",
+        "--cpg",
+        "--exclude-tag",
+        "HP",
+    ])
+    .is_err());
+}
+
+#[test]
+fn test_entropy_read_level_out() {
+    use std::collections::HashMap;
+
+    for (name, extra) in [
+        ("me", vec![]),
+        ("markov", vec!["--markov-order", "2"]),
+        ("me_mm", vec!["--miller-madow", "--entropy-norm", "reads"]),
+        ("markov_mm", vec!["--markov-order", "2", "--miller-madow"]),
+    ] {
+        let out_fp = std::env::temp_dir()
+            .join(format!("test_entropy_read_level_out.{name}.bed"));
+        let read_level_fp = std::env::temp_dir()
+            .join(format!("test_entropy_read_level_out.{name}.tsv"));
+        let mut args = vec![
+            "entropy",
+            "-s",
+            "tests/resources/bc_anchored_10_reads.sorted.bam",
+            "-o",
+            out_fp.to_str().unwrap(),
+            "--read-level-out",
+            read_level_fp.to_str().unwrap(),
+            "--ref",
+            "tests/resources/CGI_ladder_3.6kb_ref.fa",
+            "--cpg",
+            "--min-coverage",
+            "1",
+            "--header",
+            "--force",
+        ];
+        args.extend_from_slice(&extra);
+        run_modkit(&args).unwrap();
+
+        let mut contributions = HashMap::new();
+        let read_level = std::fs::read_to_string(&read_level_fp).unwrap();
+        let mut lines = read_level.lines();
+        assert_eq!(
+            lines.next().unwrap(),
+            [
+                "#chrom",
+                "start",
+                "end",
+                "strand",
+                "read_id",
+                "pattern",
+                "entropy_contribution"
+            ]
+            .join("\t")
+        );
+        for line in lines {
+            let parts = line.split('\t').collect::<Vec<&str>>();
+            assert_eq!(parts.len(), 7, "{line}");
+            assert_eq!(parts[5].len(), 4, "{line}");
+            let key = (
+                parts[0].to_string(),
+                parts[1].to_string(),
+                parts[2].to_string(),
+                parts[3].to_string(),
+            );
+            let (sum, n_reads) =
+                contributions.entry(key).or_insert((0f32, 0usize));
+            *sum += parts[6].parse::<f32>().unwrap();
+            *n_reads += 1;
+        }
+
+        let windows = std::fs::read_to_string(&out_fp).unwrap();
+        let windows = windows
+            .lines()
+            .filter(|l| !l.starts_with('#'))
+            .collect::<Vec<&str>>();
+        assert!(!windows.is_empty());
+        assert_eq!(windows.len(), contributions.len());
+        for line in windows {
+            let parts = line.split('\t').collect::<Vec<&str>>();
+            let key = (
+                parts[0].to_string(),
+                parts[1].to_string(),
+                parts[2].to_string(),
+                parts[4].to_string(),
+            );
+            let (sum, n_reads) = contributions[&key];
+            let entropy = parts[3].parse::<f32>().unwrap();
+            assert!((sum.max(0f32) - entropy).abs() < 1e-4, "{line} {sum}");
+            assert_eq!(n_reads, parts[5].parse::<usize>().unwrap());
+        }
+    }
+}
+
+#[test]
+fn test_entropy_report_epialleles() {
+    use std::collections::HashMap;
+
+    let out_fp =
+        std::env::temp_dir().join("test_entropy_report_epialleles.bed");
+    let epialleles_fp =
+        std::env::temp_dir().join("test_entropy_report_epialleles.tsv");
+    run_modkit(&[
+        "entropy",
+        "-s",
+        "tests/resources/bc_anchored_10_reads.sorted.bam",
+        "-o",
+        out_fp.to_str().unwrap(),
+        "--report-epialleles",
+        epialleles_fp.to_str().unwrap(),
+        "--ref",
+        "tests/resources/CGI_ladder_3.6kb_ref.fa",
+        "--cpg",
+        "--min-coverage",
+        "1",
+        "--header",
+        "--force",
+    ])
+    .unwrap();
+
+    let epialleles = std::fs::read_to_string(&epialleles_fp).unwrap();
+    let mut lines = epialleles.lines();
+    assert_eq!(
+        lines.next().unwrap(),
+        ["#chrom", "start", "end", "strand", "pattern", "count", "frequency"]
+            .join("\t")
+    );
+    let mut spectra = HashMap::<_, Vec<(String, usize, f32)>>::new();
+    for line in lines {
+        let parts = line.split('\t').collect::<Vec<&str>>();
+        assert_eq!(parts.len(), 7, "{line}");
+        assert_eq!(parts[4].len(), 4, "{line}");
+        let key = (
+            parts[0].to_string(),
+            parts[1].to_string(),
+            parts[2].to_string(),
+            parts[3].to_string(),
+        );
+        spectra.entry(key).or_default().push((
+            parts[4].to_string(),
+            parts[5].parse::<usize>().unwrap(),
+            parts[6].parse::<f32>().unwrap(),
+        ));
+    }
+
+    let windows = std::fs::read_to_string(&out_fp).unwrap();
+    let columns = windows
+        .lines()
+        .find(|l| l.starts_with("#chrom"))
+        .unwrap()
+        .split('\t')
+        .collect::<Vec<&str>>();
+    assert_eq!(columns[6], "epipolymorphism");
+    let windows =
+        windows.lines().filter(|l| !l.starts_with('#')).collect::<Vec<&str>>();
+    assert!(!windows.is_empty());
+    assert_eq!(windows.len(), spectra.len());
+    for line in windows {
+        let parts = line.split('\t').collect::<Vec<&str>>();
+        let key = (
+            parts[0].to_string(),
+            parts[1].to_string(),
+            parts[2].to_string(),
+            parts[4].to_string(),
+        );
+        let spectrum = &spectra[&key];
+        let num_reads = parts[5].parse::<usize>().unwrap();
+        assert_eq!(
+            spectrum.iter().map(|(_, count, _)| count).sum::<usize>(),
+            num_reads,
+            "{line}"
+        );
+        let total_frequency =
+            spectrum.iter().map(|(_, _, freq)| freq).sum::<f32>();
+        assert!((total_frequency - 1f32).abs() < 1e-4, "{line}");
+        // most common pattern first
+        assert!(spectrum.windows(2).all(|w| w[0].1 >= w[1].1), "{line}");
+
+        let epipolymorphism = parts[6].parse::<f32>().unwrap();
+        assert!((0f32..=1f32).contains(&epipolymorphism), "{line}");
+        if spectrum.len() == 1 && !spectrum[0].0.contains('*') {
+            assert_eq!(epipolymorphism, 0f32, "{line}");
+        }
+    }
+}
+
+#[test]
+fn test_entropy_bigwig() {
+    use bigtools::BigWigRead;
+
+    let out_fp = std::env::temp_dir().join("test_entropy_bigwig.bed");
+    let out_bw = std::env::temp_dir().join("test_entropy_bigwig.bw");
+    run_modkit(&[
+        "entropy",
+        "-s",
+        "tests/resources/bc_anchored_10_reads.sorted.bam",
+        "-o",
+        out_fp.to_str().unwrap(),
+        "--bigwig",
+        out_bw.to_str().unwrap(),
+        "--ref",
+        "tests/resources/CGI_ladder_3.6kb_ref.fa",
+        "--cpg",
+        "--min-coverage",
+        "1",
+        "--force",
+    ])
+    .unwrap();
+    let windows = std::fs::read_to_string(&out_fp).unwrap();
+    let windows = windows
+        .lines()
+        .map(|l| {
+            let parts = l.split('\t').collect::<Vec<&str>>();
+            (
+                parts[0].to_string(),
+                parts[1].parse::<u32>().unwrap(),
+                parts[3].parse::<f32>().unwrap(),
+            )
+        })
+        .collect::<Vec<(String, u32, f32)>>();
+    assert!(!windows.is_empty());
+
+    let mut reader = BigWigRead::open_file(&out_bw).unwrap();
+    let chrom = windows[0].0.clone();
+    let chrom_size =
+        reader.chroms().iter().find(|c| c.name == chrom).unwrap().length;
+    let values = reader
+        .get_interval(&chrom, 0, chrom_size)
+        .unwrap()
+        .map(|v| v.unwrap())
+        .collect::<Vec<_>>();
+    // one interval per window start, intervals do not overlap
+    assert_eq!(values.len(), windows.len());
+    for (value, (_, start, entropy)) in values.iter().zip(windows.iter()) {
+        assert_eq!(value.start, *start);
+        assert!((value.value - entropy).abs() < 1e-6);
+    }
+    assert!(values.windows(2).all(|w| w[0].end <= w[1].start));
+
+    // without combining strands a track is written for each strand
+    let stranded_bw = std::env::temp_dir().join("test_entropy_bigwig_cg.bw");
+    run_modkit(&[
+        "entropy",
+        "-s",
+        "tests/resources/bc_anchored_10_reads.sorted.bam",
+        "-o",
+        out_fp.to_str().unwrap(),
+        "--bigwig",
+        stranded_bw.to_str().unwrap(),
+        "--ref",
+        "tests/resources/CGI_ladder_3.6kb_ref.fa",
+        "--motif",
+        "CG",
+        "0",
+        "--min-coverage",
+        "1",
+        "--force",
+    ])
+    .unwrap();
+    for strand in ["positive", "negative"] {
+        let fp = std::env::temp_dir()
+            .join(format!("test_entropy_bigwig_cg_{strand}.bw"));
+        assert!(BigWigRead::open_file(&fp).is_ok(), "{fp:?}");
+    }
+}
+
+#[test]
+fn test_entropy_per_mod_code() {
+    let joint_fp = std::env::temp_dir().join("test_entropy_per_mod_code.bed");
+    let per_code_fp =
+        std::env::temp_dir().join("test_entropy_per_mod_code.m_h.bed");
+    for (out_fp, extra_args) in
+        [(&joint_fp, vec![]), (&per_code_fp, vec!["--per-mod-code", "m,h"])]
+    {
+        let mut args = vec![
+            "entropy",
+            "-s",
+            "tests/resources/bc_anchored_10_reads.sorted.bam",
+            "-o",
+            out_fp.to_str().unwrap(),
+            "--ref",
+            "tests/resources/CGI_ladder_3.6kb_ref.fa",
+            "--cpg",
+            "--min-coverage",
+            "1",
+            "--header",
+            "--force",
+        ];
+        args.extend(extra_args);
+        run_modkit(&args).unwrap();
+    }
+    let joint = std::fs::read_to_string(&joint_fp).unwrap();
+    let per_code = std::fs::read_to_string(&per_code_fp).unwrap();
+    let columns = per_code
+        .lines()
+        .find(|l| l.starts_with("#chrom"))
+        .unwrap()
+        .split('\t')
+        .collect::<Vec<&str>>();
+    assert_eq!(&columns[7..], &["entropy_m", "entropy_h"]);
+
+    let joint_rows =
+        joint.lines().filter(|l| !l.starts_with('#')).collect::<Vec<&str>>();
+    let per_code_rows =
+        per_code.lines().filter(|l| !l.starts_with('#')).collect::<Vec<&str>>();
+    assert!(!joint_rows.is_empty());
+    assert_eq!(joint_rows.len(), per_code_rows.len());
+    for (joint_row, per_code_row) in joint_rows.iter().zip(per_code_rows) {
+        let parts = per_code_row.split('\t').collect::<Vec<&str>>();
+        assert_eq!(parts.len(), 9);
+        // the joint entropy columns are unchanged
+        assert_eq!(&parts[..7].join("\t"), joint_row);
+        let joint_entropy = parts[3].parse::<f32>().unwrap();
+        for raw in &parts[7..] {
+            let entropy = raw.parse::<f32>().unwrap();
+            // a single modification has at most as much entropy as all of
+            // them together
+            assert!(entropy >= 0f32, "{per_code_row}");
+            assert!(entropy <= joint_entropy + 1e-6, "{per_code_row}");
+        }
+    }
+
+    let td = std::env::temp_dir().join("test_entropy_per_mod_code_regions");
+    run_modkit(&[
+        "entropy",
+        "-s",
+        "tests/resources/bc_anchored_10_reads.sorted.bam",
+        "-o",
+        td.to_str().unwrap(),
+        "--ref",
+        "tests/resources/CGI_ladder_3.6kb_ref.fa",
+        "--regions",
+        "tests/resources/entropy_test_regions.bed",
+        "--cpg",
+        "--min-coverage",
+        "1",
+        "--per-mod-code",
+        "h",
+        "--header",
+        "--force",
+    ])
+    .unwrap();
+    let regions = std::fs::read_to_string(td.join("regions.bed")).unwrap();
+    let mut lines = regions.lines().filter(|l| {
+        !(l.starts_with("#thresholds") || l.starts_with("#basecall"))
+    });
+    let columns = lines.next().unwrap().split('\t').collect::<Vec<&str>>();
+    assert_eq!(columns.last(), Some(&"mean_entropy_h"));
+    for line in lines {
+        assert_eq!(line.split('\t').count(), columns.len(), "{line}");
+    }
+}
+
+#[test]
+fn test_entropy_max_reads_per_window() {
+    let run = |name: &str, extra_args: &[&str]| {
+        let out_fp = std::env::temp_dir().join(name);
+        let mut args = vec![
+            "entropy",
+            "-s",
+            "tests/resources/bc_anchored_10_reads.sorted.bam",
+            "-o",
+            out_fp.to_str().unwrap(),
+            "--ref",
+            "tests/resources/CGI_ladder_3.6kb_ref.fa",
+            "--cpg",
+            "--min-coverage",
+            "1",
+            "--force",
+        ];
+        args.extend_from_slice(extra_args);
+        run_modkit(&args).unwrap();
+        std::fs::read_to_string(&out_fp)
+            .unwrap()
+            .lines()
+            .map(|l| l.split('\t').map(|x| x.to_string()).collect())
+            .collect::<Vec<Vec<String>>>()
+    };
+    let all_reads = run("test_entropy_max_reads.all.bed", &[]);
+    let capped = run(
+        "test_entropy_max_reads.capped.bed",
+        &["--max-reads-per-window", "4"],
+    );
+    let capped_again = run(
+        "test_entropy_max_reads.capped_again.bed",
+        &["--max-reads-per-window", "4", "--threads", "1"],
+    );
+    assert_eq!(capped, capped_again, "subsampling should be reproducible");
+    assert_eq!(all_reads.len(), capped.len());
+    let mut any_subsampled = false;
+    for (all_row, capped_row) in all_reads.iter().zip(capped.iter()) {
+        assert_eq!(all_row[..3], capped_row[..3]);
+        let n_all = all_row[5].parse::<usize>().unwrap();
+        let n_capped = capped_row[5].parse::<usize>().unwrap();
+        assert_eq!(n_capped, n_all.min(4), "{capped_row:?}");
+        if n_all <= 4 {
+            assert_eq!(all_row, capped_row);
+        } else {
+            any_subsampled = true;
+        }
+    }
+    assert!(any_subsampled);
+
+    let out_fp = std::env::temp_dir().join("test_entropy_max_reads.bad.bed");
+    let err = run_modkit(&[
+        "entropy",
+        "-s",
+        "tests/resources/bc_anchored_10_reads.sorted.bam",
+        "-o",
+        out_fp.to_str().unwrap(),
+        "--ref",
+        "tests/resources/CGI_ladder_3.6kb_ref.fa",
+        "--cpg",
+        "--min-coverage",
+        "5",
+        "--max-reads-per-window",
+        "4",
+        "--force",
+    ]);
+    assert!(err.is_err(), "max reads must be at least the min coverage");
+}
+
+#[test]
+fn test_entropy_norm_and_miller_madow() {
+    let run = |name: &str, extra_args: &[&str]| {
+        let out_fp = std::env::temp_dir().join(name);
+        let mut args = vec![
+            "entropy",
+            "-s",
+            "tests/resources/bc_anchored_10_reads.sorted.bam",
+            "-o",
+            out_fp.to_str().unwrap(),
+            "--ref",
+            "tests/resources/CGI_ladder_3.6kb_ref.fa",
+            "--cpg",
+            "--min-coverage",
+            "1",
+            "--num-positions",
+            "4",
+            "--force",
+        ];
+        args.extend_from_slice(extra_args);
+        run_modkit(&args).unwrap();
+        std::fs::read_to_string(&out_fp)
+            .unwrap()
+            .lines()
+            .map(|l| {
+                let parts = l.split('\t').collect::<Vec<&str>>();
+                (
+                    parts[3].parse::<f32>().unwrap(),
+                    parts[5].parse::<usize>().unwrap(),
+                )
+            })
+            .collect::<Vec<(f32, usize)>>()
+    };
+    let window = run("test_entropy_norm.window.bed", &[]);
+    let bits = run("test_entropy_norm.none.bed", &["--entropy-norm", "none"]);
+    let reads =
+        run("test_entropy_norm.reads.bed", &["--entropy-norm", "reads"]);
+    let corrected = run(
+        "test_entropy_norm.none_mm.bed",
+        &["--entropy-norm", "none", "--miller-madow"],
+    );
+    assert!(!window.is_empty());
+    assert_eq!(window.len(), bits.len());
+    assert_eq!(window.len(), reads.len());
+    assert_eq!(window.len(), corrected.len());
+    let mut any_corrected = false;
+    for i in 0..window.len() {
+        let (bits, n_reads) = bits[i];
+        assert!((window[i].0 - bits / 4f32).abs() < 1e-5);
+        if n_reads > 1 {
+            let expected = bits / (n_reads as f32).log2();
+            assert!((reads[i].0 - expected).abs() < 1e-5);
+            assert!(reads[i].0 <= 1f32 + 1e-5);
+        }
+        // the correction is never negative, and zero when every read has
+        // the same pattern
+        let correction = corrected[i].0 - bits;
+        assert!(correction >= -1e-5, "{correction}");
+        if bits == 0f32 {
+            assert_eq!(corrected[i].0, 0f32);
+        }
+        any_corrected |= correction > 0f32;
+    }
+    assert!(any_corrected);
+
+    let out_fp = std::env::temp_dir().join("test_entropy_norm.bad.bed");
+    let err = run_modkit(&[
+        "entropy",
+        "-s",
+        "tests/resources/bc_anchored_10_reads.sorted.bam",
+        "-o",
+        out_fp.to_str().unwrap(),
+        "--ref",
+        "tests/resources/CGI_ladder_3.6kb_ref.fa",
+        "--cpg",
+        "--markov-order",
+        "2",
+        "--entropy-norm",
+        "reads",
+        "--force",
+    ]);
+    assert!(err.is_err(), "transition entropy isn't normalized");
+}
+
+#[test]
+fn test_entropy_from_bedmethyl() {
+    let bedmethyl = "tests/resources/\
+                     lung_00733-m_adjacent-normal_5mc-5hmc_chr20_cpg_pileup.\
+                     bed.gz";
+    let run = |name: &str, extra_args: &[&str]| {
+        let out_fp = std::env::temp_dir().join(name);
+        let mut args = vec![
+            "entropy",
+            "--in-bedmethyl",
+            bedmethyl,
+            "-o",
+            out_fp.to_str().unwrap(),
+            "--force",
+        ];
+        args.extend_from_slice(extra_args);
+        run_modkit(&args).unwrap();
+        std::fs::read_to_string(&out_fp)
+            .unwrap()
+            .lines()
+            .map(|l| l.split('\t').map(|x| x.to_string()).collect())
+            .collect::<Vec<Vec<String>>>()
+    };
+    let combined = run(
+        "test_entropy_from_bedmethyl.combined.bed",
+        &["--cpg", "--min-coverage", "1"],
+    );
+    assert!(!combined.is_empty());
+    for row in combined.iter() {
+        assert_eq!(row.len(), 7, "{row:?}");
+        assert_eq!(row[4], "+");
+        let entropy = row[3].parse::<f32>().unwrap();
+        let epipolymorphism = row[6].parse::<f32>().unwrap();
+        // two categories (modified and canonical), so at most 1 bit per
+        // position
+        assert!((0f32..=1f32).contains(&entropy), "{row:?}");
+        assert!((0f32..=1f32).contains(&epipolymorphism), "{row:?}");
+        assert_eq!(entropy == 0f32, epipolymorphism == 0f32, "{row:?}");
+    }
+    let deep = run(
+        "test_entropy_from_bedmethyl.deep.bed",
+        &["--cpg", "--min-coverage", "20"],
+    );
+    assert!(deep.len() < combined.len());
+    assert!(deep.iter().all(|row| row[5].parse::<u64>().unwrap() >= 20));
+
+    let stranded = run(
+        "test_entropy_from_bedmethyl.stranded.bed",
+        &["--min-coverage", "1"],
+    );
+    assert!(stranded.iter().any(|row| row[4] == "-"));
+
+    let out_fp = std::env::temp_dir().join("test_entropy_from_bedmethyl.fail");
+    assert!(run_modkit(&[
+        "entropy",
+        "--in-bedmethyl",
+        bedmethyl,
+        "-o",
+        out_fp.to_str().unwrap(),
+        "--entropy-norm",
+        "reads",
+        "--force",
+    ])
+    .is_err());
+}
+
+#[test]
+fn test_entropy_windows_bed() {
+    let windows_fp =
+        std::env::temp_dir().join("test_entropy_windows_bed_windows.bed");
+    std::fs::write(
+        &windows_fp,
+        [
+            "oligo_1512_adapters\t60\t100",
+            "oligo_1512_adapters\t0\t8",
+            "oligo_1512_adapters\t60\t140",
+        ]
+        .join("\n"),
+    )
+    .unwrap();
+    let out_fp = std::env::temp_dir().join("test_entropy_windows_bed.bed");
+    run_modkit(&[
+        "entropy",
+        "-s",
+        "tests/resources/bc_anchored_10_reads.sorted.bam",
+        "-o",
+        out_fp.to_str().unwrap(),
+        "--min-coverage",
+        "1",
+        "--max-filtered-positions",
+        "20",
+        "--ref",
+        "tests/resources/CGI_ladder_3.6kb_ref.fa",
+        "--cpg",
+        "--windows-bed",
+        windows_fp.to_str().unwrap(),
+        "--force",
+    ])
+    .unwrap();
+    let intervals = std::fs::read_to_string(&out_fp)
+        .unwrap()
+        .lines()
+        .map(|l| {
+            let parts = l.split('\t').collect::<Vec<&str>>();
+            assert_eq!(parts[4], "+");
+            (parts[1].parse::<u64>().unwrap(), parts[2].parse::<u64>().unwrap())
+        })
+        .collect::<Vec<(u64, u64)>>();
+    // the window without any CpGs is skipped, the others are reported with
+    // exactly the intervals in the BED file
+    assert_eq!(intervals, vec![(60, 100), (60, 140)]);
+
+    assert!(run_modkit(&[
+        "entropy",
+        "-s",
+        "tests/resources/bc_anchored_10_reads.sorted.bam",
+        "-o",
+        out_fp.to_str().unwrap(),
+        "--ref",
+        "tests/resources/CGI_ladder_3.6kb_ref.fa",
+        "--cpg",
+        "--windows-bed",
+        windows_fp.to_str().unwrap(),
+        "--cgi-auto",
+        "--force",
+    ])
+    .is_err());
+}
+
+#[test]
+fn test_entropy_read_filters() {
+    let run = |name: &str, extra_args: &[&str]| {
+        let out_fp = std::env::temp_dir().join(name);
+        let mut args = vec![
+            "entropy",
+            "-s",
+            "tests/resources/bc_anchored_10_reads.sorted.bam",
+            "-o",
+            out_fp.to_str().unwrap(),
+            "--min-coverage",
+            "1",
+            "--ref",
+            "tests/resources/CGI_ladder_3.6kb_ref.fa",
+            "--cpg",
+            "--force",
+        ];
+        args.extend_from_slice(extra_args);
+        run_modkit(&args).unwrap();
+        std::fs::read_to_string(&out_fp)
+            .unwrap()
+            .lines()
+            .map(|l| l.split('\t').nth(5).unwrap().parse::<usize>().unwrap())
+            .collect::<Vec<usize>>()
+    };
+    let unfiltered = run("test_entropy_read_filters.bed", &[]);
+    assert!(!unfiltered.is_empty());
+    assert_eq!(
+        run("test_entropy_read_filters.mapq0.bed", &["--min-mapq", "0"]),
+        unfiltered
+    );
+    let num_reads = |rows: &[usize]| rows.iter().sum::<usize>();
+    for (name, extra_args) in [
+        ("mapq", ["--min-mapq", "30"]),
+        ("identity", ["--min-identity", "0.9"]),
+    ] {
+        let filtered =
+            run(&format!("test_entropy_read_filters.{name}.bed"), &extra_args);
+        assert!(!filtered.is_empty());
+        assert!(num_reads(&filtered) < num_reads(&unfiltered), "{name}");
+    }
+    assert!(run(
+        "test_entropy_read_filters.length.bed",
+        &["--min-read-length", "100000"]
+    )
+    .is_empty());
+}
+
+#[test]
+fn test_entropy_bootstrap() {
+    let out_fp = std::env::temp_dir().join("test_entropy_bootstrap.bed");
+    let run = |bootstrap: &str| {
+        run_modkit(&[
+            "entropy",
+            "-s",
+            "tests/resources/bc_anchored_10_reads.sorted.bam",
+            "-o",
+            out_fp.to_str().unwrap(),
+            "--min-coverage",
+            "1",
+            "--ref",
+            "tests/resources/CGI_ladder_3.6kb_ref.fa",
+            "--cpg",
+            "--bootstrap",
+            bootstrap,
+            "--header",
+            "--force",
+        ])
+    };
+    run("100").unwrap();
+    let contents = std::fs::read_to_string(&out_fp).unwrap();
+    let header = contents.lines().find(|l| l.starts_with("#chrom")).unwrap();
+    assert!(header.ends_with("entropy_lower_ci\tentropy_upper_ci"));
+    let rows = contents
+        .lines()
+        .filter(|l| !l.starts_with('#'))
+        .map(|l| l.split('\t').collect::<Vec<&str>>())
+        .collect::<Vec<Vec<&str>>>();
+    assert!(!rows.is_empty());
+    for row in rows {
+        assert_eq!(row.len(), 9);
+        let lower = row[7].parse::<f32>().unwrap();
+        let upper = row[8].parse::<f32>().unwrap();
+        assert!(lower <= upper, "{row:?}");
+    }
+    // resampling is seeded, so the intervals are the same between runs
+    run("100").unwrap();
+    assert_eq!(std::fs::read_to_string(&out_fp).unwrap(), contents);
+
+    assert!(run("1").is_err());
+}
+
+#[test]
+fn test_entropy_shuffle_null() {
+    let out_fp = std::env::temp_dir().join("test_entropy_shuffle_null.bed");
+    let run = |num_shuffles: &str| {
+        run_modkit(&[
+            "entropy",
+            "-s",
+            "tests/resources/bc_anchored_10_reads.sorted.bam",
+            "-o",
+            out_fp.to_str().unwrap(),
+            "--min-coverage",
+            "1",
+            "--ref",
+            "tests/resources/CGI_ladder_3.6kb_ref.fa",
+            "--cpg",
+            "--shuffle-null",
+            num_shuffles,
+            "--header",
+            "--force",
+        ])
+    };
+    run("50").unwrap();
+    let contents = std::fs::read_to_string(&out_fp).unwrap();
+    let header = contents.lines().find(|l| l.starts_with("#chrom")).unwrap();
+    assert!(header.ends_with("null_entropy\tentropy_z_score"));
+    let rows = contents
+        .lines()
+        .filter(|l| !l.starts_with('#'))
+        .map(|l| l.split('\t').collect::<Vec<&str>>())
+        .collect::<Vec<Vec<&str>>>();
+    assert!(!rows.is_empty());
+    let mut n_z_scores = 0usize;
+    for row in rows {
+        assert_eq!(row.len(), 9);
+        let null_entropy = row[7].parse::<f32>().unwrap();
+        assert!(null_entropy >= 0f32, "{row:?}");
+        if row[8] != "." {
+            row[8].parse::<f32>().unwrap();
+            n_z_scores += 1;
+        }
+    }
+    assert!(n_z_scores > 0);
+    // shuffling is seeded, so the null is the same between runs
+    run("50").unwrap();
+    assert_eq!(std::fs::read_to_string(&out_fp).unwrap(), contents);
+
+    assert!(run("1").is_err());
+}
+
+#[test]
+fn test_entropy_step() {
+    let run = |name: &str, extra_args: &[&str]| {
+        let out_fp = std::env::temp_dir().join(name);
+        let mut args = vec![
+            "entropy",
+            "-s",
+            "tests/resources/bc_anchored_10_reads.sorted.bam",
+            "-o",
+            out_fp.to_str().unwrap(),
+            "--min-coverage",
+            "1",
+            "--ref",
+            "tests/resources/CGI_ladder_3.6kb_ref.fa",
+            "--cpg",
+            "--force",
+        ];
+        args.extend_from_slice(extra_args);
+        run_modkit(&args).map(|_| {
+            std::fs::read_to_string(&out_fp)
+                .unwrap()
+                .lines()
+                .map(|l| l.to_string())
+                .collect::<Vec<String>>()
+        })
+    };
+    let default_windows = run("test_entropy_step.default.bed", &[]).unwrap();
+    assert!(default_windows.len() > 2);
+    assert_eq!(
+        run("test_entropy_step.1.bed", &["--step", "1"]).unwrap(),
+        default_windows
+    );
+    // each window with step 1 starts at the next CpG, so stepping by 2 CpGs
+    // keeps every other window
+    let every_other =
+        default_windows.iter().step_by(2).cloned().collect::<Vec<String>>();
+    assert_eq!(
+        run("test_entropy_step.2.bed", &["--step", "2"]).unwrap(),
+        every_other
+    );
+    // windows of 4 CpGs stepped by 4 CpGs don't overlap
+    let non_overlapping =
+        run("test_entropy_step.4.bed", &["--step", "4"]).unwrap();
+    assert!(!non_overlapping.is_empty());
+    let starts_and_ends = non_overlapping
+        .iter()
+        .map(|l| {
+            let parts = l.split('\t').collect::<Vec<&str>>();
+            (parts[1].parse::<u64>().unwrap(), parts[2].parse::<u64>().unwrap())
+        })
+        .collect::<Vec<(u64, u64)>>();
+    for ((_, end), (next_start, _)) in
+        starts_and_ends.iter().zip(starts_and_ends.iter().skip(1))
+    {
+        assert!(next_start >= end);
+    }
+    // stepping by base pairs, each window starts at least 10 bp after the
+    // previous one
+    let bp_windows =
+        run("test_entropy_step.bp.bed", &["--step", "10", "--step-unit", "bp"])
+            .unwrap();
+    assert!(!bp_windows.is_empty());
+    let bp_starts = bp_windows
+        .iter()
+        .map(|l| l.split('\t').nth(1).unwrap().parse::<u64>().unwrap())
+        .collect::<Vec<u64>>();
+    assert!(bp_starts.windows(2).all(|w| w[1] >= w[0] + 10));
+
+    assert!(run("test_entropy_step.0.bed", &["--step", "0"]).is_err());
+}
+
+#[test]
+fn test_entropy_cram() {
+    let run = |name: &str, in_fp: &str| {
+        let out_fp = std::env::temp_dir().join(name);
+        run_modkit(&[
+            "entropy",
+            "-s",
+            in_fp,
+            "-o",
+            out_fp.to_str().unwrap(),
+            "--min-coverage",
+            "1",
+            "--ref",
+            "tests/resources/CGI_ladder_3.6kb_ref.fa",
+            "--cpg",
+            "--force",
+        ])
+        .unwrap();
+        std::fs::read_to_string(&out_fp).unwrap()
+    };
+    let expected = run(
+        "test_entropy_cram.bam.bed",
+        "tests/resources/bc_anchored_10_reads.sorted.bam",
+    );
+    let cram = run(
+        "test_entropy_cram.cram.bed",
+        "tests/resources/bc_anchored_10_reads.sorted.cram",
+    );
+    assert!(!expected.is_empty());
+    assert_eq!(cram, expected);
+}
+
+#[test]
+fn test_entropy_bgzf() {
+    use rust_htslib::tbx::{Read as TbxRead, Reader as TbxReader};
+    use std::io::Read;
+
+    let run = |out_fp: &std::path::Path, extra: &[&str]| {
+        let mut args = vec![
+            "entropy",
+            "-s",
+            "tests/resources/bc_anchored_10_reads.sorted.bam",
+            "-o",
+            out_fp.to_str().unwrap(),
+            "--min-coverage",
+            "1",
+            "--ref",
+            "tests/resources/CGI_ladder_3.6kb_ref.fa",
+            "--cpg",
+            "--header",
+            "--force",
+        ];
+        args.extend_from_slice(extra);
+        run_modkit(&args)
+    };
+    let plain_fp = std::env::temp_dir().join("test_entropy_bgzf.bed");
+    run(&plain_fp, &[]).unwrap();
+    let expected = std::fs::read_to_string(&plain_fp).unwrap();
+
+    let bgzf_fp = std::env::temp_dir().join("test_entropy_bgzf.bed.gz");
+    run(&bgzf_fp, &["--bgzf"]).unwrap();
+    let mut observed = String::new();
+    rust_htslib::bgzf::Reader::from_path(&bgzf_fp)
+        .unwrap()
+        .read_to_string(&mut observed)
+        .unwrap();
+    assert_eq!(observed, expected);
+    assert!(bgzf_fp.with_extension("gz.tbi").exists());
+
+    // the index can be used to fetch the windows on a contig
+    let chroms = expected
+        .lines()
+        .filter(|l| !l.starts_with('#'))
+        .map(|l| l.split('\t').next().unwrap())
+        .collect::<Vec<&str>>();
+    let chrom = chroms[0];
+    let n_windows = chroms.iter().filter(|c| **c == chrom).count();
+    let mut reader = TbxReader::from_path(&bgzf_fp).unwrap();
+    let tid = reader.tid(chrom).unwrap();
+    reader.fetch(tid, 0, u32::MAX as u64).unwrap();
+    assert_eq!(reader.records().count(), n_windows);
+
+    // with --out-dir the output is named .bed.gz
+    let td = std::env::temp_dir().join("test_entropy_bgzf_out_dir");
+    run_modkit(&[
+        "entropy",
+        "-s",
+        "tests/resources/bc_anchored_10_reads.sorted.bam",
+        "--out-dir",
+        td.to_str().unwrap(),
+        "--min-coverage",
+        "1",
+        "--ref",
+        "tests/resources/CGI_ladder_3.6kb_ref.fa",
+        "--cpg",
+        "--bgzf",
+        "--force",
+    ])
+    .unwrap();
+    assert!(td.join("entropy.bed.gz").exists());
+    assert!(td.join("entropy.bed.gz.tbi").exists());
+
+    // bgzf output has to go to a file
+    assert!(run_modkit(&[
+        "entropy",
+        "-s",
+        "tests/resources/bc_anchored_10_reads.sorted.bam",
+        "--ref",
+        "tests/resources/CGI_ladder_3.6kb_ref.fa",
+        "--cpg",
+        "--bgzf",
+    ])
+    .is_err());
+}
+
+#[test]
+fn test_entropy_region_quantiles() {
+    let td = std::env::temp_dir().join("test_entropy_region_quantiles");
+    let run = |extra: &[&str]| {
+        let mut args = vec![
+            "entropy",
+            "-s",
+            "tests/resources/bc_anchored_10_reads.sorted.bam",
+            "-o",
+            td.to_str().unwrap(),
+            "--min-coverage",
+            "1",
+            "--ref",
+            "tests/resources/CGI_ladder_3.6kb_ref.fa",
+            "--regions",
+            "tests/resources/entropy_test_regions.bed",
+            "--cpg",
+            "--header",
+            "--force",
+        ];
+        args.extend_from_slice(extra);
+        run_modkit(&args)
+    };
+    run(&["--quantiles", "0,0.25,0.5,0.9,1"]).unwrap();
+    let regions = std::fs::read_to_string(td.join("regions.bed")).unwrap();
+    let mut lines = regions.lines().filter(|l| !l.starts_with('#'));
+    let columns = lines.next().unwrap().split('\t').collect::<Vec<&str>>();
+    assert_eq!(
+        columns[columns.len() - 5..],
+        [
+            "entropy_q0",
+            "entropy_q0.25",
+            "entropy_q0.5",
+            "entropy_q0.9",
+            "entropy_q1"
+        ]
+    );
+    let mut n_regions = 0;
+    for line in lines {
+        let fields = line.split('\t').collect::<Vec<&str>>();
+        assert_eq!(fields.len(), columns.len(), "{line}");
+        let value = |name: &str| {
+            let idx = columns.iter().position(|c| *c == name).unwrap();
+            fields[idx].parse::<f32>().unwrap()
+        };
+        assert_eq!(value("entropy_q0"), value("min_entropy"));
+        assert_eq!(value("entropy_q0.5"), value("median_entropy"));
+        assert_eq!(value("entropy_q1"), value("max_entropy"));
+        assert!(value("entropy_q0.25") <= value("entropy_q0.5"));
+        assert!(value("entropy_q0.5") <= value("entropy_q0.9"));
+        n_regions += 1;
+    }
+    assert!(n_regions > 0);
+
+    assert!(run(&["--quantiles", "1.5"]).is_err());
+}
+
+#[test]
+fn test_entropy_exclude_bed() {
+    let run = |name: &str, extra_args: &[&str]| {
+        let out_fp = std::env::temp_dir().join(name);
+        let mut args = vec![
+            "entropy",
+            "-s",
+            "tests/resources/bc_anchored_10_reads.sorted.bam",
+            "-o",
+            out_fp.to_str().unwrap(),
+            "--min-coverage",
+            "1",
+            "--ref",
+            "tests/resources/CGI_ladder_3.6kb_ref.fa",
+            "--cpg",
+            "--force",
+        ];
+        args.extend_from_slice(extra_args);
+        run_modkit(&args).map(|_| {
+            std::fs::read_to_string(&out_fp)
+                .unwrap()
+                .lines()
+                .map(|l| l.to_string())
+                .collect::<Vec<String>>()
+        })
+    };
+    let default_windows =
+        run("test_entropy_exclude_bed.default.bed", &[]).unwrap();
+    assert!(default_windows.len() > 2);
+
+    let (exclude_start, exclude_end) = (80u64, 85u64);
+    let exclude_fp = std::env::temp_dir().join("test_entropy_exclude.bed");
+    std::fs::write(
+        &exclude_fp,
+        format!("oligo_1512_adapters\t{exclude_start}\t{exclude_end}\n"),
+    )
+    .unwrap();
+    let excluded = run(
+        "test_entropy_exclude_bed.excluded.bed",
+        &["--exclude-bed", exclude_fp.to_str().unwrap()],
+    )
+    .unwrap();
+    // the end of a window is its last position
+    let expected = default_windows
+        .iter()
+        .filter(|l| {
+            let parts = l.split('\t').collect::<Vec<&str>>();
+            let start = parts[1].parse::<u64>().unwrap();
+            let end = parts[2].parse::<u64>().unwrap();
+            end < exclude_start || start >= exclude_end
+        })
+        .cloned()
+        .collect::<Vec<String>>();
+    assert!(!expected.is_empty());
+    assert!(expected.len() < default_windows.len());
+    assert_eq!(excluded, expected);
+
+    // excluded intervals that don't overlap any windows don't change them
+    let no_overlap_fp =
+        std::env::temp_dir().join("test_entropy_exclude_no_overlap.bed");
+    std::fs::write(&no_overlap_fp, "oligo_1512_adapters\t0\t1\n").unwrap();
+    assert_eq!(
+        run(
+            "test_entropy_exclude_bed.no_overlap.bed",
+            &["--exclude-bed", no_overlap_fp.to_str().unwrap()],
+        )
+        .unwrap(),
+        default_windows
+    );
+}
+
+#[test]
+fn test_entropy_strand() {
+    let run = |name: &str, extra_args: &[&str]| {
+        let out_fp = std::env::temp_dir().join(name);
+        let mut args = vec![
+            "entropy",
+            "-s",
+            "tests/resources/bc_anchored_10_reads.sorted.bam",
+            "-o",
+            out_fp.to_str().unwrap(),
+            "--min-coverage",
+            "1",
+            "--ref",
+            "tests/resources/CGI_ladder_3.6kb_ref.fa",
+            "--motif",
+            "CG",
+            "0",
+            "--force",
+        ];
+        args.extend_from_slice(extra_args);
+        run_modkit(&args).map(|_| out_fp)
+    };
+    let read_lines = |fp: &std::path::Path| {
+        std::fs::read_to_string(fp)
+            .unwrap()
+            .lines()
+            .map(|l| l.to_string())
+            .collect::<Vec<String>>()
+    };
+    let on_strand = |lines: &[String], column: usize, strand: &str| {
+        lines
+            .iter()
+            .filter(|l| l.split('\t').nth(column) == Some(strand))
+            .cloned()
+            .collect::<Vec<String>>()
+    };
+    let both = read_lines(&run("test_entropy_strand.both.bed", &[]).unwrap());
+    for (strand, symbol) in [("positive", "+"), ("negative", "-")] {
+        let expected = on_strand(&both, 4, symbol);
+        assert!(!expected.is_empty());
+        let windows = read_lines(
+            &run(
+                &format!("test_entropy_strand.{strand}.bed"),
+                &["--strand", strand],
+            )
+            .unwrap(),
+        );
+        assert_eq!(windows, expected);
+    }
+
+    // regions only have statistics for the requested strand, the region
+    // interval is the span of the windows used so it isn't compared
+    let without_interval = |lines: Vec<String>| {
+        lines
+            .iter()
+            .map(|l| {
+                l.split('\t')
+                    .enumerate()
+                    .filter(|(i, _)| *i != 1 && *i != 2)
+                    .map(|(_, x)| x)
+                    .collect::<Vec<&str>>()
+                    .join("\t")
+            })
+            .collect::<Vec<String>>()
+    };
+    let regions_dir = |name: &str, extra_args: &[&str]| {
+        let out_dir = run(
+            name,
+            &[
+                &["--regions", "tests/resources/entropy_test_regions.bed"],
+                extra_args,
+            ]
+            .concat(),
+        )
+        .unwrap();
+        read_lines(&out_dir.join("regions.bed"))
+    };
+    let both_regions = regions_dir("test_entropy_strand_regions.both", &[]);
+    let negative_regions = regions_dir(
+        "test_entropy_strand_regions.negative",
+        &["--strand", "negative"],
+    );
+    assert!(!negative_regions.is_empty());
+    assert_eq!(
+        without_interval(negative_regions),
+        without_interval(on_strand(&both_regions, 5, "-"))
+    );
+
+    assert!(run(
+        "test_entropy_strand.cpg.bed",
+        &["--cpg", "--strand", "positive"]
+    )
+    .is_err());
+}
+
+#[test]
+fn test_entropy_stranded_regions() {
+    let regions =
+        std::fs::read_to_string("tests/resources/entropy_test_regions.bed")
+            .unwrap();
+    let run = |name: &str, regions_fp: &str, extra_args: &[&str]| {
+        let out_dir = std::env::temp_dir().join(name);
+        let mut args = vec![
+            "entropy",
+            "-s",
+            "tests/resources/bc_anchored_10_reads.sorted.bam",
+            "-o",
+            out_dir.to_str().unwrap(),
+            "--min-coverage",
+            "1",
+            "--ref",
+            "tests/resources/CGI_ladder_3.6kb_ref.fa",
+            "--motif",
+            "CG",
+            "0",
+            "--regions",
+            regions_fp,
+            "--force",
+        ];
+        args.extend_from_slice(extra_args);
+        run_modkit(&args).unwrap();
+        ["regions.bed", "windows.bedgraph"]
+            .map(|fp| std::fs::read_to_string(out_dir.join(fp)).unwrap())
+    };
+    let with_strand = |name: &str, symbol: &str| {
+        let fp = std::env::temp_dir()
+            .join(format!("test_entropy_stranded_regions.{name}.bed"));
+        let stranded = regions
+            .lines()
+            .map(|l| format!("{l}\t0\t{symbol}\n"))
+            .collect::<String>();
+        std::fs::write(&fp, stranded).unwrap();
+        fp.to_str().unwrap().to_string()
+    };
+
+    let unstranded = run(
+        "test_entropy_stranded_regions.both",
+        "tests/resources/entropy_test_regions.bed",
+        &[],
+    );
+    assert!(unstranded[0].lines().count() > 1);
+    assert_eq!(
+        run("test_entropy_stranded_regions.dot", &with_strand("dot", "."), &[]),
+        unstranded
+    );
+    for (strand, symbol) in [("positive", "+"), ("negative", "-")] {
+        // a stranded region only uses the windows on its strand
+        let expected = run(
+            &format!("test_entropy_stranded_regions.{strand}"),
+            "tests/resources/entropy_test_regions.bed",
+            &["--strand", strand],
+        );
+        assert_ne!(expected, unstranded);
+        assert_eq!(
+            run(
+                &format!("test_entropy_stranded_regions.{strand}_bed"),
+                &with_strand(strand, symbol),
+                &[]
+            ),
+            expected
+        );
+    }
+}
+
+#[test]
+fn test_entropy_dry_run() {
+    let td = std::env::temp_dir().join("test_entropy_dry_run");
+    if td.exists() {
+        std::fs::remove_dir_all(&td).unwrap();
+    }
+    run_modkit(&[
+        "entropy",
+        "-s",
+        "tests/resources/bc_anchored_10_reads.sorted.bam",
+        "-o",
+        td.to_str().unwrap(),
+        "--ref",
+        "tests/resources/CGI_ladder_3.6kb_ref.fa",
+        "--regions",
+        "tests/resources/entropy_test_regions.bed",
+        "--cpg",
+        "--dry-run",
+    ])
+    .expect("should run entropy dry run");
+    // nothing is written in a dry run
+    assert!(!td.exists());
+
+    // existing outputs are checked
+    std::fs::create_dir_all(&td).unwrap();
+    let summary_fp = td.join("summary.json");
+    std::fs::write(&summary_fp, "{}").unwrap();
+    assert!(run_modkit(&[
+        "entropy",
+        "-s",
+        "tests/resources/bc_anchored_10_reads.sorted.bam",
+        "-o",
+        td.to_str().unwrap(),
+        "--ref",
+        "tests/resources/CGI_ladder_3.6kb_ref.fa",
+        "--regions",
+        "tests/resources/entropy_test_regions.bed",
+        "--cpg",
+        "--summary",
+        summary_fp.to_str().unwrap(),
+        "--dry-run",
+    ])
+    .is_err());
+}
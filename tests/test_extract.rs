@@ -3,6 +3,7 @@ use crate::common::{
 };
 use anyhow::{anyhow, Context};
 use common::{check_legal_csv, run_modkit, ExtractFullRecord};
+use itertools::Itertools;
 use std::collections::{HashMap, HashSet};
 use std::fs::File;
 use std::io::BufRead;
@@ -605,3 +606,723 @@ fn test_extract_region_correct_output() {
     .context("test_extract_collapse_correct_output, output didn't match")
     .unwrap();
 }
+
+#[test]
+fn test_extract_sqlite_output() {
+    let tsv_fp = std::env::temp_dir().join("test_extract_sqlite_output.tsv");
+    let db_fp = std::env::temp_dir().join("test_extract_sqlite_output.sqlite");
+    for (out_fp, out_format) in [(&tsv_fp, "tsv"), (&db_fp, "sqlite")] {
+        run_modkit(&[
+            "extract",
+            "calls",
+            "tests/resources/bc_anchored_10_reads.sorted.bam",
+            out_fp.to_str().unwrap(),
+            "--out-format",
+            out_format,
+            "--force",
+        ])
+        .unwrap();
+    }
+    let n_tsv_rows =
+        BufReader::new(File::open(&tsv_fp).unwrap()).lines().skip(1).count();
+    assert!(n_tsv_rows > 0);
+
+    let connection = rusqlite::Connection::open(&db_fp).unwrap();
+    let n_rows: usize = connection
+        .query_row("SELECT count(*) FROM calls", [], |row| row.get(0))
+        .unwrap();
+    assert_eq!(n_rows, n_tsv_rows);
+    let n_indices: usize = connection
+        .query_row(
+            "SELECT count(*) FROM sqlite_master WHERE type = 'index' AND \
+             tbl_name = 'calls'",
+            [],
+            |row| row.get(0),
+        )
+        .unwrap();
+    assert_eq!(n_indices, 2);
+
+    // refuses to overwrite without --force
+    assert!(run_modkit(&[
+        "extract",
+        "calls",
+        "tests/resources/bc_anchored_10_reads.sorted.bam",
+        db_fp.to_str().unwrap(),
+        "--out-format",
+        "sqlite",
+    ])
+    .is_err());
+}
+
+#[test]
+fn test_extract_parquet_output() {
+    use arrow_array::{Array, Float64Array, Int64Array, StringArray};
+    use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+
+    let tsv_fp = std::env::temp_dir().join("test_extract_parquet_output.tsv");
+    let pq_fp =
+        std::env::temp_dir().join("test_extract_parquet_output.parquet");
+    for (out_fp, out_format) in [(&tsv_fp, "tsv"), (&pq_fp, "parquet")] {
+        run_modkit(&[
+            "extract",
+            "full",
+            "tests/resources/bc_anchored_10_reads.sorted.bam",
+            out_fp.to_str().unwrap(),
+            "--out-format",
+            out_format,
+            "--force",
+        ])
+        .unwrap();
+    }
+    let mut tsv_lines = BufReader::new(File::open(&tsv_fp).unwrap()).lines();
+    let header = tsv_lines.next().unwrap().unwrap();
+    let header = header.split('\t').collect::<Vec<&str>>();
+    let col_idx = |name: &str| header.iter().position(|c| *c == name).unwrap();
+    let expected = tsv_lines
+        .map(|l| {
+            let l = l.unwrap();
+            let fields = l.split('\t').collect::<Vec<&str>>();
+            (
+                fields[col_idx("read_id")].to_string(),
+                fields[col_idx("forward_read_position")]
+                    .parse::<i64>()
+                    .unwrap(),
+                fields[col_idx("mod_qual")].parse::<f64>().unwrap(),
+            )
+        })
+        .collect::<Vec<(String, i64, f64)>>();
+    assert!(!expected.is_empty());
+
+    let reader =
+        ParquetRecordBatchReaderBuilder::try_new(File::open(&pq_fp).unwrap())
+            .unwrap()
+            .build()
+            .unwrap();
+    let mut observed = Vec::new();
+    for batch in reader {
+        let batch = batch.unwrap();
+        assert_eq!(batch.num_columns(), header.len());
+        let column = |name: &str| batch.column_by_name(name).unwrap().clone();
+        let read_ids = column("read_id");
+        let read_ids = read_ids.as_any().downcast_ref::<StringArray>().unwrap();
+        let positions = column("forward_read_position");
+        let positions =
+            positions.as_any().downcast_ref::<Int64Array>().unwrap();
+        let mod_quals = column("mod_qual");
+        let mod_quals =
+            mod_quals.as_any().downcast_ref::<Float64Array>().unwrap();
+        for i in 0..batch.num_rows() {
+            observed.push((
+                read_ids.value(i).to_string(),
+                positions.value(i),
+                mod_quals.value(i),
+            ));
+        }
+    }
+    assert_eq!(observed, expected);
+
+    // refuses to overwrite without --force
+    assert!(run_modkit(&[
+        "extract",
+        "full",
+        "tests/resources/bc_anchored_10_reads.sorted.bam",
+        pq_fp.to_str().unwrap(),
+        "--out-format",
+        "parquet",
+    ])
+    .is_err());
+}
+
+#[test]
+fn test_extract_rna_labels() {
+    let out_fp = std::env::temp_dir().join("test_extract_rna_labels.tsv");
+    run_modkit(&[
+        "extract",
+        "full",
+        "tests/resources/bc_anchored_10_reads.sorted.bam",
+        out_fp.to_str().unwrap(),
+        "--force",
+        "--rna",
+        "--ref",
+        "tests/resources/CGI_ladder_3.6kb_ref.fa",
+    ])
+    .unwrap();
+    let reader = BufReader::new(File::open(&out_fp).unwrap());
+    let mut lines = reader.lines().map(|l| l.unwrap());
+    let header = lines.next().unwrap();
+    let columns = header.split('\t').collect::<Vec<&str>>();
+    let kmer_idxs = ["ref_kmer", "query_kmer"]
+        .iter()
+        .map(|name| columns.iter().position(|c| c == name).unwrap())
+        .collect::<Vec<usize>>();
+    let mut n_rows = 0usize;
+    for line in lines {
+        let parts = line.split('\t').collect::<Vec<&str>>();
+        for &idx in kmer_idxs.iter() {
+            assert!(!parts[idx].contains('T'), "found T in {}", parts[idx]);
+        }
+        n_rows += 1;
+    }
+    assert!(n_rows > 0);
+}
+
+#[test]
+fn test_extract_alignment_identity_filter() {
+    let read_ids = |fp: &PathBuf| -> HashSet<String> {
+        let reader = BufReader::new(File::open(fp).unwrap());
+        reader
+            .lines()
+            .skip(1)
+            .map(|l| l.unwrap().split('\t').next().unwrap().to_string())
+            .collect()
+    };
+    let run = |name: &str, args: &[&str]| -> HashSet<String> {
+        let out_fp = std::env::temp_dir().join(name);
+        let mut cmd = vec![
+            "extract",
+            "full",
+            "tests/resources/bc_anchored_10_reads.sorted.bam",
+            out_fp.to_str().unwrap(),
+            "--force",
+        ];
+        cmd.extend_from_slice(args);
+        run_modkit(&cmd).unwrap();
+        read_ids(&out_fp)
+    };
+
+    let all_reads = run("test_extract_alignment_identity_all.tsv", &[]);
+    assert!(!all_reads.is_empty());
+    let permissive = run(
+        "test_extract_alignment_identity_permissive.tsv",
+        &["--min-identity", "0"],
+    );
+    assert_eq!(permissive, all_reads);
+    let strict = run(
+        "test_extract_alignment_identity_strict.tsv",
+        &["--min-identity", "0.99"],
+    );
+    assert!(strict.len() < all_reads.len());
+    assert!(strict.is_subset(&all_reads));
+    let no_edits =
+        run("test_extract_alignment_identity_max_nm.tsv", &["--max-nm", "0"]);
+    assert!(no_edits.is_subset(&strict));
+}
+
+#[test]
+fn test_extract_regions_bed_correct_output() {
+    let out_fp =
+        std::env::temp_dir().join("test_extract_regions_bed_correct_output");
+    let regions_fp = std::env::temp_dir()
+        .join("test_extract_regions_bed_correct_output_regions.bed");
+    std::fs::write(&regions_fp, "oligo_1512_adapters\t55\t95\n").unwrap();
+    run_modkit(&[
+        "extract",
+        "full",
+        "tests/resources/bc_anchored_10_reads.sorted.bam",
+        out_fp.to_str().unwrap(),
+        "--ignore",
+        "h",
+        "-i",
+        "10",
+        "--regions",
+        regions_fp.to_str().unwrap(),
+        "--force",
+    ])
+    .unwrap();
+
+    // same reads and calls as with --region
+    check_mod_profiles_same(
+        &out_fp,
+        &Path::new(
+            "tests/resources/bc_anchored_10_reads.sorted.\
+             methylprofile_ignoreh.tsv",
+        )
+        .to_path_buf(),
+    )
+    .context("test_extract_regions_bed_correct_output, output didn't match")
+    .unwrap();
+}
+
+#[test]
+fn test_extract_mod_code_filters() {
+    let run = |name: &str, extra_args: &[&str]| {
+        let out_fp = std::env::temp_dir().join(name);
+        let mut args = vec![
+            "extract",
+            "full",
+            "tests/resources/bc_anchored_10_reads.sorted.bam",
+            out_fp.to_str().unwrap(),
+            "--force",
+        ];
+        args.extend_from_slice(extra_args);
+        run_modkit(&args).unwrap();
+        let contents = std::fs::read_to_string(&out_fp).unwrap();
+        let mut lines = contents.lines();
+        let header = lines.next().unwrap().split('\t').collect::<Vec<&str>>();
+        let mod_code_idx =
+            header.iter().position(|&col| col == "mod_code").unwrap();
+        lines
+            .map(|l| l.to_string())
+            .map(|l| (l.split('\t').nth(mod_code_idx).unwrap().to_string(), l))
+            .sorted()
+            .collect::<Vec<(String, String)>>()
+    };
+
+    let all_rows = run("test_extract_mod_codes_all.tsv", &[]);
+    let m_rows = all_rows
+        .iter()
+        .filter(|(code, _)| code == "m")
+        .cloned()
+        .collect::<Vec<(String, String)>>();
+    assert!(!m_rows.is_empty());
+    assert!(m_rows.len() < all_rows.len());
+
+    let included =
+        run("test_extract_mod_codes_include.tsv", &["--mod-codes", "m"]);
+    assert_eq!(included, m_rows);
+    let excluded = run(
+        "test_extract_mod_codes_exclude.tsv",
+        &["--exclude-mod-codes", "h"],
+    );
+    assert_eq!(excluded, m_rows);
+    let both = run("test_extract_mod_codes_both.tsv", &["--mod-codes", "m,h"]);
+    assert_eq!(both, all_rows);
+}
+
+#[test]
+fn test_extract_cigar_context() {
+    let run = |subcommand: &str| -> Vec<HashMap<String, String>> {
+        let out_fp = std::env::temp_dir()
+            .join(format!("test_extract_cigar_context_{subcommand}.tsv"));
+        run_modkit(&[
+            "extract",
+            subcommand,
+            "tests/resources/bc_anchored_10_reads.sorted.bam",
+            out_fp.to_str().unwrap(),
+            "--force",
+            "--cigar-context",
+        ])
+        .unwrap();
+        let reader = BufReader::new(File::open(&out_fp).unwrap());
+        let mut lines = reader.lines().map(|l| l.unwrap());
+        let header = lines.next().unwrap();
+        let columns = header.split('\t').map(|s| s.to_string()).collect_vec();
+        assert_eq!(
+            &columns[columns.len() - 2..],
+            &["cigar_context", "indel_distance"]
+        );
+        lines
+            .map(|l| {
+                columns
+                    .iter()
+                    .cloned()
+                    .zip(l.split('\t').map(|s| s.to_string()))
+                    .collect()
+            })
+            .collect()
+    };
+
+    for subcommand in ["full", "calls"] {
+        let rows = run(subcommand);
+        assert!(!rows.is_empty());
+        let mut n_near_indel = 0usize;
+        for row in rows.iter() {
+            let get = |name: &str| -> i64 { row[name].parse().unwrap() };
+            let forward_pos = get("forward_read_position");
+            let in_soft_clip = forward_pos < get("fw_soft_clipped_start")
+                || forward_pos
+                    >= get("read_length") - get("fw_soft_clipped_end");
+            let distance = get("indel_distance");
+            match row["cigar_context"].as_str() {
+                "match" => {
+                    assert!(get("ref_position") >= 0);
+                    assert!(!in_soft_clip);
+                    assert!(distance != 0);
+                }
+                "insertion" => {
+                    assert_eq!(get("ref_position"), -1);
+                    assert_eq!(distance, 0);
+                }
+                "soft_clip" => {
+                    assert_eq!(get("ref_position"), -1);
+                    assert!(in_soft_clip);
+                }
+                "." => assert_eq!(row["chrom"], "."),
+                op => panic!("unexpected cigar context {op}"),
+            }
+            if (0..=2).contains(&distance) {
+                n_near_indel += 1;
+            }
+        }
+        assert!(n_near_indel > 0);
+    }
+}
+
+#[test]
+fn test_extract_include_softclipped() {
+    let run = |subcommand: &str, args: &[&str]| -> Vec<Vec<String>> {
+        let out_fp = std::env::temp_dir().join(format!(
+            "test_extract_include_softclipped_{subcommand}_{}.tsv",
+            args.len()
+        ));
+        let mut cmd = vec![
+            "extract",
+            subcommand,
+            "tests/resources/bc_anchored_10_reads.sorted.bam",
+            out_fp.to_str().unwrap(),
+            "--ref",
+            "tests/resources/CGI_ladder_3.6kb_ref.fa",
+            "--cpg",
+            "--force",
+        ];
+        cmd.extend_from_slice(args);
+        run_modkit(&cmd).unwrap();
+        let reader = BufReader::new(File::open(&out_fp).unwrap());
+        reader
+            .lines()
+            .skip(1)
+            .map(|l| l.unwrap().split('\t').map(|s| s.to_string()).collect())
+            .collect()
+    };
+
+    for subcommand in ["full", "calls"] {
+        // with a motif only mapped positions are output
+        let mapped = run(subcommand, &[]);
+        assert!(mapped.iter().all(|row| row[2] != "-1"));
+        let with_soft_clipped = run(subcommand, &["--include-softclipped"]);
+        let (soft_clipped, rest): (Vec<_>, Vec<_>) =
+            with_soft_clipped.into_iter().partition(|row| row[2] == "-1");
+        assert!(!soft_clipped.is_empty());
+        for row in soft_clipped {
+            assert_eq!(row[row.len() - 2], "soft_clip");
+        }
+        assert_eq!(rest.len(), mapped.len());
+    }
+}
+
+#[test]
+fn test_extract_include_sites_compressed_bed() {
+    let include_bed_fp = "tests/resources/CGI_ladder_3.6kb_ref_CG.bed";
+    let compressed_bed_fp = std::env::temp_dir()
+        .join("test_extract_include_sites_compressed_bed.bed.gz");
+    let mut writer =
+        rust_htslib::bgzf::Writer::from_path(&compressed_bed_fp).unwrap();
+    std::io::Write::write_all(
+        &mut writer,
+        &std::fs::read(include_bed_fp).unwrap(),
+    )
+    .unwrap();
+    drop(writer);
+
+    let run = |name: &str, bed_fp: &str| -> String {
+        let out_fp = std::env::temp_dir().join(name);
+        run_modkit(&[
+            "extract",
+            "full",
+            "tests/resources/bc_anchored_10_reads.sorted.bam",
+            out_fp.to_str().unwrap(),
+            "--include-bed",
+            bed_fp,
+            "--force",
+        ])
+        .unwrap();
+        std::fs::read_to_string(out_fp).unwrap()
+    };
+    let plain = run(
+        "test_extract_include_sites_compressed_bed.plain.tsv",
+        include_bed_fp,
+    );
+    let compressed = run(
+        "test_extract_include_sites_compressed_bed.gz.tsv",
+        compressed_bed_fp.to_str().unwrap(),
+    );
+    assert!(plain.lines().count() > 1);
+    assert_eq!(plain, compressed);
+}
+
+#[test]
+fn test_extract_include_no_calls() {
+    // (read_id, ref_position, ref_mod_strand) of each row and whether it's a
+    // no-call
+    let run = |subcommand: &str, extra_args: &[&str]| {
+        let out_fp = std::env::temp_dir().join(format!(
+            "test_extract_include_no_calls_{subcommand}_{}.tsv",
+            extra_args.len()
+        ));
+        let mut args = vec![
+            "extract",
+            subcommand,
+            "tests/resources/bc_anchored_10_reads.sorted.bam",
+            out_fp.to_str().unwrap(),
+            "--ref",
+            "tests/resources/CGI_ladder_3.6kb_ref.fa",
+            "--cpg",
+            "--force",
+        ];
+        args.extend_from_slice(extra_args);
+        run_modkit(&args).unwrap();
+        let reader = BufReader::new(File::open(&out_fp).unwrap());
+        let mut lines = reader.lines().map(|l| l.unwrap());
+        let header = lines.next().unwrap();
+        let columns = header.split('\t').collect_vec();
+        let idx = |name: &str| columns.iter().position(|c| *c == name).unwrap();
+        let (read_id, ref_position, ref_mod_strand, code) =
+            if subcommand == "full" {
+                (
+                    idx("read_id"),
+                    idx("ref_position"),
+                    idx("ref_mod_strand"),
+                    idx("mod_code"),
+                )
+            } else {
+                (
+                    idx("read_id"),
+                    idx("ref_position"),
+                    idx("ref_mod_strand"),
+                    idx("call_code"),
+                )
+            };
+        lines
+            .map(|l| {
+                let parts = l.split('\t').map(|s| s.to_string()).collect_vec();
+                assert_eq!(parts.len(), columns.len());
+                let site = (
+                    parts[read_id].clone(),
+                    parts[ref_position].clone(),
+                    parts[ref_mod_strand].clone(),
+                );
+                (site, parts[code] == ".")
+            })
+            .collect::<Vec<((String, String, String), bool)>>()
+    };
+
+    for subcommand in ["full", "calls"] {
+        let all_sites = run(subcommand, &[])
+            .into_iter()
+            .map(|(site, no_call)| {
+                assert!(!no_call);
+                site
+            })
+            .collect::<HashSet<(String, String, String)>>();
+        let edge_filtered =
+            run(subcommand, &["--edge-filter", "50", "--include-no-calls"]);
+        let (no_calls, calls): (Vec<_>, Vec<_>) =
+            edge_filtered.into_iter().partition(|(_, no_call)| *no_call);
+        let no_calls = no_calls.into_iter().map(|(site, _)| site).collect_vec();
+        let calls = calls.into_iter().map(|(site, _)| site).collect::<HashSet<(
+            String,
+            String,
+            String,
+        )>>();
+        assert!(!no_calls.is_empty());
+        // every call removed by the edge filter is now a no-call, and each
+        // no-call is a site without a call
+        let no_call_sites = no_calls.iter().cloned().collect::<HashSet<_>>();
+        assert_eq!(no_call_sites.len(), no_calls.len());
+        assert!(no_call_sites.is_disjoint(&calls));
+        assert!(all_sites
+            .difference(&calls)
+            .all(|s| no_call_sites.contains(s)));
+    }
+
+    assert!(run_modkit(&[
+        "extract",
+        "full",
+        "tests/resources/bc_anchored_10_reads.sorted.bam",
+        std::env::temp_dir()
+            .join("test_extract_include_no_calls_no_motif.tsv")
+            .to_str()
+            .unwrap(),
+        "--include-no-calls",
+        "--force",
+    ])
+    .is_err());
+}
+
+#[test]
+fn test_extract_cram() {
+    let run = |subcommand: &str, ext: &str| {
+        let in_fp =
+            format!("tests/resources/bc_anchored_10_reads.sorted.{ext}");
+        let out_fp = std::env::temp_dir()
+            .join(format!("test_extract_cram_{subcommand}_{ext}.tsv"));
+        run_modkit(&[
+            "extract",
+            subcommand,
+            &in_fp,
+            out_fp.to_str().unwrap(),
+            "--ref",
+            "tests/resources/CGI_ladder_3.6kb_ref.fa",
+            "--force",
+        ])
+        .unwrap();
+        std::fs::read_to_string(&out_fp).unwrap()
+    };
+    for subcommand in ["full", "calls"] {
+        let expected = run(subcommand, "bam");
+        assert!(expected.lines().count() > 1);
+        assert_eq!(run(subcommand, "cram"), expected);
+    }
+}
+
+#[test]
+fn test_extract_unindexed() {
+    let bam_fp = "tests/resources/bc_anchored_10_reads.sorted.bam";
+    let unindexed_fp = std::env::temp_dir().join("test_extract_unindexed.bam");
+    std::fs::copy(bam_fp, &unindexed_fp).unwrap();
+    let run = |subcommand: &str, in_fp: &str, name: &str, extra: &[&str]| {
+        let out_fp = std::env::temp_dir()
+            .join(format!("test_extract_unindexed_{subcommand}_{name}.tsv"));
+        let mut args = vec![
+            "extract",
+            subcommand,
+            in_fp,
+            out_fp.to_str().unwrap(),
+            "--threads",
+            "4",
+            "--force",
+        ];
+        args.extend_from_slice(extra);
+        run_modkit(&args).unwrap();
+        let mut lines = std::fs::read_to_string(&out_fp)
+            .unwrap()
+            .lines()
+            .map(|l| l.to_string())
+            .collect::<Vec<String>>();
+        // the header stays first, the rows are in a different order
+        // without the index
+        lines[1..].sort();
+        lines
+    };
+    for subcommand in ["full", "calls"] {
+        let expected = run(subcommand, bam_fp, "indexed", &[]);
+        assert!(expected.len() > 1);
+        let unindexed =
+            run(subcommand, unindexed_fp.to_str().unwrap(), "unindexed", &[]);
+        assert_eq!(unindexed, expected);
+
+        let n_reads = run(
+            subcommand,
+            unindexed_fp.to_str().unwrap(),
+            "num_reads",
+            &["--num-reads", "3"],
+        );
+        let read_ids = n_reads[1..]
+            .iter()
+            .map(|l| l.split('\t').next().unwrap())
+            .collect::<HashSet<&str>>();
+        assert_eq!(read_ids.len(), 3);
+    }
+}
+
+#[test]
+fn test_extract_feature_coords() {
+    let bed_fp = std::env::temp_dir().join("test_extract_feature_coords.bed");
+    std::fs::write(
+        &bed_fp,
+        "oligo_1512_adapters\t0\t100\tfeat_fwd\t0\t+\n\
+         oligo_1512_adapters\t100\t200\tfeat_rev\t0\t-\n",
+    )
+    .unwrap();
+    for subcommand in ["full", "calls"] {
+        let out_fp = std::env::temp_dir()
+            .join(format!("test_extract_feature_coords_{subcommand}.tsv"));
+        run_modkit(&[
+            "extract",
+            subcommand,
+            "tests/resources/bc_anchored_10_reads.sorted.bam",
+            out_fp.to_str().unwrap(),
+            "--include-bed",
+            bed_fp.to_str().unwrap(),
+            "--feature-coords",
+            "--force",
+        ])
+        .unwrap();
+        let contents = std::fs::read_to_string(&out_fp).unwrap();
+        let mut lines = contents.lines();
+        let header = lines.next().unwrap().split('\t').collect::<Vec<&str>>();
+        let column =
+            |name: &str| header.iter().position(|col| *col == name).unwrap();
+        let (ref_pos_col, name_col, start_col, center_col) = (
+            column("ref_position"),
+            column("feature_name"),
+            column("feature_start_offset"),
+            column("feature_center_offset"),
+        );
+        let mut n_rows = 0;
+        for line in lines {
+            let fields = line.split('\t').collect::<Vec<&str>>();
+            let ref_pos = fields[ref_pos_col].parse::<i64>().unwrap();
+            let start_offset = fields[start_col].parse::<i64>().unwrap();
+            let center_offset = fields[center_col].parse::<i64>().unwrap();
+            match fields[name_col] {
+                "feat_fwd" => {
+                    assert_eq!(start_offset, ref_pos);
+                    assert_eq!(center_offset, ref_pos - 50);
+                }
+                "feat_rev" => {
+                    assert_eq!(start_offset, 199 - ref_pos);
+                    assert_eq!(center_offset, 150 - ref_pos);
+                }
+                name => panic!("unexpected feature {name}"),
+            }
+            n_rows += 1;
+        }
+        assert!(n_rows > 0);
+    }
+
+    let out_fp = std::env::temp_dir().join("test_extract_feature_coords.tsv");
+    assert!(run_modkit(&[
+        "extract",
+        "full",
+        "tests/resources/bc_anchored_10_reads.sorted.bam",
+        out_fp.to_str().unwrap(),
+        "--feature-coords",
+        "--force",
+    ])
+    .is_err());
+}
+
+#[test]
+fn test_extract_dry_run() {
+    let tmp_dir = std::env::temp_dir().join("test_extract_dry_run");
+    if tmp_dir.exists() {
+        std::fs::remove_dir_all(&tmp_dir).unwrap();
+    }
+    let out_fp = tmp_dir.join("extract.tsv");
+    run_modkit(&[
+        "extract",
+        "full",
+        "tests/resources/bc_anchored_10_reads.sorted.bam",
+        out_fp.to_str().unwrap(),
+        "--cpg",
+        "--ref",
+        "tests/resources/CGI_ladder_3.6kb_ref.fa",
+        "--dry-run",
+    ])
+    .unwrap();
+    run_modkit(&[
+        "extract",
+        "calls",
+        "tests/resources/bc_anchored_10_reads.sorted.bam",
+        out_fp.to_str().unwrap(),
+        "--filter-threshold",
+        "0.7",
+        "--dry-run",
+    ])
+    .unwrap();
+    // nothing is written in a dry run
+    assert!(!tmp_dir.exists());
+
+    // existing outputs are checked
+    std::fs::create_dir_all(&tmp_dir).unwrap();
+    File::create(&out_fp).unwrap();
+    assert!(run_modkit(&[
+        "extract",
+        "full",
+        "tests/resources/bc_anchored_10_reads.sorted.bam",
+        out_fp.to_str().unwrap(),
+        "--dry-run",
+    ])
+    .is_err());
+}
@@ -0,0 +1,101 @@
+use std::io::{BufRead, BufReader};
+
+use mod_kit::dmr::bedmethyl::BedMethylLine;
+use rust_htslib::bgzf;
+
+use crate::common::{read_rows, run_modkit};
+
+mod common;
+
+const BEDMETHYL: &str =
+    "tests/resources/lung_00733-m_adjacent-normal_5mc-5hmc_chr20_cpg_pileup.\
+     bed.gz";
+const GTF: &str = "tests/resources/chr20_test_genes.gtf";
+
+#[test]
+fn test_metagene_help() {
+    let _ = run_modkit(&["metagene", "--help"])
+        .expect("failed to run modkit metagene help");
+}
+
+#[test]
+fn test_metagene_body() {
+    let out_fp = std::env::temp_dir().join("test_metagene_body.tsv");
+    run_modkit(&[
+        "metagene",
+        BEDMETHYL,
+        "--gtf",
+        GTF,
+        "--bins",
+        "4",
+        "--flank",
+        "0",
+        "--min-coverage",
+        "1",
+        "-o",
+        out_fp.to_str().unwrap(),
+        "-f",
+    ])
+    .unwrap();
+    let rows = read_rows(&out_fp);
+    assert_eq!(rows.len(), 4);
+    assert!(rows.iter().all(|row| row[1] == "body"));
+    assert_eq!(
+        rows.iter().map(|row| row[3].as_str()).collect::<Vec<&str>>(),
+        vec!["0", "1", "2", "3"]
+    );
+
+    // without flanks, every record in a gene body is counted once
+    let genes = [(9_750_000u64, 9_780_000u64), (10_300_000, 10_350_000)];
+    let genes = genes.iter().chain(&[(10_600_000, 10_610_000)]);
+    let genes = genes.collect::<Vec<_>>();
+    let expected = BufReader::new(bgzf::Reader::from_path(BEDMETHYL).unwrap())
+        .lines()
+        .map(|l| BedMethylLine::parse(&l.unwrap()).unwrap())
+        .filter(|bm| bm.valid_coverage >= 1)
+        .filter(|bm| {
+            genes.iter().any(|(s, e)| bm.start() >= *s && bm.start() < *e)
+        })
+        .count();
+    let observed =
+        rows.iter().map(|row| row[5].parse::<usize>().unwrap()).sum::<usize>();
+    assert_eq!(observed, expected);
+}
+
+#[test]
+fn test_metagene_utr_cds() {
+    let out_fp = std::env::temp_dir().join("test_metagene_utr_cds.tsv");
+    run_modkit(&[
+        "metagene",
+        BEDMETHYL,
+        "--gtf",
+        GTF,
+        "--mode",
+        "utr-cds",
+        "--bins",
+        "4",
+        "--flank-bins",
+        "2",
+        "--min-coverage",
+        "1",
+        "-o",
+        out_fp.to_str().unwrap(),
+        "-f",
+    ])
+    .unwrap();
+    let rows = read_rows(&out_fp);
+    let segments =
+        rows.iter().map(|row| row[1].as_str()).collect::<Vec<&str>>();
+    for segment in ["upstream", "5utr", "cds", "3utr", "downstream"] {
+        assert!(segments.contains(&segment), "missing {segment}");
+    }
+    // only the two coding transcripts are used
+    assert!(rows.iter().all(|row| row[4].parse::<usize>().unwrap() <= 2));
+    // bins are in transcript order
+    let bins = rows
+        .iter()
+        .map(|row| row[3].parse::<usize>().unwrap())
+        .collect::<Vec<_>>();
+    assert!(bins.windows(2).all(|w| w[0] < w[1]));
+    assert!(*bins.last().unwrap() < 2 + 4 * 3 + 2);
+}
@@ -0,0 +1,69 @@
+use std::io::{BufRead, BufReader};
+
+use mod_kit::dmr::bedmethyl::BedMethylLine;
+use rust_htslib::bgzf;
+use rustc_hash::FxHashMap;
+
+use crate::common::{read_rows, run_modkit};
+
+mod common;
+
+const BEDMETHYL: &str =
+    "tests/resources/lung_00733-m_adjacent-normal_5mc-5hmc_chr20_cpg_pileup.\
+     bed.gz";
+const REFERENCE: &str = "tests/resources/GRCh38_chr20.fa";
+
+#[test]
+fn test_motif_stats_help() {
+    let _ = run_modkit(&["motif-stats", "--help"])
+        .expect("failed to run modkit motif-stats help");
+}
+
+#[test]
+fn test_motif_stats_cpg_bedmethyl() {
+    let out_fp = std::env::temp_dir().join("test_motif_stats_cpg.tsv");
+    run_modkit(&[
+        "motif-stats",
+        BEDMETHYL,
+        "--ref",
+        REFERENCE,
+        "--preset",
+        "cpg,chh",
+        "--min-coverage",
+        "5",
+        "-o",
+        out_fp.to_str().unwrap(),
+        "-f",
+    ])
+    .unwrap();
+    let rows = read_rows(&out_fp);
+
+    // all of the records are at CpGs, so none should be reported for CHH and
+    // every record passing the coverage filter should be counted
+    let mut expected = FxHashMap::default();
+    let reader = BufReader::new(bgzf::Reader::from_path(BEDMETHYL).unwrap());
+    for line in reader.lines().map(|l| l.unwrap()) {
+        let record = BedMethylLine::parse(&line).unwrap();
+        if record.valid_coverage < 5 {
+            continue;
+        }
+        let counts = expected
+            .entry(record.raw_mod_code.to_string())
+            .or_insert((0u64, 0u64, 0u64));
+        counts.0 += 1;
+        counts.1 += record.count_methylated;
+        counts.2 += record.valid_coverage;
+    }
+    assert!(!expected.is_empty());
+    assert_eq!(rows.len(), expected.len());
+    for row in rows {
+        assert_eq!(row[0], "CG");
+        assert_eq!(row[1], "0");
+        let (n_sites, n_mod, n_valid) = expected[&row[2]];
+        assert_eq!(row[3].parse::<u64>().unwrap(), n_sites);
+        assert_eq!(row[4].parse::<u64>().unwrap(), n_mod);
+        assert_eq!(row[5].parse::<u64>().unwrap(), n_valid);
+        let frac = row[6].parse::<f64>().unwrap();
+        assert!((frac - n_mod as f64 / n_valid as f64).abs() < 1e-5);
+    }
+}
@@ -2,10 +2,10 @@ use anyhow::Context;
 use itertools::Itertools;
 use rust_htslib::bam;
 use std::cmp::Ordering;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::fs::File;
 use std::io::{BufRead, BufReader};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 use common::{check_against_expected_text_file, run_modkit};
 use mod_kit::dmr::bedmethyl::BedMethylLine;
@@ -40,6 +40,218 @@ fn test_pileup_no_filt() {
     );
 }
 
+#[test]
+fn test_pileup_out_dir() {
+    let out_dir = std::env::temp_dir().join("test_pileup_out_dir");
+    if out_dir.exists() {
+        std::fs::remove_dir_all(&out_dir).unwrap();
+    }
+    run_modkit(&[
+        "pileup",
+        "--no-filtering",
+        "--only-tabs",
+        "tests/resources/bc_anchored_10_reads.sorted.bam",
+        "--out-dir",
+        out_dir.to_str().unwrap(),
+        "--prefix",
+        "sample",
+    ])
+    .unwrap();
+    check_against_expected_text_file(
+        out_dir.join("sample_pileup.bed").to_str().unwrap(),
+        "tests/resources/modbam.modpileup_nofilt.methyl.bed",
+    );
+    // an output path and --out-dir are mutually exclusive
+    assert!(run_modkit(&[
+        "pileup",
+        "tests/resources/bc_anchored_10_reads.sorted.bam",
+        out_dir.join("other.bed").to_str().unwrap(),
+        "--out-dir",
+        out_dir.to_str().unwrap(),
+    ])
+    .is_err());
+}
+
+#[test]
+fn test_pileup_bgzf() {
+    use rust_htslib::tbx::{self, Read};
+    let temp_file = std::env::temp_dir().join("test_pileup_bgzf.bed.gz");
+    run_modkit(&[
+        "pileup",
+        "-i",
+        "25",
+        "--no-filtering",
+        "--bgzf",
+        "tests/resources/bc_anchored_10_reads.sorted.bam",
+        temp_file.to_str().unwrap(),
+    ])
+    .unwrap();
+    let index_fp = temp_file.with_extension("gz.tbi");
+    assert!(index_fp.exists(), "missing tabix index {index_fp:?}");
+
+    let expected = BufReader::new(
+        File::open("tests/resources/modbam.modpileup_nofilt.methyl.bed")
+            .unwrap(),
+    )
+    .lines()
+    .map(|l| l.unwrap())
+    .collect::<Vec<String>>();
+    let mut reader = tbx::Reader::from_path(&temp_file).unwrap();
+    let contigs = reader.seqnames();
+    assert!(!contigs.is_empty());
+    let mut observed = Vec::new();
+    for contig in contigs {
+        let tid = reader.tid(&contig).unwrap();
+        reader.fetch(tid, 0, u32::MAX as u64).unwrap();
+        for record in reader.records() {
+            observed.push(String::from_utf8(record.unwrap()).unwrap());
+        }
+    }
+    assert_eq!(observed, expected);
+
+    // bgzf output needs a file to index
+    assert!(run_modkit(&[
+        "pileup",
+        "--bgzf",
+        "tests/resources/bc_anchored_10_reads.sorted.bam",
+        "-",
+    ])
+    .is_err());
+}
+
+#[test]
+fn test_pileup_bigwig() {
+    use bigtools::BigWigRead;
+    let bedgraph_dir = std::env::temp_dir().join("test_pileup_bigwig_bedgraph");
+    let bigwig_dir = std::env::temp_dir().join("test_pileup_bigwig_bigwig");
+    for (out_dir, extra_args) in
+        [(&bedgraph_dir, vec![]), (&bigwig_dir, vec!["--bigwig"])]
+    {
+        if out_dir.exists() {
+            std::fs::remove_dir_all(out_dir).unwrap();
+        }
+        let mut args = vec![
+            "pileup",
+            "--no-filtering",
+            "--bedgraph",
+            "--bedgraph-coverage",
+            "tests/resources/bc_anchored_10_reads.sorted.bam",
+            out_dir.to_str().unwrap(),
+        ];
+        args.extend(extra_args);
+        run_modkit(&args).unwrap();
+    }
+
+    let bedgraphs = std::fs::read_dir(&bedgraph_dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .sorted()
+        .collect::<Vec<PathBuf>>();
+    assert!(!bedgraphs.is_empty());
+    for bedgraph_fp in bedgraphs {
+        let expected = BufReader::new(File::open(&bedgraph_fp).unwrap())
+            .lines()
+            .map(|l| {
+                let l = l.unwrap();
+                let parts = l.split('\t').collect::<Vec<&str>>();
+                (
+                    parts[0].to_string(),
+                    parts[1].parse::<u32>().unwrap(),
+                    parts[3].parse::<f32>().unwrap(),
+                )
+            })
+            .collect::<Vec<(String, u32, f32)>>();
+        // named like the bedGraphs with a .bw extension
+        let bigwig_fp = bigwig_dir
+            .join(bedgraph_fp.file_name().unwrap())
+            .with_extension("bw");
+        let mut reader = BigWigRead::open_file(&bigwig_fp)
+            .with_context(|| format!("missing {bigwig_fp:?}"))
+            .unwrap();
+        let chroms = reader.chroms().to_vec();
+        let mut observed = Vec::new();
+        for chrom in chroms.iter().sorted_by(|a, b| a.name.cmp(&b.name)) {
+            for value in
+                reader.get_interval(&chrom.name, 0, chrom.length).unwrap()
+            {
+                let value = value.unwrap();
+                assert_eq!(value.end, value.start + 1);
+                observed.push((chrom.name.clone(), value.start, value.value));
+            }
+        }
+        let sort = |xs: Vec<(String, u32, f32)>| {
+            xs.into_iter()
+                .sorted_by(|a, b| (&a.0, a.1).cmp(&(&b.0, b.1)))
+                .collect::<Vec<(String, u32, f32)>>()
+        };
+        let (expected, observed) = (sort(expected), sort(observed));
+        assert_eq!(expected.len(), observed.len(), "{bigwig_fp:?}");
+        for (exp, obs) in expected.iter().zip(observed.iter()) {
+            assert_eq!((&exp.0, exp.1), (&obs.0, obs.1));
+            assert!((exp.2 - obs.2).abs() < 1e-6, "{exp:?} {obs:?}");
+        }
+    }
+}
+
+#[test]
+fn test_pileup_cigar_states() {
+    let out_bed = std::env::temp_dir().join("test_pileup_cigar_states.bed");
+    let states_fp = std::env::temp_dir().join("test_pileup_cigar_states.tsv");
+    run_modkit(&[
+        "pileup",
+        "--no-filtering",
+        "tests/resources/bc_anchored_10_reads.sorted.bam",
+        out_bed.to_str().unwrap(),
+        "--cigar-states",
+        states_fp.to_str().unwrap(),
+    ])
+    .unwrap();
+    // calls (valid coverage and N_fail) for 5mC, summed over strands
+    let mut calls_per_position = HashMap::new();
+    for line in BufReader::new(File::open(&out_bed).unwrap()).lines() {
+        let line = line.unwrap();
+        let parts = line.split('\t').collect::<Vec<&str>>();
+        if parts[3] != "m" {
+            continue;
+        }
+        let n_calls = parts[9].parse::<u32>().unwrap()
+            + parts[15].parse::<u32>().unwrap();
+        *calls_per_position
+            .entry(parts[1].parse::<u32>().unwrap())
+            .or_insert(0u32) += n_calls;
+    }
+
+    let states = std::fs::read_to_string(&states_fp).unwrap();
+    let mut lines = states.lines();
+    assert_eq!(
+        lines.next().unwrap(),
+        [
+            "chrom",
+            "start",
+            "end",
+            "n_match_call",
+            "n_match_nocall",
+            "n_delete",
+            "n_refskip",
+            "n_softclip"
+        ]
+        .join("\t")
+    );
+    let mut positions = Vec::new();
+    let mut total_softclip = 0u32;
+    for line in lines {
+        let parts = line.split('\t').collect::<Vec<&str>>();
+        let pos = parts[1].parse::<u32>().unwrap();
+        let n_match_call = parts[3].parse::<u32>().unwrap();
+        assert_eq!(n_match_call, calls_per_position[&pos], "{line}");
+        total_softclip += parts[7].parse::<u32>().unwrap();
+        positions.push(pos);
+    }
+    assert!(positions.windows(2).all(|w| w[0] < w[1]));
+    assert_eq!(positions.len(), calls_per_position.len());
+    assert!(total_softclip > 0);
+}
+
 #[test]
 fn test_pileup_with_filt() {
     let temp_file = std::env::temp_dir().join("test_pileup_withfilt.bed");
@@ -534,6 +746,9 @@ fn test_pileup_partition_tags_partitioned() {
     let mut count = 0;
     for result in tmp_dir.read_dir().unwrap() {
         let dir_entry = result.unwrap().path();
+        if dir_entry.extension().and_then(|s| s.to_str()) != Some("bed") {
+            continue;
+        }
         check_against_expected_text_file(
             dir_entry.to_str().unwrap(),
             control_file.to_str().unwrap(),
@@ -543,6 +758,123 @@ fn test_pileup_partition_tags_partitioned() {
     assert_eq!(count, 6);
 }
 
+#[test]
+fn test_pileup_partition_tags_manifest() {
+    let tmp_dir =
+        std::env::temp_dir().join("test_pileup_partition_tags_manifest");
+    if tmp_dir.exists() {
+        std::fs::remove_dir_all(&tmp_dir).unwrap();
+    }
+    run_modkit(&[
+        "pileup",
+        "tests/resources/bc_anchored_10_reads.haplotyped.sorted.bam",
+        tmp_dir.to_str().unwrap(),
+        "--partition-tag",
+        "RG",
+        "--partition-tag",
+        "HP",
+        "--prefix",
+        "sample",
+        "--no-filtering",
+    ])
+    .unwrap();
+    let manifest =
+        std::fs::read_to_string(tmp_dir.join("sample_manifest.json")).unwrap();
+    assert!(manifest.starts_with(r#"{"tags":["RG","HP"],"partitions":["#));
+    // the haplotyped test file has 10 reads in read groups A, B, C and
+    // haplotypes 1 and 2
+    let mut n_partitions = 0;
+    let mut total_reads = 0;
+    for entry in tmp_dir.read_dir().unwrap() {
+        let path = entry.unwrap().path();
+        if path.extension().and_then(|s| s.to_str()) != Some("bed") {
+            continue;
+        }
+        let file_name = path.file_name().unwrap().to_str().unwrap();
+        let name = file_name
+            .strip_prefix("sample_")
+            .unwrap()
+            .strip_suffix(".bed")
+            .unwrap();
+        let (rg, hp) = name.split_once('_').unwrap();
+        let n_rows = std::fs::read_to_string(&path).unwrap().lines().count();
+        let expected = format!(
+            r#"{{"name":"{name}","tag_values":{{"RG":"{rg}","HP":"{hp}"}},"files":["{file_name}"],"n_reads":"#
+        );
+        let start = manifest.find(&expected).expect(&expected);
+        let rest = &manifest[start + expected.len()..];
+        let (n_reads, rest) = rest.split_once(',').unwrap();
+        total_reads += n_reads.parse::<usize>().unwrap();
+        let n_rows_field = format!(r#""n_rows":{n_rows}}}"#);
+        assert!(rest.starts_with(&n_rows_field), "{rest}");
+        n_partitions += 1;
+    }
+    assert_eq!(n_partitions, 6);
+    assert_eq!(total_reads, 6 * 10);
+}
+
+#[test]
+fn test_pileup_partition_tags_nested() {
+    let tmp_dir =
+        std::env::temp_dir().join("test_pileup_partition_tags_nested");
+    if tmp_dir.exists() {
+        std::fs::remove_dir_all(&tmp_dir).unwrap();
+    }
+    let control_file =
+        std::env::temp_dir().join("test_pileup_partition_tags_nested.bed");
+    run_modkit(&[
+        "pileup",
+        "tests/resources/bc_anchored_10_reads.sorted.bam",
+        control_file.to_str().unwrap(),
+        "--no-filtering",
+    ])
+    .unwrap();
+    run_modkit(&[
+        "pileup",
+        "tests/resources/bc_anchored_10_reads.haplotyped.sorted.bam",
+        tmp_dir.to_str().unwrap(),
+        "--partition-tag",
+        "RG",
+        "--partition-tag",
+        "HP",
+        "--nested-partitions",
+        "--prefix",
+        "sample",
+        "--no-filtering",
+    ])
+    .unwrap();
+    let manifest =
+        std::fs::read_to_string(tmp_dir.join("sample_manifest.json")).unwrap();
+    // 3 read groups, each with a directory for haplotypes 1 and 2
+    let mut n_partitions = 0;
+    for rg in ["A", "B", "C"] {
+        let rg_dir = tmp_dir.join(format!("RG={rg}"));
+        assert!(rg_dir.is_dir(), "{rg_dir:?}");
+        for hp in ["1", "2"] {
+            let fp = rg_dir.join(format!("HP={hp}")).join("sample_pileup.bed");
+            check_against_expected_text_file(
+                fp.to_str().unwrap(),
+                control_file.to_str().unwrap(),
+            );
+            let expected = format!(
+                r#""tag_values":{{"RG":"{rg}","HP":"{hp}"}},"files":["RG={rg}/HP={hp}/sample_pileup.bed"]"#
+            );
+            assert!(manifest.contains(&expected), "{expected}");
+            n_partitions += 1;
+        }
+    }
+    assert_eq!(n_partitions, 6);
+    assert!(!tmp_dir.join("sample_ungrouped.bed").exists());
+
+    let err = run_modkit(&[
+        "pileup",
+        "tests/resources/bc_anchored_10_reads.haplotyped.sorted.bam",
+        tmp_dir.join("out.bed").to_str().unwrap(),
+        "--nested-partitions",
+    ]);
+    assert!(err.is_err());
+}
+
 #[test]
 fn test_pileup_partition_tags_bedgraph() {
     let tmp_dir = std::env::temp_dir()
@@ -725,6 +1057,9 @@ fn test_pileup_partition_tags_combine_strands() {
     let mut count = 0;
     for result in exp_dir.read_dir().unwrap() {
         let dir_entry = result.unwrap().path();
+        if dir_entry.extension().and_then(|s| s.to_str()) != Some("bed") {
+            continue;
+        }
         check_against_expected_text_file(
             dir_entry.to_str().unwrap(),
             control_file.to_str().unwrap(),
@@ -916,3 +1251,1353 @@ fn test_pileup_with_header() {
         "tests/resources/pileup_with_header.bed",
     );
 }
+
+#[test]
+fn test_pileup_sqlite_output() {
+    let bed_fp = std::env::temp_dir().join("test_pileup_sqlite_output.bed");
+    let db_fp = std::env::temp_dir().join("test_pileup_sqlite_output.sqlite");
+    for (out_fp, out_format) in [(&bed_fp, "bedmethyl"), (&db_fp, "sqlite")] {
+        run_modkit(&[
+            "pileup",
+            "--no-filtering",
+            "--out-format",
+            out_format,
+            "tests/resources/bc_anchored_10_reads.sorted.bam",
+            out_fp.to_str().unwrap(),
+            "--force",
+        ])
+        .unwrap();
+    }
+    let expected = BufReader::new(File::open(&bed_fp).unwrap())
+        .lines()
+        .map(|l| BedMethylLine::parse(&l.unwrap()).unwrap())
+        .map(|bm| (bm.chrom.clone(), bm.start(), bm.valid_coverage))
+        .collect::<Vec<(String, u64, u64)>>();
+    assert!(!expected.is_empty());
+
+    let connection = rusqlite::Connection::open(&db_fp).unwrap();
+    let observed = connection
+        .prepare(
+            "SELECT chrom, chromStart, valid_coverage FROM pileup ORDER BY \
+             rowid",
+        )
+        .unwrap()
+        .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))
+        .unwrap()
+        .collect::<Result<Vec<(String, u64, u64)>, _>>()
+        .unwrap();
+    assert_eq!(observed, expected);
+
+    // existing databases are only overwritten with --force
+    assert!(run_modkit(&[
+        "pileup",
+        "--out-format",
+        "sqlite",
+        "tests/resources/bc_anchored_10_reads.sorted.bam",
+        db_fp.to_str().unwrap(),
+    ])
+    .is_err());
+
+    // sqlite output can't be streamed
+    assert!(run_modkit(&[
+        "pileup",
+        "--out-format",
+        "sqlite",
+        "tests/resources/bc_anchored_10_reads.sorted.bam",
+        "-",
+    ])
+    .is_err());
+}
+
+#[test]
+fn test_pileup_parquet_output() {
+    use arrow_array::{Array, Float64Array, Int64Array, StringArray};
+    use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+
+    let bed_fp = std::env::temp_dir().join("test_pileup_parquet_output.bed");
+    let pq_fp = std::env::temp_dir().join("test_pileup_parquet_output.parquet");
+    for (out_fp, out_format) in [(&bed_fp, "bedmethyl"), (&pq_fp, "parquet")] {
+        run_modkit(&[
+            "pileup",
+            "--no-filtering",
+            "--out-format",
+            out_format,
+            "tests/resources/bc_anchored_10_reads.sorted.bam",
+            out_fp.to_str().unwrap(),
+            "--force",
+        ])
+        .unwrap();
+    }
+    let expected = BufReader::new(File::open(&bed_fp).unwrap())
+        .lines()
+        .map(|l| BedMethylLine::parse(&l.unwrap()).unwrap())
+        .map(|bm| {
+            (
+                bm.chrom.clone(),
+                bm.start() as i64,
+                bm.valid_coverage as i64,
+                bm.count_methylated as f64 * 100f64 / bm.valid_coverage as f64,
+            )
+        })
+        .collect::<Vec<(String, i64, i64, f64)>>();
+    assert!(!expected.is_empty());
+
+    let reader =
+        ParquetRecordBatchReaderBuilder::try_new(File::open(&pq_fp).unwrap())
+            .unwrap()
+            .build()
+            .unwrap();
+    let mut observed = Vec::new();
+    for batch in reader {
+        let batch = batch.unwrap();
+        let column = |name: &str| batch.column_by_name(name).unwrap().clone();
+        let chroms = column("chrom");
+        let chroms = chroms.as_any().downcast_ref::<StringArray>().unwrap();
+        let starts = column("chromStart");
+        let starts = starts.as_any().downcast_ref::<Int64Array>().unwrap();
+        let coverages = column("valid_coverage");
+        let coverages =
+            coverages.as_any().downcast_ref::<Int64Array>().unwrap();
+        let percents = column("percent_modified");
+        let percents =
+            percents.as_any().downcast_ref::<Float64Array>().unwrap();
+        for i in 0..batch.num_rows() {
+            observed.push((
+                chroms.value(i).to_string(),
+                starts.value(i),
+                coverages.value(i),
+                percents.value(i),
+            ));
+        }
+    }
+    assert_eq!(observed.len(), expected.len());
+    for (obs, exp) in observed.iter().zip(expected.iter()) {
+        assert_eq!((&obs.0, obs.1, obs.2), (&exp.0, exp.1, exp.2));
+        assert!((obs.3 - exp.3).abs() < 0.01, "{obs:?} != {exp:?}");
+    }
+
+    // parquet output can't be streamed
+    assert!(run_modkit(&[
+        "pileup",
+        "--out-format",
+        "parquet",
+        "tests/resources/bc_anchored_10_reads.sorted.bam",
+        "-",
+    ])
+    .is_err());
+}
+
+#[test]
+fn test_pileup_multiple_references() {
+    // split the reference into two FASTAs, as if the contigs came from two
+    // assemblies
+    let reference =
+        std::fs::read_to_string("tests/resources/CGI_ladder_3.6kb_ref.fa")
+            .unwrap();
+    let records = reference
+        .lines()
+        .collect::<Vec<&str>>()
+        .chunks(2)
+        .map(|record| (record[0].trim_start_matches('>'), record[1]))
+        .collect::<Vec<(&str, &str)>>();
+    let (first, second) = records.split_at(records.len() / 2);
+    let write_reference = |name: &str, records: &[(&str, &str)]| {
+        let fp = std::env::temp_dir().join(name);
+        let mut fasta = String::new();
+        let mut fai = String::new();
+        for (contig, seq) in records {
+            fasta.push_str(&format!(">{contig}\n"));
+            fai.push_str(&format!(
+                "{contig}\t{}\t{}\t{}\t{}\n",
+                seq.len(),
+                fasta.len(),
+                seq.len(),
+                seq.len() + 1
+            ));
+            fasta.push_str(&format!("{seq}\n"));
+        }
+        std::fs::write(&fp, fasta).unwrap();
+        std::fs::write(fp.with_extension("fa.fai"), fai).unwrap();
+        fp
+    };
+    let first_fp = write_reference("test_pileup_multi_ref_1.fa", first);
+    let second_fp = write_reference("test_pileup_multi_ref_2.fa", second);
+
+    let run = |out_name: &str, refs: &[&Path]| {
+        let out_fp = std::env::temp_dir().join(out_name);
+        let mut args = vec![
+            "pileup",
+            "tests/resources/bc_anchored_10_reads.sorted.bam",
+            out_fp.to_str().unwrap(),
+            "--no-filtering",
+            "--cpg",
+        ];
+        for ref_fp in refs {
+            args.push("--ref");
+            args.push(ref_fp.to_str().unwrap());
+        }
+        run_modkit(&args).map(|_| std::fs::read_to_string(&out_fp).unwrap())
+    };
+    let expected = run(
+        "test_pileup_multi_ref_expected.bed",
+        &[Path::new("tests/resources/CGI_ladder_3.6kb_ref.fa")],
+    )
+    .unwrap();
+    assert!(!expected.is_empty());
+    let observed =
+        run("test_pileup_multi_ref.bed", &[&first_fp, &second_fp]).unwrap();
+    assert_eq!(observed, expected);
+
+    // contigs that aren't in any reference are an error, as are contigs in
+    // more than one reference
+    assert!(run("test_pileup_multi_ref_missing.bed", &[&first_fp]).is_err());
+    assert!(run(
+        "test_pileup_multi_ref_dup.bed",
+        &[&first_fp, &second_fp, &first_fp]
+    )
+    .is_err());
+}
+
+#[test]
+fn test_pileup_cpg_islands_preset() {
+    let islands_bed = std::env::temp_dir().join("test_pileup_cpg_islands.bed");
+    std::fs::write(&islands_bed, "oligo_1512_adapters\t0\t100\tfirst\n")
+        .unwrap();
+    let out_bed =
+        std::env::temp_dir().join("test_pileup_cpg_islands_pileup.bed");
+    let islands_out =
+        std::env::temp_dir().join("test_pileup_cpg_islands_out.bed");
+    run_modkit(&[
+        "pileup",
+        "--no-filtering",
+        "tests/resources/bc_anchored_10_reads.sorted.bam",
+        out_bed.to_str().unwrap(),
+        "--ref",
+        "tests/resources/CGI_ladder_3.6kb_ref.fa",
+        "--preset",
+        "cpg-islands",
+        "--cpg-islands-bed",
+        islands_bed.to_str().unwrap(),
+        "--cpg-islands-out",
+        islands_out.to_str().unwrap(),
+    ])
+    .unwrap();
+
+    let (expected_valid, expected_mod, expected_sites) =
+        BufReader::new(File::open(&out_bed).unwrap())
+            .lines()
+            .map(|l| BedMethylLine::parse(&l.unwrap()).unwrap())
+            .filter(|bm| bm.chrom == "oligo_1512_adapters" && bm.start() < 100)
+            .fold((0u64, 0u64, 0usize), |(valid, n_mod, n), bm| {
+                (valid + bm.valid_coverage, n_mod + bm.count_methylated, n + 1)
+            });
+    let rows = BufReader::new(File::open(&islands_out).unwrap())
+        .lines()
+        .map(|l| l.unwrap())
+        .filter(|l| !l.starts_with('#'))
+        .collect::<Vec<String>>();
+    assert_eq!(rows.len(), 1);
+    let fields = rows[0].split('\t').collect::<Vec<&str>>();
+    assert_eq!(&fields[..4], &["oligo_1512_adapters", "0", "100", "first"]);
+    assert_eq!(fields[6], "m");
+    assert_eq!(fields[8].parse::<usize>().unwrap(), expected_sites);
+    assert_eq!(fields[9].parse::<u64>().unwrap(), expected_valid);
+    assert_eq!(fields[10].parse::<u64>().unwrap(), expected_mod);
+
+    // the preset needs somewhere to write the islands
+    assert!(run_modkit(&[
+        "pileup",
+        "--no-filtering",
+        "tests/resources/bc_anchored_10_reads.sorted.bam",
+        out_bed.to_str().unwrap(),
+        "--ref",
+        "tests/resources/CGI_ladder_3.6kb_ref.fa",
+        "--preset",
+        "cpg-islands",
+    ])
+    .is_err());
+}
+
+#[test]
+fn test_pileup_rna_reference_uracil_same_as_thymine() {
+    let rna_ref_fp =
+        std::env::temp_dir().join("test_pileup_rna_reference_uracil.fa");
+    let reference =
+        std::fs::read_to_string("tests/resources/CGI_ladder_3.6kb_ref.fa")
+            .unwrap();
+    let rna_reference = reference
+        .lines()
+        .map(|l| {
+            if l.starts_with('>') {
+                l.to_string()
+            } else {
+                l.replace('T', "U").replace('t', "u")
+            }
+        })
+        .collect::<Vec<String>>()
+        .join("\n");
+    std::fs::write(&rna_ref_fp, rna_reference).unwrap();
+    // same sequence lengths and line widths, the index is still valid
+    std::fs::copy(
+        "tests/resources/CGI_ladder_3.6kb_ref.fa.fai",
+        rna_ref_fp.with_extension("fa.fai"),
+    )
+    .unwrap();
+
+    let dna_out_fp =
+        std::env::temp_dir().join("test_pileup_rna_reference_uracil_dna.bed");
+    let rna_out_fp =
+        std::env::temp_dir().join("test_pileup_rna_reference_uracil_rna.bed");
+    for (ref_fp, out_fp) in [
+        ("tests/resources/CGI_ladder_3.6kb_ref.fa", &dna_out_fp),
+        (rna_ref_fp.to_str().unwrap(), &rna_out_fp),
+    ] {
+        run_modkit(&[
+            "pileup",
+            "tests/resources/bc_anchored_10_reads.sorted.bam",
+            out_fp.to_str().unwrap(),
+            "--no-filtering",
+            "--motif",
+            "GAUC",
+            "1",
+            "--motif",
+            "CG",
+            "0",
+            "--ref",
+            ref_fp,
+        ])
+        .unwrap();
+    }
+    check_against_expected_text_file(
+        rna_out_fp.to_str().unwrap(),
+        dna_out_fp.to_str().unwrap(),
+    );
+}
+
+#[test]
+fn test_pileup_m6a_drach_preset() {
+    let out_fp = std::env::temp_dir().join("test_pileup_m6a_drach_preset.bed");
+    run_modkit(&[
+        "pileup",
+        "tests/resources/bc_anchored_10_reads.sorted.bam",
+        out_fp.to_str().unwrap(),
+        "--no-filtering",
+        "--preset",
+        "m6A-DRACH",
+        "--ref",
+        "tests/resources/CGI_ladder_3.6kb_ref.fa",
+    ])
+    .unwrap();
+    let reader = BufReader::new(File::open(&out_fp).unwrap());
+    for line in reader.lines().map(|l| l.unwrap()) {
+        assert_eq!(line.split('\t').nth(5), Some("+"), "{line}");
+    }
+}
+
+#[test]
+fn test_pileup_rna_motif_labels() {
+    let name_column = |out_fp: &PathBuf| {
+        let reader = BufReader::new(File::open(out_fp).unwrap());
+        reader
+            .lines()
+            .map(|l| l.unwrap().split('\t').nth(3).unwrap().to_string())
+            .collect::<HashSet<String>>()
+    };
+    let uracil_fp =
+        std::env::temp_dir().join("test_pileup_rna_motif_labels_uracil.bed");
+    let rna_fp =
+        std::env::temp_dir().join("test_pileup_rna_motif_labels_rna.bed");
+    let dna_fp =
+        std::env::temp_dir().join("test_pileup_rna_motif_labels_dna.bed");
+    for (motif, out_fp, rna) in [
+        ("UCG", &uracil_fp, false),
+        ("TCG", &rna_fp, true),
+        ("TCG", &dna_fp, false),
+    ] {
+        let mut args = vec![
+            "pileup",
+            "tests/resources/bc_anchored_10_reads.sorted.bam",
+            out_fp.to_str().unwrap(),
+            "--no-filtering",
+            "--motif",
+            motif,
+            "1",
+            "--motif",
+            "CG",
+            "0",
+            "--ref",
+            "tests/resources/CGI_ladder_3.6kb_ref.fa",
+        ];
+        if rna {
+            args.push("--rna");
+        }
+        run_modkit(&args).unwrap();
+    }
+    let uracil_names = name_column(&uracil_fp);
+    assert!(uracil_names.contains("m,UCG,1"), "{uracil_names:?}");
+    assert!(uracil_names.iter().all(|name| !name.contains("TCG")));
+    assert_eq!(uracil_names, name_column(&rna_fp));
+    let dna_names = name_column(&dna_fp);
+    assert!(dna_names.contains("m,TCG,1"), "{dna_names:?}");
+}
+
+#[test]
+fn test_pileup_motif_chunk_boundaries() {
+    let small_fp = std::env::temp_dir()
+        .join("test_pileup_motif_chunk_boundaries_small.bed");
+    let large_fp = std::env::temp_dir()
+        .join("test_pileup_motif_chunk_boundaries_large.bed");
+    for (interval_size, out_fp) in [("5", &small_fp), ("100000", &large_fp)] {
+        run_modkit(&[
+            "pileup",
+            "tests/resources/bc_anchored_10_reads.sorted.bam",
+            out_fp.to_str().unwrap(),
+            "--no-filtering",
+            "-i",
+            interval_size,
+            "--motif",
+            "CG",
+            "0",
+            "--motif",
+            "GATC",
+            "1",
+            "--ref",
+            "tests/resources/CGI_ladder_3.6kb_ref.fa",
+        ])
+        .unwrap();
+    }
+    check_against_expected_text_file(
+        small_fp.to_str().unwrap(),
+        large_fp.to_str().unwrap(),
+    );
+}
+
+#[test]
+fn test_pileup_sampling_frac_per_contig() {
+    use rust_htslib::bam::header::HeaderRecord;
+    use rust_htslib::bam::Read;
+
+    // uneven coverage, 19 copies of the reads on the first contig and a
+    // single copy on a second contig
+    let bam_fp =
+        std::env::temp_dir().join("test_pileup_sampling_frac_per_contig.bam");
+    {
+        let mut reader = bam::Reader::from_path(
+            "tests/resources/bc_anchored_10_reads.sorted.bam",
+        )
+        .unwrap();
+        let length = reader.header().target_len(0).unwrap();
+        let mut header = bam::Header::from_template(reader.header());
+        header.push_record(
+            HeaderRecord::new(b"SQ")
+                .push_tag(b"SN", "second_contig")
+                .push_tag(b"LN", length),
+        );
+        let records =
+            reader.records().map(|r| r.unwrap()).collect::<Vec<_>>();
+        assert_eq!(records.len(), 10);
+        let mut writer =
+            bam::Writer::from_path(&bam_fp, &header, bam::Format::Bam).unwrap();
+        for record in records.iter() {
+            for copy in 0..19 {
+                let mut record = record.clone();
+                let name = format!(
+                    "{}_{copy}",
+                    String::from_utf8_lossy(record.qname())
+                );
+                record.set_qname(name.as_bytes());
+                writer.write(&record).unwrap();
+            }
+        }
+        for record in records.iter() {
+            let mut record = record.clone();
+            record.set_tid(1);
+            writer.write(&record).unwrap();
+        }
+    }
+    bam::index::build(&bam_fp, None, bam::index::Type::Bai, 1).unwrap();
+
+    // number of reads sampled from each contig to estimate the threshold,
+    // from the debug log
+    let sampled_per_contig = |name: &str, extra_args: &[&str]| {
+        let out_bed = std::env::temp_dir().join(format!("{name}.bed"));
+        let log_fp = std::env::temp_dir().join(format!("{name}.log"));
+        let _ = std::fs::remove_file(&log_fp);
+        let mut args = vec![
+            "pileup",
+            bam_fp.to_str().unwrap(),
+            out_bed.to_str().unwrap(),
+            "--num-reads",
+            "20",
+            "--force",
+            "--log-filepath",
+            log_fp.to_str().unwrap(),
+        ];
+        args.extend_from_slice(extra_args);
+        run_modkit(&args).unwrap();
+        std::fs::read_to_string(&log_fp)
+            .unwrap()
+            .split("final mapped reads sampled:")
+            .nth(1)
+            .unwrap()
+            .lines()
+            .skip(1)
+            .map(|l| l.split_whitespace().collect::<Vec<&str>>())
+            .take_while(|fields| fields.len() == 2 && fields[0] != "total")
+            .map(|fields| {
+                (fields[0].parse::<u32>().unwrap(), fields[1].parse().unwrap())
+            })
+            .collect::<HashMap<u32, usize>>()
+    };
+
+    // by default the reads are divided in proportion to the coverage
+    let proportional =
+        sampled_per_contig("test_pileup_sampling_frac_default", &[]);
+    assert_eq!(proportional, HashMap::from([(0, 19), (1, 1)]));
+    let uniform = sampled_per_contig(
+        "test_pileup_sampling_frac_per_contig",
+        &["--sampling-frac-per-contig"],
+    );
+    assert_eq!(uniform, HashMap::from([(0, 10), (1, 10)]));
+    let contig_quotas = sampled_per_contig(
+        "test_pileup_sampling_frac_contig_quotas",
+        &["--contig-quotas", "uniform"],
+    );
+    assert_eq!(contig_quotas, uniform);
+    assert!(run_modkit(&[
+        "pileup",
+        bam_fp.to_str().unwrap(),
+        std::env::temp_dir()
+            .join("test_pileup_sampling_frac_conflict.bed")
+            .to_str()
+            .unwrap(),
+        "--sampling-frac-per-contig",
+        "--contig-quotas",
+        "reads",
+    ])
+    .is_err());
+}
+
+#[test]
+fn test_pileup_qc_guardrails() {
+    use rust_htslib::bam::Read;
+
+    let out_fp =
+        std::env::temp_dir().join("test_pileup_qc_guardrails_pass.bed");
+    run_modkit(&[
+        "pileup",
+        "tests/resources/bc_anchored_10_reads.sorted.bam",
+        out_fp.to_str().unwrap(),
+        "--no-filtering",
+        "--min-mod-tag-rate",
+        "0.9",
+        "--max-fail-rate",
+        "0.1",
+    ])
+    .unwrap();
+    assert!(out_fp.exists());
+
+    // strip the MM/ML tags from every record, the guard should abort the run
+    let no_tags_bam =
+        std::env::temp_dir().join("test_pileup_qc_guardrails_no_tags.bam");
+    let mut reader = bam::Reader::from_path(
+        "tests/resources/bc_anchored_10_reads.sorted.bam",
+    )
+    .unwrap();
+    let header = bam::Header::from_template(reader.header());
+    let mut writer =
+        bam::Writer::from_path(&no_tags_bam, &header, bam::Format::Bam)
+            .unwrap();
+    for record in reader.records() {
+        let mut record = record.unwrap();
+        for tag in [b"MM", b"ML", b"Mm", b"Ml"] {
+            let _ = record.remove_aux(tag);
+        }
+        writer.write(&record).unwrap();
+    }
+    drop(writer);
+    bam::index::build(&no_tags_bam, None, bam::index::Type::Bai, 1).unwrap();
+    let out_fp =
+        std::env::temp_dir().join("test_pileup_qc_guardrails_fail.bed");
+    let args = [
+        "pileup",
+        no_tags_bam.to_str().unwrap(),
+        out_fp.to_str().unwrap(),
+        "--no-filtering",
+        "--min-mod-tag-rate",
+        "0.5",
+    ];
+    assert!(run_modkit(&args).is_err());
+    // without the guard the run completes
+    run_modkit(&args[..4]).unwrap();
+}
+
+#[test]
+fn test_pileup_run_summary() {
+    let out_bed = std::env::temp_dir().join("test_pileup_run_summary.bed");
+    let summary_fp = std::env::temp_dir().join("test_pileup_run_summary.json");
+    run_modkit(&[
+        "pileup",
+        "tests/resources/bc_anchored_10_reads.sorted.bam",
+        out_bed.to_str().unwrap(),
+        "--run-summary",
+        summary_fp.to_str().unwrap(),
+    ])
+    .unwrap();
+    let summary = std::fs::read_to_string(&summary_fp).unwrap();
+    assert_eq!(
+        summary.trim(),
+        r#"{"command":"pileup","success":true,"error":null,"reads":{"used":10,"skipped":0,"failed":0},"total_errors":0,"errors":{}}"#
+    );
+
+    // the summary is also written when the command fails
+    assert!(run_modkit(&[
+        "--run-summary",
+        summary_fp.to_str().unwrap(),
+        "pileup",
+        "tests/resources/not_a_file.bam",
+        out_bed.to_str().unwrap(),
+    ])
+    .is_err());
+    let summary = std::fs::read_to_string(&summary_fp).unwrap();
+    assert!(summary.contains(r#""success":false"#));
+}
+
+#[test]
+fn test_pileup_mm_mn_mismatch() {
+    use rust_htslib::bam::record::Aux;
+    use rust_htslib::bam::Read;
+
+    // add MN tags to all records and make the MM tag of one record refer to
+    // more Cs than the sequence has
+    let bam_fp = std::env::temp_dir().join("test_pileup_mm_mn_mismatch.bam");
+    {
+        let mut reader = bam::Reader::from_path(
+            "tests/resources/bc_anchored_10_reads.sorted.bam",
+        )
+        .unwrap();
+        let header = bam::Header::from_template(reader.header());
+        let mut writer =
+            bam::Writer::from_path(&bam_fp, &header, bam::Format::Bam).unwrap();
+        for (i, record) in reader.records().enumerate() {
+            let mut record = record.unwrap();
+            let _ = record.remove_aux(b"MN");
+            record.push_aux(b"MN", Aux::U32(record.seq_len() as u32)).unwrap();
+            if i == 0 {
+                let mm = match record.aux(b"MM").unwrap() {
+                    Aux::String(mm) => mm.to_string(),
+                    _ => panic!("MM should be a string"),
+                };
+                let bad_mm = mm.replacen(';', ",100000;", 1);
+                record.remove_aux(b"MM").unwrap();
+                record.push_aux(b"MM", Aux::String(&bad_mm)).unwrap();
+            }
+            writer.write(&record).unwrap();
+        }
+    }
+    bam::index::build(&bam_fp, None, bam::index::Type::Bai, 1).unwrap();
+
+    let out_bed = std::env::temp_dir().join("test_pileup_mm_mn_mismatch.bed");
+    let summary_fp =
+        std::env::temp_dir().join("test_pileup_mm_mn_mismatch.json");
+    run_modkit(&[
+        "pileup",
+        bam_fp.to_str().unwrap(),
+        out_bed.to_str().unwrap(),
+        "--run-summary",
+        summary_fp.to_str().unwrap(),
+    ])
+    .unwrap();
+    let summary = std::fs::read_to_string(&summary_fp).unwrap();
+    assert_eq!(
+        summary.trim(),
+        r#"{"command":"pileup","success":true,"error":null,"reads":{"used":9,"skipped":1,"failed":0},"total_errors":1,"errors":{"MM-MN-mismatch":1}}"#
+    );
+
+    let out_tsv = std::env::temp_dir().join("test_pileup_mm_mn_mismatch.tsv");
+    run_modkit(&[
+        "extract",
+        "full",
+        bam_fp.to_str().unwrap(),
+        out_tsv.to_str().unwrap(),
+        "--force",
+        "--run-summary",
+        summary_fp.to_str().unwrap(),
+    ])
+    .unwrap();
+    let summary = std::fs::read_to_string(&summary_fp).unwrap();
+    assert!(summary.contains(r#""errors":{"MM-MN-mismatch":1}"#), "{summary}");
+}
+
+#[test]
+fn test_pileup_mod_colors() {
+    let out_bed = std::env::temp_dir().join("test_pileup_mod_colors.bed");
+    let color_file = std::env::temp_dir().join("test_pileup_mod_colors.tsv");
+    std::fs::write(&color_file, "m\t#0000FF\n").unwrap();
+    run_modkit(&[
+        "pileup",
+        "tests/resources/bc_anchored_10_reads.sorted.bam",
+        out_bed.to_str().unwrap(),
+        "--color-by-code",
+        "--mod-color-file",
+        color_file.to_str().unwrap(),
+        "--mod-color",
+        "h",
+        "0,255,0",
+    ])
+    .unwrap();
+    let colors = std::fs::read_to_string(&out_bed)
+        .unwrap()
+        .lines()
+        .map(|l| {
+            let parts = l.split('\t').collect::<Vec<&str>>();
+            (parts[3].to_string(), parts[8].to_string())
+        })
+        .collect::<HashSet<(String, String)>>();
+    assert!(colors.contains(&("m".to_string(), "0,0,255".to_string())));
+    assert!(colors.contains(&("h".to_string(), "0,255,0".to_string())));
+    for (code, color) in colors {
+        match code.as_str() {
+            "m" => assert_eq!(color, "0,0,255"),
+            "h" => assert_eq!(color, "0,255,0"),
+            _ => assert_eq!(color, "255,0,0"),
+        }
+    }
+
+    assert!(run_modkit(&[
+        "pileup",
+        "tests/resources/bc_anchored_10_reads.sorted.bam",
+        out_bed.to_str().unwrap(),
+        "--mod-color",
+        "h",
+        "#00FF",
+    ])
+    .is_err());
+}
+
+#[test]
+fn test_pileup_bedgraph_coverage_tracks() {
+    let out_dir =
+        std::env::temp_dir().join("test_pileup_bedgraph_coverage_tracks");
+    if out_dir.exists() {
+        std::fs::remove_dir_all(&out_dir).unwrap();
+    }
+    run_modkit(&[
+        "pileup",
+        "tests/resources/bc_anchored_10_reads.sorted.bam",
+        out_dir.to_str().unwrap(),
+        "--bedgraph",
+        "--bedgraph-coverage",
+    ])
+    .unwrap();
+    let read_rows = |fp: PathBuf| {
+        std::fs::read_to_string(fp)
+            .unwrap()
+            .lines()
+            .map(|l| l.split('\t').map(|x| x.to_string()).collect::<Vec<_>>())
+            .collect::<Vec<Vec<String>>>()
+    };
+    let mut n_tracks = 0;
+    for entry in std::fs::read_dir(&out_dir).unwrap() {
+        let fp = entry.unwrap().path();
+        let name = fp.file_name().unwrap().to_str().unwrap().to_string();
+        if name.ends_with("_coverage.bedgraph") {
+            continue;
+        }
+        let coverage_fp =
+            out_dir.join(name.replace(".bedgraph", "_coverage.bedgraph"));
+        let frac_rows = read_rows(fp);
+        let coverage_rows = read_rows(coverage_fp);
+        assert!(!frac_rows.is_empty());
+        assert_eq!(frac_rows.len(), coverage_rows.len());
+        for (frac_row, coverage_row) in frac_rows.iter().zip(&coverage_rows) {
+            assert_eq!(coverage_row.len(), 4);
+            assert_eq!(frac_row[..3], coverage_row[..3]);
+            assert_eq!(frac_row[4], coverage_row[3]);
+        }
+        n_tracks += 1;
+    }
+    assert!(n_tracks > 0);
+}
+
+#[test]
+fn test_pileup_one_based() {
+    let zero_based_fp = std::env::temp_dir().join("test_pileup_zero_based.bed");
+    let one_based_fp = std::env::temp_dir().join("test_pileup_one_based.bed");
+    for (fp, extra_args) in [
+        (&zero_based_fp, vec!["--header"]),
+        (&one_based_fp, vec!["--header", "--one-based"]),
+    ] {
+        let mut args = vec![
+            "pileup",
+            "--no-filtering",
+            "tests/resources/bc_anchored_10_reads.sorted.bam",
+            fp.to_str().unwrap(),
+        ];
+        args.extend(extra_args);
+        run_modkit(&args).unwrap();
+    }
+    let read_lines = |fp: &PathBuf| {
+        std::fs::read_to_string(fp)
+            .unwrap()
+            .lines()
+            .map(|l| l.to_string())
+            .collect::<Vec<String>>()
+    };
+    let zero_based = read_lines(&zero_based_fp);
+    let one_based = read_lines(&one_based_fp);
+    assert_eq!(one_based[0], zero_based[0]);
+    assert!(one_based[1].starts_with("#coordinates: 1-based"));
+    let rows = |lines: &[String]| {
+        lines
+            .iter()
+            .filter(|l| !l.starts_with('#'))
+            .cloned()
+            .collect::<Vec<String>>()
+    };
+    let zero_based_rows = rows(&zero_based);
+    let one_based_rows = rows(&one_based);
+    assert!(!zero_based_rows.is_empty());
+    assert_eq!(zero_based_rows.len(), one_based_rows.len());
+    for (zb, ob) in zero_based_rows.iter().zip(one_based_rows.iter()) {
+        let zb = zb.split('\t').collect::<Vec<&str>>();
+        let ob = ob.split('\t').collect::<Vec<&str>>();
+        let start = zb[1].parse::<u32>().unwrap();
+        let end = zb[2].parse::<u32>().unwrap();
+        assert_eq!(ob[1].parse::<u32>().unwrap(), start + 1);
+        assert_eq!(ob[2].parse::<u32>().unwrap(), end);
+        assert_eq!(ob[6], ob[1]);
+        assert_eq!(ob[7], ob[2]);
+        assert_eq!(zb[3..6], ob[3..6]);
+        assert_eq!(zb[8..], ob[8..]);
+    }
+}
+
+#[test]
+fn test_pileup_score_column() {
+    let read_rows = |score: &str| {
+        let fp = std::env::temp_dir()
+            .join(format!("test_pileup_score_column_{score}.bed"));
+        run_modkit(&[
+            "pileup",
+            "--no-filtering",
+            "--score",
+            score,
+            "tests/resources/bc_anchored_10_reads.sorted.bam",
+            fp.to_str().unwrap(),
+        ])
+        .unwrap();
+        std::fs::read_to_string(fp)
+            .unwrap()
+            .lines()
+            .map(|l| {
+                l.split('\t').map(|x| x.to_string()).collect::<Vec<String>>()
+            })
+            .collect::<Vec<Vec<String>>>()
+    };
+    let coverage = read_rows("coverage");
+    let capped = read_rows("capped-coverage");
+    let percent = read_rows("percent");
+    assert!(!coverage.is_empty());
+    assert_eq!(coverage.len(), capped.len());
+    assert_eq!(coverage.len(), percent.len());
+    for ((cov, cap), pct) in
+        coverage.iter().zip(capped.iter()).zip(percent.iter())
+    {
+        let valid_coverage = cov[9].parse::<usize>().unwrap();
+        assert_eq!(cov[4].parse::<usize>().unwrap(), valid_coverage);
+        assert_eq!(cap[4].parse::<usize>().unwrap(), valid_coverage.min(1000));
+        // the percent column is rounded to 2 decimal places
+        let expected = (pct[10].parse::<f32>().unwrap() * 10f32).round();
+        let score = pct[4].parse::<f32>().unwrap();
+        assert!(score <= 1000f32);
+        assert!((score - expected).abs() <= 1f32, "{score} != {expected}");
+        assert_eq!(cov[..4], pct[..4]);
+        assert_eq!(cov[5..], pct[5..]);
+        assert_eq!(cov[5..], cap[5..]);
+    }
+}
+
+#[test]
+fn test_pileup_one_based_bedgraph() {
+    let out_dir = std::env::temp_dir().join("test_pileup_one_based_bedgraph");
+    if out_dir.exists() {
+        std::fs::remove_dir_all(&out_dir).unwrap();
+    }
+    run_modkit(&[
+        "pileup",
+        "tests/resources/bc_anchored_10_reads.sorted.bam",
+        out_dir.to_str().unwrap(),
+        "--bedgraph",
+        "--bedgraph-coverage",
+        "--one-based",
+    ])
+    .unwrap();
+    let mut n_tracks = 0;
+    for entry in std::fs::read_dir(&out_dir).unwrap() {
+        let contents = std::fs::read_to_string(entry.unwrap().path()).unwrap();
+        let mut lines = contents.lines();
+        assert!(lines.next().unwrap().starts_with("#coordinates: 1-based"));
+        for line in lines {
+            let fields = line.split('\t').collect::<Vec<&str>>();
+            assert_eq!(fields[1], fields[2]);
+        }
+        n_tracks += 1;
+    }
+    assert!(n_tracks > 0);
+}
+
+#[test]
+fn test_pileup_convert_matches_adjust_mods() {
+    let adjusted_bam =
+        std::env::temp_dir().join("test_pileup_convert_adjusted.bam");
+    run_modkit(&[
+        "adjust-mods",
+        "--convert",
+        "h",
+        "m",
+        "tests/resources/bc_anchored_10_reads.sorted.bam",
+        adjusted_bam.to_str().unwrap(),
+    ])
+    .unwrap();
+    bam::index::build(&adjusted_bam, None, bam::index::Type::Bai, 1).unwrap();
+
+    let expected_fp =
+        std::env::temp_dir().join("test_pileup_convert_expected.bed");
+    run_modkit(&[
+        "pileup",
+        "--no-filtering",
+        adjusted_bam.to_str().unwrap(),
+        expected_fp.to_str().unwrap(),
+    ])
+    .unwrap();
+    let converted_fp = std::env::temp_dir().join("test_pileup_convert.bed");
+    run_modkit(&[
+        "pileup",
+        "--no-filtering",
+        "--convert",
+        "h:m",
+        "tests/resources/bc_anchored_10_reads.sorted.bam",
+        converted_fp.to_str().unwrap(),
+    ])
+    .unwrap();
+
+    let converted = std::fs::read_to_string(&converted_fp).unwrap();
+    assert!(!converted.is_empty());
+    assert!(converted.lines().all(|l| l.split('\t').nth(3) != Some("h")));
+    assert_eq!(converted, std::fs::read_to_string(&expected_fp).unwrap());
+}
+
+#[test]
+fn test_pileup_convert_requires_single_target() {
+    let out_fp = std::env::temp_dir().join("test_pileup_convert_targets.bed");
+    let res = run_modkit(&[
+        "pileup",
+        "--convert",
+        "h:m",
+        "--convert",
+        "a:17596",
+        "tests/resources/bc_anchored_10_reads.sorted.bam",
+        out_fp.to_str().unwrap(),
+    ]);
+    assert!(res.is_err());
+}
+
+#[test]
+fn test_pileup_convert_mod_codes() {
+    let out_fp = std::env::temp_dir().join("test_pileup_convert_mod_codes.bed");
+    let convert = |extra_args: &[&str]| {
+        let mut args = vec![
+            "pileup",
+            "--no-filtering",
+            "tests/resources/bc_anchored_10_reads.sorted.bam",
+            out_fp.to_str().unwrap(),
+        ];
+        args.extend_from_slice(extra_args);
+        run_modkit(&args)
+    };
+    // 5hmC can't be converted to a modification of adenine
+    assert!(convert(&["--convert", "h:a"]).is_err());
+
+    let mod_codes_fp =
+        std::env::temp_dir().join("test_pileup_convert_mod_codes.tsv");
+    std::fs::write(&mod_codes_fp, "x\tA\tmy mod\n").unwrap();
+    let mod_codes = mod_codes_fp.to_str().unwrap();
+    assert!(
+        convert(&["--convert", "h:x", "--mod-code-file", mod_codes]).is_err()
+    );
+
+    std::fs::write(&mod_codes_fp, "x\tC\tmy mod\t#00AA00\n").unwrap();
+    convert(&["--convert", "h:x", "--mod-code-file", mod_codes]).unwrap();
+    let converted = std::fs::read_to_string(&out_fp).unwrap();
+    assert!(converted.lines().any(|l| l.split('\t').nth(3) == Some("x")));
+    assert!(converted.lines().all(|l| l.split('\t').nth(3) != Some("h")));
+
+    // built-in codes keep their primary base
+    std::fs::write(&mod_codes_fp, "h\tA\tnot 5hmC\n").unwrap();
+    assert!(convert(&["--mod-code-file", mod_codes]).is_err());
+}
+
+#[test]
+fn test_pileup_read_group_thresholds() {
+    use rust_htslib::bam::record::Aux;
+    use rust_htslib::bam::Read;
+
+    // write one copy of the reads with every other read in read group rg1
+    // and the rest in rg2, and one copy with all reads in rg1
+    let write_read_groups = |name: &str, split: bool| -> PathBuf {
+        let bam_fp = std::env::temp_dir().join(name);
+        {
+            let mut reader = bam::Reader::from_path(
+                "tests/resources/bc_anchored_10_reads.sorted.bam",
+            )
+            .unwrap();
+            let header = bam::Header::from_template(reader.header());
+            let mut writer =
+                bam::Writer::from_path(&bam_fp, &header, bam::Format::Bam)
+                    .unwrap();
+            for (i, record) in reader.records().enumerate() {
+                let mut record = record.unwrap();
+                let _ = record.remove_aux(b"RG");
+                let read_group =
+                    if split && i % 2 == 1 { "rg2" } else { "rg1" };
+                record.push_aux(b"RG", Aux::String(read_group)).unwrap();
+                writer.write(&record).unwrap();
+            }
+        }
+        bam::index::build(&bam_fp, None, bam::index::Type::Bai, 1).unwrap();
+        bam_fp
+    };
+    let split_bam =
+        write_read_groups("test_pileup_rg_thresholds_split.bam", true);
+    let single_bam =
+        write_read_groups("test_pileup_rg_thresholds_single.bam", false);
+
+    let run_pileup = |bam_fp: &PathBuf, name: &str, extra_args: &[&str]| {
+        let out_bed = std::env::temp_dir().join(name);
+        let mut args =
+            vec!["pileup", bam_fp.to_str().unwrap(), out_bed.to_str().unwrap()];
+        args.extend_from_slice(extra_args);
+        run_modkit(&args).unwrap();
+        std::fs::read_to_string(&out_bed).unwrap()
+    };
+    let sum_column = |bedmethyl: &str, column: usize| -> usize {
+        bedmethyl
+            .lines()
+            .map(|l| {
+                l.split('\t').nth(column).unwrap().parse::<usize>().unwrap()
+            })
+            .sum()
+    };
+
+    let global = run_pileup(
+        &split_bam,
+        "test_pileup_rg_thresholds_global.bed",
+        &["--filter-threshold", "0.7"],
+    );
+    // thresholds for a read group that isn't in the BAM don't change anything
+    let missing_rg = run_pileup(
+        &split_bam,
+        "test_pileup_rg_thresholds_missing.bed",
+        &["--filter-threshold", "0.7", "--rg-filter-threshold", "rg3", "0.99"],
+    );
+    assert_eq!(global, missing_rg);
+    // a stricter threshold for one read group fails more calls
+    let strict_rg = run_pileup(
+        &split_bam,
+        "test_pileup_rg_thresholds_strict.bed",
+        &[
+            "--filter-threshold",
+            "0.7",
+            "--rg-filter-threshold",
+            "rg1",
+            "C:0.99",
+        ],
+    );
+    assert_ne!(global, strict_rg);
+    // N_fail is the 16th column
+    assert!(sum_column(&strict_rg, 15) > sum_column(&global, 15));
+    // N_valid_cov is the 10th column
+    assert!(sum_column(&strict_rg, 9) < sum_column(&global, 9));
+
+    // with all of the reads in one read group, the estimated threshold for
+    // the read group is the same as the global threshold
+    let estimated = run_pileup(
+        &single_bam,
+        "test_pileup_rg_thresholds_estimated.bed",
+        &["--per-rg-thresholds"],
+    );
+    let not_estimated = run_pileup(
+        &single_bam,
+        "test_pileup_rg_thresholds_not_estimated.bed",
+        &[],
+    );
+    assert_eq!(estimated, not_estimated);
+    run_pileup(
+        &split_bam,
+        "test_pileup_rg_thresholds_estimated_split.bed",
+        &["--per-rg-thresholds"],
+    );
+
+    let invalid_bed =
+        std::env::temp_dir().join("test_pileup_rg_thresholds_invalid.bed");
+    assert!(run_modkit(&[
+        "pileup",
+        split_bam.to_str().unwrap(),
+        invalid_bed.to_str().unwrap(),
+        "--rg-filter-threshold",
+        "rg1",
+        "X:0.8",
+    ])
+    .is_err());
+}
+
+#[test]
+fn test_pileup_dedup_fragments() {
+    use rust_htslib::bam::{record::Aux, Read};
+
+    // write every read twice, once with the same name and once renamed with
+    // the original name in a `pi` tag, as if they were reads from the same
+    // fragment
+    let same_name_bam =
+        std::env::temp_dir().join("test_pileup_dedup_fragments.names.bam");
+    let tagged_bam =
+        std::env::temp_dir().join("test_pileup_dedup_fragments.tagged.bam");
+    {
+        let mut reader = bam::Reader::from_path(
+            "tests/resources/bc_anchored_10_reads.sorted.bam",
+        )
+        .unwrap();
+        let header = bam::Header::from_template(reader.header());
+        let mut same_name =
+            bam::Writer::from_path(&same_name_bam, &header, bam::Format::Bam)
+                .unwrap();
+        let mut tagged =
+            bam::Writer::from_path(&tagged_bam, &header, bam::Format::Bam)
+                .unwrap();
+        for record in reader.records() {
+            let record = record.unwrap();
+            same_name.write(&record).unwrap();
+            same_name.write(&record).unwrap();
+            let name = String::from_utf8(record.qname().to_vec()).unwrap();
+            for copy in 0..2 {
+                let mut renamed = record.clone();
+                renamed.set_qname(format!("{name}_{copy}").as_bytes());
+                renamed.push_aux(b"pi", Aux::String(&name)).unwrap();
+                tagged.write(&renamed).unwrap();
+            }
+        }
+    }
+    for fp in [&same_name_bam, &tagged_bam] {
+        bam::index::build(fp, None, bam::index::Type::Bai, 1).unwrap();
+    }
+
+    let run_pileup = |bam_fp: &str, name: &str, extra: &[&str]| {
+        let out_fp = std::env::temp_dir().join(name);
+        let mut args = vec![
+            "pileup",
+            bam_fp,
+            out_fp.to_str().unwrap(),
+            "--no-filtering",
+        ];
+        args.extend_from_slice(extra);
+        run_modkit(&args).unwrap();
+        std::fs::read_to_string(&out_fp).unwrap()
+    };
+    let expected = run_pileup(
+        "tests/resources/bc_anchored_10_reads.sorted.bam",
+        "test_pileup_dedup_fragments.expected.bed",
+        &[],
+    );
+    let doubled = run_pileup(
+        same_name_bam.to_str().unwrap(),
+        "test_pileup_dedup_fragments.doubled.bed",
+        &[],
+    );
+    assert_ne!(doubled, expected);
+    let deduped = run_pileup(
+        same_name_bam.to_str().unwrap(),
+        "test_pileup_dedup_fragments.deduped.bed",
+        &["--dedup-fragments"],
+    );
+    assert_eq!(deduped, expected);
+    // renamed reads are only recognized with the tag
+    let renamed = run_pileup(
+        tagged_bam.to_str().unwrap(),
+        "test_pileup_dedup_fragments.renamed.bed",
+        &["--dedup-fragments"],
+    );
+    assert_eq!(renamed, doubled);
+    let tagged = run_pileup(
+        tagged_bam.to_str().unwrap(),
+        "test_pileup_dedup_fragments.tagged.bed",
+        &["--dedup-fragments", "--fragment-tag", "pi"],
+    );
+    assert_eq!(tagged, expected);
+}
+
+#[test]
+fn test_pileup_cram() {
+    let temp_file = std::env::temp_dir().join("test_pileup_cram.bed");
+    let args = [
+        "pileup",
+        "-i",
+        "25",
+        "--no-filtering",
+        "--only-tabs",
+        "--ref",
+        "tests/resources/CGI_ladder_3.6kb_ref.fa",
+        "tests/resources/bc_anchored_10_reads.sorted.cram",
+        temp_file.to_str().unwrap(),
+    ];
+
+    run_modkit(&args).unwrap();
+
+    check_against_expected_text_file(
+        temp_file.to_str().unwrap(),
+        "tests/resources/modbam.modpileup_nofilt.methyl.bed",
+    );
+}
+
+#[test]
+fn test_pileup_dry_run() {
+    let tmp_dir = std::env::temp_dir().join("test_pileup_dry_run");
+    if tmp_dir.exists() {
+        std::fs::remove_dir_all(&tmp_dir).unwrap();
+    }
+    let out_bed = tmp_dir.join("pileup.bed");
+    run_modkit(&[
+        "pileup",
+        "tests/resources/bc_anchored_10_reads.sorted.bam",
+        out_bed.to_str().unwrap(),
+        "--cpg",
+        "--ref",
+        "tests/resources/CGI_ladder_3.6kb_ref.fa",
+        "--filter-threshold",
+        "0.7",
+        "--dry-run",
+    ])
+    .unwrap();
+    // nothing is written in a dry run
+    assert!(!tmp_dir.exists());
+
+    // inputs are still validated
+    assert!(run_modkit(&[
+        "pileup",
+        "tests/resources/bc_anchored_10_reads.sorted.bam",
+        out_bed.to_str().unwrap(),
+        "--filter-threshold",
+        "C:not_a_number",
+        "--dry-run",
+    ])
+    .is_err());
+    assert!(run_modkit(&[
+        "pileup",
+        "tests/resources/bc_anchored_10_reads.sorted.bam",
+        out_bed.to_str().unwrap(),
+        "--cpg",
+        "--ref",
+        "tests/resources/GRCh38_chr20.fa",
+        "--dry-run",
+    ])
+    .is_err());
+    assert!(!tmp_dir.exists());
+
+    // existing outputs are checked before anything is loaded
+    std::fs::create_dir_all(&tmp_dir).unwrap();
+    let out_db = tmp_dir.join("pileup.sqlite");
+    std::fs::write(&out_db, "").unwrap();
+    assert!(run_modkit(&[
+        "pileup",
+        "tests/resources/bc_anchored_10_reads.sorted.bam",
+        out_db.to_str().unwrap(),
+        "--out-format",
+        "sqlite",
+        "--dry-run",
+    ])
+    .is_err());
+}
+
+#[test]
+fn test_pileup_phased_vcf() {
+    let tmp_dir = std::env::temp_dir().join("test_pileup_phased_vcf");
+    if tmp_dir.exists() {
+        std::fs::remove_dir_all(&tmp_dir).unwrap();
+    }
+    std::fs::create_dir_all(&tmp_dir).unwrap();
+    let control_file = tmp_dir.join("control.bed");
+    run_modkit(&[
+        "pileup",
+        "tests/resources/bc_anchored_10_reads.sorted.bam",
+        control_file.to_str().unwrap(),
+        "--no-filtering",
+    ])
+    .unwrap();
+
+    // the reads have the reference base (C) at position 50, the unphased SNV
+    // is ignored
+    let write_vcf = |name: &str, genotype: &str| -> PathBuf {
+        let fp = tmp_dir.join(name);
+        let vcf = format!(
+            "##fileformat=VCFv4.2\n\
+             ##contig=<ID=oligo_1512_adapters,length=156>\n\
+             ##FORMAT=<ID=GT,Number=1,Type=String,Description=\"Genotype\">\n\
+             #CHROM\tPOS\tID\tREF\tALT\tQUAL\tFILTER\tINFO\tFORMAT\tsample\n\
+             oligo_1512_adapters\t51\t.\tC\tT\t.\tPASS\t.\tGT\t{genotype}\n\
+             oligo_1512_adapters\t61\t.\tG\tA\t.\tPASS\t.\tGT\t0/1\n"
+        );
+        std::fs::write(&fp, vcf).unwrap();
+        fp
+    };
+    for (genotype, haplotype) in [("0|1", "1"), ("1|0", "2")] {
+        let vcf_fp = write_vcf(&format!("hap{haplotype}.vcf"), genotype);
+        let out_dir = tmp_dir.join(format!("hap{haplotype}"));
+        run_modkit(&[
+            "pileup",
+            "tests/resources/bc_anchored_10_reads.sorted.bam",
+            out_dir.to_str().unwrap(),
+            "--phased-vcf",
+            vcf_fp.to_str().unwrap(),
+            "--prefix",
+            "sample",
+            "--no-filtering",
+        ])
+        .unwrap();
+        check_against_expected_text_file(
+            out_dir
+                .join(format!("sample_{haplotype}.bed"))
+                .to_str()
+                .unwrap(),
+            control_file.to_str().unwrap(),
+        );
+        let manifest =
+            std::fs::read_to_string(out_dir.join("sample_manifest.json"))
+                .unwrap();
+        let expected = format!(
+            r#"{{"tags":["HP"],"partitions":[{{"name":"{haplotype}","tag_values":{{"HP":"{haplotype}"}},"files":["sample_{haplotype}.bed"],"n_reads":10,"#
+        );
+        assert!(manifest.starts_with(&expected), "{manifest}");
+    }
+
+    let vcf_fp = write_vcf("unknown_sample.vcf", "0|1");
+    assert!(run_modkit(&[
+        "pileup",
+        "tests/resources/bc_anchored_10_reads.sorted.bam",
+        tmp_dir.join("unknown_sample").to_str().unwrap(),
+        "--phased-vcf",
+        vcf_fp.to_str().unwrap(),
+        "--vcf-sample",
+        "other",
+    ])
+    .is_err());
+    // only unphased SNVs
+    let vcf_fp = write_vcf("unphased.vcf", "0/1");
+    assert!(run_modkit(&[
+        "pileup",
+        "tests/resources/bc_anchored_10_reads.sorted.bam",
+        tmp_dir.join("unphased").to_str().unwrap(),
+        "--phased-vcf",
+        vcf_fp.to_str().unwrap(),
+    ])
+    .is_err());
+}
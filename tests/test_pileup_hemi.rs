@@ -60,3 +60,63 @@ fn test_pileup_hemi_preset() {
 }
 
 // todo test with combine mods
+
+#[test]
+fn test_pileup_hemi_multiple_motifs() {
+    let run_hemi = |name: &str, motifs: &[&str]| -> Vec<String> {
+        let temp_file = std::env::temp_dir().join(name);
+        let mut args = vec![
+            "pileup-hemi",
+            "tests/resources/duplex_modcalls_sort.bam",
+            "-o",
+            temp_file.to_str().unwrap(),
+            "-r",
+            "tests/resources/GRCh38_chr20.fa",
+            "--region",
+            "chr20:22,613,835-22,640,468",
+            "--no-filtering",
+            "--mixed-delim",
+        ];
+        args.extend_from_slice(motifs);
+        run_modkit(&args).unwrap();
+        std::fs::read_to_string(&temp_file)
+            .unwrap()
+            .lines()
+            .map(|l| l.to_string())
+            .collect()
+    };
+
+    // GCGC has the focus base at offset 1 and the paired negative-strand base
+    // at offset 2, every GCGC site is also a CG site.
+    let cg_rows = run_hemi("test_pileup_hemi_cg.bed", &["--motif", "CG", "0"]);
+    let gcgc_rows =
+        run_hemi("test_pileup_hemi_gcgc.bed", &["--motif", "GCGC", "1"]);
+    assert!(!gcgc_rows.is_empty());
+    assert!(gcgc_rows.len() < cg_rows.len());
+    assert!(gcgc_rows.iter().all(|row| cg_rows.contains(row)));
+
+    // the reads only have 5mC calls, so adding GATC doesn't change the output
+    let multi_rows = run_hemi(
+        "test_pileup_hemi_cg_gatc.bed",
+        &["--motif", "CG", "0", "--motif", "GATC", "1"],
+    );
+    assert_eq!(multi_rows, cg_rows);
+
+    let temp_file = std::env::temp_dir().join("test_pileup_hemi_nonpal.bed");
+    let args = [
+        "pileup-hemi",
+        "tests/resources/duplex_modcalls_sort.bam",
+        "-o",
+        temp_file.to_str().unwrap(),
+        "-r",
+        "tests/resources/GRCh38_chr20.fa",
+        "--motif",
+        "CG",
+        "0",
+        "--motif",
+        "CHH",
+        "0",
+        "--no-filtering",
+    ];
+    assert!(run_modkit(&args).is_err());
+}
@@ -0,0 +1,13 @@
+use crate::common::run_modkit;
+
+mod common;
+
+#[test]
+fn test_serve_help() {
+    run_modkit(&["serve", "--help"]).expect("serve help");
+}
+
+#[test]
+fn test_serve_requires_inputs() {
+    assert!(run_modkit(&["serve", "--port", "0"]).is_err());
+}
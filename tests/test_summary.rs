@@ -5,7 +5,7 @@ use crate::common::{
 use anyhow::Context;
 use mod_kit::mod_bam::{CollapseMethod, EdgeFilter};
 use mod_kit::mod_base_code::{BaseState, DnaBase};
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 use std::path::Path;
 
 mod common;
@@ -165,3 +165,318 @@ fn test_summary_implicit_calls() {
     //     region: None,
     // }
 }
+
+#[test]
+fn test_summary_contig_quotas() {
+    for quotas in ["reads", "length"] {
+        run_modkit(&[
+            "summary",
+            "tests/resources/bc_anchored_10_reads.sorted.bam",
+            "--num-reads",
+            "5",
+            "--contig-quotas",
+            quotas,
+        ])
+        .context(format!("failed to run summary with --contig-quotas {quotas}"))
+        .unwrap();
+    }
+}
+
+#[test]
+fn test_summary_table_formats() {
+    for table_format in ["pretty", "markdown", "csv"] {
+        run_modkit(&[
+            "summary",
+            "tests/resources/bc_anchored_10_reads.sorted.bam",
+            "--table-format",
+            table_format,
+        ])
+        .context(format!("failed to run summary with {table_format}"))
+        .unwrap();
+    }
+    assert!(run_modkit(&[
+        "summary",
+        "tests/resources/bc_anchored_10_reads.sorted.bam",
+        "--tsv",
+        "--table-format",
+        "csv",
+    ])
+    .is_err());
+}
+
+#[test]
+fn test_sample_probs_table_formats() {
+    let out_dir = std::env::temp_dir().join("test_sample_probs_table_formats");
+    run_modkit(&[
+        "sample-probs",
+        "tests/resources/bc_anchored_10_reads.sorted.bam",
+        "--hist",
+        "--table-format",
+        "csv",
+        "-o",
+        out_dir.to_str().unwrap(),
+        "--force",
+    ])
+    .unwrap();
+    let thresholds =
+        std::fs::read_to_string(out_dir.join("thresholds.csv")).unwrap();
+    let mut lines = thresholds.lines();
+    assert_eq!(lines.next(), Some("base,percentile,threshold"));
+    assert_eq!(lines.count(), 3);
+    let probabilities =
+        std::fs::read_to_string(out_dir.join("probabilities.csv")).unwrap();
+    assert!(probabilities.starts_with(
+        "code,primary_base,range_start,range_end,count,frac,percentile_rank"
+    ));
+
+    run_modkit(&[
+        "sample-probs",
+        "tests/resources/bc_anchored_10_reads.sorted.bam",
+        "--table-format",
+        "markdown",
+        "-o",
+        out_dir.to_str().unwrap(),
+        "--force",
+    ])
+    .unwrap();
+    let thresholds =
+        std::fs::read_to_string(out_dir.join("thresholds.md")).unwrap();
+    let lines = thresholds.lines().collect::<Vec<&str>>();
+    assert_eq!(lines.len(), 5);
+    assert!(lines[0].starts_with("| base"));
+    assert!(lines[1].starts_with("|---"));
+}
+
+#[test]
+fn test_summary_html_report() {
+    let html_fp = std::env::temp_dir().join("test_summary_html_report.html");
+    run_modkit(&[
+        "summary",
+        "tests/resources/bc_anchored_10_reads.sorted.bam",
+        "--html",
+        html_fp.to_str().unwrap(),
+        "--force",
+    ])
+    .unwrap();
+    let report = std::fs::read_to_string(&html_fp).unwrap();
+    assert!(report.starts_with("<!DOCTYPE html>"));
+    assert!(report.contains("<tr><td>total_reads_used</td><td>10</td></tr>"));
+    assert!(report.contains("<th>pass_frac</th>"));
+    assert!(report.contains(
+        "<tr><td>basecall_model</td><td>dna_r10.4.1_e8.2_400bps_hac@v3.5.2\
+         </td></tr>"
+    ));
+    assert!(report.contains("getElementById('counts_chart')"));
+    assert!(report.contains("getElementById('proportion_chart')"));
+    // echarts is embedded, the report doesn't need network access
+    assert!(!report.contains("<script src="));
+    // refuses to overwrite without --force
+    assert!(run_modkit(&[
+        "summary",
+        "tests/resources/bc_anchored_10_reads.sorted.bam",
+        "--html",
+        html_fp.to_str().unwrap(),
+    ])
+    .is_err());
+}
+
+#[test]
+fn test_summary_out_dir() {
+    let out_dir = std::env::temp_dir().join("test_summary_out_dir");
+    run_modkit(&[
+        "summary",
+        "tests/resources/bc_anchored_10_reads.sorted.bam",
+        "--tsv",
+        "--out-dir",
+        out_dir.to_str().unwrap(),
+        "--prefix",
+        "sample",
+        "--force",
+    ])
+    .unwrap();
+    let summary =
+        std::fs::read_to_string(out_dir.join("sample_summary.tsv")).unwrap();
+    assert!(summary.contains("total_reads_used\t10"));
+    run_modkit(&[
+        "summary",
+        "tests/resources/bc_anchored_10_reads.sorted.bam",
+        "--table-format",
+        "markdown",
+        "--out-dir",
+        out_dir.to_str().unwrap(),
+        "--force",
+    ])
+    .unwrap();
+    assert!(out_dir.join("summary.md").exists());
+    // refuses to overwrite without --force
+    assert!(run_modkit(&[
+        "summary",
+        "tests/resources/bc_anchored_10_reads.sorted.bam",
+        "--tsv",
+        "--out-dir",
+        out_dir.to_str().unwrap(),
+        "--prefix",
+        "sample",
+    ])
+    .is_err());
+}
+
+#[test]
+fn test_summary_histograms_tsv() {
+    let hist_fp = std::env::temp_dir().join("test_summary_histograms.tsv");
+    run_modkit(&[
+        "summary",
+        "tests/resources/bc_anchored_10_reads.sorted.bam",
+        "--no-sampling",
+        "--histograms-tsv",
+        hist_fp.to_str().unwrap(),
+        "--force",
+    ])
+    .unwrap();
+    let table = std::fs::read_to_string(&hist_fp).unwrap();
+    let mut lines = table.lines();
+    assert_eq!(
+        lines.next().unwrap(),
+        [
+            "code",
+            "primary_base",
+            "range_start",
+            "range_end",
+            "count",
+            "frac",
+            "percentile_rank"
+        ]
+        .join("\t")
+    );
+    let mut frac_sums = HashMap::new();
+    for line in lines {
+        let parts = line.split('\t').collect::<Vec<&str>>();
+        assert_eq!(parts.len(), 7);
+        *frac_sums.entry(parts[0].to_string()).or_insert(0f32) +=
+            parts[5].parse::<f32>().unwrap();
+    }
+    assert!(frac_sums.contains_key("m"));
+    for (code, frac_sum) in frac_sums {
+        assert!((frac_sum - 1f32).abs() < 1e-3, "{code} {frac_sum}");
+    }
+}
+
+#[test]
+fn test_sample_probs_thresholds_json() {
+    let out_dir =
+        std::env::temp_dir().join("test_sample_probs_thresholds_json");
+    run_modkit(&[
+        "sample-probs",
+        "tests/resources/bc_anchored_10_reads.sorted.bam",
+        "--json",
+        "--prefix",
+        "sample",
+        "-o",
+        out_dir.to_str().unwrap(),
+        "--force",
+    ])
+    .unwrap();
+    let json = std::fs::read_to_string(out_dir.join("sample_thresholds.json"))
+        .unwrap();
+    assert!(json.starts_with("{\"bases\":{\"C\":{\"thresholds\":["));
+    assert!(json.contains("{\"percentile\":10,\"threshold\":"));
+    assert!(json.contains("\"curve\":[{\"percentile\":0,\"threshold\":"));
+    assert!(json.contains("{\"percentile\":7.5,\"threshold\":"));
+    assert_eq!(json.matches("\"percentile\"").count(), 3 + 201);
+    assert!(out_dir.join("sample_thresholds.tsv").exists());
+}
+
+#[test]
+fn test_summary_regions_bed() {
+    let regions_fp =
+        std::env::temp_dir().join("test_summary_regions_bed_regions.bed");
+    std::fs::write(&regions_fp, "oligo_1512_adapters\t55\t95\n").unwrap();
+    let region_html =
+        std::env::temp_dir().join("test_summary_regions_bed_region.html");
+    let regions_html =
+        std::env::temp_dir().join("test_summary_regions_bed_regions.html");
+    for (html_fp, args) in [
+        (&region_html, ["--region", "oligo_1512_adapters:55-95"]),
+        (&regions_html, ["--regions", regions_fp.to_str().unwrap()]),
+    ] {
+        run_modkit(&[
+            "summary",
+            "tests/resources/bc_anchored_10_reads.sorted.bam",
+            "--no-sampling",
+            "--only-mapped",
+            "--html",
+            html_fp.to_str().unwrap(),
+            "--force",
+            args[0],
+            args[1],
+        ])
+        .unwrap();
+    }
+    // the same reads and calls are summarized as with --region, only the
+    // region row of the metadata table differs
+    let region_report = std::fs::read_to_string(&region_html).unwrap().replace(
+        "<tr><td>region</td><td>oligo_1512_adapters:55-95</td></tr>\n",
+        "",
+    );
+    assert_eq!(region_report, std::fs::read_to_string(&regions_html).unwrap());
+
+    // --regions requires an index
+    let unindexed = std::env::temp_dir().join("test_summary_regions_bed.bam");
+    std::fs::copy(
+        "tests/resources/bc_anchored_10_reads.sorted.bam",
+        &unindexed,
+    )
+    .unwrap();
+    assert!(run_modkit(&[
+        "summary",
+        unindexed.to_str().unwrap(),
+        "--regions",
+        regions_fp.to_str().unwrap(),
+    ])
+    .is_err());
+}
+
+#[test]
+fn test_summary_by_strand() {
+    let html_fp = std::env::temp_dir().join("test_summary_by_strand.html");
+    run_modkit(&[
+        "summary",
+        "tests/resources/fwd_rev_modbase_records.sorted.bam",
+        "--no-sampling",
+        "--no-filtering",
+        "--by-strand",
+        "--html",
+        html_fp.to_str().unwrap(),
+        "--force",
+    ])
+    .unwrap();
+    let report = std::fs::read_to_string(&html_fp).unwrap();
+    assert!(report.contains("<h2>Modification calls by reference strand</h2>"));
+    let rows = report
+        .lines()
+        .filter(|l| l.starts_with("<tr><td>C</td>"))
+        .map(|l| {
+            l.trim_start_matches("<tr><td>")
+                .trim_end_matches("</td></tr>")
+                .split("</td><td>")
+                .map(|x| x.to_string())
+                .collect::<Vec<String>>()
+        })
+        .collect::<Vec<Vec<String>>>();
+    // base code pass_count pass_frac all_count all_frac
+    let totals = rows
+        .iter()
+        .filter(|r| r.len() == 6)
+        .map(|r| (r[1].clone(), r[4].parse::<u64>().unwrap()))
+        .collect::<HashMap<String, u64>>();
+    // base strand code pass_count pass_frac all_count all_frac
+    let stranded = rows.iter().filter(|r| r.len() == 7).collect::<Vec<_>>();
+    assert!(stranded.iter().any(|r| r[1] == "+"));
+    assert!(stranded.iter().any(|r| r[1] == "-"));
+    let mut stranded_totals = HashMap::new();
+    for r in stranded {
+        *stranded_totals.entry(r[2].clone()).or_insert(0u64) +=
+            r[5].parse::<u64>().unwrap();
+    }
+    assert_eq!(totals, stranded_totals);
+}
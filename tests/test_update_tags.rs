@@ -34,3 +34,48 @@ fn test_update_tags_implicit_no_probabilities() {
     };
     assert_eq!(old_tags, "C+h.;C+m.;");
 }
+
+#[test]
+fn test_update_tags_threads_keep_record_order() {
+    let in_bam = "tests/resources/bc_anchored_10_reads.sorted.bam";
+    let read_names_and_tags = |fp: &str| {
+        let mut reader = bam::Reader::from_path(fp).unwrap();
+        reader
+            .records()
+            .map(|r| r.unwrap())
+            .map(|record| {
+                let name = String::from_utf8(record.qname().to_vec()).unwrap();
+                let mm = match record.aux("MM".as_bytes()) {
+                    Ok(Aux::String(mm)) => mm.to_string(),
+                    _ => String::new(),
+                };
+                (name, mm)
+            })
+            .collect::<Vec<(String, String)>>()
+    };
+    let mut outputs = Vec::new();
+    for threads in ["1", "4"] {
+        let out_bam = std::env::temp_dir()
+            .join(format!("test_update_tags_threads_{threads}.bam"));
+        run_modkit(&[
+            "update-tags",
+            in_bam,
+            out_bam.to_str().unwrap(),
+            "--threads",
+            threads,
+        ])
+        .expect("should run update-tags");
+        outputs.push(read_names_and_tags(out_bam.to_str().unwrap()));
+    }
+    let input_names = read_names_and_tags(in_bam)
+        .into_iter()
+        .map(|(name, _)| name)
+        .collect::<Vec<String>>();
+    assert!(!outputs[0].is_empty());
+    assert_eq!(outputs[0], outputs[1]);
+    let output_names = outputs[1]
+        .iter()
+        .map(|(name, _)| name.to_string())
+        .collect::<Vec<String>>();
+    assert_eq!(output_names, input_names);
+}
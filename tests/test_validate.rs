@@ -52,3 +52,71 @@ fn test_validate_expected() {
         }
     }
 }
+
+#[test]
+fn test_validate_bedmethyl_vs_truth() {
+    let bedmethyl_fp = "tests/resources/\
+                        bc_anchored_10_reads_nofilt_cg_motif_strand_combine.\
+                        bed";
+    let truth_fp = std::env::temp_dir().join("test_validate_bm_truth.bed");
+    let truth = BufReader::new(File::open(bedmethyl_fp).unwrap())
+        .lines()
+        .map(|l| l.unwrap())
+        .filter(|l| l.split_whitespace().nth(3) == Some("m"))
+        .collect::<Vec<String>>()
+        .join("\n");
+    std::fs::write(&truth_fp, truth).unwrap();
+    let output_file = std::env::temp_dir().join("test_validate_bm_truth.tsv");
+    run_modkit(&[
+        "validate",
+        "--bedmethyl-and-truth",
+        bedmethyl_fp,
+        truth_fp.to_str().unwrap(),
+        "--truth-column",
+        "11",
+        "--truth-percent",
+        "--mod-code",
+        "m",
+        "--out-filepath",
+        output_file.to_str().unwrap(),
+    ])
+    .context("should run validate with bedMethyl and truth")
+    .unwrap();
+
+    let reader = BufReader::new(File::open(&output_file).unwrap());
+    let lines = reader.lines().map(|l| l.unwrap()).collect::<Vec<String>>();
+    assert!(lines[0].starts_with("coverage\tn_sites"));
+    let all = lines.last().unwrap().split('\t').collect::<Vec<&str>>();
+    assert_eq!(all[0], "all");
+    assert!(all[1].parse::<u64>().unwrap() > 0);
+    let pearson_r = all[2].parse::<f64>().unwrap();
+    let rmse = all[3].parse::<f64>().unwrap();
+    let bias = all[4].parse::<f64>().unwrap();
+    assert!((pearson_r - 1f64).abs() < 1e-4, "{pearson_r}");
+    assert!(rmse < 1e-4, "{rmse}");
+    assert!(bias.abs() < 1e-4, "{bias}");
+
+    // same output at the standardized path with --out-dir
+    let out_dir = std::env::temp_dir().join("test_validate_bm_truth_out_dir");
+    run_modkit(&[
+        "validate",
+        "--bedmethyl-and-truth",
+        bedmethyl_fp,
+        truth_fp.to_str().unwrap(),
+        "--truth-column",
+        "11",
+        "--truth-percent",
+        "--mod-code",
+        "m",
+        "--out-dir",
+        out_dir.to_str().unwrap(),
+        "--prefix",
+        "sample",
+    ])
+    .unwrap();
+    assert_eq!(
+        std::fs::read_to_string(&output_file).unwrap(),
+        std::fs::read_to_string(out_dir.join("sample_validate_truth.tsv"))
+            .unwrap()
+    );
+}